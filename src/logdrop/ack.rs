@@ -0,0 +1,204 @@
+//! Acknowledgment of records back to the input they came from.
+//!
+//! An input wanting to know when its records are handled, e.g. to advance a checkpoint only past
+//! records that can no longer be lost, sends each one with an `Ack`. The dispatch hands a copy of
+//! it to every output required to confirm the record, each one confirming it once the output
+//! has accepted the record and flushed it, as batching outputs merely queue records they accept.
+//! Once the last copy is gone, the input is told through the channel the token was made with
+//! whether every holder has confirmed it.
+//!
+//! A copy dropped unconfirmed, e.g. by an output failing to accept the record or by a worker
+//! going away with the record still queued, fails the record, so a record is never reported as
+//! delivered without being so.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvError, Sender};
+
+/// Outcome of a record: its id and whether every holder of its token has confirmed it.
+pub type Outcome = (u64, bool);
+
+struct Token {
+    id: u64,
+    failed: AtomicBool,
+    tx: Mutex<Sender<Outcome>>,
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        let delivered = !self.failed.load(Ordering::SeqCst);
+        let _ = self.tx.lock().unwrap().send((self.id, delivered));
+    }
+}
+
+/// Copy of the acknowledgment token of a record, see the module documentation.
+pub struct Ack {
+    token: Arc<Token>,
+    confirmed: bool,
+}
+
+impl Ack {
+    /// Creates the token of the record with the given id, reporting its outcome through the
+    /// channel.
+    pub fn new(id: u64, tx: Sender<Outcome>) -> Ack {
+        let token = Token {
+            id: id,
+            failed: AtomicBool::new(false),
+            tx: Mutex::new(tx),
+        };
+
+        Ack {
+            token: Arc::new(token),
+            confirmed: false,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.token.id
+    }
+
+    /// Confirms the record on behalf of this holder.
+    pub fn confirm(mut self) {
+        self.confirmed = true;
+    }
+}
+
+impl Clone for Ack {
+    /// Returns another copy of the token, to be confirmed on its own.
+    fn clone(&self) -> Ack {
+        Ack {
+            token: self.token.clone(),
+            confirmed: false,
+        }
+    }
+}
+
+impl fmt::Debug for Ack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ack({})", self.token.id)
+    }
+}
+
+impl Drop for Ack {
+    fn drop(&mut self) {
+        if !self.confirmed {
+            self.token.failed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Tracks outcomes of records sent with consecutive ids, telling how far all of them are
+/// delivered.
+pub struct Tracker {
+    rx: Receiver<Outcome>,
+    /// Id of the first record not known to be delivered.
+    next: u64,
+    /// Ids of records delivered past the first one not known to be.
+    ahead: HashSet<u64>,
+    /// Id of the first failed record, if any, beyond which the tracker never advances.
+    failed: Option<u64>,
+}
+
+impl Tracker {
+    /// Creates the tracker of records with ids starting from the given one.
+    pub fn new(rx: Receiver<Outcome>, first: u64) -> Tracker {
+        Tracker {
+            rx: rx,
+            next: first,
+            ahead: HashSet::new(),
+            failed: None,
+        }
+    }
+
+    /// Returns the number of records, counting from id zero, delivered in full so far.
+    pub fn delivered(&self) -> u64 {
+        self.next
+    }
+
+    pub fn failed(&self) -> Option<u64> {
+        self.failed
+    }
+
+    /// Takes outcomes reported so far, returning the number of records delivered in full.
+    pub fn poll(&mut self) -> u64 {
+        while let Ok(outcome) = self.rx.try_recv() {
+            self.take(outcome);
+        }
+        self.next
+    }
+
+    /// Waits for outcomes until records up to the given id, exclusive, are delivered, one of them
+    /// fails or all tokens are gone, returning the number of records delivered in full.
+    pub fn wait(&mut self, until: u64) -> u64 {
+        self.poll();
+        while self.next < until && self.failed.map_or(true, |failed| failed >= until) {
+            match self.rx.recv() {
+                Ok(outcome) => self.take(outcome),
+                Err(RecvError) => break,
+            }
+        }
+        self.next
+    }
+
+    fn take(&mut self, (id, delivered): Outcome) {
+        if !delivered {
+            self.failed = Some(self.failed.map_or(id, |failed| if id < failed { id } else { failed }));
+            return;
+        }
+
+        self.ahead.insert(id);
+        while self.failed != Some(self.next) && self.ahead.remove(&self.next) {
+            self.next += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use super::{Ack, Tracker};
+
+    #[test]
+    fn deliver_once_every_copy_is_confirmed() {
+        let (tx, rx) = channel();
+
+        let ack = Ack::new(7, tx.clone());
+        let copy = ack.clone();
+        ack.confirm();
+        assert!(rx.try_recv().is_err());
+        copy.confirm();
+        assert_eq!(Ok((7, true)), rx.try_recv());
+
+        let ack = Ack::new(8, tx);
+        let copy = ack.clone();
+        drop(copy);
+        ack.confirm();
+        assert_eq!(Ok((8, false)), rx.try_recv());
+    }
+
+    #[test]
+    fn advance_over_delivered_prefix_only() {
+        let (tx, rx) = channel();
+        let mut tracker = Tracker::new(rx, 2);
+        let acks: Vec<Ack> = (2..7).map(|id| Ack::new(id, tx.clone())).collect();
+        drop(tx);
+
+        let mut acks = acks.into_iter();
+        let (first, second, third, fourth, fifth) =
+            (acks.next().unwrap(), acks.next().unwrap(), acks.next().unwrap(), acks.next().unwrap(), acks.next().unwrap());
+
+        second.confirm();
+        assert_eq!(2, tracker.poll());
+        first.confirm();
+        assert_eq!(4, tracker.poll());
+
+        drop(fourth);
+        fifth.confirm();
+        third.confirm();
+        assert_eq!(5, tracker.wait(7));
+        assert_eq!(Some(5), tracker.failed());
+    }
+}
@@ -0,0 +1,547 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error, FromConfig};
+use super::metrics;
+
+/// Value of the record `type` field marking alert records.
+pub const TYPE: &'static str = "logdrop_alert";
+
+/// Quantity derived from metrics a rule watches.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// Current value of the metric.
+    Value(String),
+    /// Increase of the metric per second over the window in milliseconds.
+    Rate(String, u64),
+    /// Ratio of the first metric to the second one, of their increases over the window in
+    /// milliseconds if given, or of their values otherwise.
+    Ratio(String, String, Option<u64>),
+}
+
+impl Condition {
+    fn window(&self) -> Option<u64> {
+        match *self {
+            Condition::Value(..) => None,
+            Condition::Rate(_, window) => Some(window),
+            Condition::Ratio(_, _, window) => window,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Condition::Value(ref metric) => write!(f, "{}", metric),
+            Condition::Rate(ref metric, window) => write!(f, "rate of {} over {} ms", metric, window),
+            Condition::Ratio(ref numerator, ref denominator, None) => write!(f, "{} / {}", numerator, denominator),
+            Condition::Ratio(ref numerator, ref denominator, Some(window)) => {
+                write!(f, "{} / {} over {} ms", numerator, denominator, window)
+            }
+        }
+    }
+}
+
+/// Threshold the watched quantity is breaching past.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Bound {
+    Above(f64),
+    Below(f64),
+}
+
+impl Bound {
+    fn breached(&self, value: f64) -> bool {
+        match *self {
+            Bound::Above(threshold) => value > threshold,
+            Bound::Below(threshold) => value < threshold,
+        }
+    }
+
+    fn threshold(&self) -> f64 {
+        match *self {
+            Bound::Above(threshold) | Bound::Below(threshold) => threshold,
+        }
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bound::Above(threshold) => write!(f, "above {}", threshold),
+            Bound::Below(threshold) => write!(f, "below {}", threshold),
+        }
+    }
+}
+
+/// Alert rule, firing after the given number of consecutive breaching evaluations and resolving
+/// after the given number of consecutive clear ones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    pub bound: Bound,
+    pub breaches: u32,
+    pub clears: u32,
+}
+
+impl FromConfig for Rule {
+    fn from_config(config: &Config) -> Result<Rule, Error> {
+        let name = try!(config.string("name"));
+        let window = match config.find("window") {
+            Some(..) => {
+                match try!(config.u64_or("window", 0)) {
+                    0 => return Err(Error::Invalid(format!("alert '{}' window must be positive", name))),
+                    window => Some(window),
+                }
+            }
+            None => None,
+        };
+
+        let condition = match &try!(config.string_or("condition", "value"))[..] {
+            "value" => Condition::Value(try!(config.string("metric"))),
+            "rate" => {
+                match window {
+                    Some(window) => Condition::Rate(try!(config.string("metric")), window),
+                    None => return Err(Error::Invalid(format!("alert '{}' rate requires a window", name))),
+                }
+            }
+            "ratio" => Condition::Ratio(try!(config.string("numerator")), try!(config.string("denominator")), window),
+            other => return Err(Error::Invalid(format!("alert '{}' condition '{}' is unknown", name, other))),
+        };
+
+        let bound = match (config.find("above"), config.find("below")) {
+            (Some(..), None) => Bound::Above(try!(config.f64_or("above", 0.0))),
+            (None, Some(..)) => Bound::Below(try!(config.f64_or("below", 0.0))),
+            _ => return Err(Error::Invalid(format!("alert '{}' requires either 'above' or 'below'", name))),
+        };
+
+        let breaches = try!(config.u64_or("for", 1));
+        let clears = try!(config.u64_or("clear", 1));
+        if breaches == 0 || clears == 0 {
+            return Err(Error::Invalid(format!("alert '{}' 'for' and 'clear' must be positive", name)));
+        }
+
+        Ok(Rule {
+            name: name,
+            condition: condition,
+            bound: bound,
+            breaches: breaches as u32,
+            clears: clears as u32,
+        })
+    }
+}
+
+/// Alert rules and the interval in milliseconds they are evaluated at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub interval: u64,
+    pub rules: Vec<Rule>,
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let interval = try!(config.u64_or("interval", 10000));
+        if interval == 0 {
+            return Err(Error::Invalid("alert interval must be positive".to_string()));
+        }
+
+        let mut rules = Vec::new();
+        let mut names = HashSet::new();
+        for config in try!(config.list("rules")).iter() {
+            let rule = try!(Rule::from_config(config));
+            if !names.insert(rule.name.clone()) {
+                return Err(Error::Invalid(format!("alert '{}' is defined twice", rule.name)));
+            }
+            rules.push(rule);
+        }
+
+        Ok(Settings {
+            interval: interval,
+            rules: rules,
+        })
+    }
+}
+
+/// Metric values taken at the given time in milliseconds. Metrics not registered yet are zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub at: u64,
+    pub values: HashMap<String, usize>,
+}
+
+impl Sample {
+    /// Takes the current values of the process-wide registry.
+    pub fn take(at: u64) -> Sample {
+        Sample {
+            at: at,
+            values: metrics::registry().snapshot().into_iter().collect(),
+        }
+    }
+
+    fn get(&self, metric: &str) -> f64 {
+        self.values.get(metric).map(|&value| value as f64).unwrap_or(0.0)
+    }
+}
+
+/// Returns the quantity the condition watches given the current sample and the previous ones,
+/// oldest first, or None if it can't be told yet, i.e. no previous sample falls within the
+/// window, or the ratio denominator is zero.
+///
+/// Counters reset by a restart are taken as not increased.
+pub fn measure(condition: &Condition, history: &VecDeque<Sample>, current: &Sample) -> Option<f64> {
+    let base = |window: u64| {
+        history.iter().find(|sample| sample.at < current.at && current.at - sample.at <= window)
+    };
+    let increase = |metric: &str, base: &Sample| {
+        let (now, then) = (current.get(metric), base.get(metric));
+        if now > then { now - then } else { 0.0 }
+    };
+
+    match *condition {
+        Condition::Value(ref metric) => Some(current.get(metric)),
+        Condition::Rate(ref metric, window) => {
+            base(window).map(|base| increase(metric, base) * 1000.0 / (current.at - base.at) as f64)
+        }
+        Condition::Ratio(ref numerator, ref denominator, window) => {
+            let (numerator, denominator) = match window {
+                Some(window) => {
+                    match base(window) {
+                        Some(base) => (increase(numerator, base), increase(denominator, base)),
+                        None => return None,
+                    }
+                }
+                None => (current.get(numerator), current.get(denominator)),
+            };
+
+            if denominator == 0.0 {
+                None
+            } else {
+                Some(numerator / denominator)
+            }
+        }
+    }
+}
+
+/// Alert state of a rule.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
+    /// The rule has never breached, or has cleared before firing.
+    Inactive,
+    /// The rule is breaching, though not for long enough to fire.
+    Pending,
+    /// The rule has breached for the configured number of evaluations.
+    Firing,
+    /// The rule has cleared for the configured number of evaluations after firing.
+    Resolved,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            State::Inactive => "inactive",
+            State::Pending => "pending",
+            State::Firing => "firing",
+            State::Resolved => "resolved",
+        }
+    }
+}
+
+/// Rule firing or resolving, with the quantity measured at the time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub rule: Rule,
+    pub state: State,
+    pub value: f64,
+    pub at: u64,
+}
+
+impl Transition {
+    /// Returns the record announcing the transition.
+    pub fn record(&self) -> Record {
+        let mut record = Record::new();
+        record.insert("type".to_string(), RecordItem::String(TYPE.to_string()));
+        record.insert("timestamp".to_string(), RecordItem::F64(self.at as f64 / 1000.0));
+        record.insert("alert".to_string(), RecordItem::String(self.rule.name.clone()));
+        record.insert("state".to_string(), RecordItem::String(self.state.name().to_string()));
+        record.insert("condition".to_string(), RecordItem::String(self.rule.condition.to_string()));
+        record.insert("threshold".to_string(), RecordItem::F64(self.rule.bound.threshold()));
+        record.insert("value".to_string(), RecordItem::F64(self.value));
+        record.insert("message".to_string(), RecordItem::String(self.to_string()));
+        record
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "alert '{}' is {}: {} is {}, breaching {}", self.rule.name, self.state.name(),
+               self.rule.condition, self.value, self.rule.bound)
+    }
+}
+
+struct Alert {
+    state: State,
+    breaches: u32,
+    clears: u32,
+}
+
+/// Evaluates alert rules over metric samples, tracking consecutive breaches and clears.
+///
+/// The evaluator doesn't read metrics or time on its own, so that it's driven by samples alone.
+/// Evaluations the quantity can't be told for leave the rule state as it is.
+pub struct Evaluator {
+    rules: Vec<Rule>,
+    alerts: Vec<Alert>,
+    /// Previous samples within the widest rule window, oldest first.
+    history: VecDeque<Sample>,
+    window: u64,
+}
+
+impl Evaluator {
+    pub fn new(rules: Vec<Rule>) -> Evaluator {
+        let window = rules.iter().filter_map(|rule| rule.condition.window()).max().unwrap_or(0);
+        let alerts = rules.iter().map(|_| Alert { state: State::Inactive, breaches: 0, clears: 0 }).collect();
+
+        Evaluator {
+            rules: rules,
+            alerts: alerts,
+            history: VecDeque::new(),
+            window: window,
+        }
+    }
+
+    /// Evaluates all rules against the sample, returning rules that fired or resolved.
+    pub fn evaluate(&mut self, sample: Sample) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+
+        for (rule, alert) in self.rules.iter().zip(self.alerts.iter_mut()) {
+            let value = match measure(&rule.condition, &self.history, &sample) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let state = if rule.bound.breached(value) {
+                alert.clears = 0;
+                alert.breaches += 1;
+                match alert.state {
+                    State::Firing => State::Firing,
+                    _ if alert.breaches >= rule.breaches => State::Firing,
+                    _ => State::Pending,
+                }
+            } else {
+                alert.breaches = 0;
+                alert.clears += 1;
+                match alert.state {
+                    State::Firing if alert.clears >= rule.clears => State::Resolved,
+                    State::Firing => State::Firing,
+                    State::Pending => State::Inactive,
+                    state => state,
+                }
+            };
+
+            if state != alert.state && (state == State::Firing || state == State::Resolved) {
+                transitions.push(Transition {
+                    rule: rule.clone(),
+                    state: state,
+                    value: value,
+                    at: sample.at,
+                });
+            }
+            alert.state = state;
+        }
+
+        while self.history.front().map_or(false, |oldest| sample.at.saturating_sub(oldest.at) > self.window) {
+            self.history.pop_front();
+        }
+        if self.window > 0 {
+            self.history.push_back(sample);
+        }
+
+        transitions
+    }
+
+    /// Returns states of all rules by rule name.
+    pub fn states(&self) -> Vec<(String, State)> {
+        self.rules.iter().zip(self.alerts.iter()).map(|(rule, alert)| (rule.name.clone(), alert.state)).collect()
+    }
+}
+
+/// Evaluator over the process-wide metrics registry, sampled at the time its clock tells.
+pub struct Alerting {
+    evaluator: Evaluator,
+    clock: Arc<Clock>,
+}
+
+impl Alerting {
+    pub fn new(rules: Vec<Rule>) -> Alerting {
+        Alerting::with_clock(rules, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(rules: Vec<Rule>, clock: Arc<Clock>) -> Alerting {
+        Alerting {
+            evaluator: Evaluator::new(rules),
+            clock: clock,
+        }
+    }
+
+    /// Evaluates the rules against current metrics, logging transitions and returning records
+    /// announcing them.
+    pub fn tick(&mut self) -> Vec<Record> {
+        let transitions = self.evaluator.evaluate(Sample::take(self.clock.now()));
+
+        transitions.iter().map(|transition| {
+            match transition.state {
+                State::Firing => error!(target: "Alert", "ALERT {}", transition),
+                _ => warn!(target: "Alert", "{}", transition),
+            }
+            transition.record()
+        }).collect()
+    }
+
+    pub fn states(&self) -> Vec<(String, State)> {
+        self.evaluator.states()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    use super::super::RecordItem;
+    use super::super::clock::MockClock;
+    use super::super::metrics;
+    use super::{Alerting, Bound, Condition, Evaluator, Rule, Sample, State, measure};
+
+    fn sample(at: u64, values: Vec<(&str, usize)>) -> Sample {
+        Sample {
+            at: at,
+            values: values.into_iter().map(|(name, value)| (name.to_string(), value)).collect(),
+        }
+    }
+
+    fn rule(condition: Condition, bound: Bound, breaches: u32, clears: u32) -> Rule {
+        Rule {
+            name: "test".to_string(),
+            condition: condition,
+            bound: bound,
+            breaches: breaches,
+            clears: clears,
+        }
+    }
+
+    #[test]
+    fn measure_value() {
+        let condition = Condition::Value("errors".to_string());
+        let history = VecDeque::new();
+
+        assert_eq!(Some(42.0), measure(&condition, &history, &sample(0, vec![("errors", 42)])));
+        assert_eq!(Some(0.0), measure(&condition, &history, &sample(0, vec![])));
+    }
+
+    #[test]
+    fn measure_rate_over_window() {
+        let condition = Condition::Rate("errors".to_string(), 60000);
+        let mut history = VecDeque::new();
+
+        assert_eq!(None, measure(&condition, &history, &sample(10000, vec![("errors", 10)])));
+
+        history.push_back(sample(0, vec![("errors", 10)]));
+        history.push_back(sample(30000, vec![("errors", 70)]));
+        // The oldest sample within the window is the base.
+        assert_eq!(Some(3.0), measure(&condition, &history, &sample(60000, vec![("errors", 190)])));
+        assert_eq!(Some(4.0), measure(&condition, &history, &sample(90000, vec![("errors", 310)])));
+        // Reset counters are not increased.
+        assert_eq!(Some(0.0), measure(&condition, &history, &sample(60000, vec![("errors", 5)])));
+    }
+
+    #[test]
+    fn measure_ratio() {
+        let condition = Condition::Ratio("dropped".to_string(), "received".to_string(), None);
+        let history = VecDeque::new();
+        assert_eq!(Some(0.25), measure(&condition, &history, &sample(0, vec![("dropped", 5), ("received", 20)])));
+
+        let condition = Condition::Ratio("dropped".to_string(), "received".to_string(), Some(60000));
+        let mut history = VecDeque::new();
+        history.push_back(sample(0, vec![("dropped", 5), ("received", 20)]));
+        assert_eq!(Some(0.1), measure(&condition, &history, &sample(10000, vec![("dropped", 15), ("received", 120)])));
+    }
+
+    #[test]
+    fn skip_ratio_with_zero_denominator() {
+        let condition = Condition::Ratio("dropped".to_string(), "received".to_string(), None);
+        assert_eq!(None, measure(&condition, &VecDeque::new(), &sample(0, vec![("dropped", 5)])));
+
+        let mut evaluator = Evaluator::new(vec![rule(condition, Bound::Above(0.05), 1, 1)]);
+        assert!(!evaluator.evaluate(sample(0, vec![("dropped", 5), ("received", 10)])).is_empty());
+        // Nothing to tell about, the alert keeps firing.
+        assert!(evaluator.evaluate(sample(1000, vec![("dropped", 5), ("received", 0)])).is_empty());
+        assert_eq!(vec![("test".to_string(), State::Firing)], evaluator.states());
+    }
+
+    #[test]
+    fn fire_and_resolve_after_consecutive_evaluations() {
+        let condition = Condition::Value("errors".to_string());
+        let mut evaluator = Evaluator::new(vec![rule(condition, Bound::Above(10.0), 3, 2)]);
+        let mut evaluate = |at: u64, errors: usize| {
+            let transitions = evaluator.evaluate(sample(at, vec![("errors", errors)]));
+            (transitions.into_iter().map(|transition| transition.state).collect::<Vec<State>>(),
+             evaluator.states()[0].1)
+        };
+
+        assert_eq!((vec![], State::Inactive), evaluate(0, 5));
+        assert_eq!((vec![], State::Pending), evaluate(1000, 20));
+        assert_eq!((vec![], State::Pending), evaluate(2000, 20));
+        // A clear evaluation breaks the streak.
+        assert_eq!((vec![], State::Inactive), evaluate(3000, 5));
+        assert_eq!((vec![], State::Pending), evaluate(4000, 20));
+        assert_eq!((vec![], State::Pending), evaluate(5000, 20));
+        assert_eq!((vec![State::Firing], State::Firing), evaluate(6000, 20));
+        assert_eq!((vec![], State::Firing), evaluate(7000, 20));
+
+        assert_eq!((vec![], State::Firing), evaluate(8000, 5));
+        assert_eq!((vec![], State::Firing), evaluate(9000, 20));
+        assert_eq!((vec![], State::Firing), evaluate(10000, 5));
+        assert_eq!((vec![State::Resolved], State::Resolved), evaluate(11000, 5));
+
+        assert_eq!((vec![], State::Pending), evaluate(12000, 20));
+    }
+
+    #[test]
+    fn fire_on_rate_below_bound() {
+        let condition = Condition::Rate("received".to_string(), 2000);
+        let mut evaluator = Evaluator::new(vec![rule(condition, Bound::Below(1.0), 1, 1)]);
+
+        assert!(evaluator.evaluate(sample(0, vec![("received", 0)])).is_empty());
+        assert!(evaluator.evaluate(sample(1000, vec![("received", 10)])).is_empty());
+        assert!(evaluator.evaluate(sample(2000, vec![("received", 10)])).is_empty());
+        // The first sample falls out of the window.
+        let transitions = evaluator.evaluate(sample(3000, vec![("received", 10)]));
+        assert_eq!(1, transitions.len());
+        assert_eq!((State::Firing, 0.0), (transitions[0].state, transitions[0].value));
+    }
+
+    #[test]
+    fn announce_transition_in_record() {
+        let condition = Condition::Value("alert.test.record.errors".to_string());
+        let clock = MockClock::new(5000);
+        let mut alerting = Alerting::with_clock(vec![rule(condition, Bound::Above(1.0), 1, 1)], Arc::new(clock.clone()));
+
+        assert!(alerting.tick().is_empty());
+        metrics::registry().counter("alert.test.record.errors").add(3);
+        let records = alerting.tick();
+        assert_eq!(1, records.len());
+
+        let field = |name: &str| records[0].find(name).cloned();
+        assert_eq!(Some(RecordItem::String("logdrop_alert".to_string())), field("type"));
+        assert_eq!(Some(RecordItem::String("test".to_string())), field("alert"));
+        assert_eq!(Some(RecordItem::String("firing".to_string())), field("state"));
+        assert_eq!(Some(RecordItem::String("alert.test.record.errors".to_string())), field("condition"));
+        assert_eq!(Some(RecordItem::F64(1.0)), field("threshold"));
+        assert_eq!(Some(RecordItem::F64(3.0)), field("value"));
+        assert_eq!(Some(RecordItem::F64(5.0)), field("timestamp"));
+        assert!(records[0].find("message").and_then(|message| message.as_str()).is_some());
+    }
+}
@@ -0,0 +1,471 @@
+use std::fmt;
+use std::io;
+use std::iter::repeat;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error, FromConfig};
+use super::digest;
+use super::spool::{Reader, Writer};
+
+/// Value of the record `type` field marking audit records.
+pub const TYPE: &'static str = "logdrop_audit";
+
+/// Where the audit trail is kept and whether its entries are also emitted into the pipeline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub path: PathBuf,
+    pub records: bool,
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        Ok(Settings {
+            path: PathBuf::from(try!(config.string("path"))),
+            records: try!(config.bool_or("records", true)),
+        })
+    }
+}
+
+/// Audited change of the running process, chained to the previous one by hash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Position in the trail, counting from 1.
+    pub sequence: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: f64,
+    /// What was done, e.g. `reload` or `breaker`.
+    pub kind: String,
+    /// Who did it, e.g. the control connection peer, or `internal` for automatic changes.
+    pub source: String,
+    /// Description of the change, ordered by name.
+    pub details: Vec<(String, String)>,
+    /// Hash of the previous entry, zeros for the first one.
+    pub previous: String,
+    pub hash: String,
+}
+
+impl Entry {
+    /// Hashes every field except the hash itself, each one prefixed by its length so that no
+    /// content can be shifted from one field to another.
+    fn digest(&self) -> String {
+        let mut fields = vec![self.sequence.to_string(), self.timestamp.to_string(), self.kind.clone(),
+                              self.source.clone(), self.details.len().to_string()];
+        for &(ref name, ref value) in self.details.iter() {
+            fields.push(name.clone());
+            fields.push(value.clone());
+        }
+        fields.push(self.previous.clone());
+
+        let mut data = Vec::new();
+        for field in fields.iter() {
+            let len = field.len() as u64;
+            for shift in (0..8).rev() {
+                data.push((len >> (shift * 8)) as u8);
+            }
+            data.extend(field.as_bytes().iter().cloned());
+        }
+
+        digest::hex(&digest::sha256(&data))
+    }
+
+    /// Returns the record the entry is stored and emitted as.
+    pub fn record(&self) -> Record {
+        let details = self.details.iter().map(|&(ref name, ref value)| {
+            (name.clone(), RecordItem::String(value.clone()))
+        }).collect();
+
+        let mut record = Record::new();
+        record.insert("type".to_string(), RecordItem::String(TYPE.to_string()));
+        record.insert("sequence".to_string(), RecordItem::U64(self.sequence));
+        record.insert("timestamp".to_string(), RecordItem::F64(self.timestamp));
+        record.insert("kind".to_string(), RecordItem::String(self.kind.clone()));
+        record.insert("source".to_string(), RecordItem::String(self.source.clone()));
+        record.insert("details".to_string(), RecordItem::Object(details));
+        record.insert("previous".to_string(), RecordItem::String(self.previous.clone()));
+        record.insert("hash".to_string(), RecordItem::String(self.hash.clone()));
+        record
+    }
+
+    /// Restores the entry from its record, without checking its hash.
+    pub fn from_record(record: &Record) -> Result<Entry, String> {
+        let string = |name: &str| {
+            match record.find(name).and_then(|value| value.as_str()) {
+                Some(value) => Ok(value.to_string()),
+                None => Err(format!("missing '{}'", name)),
+            }
+        };
+
+        let sequence = match record.find("sequence") {
+            Some(&RecordItem::U64(sequence)) => sequence,
+            Some(&RecordItem::I64(sequence)) if sequence >= 0 => sequence as u64,
+            _ => return Err("missing 'sequence'".to_string()),
+        };
+        let timestamp = match record.find("timestamp").and_then(|value| value.as_f64()) {
+            Some(timestamp) => timestamp,
+            None => return Err("missing 'timestamp'".to_string()),
+        };
+        let mut details = Vec::new();
+        match record.find("details") {
+            Some(&RecordItem::Object(ref map)) => {
+                for (name, value) in super::sorted(map) {
+                    match value.as_str() {
+                        Some(value) => details.push((name.clone(), value.to_string())),
+                        None => return Err(format!("detail '{}' is not a string", name)),
+                    }
+                }
+            }
+            _ => return Err("missing 'details'".to_string()),
+        }
+
+        Ok(Entry {
+            sequence: sequence,
+            timestamp: timestamp,
+            kind: try!(string("kind")),
+            source: try!(string("source")),
+            details: details,
+            previous: try!(string("previous")),
+            hash: try!(string("hash")),
+        })
+    }
+}
+
+/// Hash the first entry of a trail is chained to.
+fn genesis() -> String {
+    repeat('0').take(64).collect()
+}
+
+/// Intact trail, as found by `verify`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chain {
+    pub entries: u64,
+    /// Hash of the last entry, which the operational log also reports, so that dropping entries
+    /// off the end can be told too.
+    pub last: String,
+}
+
+/// Entry breaking the trail, counting from 1, with the reason.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Broken {
+    pub position: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for Broken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "entry {} is broken: {}", self.position, self.reason)
+    }
+}
+
+/// Checks the trail entry by entry: each one must be intact, directly follow the previous one and
+/// be chained to its hash. A missing file is an empty trail.
+pub fn verify(path: &Path) -> Result<Chain, Broken> {
+    let mut chain = Chain {
+        entries: 0,
+        last: genesis(),
+    };
+    if !path.exists() {
+        return Ok(chain);
+    }
+
+    let broken = |position: u64, reason: String| Broken { position: position, reason: reason };
+    let mut rd = try!(Reader::open(path).map_err(|err| broken(0, format!("unable to open: {}", err))));
+    loop {
+        let position = chain.entries + 1;
+        let record = match rd.next() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(err) => return Err(broken(position, format!("unreadable: {}", err))),
+        };
+
+        let entry = try!(Entry::from_record(&record).map_err(|reason| broken(position, reason)));
+        if entry.sequence != position {
+            return Err(broken(position, format!("sequence is {}, expected {}", entry.sequence, position)));
+        }
+        if entry.previous != chain.last {
+            return Err(broken(position, "not chained to the previous entry".to_string()));
+        }
+        if entry.digest() != entry.hash {
+            return Err(broken(position, "hash mismatch, the entry is altered".to_string()));
+        }
+
+        chain.entries = position;
+        chain.last = entry.hash;
+    }
+
+    Ok(chain)
+}
+
+/// Appends entries to the audit trail, synchronously as they are low-volume and must survive a
+/// crash right after the change they describe.
+pub struct Auditor {
+    path: PathBuf,
+    writer: Writer,
+    sequence: u64,
+    previous: String,
+    clock: Arc<Clock>,
+}
+
+impl Auditor {
+    /// Opens the trail, continuing the chain of the existing entries, which must be intact.
+    pub fn open(path: &Path) -> Result<Auditor, String> {
+        let chain = try!(verify(path).map_err(|err| format!("audit trail {:?} is broken: {}", path, err)));
+        let writer = try!(Writer::open(path).map_err(|err| format!("unable to open audit trail {:?}: {}", path, err)));
+
+        Ok(Auditor {
+            path: path.to_path_buf(),
+            writer: writer,
+            sequence: chain.entries,
+            previous: chain.last,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    pub fn clock(mut self, clock: Arc<Clock>) -> Auditor {
+        self.clock = clock;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the entry describing the change, returning it once on the disk.
+    pub fn append(&mut self, kind: &str, source: &str, details: Vec<(&str, String)>) -> io::Result<Entry> {
+        let mut details: Vec<(String, String)> = details.into_iter().map(|(name, value)| {
+            (name.to_string(), value)
+        }).collect();
+        details.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut entry = Entry {
+            sequence: self.sequence + 1,
+            timestamp: self.clock.now() as f64 / 1000.0,
+            kind: kind.to_string(),
+            source: source.to_string(),
+            details: details,
+            previous: self.previous.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+
+        try!(self.writer.append(&entry.record()));
+        try!(self.writer.sync());
+
+        self.sequence = entry.sequence;
+        self.previous = entry.hash.clone();
+        Ok(entry)
+    }
+}
+
+/// Installed auditor with pipelines subscribed to its entries.
+struct Audit {
+    auditor: Option<Auditor>,
+    subscribers: Vec<Sender<Record>>,
+}
+
+fn slot() -> &'static Mutex<Audit> {
+    static mut SLOT: *const Mutex<Audit> = 0 as *const Mutex<Audit>;
+    static INIT: Once = ONCE_INIT;
+
+    unsafe {
+        INIT.call_once(|| {
+            let audit = Audit {
+                auditor: None,
+                subscribers: Vec::new(),
+            };
+            SLOT = mem::transmute(Box::new(Mutex::new(audit)));
+        });
+
+        &*SLOT
+    }
+}
+
+/// Makes the auditor record changes of the process, returning the one installed before.
+pub fn install(auditor: Auditor) -> Option<Auditor> {
+    info!(target: "Audit", "auditing changes to {:?}", auditor.path());
+    mem::replace(&mut slot().lock().unwrap().auditor, Some(auditor))
+}
+
+pub fn uninstall() -> Option<Auditor> {
+    slot().lock().unwrap().auditor.take()
+}
+
+/// Returns the receiver of entry records appended from now on, e.g. for a pipeline to collect.
+pub fn subscribe() -> Receiver<Record> {
+    let (tx, rx) = channel();
+    slot().lock().unwrap().subscribers.push(tx);
+    rx
+}
+
+/// Records the change with its description, if the auditor is installed.
+///
+/// Failing to write the trail is logged rather than failing the change itself.
+pub fn record(kind: &str, source: &str, details: Vec<(&str, String)>) {
+    let mut audit = slot().lock().unwrap();
+    let entry = match audit.auditor {
+        Some(ref mut auditor) => auditor.append(kind, source, details),
+        None => return,
+    };
+
+    match entry {
+        Ok(entry) => {
+            info!(target: "Audit", "#{} {} by {}, hash {}", entry.sequence, entry.kind, entry.source, entry.hash);
+            let record = entry.record();
+            audit.subscribers.retain(|tx| tx.send(record.clone()).is_ok());
+        }
+        Err(err) => error!(target: "Audit", "unable to record {} by {}: {}", kind, source, err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::super::RecordItem;
+    use super::super::clock::MockClock;
+    use super::super::codec::MessagePack;
+    use super::super::control::Control;
+    use super::super::pipeline::Pipeline;
+    use super::super::reload::Reloader;
+    use super::super::spool::{Reader, Writer};
+    use super::super::testing::{Collector, Feeder, record, string};
+    use super::super::validation::{Missing, Validation};
+    use super::{Auditor, Broken, Entry, TYPE, install, uninstall, verify};
+
+    fn entries(path: &Path) -> Vec<Entry> {
+        let mut rd = Reader::open(path).unwrap();
+        let mut entries = Vec::new();
+        while let Some(record) = rd.next().unwrap() {
+            entries.push(Entry::from_record(&record).unwrap());
+        }
+        entries
+    }
+
+    fn rewrite(path: &Path, entries: &[Entry]) {
+        let mut wr = Writer::create(path).unwrap();
+        for entry in entries.iter() {
+            wr.append(&entry.record()).unwrap();
+        }
+    }
+
+    #[test]
+    fn chain_entries_and_pinpoint_broken_one() {
+        let path = env::temp_dir().join("logdrop-audit-chain.log");
+        let _ = fs::remove_file(&path);
+
+        let clock = MockClock::new(1000);
+        let mut auditor = Auditor::open(&path).unwrap().clock(Arc::new(clock.clone()));
+        auditor.append("control", "127.0.0.1:5000", vec![("command", "flush".to_string()), ("outcome", "ok".to_string())]).unwrap();
+        clock.advance(1500);
+        auditor.append("log_level", "stdin", vec![("level", "debug".to_string())]).unwrap();
+        drop(auditor);
+
+        // Reopening continues the chain.
+        let mut auditor = Auditor::open(&path).unwrap();
+        let last = auditor.append("breaker", "internal", vec![("state", "open".to_string())]).unwrap();
+        assert_eq!(3, last.sequence);
+        drop(auditor);
+
+        let chain = verify(&path).unwrap();
+        assert_eq!(3, chain.entries);
+        assert_eq!(last.hash, chain.last);
+
+        let intact = entries(&path);
+        assert_eq!(2.5, intact[1].timestamp);
+        assert_eq!(intact[0].hash, intact[1].previous);
+
+        let mut altered = intact.clone();
+        altered[1].details[0].1 = "trace".to_string();
+        rewrite(&path, &altered);
+        let expected = Broken { position: 2, reason: "hash mismatch, the entry is altered".to_string() };
+        assert_eq!(Err(expected), verify(&path));
+        assert!(Auditor::open(&path).is_err());
+
+        rewrite(&path, &intact[1..]);
+        let expected = Broken { position: 1, reason: "sequence is 2, expected 1".to_string() };
+        assert_eq!(Err(expected), verify(&path));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn emit_audited_changes_into_pipeline() {
+        let path = env::temp_dir().join("logdrop-audit-pipeline.log");
+        let config = env::temp_dir().join("logdrop-audit-pipeline.json");
+        let _ = fs::remove_file(&path);
+        File::create(&config).unwrap().write_all(b"{").unwrap();
+        install(Auditor::open(&path).unwrap());
+
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("audited", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_audit_records();
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        for _ in 0..200 {
+            if !collector.records().is_empty() {
+                break;
+            }
+            thread::sleep_ms(10);
+        }
+
+        let control = Control::new(handle.clone());
+        control.execute("set-level debug", "audit-test");
+        control.execute("restart now", "audit-test");
+        Reloader::new(&config, "{}".to_string(), handle.clone()).reload();
+
+        drop(tx);
+        handle.close();
+        pipeline.join().unwrap();
+        uninstall();
+
+        assert!(verify(&path).is_ok());
+        let entries = entries(&path);
+        let audited: Vec<Entry> = collector.records().iter().filter(|record| {
+            record.find("type").and_then(|value| value.as_str()) == Some(TYPE)
+        }).map(|record| Entry::from_record(record).unwrap()).collect();
+
+        for entry in audited.iter() {
+            assert_eq!(Some(entry), entries.iter().find(|other| other.sequence == entry.sequence));
+        }
+
+        let ours = |entries: &[Entry]| -> Vec<(String, String, Vec<(String, String)>)> {
+            entries.iter().filter(|entry| {
+                entry.source == "audit-test" || entry.details.iter().any(|&(_, ref value)| *value == config.display().to_string())
+            }).map(|entry| (entry.kind.clone(), entry.source.clone(), entry.details.clone())).collect()
+        };
+        let strings = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+        };
+        let expected = vec![
+            ("log_level".to_string(), "audit-test".to_string(), strings(&[("level", "debug")])),
+            ("control".to_string(), "audit-test".to_string(), strings(&[("command", "set-level debug"), ("outcome", "ok")])),
+            ("control".to_string(), "audit-test".to_string(),
+             strings(&[("command", "restart now"), ("outcome", "error: unknown command 'restart'")])),
+        ];
+        assert_eq!(expected, ours(&entries)[..3].to_vec());
+        assert_eq!(ours(&entries), ours(&audited));
+
+        let reload = &ours(&entries)[3];
+        assert_eq!(("reload", "reload"), (&reload.0[..], &reload.1[..]));
+        assert!(reload.2[0].1.starts_with("rejected: "));
+        assert_eq!(Some(&RecordItem::String("first".to_string())), collector.records()[0].find("id"));
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&config);
+    }
+}
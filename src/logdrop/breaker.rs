@@ -0,0 +1,331 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::audit;
+use super::clock::Clock;
+use super::config::{Config, Error, FromConfig};
+use super::metrics;
+
+/// State of the circuit breaker.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
+    /// Calls pass through, their outcomes being tracked.
+    Closed,
+    /// Calls are refused until the cool-down passes.
+    Open,
+    /// Calls pass through as a probe, the first outcome closing or re-opening the breaker.
+    HalfOpen,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+
+    fn from_usize(value: usize) -> State {
+        match value {
+            1 => State::Open,
+            2 => State::HalfOpen,
+            _ => State::Closed,
+        }
+    }
+}
+
+/// Thresholds opening the breaker and cool-downs in milliseconds keeping it open.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Number of consecutive failures opening the breaker.
+    pub failures: u32,
+    /// Bound of the 95th percentile of call latencies opening the breaker, if any.
+    pub latency: Option<u64>,
+    /// Number of the last calls the latency percentile is taken over.
+    pub window: usize,
+    /// Cool-down after opening, doubled on every failed probe.
+    pub cooldown: u64,
+    /// Cap of the growing cool-down.
+    pub max_cooldown: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            failures: 5,
+            latency: None,
+            window: 20,
+            cooldown: 60000,
+            max_cooldown: 600000,
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let default = Settings::default();
+        let latency = match config.find("latency") {
+            Some(..) => Some(try!(config.u64_or("latency", 0))),
+            None => None,
+        };
+
+        Ok(Settings {
+            failures: try!(config.u64_or("failures", default.failures as u64)) as u32,
+            latency: latency,
+            window: try!(config.u64_or("window", default.window as u64)) as usize,
+            cooldown: try!(config.u64_or("cooldown", default.cooldown)),
+            max_cooldown: try!(config.u64_or("max_cooldown", default.max_cooldown)),
+        })
+    }
+}
+
+/// View of the breaker state for other threads, e.g. for status reporting.
+#[derive(Clone)]
+pub struct Status(Arc<AtomicUsize>);
+
+impl Status {
+    pub fn get(&self) -> State {
+        State::from_usize(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// Circuit breaker sparing a failing destination, and its caller, from calls bound to fail.
+///
+/// The breaker opens after the configured number of consecutive failures, or once the 95th
+/// percentile of the last call latencies exceeds the bound, for destinations accepting
+/// connections while timing every request out. While open it refuses calls until the cool-down
+/// passes, then half-opens to let a probe through: a success closes it, while a failure opens it
+/// again for twice the previous cool-down, up to the cap.
+///
+/// Transitions are logged and counted in the `<name>.breaker.<state>` metrics.
+pub struct Breaker {
+    name: String,
+    settings: Settings,
+    clock: Arc<Clock>,
+    state: State,
+    failures: u32,
+    latencies: VecDeque<u64>,
+    opened: u64,
+    cooldown: u64,
+    status: Status,
+}
+
+impl Breaker {
+    pub fn new(name: &str, settings: Settings, clock: Arc<Clock>) -> Breaker {
+        let cooldown = settings.cooldown;
+
+        Breaker {
+            name: name.to_string(),
+            settings: settings,
+            clock: clock,
+            state: State::Closed,
+            failures: 0,
+            latencies: VecDeque::new(),
+            opened: 0,
+            cooldown: cooldown,
+            status: Status(Arc::new(AtomicUsize::new(State::Closed as usize))),
+        }
+    }
+
+    /// Makes the breaker follow the given clock, e.g. the logical one in the deterministic mode.
+    pub fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.clone()
+    }
+
+    /// Returns the current cool-down in milliseconds.
+    pub fn cooldown(&self) -> u64 {
+        self.cooldown
+    }
+
+    /// Returns the current time of the breaker clock, for measuring call latencies.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Returns true if the call may be made, half-opening the breaker once the cool-down passes.
+    pub fn allow(&mut self) -> bool {
+        match self.state {
+            State::Closed | State::HalfOpen => true,
+            State::Open => {
+                if self.clock.now().saturating_sub(self.opened) < self.cooldown {
+                    return false;
+                }
+                self.transition(State::HalfOpen);
+                true
+            }
+        }
+    }
+
+    pub fn success(&mut self, latency: u64) {
+        match self.state {
+            State::Closed => {
+                self.failures = 0;
+                if self.sample(latency) {
+                    self.open();
+                }
+            }
+            State::HalfOpen => {
+                self.cooldown = self.settings.cooldown;
+                self.transition(State::Closed);
+            }
+            State::Open => {}
+        }
+    }
+
+    pub fn failure(&mut self, latency: u64) {
+        match self.state {
+            State::Closed => {
+                self.failures += 1;
+                let slow = self.sample(latency);
+                if slow || self.failures >= self.settings.failures {
+                    self.open();
+                }
+            }
+            State::HalfOpen => {
+                self.cooldown = cmp::min(self.cooldown * 2, self.settings.max_cooldown);
+                self.open();
+            }
+            State::Open => {}
+        }
+    }
+
+    /// Remembers the latency, returning true if the percentile is over the bound.
+    fn sample(&mut self, latency: u64) -> bool {
+        let bound = match self.settings.latency {
+            Some(bound) if self.settings.window > 0 => bound,
+            Some(..) | None => return false,
+        };
+
+        if self.latencies.len() == self.settings.window {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+        if self.latencies.len() < self.settings.window {
+            return false;
+        }
+
+        let mut latencies: Vec<u64> = self.latencies.iter().cloned().collect();
+        latencies.sort();
+        let rank = (latencies.len() * 95 + 99) / 100;
+        latencies[rank - 1] > bound
+    }
+
+    fn open(&mut self) {
+        self.opened = self.clock.now();
+        self.failures = 0;
+        self.latencies.clear();
+        self.transition(State::Open);
+    }
+
+    fn transition(&mut self, state: State) {
+        match state {
+            State::Open => warn!(target: "Breaker", "'{}' breaker is open for {} ms", self.name, self.cooldown),
+            State::HalfOpen => info!(target: "Breaker", "'{}' breaker is half-open, probing", self.name),
+            State::Closed => info!(target: "Breaker", "'{}' breaker is closed", self.name),
+        }
+
+        self.state = state;
+        self.status.0.store(state as usize, Ordering::SeqCst);
+        metrics::registry().counter(&format!("{}.breaker.{}", self.name, state.name())).inc();
+        audit::record("breaker", "internal", vec![("breaker", self.name.clone()), ("state", state.name().to_string())]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::clock::MockClock;
+    use super::{Breaker, Settings, State};
+
+    fn settings() -> Settings {
+        Settings {
+            failures: 3,
+            latency: None,
+            window: 4,
+            cooldown: 1000,
+            max_cooldown: 3000,
+        }
+    }
+
+    #[test]
+    fn open_after_consecutive_failures_and_close_on_probe() {
+        let clock = MockClock::new(0);
+        let mut breaker = Breaker::new("test.transitions", settings(), Arc::new(clock.clone()));
+
+        breaker.failure(0);
+        breaker.failure(0);
+        breaker.success(0);
+        breaker.failure(0);
+        breaker.failure(0);
+        assert_eq!(State::Closed, breaker.state());
+
+        breaker.failure(0);
+        assert_eq!(State::Open, breaker.state());
+        assert!(!breaker.allow());
+
+        clock.advance(999);
+        assert!(!breaker.allow());
+        clock.advance(1);
+        assert!(breaker.allow());
+        assert_eq!(State::HalfOpen, breaker.state());
+        assert_eq!(State::HalfOpen, breaker.status().get());
+
+        breaker.success(0);
+        assert_eq!(State::Closed, breaker.state());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn grow_cooldown_on_failed_probes_up_to_cap() {
+        let clock = MockClock::new(0);
+        let mut breaker = Breaker::new("test.cooldown", settings(), Arc::new(clock.clone()));
+
+        for _ in 0..3 {
+            breaker.failure(0);
+        }
+
+        let mut cooldowns = Vec::new();
+        for _ in 0..3 {
+            clock.advance(breaker.cooldown());
+            assert!(breaker.allow());
+            breaker.failure(0);
+            assert_eq!(State::Open, breaker.state());
+            cooldowns.push(breaker.cooldown());
+        }
+        assert_eq!(vec![2000, 3000, 3000], cooldowns);
+
+        clock.advance(2999);
+        assert!(!breaker.allow());
+        clock.advance(1);
+        assert!(breaker.allow());
+        breaker.success(0);
+        assert_eq!(1000, breaker.cooldown());
+    }
+
+    #[test]
+    fn open_on_slow_calls() {
+        let clock = MockClock::new(0);
+        let settings = Settings { latency: Some(500), ..settings() };
+        let mut breaker = Breaker::new("test.latency", settings, Arc::new(clock.clone()));
+
+        for latency in [100, 30000, 100].iter() {
+            breaker.success(*latency);
+        }
+        assert_eq!(State::Closed, breaker.state());
+
+        breaker.success(100);
+        assert_eq!(State::Open, breaker.state());
+    }
+}
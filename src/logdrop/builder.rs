@@ -0,0 +1,550 @@
+use std::marker::PhantomData;
+
+use super::alert;
+use super::breaker;
+use super::capture::Capture;
+use super::codec::{Codec, Projection};
+use super::config::Error;
+use super::determinism;
+use super::encoding::Encoding;
+use super::filter::Filter;
+use super::input::Input;
+use super::lineage;
+use super::numeric::NonFinite;
+use super::output::{Delivery, OnError, Output};
+use super::oversize;
+use super::poison;
+use super::pipeline::{Pipeline, Unavailable};
+use super::queue;
+use super::quota::Quota;
+use super::retention::Retention;
+use super::sched;
+use super::snapshot;
+use super::source::Resolution;
+use super::validation::Validation;
+
+/// Stage of adding inputs together with their decoding settings.
+pub enum Inputs {}
+/// Stage of adding filters, enrichment ones included, in the order records pass them.
+pub enum Filters {}
+/// Stage of deciding where records go apart from outputs: the dead-letter output, oversize
+/// records, transforms captures and records no output is able to take.
+pub enum Routing {}
+/// Stage of adding outputs, the last one before `build`.
+pub enum Outputs {}
+
+/// Marks stages the builder may move on from to the given one, skipping the ones in between.
+pub trait Precedes<S> {}
+
+impl Precedes<Filters> for Inputs {}
+impl Precedes<Routing> for Inputs {}
+impl Precedes<Routing> for Filters {}
+impl Precedes<Outputs> for Inputs {}
+impl Precedes<Outputs> for Filters {}
+impl Precedes<Outputs> for Routing {}
+
+/// Builds the pipeline stage by stage, the stage being the part of its type, so that wiring
+/// stages out of order, e.g. adding a filter once outputs are added, doesn't compile:
+///
+/// ```ignore
+/// let pipeline = PipelineBuilder::new(Validation::default())
+///     .input("tcp", input, codec, None)
+///     .outputs()
+///     .output("null", Box::new(Null))
+///     .filters()      // no method named `filters` found for `PipelineBuilder<Outputs>`
+///     .build();
+/// ```
+///
+/// The crate being a binary one, the example is kept as `misordered`, which the test compiles
+/// apart to see it refused.
+///
+/// Settings of the whole pipeline are accepted at any stage. Components are checked against each
+/// other by `build`, which reports all problems found instead of stopping at the first one.
+pub struct PipelineBuilder<S> {
+    pipeline: Pipeline,
+    /// Filter names, which are their types for ones built out of the config.
+    filters: Vec<String>,
+    /// Output identifiers together with the files they write to, if any.
+    outputs: Vec<(String, Option<String>)>,
+    /// Components writing to files apart from outputs, e.g. the dead-letter one.
+    targets: Vec<(String, String)>,
+    /// Identifiers of outputs reordering records, see `Output::ordered`.
+    unordered: Vec<String>,
+    /// Whether records of a connection are kept in order within each output.
+    connection_order: bool,
+    /// Sections refused in the deterministic mode, see `determinism::SECTIONS`, which are set.
+    sections: Vec<&'static str>,
+    strict: bool,
+    deterministic: bool,
+    stage: PhantomData<S>,
+}
+
+impl PipelineBuilder<Inputs> {
+    /// Starts building the pipeline judging records by the given default validation rules.
+    pub fn new(validation: Validation) -> PipelineBuilder<Inputs> {
+        PipelineBuilder {
+            pipeline: Pipeline::new(validation),
+            filters: Vec::new(),
+            outputs: Vec::new(),
+            targets: Vec::new(),
+            unordered: Vec::new(),
+            connection_order: true,
+            sections: Vec::new(),
+            strict: false,
+            deterministic: false,
+            stage: PhantomData,
+        }
+    }
+
+    /// Adds the named input, see `Pipeline::add_input`.
+    pub fn input(mut self, name: &str, input: Box<Input>, codec: Box<Codec>, validation: Option<Validation>) -> Self {
+        self.pipeline.add_input(name, input, codec, validation);
+        self
+    }
+
+    pub fn capture(mut self, input: &str, capture: Capture) -> Self {
+        self.pipeline.set_capture(input, capture);
+        self
+    }
+
+    pub fn projection(mut self, input: &str, projection: Projection) -> Self {
+        self.pipeline.set_projection(input, projection);
+        self
+    }
+
+    pub fn encoding(mut self, input: &str, encoding: Encoding) -> Self {
+        self.pipeline.set_encoding(input, encoding);
+        self
+    }
+
+    pub fn non_finite(mut self, input: &str, non_finite: NonFinite) -> Self {
+        self.pipeline.set_non_finite(input, non_finite);
+        self
+    }
+
+    pub fn decode_errors(mut self, input: &str, limit: usize) -> Self {
+        self.pipeline.set_decode_errors(input, limit);
+        self
+    }
+
+    /// Decodes only fields read past the validation stage, see `Pipeline::set_strict_projection`.
+    /// The build fails if any component may read any field.
+    pub fn strict_projection(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self.pipeline.set_strict_projection(strict);
+        self
+    }
+}
+
+impl PipelineBuilder<Filters> {
+    /// Adds the filter named by its type, which is what the deterministic mode checks.
+    pub fn filter(mut self, name: &str, filter: Box<Filter>) -> Self {
+        self.filters.push(name.to_string());
+        self.pipeline.add_filter(filter);
+        self
+    }
+
+    /// Adds the filter recording fields it touches in the lineage, see `Pipeline::add_traced_filter`.
+    pub fn traced_filter(mut self, name: &str, filter: Box<Filter>) -> Self {
+        self.filters.push(name.to_string());
+        self.pipeline.add_traced_filter(filter, name);
+        self
+    }
+
+    pub fn lineage(mut self, settings: lineage::Settings) -> Self {
+        self.pipeline.set_lineage(settings);
+        self
+    }
+}
+
+impl PipelineBuilder<Routing> {
+    pub fn dead_letter(mut self, output: Box<Output>) -> Self {
+        if let Some(target) = output.target() {
+            self.targets.push((target, "dead-letter output".to_string()));
+        }
+        self.pipeline.set_dead_letter(output);
+        self
+    }
+
+    pub fn oversize(mut self, settings: oversize::Settings) -> Self {
+        self.pipeline.set_oversize(settings);
+        self
+    }
+
+    pub fn transforms(mut self, attach: bool, output: Option<Box<Output>>) -> Self {
+        if let Some(target) = output.as_ref().and_then(|output| output.target()) {
+            self.targets.push((target, "transforms output".to_string()));
+        }
+        self.pipeline.set_transforms(attach, output);
+        self
+    }
+
+    pub fn unavailable(mut self, policy: Unavailable) -> Self {
+        self.pipeline.set_unavailable(policy);
+        self
+    }
+}
+
+impl PipelineBuilder<Outputs> {
+    /// Adds the output delivering records at least once, identified by the given string unique
+    /// among outputs.
+    pub fn output(self, id: &str, output: Box<Output>) -> Self {
+        self.output_with(id, output, Delivery::AtLeastOnce, None)
+    }
+
+    /// Adds the output with the delivery mode and, if given, the policy for records it fails.
+    pub fn output_with(mut self, id: &str, output: Box<Output>, delivery: Delivery, on_error: Option<OnError>) -> Self {
+        self.outputs.push((id.to_string(), output.target()));
+        if !output.ordered() {
+            self.unordered.push(id.to_string());
+        }
+        match on_error {
+            Some(on_error) => self.pipeline.add_guarded_output(output, delivery, on_error),
+            None => self.pipeline.add_output_with_delivery(output, delivery),
+        }
+        self
+    }
+
+    /// Makes only outputs at the given positions, in the order they are added, confirm records
+    /// to inputs, see `Pipeline::set_acknowledging`.
+    pub fn acknowledging(mut self, outputs: Vec<usize>) -> Self {
+        self.pipeline.set_acknowledging(outputs);
+        self
+    }
+
+    /// Checks components against each other, returning the pipeline or all problems found:
+    /// outputs sharing the identifier, components writing to the same file, components refused
+    /// in the deterministic mode, outputs reordering records while the order is kept and the
+    /// strict projection with a component reading any field.
+    pub fn build(self) -> Result<Pipeline, Vec<Error>> {
+        let mut errors = Vec::new();
+
+        // Reported once, on the second use.
+        for (position, &(ref id, _)) in self.outputs.iter().enumerate() {
+            if self.outputs[..position].iter().filter(|&&(ref other, _)| other == id).count() == 1 {
+                errors.push(Error::Invalid(format!("output id '{}' is used more than once", id)));
+            }
+        }
+
+        let mut targets = Vec::new();
+        for &(ref id, ref target) in self.outputs.iter() {
+            if let Some(ref target) = *target {
+                targets.push((target.clone(), format!("'{}' output", id)));
+            }
+        }
+        targets.extend(self.targets.iter().cloned());
+        for (position, &(ref target, ref component)) in targets.iter().enumerate() {
+            if let Some(&(_, ref other)) = targets[..position].iter().find(|&&(ref other, _)| other == target) {
+                errors.push(Error::Invalid(format!("'{}' is written by both {} and {}", target, other, component)));
+            }
+        }
+
+        if self.deterministic {
+            for name in self.filters.iter() {
+                if let Some(&(_, reason)) = determinism::FILTERS.iter().find(|&&(filter, _)| filter == *name) {
+                    errors.push(Error::Invalid(format!("'{}' filter is unavailable in the deterministic mode: {}", name, reason)));
+                }
+            }
+            for &(key, reason) in determinism::SECTIONS.iter() {
+                if self.sections.contains(&key) {
+                    errors.push(Error::Invalid(format!("'{}' section is unavailable in the deterministic mode: {}", key, reason)));
+                }
+            }
+        }
+
+        for id in self.unordered.iter() {
+            if self.deterministic {
+                errors.push(Error::Invalid(format!("'{}' output reorders records, which is unavailable in the deterministic mode", id)));
+            } else if self.connection_order {
+                errors.push(Error::Invalid(format!("'{}' output reorders records of a connection, which requires 'connection_order' to be off", id)));
+            }
+        }
+
+        if self.strict {
+            if let Err(component) = self.pipeline.describe_fields() {
+                errors.push(Error::Invalid(format!("strict projection is impossible, as {} may read any field", component)));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(self.pipeline)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl<S: Precedes<Filters>> PipelineBuilder<S> {
+    pub fn filters(self) -> PipelineBuilder<Filters> {
+        self.stage()
+    }
+}
+
+impl<S: Precedes<Routing>> PipelineBuilder<S> {
+    pub fn routing(self) -> PipelineBuilder<Routing> {
+        self.stage()
+    }
+}
+
+impl<S: Precedes<Outputs>> PipelineBuilder<S> {
+    pub fn outputs(self) -> PipelineBuilder<Outputs> {
+        self.stage()
+    }
+}
+
+/// Settings of the whole pipeline, see the `Pipeline` setters of the same names.
+impl<S> PipelineBuilder<S> {
+    fn stage<T>(self) -> PipelineBuilder<T> {
+        PipelineBuilder {
+            pipeline: self.pipeline,
+            filters: self.filters,
+            outputs: self.outputs,
+            targets: self.targets,
+            unordered: self.unordered,
+            connection_order: self.connection_order,
+            sections: self.sections,
+            strict: self.strict,
+            deterministic: self.deterministic,
+            stage: PhantomData,
+        }
+    }
+
+    pub fn flush_interval(mut self, interval: u32) -> Self {
+        self.pipeline.set_flush_interval(interval);
+        self
+    }
+
+    pub fn elastic(mut self, settings: queue::Settings) -> Self {
+        self.pipeline.set_elastic(settings);
+        self
+    }
+
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.pipeline.set_retention(retention);
+        self
+    }
+
+    pub fn source(mut self, resolution: Resolution) -> Self {
+        self.pipeline.set_source(resolution);
+        self
+    }
+
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.sections.push("quota");
+        self.pipeline.set_quota(quota);
+        self
+    }
+
+    pub fn slow_consumer(mut self, high: usize, interval: u64) -> Self {
+        self.pipeline.set_slow_consumer(high, interval);
+        self
+    }
+
+    pub fn breaker(mut self, settings: breaker::Settings) -> Self {
+        self.pipeline.set_breaker(settings);
+        self
+    }
+
+    pub fn snapshot(mut self, settings: snapshot::Settings) -> Self {
+        self.sections.push("snapshot");
+        self.pipeline.set_snapshot(settings);
+        self
+    }
+
+    pub fn alerts(mut self, settings: alert::Settings) -> Self {
+        self.sections.push("alerts");
+        self.pipeline.set_alerts(settings);
+        self
+    }
+
+    pub fn audit_records(mut self) -> Self {
+        self.pipeline.set_audit_records();
+        self
+    }
+
+    pub fn poison(mut self, settings: poison::Settings) -> Self {
+        self.pipeline.set_poison(settings);
+        self
+    }
+
+    pub fn scheduling(mut self, settings: sched::Settings) -> Self {
+        self.pipeline.set_scheduling(settings);
+        self
+    }
+
+    /// Keeps records of a connection in order within each output, refusing outputs reordering
+    /// them, e.g. ones sending batches concurrently, see `Output::ordered`. On by default.
+    pub fn connection_order(mut self, ordered: bool) -> Self {
+        self.connection_order = ordered;
+        self
+    }
+
+    pub fn deterministic(mut self, settings: determinism::Settings) -> Self {
+        self.deterministic = true;
+        self.pipeline.set_deterministic(settings);
+        self
+    }
+
+    pub fn startup_record(mut self, enabled: bool) -> Self {
+        self.pipeline.set_startup_record(enabled);
+        self
+    }
+
+    pub fn warm_up(mut self, duration: u64) -> Self {
+        self.pipeline.set_warm_up(duration);
+        self
+    }
+
+    pub fn thread_prefix(mut self, prefix: &str) -> Self {
+        self.pipeline.set_thread_prefix(prefix);
+        self
+    }
+}
+
+/// Wires stages out of order, which must not compile, see `refuse_stages_out_of_order`.
+#[cfg(misordered_builder)]
+#[allow(dead_code)]
+fn misordered() -> Result<Pipeline, Vec<Error>> {
+    PipelineBuilder::new(Validation::default())
+        .outputs()
+        .output("null", Box::new(super::output::Null))
+        .filters()
+        .build()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::Arc;
+
+    use super::super::clock::SystemClock;
+    use super::super::codec::MessagePack;
+    use super::super::config;
+    use super::super::config::Error;
+    use super::super::determinism;
+    use super::super::filter::{Coalesce, Governor};
+    use super::super::input::TcpInput;
+    use super::super::output::{Archive, ElasticsearchOutput, FileOutput, Null};
+    use super::super::testing::Collector;
+    use super::super::validation::Validation;
+    use super::PipelineBuilder;
+
+    fn file(path: &str) -> Box<FileOutput> {
+        Box::new(FileOutput::new(path, "{message}").unwrap())
+    }
+
+    fn archive(path: &str) -> Box<Archive> {
+        Box::new(Archive::open(Path::new(path)).unwrap())
+    }
+
+    #[test]
+    fn build_same_pipeline_as_config() {
+        let content = r#"{
+            "projection": "strict",
+            "inputs": [{"type": "tcp", "name": "app", "host": "127.0.0.1", "port": 0, "codec": "msgpack"}],
+            "filters": [{"type": "coalesce", "fields": ["request_id"]}],
+            "dead_letter": {"type": "null"},
+            "outputs": [{"type": "file", "path": "/tmp/logdrop-builder-{host}.log", "format": "{message}"}, {"type": "null"}]
+        }"#;
+        let configured = config::parse(content).unwrap();
+
+        let built = PipelineBuilder::new(Validation::default())
+            .strict_projection(true)
+            .input("app", Box::new(TcpInput::new("127.0.0.1".to_string(), 0)), Box::new(MessagePack::default()), None)
+            .filters()
+            .filter("coalesce", Box::new(Coalesce::new(vec!["request_id".to_string()])))
+            .routing()
+            .dead_letter(Box::new(Null))
+            .outputs()
+            .output("0", file("/tmp/logdrop-builder-{host}.log"))
+            .output("1", Box::new(Null))
+            .build()
+            .unwrap();
+
+        assert_eq!(configured.summary(), built.summary());
+        assert_eq!(configured.describe_fields(), built.describe_fields());
+    }
+
+    #[test]
+    fn refuse_stages_out_of_order() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let output = Command::new("rustc")
+            .arg(format!("{}/src/main.rs", root))
+            .args(&["--cfg", "misordered_builder", "-Z", "no-trans"])
+            .arg("-L").arg(format!("{}/target/debug/deps", root))
+            .output()
+            .unwrap();
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.contains("no method named `filters` found"), "{}", stderr);
+    }
+
+    #[test]
+    fn share_files_appended_by_lines() {
+        let pipeline = PipelineBuilder::new(Validation::default())
+            .outputs()
+            .output("first", file("/tmp/logdrop-builder-shared.log"))
+            .output("second", file("/tmp/logdrop-builder-shared.log"))
+            .build();
+        assert!(pipeline.is_ok());
+    }
+
+    #[test]
+    fn skip_optional_stages() {
+        let pipeline = PipelineBuilder::new(Validation::default()).outputs().output("null", Box::new(Null)).build();
+        assert!(pipeline.is_ok());
+    }
+
+    #[test]
+    fn report_all_problems_at_once() {
+        let result = PipelineBuilder::new(Validation::default())
+            .strict_projection(true)
+            .deterministic(determinism::Settings { seed: 7 })
+            .filters()
+            .filter("governor", Box::new(Governor::new(1000, 1000, Arc::new(SystemClock))))
+            .routing()
+            .dead_letter(archive("/tmp/logdrop-builder.spool"))
+            .outputs()
+            .output("main", archive("/tmp/logdrop-builder.spool"))
+            .output("main", Box::new(Collector::new()))
+            .output("main", Box::new(Null))
+            .build();
+
+        let errors: Vec<String> = match result {
+            Err(errors) => errors.iter().map(|err: &Error| err.to_string()).collect(),
+            Ok(..) => panic!("expected errors"),
+        };
+        assert_eq!(4, errors.len(), "{:?}", errors);
+        assert!(errors[0].contains("output id 'main' is used more than once"));
+        assert!(errors[1].contains("'/tmp/logdrop-builder.spool' is written by both 'main' output and dead-letter output"));
+        assert!(errors[2].contains("'governor' filter is unavailable in the deterministic mode"));
+        assert!(errors[3].contains("strict projection is impossible"));
+    }
+
+    #[test]
+    fn refuse_reordering_output_unless_order_is_waived() {
+        let parallel = || Box::new(ElasticsearchOutput::new("localhost", 9200).parallelism(4, 400));
+
+        let result = PipelineBuilder::new(Validation::default()).outputs().output("es", parallel()).build();
+        match result {
+            Err(errors) => assert!(errors[0].to_string().contains("'es' output reorders records of a connection")),
+            Ok(..) => panic!("expected errors"),
+        }
+
+        let result = PipelineBuilder::new(Validation::default())
+            .connection_order(false)
+            .deterministic(determinism::Settings { seed: 7 })
+            .outputs()
+            .output("es", parallel())
+            .build();
+        match result {
+            Err(errors) => assert!(errors[0].to_string().contains("unavailable in the deterministic mode")),
+            Ok(..) => panic!("expected errors"),
+        }
+
+        let pipeline = PipelineBuilder::new(Validation::default()).connection_order(false).outputs().output("es", parallel()).build();
+        assert!(pipeline.is_ok());
+    }
+}
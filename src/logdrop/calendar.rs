@@ -0,0 +1,414 @@
+//! Windows aligned to the wall clock rather than to the start of the component using them.
+//!
+//! Summaries of instances started at different times cover the same windows, so that they can be
+//! summed downstream. The window the component has started within covers only part of it, being
+//! marked `partial` like summaries of the warm-up phase are.
+
+use std::mem;
+use std::sync::Arc;
+
+use super::{Record, RecordItem};
+use super::clock::Clock;
+use super::config::{Config, Error, FromConfig};
+
+const MINUTE: i64 = 60 * 1000;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+/// Daylight-saving rule of a zone, shifting the clock an hour ahead for the summer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dst {
+    /// From the last Sunday of March to the last Sunday of October, switching at 01:00 UTC.
+    Eu,
+    /// From the second Sunday of March to the first Sunday of November, switching at 02:00 of
+    /// the local time.
+    Us,
+}
+
+impl Dst {
+    /// Returns the period of the year the rule is in effect within, in milliseconds since the
+    /// Unix epoch, for the zone of the given standard offset.
+    fn period(&self, year: i64, offset: i64) -> (i64, i64) {
+        match *self {
+            Dst::Eu => (sunday(year, 3, 0) * DAY + HOUR, sunday(year, 10, 0) * DAY + HOUR),
+            Dst::Us => (sunday(year, 3, 2) * DAY + 2 * HOUR - offset, sunday(year, 11, 1) * DAY + HOUR - offset),
+        }
+    }
+}
+
+/// Time zone given by its standard offset from UTC and the daylight-saving rule, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    /// Standard offset in milliseconds east of UTC.
+    offset: i64,
+    dst: Option<Dst>,
+}
+
+impl Zone {
+    pub fn utc() -> Zone {
+        Zone::new(0, None)
+    }
+
+    /// Creates the zone of the standard offset given in minutes east of UTC.
+    pub fn new(offset: i64, dst: Option<Dst>) -> Zone {
+        Zone {
+            offset: offset * MINUTE,
+            dst: dst,
+        }
+    }
+
+    /// Parses the zone given as `UTC` or as the standard offset, e.g. `+05:30`, optionally
+    /// followed by the daylight-saving rule, `eu` or `us`, e.g. `+01:00 eu`.
+    pub fn parse(spec: &str) -> Result<Zone, String> {
+        let mut parts = spec.split_whitespace();
+        let offset = match parts.next() {
+            Some("UTC") | Some("utc") => 0,
+            Some(offset) => try!(minutes(offset).ok_or_else(|| format!("invalid zone offset '{}'", offset))),
+            None => return Err("zone is empty".to_string()),
+        };
+        let dst = match parts.next() {
+            Some("eu") => Some(Dst::Eu),
+            Some("us") => Some(Dst::Us),
+            Some(rule) => return Err(format!("unknown daylight-saving rule '{}'", rule)),
+            None => None,
+        };
+
+        match parts.next() {
+            Some(..) => Err(format!("invalid zone '{}'", spec)),
+            None => Ok(Zone::new(offset, dst)),
+        }
+    }
+
+    /// Returns the offset from UTC in milliseconds in effect at the instant.
+    fn offset(&self, at: i64) -> i64 {
+        let dst = match self.dst {
+            Some(dst) => dst,
+            None => return self.offset,
+        };
+
+        let (start, end) = dst.period(year(div(at + self.offset, DAY)), self.offset);
+        if start <= at && at < end { self.offset + HOUR } else { self.offset }
+    }
+
+    /// Returns the instant the local time given in milliseconds since the Unix epoch happens at.
+    ///
+    /// Rules switch away from midnight, so local midnights are never skipped nor repeated.
+    fn instant(&self, local: i64) -> i64 {
+        local - self.offset(local - self.offset)
+    }
+}
+
+/// How a daylight-saving transition within a day window changes its length.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Shift {
+    /// The clock has moved forward, the day is 23 hours long.
+    Forward,
+    /// The clock has moved back, the day is 25 hours long.
+    Backward,
+}
+
+impl Shift {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Shift::Forward => "forward",
+            Shift::Backward => "backward",
+        }
+    }
+}
+
+/// Window of the calendar, its bounds in milliseconds since the Unix epoch.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Window {
+    pub start: u64,
+    pub end: u64,
+    /// Whether only part of the window is covered, as the component has started within it.
+    pub partial: bool,
+    /// Daylight-saving transition within the day window, if any.
+    pub shift: Option<Shift>,
+}
+
+impl Window {
+    /// Writes bounds of the window in seconds into `window_start` and `window_end`, together with
+    /// the `partial` marker and the `dst` transition, if any, for summaries of the window.
+    pub fn annotate(&self, record: &mut Record) {
+        record.insert("window_start".to_string(), RecordItem::F64(self.start as f64 / 1000.0));
+        record.insert("window_end".to_string(), RecordItem::F64(self.end as f64 / 1000.0));
+        record.insert("partial".to_string(), RecordItem::Bool(self.partial));
+        if let Some(shift) = self.shift {
+            record.insert("dst".to_string(), RecordItem::String(shift.name().to_string()));
+        }
+    }
+}
+
+/// Splits time into windows of the given length aligned to the local time of the zone: minute
+/// and hour windows start at full minutes and hours, day windows at midnights, all of them moved
+/// by the anchor offset, e.g. days starting at 06:00.
+///
+/// Day windows span 23 or 25 hours across daylight-saving transitions, telling so by their shift,
+/// while shorter ones keep their length.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Calendar {
+    length: u64,
+    anchor: u64,
+    zone: Zone,
+}
+
+impl Calendar {
+    /// Creates the calendar of windows of the length and anchor in milliseconds, the length
+    /// dividing a day evenly and the anchor being shorter than it.
+    pub fn new(length: u64, anchor: u64, zone: Zone) -> Calendar {
+        assert!(length > 0 && DAY as u64 % length == 0, "window length must divide a day evenly");
+        assert!(anchor < length, "window anchor must be shorter than its length");
+
+        Calendar {
+            length: length,
+            anchor: anchor,
+            zone: zone,
+        }
+    }
+
+    /// Returns the window the instant falls into.
+    pub fn window(&self, now: u64) -> Window {
+        let now = now as i64;
+        let (length, anchor) = (self.length as i64, self.anchor as i64);
+        let local = now + self.zone.offset(now) - anchor;
+
+        if length < DAY {
+            let start = now - modulo(local, length);
+            return Window { start: start as u64, end: (start + length) as u64, partial: false, shift: None };
+        }
+
+        let midnight = local - modulo(local, DAY) + anchor;
+        let start = self.zone.instant(midnight);
+        let end = self.zone.instant(midnight + DAY);
+        let shift = if end - start < DAY {
+            Some(Shift::Forward)
+        } else if end - start > DAY {
+            Some(Shift::Backward)
+        } else {
+            None
+        };
+
+        Window { start: start as u64, end: end as u64, partial: false, shift: shift }
+    }
+}
+
+/// Reads the `length` of windows, `minute`, `hour` or `day`, the `anchor` offset in milliseconds
+/// and the `timezone`, see `Zone::parse`.
+impl FromConfig for Calendar {
+    fn from_config(config: &Config) -> Result<Calendar, Error> {
+        let length = match &try!(config.string_or("length", "minute"))[..] {
+            "minute" => MINUTE,
+            "hour" => HOUR,
+            "day" => DAY,
+            length => return Err(Error::Invalid(format!("unknown window length '{}'", length))),
+        } as u64;
+
+        let anchor = try!(config.u64_or("anchor", 0));
+        if anchor >= length {
+            return Err(Error::Invalid("window 'anchor' must be shorter than its length".to_string()));
+        }
+
+        let zone = try!(Zone::parse(&try!(config.string_or("timezone", "UTC"))).map_err(Error::Invalid));
+        Ok(Calendar::new(length, anchor, zone))
+    }
+}
+
+/// Windows of the calendar passing by as the clock goes, telling when the current one closes.
+pub struct Windows {
+    calendar: Calendar,
+    clock: Arc<Clock>,
+    current: Window,
+}
+
+impl Windows {
+    /// Starts within the window of the current time, partial unless it has just begun.
+    pub fn new(calendar: Calendar, clock: Arc<Clock>) -> Windows {
+        let now = clock.now();
+        let mut current = calendar.window(now);
+        current.partial = now > current.start;
+
+        Windows {
+            calendar: calendar,
+            clock: clock,
+            current: current,
+        }
+    }
+
+    pub fn current(&self) -> &Window {
+        &self.current
+    }
+
+    /// Returns the current window once the clock has passed its end, moving on to the window of
+    /// the current time. Windows passed in between are skipped.
+    pub fn poll(&mut self) -> Option<Window> {
+        let now = self.clock.now();
+        if now < self.current.end {
+            return None;
+        }
+
+        let next = self.calendar.window(now);
+        Some(mem::replace(&mut self.current, next))
+    }
+}
+
+/// Returns the non-negative remainder of the division.
+fn modulo(value: i64, divisor: i64) -> i64 {
+    ((value % divisor) + divisor) % divisor
+}
+
+/// Returns the quotient of the division rounded towards negative infinity.
+fn div(value: i64, divisor: i64) -> i64 {
+    (value - modulo(value, divisor)) / divisor
+}
+
+/// Parses the offset given as `+HH:MM` or `-HH:MM` into minutes.
+fn minutes(offset: &str) -> Option<i64> {
+    let (sign, rest) = match offset.chars().next() {
+        Some('+') => (1, &offset[1..]),
+        Some('-') => (-1, &offset[1..]),
+        _ => return None,
+    };
+
+    let mut parts = rest.splitn(2, ':');
+    match (parts.next().and_then(|h| h.parse::<i64>().ok()), parts.next().and_then(|m| m.parse::<i64>().ok())) {
+        (Some(hours), Some(minutes)) if hours <= 14 && minutes < 60 => Some(sign * (hours * 60 + minutes)),
+        _ => None,
+    }
+}
+
+/// Returns the number of days since the Unix epoch of the civil date.
+fn days(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = div(year, 400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Returns the year of the day given as the number of days since the Unix epoch.
+fn year(days: i64) -> i64 {
+    let z = days + 719468;
+    let era = div(z, 146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    yoe + era * 400 + if mp >= 10 { 1 } else { 0 }
+}
+
+/// Returns the day of the n-th Sunday of the month, counting from one, or of the last one for
+/// zero, as the number of days since the Unix epoch.
+fn sunday(year: i64, month: i64, nth: i64) -> i64 {
+    // The epoch is a Thursday, so the weekday counted from Sunday is four days ahead.
+    let weekday = |day: i64| modulo(day + 4, 7);
+
+    if nth == 0 {
+        let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+        let last = days(year, month, 1) - 1;
+        last - weekday(last)
+    } else {
+        let first = days(year, month, 1);
+        first + modulo(7 - weekday(first), 7) + 7 * (nth - 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::RecordItem;
+    use super::super::clock::MockClock;
+    use super::super::testing::record;
+    use super::{Calendar, DAY, Dst, HOUR, MINUTE, Shift, Windows, Zone, days};
+
+    /// 2015-06-15 00:00:00 UTC, a Monday.
+    const MONDAY: u64 = 1434326400000;
+
+    #[test]
+    fn align_minute_and_hour_windows_started_mid_window() {
+        let clock = MockClock::new(MONDAY + 5 * HOUR as u64 + 17 * MINUTE as u64 + 42000);
+
+        let mut minutes = Windows::new(Calendar::new(MINUTE as u64, 0, Zone::utc()), Arc::new(clock.clone()));
+        let mut hours = Windows::new(Calendar::new(HOUR as u64, 0, Zone::parse("+05:30").unwrap()), Arc::new(clock.clone()));
+
+        let minute = *minutes.current();
+        assert_eq!(MONDAY + 5 * HOUR as u64 + 17 * MINUTE as u64, minute.start);
+        assert_eq!(minute.start + MINUTE as u64, minute.end);
+        assert!(minute.partial);
+
+        // Full hours of the zone half an hour off UTC.
+        let hour = *hours.current();
+        assert_eq!(MONDAY + 4 * HOUR as u64 + 30 * MINUTE as u64, hour.start);
+        assert_eq!(hour.start + HOUR as u64, hour.end);
+        assert!(hour.partial);
+
+        clock.set(minute.end - 1);
+        assert_eq!(None, minutes.poll());
+        clock.set(minute.end);
+        assert_eq!(Some(minute), minutes.poll());
+
+        let next = *minutes.current();
+        assert_eq!((minute.end, minute.end + MINUTE as u64, false), (next.start, next.end, next.partial));
+
+        clock.set(hour.end + 10 * MINUTE as u64);
+        assert_eq!(Some(hour), hours.poll());
+        assert!(!hours.current().partial);
+        assert_eq!(hour.end, hours.current().start);
+    }
+
+    #[test]
+    fn start_whole_window_at_its_boundary() {
+        let clock = MockClock::new(MONDAY + HOUR as u64);
+        let windows = Windows::new(Calendar::new(HOUR as u64, 0, Zone::utc()), Arc::new(clock));
+        assert!(!windows.current().partial);
+    }
+
+    #[test]
+    fn anchor_day_windows() {
+        let calendar = Calendar::new(DAY as u64, 6 * HOUR as u64, Zone::utc());
+        let window = calendar.window(MONDAY + 3 * HOUR as u64);
+        assert_eq!((MONDAY - 18 * HOUR as u64, MONDAY + 6 * HOUR as u64), (window.start, window.end));
+    }
+
+    #[test]
+    fn flag_daylight_saving_days() {
+        let at = |year: i64, month: i64, day: i64| (days(year, month, day) * DAY + 12 * HOUR) as u64;
+        let day = |zone: &Zone, instant: u64| Calendar::new(DAY as u64, 0, zone.clone()).window(instant);
+
+        // Central Europe moves forward on 2015-03-29 and back on 2015-10-25.
+        let cet = Zone::new(60, Some(Dst::Eu));
+        let spring = day(&cet, at(2015, 3, 29));
+        assert_eq!(23 * HOUR as u64, spring.end - spring.start);
+        assert_eq!(Some(Shift::Forward), spring.shift);
+        assert_eq!((days(2015, 3, 29) * DAY - HOUR) as u64, spring.start);
+
+        let autumn = day(&cet, at(2015, 10, 25));
+        assert_eq!(25 * HOUR as u64, autumn.end - autumn.start);
+        assert_eq!(Some(Shift::Backward), autumn.shift);
+        assert_eq!((days(2015, 10, 25) * DAY - 2 * HOUR) as u64, autumn.start);
+
+        let summer = day(&cet, at(2015, 6, 15));
+        assert_eq!((DAY as u64, None), (summer.end - summer.start, summer.shift));
+
+        // US Eastern moves forward on 2015-03-08 and back on 2015-11-01.
+        let eastern = Zone::parse("-05:00 us").unwrap();
+        assert_eq!(Some(Shift::Forward), day(&eastern, at(2015, 3, 8) + 6 * HOUR as u64).shift);
+        assert_eq!(Some(Shift::Backward), day(&eastern, at(2015, 11, 1) + 6 * HOUR as u64).shift);
+        assert_eq!(None, day(&eastern, at(2015, 11, 2) + 6 * HOUR as u64).shift);
+
+        let mut summary = record(vec![]);
+        spring.annotate(&mut summary);
+        assert_eq!(Some(&RecordItem::String("forward".to_string())), summary.find("dst"));
+    }
+
+    #[test]
+    fn parse_zones() {
+        assert_eq!(Ok(Zone::utc()), Zone::parse("UTC"));
+        assert_eq!(Ok(Zone::new(-210, None)), Zone::parse("-03:30"));
+        assert_eq!(Ok(Zone::new(60, Some(Dst::Eu))), Zone::parse("+01:00 eu"));
+        assert!(Zone::parse("+01:00 mars").is_err());
+        assert!(Zone::parse("Europe/Berlin").is_err());
+    }
+}
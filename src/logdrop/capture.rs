@@ -0,0 +1,297 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::output::IDEMPOTENCY_FIELD;
+
+/// Where the raw payload of a record goes.
+pub enum Target {
+    /// Attached to the record itself as bytes under the given key.
+    Field(String),
+    /// Written to the raw archive keyed by the idempotency id of the record, see `Capture::attach`.
+    Archive(Mutex<Archive>),
+}
+
+/// Raw payload capture settings of an input.
+///
+/// Payloads longer than the limit are truncated and the record is annotated with the original
+/// size in the `_raw_truncated` field.
+pub struct Capture {
+    target: Target,
+    limit: usize,
+    /// Field holding the idempotency id archived payloads are keyed by.
+    field: String,
+    input: String,
+    /// Time the capture started in milliseconds, keeping archive ids unique across restarts.
+    epoch: AtomicUsize,
+    sequence: AtomicUsize,
+}
+
+impl Capture {
+    pub fn new(input: &str, target: Target, limit: usize) -> Capture {
+        Capture {
+            target: target,
+            limit: limit,
+            field: IDEMPOTENCY_FIELD.to_string(),
+            input: input.to_string(),
+            epoch: AtomicUsize::new(SystemClock.now() as usize),
+            sequence: AtomicUsize::new(0),
+        }
+    }
+
+    /// Keys archived payloads by the idempotency id in the given field.
+    pub fn field(mut self, field: &str) -> Capture {
+        self.field = field.to_string();
+        self
+    }
+
+    /// Restarts archive ids from the given epoch, e.g. a fixed one in the deterministic mode.
+    pub fn set_epoch(&self, epoch: u64) {
+        self.epoch.store(epoch as usize, Ordering::SeqCst);
+        self.sequence.store(0, Ordering::SeqCst);
+    }
+
+    /// Attaches the raw payload that produced the record.
+    ///
+    /// Archived payloads are keyed by the idempotency id of the record, the one later used to
+    /// deduplicate it, see `Dedup`. Records lacking it are given a generated one, but only once the
+    /// payload is archived, so that no record refers to a payload missing from the archive.
+    pub fn attach(&self, record: &mut Record, mut raw: Vec<u8>) {
+        let size = raw.len();
+        if size > self.limit {
+            raw.truncate(self.limit);
+            record.insert("_raw_truncated".to_string(), RecordItem::F64(size as f64));
+        }
+
+        match self.target {
+            Target::Field(ref key) => {
+                record.insert(key.clone(), RecordItem::Bytes(raw));
+            }
+            Target::Archive(ref archive) => {
+                let (id, generated) = match record.find(&self.field) {
+                    Some(&RecordItem::F64(id)) => (id.to_string(), false),
+                    Some(&RecordItem::I64(id)) => (id.to_string(), false),
+                    Some(&RecordItem::U64(id)) => (id.to_string(), false),
+                    Some(id) if id.as_str().is_some() => (id.as_str().unwrap().to_string(), false),
+                    _ => {
+                        let epoch = self.epoch.load(Ordering::Relaxed);
+                        (format!("{}-{}-{}", self.input, epoch, self.sequence.fetch_add(1, Ordering::Relaxed)), true)
+                    }
+                };
+
+                match archive.lock().unwrap().write(&id, size, &raw) {
+                    Ok(()) if generated => {
+                        record.insert(self.field.clone(), RecordItem::String(id));
+                    }
+                    Ok(()) => {}
+                    Err(err) => error!(target: "Capture", "failed to archive raw payload of '{}' record: {}", id, err),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let target = match self.target {
+            Target::Field(ref key) => format!("field '{}'", key),
+            Target::Archive(..) => "archive".to_string(),
+        };
+        write!(f, "Capture {{ target: {}, limit: {} }}", target, self.limit)
+    }
+}
+
+/// Archived raw payload.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub id: String,
+    /// Size of the payload before truncation.
+    pub size: usize,
+    pub raw: Vec<u8>,
+}
+
+/// Append-only file of raw payloads keyed by the join id.
+///
+/// Each entry is the id, the original payload size and the captured bytes, where the id and the
+/// bytes are prefixed with their 4-byte big-endian length.
+pub struct Archive {
+    file: File,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> io::Result<Archive> {
+        let file = try!(OpenOptions::new().write(true).append(true).create(true).open(path));
+        Ok(Archive { file: file })
+    }
+
+    pub fn write(&mut self, id: &str, size: usize, raw: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(12 + id.len() + raw.len());
+        put_u32(&mut buf, id.len() as u32);
+        buf.extend(id.bytes());
+        put_u32(&mut buf, size as u32);
+        put_u32(&mut buf, raw.len() as u32);
+        buf.extend(raw.iter().cloned());
+
+        self.file.write_all(&buf)
+    }
+
+    /// Reads all entries of the archive at the given path.
+    pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+        let mut buf = Vec::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut buf)));
+
+        let mut entries = Vec::new();
+        let mut rd = &buf[..];
+        while !rd.is_empty() {
+            let len = try!(get_u32(&mut rd)) as usize;
+            let id = try!(get_bytes(&mut rd, len));
+            let id = try!(String::from_utf8(id).map_err(|_| invalid("non-UTF-8 id")));
+            let size = try!(get_u32(&mut rd)) as usize;
+            let len = try!(get_u32(&mut rd)) as usize;
+            let raw = try!(get_bytes(&mut rd, len));
+
+            entries.push(Entry { id: id, size: size, raw: raw });
+        }
+
+        Ok(entries)
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend([(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8].iter().cloned());
+}
+
+fn get_u32(rd: &mut &[u8]) -> io::Result<u32> {
+    let bytes = try!(get_bytes(rd, 4));
+    Ok((bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32)
+}
+
+fn get_bytes(rd: &mut &[u8], len: usize) -> io::Result<Vec<u8>> {
+    if rd.len() < len {
+        return Err(invalid("truncated archive entry"));
+    }
+
+    let bytes = rd[..len].to_vec();
+    *rd = &rd[len..];
+    Ok(bytes)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+
+    use super::super::{Origin, Record, RecordItem};
+    use super::super::codec::MessagePack;
+    use super::super::codec::msgpack;
+    use super::super::input::Sink;
+    use super::super::numeric::NonFinite;
+    use super::super::testing::{record, string};
+    use super::super::validation::Validation;
+    use super::{Archive, Capture, Target};
+
+    /// Sends the concatenated encoded records through the sink, returning what arrived.
+    fn consume(capture: Capture, records: &[Record]) -> Vec<Record> {
+        let mut data = Vec::new();
+        for record in records.iter() {
+            data.extend(msgpack::encode(record).into_iter());
+        }
+
+        let origin = Origin {
+            input: "tcp".to_string(),
+            validation: Arc::new(Validation::default()),
+            capture: Some(Arc::new(capture)),
+            projection: None,
+            encoding: None,
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+
+        let (tx, rx) = channel();
+        Sink::new(tx, Arc::new(origin)).consume(&MessagePack::default(), Box::new(Cursor::new(data))).unwrap();
+        rx.iter().map(|envelope| envelope.record).collect()
+    }
+
+    fn bytes(record: &Record, key: &str) -> Vec<u8> {
+        match record.find(key) {
+            Some(&RecordItem::Bytes(ref raw)) => raw.clone(),
+            other => panic!("unexpected raw payload: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn captured_bytes_decode_to_equal_record() {
+        let payload = vec![
+            record(vec![("message", string("first"))]),
+            record(vec![("message", string("second")), ("code", RecordItem::F64(42.0))]),
+        ];
+
+        let records = consume(Capture::new("tcp", Target::Field("raw".to_string()), 1024), &payload);
+        assert_eq!(2, records.len());
+
+        for (expected, mut record) in payload.into_iter().zip(records.into_iter()) {
+            let raw = bytes(&record, "raw");
+            record.remove("raw");
+            assert_eq!(expected, record);
+            assert_eq!(Some(expected), msgpack::decode(&raw));
+        }
+    }
+
+    #[test]
+    fn archive_keyed_by_idempotency_id() {
+        let path = env::temp_dir().join("logdrop-capture-archive.raw");
+        let _ = fs::remove_file(&path);
+
+        let payload = vec![
+            record(vec![("message", string("le message"))]),
+            record(vec![("message", string("le message")), ("idempotency_id", string("supplied"))]),
+        ];
+        let archive = Archive::open(&path).unwrap();
+        let records = consume(Capture::new("tcp", Target::Archive(Mutex::new(archive)), 1024), &payload);
+
+        let entries = Archive::read(&path).unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!(Some(&RecordItem::String(entries[0].id.clone())), records[0].find("idempotency_id"));
+        assert_eq!("supplied", entries[1].id);
+        assert_eq!(payload[1], records[1]);
+        for (entry, payload) in entries.iter().zip(payload.into_iter()) {
+            assert_eq!(Some(payload), msgpack::decode(&entry.raw));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn attach_no_id_unless_archived() {
+        let payload = vec![record(vec![("message", string("le message"))])];
+        let archive = Archive::open(Path::new("/dev/full")).unwrap();
+        let records = consume(Capture::new("tcp", Target::Archive(Mutex::new(archive)), 1024), &payload);
+
+        assert_eq!(payload, records);
+    }
+
+    #[test]
+    fn truncate_oversized_payload() {
+        let payload = vec![record(vec![("message", string("a rather long message"))])];
+        let size = msgpack::encode(&payload[0]).len();
+
+        let records = consume(Capture::new("tcp", Target::Field("raw".to_string()), 8), &payload);
+
+        assert_eq!(8, bytes(&records[0], "raw").len());
+        assert_eq!(Some(&RecordItem::F64(size as f64)), records[0].find("_raw_truncated"));
+    }
+}
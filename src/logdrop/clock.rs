@@ -0,0 +1,109 @@
+use std::sync::{Arc, Mutex};
+
+use chrono;
+use chrono::Timelike;
+
+use super::{Record, RecordItem};
+
+/// Source of the current time, in milliseconds since the Unix epoch.
+///
+/// Time-dependent components take a clock instead of asking the system directly, which allows
+/// tests to drive them with `MockClock`.
+pub trait Clock : Sync + Send {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        let now = chrono::UTC::now();
+        now.timestamp() as u64 * 1000 + (now.nanosecond() / 1000000) as u64
+    }
+}
+
+/// Manually driven clock. Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<u64>>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> MockClock {
+        MockClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, ms: u64) {
+        *self.now.lock().unwrap() += ms;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Clock driven by the `timestamp` field of records rather than the wall clock, in seconds since
+/// the Unix epoch. It starts at zero and never goes back, standing still between records and on
+/// records without the timestamp. Clones share the same time.
+#[derive(Clone)]
+pub struct LogicalClock {
+    now: Arc<Mutex<u64>>,
+}
+
+impl LogicalClock {
+    pub fn new() -> LogicalClock {
+        LogicalClock {
+            now: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Moves the clock forward to the timestamp of the record, if it is later.
+    pub fn observe(&self, record: &Record) {
+        let seconds = match record.find("timestamp") {
+            Some(&RecordItem::F64(value)) if value.is_finite() && value >= 0.0 => value,
+            Some(&RecordItem::I64(value)) if value >= 0 => value as f64,
+            Some(&RecordItem::U64(value)) => value as f64,
+            _ => return,
+        };
+
+        let mut now = self.now.lock().unwrap();
+        let at = (seconds * 1000.0) as u64;
+        if at > *now {
+            *now = at;
+        }
+    }
+}
+
+impl Clock for LogicalClock {
+    fn now(&self) -> u64 {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::RecordItem;
+    use super::super::testing::{record, string};
+    use super::{Clock, LogicalClock};
+
+    #[test]
+    fn follow_record_timestamps_forward() {
+        let clock = LogicalClock::new();
+        assert_eq!(0, clock.now());
+
+        clock.observe(&record(vec![("timestamp", RecordItem::F64(1.5))]));
+        assert_eq!(1500, clock.now());
+        clock.observe(&record(vec![("timestamp", RecordItem::I64(1))]));
+        clock.observe(&record(vec![("timestamp", string("2015-06-01T12:34:56Z"))]));
+        clock.observe(&record(vec![]));
+        assert_eq!(1500, clock.clone().now());
+    }
+}
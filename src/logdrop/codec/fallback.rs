@@ -0,0 +1,209 @@
+use std::io::{Cursor, ErrorKind, Read};
+use std::sync::Arc;
+
+use super::{Codec, DecodeError, Malformed, Projection};
+use super::super::Record;
+use super::super::config::{Config, Error};
+use super::super::registry;
+
+/// Number of bytes read at most before giving up on picking the codec.
+const PROBE: usize = 64 * 1024;
+
+/// Size of chunks read from the stream while picking the codec.
+const CHUNK: usize = 4096;
+
+/// Codec trying codecs in order on the first bytes of the stream, decoding the whole stream with
+/// the first one able to decode a record out of them, e.g. a msgpack port receiving occasional
+/// JSON health checks.
+///
+/// The stream is read until a codec succeeds, up to 64KiB, the primary codec decoding the stream
+/// if none does. A codec is passed over only once it definitely fails on the bytes read so far,
+/// while its record being cut short keeps the stream read, so that a record of an earlier codec
+/// split across reads is never taken for one of a later codec.
+pub struct Fallback {
+    codecs: Vec<Box<Codec>>,
+}
+
+impl Fallback {
+    /// Creates the codec trying the given ones in order, the first one being the primary one.
+    pub fn new(codecs: Vec<Box<Codec>>) -> Fallback {
+        assert!(!codecs.is_empty());
+
+        Fallback {
+            codecs: codecs,
+        }
+    }
+
+    /// Returns the index of the first codec decoding a record of the buffer, if any.
+    ///
+    /// Unless the stream is over, nothing is picked while some codec is yet to see its record
+    /// whole, as the codecs following it may decode a record of the beginning of its one.
+    fn pick(&self, buf: &[u8], end: bool) -> Option<usize> {
+        for (id, codec) in self.codecs.iter().enumerate() {
+            match codec.decode_one(buf) {
+                Ok(Some(..)) => return Some(id),
+                Ok(None) if !end => return None,
+                Err(..) if !end && codec.incomplete(buf) => return None,
+                Ok(None) | Err(..) => {}
+            }
+        }
+
+        None
+    }
+
+    /// Reads the stream until the codec is picked, returning the codec together with the stream
+    /// restored to its beginning.
+    fn probe(&self, mut rd: Box<Read>) -> (&Codec, Box<Read>) {
+        let mut buf = Vec::new();
+        let mut chunk = [0; CHUNK];
+        let mut id = None;
+        while id.is_none() {
+            let len = match rd.read(&mut chunk) {
+                Ok(len) => len,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(..) => 0,
+            };
+            buf.extend(chunk[..len].iter().cloned());
+
+            let end = len == 0 || buf.len() >= PROBE;
+            id = self.pick(&buf, end);
+            if end {
+                break;
+            }
+        }
+
+        let id = id.unwrap_or(0);
+        if id > 0 {
+            debug!(target: "Codec::Fallback", "decoding stream with fallback codec #{}", id);
+        }
+        (&*self.codecs[id], Box::new(Cursor::new(buf).chain(rd)))
+    }
+}
+
+/// Constructs the codec trying the codecs of the `codecs` list in order.
+pub fn factory(config: &Config) -> Result<Box<Codec>, Error> {
+    let mut codecs = Vec::new();
+    for entry in try!(config.list("codecs")).iter() {
+        codecs.push(try!(registry::codec(entry)));
+    }
+
+    if codecs.is_empty() {
+        return Err(Error::Invalid("fallback requires at least one codec".to_string()));
+    }
+
+    Ok(Box::new(Fallback::new(codecs)))
+}
+
+impl Codec for Fallback {
+    fn new(&self) -> Box<Codec> {
+        Box::new(Fallback::new(self.codecs.iter().map(|codec| codec.new()).collect()))
+    }
+
+    fn decode(&self, rd: Box<Read>) -> Box<Iterator<Item=Record>> {
+        let (codec, rd) = self.probe(rd);
+        codec.decode(rd)
+    }
+
+    fn decode_raw(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=(Record, Vec<u8>)>>, Box<Read>> {
+        let (codec, rd) = self.probe(rd);
+        codec.decode_raw(rd)
+    }
+
+    fn decode_checked(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=Result<Record, Malformed>>>, Box<Read>> {
+        let (codec, rd) = self.probe(rd);
+        codec.decode_checked(rd)
+    }
+
+    fn decode_projected(&self, rd: Box<Read>, projection: Arc<Projection>) -> Box<Iterator<Item=Record>> {
+        let (codec, rd) = self.probe(rd);
+        codec.decode_projected(rd, projection)
+    }
+
+    fn decode_one(&self, buf: &[u8]) -> Result<Option<Record>, DecodeError> {
+        let id = self.pick(buf, true).unwrap_or(0);
+        self.codecs[id].decode_one(buf)
+    }
+
+    /// The stream is transcoded before any codec is picked, so all of them must decode text.
+    fn text(&self) -> bool {
+        self.codecs.iter().all(|codec| codec.text())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::io::{Cursor, Read};
+
+    use super::super::{Codec, Json, Logfmt, MessagePack, msgpack};
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::testing::{record, string};
+    use super::Fallback;
+
+    fn fallback() -> Fallback {
+        let codecs: Vec<Box<Codec>> = vec![Box::new(MessagePack::default()), Box::new(Json::default()), Box::new(Logfmt::default())];
+        Fallback::new(codecs)
+    }
+
+    fn decode(codec: &Codec, data: &[u8]) -> Vec<Record> {
+        codec.decode(Box::new(Cursor::new(data.to_vec()))).collect()
+    }
+
+    #[test]
+    fn decode_with_primary_codec() {
+        let expected = record(vec![("message", string("first")), ("code", RecordItem::I64(42))]);
+        let mut data = msgpack::encode(&expected);
+        data.extend(msgpack::encode(&expected).into_iter());
+
+        assert_eq!(vec![expected.clone(), expected], decode(&fallback(), &data));
+        assert!(decode(&fallback(), b"").is_empty());
+    }
+
+    #[test]
+    fn fall_back_to_json_on_msgpack_port() {
+        let data = br#"{"type": "health_check", "ok": true} {"type": "health_check", "ok": false}"#;
+        assert_eq!(vec![
+            record(vec![("type", string("health_check")), ("ok", RecordItem::Bool(true))]),
+            record(vec![("type", string("health_check")), ("ok", RecordItem::Bool(false))]),
+        ], decode(&fallback(), data));
+
+        // Probes of anything else end up as messages.
+        assert_eq!(vec![record(vec![("message", string("PING"))])], decode(&fallback(), b"PING\n"));
+
+        // Without any codec able to decode the stream, the primary one decodes nothing.
+        let codecs: Vec<Box<Codec>> = vec![Box::new(MessagePack::default()), Box::new(Json::default())];
+        let primary = Fallback::new(codecs);
+        assert!(decode(&primary, b"PING\n").is_empty());
+        assert_eq!(Some(&RecordItem::Bool(true)), primary.decode_one(br#"{"ok": true}"#).unwrap().unwrap().find("ok"));
+    }
+
+    /// Reader handing out the data in chunks of the given sizes, like a socket does.
+    struct Chunked {
+        data: Cursor<Vec<u8>>,
+        sizes: Vec<usize>,
+    }
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = if self.sizes.is_empty() { buf.len() } else { self.sizes.remove(0) };
+            let len = if len < buf.len() { len } else { buf.len() };
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn keep_reading_record_cut_short() {
+        let expected = record(vec![("message", string("split across reads"))]);
+        let data = msgpack::encode(&expected);
+
+        // The primary codec waits for the rest of its record.
+        let rd = Chunked { data: Cursor::new(data.clone()), sizes: vec![3] };
+        assert_eq!(vec![expected.clone()], fallback().decode(Box::new(rd)).collect::<Vec<Record>>());
+
+        // The beginning of a JSON health check is not taken for a logfmt message.
+        let data = br#"{"type": "health_check", "ok": true}"#.to_vec();
+        let rd = Chunked { data: Cursor::new(data), sizes: vec![10] };
+        assert_eq!(vec![record(vec![("type", string("health_check")), ("ok", RecordItem::Bool(true))])],
+            fallback().decode(Box::new(rd)).collect::<Vec<Record>>());
+    }
+}
@@ -0,0 +1,642 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::From;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::str;
+use std::sync::Arc;
+
+use super::{Codec, DecodeError, Malformed, Projection};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::{Record, RecordItem, sorted};
+use super::super::json;
+use super::super::json::{Parser, Value};
+use super::super::metrics;
+use super::super::metrics::Counter;
+
+/// Default bound of a single value in bytes.
+const LIMIT: usize = 1024 * 1024;
+
+/// Size of chunks read from the stream.
+const CHUNK: usize = 8192;
+
+/// Codec for a stream of concatenated or whitespace-separated JSON objects.
+///
+/// Streams are parsed incrementally, a top-level value at a time, holding at most the limit of
+/// bytes of the value in progress. Longer values are skipped up to their end and counted in the
+/// `codec.json.oversize` metric, so that a never-completing value can't exhaust memory while the
+/// values following a completed one still decode.
+#[derive(Clone)]
+pub struct Json {
+    limit: usize,
+}
+
+impl Json {
+    pub fn new(limit: usize) -> Json {
+        Json {
+            limit: limit,
+        }
+    }
+}
+
+impl Default for Json {
+    fn default() -> Json {
+        Json::new(LIMIT)
+    }
+}
+
+impl From<Value> for RecordItem {
+    fn from(v: Value) -> RecordItem {
+        match v {
+            Value::Null => RecordItem::Null,
+            Value::Bool(v) => RecordItem::Bool(v),
+            Value::F64(v) => RecordItem::F64(v),
+            Value::I64(v) => RecordItem::I64(v),
+            Value::U64(v) => RecordItem::U64(v),
+            Value::String(v) => RecordItem::String(v),
+            Value::List(v) => RecordItem::Array(v.into_iter().map(From::from).collect()),
+            Value::Object(v) => {
+                let mut res = HashMap::new();
+                for (k, v) in v {
+                    res.insert(k, From::from(v));
+                }
+                RecordItem::Object(res)
+            }
+        }
+    }
+}
+
+/// Converts the item into a JSON value as it would be encoded, i.e. bytes become base64 strings.
+impl From<RecordItem> for Value {
+    fn from(v: RecordItem) -> Value {
+        match v {
+            RecordItem::Null => Value::Null,
+            RecordItem::Bool(v) => Value::Bool(v),
+            RecordItem::F64(v) => Value::F64(v),
+            RecordItem::I64(v) => Value::I64(v),
+            RecordItem::U64(v) => Value::U64(v),
+            RecordItem::String(v) => Value::String(v),
+            RecordItem::Text(v) => Value::String(v.as_str().to_string()),
+            RecordItem::Bytes(v) => {
+                let mut res = String::new();
+                encode_base64(&v, &mut res);
+                Value::String(res)
+            }
+            RecordItem::Array(v) => Value::List(v.into_iter().map(From::from).collect()),
+            RecordItem::Object(v) => {
+                let mut res = BTreeMap::new();
+                for (k, v) in v {
+                    res.insert(k, From::from(v));
+                }
+                Value::Object(res)
+            }
+        }
+    }
+}
+
+/// Converts the record into a JSON object, see `From<RecordItem>`.
+impl From<Record> for Value {
+    fn from(record: Record) -> Value {
+        let mut res = BTreeMap::new();
+        for (k, v) in record.iter() {
+            res.insert(k.clone(), From::from(v.clone()));
+        }
+        Value::Object(res)
+    }
+}
+
+impl Record {
+    /// Converts a JSON object into a record, returning `None` for any other top-level value.
+    pub fn from_json(value: Value) -> Option<Record> {
+        match value {
+            Value::Object(object) => {
+                let mut record = Record::new();
+                for (key, value) in object {
+                    record.insert(key, From::from(value));
+                }
+                Some(record)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Splits the stream into top-level values by tracking nesting and strings, without parsing.
+///
+/// Bare scalars, e.g. numbers, end with whitespace or the start of another value. Values are
+/// framed whether they are well-formed or not, so that a malformed one costs only itself.
+struct Frames<R> {
+    rd: R,
+    limit: usize,
+    chunk: Vec<u8>,
+    pos: usize,
+    /// Bytes of the value in progress, unless it's being skipped.
+    value: Vec<u8>,
+    /// Number of bytes of the value being skipped for exceeding the limit, if it is.
+    skipped: Option<usize>,
+    depth: usize,
+    string: bool,
+    escape: bool,
+    bare: bool,
+    eof: bool,
+    oversize: Counter,
+}
+
+impl<R: Read> Frames<R> {
+    fn new(rd: R, limit: usize) -> Frames<R> {
+        Frames {
+            rd: rd,
+            limit: limit,
+            chunk: Vec::new(),
+            pos: 0,
+            value: Vec::new(),
+            skipped: None,
+            depth: 0,
+            string: false,
+            escape: false,
+            bare: false,
+            eof: false,
+            oversize: metrics::registry().counter("codec.json.oversize"),
+        }
+    }
+
+    /// Reads the next chunk, returning false once the stream is over or broken.
+    fn fill(&mut self) -> bool {
+        let mut chunk = vec![0; CHUNK];
+        loop {
+            match self.rd.read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(size) => {
+                    chunk.truncate(size);
+                    self.chunk = chunk;
+                    self.pos = 0;
+                    return true;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    debug!(target: "Codec::JSON", "stream is broken: {}", err);
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if let Some(ref mut skipped) = self.skipped {
+            *skipped += 1;
+            return;
+        }
+
+        self.value.push(byte);
+        if self.value.len() > self.limit {
+            warn!(target: "Codec::JSON", "skipping value: exceeds the limit of {} bytes", self.limit);
+            self.oversize.inc();
+            self.skipped = Some(self.value.len());
+            self.value = Vec::new();
+        }
+    }
+
+    /// Completes the value in progress, returning its bytes unless it has been skipped.
+    fn complete(&mut self) -> Option<Vec<u8>> {
+        self.bare = false;
+        match self.skipped.take() {
+            Some(size) => {
+                debug!(target: "Codec::JSON", "skipped value of {} bytes", size);
+                None
+            }
+            None => Some(mem::replace(&mut self.value, Vec::new())),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Frames<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if self.pos == self.chunk.len() {
+                if self.eof || !self.fill() {
+                    self.eof = true;
+                    // Whatever is left is incomplete, unless it's a bare scalar.
+                    if self.value.is_empty() && self.skipped.is_none() {
+                        return None;
+                    }
+                    match self.complete() {
+                        Some(value) => return Some(value),
+                        None => continue,
+                    }
+                }
+                continue;
+            }
+
+            let byte = self.chunk[self.pos];
+            let space = byte == b' ' || byte == b'\t' || byte == b'\n' || byte == b'\r';
+
+            if self.bare && (space || byte == b'{' || byte == b'[' || byte == b'"') {
+                if let Some(value) = self.complete() {
+                    return Some(value);
+                }
+                continue;
+            }
+            self.pos += 1;
+
+            if self.string {
+                self.push(byte);
+                if self.escape {
+                    self.escape = false;
+                } else if byte == b'\\' {
+                    self.escape = true;
+                } else if byte == b'"' {
+                    self.string = false;
+                    if self.depth == 0 {
+                        if let Some(value) = self.complete() {
+                            return Some(value);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if self.depth == 0 && !self.bare {
+                if space {
+                    continue;
+                }
+                match byte {
+                    b'{' | b'[' => self.depth = 1,
+                    b'"' => self.string = true,
+                    _ => self.bare = true,
+                }
+                self.push(byte);
+                continue;
+            }
+
+            self.push(byte);
+            if self.bare {
+                continue;
+            }
+            match byte {
+                b'"' => self.string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        if let Some(value) = self.complete() {
+                            return Some(value);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parses the first value of the buffer into a record.
+fn first(buf: &[u8]) -> Result<Option<Record>, DecodeError> {
+    let src = try!(str::from_utf8(buf).map_err(|err| DecodeError::Syntax(format!("invalid UTF-8: {}", err))));
+    match try!(json::first(src).map_err(|err| DecodeError::Syntax(format!("{:?}", err)))) {
+        Some(value) => {
+            match Record::from_json(value) {
+                Some(record) => Ok(Some(record)),
+                None => Err(DecodeError::Unsupported("top-level value must be an object".to_string())),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Parses the framed value into a record, warning about anything else.
+fn parse(frame: &[u8]) -> Option<Record> {
+    match first(frame) {
+        Ok(record) => record,
+        Err(err) => {
+            warn!(target: "Codec::JSON", "skipping value: {}", err);
+            None
+        }
+    }
+}
+
+/// Parses the framed value into a record, building only the members the projection keeps.
+fn project(frame: &[u8], projection: &Projection) -> Option<Record> {
+    let src = match str::from_utf8(frame) {
+        Ok(src) => src,
+        Err(err) => {
+            warn!(target: "Codec::JSON", "skipping value: invalid UTF-8: {}", err);
+            return None;
+        }
+    };
+
+    let mut parser = Parser::new(src.chars());
+    match json::project(&mut parser, |key| projection.keeps(key)) {
+        Some(Ok(value)) => {
+            let record = Record::from_json(value);
+            if record.is_none() {
+                warn!(target: "Codec::JSON", "skipping value: top-level value must be an object");
+            }
+            record
+        }
+        Some(Err(err)) => {
+            warn!(target: "Codec::JSON", "skipping value: {:?}", err);
+            None
+        }
+        None => None,
+    }
+}
+
+impl FromConfig for Json {
+    fn from_config(config: &Config) -> Result<Json, Error> {
+        let limit = try!(config.u64_or("limit", LIMIT as u64));
+        if limit == 0 {
+            return Err(Error::Invalid("'limit' must be positive".to_string()));
+        }
+
+        Ok(Json::new(limit as usize))
+    }
+}
+
+impl Codec for Json {
+    fn new(&self) -> Box<Codec> {
+        Box::new(self.clone())
+    }
+
+    fn decode(&self, rd: Box<Read>) -> Box<Iterator<Item=Record>> {
+        Box::new(Frames::new(rd, self.limit).filter_map(|frame| parse(&frame)))
+    }
+
+    fn decode_checked(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=Result<Record, Malformed>>>, Box<Read>> {
+        Ok(Box::new(Frames::new(rd, self.limit).filter_map(|frame| {
+            match first(&frame) {
+                Ok(record) => record.map(Ok),
+                Err(err) => Some(Err(Malformed { raw: frame, error: err })),
+            }
+        })))
+    }
+
+    fn decode_projected(&self, rd: Box<Read>, projection: Arc<Projection>) -> Box<Iterator<Item=Record>> {
+        Box::new(Frames::new(rd, self.limit).filter_map(move |frame| project(&frame, &projection)))
+    }
+
+    fn decode_one(&self, buf: &[u8]) -> Result<Option<Record>, DecodeError> {
+        first(buf)
+    }
+
+    /// The value is cut short if the parser runs out of input within it, or within a character.
+    fn incomplete(&self, buf: &[u8]) -> bool {
+        let src = match str::from_utf8(buf) {
+            Ok(src) => src,
+            Err(err) => return buf.len() - err.valid_up_to() < 4,
+        };
+
+        match json::first(src) {
+            Err(json::ParserError::SyntaxError(json::Error::EOFWhileParsingString)) |
+            Err(json::ParserError::SyntaxError(json::Error::EOFWhileParsingArray)) |
+            Err(json::ParserError::SyntaxError(json::Error::EOFWhileParsingObject)) |
+            Err(json::ParserError::SyntaxError(json::Error::EOFWhileParsingObjectKey)) |
+            Err(json::ParserError::SyntaxError(json::Error::EOFWhileParsingObjectColon)) |
+            Err(json::ParserError::SyntaxError(json::Error::EOFWhileParsingObjectValue)) => true,
+            Ok(..) | Err(..) => false,
+        }
+    }
+}
+
+/// Encodes the record as a single-line JSON object, keys being written in the hash order.
+pub fn encode(record: &Record) -> String {
+    encode_record(record, false)
+}
+
+/// Encodes the record like `encode` does, keys of every object being ordered by name, so that
+/// equal records always encode into the same bytes, e.g. for hashing or in the deterministic mode.
+pub fn encode_sorted(record: &Record) -> String {
+    encode_record(record, true)
+}
+
+/// Encodes the record, keys of every object being ordered by name if requested.
+pub fn encode_record(record: &Record, ordered: bool) -> String {
+    let mut result = String::new();
+    if ordered {
+        encode_object(record.sorted().into_iter(), true, &mut result);
+    } else {
+        encode_object(record.iter(), false, &mut result);
+    }
+    result
+}
+
+fn encode_object<'a, I>(pairs: I, ordered: bool, result: &mut String)
+    where I: Iterator<Item=(&'a String, &'a RecordItem)>
+{
+    result.push('{');
+    for (id, (key, value)) in pairs.enumerate() {
+        if id > 0 {
+            result.push(',');
+        }
+        encode_string(key, result);
+        result.push(':');
+        encode_item(value, ordered, result);
+    }
+    result.push('}');
+}
+
+fn encode_item(item: &RecordItem, ordered: bool, result: &mut String) {
+    match *item {
+        RecordItem::Null => result.push_str("null"),
+        RecordItem::Bool(v) => result.push_str(if v { "true" } else { "false" }),
+        RecordItem::F64(v) => {
+            // The last line of defense, as inputs handle non-finite numbers on arrival, while
+            // only a filter may produce one afterwards.
+            if v.is_finite() {
+                result.push_str(&format!("{}", v));
+            } else {
+                warn!(target: "Codec::JSON", "encoding non-finite number {} as null", v);
+                metrics::registry().counter("codec.json.non_finite").inc();
+                result.push_str("null");
+            }
+        }
+        // Integers are written digit by digit, never passing through a float.
+        RecordItem::I64(v) => result.push_str(&v.to_string()),
+        RecordItem::U64(v) => result.push_str(&v.to_string()),
+        RecordItem::String(ref v) => encode_string(v, result),
+        RecordItem::Text(ref v) => encode_string(v, result),
+        RecordItem::Bytes(ref v) => {
+            result.push('"');
+            encode_base64(v, result);
+            result.push('"');
+        }
+        RecordItem::Array(ref v) => {
+            result.push('[');
+            for (id, item) in v.iter().enumerate() {
+                if id > 0 {
+                    result.push(',');
+                }
+                encode_item(item, ordered, result);
+            }
+            result.push(']');
+        }
+        RecordItem::Object(ref v) if ordered => encode_object(sorted(v).into_iter(), true, result),
+        RecordItem::Object(ref v) => encode_object(v.iter(), false, result),
+    }
+}
+
+/// Appends the standard base64 encoding of the bytes, with padding.
+pub fn encode_base64(v: &[u8], result: &mut String) {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    for chunk in v.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (id, &b)| acc | (b as u32) << (16 - 8 * id));
+        for id in 0..4 {
+            if id <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * id) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+}
+
+/// Appends the string quoted and escaped as a JSON string.
+pub fn encode_string(v: &str, result: &mut String) {
+    result.push('"');
+    for ch in v.chars() {
+        match ch {
+            '"'  => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => result.push(ch),
+        }
+    }
+    result.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::io;
+    use std::io::{Cursor, Read};
+    use std::iter::repeat;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use super::super::Codec;
+    use super::super::super::{Record, RecordItem, Text};
+    use super::super::super::json::Value;
+    use super::super::super::metrics;
+    use super::super::super::testing::{record, string};
+    use super::{Json, encode, encode_sorted};
+
+    /// Reader handing the data out in small pieces, like a socket does.
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Trickle {
+        fn new(data: String) -> Box<Read> {
+            Box::new(Trickle { data: data.into_bytes(), pos: 0 })
+        }
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = cmp::min(cmp::min(buf.len(), 7), self.data.len() - self.pos);
+            for (dst, src) in buf.iter_mut().zip(self.data[self.pos..self.pos + size].iter()) {
+                *dst = *src;
+            }
+            self.pos += size;
+            Ok(size)
+        }
+    }
+
+    fn ids(count: usize) -> Vec<Record> {
+        (1..count + 1).map(|id| record(vec![("id", RecordItem::F64(id as f64))])).collect()
+    }
+
+    #[test]
+    fn decode_stream() {
+        let data = r#"{"message": "first"} [42] {"message": "second", "code": 42}"#;
+        let records: Vec<_> = Json::default().decode(Box::new(Cursor::new(data.as_bytes().to_vec()))).collect();
+
+        assert_eq!(vec![
+            record(vec![("message", string("first"))]),
+            record(vec![("message", string("second")), ("code", RecordItem::F64(42.0))]),
+        ], records);
+    }
+
+    #[test]
+    fn skip_value_over_limit() {
+        let oversize = metrics::registry().counter("codec.json.oversize");
+        let before = oversize.get();
+
+        let large: String = repeat('x').take(1000).collect();
+        let data = format!(r#"{{"message": "{}", "nested": {{"quoted": "}}\""}}}} {{"id": 1}}{{"id": 2}}"#, large);
+        let records: Vec<_> = Json::new(100).decode(Trickle::new(data)).collect();
+
+        assert_eq!(ids(2), records);
+        assert!(oversize.get() > before);
+    }
+
+    #[test]
+    fn resync_after_malformed_value() {
+        let data = r#"{"id": 1,} "text" 42{"id": 1}[{"}": 2}]{"id": 2} {"id": 3"#.to_string();
+        let records: Vec<_> = Json::new(100).decode(Trickle::new(data)).collect();
+
+        assert_eq!(ids(2), records);
+    }
+
+    #[test]
+    fn encode_escaped_string() {
+        let payload = record(vec![("message", string("\"quoted\"\n\u{1}"))]);
+        assert_eq!(r#"{"message":"\"quoted\"\n\u0001"}"#, encode(&payload));
+    }
+
+    #[test]
+    fn encode_bytes_as_base64() {
+        let payload = record(vec![("raw", RecordItem::Bytes(b"logdrop".to_vec()))]);
+        assert_eq!(r#"{"raw":"bG9nZHJvcA=="}"#, encode(&payload));
+    }
+
+    #[test]
+    fn encode_keys_in_order() {
+        let mut user = HashMap::new();
+        for name in ["role", "id", "name", "zone"].iter() {
+            user.insert(name.to_string(), RecordItem::Null);
+        }
+        let payload = record(vec![("user", RecordItem::Object(user)), ("message", string("m")), ("at", RecordItem::I64(1))]);
+        let expected = r#"{"at":1,"message":"m","user":{"id":null,"name":null,"role":null,"zone":null}}"#;
+        assert_eq!(expected, encode_sorted(&payload));
+    }
+
+    #[test]
+    fn encode_nested() {
+        let payload = record(vec![("list", RecordItem::Array(vec![RecordItem::Null, RecordItem::F64(4.5), RecordItem::Bool(false)]))]);
+        assert_eq!(r#"{"list":[null,4.5,false]}"#, encode(&payload));
+    }
+
+    #[test]
+    fn convert_record_to_value_and_back() {
+        let mut user = HashMap::new();
+        user.insert("id".to_string(), RecordItem::F64(42.0));
+        user.insert("roles".to_string(), RecordItem::Array(vec![string("admin"), RecordItem::Null]));
+        let payload = record(vec![
+            ("message", string("le message")),
+            ("ratio", RecordItem::F64(0.5)),
+            ("sampled", RecordItem::Bool(true)),
+            ("user", RecordItem::Object(user)),
+        ]);
+
+        let value = Value::from(payload.clone());
+        assert_eq!(Some(&Value::String("le message".to_string())), value.find("message"));
+        assert_eq!(Some(payload), Record::from_json(value));
+    }
+
+    #[test]
+    fn convert_shared_text_and_bytes_to_strings() {
+        let text = Text::new(Arc::new(b"le message".to_vec()), 0, 10).unwrap();
+        assert_eq!(Value::String("le message".to_string()), Value::from(RecordItem::Text(text)));
+
+        let value = Value::from(RecordItem::Bytes(b"logdrop".to_vec()));
+        assert_eq!(Value::String("bG9nZHJvcA==".to_string()), value);
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::iter::Peekable;
+use std::str;
+use std::str::Chars;
+
+use super::{Codec, DecodeError, Malformed};
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+
+/// Field the whole line goes to if it has no pairs at all.
+const MESSAGE: &'static str = "message";
+
+/// Field listing keys that occurred more than once in the line, if annotated.
+const DUPLICATED: &'static str = "_keys_duplicated";
+
+/// Field marking records decoded from lines cut at the length limit.
+const TRUNCATED: &'static str = "_line_truncated";
+
+/// Conventional logfmt keys and the canonical names they are renamed to by default.
+const CANONICAL: [(&'static str, &'static str); 3] = [
+    ("msg", "message"),
+    ("level", "severity"),
+    ("ts", "timestamp"),
+];
+
+/// Codec for newline-separated logfmt lines, e.g. `level=info msg="user logged in" user_id=42`.
+///
+/// Values are either bare, ending with whitespace, or double-quoted with backslash escapes. A
+/// quote left open ends the value at the end of the line, never spanning the following ones. Keys
+/// without `=` are flags, becoming `true`. The last of the repeated keys wins, optionally listing
+/// them in the `_keys_duplicated` field.
+///
+/// Lines without a single `=` are not logfmt at all and are kept whole in the `message` field.
+///
+/// With the maximum line length given, at most that many bytes of a line are ever buffered, so
+/// that a producer never sending a newline can't exhaust the memory. Longer lines are either cut
+/// at the limit, marked with the `_line_truncated` flag, or skipped up to the next newline,
+/// reported as malformed.
+#[derive(Clone, Debug)]
+pub struct Logfmt {
+    mapping: HashMap<String, String>,
+    numbers: bool,
+    annotate: bool,
+    /// Maximum line length in bytes together with what happens to longer lines, if limited.
+    max_line: Option<(usize, Overlong)>,
+}
+
+/// What happens to lines longer than the limit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overlong {
+    /// Decode the line cut at the limit, marking the record.
+    Truncate,
+    /// Skip the line, resuming at the next one.
+    Skip,
+}
+
+/// Splits the stream into lines without the delimiter, buffering at most `limit` bytes of each
+/// and discarding the rest, telling whether the line was cut. Ends on read errors.
+struct Lines<R> {
+    rd: R,
+    limit: Option<usize>,
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = (Vec<u8>, bool);
+
+    fn next(&mut self) -> Option<(Vec<u8>, bool)> {
+        let mut line = Vec::new();
+        let mut cut = false;
+        loop {
+            let (done, used) = {
+                let buf = match self.rd.fill_buf() {
+                    Ok(buf) => buf,
+                    Err(..) => return None,
+                };
+                if buf.is_empty() {
+                    return if line.is_empty() && !cut { None } else { Some((line, cut)) };
+                }
+
+                let end = buf.iter().position(|&byte| byte == b'\n');
+                let chunk = &buf[..end.unwrap_or(buf.len())];
+                let mut room = match self.limit {
+                    Some(..) if cut => 0,
+                    Some(limit) => limit.saturating_sub(line.len()),
+                    None => chunk.len(),
+                };
+                if room < chunk.len() {
+                    // Never split a character.
+                    while room > 0 && chunk[room] & 0xc0 == 0x80 {
+                        room -= 1;
+                    }
+                    cut = true;
+                } else {
+                    room = chunk.len();
+                }
+                line.extend(chunk[..room].iter().cloned());
+
+                (end.is_some(), end.map_or(buf.len(), |end| end + 1))
+            };
+
+            self.rd.consume(used);
+            if done {
+                return Some((line, cut));
+            }
+        }
+    }
+}
+
+impl Logfmt {
+    /// Creates the codec renaming keys according to the mapping.
+    pub fn new(mapping: HashMap<String, String>) -> Logfmt {
+        Logfmt {
+            mapping: mapping,
+            numbers: false,
+            annotate: false,
+            max_line: None,
+        }
+    }
+
+    /// Types bare values looking like numbers as `F64`, keeping quoted ones strings.
+    pub fn numbers(mut self, numbers: bool) -> Logfmt {
+        self.numbers = numbers;
+        self
+    }
+
+    /// Lists keys occurring more than once in the line in the `_keys_duplicated` field.
+    pub fn annotate(mut self, annotate: bool) -> Logfmt {
+        self.annotate = annotate;
+        self
+    }
+
+    /// Limits lines to the given number of bytes.
+    pub fn max_line(mut self, limit: usize, overlong: Overlong) -> Logfmt {
+        self.max_line = Some((limit, overlong));
+        self
+    }
+
+    fn lines(&self, rd: Box<Read>) -> Lines<BufReader<Box<Read>>> {
+        Lines {
+            rd: BufReader::new(rd),
+            limit: self.max_line.map(|(limit, _)| limit),
+        }
+    }
+
+    /// Decodes the line cut at the limit unless it's to be skipped, reporting why it is.
+    fn decode_line(&self, line: &[u8], cut: bool) -> Result<Option<Record>, DecodeError> {
+        if cut {
+            if let Some((limit, Overlong::Skip)) = self.max_line {
+                return Err(DecodeError::Syntax(format!("line exceeds {} bytes", limit)));
+            }
+        }
+
+        match str::from_utf8(line) {
+            Ok(line) if line.trim().is_empty() => Ok(None),
+            Ok(line) => {
+                let mut record = self.parse(line.trim());
+                if cut {
+                    record.insert(TRUNCATED.to_string(), RecordItem::Bool(true));
+                }
+                Ok(Some(record))
+            }
+            Err(err) => Err(DecodeError::Syntax(format!("invalid UTF-8: {}", err))),
+        }
+    }
+
+    /// Decodes the line, which must not be blank.
+    fn parse(&self, line: &str) -> Record {
+        let mut record = Record::new();
+        if !line.contains('=') {
+            record.insert(MESSAGE.to_string(), RecordItem::String(line.to_string()));
+            return record;
+        }
+
+        let mut duplicated = Vec::new();
+        let mut chars = line.chars().peekable();
+        loop {
+            while chars.peek().map_or(false, |c| c.is_whitespace()) {
+                chars.next();
+            }
+
+            let mut key = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == '=' || c.is_whitespace() {
+                    break;
+                }
+                key.push(c);
+                chars.next();
+            }
+
+            let value = if chars.peek() == Some(&'=') {
+                chars.next();
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    RecordItem::String(quoted(&mut chars))
+                } else {
+                    self.bare(bare(&mut chars))
+                }
+            } else if key.is_empty() {
+                break;
+            } else {
+                RecordItem::Bool(true)
+            };
+
+            // Values of `=value` tokens have nothing to be named after.
+            if key.is_empty() {
+                continue;
+            }
+
+            let name = self.mapping.get(&key).cloned();
+            let key = name.unwrap_or(key);
+            if record.insert(key.clone(), value).is_some() {
+                let key = RecordItem::String(key);
+                if !duplicated.contains(&key) {
+                    duplicated.push(key);
+                }
+            }
+        }
+
+        if self.annotate && !duplicated.is_empty() {
+            record.insert(DUPLICATED.to_string(), RecordItem::Array(duplicated));
+        }
+        record
+    }
+
+    fn bare(&self, value: String) -> RecordItem {
+        if self.numbers {
+            if let Some(number) = number(&value) {
+                return RecordItem::F64(number);
+            }
+        }
+        RecordItem::String(value)
+    }
+}
+
+impl Default for Logfmt {
+    fn default() -> Logfmt {
+        Logfmt::new(CANONICAL.iter().map(|&(key, name)| (key.to_string(), name.to_string())).collect())
+    }
+}
+
+/// Reads the quoted value past the opening quote up to the closing one or the end of the line.
+///
+/// Unknown escapes are kept as they are.
+fn quoted(chars: &mut Peekable<Chars>) -> String {
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => {
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => value.push('\\'),
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+fn bare(chars: &mut Peekable<Chars>) -> String {
+    let mut value = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            break;
+        }
+        value.push(c);
+        chars.next();
+    }
+    value
+}
+
+/// Parses the value as a finite number, so that e.g. `inf` or `3ms` stay strings.
+fn number(value: &str) -> Option<f64> {
+    match value.chars().next() {
+        Some('0'...'9') | Some('-') | Some('+') | Some('.') => {}
+        _ => return None,
+    }
+
+    match value.parse::<f64>() {
+        Ok(number) if number.is_finite() => Some(number),
+        _ => None,
+    }
+}
+
+impl FromConfig for Logfmt {
+    fn from_config(config: &Config) -> Result<Logfmt, Error> {
+        let mapping = match config.find("mapping") {
+            Some(..) => try!(config.mapping("mapping")),
+            None => Logfmt::default().mapping,
+        };
+
+        let codec = Logfmt::new(mapping)
+            .numbers(try!(config.bool_or("numbers", false)))
+            .annotate(try!(config.bool_or("annotate_duplicates", false)));
+
+        if config.find("max_line_bytes").is_none() {
+            return Ok(codec);
+        }
+
+        let limit = try!(config.u64_or("max_line_bytes", 0));
+        if limit == 0 {
+            return Err(Error::Invalid("'max_line_bytes' must be positive".to_string()));
+        }
+        let overlong = match &try!(config.string_or("overlong", "truncate"))[..] {
+            "truncate" => Overlong::Truncate,
+            "skip" => Overlong::Skip,
+            overlong => return Err(Error::Invalid(format!("unknown overlong line policy '{}'", overlong))),
+        };
+
+        Ok(codec.max_line(limit as usize, overlong))
+    }
+}
+
+impl Codec for Logfmt {
+    fn new(&self) -> Box<Codec> {
+        Box::new(self.clone())
+    }
+
+    fn decode(&self, rd: Box<Read>) -> Box<Iterator<Item=Record>> {
+        let codec = self.clone();
+        Box::new(self.lines(rd).filter_map(move |(line, cut)| {
+            match codec.decode_line(&line, cut) {
+                Ok(record) => record,
+                Err(err) => {
+                    warn!(target: "Codec::Logfmt", "skipping line: {}", err);
+                    None
+                }
+            }
+        }))
+    }
+
+    fn decode_checked(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=Result<Record, Malformed>>>, Box<Read>> {
+        let codec = self.clone();
+        Ok(Box::new(self.lines(rd).filter_map(move |(line, cut)| {
+            match codec.decode_line(&line, cut) {
+                Ok(record) => record.map(Ok),
+                Err(error) => Some(Err(Malformed { raw: line, error: error })),
+            }
+        })))
+    }
+
+    fn decode_one(&self, buf: &[u8]) -> Result<Option<Record>, DecodeError> {
+        let src = try!(str::from_utf8(buf).map_err(|err| DecodeError::Syntax(format!("invalid UTF-8: {}", err))));
+        Ok(src.lines().map(|line| line.trim()).find(|line| !line.is_empty()).map(|line| self.parse(line)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::iter;
+
+    use super::super::Codec;
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::testing::{record, string};
+    use super::{Logfmt, Overlong};
+
+    fn decode(codec: &Logfmt, data: &str) -> Vec<Record> {
+        codec.decode(Box::new(Cursor::new(data.as_bytes().to_vec()))).collect()
+    }
+
+    #[test]
+    fn decode_quoted_values_and_flags() {
+        let data = "level=info msg=\"user \\\"bob\\\" logged in\\n\" path=C:\\tmp user_id=42 dur=3ms cached\n\n  \r\n";
+        assert_eq!(vec![record(vec![
+            ("severity", string("info")),
+            ("message", string("user \"bob\" logged in\n")),
+            ("path", string("C:\\tmp")),
+            ("user_id", string("42")),
+            ("dur", string("3ms")),
+            ("cached", RecordItem::Bool(true)),
+        ])], decode(&Logfmt::default(), data));
+
+        // Unknown escapes and empty values are kept.
+        assert_eq!(vec![record(vec![("a", string("\\d")), ("b", string("")), ("c", string(""))])],
+            decode(&Logfmt::default(), r#"a="\d" b= c="" =orphan"#));
+    }
+
+    #[test]
+    fn keep_last_of_duplicated_keys() {
+        let data = "a=1 b=2 a=3 msg=first message=second a=4";
+        assert_eq!(vec![record(vec![("a", string("4")), ("b", string("2")), ("message", string("second"))])],
+            decode(&Logfmt::default(), data));
+
+        assert_eq!(vec![record(vec![
+            ("a", string("4")),
+            ("b", string("2")),
+            ("message", string("second")),
+            ("_keys_duplicated", RecordItem::Array(vec![string("a"), string("message")])),
+        ])], decode(&Logfmt::default().annotate(true), data));
+    }
+
+    #[test]
+    fn type_numbers_if_enabled() {
+        let data = r#"count=42 ratio=-0.5 exp=1e3 dur=3ms quoted="7" nan=NaN inf=-inf"#;
+        assert_eq!(vec![record(vec![
+            ("count", RecordItem::F64(42.0)),
+            ("ratio", RecordItem::F64(-0.5)),
+            ("exp", RecordItem::F64(1000.0)),
+            ("dur", string("3ms")),
+            ("quoted", string("7")),
+            ("nan", string("NaN")),
+            ("inf", string("-inf")),
+        ])], decode(&Logfmt::default().numbers(true), data));
+
+        assert_eq!(Some(&string("42")), decode(&Logfmt::default(), data)[0].find("count"));
+    }
+
+    #[test]
+    fn map_conventional_keys() {
+        let data = "ts=2015-06-01T12:34:56Z level=warn msg=slow";
+        assert_eq!(vec![record(vec![
+            ("timestamp", string("2015-06-01T12:34:56Z")),
+            ("severity", string("warn")),
+            ("message", string("slow")),
+        ])], decode(&Logfmt::default(), data));
+
+        let mut mapping = HashMap::new();
+        mapping.insert("lvl".to_string(), "level".to_string());
+        assert_eq!(vec![record(vec![("level", string("warn")), ("msg", string("slow"))])],
+            decode(&Logfmt::new(mapping), "lvl=warn msg=slow"));
+    }
+
+    #[test]
+    fn keep_other_lines_as_messages() {
+        let data = "GET /index.html 200\nlevel=info msg=ok";
+        assert_eq!(vec![
+            record(vec![("message", string("GET /index.html 200"))]),
+            record(vec![("severity", string("info")), ("message", string("ok"))]),
+        ], decode(&Logfmt::default(), data));
+    }
+
+    #[test]
+    fn end_unterminated_quote_at_end_of_line() {
+        let data = "level=error msg=\"broken value next=1\nlevel=info msg=fine";
+        assert_eq!(vec![
+            record(vec![("severity", string("error")), ("message", string("broken value next=1"))]),
+            record(vec![("severity", string("info")), ("message", string("fine"))]),
+        ], decode(&Logfmt::default(), data));
+
+        assert_eq!(Ok(Some(record(vec![("message", string("trailing \\"))]))),
+            Logfmt::default().decode_one(b"msg=\"trailing \\"));
+    }
+
+    #[test]
+    fn truncate_overlong_line_without_newline() {
+        let codec = Logfmt::default().max_line(20, Overlong::Truncate);
+        let data = format!("level=info msg=ok\nmsg={}", iter::repeat('x').take(1 << 20).collect::<String>());
+
+        assert_eq!(vec![
+            record(vec![("severity", string("info")), ("message", string("ok"))]),
+            record(vec![("message", string("xxxxxxxxxxxxxxxx")), ("_line_truncated", RecordItem::Bool(true))]),
+        ], decode(&codec, &data));
+
+        // Characters are never split.
+        assert_eq!(vec![record(vec![("message", string("ééé")), ("_line_truncated", RecordItem::Bool(true))])],
+            decode(&Logfmt::default().max_line(11, Overlong::Truncate), "msg=éééé"));
+    }
+
+    #[test]
+    fn skip_overlong_line_up_to_next_newline() {
+        let codec = Logfmt::default().max_line(20, Overlong::Skip);
+        let data = format!("msg={}\nlevel=info msg=ok\nmsg={}", iter::repeat('x').take(100).collect::<String>(),
+            iter::repeat('y').take(1 << 20).collect::<String>());
+
+        assert_eq!(vec![record(vec![("severity", string("info")), ("message", string("ok"))])], decode(&codec, &data));
+
+        let results: Vec<_> = codec.decode_checked(Box::new(Cursor::new(data.into_bytes()))).ok().unwrap().collect();
+        assert_eq!(3, results.len());
+        match results[0] {
+            Err(ref malformed) => assert_eq!(20, malformed.raw.len()),
+            ref result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(results[1].is_ok());
+        assert!(results[2].is_err());
+    }
+}
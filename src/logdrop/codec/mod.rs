@@ -1,13 +1,613 @@
+use std::fmt;
 use std::io::Read;
+use std::sync::Arc;
 
 use super::Record;
 
+/// Failure to decode a record from untrusted bytes.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The bytes are not valid in the codec format.
+    Syntax(String),
+    /// The bytes are valid, but do not describe a record, e.g. a top-level array.
+    Unsupported(String),
+}
+
+/// Value failing to decode together with its bytes, see `Codec::decode_checked`.
+#[derive(Debug, PartialEq)]
+pub struct Malformed {
+    pub raw: Vec<u8>,
+    pub error: DecodeError,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Syntax(ref reason) => write!(f, "malformed input - {}", reason),
+            DecodeError::Unsupported(ref reason) => write!(f, "unsupported input - {}", reason),
+        }
+    }
+}
+
 pub trait Codec: Sync + Send {
     fn new(&self) -> Box<Codec>;
     fn decode(&self, rd: Box<Read>) -> Box<Iterator<Item=Record>>;
+
+    /// Decodes records together with the exact bytes each one was decoded from.
+    ///
+    /// Codecs unable to tell record boundaries in the stream give the reader back.
+    fn decode_raw(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=(Record, Vec<u8>)>>, Box<Read>> {
+        Err(rd)
+    }
+
+    /// Decodes records like `decode`, giving values failing to decode back with their bytes
+    /// rather than skipping them with a warning.
+    ///
+    /// Codecs unable to tell value boundaries in the stream give the reader back.
+    fn decode_checked(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=Result<Record, Malformed>>>, Box<Read>> {
+        Err(rd)
+    }
+
+    /// Decodes records keeping only the top-level fields the projection keeps.
+    ///
+    /// Codecs able to skip values without building them should override this, while the default
+    /// decodes everything and drops unwanted fields afterwards.
+    fn decode_projected(&self, rd: Box<Read>, projection: Arc<Projection>) -> Box<Iterator<Item=Record>> {
+        Box::new(self.decode(rd).map(move |record| projection.apply(record)))
+    }
+
+    /// Decodes the first record of the buffer, ignoring anything after it.
+    ///
+    /// Returns `None` if the buffer holds no record at all. Must never panic, whatever the input.
+    fn decode_one(&self, _: &[u8]) -> Result<Option<Record>, DecodeError> {
+        Err(DecodeError::Unsupported("codec is unable to decode a single record".to_string()))
+    }
+
+    /// Returns true if the buffer failing to decode is the beginning of a record cut short
+    /// rather than malformed, so that more bytes may make it decode, see `Fallback`.
+    fn incomplete(&self, _: &[u8]) -> bool {
+        false
+    }
+
+    /// Returns true if the codec decodes text, so that the input `encoding` may transcode the
+    /// stream into UTF-8 beforehand without mangling it.
+    fn text(&self) -> bool {
+        true
+    }
+}
+
+/// Decodes the first record of the buffer with the given codec, returning an error instead of
+/// panicking on arbitrary input.
+///
+/// This is the stable entry point for fuzzing the decode path.
+pub fn decode_one(buf: &[u8], codec: &Codec) -> Result<Option<Record>, DecodeError> {
+    codec.decode_one(buf)
+}
+
+pub mod fallback;
+pub mod json;
+pub mod logfmt;
+pub mod msgpack;
+pub mod normalize;
+pub mod projection;
+
+pub use self::fallback::Fallback;
+pub use self::json::Json;
+pub use self::logfmt::{Logfmt, Overlong};
+pub use self::msgpack::{MessagePack, SharedMessagePack};
+pub use self::normalize::{Normalization, Normalized};
+pub use self::projection::Projection;
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io;
+    use std::io::{Cursor, Read};
+    use std::sync::Arc;
+
+    use super::super::{Record, RecordItem};
+    use super::{Codec, DecodeError, Json, Logfmt, MessagePack, Projection, SharedMessagePack, decode_one};
+    use super::{json, msgpack};
+    use super::msgpack::{Extensions, Timestamps, TopLevel, Unknown};
+
+    /// Builds the record of 60 fields of every kind, with strings full of characters meaningful
+    /// for JSON and nested values.
+    pub fn fixture(seq: usize) -> Record {
+        let mut record = Record::new();
+        for id in 0..60 {
+            let value = match id % 6 {
+                0 => RecordItem::String(format!("value \"{}\" of {} with {{braces}}, [brackets] and \\", id, seq)),
+                1 => RecordItem::F64(id as f64 * 1.5 + seq as f64),
+                2 => RecordItem::Bool(id % 4 == 0),
+                3 => RecordItem::Null,
+                4 => RecordItem::Array(vec![RecordItem::F64(id as f64), RecordItem::String("}]".to_string()), RecordItem::Array(Vec::new())]),
+                _ => {
+                    let mut inner = HashMap::new();
+                    inner.insert("deep".to_string(), RecordItem::String("{\"".to_string()));
+                    let mut nested = HashMap::new();
+                    nested.insert("inner".to_string(), RecordItem::Object(inner));
+                    nested.insert("seq".to_string(), RecordItem::F64(seq as f64));
+                    RecordItem::Object(nested)
+                }
+            };
+            record.insert(format!("field_{}", id), value);
+        }
+        record
+    }
+
+    /// Keeps 12 fields out of 60, one of every kind at least.
+    pub fn allowlist() -> Projection {
+        Projection::Allow((0..60).filter(|id| *id % 5 == 0).map(|id| format!("field_{}", id)).collect())
+    }
+
+    /// Decodes the data both in full and projected, checking that projected records are full
+    /// ones restricted to the projection.
+    fn check_projection(codec: &Codec, data: Vec<u8>, projection: Projection) {
+        let full: Vec<Record> = codec.decode(Box::new(Cursor::new(data.clone()))).collect();
+        let projected: Vec<Record> = codec.decode_projected(Box::new(Cursor::new(data)), Arc::new(projection.clone())).collect();
+
+        assert_eq!(3, full.len());
+        assert_eq!(full.into_iter().map(|record| projection.apply(record)).collect::<Vec<Record>>(), projected);
+    }
+
+    /// Encodes three fixture records with a value not being a record in between.
+    fn json_stream() -> Vec<u8> {
+        let records: Vec<String> = (0..3).map(|seq| json::encode(&fixture(seq))).collect();
+        format!("{}\n[1, {{\"a\": 2}}]\n{} {}", records[0], records[1], records[2]).into_bytes()
+    }
+
+    fn msgpack_stream() -> Vec<u8> {
+        let mut data = msgpack::encode(&fixture(0));
+        data.extend([0x92, 0x01, 0x81, 0xa1, b'a', 0x02].iter().cloned());
+        data.extend(msgpack::encode(&fixture(1)).into_iter());
+        data.extend(msgpack::encode(&fixture(2)).into_iter());
+        data
+    }
+
+    #[test]
+    fn project_json_fields() {
+        check_projection(&Json::default(), json_stream(), allowlist());
+    }
+
+    #[test]
+    fn project_msgpack_fields() {
+        check_projection(&MessagePack::default(), msgpack_stream(), allowlist());
+    }
+
+    #[test]
+    fn project_denied_fields() {
+        let projection = Projection::Deny(vec!["field_0".to_string(), "field_4".to_string(), "field_5".to_string()].into_iter().collect());
+
+        check_projection(&Json::default(), json_stream(), projection.clone());
+        check_projection(&MessagePack::default(), msgpack_stream(), projection);
+    }
+
+    #[test]
+    fn decode_msgpack_lazily_like_eagerly() {
+        let eager: Vec<Record> = MessagePack::default().decode(Box::new(Cursor::new(msgpack_stream()))).collect();
+        let lazy: Vec<Record> = MessagePack::default().lazy(true).decode(Box::new(Cursor::new(msgpack_stream()))).collect();
+        let shared: Vec<Record> = SharedMessagePack::default().lazy(true).decode(Box::new(Cursor::new(msgpack_stream()))).collect();
+
+        assert_eq!(3, lazy.len());
+        for ((eager, lazy), shared) in eager.iter().zip(lazy.iter()).zip(shared.iter()) {
+            assert!(lazy.payload().is_some() && shared.payload().is_some());
+            assert_eq!(eager.len(), lazy.len());
+            assert_eq!(eager.size_hint(), lazy.size_hint());
+            assert_eq!(eager.size_hint(), shared.size_hint());
+            assert_eq!(eager.find("field_5"), lazy.find("field_5"));
+            assert_eq!(eager.find("field_0"), shared.find("field_0"));
+            assert_eq!(eager, lazy);
+            assert_eq!(eager, shared);
+        }
+
+        check_projection(&MessagePack::default().lazy(true), msgpack_stream(), allowlist());
+        check_projection(&SharedMessagePack::default().lazy(true), msgpack_stream(), allowlist());
+    }
+
+    #[test]
+    fn decode_msgpack_eagerly_what_lazy_index_is_unable_to_describe() {
+        let codec = MessagePack::default().lazy(true);
+
+        // Duplicate keys, the last value winning.
+        let data = vec![0x82, 0xa1, b'a', 0x01, 0xa1, b'a', 0x02];
+        let records: Vec<Record> = codec.decode(Box::new(Cursor::new(data))).collect();
+        assert_eq!(1, records.len());
+        assert_eq!(None, records[0].payload());
+        assert_eq!(Some(&RecordItem::I64(2)), records[0].find("a"));
+
+        // Non-finite floats are found while indexing, so the record is not known to be finite.
+        let mut record = Record::new();
+        record.insert("latency".to_string(), RecordItem::F64(::std::f64::NAN));
+        let records: Vec<Record> = codec.decode(Box::new(Cursor::new(msgpack::encode(&record)))).collect();
+        assert!(records[0].payload().is_some());
+        assert!(!records[0].is_known_finite());
+
+        let records: Vec<Record> = codec.decode(Box::new(Cursor::new(msgpack::encode(&fixture(0))))).collect();
+        assert!(records[0].is_known_finite());
+    }
+
+    #[test]
+    fn decode_shared_strings() {
+        let owned: Vec<Record> = MessagePack::default().decode(Box::new(Cursor::new(msgpack_stream()))).collect();
+        let shared: Vec<Record> = SharedMessagePack::default().decode(Box::new(Cursor::new(msgpack_stream()))).collect();
+
+        assert_eq!(3, shared.len());
+        assert_eq!(owned, shared);
+
+        // All top-level strings of the record live in its single buffer.
+        let buffers: Vec<*const Vec<u8>> = shared[0].iter().filter_map(|(_, value)| {
+            match *value {
+                RecordItem::Text(ref text) => Some(&*text.buf as *const Vec<u8>),
+                _ => None,
+            }
+        }).collect();
+        assert_eq!(10, buffers.len());
+        assert!(buffers.iter().all(|buf| *buf == buffers[0]));
+    }
+
+    #[test]
+    fn end_projected_stream_on_truncated_input() {
+        let mut data = msgpack::encode(&fixture(0));
+        let truncated = data.len() - 3;
+        data.truncate(truncated);
+
+        let records: Vec<Record> = MessagePack::default().decode_projected(Box::new(Cursor::new(data)), Arc::new(allowlist())).collect();
+        assert!(records.is_empty());
+
+        let data = br#"{"field_1": {"skipped": [1, "]"}, "field_0": "kept"}"#;
+        let records: Vec<Record> = Json::default().decode_projected(Box::new(Cursor::new(data[..40].to_vec())), Arc::new(allowlist())).collect();
+        assert!(records.is_empty());
+    }
+
+    /// Generates pseudo-random byte slices of up to the given length using the xorshift generator.
+    fn garbage(seed: u64, count: usize, len: usize) -> Vec<Vec<u8>> {
+        let mut x = seed;
+        let mut next = move || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+
+        (0..count).map(|_| {
+            let size = next() as usize % len;
+            (0..size).map(|_| next() as u8).collect()
+        }).collect()
+    }
+
+    fn is_syntax(result: Result<Option<Record>, DecodeError>) -> bool {
+        match result {
+            Err(DecodeError::Syntax(..)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn survive_random_bytes() {
+        let codecs: Vec<Box<Codec>> = vec![
+            Box::new(MessagePack::default()),
+            Box::new(SharedMessagePack::default()),
+            Box::new(Json::default()),
+            Box::new(Logfmt::default()),
+        ];
+        for codec in codecs.iter() {
+            for buf in garbage(42, 10000, 64).iter() {
+                let _ = decode_one(buf, &**codec);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_malformed_msgpack() {
+        // Truncated string, array of 2^32 - 1 elements, reserved marker, non-string key.
+        assert!(is_syntax(decode_one(&[0x81, 0xa3, b'k', b'e'], &MessagePack::default())));
+        assert!(is_syntax(decode_one(&[0xdd, 0xff, 0xff, 0xff, 0xff, 0x00], &MessagePack::default())));
+        assert!(is_syntax(decode_one(&[0xc1], &MessagePack::default())));
+        assert!(decode_one(&[0x81, 0x01, 0x02], &MessagePack::default()).is_err());
+        assert!(decode_one(&[0x92, 0x01, 0x02], &MessagePack::default()).is_err());
+
+        let nested: Vec<u8> = (0..100000).map(|_| 0x91).collect();
+        assert!(is_syntax(decode_one(&nested, &MessagePack::default())));
+
+        assert_eq!(Ok(None), decode_one(&[], &MessagePack::default()));
+    }
+
+    /// Wraps the encoded value into a record of the single field `t`.
+    fn msgpack_field(value: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x81, 0xa1, b't'];
+        data.extend(value.iter().cloned());
+        data
+    }
+
+    #[test]
+    fn decode_msgpack_timestamp_extension() {
+        let iso = Extensions { timestamps: Timestamps::Iso, unknown: Unknown::Bytes, top_level: TopLevel::Skip };
+        let codecs: Vec<(Box<Codec>, Box<Codec>)> = vec![
+            (Box::new(MessagePack::default()), Box::new(MessagePack::new(iso))),
+            (Box::new(SharedMessagePack::default()), Box::new(SharedMessagePack::new(iso))),
+        ];
+
+        // 2015-06-01T12:34:56Z as 32-bit seconds.
+        let seconds = msgpack_field(&[0xd6, 0xff, 0x55, 0x6c, 0x51, 0x70]);
+        // 2015-06-01T12:34:56.789Z as 30-bit nanoseconds and 34-bit seconds.
+        let fraction = msgpack_field(&[0xd7, 0xff, 0xbc, 0x1c, 0xbd, 0x00, 0x55, 0x6c, 0x51, 0x70]);
+        // A second before the epoch as 32-bit nanoseconds and signed 64-bit seconds.
+        let negative = msgpack_field(&[0xc7, 0x0c, 0xff, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        // Nanoseconds beyond a second.
+        let invalid = msgpack_field(&[0xc7, 0x0c, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let t = |codec: &Codec, data: &[u8]| decode_one(data, codec).unwrap().unwrap().find("t").cloned().unwrap();
+        for &(ref epoch, ref iso) in codecs.iter() {
+            assert_eq!(RecordItem::F64(1433162096.0), t(&**epoch, &seconds));
+            match t(&**epoch, &fraction) {
+                RecordItem::F64(value) => assert!((value - 1433162096.789).abs() < 1e-6),
+                value => panic!("expected epoch seconds, found {:?}", value),
+            }
+            assert_eq!(RecordItem::F64(-1.0), t(&**epoch, &negative));
+
+            assert_eq!(Some("2015-06-01T12:34:56Z"), t(&**iso, &seconds).as_str());
+            assert_eq!(Some("2015-06-01T12:34:56.789000000Z"), t(&**iso, &fraction).as_str());
+            assert_eq!(Some("1969-12-31T23:59:59Z"), t(&**iso, &negative).as_str());
+
+            match decode_one(&invalid, &**epoch) {
+                Err(DecodeError::Unsupported(..)) => {}
+                result => panic!("expected unsupported timestamp, found {:?}", result),
+            }
+        }
+    }
+
+    /// Reader returning one chunk per read, and an interruption for an empty one.
+    struct Chunked(Vec<Vec<u8>>);
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+
+            let chunk = self.0.remove(0);
+            if chunk.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+            assert!(chunk.len() <= buf.len());
+            for (pos, &byte) in chunk.iter().enumerate() {
+                buf[pos] = byte;
+            }
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn decode_msgpack_values_sharing_read() {
+        // Three records written at once, followed by the one split across reads.
+        let mut chunk = Vec::new();
+        for seq in 0..3 {
+            chunk.extend(msgpack::encode(&fixture(seq)).into_iter());
+        }
+        let last = msgpack::encode(&fixture(3));
+        chunk.extend(last[..10].iter().cloned());
+        let chunks = vec![chunk, Vec::new(), last[10..100].to_vec(), last[100..].to_vec()];
+
+        let expected: Vec<Record> = (0..4).map(fixture).collect();
+        let codecs: Vec<Box<Codec>> = vec![Box::new(MessagePack::default()), Box::new(SharedMessagePack::default())];
+        for codec in codecs.iter() {
+            assert_eq!(expected, codec.decode(Box::new(Chunked(chunks.clone()))).collect::<Vec<Record>>());
+
+            let raw: Vec<(Record, Vec<u8>)> = codec.decode_raw(Box::new(Chunked(chunks.clone()))).ok().unwrap().collect();
+            assert_eq!(4, raw.len());
+            assert_eq!(last, raw[3].1);
+        }
+    }
+
+    #[test]
+    fn decode_msgpack_value_trickling_in() {
+        let items = (0..10000).map(|seq| RecordItem::I64(seq % 100)).collect();
+        let mut record = Record::new();
+        record.insert("items".to_string(), RecordItem::Array(items));
+        let data = msgpack::encode(&record);
+        let chunks: Vec<Vec<u8>> = data.iter().map(|&byte| vec![byte]).collect();
+
+        let codecs: Vec<Box<Codec>> = vec![Box::new(MessagePack::default()), Box::new(SharedMessagePack::default())];
+        for codec in codecs.iter() {
+            assert_eq!(vec![record.clone()], codec.decode(Box::new(Chunked(chunks.clone()))).collect::<Vec<Record>>());
+        }
+    }
+
+    #[test]
+    fn decode_unknown_msgpack_extension() {
+        let skip = Extensions { timestamps: Timestamps::Epoch, unknown: Unknown::Skip, top_level: TopLevel::Skip };
+        let codecs: Vec<(Box<Codec>, Box<Codec>)> = vec![
+            (Box::new(MessagePack::default()), Box::new(MessagePack::new(skip))),
+            (Box::new(SharedMessagePack::default()), Box::new(SharedMessagePack::new(skip))),
+        ];
+
+        // Values of type 5, fixed-size and sized, the latter within an array next to an integer.
+        let mut data = vec![0x82, 0xa1, b'a', 0xd4, 0x05, 0x2a, 0xa1, b'b', 0x92, 0x01, 0xc7, 0x02, 0x05, 0x01, 0x02];
+        data.extend(msgpack::encode(&fixture(0)).into_iter());
+
+        for &(ref bytes, ref skip) in codecs.iter() {
+            let records: Vec<Record> = bytes.decode(Box::new(Cursor::new(data.clone()))).collect();
+            assert_eq!(2, records.len());
+            assert_eq!(Some(&RecordItem::Bytes(vec![0x05, 0x2a])), records[0].find("a"));
+            assert_eq!(Some(&RecordItem::Array(vec![RecordItem::I64(1), RecordItem::Bytes(vec![0x05, 0x01, 0x02])])),
+                records[0].find("b"));
+
+            let records: Vec<Record> = skip.decode(Box::new(Cursor::new(data.clone()))).collect();
+            assert_eq!(2, records.len());
+            assert_eq!(None, records[0].find("a"));
+            assert_eq!(Some(&RecordItem::Array(vec![RecordItem::I64(1)])), records[0].find("b"));
+            assert_eq!(fixture(0), records[1]);
+        }
+    }
+
+    #[test]
+    fn skip_or_wrap_msgpack_top_level_values() {
+        let wrap = Extensions { timestamps: Timestamps::Epoch, unknown: Unknown::Bytes, top_level: TopLevel::Wrap };
+        let codecs: Vec<(Box<Codec>, Box<Codec>)> = vec![
+            (Box::new(MessagePack::default()), Box::new(MessagePack::new(wrap))),
+            (Box::new(SharedMessagePack::default()), Box::new(SharedMessagePack::new(wrap))),
+        ];
+
+        // An array and the string "oops" between records.
+        let mut data = msgpack::encode(&fixture(0));
+        data.extend([0x92, 0x01, 0x81, 0xa1, b'a', 0x02, 0xa4, b'o', b'o', b'p', b's'].iter().cloned());
+        data.extend(msgpack::encode(&fixture(1)).into_iter());
+
+        let mut message = Record::new();
+        message.insert("message".to_string(), RecordItem::String("oops".to_string()));
+
+        for &(ref skip, ref wrap) in codecs.iter() {
+            let records: Vec<Record> = skip.decode(Box::new(Cursor::new(data.clone()))).collect();
+            assert_eq!(vec![fixture(0), fixture(1)], records);
+
+            let records: Vec<Record> = wrap.decode(Box::new(Cursor::new(data.clone()))).collect();
+            assert_eq!(vec![fixture(0), message.clone(), fixture(1)], records);
+        }
+
+        let projection = Arc::new(Projection::Allow(vec!["message".to_string()].into_iter().collect()));
+        let records: Vec<Record> = MessagePack::new(wrap).decode_projected(Box::new(Cursor::new(data.clone())), projection).collect();
+        assert_eq!(vec![Record::new(), message.clone(), Record::new()], records);
+        let records: Vec<Record> = MessagePack::default().decode_projected(Box::new(Cursor::new(data)), Arc::new(allowlist())).collect();
+        assert_eq!(2, records.len());
+    }
+
+    #[test]
+    fn reject_malformed_json() {
+        assert!(is_syntax(decode_one(b"{\"key\": ", &Json::default())));
+        assert!(is_syntax(decode_one(&[b'{', 0xff, b'}'], &Json::default())));
+        assert!(decode_one(b"[1, 2]", &Json::default()).is_err());
+
+        let nested: String = (0..100000).map(|_| '[').collect();
+        assert!(is_syntax(decode_one(nested.as_bytes(), &Json::default())));
+
+        let huge: String = (0..1000).map(|_| '9').collect();
+        assert!(decode_one(format!("{{\"n\": {}e99999999999999999999}}", huge).as_bytes(), &Json::default()).is_ok());
+
+        assert_eq!(Ok(None), decode_one(b"  \n", &Json::default()));
+    }
 }
 
-mod msgpack;
+#[cfg(test)]
+mod benchmarking {
+    extern crate test;
+
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use self::test::Bencher;
+
+    use super::super::{Record, RecordItem};
+    use super::{Codec, Json, MessagePack, SharedMessagePack};
+    use super::{json, msgpack};
+    use super::test::{allowlist, fixture};
+
+    fn decode(b: &mut Bencher, codec: &Codec, data: Vec<u8>, projected: bool) {
+        let projection = Arc::new(allowlist());
+        b.bytes = data.len() as u64;
+        b.iter(|| {
+            let rd = Box::new(Cursor::new(data.clone()));
+            let records = if projected {
+                codec.decode_projected(rd, projection.clone())
+            } else {
+                codec.decode(rd)
+            };
+
+            for record in records {
+                test::black_box(record);
+            }
+        });
+    }
+
+    fn json_stream() -> Vec<u8> {
+        let mut data = String::new();
+        for seq in 0..100 {
+            data.push_str(&json::encode(&fixture(seq)));
+            data.push('\n');
+        }
+        data.into_bytes()
+    }
 
-pub use self::msgpack::MessagePack;
+    fn msgpack_stream() -> Vec<u8> {
+        let mut data = Vec::new();
+        for seq in 0..100 {
+            data.extend(msgpack::encode(&fixture(seq)).into_iter());
+        }
+        data
+    }
 
+    /// Encodes records of 60 string fields each, where owned strings take an allocation per field.
+    fn msgpack_strings() -> Vec<u8> {
+        let mut data = Vec::new();
+        for seq in 0..100 {
+            let mut record = Record::new();
+            for id in 0..60 {
+                record.insert(format!("field_{}", id), RecordItem::String(format!("value {} of record {}", id, seq)));
+            }
+            data.extend(msgpack::encode(&record).into_iter());
+        }
+        data
+    }
+
+    #[bench]
+    fn decode_json_full(b: &mut Bencher) {
+        decode(b, &Json::default(), json_stream(), false);
+    }
+
+    #[bench]
+    fn decode_json_projected(b: &mut Bencher) {
+        decode(b, &Json::default(), json_stream(), true);
+    }
+
+    #[bench]
+    fn decode_msgpack_full(b: &mut Bencher) {
+        decode(b, &MessagePack::default(), msgpack_stream(), false);
+    }
+
+    #[bench]
+    fn decode_msgpack_projected(b: &mut Bencher) {
+        decode(b, &MessagePack::default(), msgpack_stream(), true);
+    }
+
+    #[bench]
+    fn decode_msgpack_shared(b: &mut Bencher) {
+        decode(b, &SharedMessagePack::default(), msgpack_stream(), false);
+    }
+
+    /// Routes records by a single field and forwards them, reusing payloads of lazy records as is.
+    fn route(b: &mut Bencher, codec: &Codec) {
+        let data = msgpack_stream();
+        b.bytes = data.len() as u64;
+        b.iter(|| {
+            let mut forwarded = 0;
+            for record in codec.decode(Box::new(Cursor::new(data.clone()))) {
+                if record.find("field_0").is_some() {
+                    forwarded += match record.payload() {
+                        Some(payload) => payload.len(),
+                        None => msgpack::encode(&record).len(),
+                    };
+                }
+            }
+            test::black_box(forwarded);
+        });
+    }
+
+    #[bench]
+    fn route_msgpack_eager(b: &mut Bencher) {
+        route(b, &MessagePack::default());
+    }
+
+    #[bench]
+    fn route_msgpack_lazy(b: &mut Bencher) {
+        route(b, &MessagePack::default().lazy(true));
+    }
+
+    #[bench]
+    fn decode_msgpack_strings_owned(b: &mut Bencher) {
+        decode(b, &MessagePack::default(), msgpack_strings(), false);
+    }
+
+    #[bench]
+    fn decode_msgpack_strings_shared(b: &mut Bencher) {
+        decode(b, &SharedMessagePack::default(), msgpack_strings(), false);
+    }
+}
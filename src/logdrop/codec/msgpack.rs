@@ -1,83 +1,1118 @@
 use std::convert::From;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::io::Read;
+use std::iter::repeat;
+use std::mem;
+use std::str;
+use std::sync::Arc;
 
-use msgpack::decode::value::{Integer, Value};
+use chrono::NaiveDateTime;
+use msgpack::decode::value::{Float, Integer, Value};
 use msgpack::decode::value::read_value;
+use msgpack::encode::value::write_value;
 
-use super::Codec;
-use super::super::{Record, RecordItem};
+use super::{Codec, DecodeError, Malformed, Projection};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::lazy::{Field, Format, Lazy};
+use super::super::numeric;
+use super::super::{Record, RecordItem, Text, sorted};
 
-#[derive(Clone)]
-pub struct MessagePack;
+/// Extension type reserved by MessagePack for timestamps.
+const TIMESTAMP: i8 = -1;
+
+/// How values of the timestamp extension are decoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Timestamps {
+    /// Seconds since the Unix epoch with the fraction, like the record timestamp.
+    Epoch,
+    /// RFC 3339 time in UTC, e.g. `2015-06-01T12:34:56.789000000Z`.
+    Iso,
+}
+
+/// How values of extension types other than the timestamp are decoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Unknown {
+    /// Bytes of the value preceded by its type byte.
+    Bytes,
+    /// Dropped from their map or array with a warning.
+    Skip,
+}
+
+/// How top-level values other than maps are decoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TopLevel {
+    /// Dropped from the stream with a warning.
+    Skip,
+    /// Scalars become records of the single `message` field, while arrays are still skipped.
+    Wrap,
+}
+
+/// Decoding of MessagePack values not mapping to records directly: extension values and
+/// top-level values other than maps.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Extensions {
+    pub timestamps: Timestamps,
+    pub unknown: Unknown,
+    pub top_level: TopLevel,
+}
+
+impl Default for Extensions {
+    fn default() -> Extensions {
+        Extensions {
+            timestamps: Timestamps::Epoch,
+            unknown: Unknown::Bytes,
+            top_level: TopLevel::Skip,
+        }
+    }
+}
+
+impl FromConfig for Extensions {
+    fn from_config(config: &Config) -> Result<Extensions, Error> {
+        let timestamps = match &try!(config.string_or("timestamps", "epoch"))[..] {
+            "epoch" => Timestamps::Epoch,
+            "iso" => Timestamps::Iso,
+            timestamps => return Err(Error::Invalid(format!("unknown timestamp format '{}'", timestamps))),
+        };
+
+        let unknown = match &try!(config.string_or("extensions", "bytes"))[..] {
+            "bytes" => Unknown::Bytes,
+            "skip" => Unknown::Skip,
+            unknown => return Err(Error::Invalid(format!("unknown extension policy '{}'", unknown))),
+        };
+
+        let top_level = match &try!(config.string_or("top_level", "skip"))[..] {
+            "skip" => TopLevel::Skip,
+            "wrap" => TopLevel::Wrap,
+            top_level => return Err(Error::Invalid(format!("unknown top-level policy '{}'", top_level))),
+        };
+
+        Ok(Extensions {
+            timestamps: timestamps,
+            unknown: unknown,
+            top_level: top_level,
+        })
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MessagePack {
+    extensions: Extensions,
+    lazy: bool,
+}
+
+impl MessagePack {
+    pub fn new(extensions: Extensions) -> MessagePack {
+        MessagePack {
+            extensions: extensions,
+            lazy: false,
+        }
+    }
+
+    /// Decodes fields of records on access rather than upfront, see `lazy`.
+    pub fn lazy(mut self, lazy: bool) -> MessagePack {
+        self.lazy = lazy;
+        self
+    }
+}
+
+/// MessagePack codec keeping string values within the buffer of their record, see `Text`.
+///
+/// Saves an allocation per string value at the cost of keeping the whole record buffer alive
+/// while any of its values is. Map keys are still owned.
+#[derive(Clone, Default)]
+pub struct SharedMessagePack {
+    extensions: Extensions,
+    lazy: bool,
+}
+
+impl SharedMessagePack {
+    pub fn new(extensions: Extensions) -> SharedMessagePack {
+        SharedMessagePack {
+            extensions: extensions,
+            lazy: false,
+        }
+    }
+
+    /// Decodes fields of records on access rather than upfront, see `lazy`.
+    pub fn lazy(mut self, lazy: bool) -> SharedMessagePack {
+        self.lazy = lazy;
+        self
+    }
+}
 
 pub struct Iter {
-    rd: Box<Read>,
+    values: Values,
+    extensions: Extensions,
 }
 
 impl Iter {
-    pub fn new(rd: Box<Read>) -> Iter {
+    pub fn new(rd: Box<Read>, extensions: Extensions) -> Iter {
         Iter {
-            rd: rd,
+            values: Values::new(rd),
+            extensions: extensions,
         }
     }
 }
 
-impl From<Value> for Record {
-    fn from(v: Value) -> Record {
-        match v {
-            Value::Map(map) => {
-                let mut res = HashMap::new();
-                for (key, val) in map {
-                    let key = match key {
-                        Value::String(v) => v,
-                        _ => unimplemented!(),
-                    };
+/// Initial size of the buffer values are read into, which is also the size it shrinks back to
+/// once a larger value is consumed.
+const BUFFER: usize = 64 * 1024;
 
-                    let val = From::from(val);
+/// Stream split into values, read in chunks as large as the buffer.
+///
+/// A single read may bring several values, e.g. ones sent by a single TCP write, which are taken
+/// back to back from the buffer without reading again. A value the buffer holds only the start of
+/// waits for more bytes, the buffer growing if it's too small to hold the value whole. It isn't
+/// scanned again until the buffer holds as many bytes as its declared lengths require, so that a
+/// large value arriving over many reads isn't rescanned from the start after each of them.
+struct Values {
+    rd: Box<Read>,
+    buf: Vec<u8>,
+    /// Bytes read but not taken yet.
+    start: usize,
+    end: usize,
+    /// Bytes the next value takes at least, known from its truncated scan.
+    wanted: usize,
+}
 
-                    res.insert(key, val);
+impl Values {
+    fn new(rd: Box<Read>) -> Values {
+        Values {
+            rd: rd,
+            buf: vec![0; BUFFER],
+            start: 0,
+            end: 0,
+            wanted: 0,
+        }
+    }
+
+    /// Returns bytes of the next value, checked by `scan`, or `None` once the stream is over.
+    fn next(&mut self) -> Result<Option<&[u8]>, DecodeError> {
+        loop {
+            if self.start < self.end && self.end - self.start >= self.wanted {
+                match measure(&self.buf[self.start..self.end], 0, &mut self.wanted) {
+                    Ok(len) => {
+                        let at = self.start;
+                        self.start += len;
+                        self.wanted = 0;
+                        return Ok(Some(&self.buf[at..at + len]));
+                    }
+                    Err(ref err) if *err == truncated() => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if !try!(self.fill()) {
+                return if self.start < self.end { Err(truncated()) } else { Ok(None) };
+            }
+        }
+    }
+
+    /// Returns bytes read but not taken yet, e.g. the rest of the stream once it's malformed.
+    fn pending(&self) -> Vec<u8> {
+        self.buf[self.start..self.end].to_vec()
+    }
+
+    /// Reads more bytes after the ones not taken yet, returning false at the end of the stream.
+    fn fill(&mut self) -> Result<bool, DecodeError> {
+        let pending = self.end - self.start;
+        for pos in 0..pending {
+            self.buf[pos] = self.buf[self.start + pos];
+        }
+        self.start = 0;
+        self.end = pending;
+
+        if pending == 0 && self.buf.len() > BUFFER {
+            self.buf.truncate(BUFFER);
+            self.buf.shrink_to_fit();
+        } else if pending == self.buf.len() {
+            let len = self.buf.len();
+            self.buf.extend(repeat(0).take(len));
+        }
+
+        loop {
+            match self.rd.read(&mut self.buf[self.end..]) {
+                Ok(0) => return Ok(false),
+                Ok(len) => {
+                    self.end += len;
+                    return Ok(true);
                 }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(DecodeError::Syntax(err.to_string())),
+            }
+        }
+    }
+}
 
-                Record(res)
+/// Converts a decoded MessagePack map into a record, or a scalar if the top-level policy wraps
+/// them.
+pub fn record(value: Value, extensions: &Extensions) -> Result<Record, DecodeError> {
+    match value {
+        Value::Map(map) => Ok(Record::from(try!(object(map, extensions)))),
+        Value::Array(..) => Err(not_map()),
+        value => {
+            if extensions.top_level == TopLevel::Skip {
+                return Err(not_map());
             }
-            _ => unimplemented!(),
+            wrap(try!(item(value, extensions)))
         }
     }
 }
 
-impl From<Value> for RecordItem {
-    fn from(v: Value) -> RecordItem {
-        match v {
-            Value::Integer(Integer::I64(v)) => RecordItem::F64(v as f64),
-            Value::Integer(Integer::U64(v)) => RecordItem::F64(v as f64),
-            Value::String(v) => RecordItem::String(v),
-            Value::Map(v) => {
-                let mut res = HashMap::new();
-                for (k, v) in v {
-                    let k = match k {
-                        Value::String(v) => v,
-                        _ => unimplemented!(),
-                    };
+/// Wraps the top-level scalar into the record of the single `message` field.
+fn wrap(item: Option<RecordItem>) -> Result<Record, DecodeError> {
+    match item {
+        Some(item) => {
+            let mut record = Record::new();
+            record.insert("message".to_string(), item);
+            Ok(record)
+        }
+        None => Err(DecodeError::Unsupported("top-level value is a skipped extension".to_string())),
+    }
+}
+
+fn not_map() -> DecodeError {
+    DecodeError::Unsupported("top-level value must be a map".to_string())
+}
+
+fn object(map: Vec<(Value, Value)>, extensions: &Extensions) -> Result<HashMap<String, RecordItem>, DecodeError> {
+    let mut res = HashMap::new();
+    for (key, val) in map {
+        let key = match key {
+            Value::String(v) => v,
+            key => return Err(DecodeError::Unsupported(format!("map key must be a string, found {:?}", key))),
+        };
+
+        if let Some(val) = try!(item(val, extensions)) {
+            res.insert(key, val);
+        }
+    }
+    Ok(res)
+}
+
+/// Converts the decoded value into a record value, returning `None` for values skipped by the
+/// extension policy.
+fn item(v: Value, extensions: &Extensions) -> Result<Option<RecordItem>, DecodeError> {
+    let item = match v {
+        Value::Integer(Integer::I64(v)) => RecordItem::I64(v),
+        Value::Integer(Integer::U64(v)) => numeric::integer(v),
+        Value::Nil => RecordItem::Null,
+        Value::Boolean(v) => RecordItem::Bool(v),
+        Value::Float(Float::F32(v)) => RecordItem::F64(v as f64),
+        Value::Float(Float::F64(v)) => RecordItem::F64(v),
+        Value::String(v) => RecordItem::String(v),
+        Value::Binary(v) => RecordItem::Bytes(v),
+        Value::Array(v) => {
+            let mut items = Vec::with_capacity(v.len());
+            for v in v {
+                items.extend(try!(item(v, extensions)).into_iter());
+            }
+            RecordItem::Array(items)
+        }
+        Value::Map(v) => RecordItem::Object(try!(object(v, extensions))),
+        Value::Ext(ty, data) => return extension(ty, &data, extensions),
+    };
+
+    Ok(Some(item))
+}
+
+/// Converts the extension value of the given type, returning `None` if it is skipped.
+///
+/// Timestamps are 32-bit seconds, 34-bit seconds with 30-bit nanoseconds or 32-bit nanoseconds
+/// with signed 64-bit seconds, see the MessagePack specification.
+fn extension(ty: i8, data: &[u8], extensions: &Extensions) -> Result<Option<RecordItem>, DecodeError> {
+    if ty != TIMESTAMP {
+        return match extensions.unknown {
+            Unknown::Bytes => {
+                let mut bytes = Vec::with_capacity(1 + data.len());
+                bytes.push(ty as u8);
+                bytes.extend(data.iter().cloned());
+                Ok(Some(RecordItem::Bytes(bytes)))
+            }
+            Unknown::Skip => {
+                warn!(target: "Codec::MessagePack", "skipping value of extension type {}", ty);
+                Ok(None)
+            }
+        };
+    }
+
+    let (secs, nanos) = match data.len() {
+        4 => (big_endian(data) as i64, 0),
+        8 => {
+            let value = big_endian(data);
+            ((value & 0x3_ffff_ffff) as i64, (value >> 34) as u32)
+        }
+        12 => (big_endian(&data[4..]) as i64, big_endian(&data[..4]) as u32),
+        len => return Err(DecodeError::Unsupported(format!("timestamp extension of {} bytes", len))),
+    };
+
+    if nanos >= 1_000_000_000 {
+        return Err(DecodeError::Unsupported(format!("timestamp nanoseconds {} out of range", nanos)));
+    }
 
-                    let v = From::from(v);
+    let item = match extensions.timestamps {
+        Timestamps::Epoch => RecordItem::F64(secs as f64 + nanos as f64 / 1e9),
+        Timestamps::Iso => {
+            let time = match NaiveDateTime::from_timestamp_opt(secs, nanos) {
+                Some(time) => time,
+                None => return Err(DecodeError::Unsupported(format!("timestamp {} out of range", secs))),
+            };
+
+            if nanos == 0 {
+                RecordItem::String(time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            } else {
+                RecordItem::String(format!("{}.{:09}Z", time.format("%Y-%m-%dT%H:%M:%S"), nanos))
+            }
+        }
+    };
+
+    Ok(Some(item))
+}
+
+/// Maximum nesting of arrays and maps accepted from untrusted input.
+const MAX_DEPTH: usize = 128;
+
+/// Returns the length of the first value in the buffer.
+///
+/// Every declared length is checked against the bytes available, so decoding the value
+/// afterwards never allocates more than the buffer holds nor recurses too deep.
+fn scan(buf: &[u8], depth: usize) -> Result<usize, DecodeError> {
+    let mut wanted = 0;
+    measure(buf, depth, &mut wanted)
+}
+
+/// Returns the length of the first value in the buffer like `scan`, setting the number of bytes
+/// the value takes at least if it's truncated.
+///
+/// Nested values not buffered yet are counted as a byte each, the least they take.
+fn measure(buf: &[u8], depth: usize, wanted: &mut usize) -> Result<usize, DecodeError> {
+    *wanted = buf.len() + 1;
+    if depth > MAX_DEPTH {
+        return Err(DecodeError::Syntax("nesting is too deep".to_string()));
+    }
+
+    let marker = match buf.first() {
+        Some(&marker) => marker,
+        None => return Err(truncated()),
+    };
+
+    let (header, len, items) = try!(layout(marker, |width| size(buf, width)));
+
+    let mut pos = header + len;
+    if pos > buf.len() {
+        *wanted = pos + items;
+        return Err(truncated());
+    }
+
+    for item in 0..items {
+        let mut nested = 0;
+        match measure(&buf[pos..], depth + 1, &mut nested) {
+            Ok(len) => pos += len,
+            Err(err) => {
+                *wanted = pos + nested + (items - item - 1);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(pos)
+}
+
+/// Returns the header length, the payload length and the number of nested values of the value
+/// starting with the marker, reading lengths of the given width following the marker through the
+/// callback.
+fn layout<F>(marker: u8, mut size: F) -> Result<(usize, usize, usize), DecodeError>
+    where F: FnMut(usize) -> Result<usize, DecodeError>
+{
+    let layout = match marker {
+        0x00...0x7f | 0xe0...0xff | 0xc0 | 0xc2 | 0xc3 => (1, 0, 0),
+        0x80...0x8f => (1, 0, 2 * (marker & 0x0f) as usize),
+        0x90...0x9f => (1, 0, (marker & 0x0f) as usize),
+        0xa0...0xbf => (1, (marker & 0x1f) as usize, 0),
+        0xc4 | 0xd9 => (2, try!(size(1)), 0),
+        0xc5 | 0xda => (3, try!(size(2)), 0),
+        0xc6 | 0xdb => (5, try!(size(4)), 0),
+        0xc7 => (3, try!(size(1)), 0),
+        0xc8 => (4, try!(size(2)), 0),
+        0xc9 => (6, try!(size(4)), 0),
+        0xca => (5, 0, 0),
+        0xcb => (9, 0, 0),
+        0xcc | 0xd0 => (2, 0, 0),
+        0xcd | 0xd1 => (3, 0, 0),
+        0xce | 0xd2 => (5, 0, 0),
+        0xcf | 0xd3 => (9, 0, 0),
+        0xd4 => (3, 0, 0),
+        0xd5 => (4, 0, 0),
+        0xd6 => (6, 0, 0),
+        0xd7 => (10, 0, 0),
+        0xd8 => (18, 0, 0),
+        0xdc => (3, 0, try!(size(2))),
+        0xdd => (5, 0, try!(size(4))),
+        0xde => (3, 0, 2 * try!(size(2))),
+        0xdf => (5, 0, 2 * try!(size(4))),
+        _ => return Err(DecodeError::Syntax(format!("reserved marker {:#x}", marker))),
+    };
+
+    Ok(layout)
+}
+
+/// Reads the big-endian length of the given width following the marker.
+fn size(buf: &[u8], width: usize) -> Result<usize, DecodeError> {
+    if buf.len() < 1 + width {
+        return Err(truncated());
+    }
+
+    Ok(buf[1..1 + width].iter().fold(0, |acc, &b| acc << 8 | b as usize))
+}
+
+/// Reads the marker of the next value, returning `None` at the end of the stream.
+fn next_marker(rd: &mut Read) -> Result<Option<u8>, DecodeError> {
+    let mut byte = [0u8];
+    match rd.read(&mut byte) {
+        Ok(0) => Ok(None),
+        Ok(..) => Ok(Some(byte[0])),
+        Err(err) => Err(DecodeError::Syntax(err.to_string())),
+    }
+}
+
+/// Reads exactly the given number of bytes, appending them to the buffer.
+fn take(rd: &mut Read, len: usize, buf: &mut Vec<u8>) -> Result<(), DecodeError> {
+    match Read::take(&mut *rd, len as u64).read_to_end(buf) {
+        Ok(count) if count == len => Ok(()),
+        Ok(..) => Err(truncated()),
+        Err(err) => Err(DecodeError::Syntax(err.to_string())),
+    }
+}
+
+/// Reads the rest of the header of the value starting with the marker into the buffer, returning
+/// the payload length and the number of nested values.
+fn header(marker: u8, rd: &mut Read, head: &mut Vec<u8>) -> Result<(usize, usize), DecodeError> {
+    let (header, len, items) = try!(layout(marker, |width| {
+        try!(take(rd, width, head));
+        Ok(head[head.len() - width..].iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }));
+
+    try!(take(rd, header - head.len(), head));
+    Ok((len, items))
+}
+
+/// Moves the value starting with the already read marker out of the stream, appending its bytes
+/// to the buffer if kept or dropping them otherwise.
+///
+/// Nothing but headers is looked at, so skipped values are never materialized.
+fn pass(marker: u8, rd: &mut Read, buf: &mut Vec<u8>, keep: bool, depth: usize) -> Result<(), DecodeError> {
+    if depth > MAX_DEPTH {
+        return Err(DecodeError::Syntax("nesting is too deep".to_string()));
+    }
 
-                    res.insert(k, v);
+    let mut head = vec![marker];
+    let (len, items) = try!(header(marker, rd, &mut head));
+
+    if keep {
+        buf.extend(head.into_iter());
+        try!(take(rd, len, buf));
+    } else {
+        let skipped = try!(io::copy(&mut Read::take(&mut *rd, len as u64), &mut io::sink())
+            .map_err(|err| DecodeError::Syntax(err.to_string())));
+        if skipped != len as u64 {
+            return Err(truncated());
+        }
+    }
+
+    for _ in 0..items {
+        let marker = try!(try!(next_marker(rd)).ok_or_else(truncated));
+        try!(pass(marker, rd, buf, keep, depth + 1));
+    }
+
+    Ok(())
+}
+
+/// Decodes a value previously moved into the buffer by `pass`.
+fn value(buf: &[u8]) -> Result<Value, DecodeError> {
+    let mut rd = buf;
+    read_value(&mut rd).map_err(|err| DecodeError::Syntax(format!("{:?}", err)))
+}
+
+/// Reads the next record from the stream, building only fields the projection keeps.
+///
+/// Values not representable as records are consumed entirely before failing, so the stream stays
+/// in sync. Returns `None` once the stream is over.
+fn project(rd: &mut Read, projection: &Projection, extensions: &Extensions) -> Result<Option<Record>, DecodeError> {
+    let marker = match try!(next_marker(rd)) {
+        Some(marker) => marker,
+        None => return Ok(None),
+    };
+
+    match marker {
+        0x80...0x8f | 0xde | 0xdf => {}
+        0x90...0x9f | 0xdc | 0xdd => {
+            try!(pass(marker, rd, &mut Vec::new(), false, 0));
+            return Err(not_map());
+        }
+        _ => {
+            let wrapped = extensions.top_level == TopLevel::Wrap;
+            let mut buf = Vec::new();
+            try!(pass(marker, rd, &mut buf, wrapped, 0));
+            if !wrapped {
+                return Err(not_map());
+            }
+            let record = try!(wrap(try!(item(try!(value(&buf)), extensions))));
+            return Ok(Some(projection.apply(record)));
+        }
+    }
+
+    let (_, items) = try!(header(marker, rd, &mut vec![marker]));
+
+    let mut record = Record::new();
+    let mut unsupported = None;
+    let mut buf = Vec::new();
+    for _ in 0..items / 2 {
+        buf.clear();
+        let marker = try!(try!(next_marker(rd)).ok_or_else(truncated));
+        try!(pass(marker, rd, &mut buf, true, 1));
+
+        let key = match try!(value(&buf)) {
+            Value::String(key) => Some(key),
+            key => {
+                unsupported = Some(DecodeError::Unsupported(format!("map key must be a string, found {:?}", key)));
+                None
+            }
+        };
+
+        buf.clear();
+        let marker = try!(try!(next_marker(rd)).ok_or_else(truncated));
+        match key {
+            Some(key) if unsupported.is_none() && projection.keeps(&key) => {
+                try!(pass(marker, rd, &mut buf, true, 1));
+                match item(try!(value(&buf)), extensions) {
+                    Ok(Some(item)) => {
+                        record.insert(key, item);
+                    }
+                    Ok(None) => {}
+                    Err(err) => unsupported = Some(err),
                 }
-                RecordItem::Object(res)
             }
-            _ => unimplemented!(),
+            _ => try!(pass(marker, rd, &mut buf, false, 1)),
         }
     }
+
+    match unsupported {
+        Some(err) => Err(err),
+        None => Ok(Some(record)),
+    }
+}
+
+fn truncated() -> DecodeError {
+    DecodeError::Syntax("unexpected end of data".to_string())
+}
+
+impl<'a> From<&'a RecordItem> for Value {
+    fn from(item: &'a RecordItem) -> Value {
+        to_value(item, false)
+    }
+}
+
+/// Converts the item, keys of every map being ordered by name if requested.
+fn to_value(item: &RecordItem, ordered: bool) -> Value {
+    match *item {
+        RecordItem::Null => Value::Nil,
+        RecordItem::Bool(v) => Value::Boolean(v),
+        RecordItem::F64(v) => Value::Float(Float::F64(v)),
+        RecordItem::I64(v) => Value::Integer(Integer::I64(v)),
+        RecordItem::U64(v) => Value::Integer(Integer::U64(v)),
+        RecordItem::String(ref v) => Value::String(v.clone()),
+        RecordItem::Text(ref v) => Value::String(v.as_str().to_string()),
+        RecordItem::Bytes(ref v) => Value::Binary(v.clone()),
+        RecordItem::Array(ref v) => Value::Array(v.iter().map(|item| to_value(item, ordered)).collect()),
+        RecordItem::Object(ref v) if ordered => map(sorted(v).into_iter(), true),
+        RecordItem::Object(ref v) => map(v.iter(), false),
+    }
+}
+
+/// Converts fields into a map, keeping their order.
+fn map<'a, I>(pairs: I, ordered: bool) -> Value
+    where I: Iterator<Item=(&'a String, &'a RecordItem)>
+{
+    Value::Map(pairs.map(|(k, v)| (Value::String(k.clone()), to_value(v, ordered))).collect())
+}
+
+/// Encodes the record as a single MessagePack map, keys being written in the hash order.
+pub fn encode(record: &Record) -> Vec<u8> {
+    encode_record(record, false)
+}
+
+/// Encodes the record like `encode` does, keys of every map being ordered by name, so that equal
+/// records always encode into the same bytes, e.g. in the deterministic mode.
+pub fn encode_sorted(record: &Record) -> Vec<u8> {
+    encode_record(record, true)
+}
+
+/// Encodes the record, keys of every map being ordered by name if requested.
+pub fn encode_record(record: &Record, ordered: bool) -> Vec<u8> {
+    let value = if ordered {
+        map(record.sorted().into_iter(), true)
+    } else {
+        map(record.iter(), false)
+    };
+
+    let mut buf = Vec::new();
+    write_value(&mut buf, &value).unwrap();
+    buf
+}
+
+/// Decodes a single record previously produced by `encode`.
+pub fn decode(buf: &[u8]) -> Option<Record> {
+    MessagePack::default().decode_one(buf).ok().and_then(|record| record)
 }
 
 impl Iterator for Iter {
     type Item = Record;
 
+    /// Returns the next record, skipping values not representable as records, until the stream
+    /// ends or turns out to be malformed.
+    ///
+    /// Each value is checked by `scan` before being decoded, so malformed input never makes the
+    /// decoder recurse too deep nor allocate for lengths not backed by actual bytes.
     fn next(&mut self) -> Option<Record> {
-        let val = read_value(&mut self.rd).unwrap();
+        loop {
+            let result = match self.values.next() {
+                Ok(Some(buf)) => value(buf).and_then(|value| record(value, &self.extensions)),
+                Ok(None) | Err(..) => return None,
+            };
+
+            match result {
+                Ok(record) => return Some(record),
+                Err(err @ DecodeError::Unsupported(..)) => warn!(target: "Codec::MessagePack", "skipping value: {}", err),
+                Err(..) => return None,
+            }
+        }
+    }
+}
+
+/// Iterator over records with fields not kept by the projection skipped unread.
+pub struct ProjectedIter {
+    rd: Box<Read>,
+    projection: Arc<Projection>,
+    extensions: Extensions,
+}
+
+impl Iterator for ProjectedIter {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            match project(&mut *self.rd, &self.projection, &self.extensions) {
+                Ok(record) => return record,
+                Err(err @ DecodeError::Unsupported(..)) => warn!(target: "Codec::MessagePack", "skipping value: {}", err),
+                Err(..) => return None,
+            }
+        }
+    }
+}
+
+/// Decodes the value at the position of the record buffer, moving the position past it and
+/// keeping strings in the buffer. Returns `None` for values skipped by the extension policy.
+///
+/// The buffer must have been checked either by `scan` or by `pass`, so all declared lengths are
+/// within it and nesting is limited.
+fn shared(buf: &Arc<Vec<u8>>, pos: &mut usize, extensions: &Extensions) -> Result<Option<RecordItem>, DecodeError> {
+    let at = *pos;
+    let marker = buf[at];
+    let (header, len, items) = try!(layout(marker, |width| size(&buf[at..], width)));
+    let payload = at + header;
+    *pos = payload + len;
+
+    let fixed = big_endian(&buf[at + 1..payload]);
+    let item = match marker {
+        0x00...0x7f => RecordItem::I64(marker as i64),
+        0xe0...0xff => RecordItem::I64(marker as i8 as i64),
+        0xc0 => RecordItem::Null,
+        0xc2 => RecordItem::Bool(false),
+        0xc3 => RecordItem::Bool(true),
+        0xca => RecordItem::F64(unsafe { mem::transmute::<u32, f32>(fixed as u32) } as f64),
+        0xcb => RecordItem::F64(unsafe { mem::transmute::<u64, f64>(fixed) }),
+        0xcc...0xcf => numeric::integer(fixed),
+        0xd0 => RecordItem::I64(fixed as u8 as i8 as i64),
+        0xd1 => RecordItem::I64(fixed as u16 as i16 as i64),
+        0xd2 => RecordItem::I64(fixed as u32 as i32 as i64),
+        0xd3 => RecordItem::I64(fixed as i64),
+        0xa0...0xbf | 0xd9...0xdb => {
+            let text = try!(Text::new(buf.clone(), payload, payload + len)
+                .map_err(|err| DecodeError::Syntax(format!("invalid UTF-8: {}", err))));
+            RecordItem::Text(text)
+        }
+        0xc4...0xc6 => RecordItem::Bytes(buf[payload..payload + len].to_vec()),
+        0x90...0x9f | 0xdc | 0xdd => {
+            let mut array = Vec::with_capacity(items);
+            for _ in 0..items {
+                array.extend(try!(shared(buf, pos, extensions)).into_iter());
+            }
+            RecordItem::Array(array)
+        }
+        0x80...0x8f | 0xde | 0xdf => {
+            let mut object = HashMap::with_capacity(items / 2);
+            for _ in 0..items / 2 {
+                let key = match try!(shared(buf, pos, extensions)) {
+                    Some(RecordItem::Text(key)) => key.as_str().to_string(),
+                    key => return Err(DecodeError::Unsupported(format!("map key must be a string, found {:?}", key))),
+                };
+                if let Some(value) = try!(shared(buf, pos, extensions)) {
+                    object.insert(key, value);
+                }
+            }
+            RecordItem::Object(object)
+        }
+        0xc7...0xc9 => return extension(buf[payload - 1] as i8, &buf[payload..payload + len], extensions),
+        // Fixed-size extension data is part of the header.
+        _ => return extension(buf[at + 1] as i8, &buf[at + 2..payload], extensions),
+    };
+
+    Ok(Some(item))
+}
+
+fn big_endian(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+/// Converts the record buffer into a record referring to it.
+fn shared_record(buf: Arc<Vec<u8>>, extensions: &Extensions) -> Result<Record, DecodeError> {
+    let mut pos = 0;
+    match try!(shared(&buf, &mut pos, extensions)) {
+        Some(RecordItem::Object(object)) => Ok(Record::from(object)),
+        Some(RecordItem::Array(..)) => Err(not_map()),
+        item => {
+            if extensions.top_level == TopLevel::Skip {
+                return Err(not_map());
+            }
+            wrap(item)
+        }
+    }
+}
+
+/// Indexes top-level fields of the record buffer checked by `scan` for decoding them lazily,
+/// leaving out fields the projection doesn't keep, if any.
+///
+/// Returns `None` unless the record would decode lazily exactly as it does eagerly, i.e. for
+/// values other than maps, maps with duplicate keys anywhere and anything decoding fails on. Such
+/// buffers are decoded eagerly, failing the same way.
+pub fn index(buf: Arc<Vec<u8>>, extensions: &Extensions, shared: bool, projection: Option<&Projection>) -> Option<Lazy> {
+    let fields = {
+        let marker = match buf.first() {
+            Some(&marker) => marker,
+            None => return None,
+        };
+        match marker {
+            0x80...0x8f | 0xde | 0xdf => {}
+            _ => return None,
+        }
+
+        let (header, _, items) = match layout(marker, |width| size(&buf, width)) {
+            Ok(layout) => layout,
+            Err(..) => return None,
+        };
+
+        let mut pos = header;
+        let mut names = HashSet::with_capacity(items / 2);
+        let mut fields = Vec::with_capacity(items / 2);
+        for _ in 0..items / 2 {
+            let name = match key(&buf, &mut pos) {
+                Ok(name) => name,
+                Err(..) => return None,
+            };
+            if !names.insert(&buf[name.0..name.1]) {
+                return None;
+            }
+
+            let start = pos;
+            let mut finite = true;
+            let hint = match measure(&buf, &mut pos, extensions, &mut finite) {
+                Ok(hint) => hint,
+                Err(..) => return None,
+            };
+
+            let kept = match projection {
+                Some(projection) => projection.keeps(str::from_utf8(&buf[name.0..name.1]).unwrap()),
+                None => true,
+            };
+            if let (Some(hint), true) = (hint, kept) {
+                fields.push(Field {
+                    name: name,
+                    value: (start, pos),
+                    hint: hint,
+                    finite: finite,
+                });
+            }
+        }
+        fields
+    };
+
+    Some(Lazy::new(buf, Format::MessagePack(*extensions, shared), fields))
+}
+
+/// Moves the position past the map key, which must be a string, returning its byte range.
+fn key(buf: &[u8], pos: &mut usize) -> Result<(usize, usize), DecodeError> {
+    let at = *pos;
+    let marker = buf[at];
+    match marker {
+        0xa0...0xbf | 0xd9...0xdb => {}
+        _ => return Err(DecodeError::Unsupported(format!("map key must be a string, found marker {:#x}", marker))),
+    }
+
+    let (header, len, _) = try!(layout(marker, |width| size(&buf[at..], width)));
+    let range = (at + header, at + header + len);
+    try!(str::from_utf8(&buf[range.0..range.1]).map_err(|err| DecodeError::Syntax(format!("invalid UTF-8: {}", err))));
+    *pos = range.1;
+    Ok(range)
+}
+
+/// Moves the position past the value, checking it the way `item` converts it, and returns its
+/// size hint, or `None` if the extension policy skips it. Clears the flag on non-finite floats.
+///
+/// The buffer must have been checked by `scan`.
+fn measure(buf: &[u8], pos: &mut usize, extensions: &Extensions, finite: &mut bool) -> Result<Option<usize>, DecodeError> {
+    let at = *pos;
+    let marker = buf[at];
+    let (header, len, items) = try!(layout(marker, |width| size(&buf[at..], width)));
+    let payload = at + header;
+    *pos = payload + len;
+
+    let hint = match marker {
+        0xc0 | 0xc2 | 0xc3 => 1,
+        0xca => {
+            let value = unsafe { mem::transmute::<u32, f32>(big_endian(&buf[at + 1..payload]) as u32) };
+            *finite = *finite && value.is_finite();
+            9
+        }
+        0xcb => {
+            let value = unsafe { mem::transmute::<u64, f64>(big_endian(&buf[at + 1..payload])) };
+            *finite = *finite && value.is_finite();
+            9
+        }
+        0x00...0x7f | 0xe0...0xff | 0xcc...0xd3 => 9,
+        0xa0...0xbf | 0xd9...0xdb => {
+            try!(str::from_utf8(&buf[payload..payload + len]).map_err(|err| DecodeError::Syntax(format!("invalid UTF-8: {}", err))));
+            len + 5
+        }
+        0xc4...0xc6 => len + 5,
+        0x90...0x9f | 0xdc | 0xdd => {
+            let mut hint = 5;
+            for _ in 0..items {
+                hint += try!(measure(buf, pos, extensions, finite)).unwrap_or(0);
+            }
+            hint
+        }
+        0x80...0x8f | 0xde | 0xdf => {
+            let mut names = HashSet::with_capacity(items / 2);
+            let mut hint = 5;
+            for _ in 0..items / 2 {
+                let name = try!(key(buf, pos));
+                if !names.insert(&buf[name.0..name.1]) {
+                    return Err(DecodeError::Unsupported("duplicate map key".to_string()));
+                }
+                if let Some(value) = try!(measure(buf, pos, extensions, finite)) {
+                    hint += name.1 - name.0 + 5 + value;
+                }
+            }
+            hint
+        }
+        0xc7...0xc9 => {
+            let item = try!(extension(buf[payload - 1] as i8, &buf[payload..payload + len], extensions));
+            return Ok(item.map(|item| item.size_hint()));
+        }
+        // Fixed-size extension data is part of the header.
+        _ => {
+            let item = try!(extension(buf[at + 1] as i8, &buf[at + 2..payload], extensions));
+            return Ok(item.map(|item| item.size_hint()));
+        }
+    };
+
+    Ok(Some(hint))
+}
+
+/// Decodes the value at the byte range of the record buffer indexed by `index`, which never
+/// fails, as the value is checked while indexing.
+pub fn field(buf: &Arc<Vec<u8>>, range: (usize, usize), extensions: &Extensions, shared: bool) -> RecordItem {
+    let item = if shared {
+        let mut pos = range.0;
+        self::shared(buf, &mut pos, extensions)
+    } else {
+        value(&buf[range.0..range.1]).and_then(|value| item(value, extensions))
+    };
+
+    item.ok().and_then(|item| item).expect("field must be checked while indexing")
+}
+
+/// Iterator over records decoding their fields on access, the ones unable to be indexed being
+/// decoded eagerly.
+pub struct LazyIter {
+    values: Values,
+    extensions: Extensions,
+    shared: bool,
+    projection: Option<Arc<Projection>>,
+}
+
+impl Iterator for LazyIter {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let buf = match self.values.next() {
+                Ok(Some(buf)) => Arc::new(buf.to_vec()),
+                Ok(None) | Err(..) => return None,
+            };
+
+            if let Some(lazy) = index(buf.clone(), &self.extensions, self.shared, self.projection.as_ref().map(|projection| &**projection)) {
+                return Some(Record::lazy(lazy));
+            }
+
+            let result = if self.shared {
+                shared_record(buf, &self.extensions)
+            } else {
+                value(&buf).and_then(|value| record(value, &self.extensions))
+            };
+
+            match result {
+                Ok(record) => {
+                    return match self.projection {
+                        Some(ref projection) => Some(projection.apply(record)),
+                        None => Some(record),
+                    };
+                }
+                Err(err @ DecodeError::Unsupported(..)) => warn!(target: "Codec::MessagePack", "skipping value: {}", err),
+                Err(err) => {
+                    // Shared strings are decoded from checked buffers, so the stream is still in sync.
+                    if !self.shared {
+                        return None;
+                    }
+                    warn!(target: "Codec::MessagePack", "skipping value: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over records and their buffers, each record sharing its buffer between string
+/// values.
+pub struct SharedIter {
+    values: Values,
+    extensions: Extensions,
+}
+
+impl Iterator for SharedIter {
+    type Item = (Record, Arc<Vec<u8>>);
 
-        Some(From::from(val))
+    fn next(&mut self) -> Option<(Record, Arc<Vec<u8>>)> {
+        loop {
+            let buf = match self.values.next() {
+                Ok(Some(buf)) => Arc::new(buf.to_vec()),
+                Ok(None) | Err(..) => return None,
+            };
+
+            match shared_record(buf.clone(), &self.extensions) {
+                Ok(record) => return Some((record, buf)),
+                Err(err) => warn!(target: "Codec::MessagePack", "skipping value: {}", err),
+            }
+        }
+    }
+}
+
+/// Iterator over records and values failing to decode, see `Codec::decode_checked`.
+///
+/// Malformed streams end with the bytes left unread, as the next value can't be found in them.
+pub struct CheckedIter {
+    values: Values,
+    extensions: Extensions,
+    shared: bool,
+    broken: bool,
+}
+
+impl Iterator for CheckedIter {
+    type Item = Result<Record, Malformed>;
+
+    fn next(&mut self) -> Option<Result<Record, Malformed>> {
+        if self.broken {
+            return None;
+        }
+
+        let err = match self.values.next() {
+            Ok(Some(buf)) => {
+                let result = if self.shared {
+                    shared_record(Arc::new(buf.to_vec()), &self.extensions)
+                } else {
+                    value(buf).and_then(|value| record(value, &self.extensions))
+                };
+
+                return match result {
+                    Ok(record) => Some(Ok(record)),
+                    Err(err) => {
+                        // Unlike owned ones, shared strings are decoded from checked buffers only.
+                        if let (false, &DecodeError::Syntax(..)) = (self.shared, &err) {
+                            self.broken = true;
+                        }
+                        Some(Err(Malformed { raw: buf.to_vec(), error: err }))
+                    }
+                };
+            }
+            Ok(None) => return None,
+            Err(err) => err,
+        };
+
+        self.broken = true;
+        Some(Err(Malformed { raw: self.values.pending(), error: err }))
+    }
+}
+
+/// Iterator over records and the bytes they were decoded from.
+pub struct RawIter {
+    values: Values,
+    extensions: Extensions,
+}
+
+impl Iterator for RawIter {
+    type Item = (Record, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Record, Vec<u8>)> {
+        loop {
+            let buf = match self.values.next() {
+                Ok(Some(buf)) => buf.to_vec(),
+                Ok(None) | Err(..) => return None,
+            };
+
+            match value(&buf).and_then(|value| record(value, &self.extensions)) {
+                Ok(record) => return Some((record, buf)),
+                Err(err @ DecodeError::Unsupported(..)) => warn!(target: "Codec::MessagePack", "skipping value: {}", err),
+                Err(..) => return None,
+            }
+        }
+    }
+}
+
+impl FromConfig for MessagePack {
+    fn from_config(config: &Config) -> Result<MessagePack, Error> {
+        Ok(MessagePack::new(try!(Extensions::from_config(config))))
+    }
+}
+
+/// Builds the MessagePack codec with either `owned` or `shared` strings, the former being the
+/// default, as filters replacing string values gain nothing from shared ones.
+///
+/// Timestamp extension values are decoded as `epoch` seconds or `iso` strings, while values of
+/// other extension types are kept as `bytes` or `skip`ped, see `Extensions`. Top-level scalars
+/// are `skip`ped or `wrap`ped into records, see `TopLevel`. Fields are decoded either `eager`ly or
+/// `lazy` on access, see `lazy`.
+pub fn factory(config: &Config) -> Result<Box<Codec>, Error> {
+    let extensions = try!(Extensions::from_config(config));
+    let lazy = match &try!(config.string_or("decode", "eager"))[..] {
+        "eager" => false,
+        "lazy" => true,
+        decode => return Err(Error::Invalid(format!("unknown decode mode '{}'", decode))),
+    };
+
+    match &try!(config.string_or("strings", "owned"))[..] {
+        "owned" => Ok(Box::new(MessagePack::new(extensions).lazy(lazy))),
+        "shared" => Ok(Box::new(SharedMessagePack::new(extensions).lazy(lazy))),
+        strings => Err(Error::Invalid(format!("unknown string representation '{}'", strings))),
     }
 }
 
@@ -87,6 +1122,95 @@ impl Codec for MessagePack {
     }
 
     fn decode(&self, rd: Box<Read>) -> Box<Iterator<Item=Record>> {
-        Box::new(Iter::new(rd))
+        if self.lazy {
+            return Box::new(LazyIter { values: Values::new(rd), extensions: self.extensions, shared: false, projection: None });
+        }
+
+        Box::new(Iter::new(rd, self.extensions))
+    }
+
+    fn decode_raw(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=(Record, Vec<u8>)>>, Box<Read>> {
+        Ok(Box::new(RawIter { values: Values::new(rd), extensions: self.extensions }))
+    }
+
+    fn decode_checked(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=Result<Record, Malformed>>>, Box<Read>> {
+        Ok(Box::new(CheckedIter { values: Values::new(rd), extensions: self.extensions, shared: false, broken: false }))
+    }
+
+    fn decode_projected(&self, rd: Box<Read>, projection: Arc<Projection>) -> Box<Iterator<Item=Record>> {
+        if self.lazy {
+            return Box::new(LazyIter { values: Values::new(rd), extensions: self.extensions, shared: false, projection: Some(projection) });
+        }
+
+        Box::new(ProjectedIter {
+            rd: rd,
+            projection: projection,
+            extensions: self.extensions,
+        })
+    }
+
+    fn decode_one(&self, buf: &[u8]) -> Result<Option<Record>, DecodeError> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let mut rd = &buf[..try!(scan(buf, 0))];
+        let value = try!(read_value(&mut rd).map_err(|err| DecodeError::Syntax(format!("{:?}", err))));
+        record(value, &self.extensions).map(Some)
+    }
+
+    fn incomplete(&self, buf: &[u8]) -> bool {
+        scan(buf, 0) == Err(truncated())
+    }
+
+    fn text(&self) -> bool {
+        false
+    }
+}
+
+impl Codec for SharedMessagePack {
+    fn new(&self) -> Box<Codec> {
+        Box::new(self.clone())
+    }
+
+    fn decode(&self, rd: Box<Read>) -> Box<Iterator<Item=Record>> {
+        if self.lazy {
+            return Box::new(LazyIter { values: Values::new(rd), extensions: self.extensions, shared: true, projection: None });
+        }
+
+        Box::new(SharedIter { values: Values::new(rd), extensions: self.extensions }.map(|(record, _)| record))
+    }
+
+    fn decode_projected(&self, rd: Box<Read>, projection: Arc<Projection>) -> Box<Iterator<Item=Record>> {
+        if self.lazy {
+            return Box::new(LazyIter { values: Values::new(rd), extensions: self.extensions, shared: true, projection: Some(projection) });
+        }
+
+        Box::new(self.decode(rd).map(move |record| projection.apply(record)))
+    }
+
+    fn decode_raw(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=(Record, Vec<u8>)>>, Box<Read>> {
+        Ok(Box::new(SharedIter { values: Values::new(rd), extensions: self.extensions }.map(|(record, buf)| (record, (*buf).clone()))))
+    }
+
+    fn decode_checked(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=Result<Record, Malformed>>>, Box<Read>> {
+        Ok(Box::new(CheckedIter { values: Values::new(rd), extensions: self.extensions, shared: true, broken: false }))
+    }
+
+    fn decode_one(&self, buf: &[u8]) -> Result<Option<Record>, DecodeError> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let len = try!(scan(buf, 0));
+        shared_record(Arc::new(buf[..len].to_vec()), &self.extensions).map(Some)
+    }
+
+    fn incomplete(&self, buf: &[u8]) -> bool {
+        scan(buf, 0) == Err(truncated())
+    }
+
+    fn text(&self) -> bool {
+        false
     }
 }
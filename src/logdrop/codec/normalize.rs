@@ -0,0 +1,269 @@
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+
+use super::{Codec, DecodeError, Malformed};
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::validation::Validation;
+
+/// Which of the keys colliding after normalization keeps its value.
+///
+/// Records carry no key order, so first and last refer to the byte order of the original keys,
+/// e.g. `MESSAGE` comes before `Message`, which comes before `message`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Precedence {
+    First,
+    Last,
+}
+
+/// Key normalization rules, applied both to decoded records and to field names in the config.
+///
+/// Keys are lowercased, ASCII-only unless Unicode folding is enabled, and optionally have spaces
+/// and dashes replaced with underscores. Values are never touched. Nested object keys are
+/// normalized down to the depth limit, the top level being the first one.
+///
+/// Keys dropped because of a collision are listed in the `_keys_merged` field.
+#[derive(Clone, Debug)]
+pub struct Normalization {
+    underscores: bool,
+    unicode: bool,
+    precedence: Precedence,
+    depth: usize,
+}
+
+impl Normalization {
+    pub fn new(precedence: Precedence) -> Normalization {
+        Normalization {
+            underscores: false,
+            unicode: false,
+            precedence: precedence,
+            depth: 8,
+        }
+    }
+
+    /// Replaces spaces and dashes in keys with underscores.
+    pub fn underscores(mut self, underscores: bool) -> Normalization {
+        self.underscores = underscores;
+        self
+    }
+
+    /// Lowercases non-ASCII characters as well.
+    pub fn unicode(mut self, unicode: bool) -> Normalization {
+        self.unicode = unicode;
+        self
+    }
+
+    pub fn depth(mut self, depth: usize) -> Normalization {
+        self.depth = depth;
+        self
+    }
+
+    /// Returns the normalized form of the key.
+    pub fn key(&self, key: &str) -> String {
+        let key = if self.unicode {
+            key.to_lowercase()
+        } else {
+            key.to_ascii_lowercase()
+        };
+
+        if self.underscores {
+            key.chars().map(|c| if c == ' ' || c == '-' { '_' } else { c }).collect()
+        } else {
+            key
+        }
+    }
+
+    /// Returns the validation rules with required field names normalized.
+    pub fn validation(&self, validation: &Validation) -> Validation {
+        let required = validation.required.iter().map(|name| self.key(name)).collect();
+        Validation::new(required, validation.missing)
+    }
+
+    pub fn apply(&self, record: Record) -> Record {
+        let mut merged = Vec::new();
+        let mut record = Record::from(self.object(record.into_fields(), "", 1, &mut merged));
+        if !merged.is_empty() {
+            record.insert("_keys_merged".to_string(), RecordItem::Array(merged));
+        }
+        record
+    }
+
+    fn object(&self, object: HashMap<String, RecordItem>, path: &str, depth: usize, merged: &mut Vec<RecordItem>)
+        -> HashMap<String, RecordItem>
+    {
+        let mut pairs: Vec<(String, RecordItem)> = object.into_iter().collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        if self.precedence == Precedence::Last {
+            pairs.reverse();
+        }
+
+        let mut result = HashMap::new();
+        for (key, value) in pairs.into_iter() {
+            let name = self.key(&key);
+            if result.contains_key(&name) {
+                merged.push(RecordItem::String(format!("{}{}", path, key)));
+                continue;
+            }
+
+            let value = match value {
+                RecordItem::Object(nested) if depth < self.depth => {
+                    RecordItem::Object(self.object(nested, &format!("{}{}.", path, key), depth + 1, merged))
+                }
+                value => value,
+            };
+            result.insert(name, value);
+        }
+
+        result
+    }
+}
+
+impl FromConfig for Normalization {
+    fn from_config(config: &Config) -> Result<Normalization, Error> {
+        let precedence = match &try!(config.string_or("precedence", "first"))[..] {
+            "first" => Precedence::First,
+            "last" => Precedence::Last,
+            precedence => return Err(Error::Invalid(format!("unknown key precedence '{}'", precedence))),
+        };
+
+        Ok(Normalization::new(precedence)
+            .underscores(try!(config.bool_or("underscores", false)))
+            .unicode(try!(config.bool_or("unicode", false)))
+            .depth(try!(config.u64_or("depth", 8)) as usize))
+    }
+}
+
+/// Codec wrapper normalizing keys of every decoded record.
+pub struct Normalized {
+    codec: Box<Codec>,
+    normalization: Arc<Normalization>,
+}
+
+impl Normalized {
+    pub fn new(codec: Box<Codec>, normalization: Normalization) -> Normalized {
+        Normalized {
+            codec: codec,
+            normalization: Arc::new(normalization),
+        }
+    }
+}
+
+impl Codec for Normalized {
+    fn new(&self) -> Box<Codec> {
+        Box::new(Normalized {
+            codec: self.codec.new(),
+            normalization: self.normalization.clone(),
+        })
+    }
+
+    fn decode(&self, rd: Box<Read>) -> Box<Iterator<Item=Record>> {
+        let normalization = self.normalization.clone();
+        Box::new(self.codec.decode(rd).map(move |record| normalization.apply(record)))
+    }
+
+    fn decode_raw(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=(Record, Vec<u8>)>>, Box<Read>> {
+        let normalization = self.normalization.clone();
+        self.codec.decode_raw(rd).map(|records| {
+            let records = records.map(move |(record, raw)| (normalization.apply(record), raw));
+            Box::new(records) as Box<Iterator<Item=(Record, Vec<u8>)>>
+        })
+    }
+
+    fn decode_checked(&self, rd: Box<Read>) -> Result<Box<Iterator<Item=Result<Record, Malformed>>>, Box<Read>> {
+        let normalization = self.normalization.clone();
+        self.codec.decode_checked(rd).map(|results| {
+            let results = results.map(move |result| result.map(|record| normalization.apply(record)));
+            Box::new(results) as Box<Iterator<Item=Result<Record, Malformed>>>
+        })
+    }
+
+    fn decode_one(&self, buf: &[u8]) -> Result<Option<Record>, DecodeError> {
+        let record = try!(self.codec.decode_one(buf));
+        Ok(record.map(|record| self.normalization.apply(record)))
+    }
+
+    fn text(&self) -> bool {
+        self.codec.text()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::testing::{record, string};
+    use super::super::super::validation::{Missing, Validation, Verdict};
+    use super::super::{Codec, MessagePack};
+    use super::super::msgpack;
+    use super::{Normalization, Normalized, Precedence};
+
+    fn collision() -> Record {
+        record(vec![("MESSAGE", string("upper")), ("Message", string("title")), ("message", string("lower"))])
+    }
+
+    #[test]
+    fn first_key_wins_collision() {
+        let expected = record(vec![
+            ("message", string("upper")),
+            ("_keys_merged", RecordItem::Array(vec![string("Message"), string("message")])),
+        ]);
+        assert_eq!(expected, Normalization::new(Precedence::First).apply(collision()));
+    }
+
+    #[test]
+    fn last_key_wins_collision() {
+        let expected = record(vec![
+            ("message", string("lower")),
+            ("_keys_merged", RecordItem::Array(vec![string("Message"), string("MESSAGE")])),
+        ]);
+        assert_eq!(expected, Normalization::new(Precedence::Last).apply(collision()));
+    }
+
+    #[test]
+    fn normalize_nested_keys_down_to_depth() {
+        let mut deep = HashMap::new();
+        deep.insert("Kept".to_string(), string("As Is"));
+        let mut nested = HashMap::new();
+        nested.insert("Host".to_string(), string("Localhost"));
+        nested.insert("Deep".to_string(), RecordItem::Object(deep.clone()));
+
+        let mut expected = HashMap::new();
+        expected.insert("host".to_string(), string("Localhost"));
+        expected.insert("deep".to_string(), RecordItem::Object(deep));
+
+        let payload = record(vec![("Request", RecordItem::Object(nested))]);
+        let expected = record(vec![("request", RecordItem::Object(expected))]);
+        assert_eq!(expected, Normalization::new(Precedence::First).depth(2).apply(payload));
+    }
+
+    #[test]
+    fn replace_spaces_and_dashes() {
+        let normalization = Normalization::new(Precedence::First).underscores(true);
+        assert_eq!("user_agent_string", normalization.key("User-Agent String"));
+        assert_eq!("user-agent string", Normalization::new(Precedence::First).key("User-Agent String"));
+    }
+
+    #[test]
+    fn keep_non_ascii_key_in_ascii_mode() {
+        assert_eq!("Сообщение", Normalization::new(Precedence::First).key("Сообщение"));
+        assert_eq!("сообщение", Normalization::new(Precedence::First).unicode(true).key("Сообщение"));
+    }
+
+    #[test]
+    fn required_field_matches_differently_cased_key() {
+        let normalization = Normalization::new(Precedence::First);
+        let validation = normalization.validation(&Validation::new(vec!["Message".to_string()], Missing::Drop));
+        let codec = Normalized::new(Box::new(MessagePack::default()), normalization);
+
+        let payload = record(vec![("Message", string("le message"))]);
+        let data = msgpack::encode(&payload);
+        let records: Vec<Record> = codec.decode(Box::new(Cursor::new(data))).collect();
+
+        let expected = record(vec![("message", string("le message"))]);
+        assert_eq!(Verdict::Accept(expected), validation.check(records[0].clone()));
+    }
+}
@@ -0,0 +1,39 @@
+use std::collections::{HashMap, HashSet};
+
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+
+/// Top-level fields kept while decoding records, the rest being skipped without building values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Keeps only the listed fields.
+    Allow(HashSet<String>),
+    /// Keeps everything but the listed fields.
+    Deny(HashSet<String>),
+}
+
+impl Projection {
+    pub fn keeps(&self, key: &str) -> bool {
+        match *self {
+            Projection::Allow(ref names) => names.contains(key),
+            Projection::Deny(ref names) => !names.contains(key),
+        }
+    }
+
+    /// Drops fields not kept from the already decoded record.
+    pub fn apply(&self, record: Record) -> Record {
+        let fields: HashMap<String, RecordItem> = record.into_fields().into_iter().filter(|&(ref key, _)| self.keeps(key)).collect();
+        Record::from(fields)
+    }
+}
+
+/// Builds the projection out of either `{"allow": [...]}` or `{"deny": [...]}`.
+impl FromConfig for Projection {
+    fn from_config(config: &Config) -> Result<Projection, Error> {
+        match (config.find("allow"), config.find("deny")) {
+            (Some(..), None) => Ok(Projection::Allow(try!(config.strings_or("allow", Vec::new())).into_iter().collect())),
+            (None, Some(..)) => Ok(Projection::Deny(try!(config.strings_or("deny", Vec::new())).into_iter().collect())),
+            _ => Err(Error::Invalid("projection requires either 'allow' or 'deny'".to_string())),
+        }
+    }
+}
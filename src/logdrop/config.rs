@@ -0,0 +1,949 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::alert;
+use super::breaker;
+use super::capture::{Archive, Capture, Target};
+use super::codec::{Codec, Normalization, Normalized, Projection};
+use super::determinism;
+use super::encoding::Encoding;
+use super::filter;
+use super::filter::{Filter, Guard};
+use super::json;
+use super::json::Value;
+use super::lineage;
+use super::numeric::NonFinite;
+use super::output;
+use super::output::{Delivery, Output};
+use super::builder::PipelineBuilder;
+use super::pipeline::{Pipeline, Reload, Unavailable};
+use super::oversize;
+use super::poison;
+use super::queue;
+use super::quota;
+use super::quota::Quota;
+use super::registry;
+use super::retention;
+use super::retention::Retention;
+use super::sched;
+use super::snapshot;
+use super::source::Resolution;
+use super::spool::Spool;
+use super::validation::Validation;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Syntax(json::ParserError),
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "unable to read config: {}", err),
+            Error::Syntax(ref err) => write!(f, "invalid config syntax: {:?}", err),
+            Error::Invalid(ref reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+/// Components constructible from a configuration entry.
+pub trait FromConfig : Sized {
+    fn from_config(config: &Config) -> Result<Self, Error>;
+}
+
+/// Read-only view over a JSON configuration entry.
+#[derive(Copy, Clone, Debug)]
+pub struct Config<'a> {
+    value: &'a Value,
+}
+
+impl<'a> Config<'a> {
+    pub fn new(value: &'a Value) -> Config<'a> {
+        Config {
+            value: value,
+        }
+    }
+
+    pub fn find(&self, key: &str) -> Option<Config<'a>> {
+        self.value.find(key).map(Config::new)
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match *self.value {
+            Value::String(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the component type name: either the entry itself, if it's a string, or its `type`
+    /// field.
+    pub fn typename(&self) -> Result<String, Error> {
+        match self.as_str() {
+            Some(name) => Ok(name.to_string()),
+            None => self.string("type"),
+        }
+    }
+
+    pub fn string(&self, key: &str) -> Result<String, Error> {
+        match self.value.find(key) {
+            Some(&Value::String(ref v)) => Ok(v.clone()),
+            Some(..) => Err(mismatch(key, "string")),
+            None => Err(Error::Invalid(format!("'{}' field required", key))),
+        }
+    }
+
+    pub fn string_or(&self, key: &str, default: &str) -> Result<String, Error> {
+        match self.value.find(key) {
+            None => Ok(default.to_string()),
+            Some(..) => self.string(key),
+        }
+    }
+
+    pub fn f64_or(&self, key: &str, default: f64) -> Result<f64, Error> {
+        match self.value.find(key) {
+            Some(value) => value.as_f64().ok_or_else(|| mismatch(key, "number")),
+            None => Ok(default),
+        }
+    }
+
+    pub fn u64_or(&self, key: &str, default: u64) -> Result<u64, Error> {
+        match self.value.find(key) {
+            Some(value) => value.as_u64().ok_or_else(|| mismatch(key, "non-negative integer")),
+            None => Ok(default),
+        }
+    }
+
+    pub fn bool_or(&self, key: &str, default: bool) -> Result<bool, Error> {
+        match self.value.find(key) {
+            Some(&Value::Bool(v)) => Ok(v),
+            Some(..) => Err(mismatch(key, "boolean")),
+            None => Ok(default),
+        }
+    }
+
+    pub fn strings_or(&self, key: &str, default: Vec<String>) -> Result<Vec<String>, Error> {
+        match self.value.find(key) {
+            Some(&Value::List(ref list)) => {
+                let mut result = Vec::new();
+                for item in list.iter() {
+                    match *item {
+                        Value::String(ref v) => result.push(v.clone()),
+                        _ => return Err(mismatch(key, "list of strings")),
+                    }
+                }
+                Ok(result)
+            }
+            Some(..) => Err(mismatch(key, "list of strings")),
+            None => Ok(default),
+        }
+    }
+
+    pub fn numbers_or(&self, key: &str, default: Vec<f64>) -> Result<Vec<f64>, Error> {
+        match self.value.find(key) {
+            Some(&Value::List(ref list)) => {
+                let mut result = Vec::new();
+                for item in list.iter() {
+                    match item.as_f64() {
+                        Some(v) => result.push(v),
+                        None => return Err(mismatch(key, "list of numbers")),
+                    }
+                }
+                Ok(result)
+            }
+            Some(..) => Err(mismatch(key, "list of numbers")),
+            None => Ok(default),
+        }
+    }
+
+    /// Returns string to string mapping, empty if absent.
+    pub fn mapping(&self, key: &str) -> Result<HashMap<String, String>, Error> {
+        match self.value.find(key) {
+            Some(&Value::Object(ref object)) => {
+                let mut result = HashMap::new();
+                for (k, v) in object.iter() {
+                    match *v {
+                        Value::String(ref v) => { result.insert(k.clone(), v.clone()); }
+                        _ => return Err(mismatch(key, "mapping of strings")),
+                    }
+                }
+                Ok(result)
+            }
+            Some(..) => Err(mismatch(key, "mapping of strings")),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Returns string to non-negative integer mapping, empty if absent.
+    pub fn sizes(&self, key: &str) -> Result<HashMap<String, u64>, Error> {
+        match self.value.find(key) {
+            Some(&Value::Object(ref object)) => {
+                let mut result = HashMap::new();
+                for (k, v) in object.iter() {
+                    match v.as_u64() {
+                        Some(v) => { result.insert(k.clone(), v); }
+                        None => return Err(mismatch(key, "mapping of non-negative integers")),
+                    }
+                }
+                Ok(result)
+            }
+            Some(..) => Err(mismatch(key, "mapping of non-negative integers")),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Returns string to fraction mapping, with numbers in [0; 1], empty if absent.
+    pub fn fractions(&self, key: &str) -> Result<HashMap<String, f64>, Error> {
+        match self.value.find(key) {
+            Some(&Value::Object(ref object)) => {
+                let mut result = HashMap::new();
+                for (k, v) in object.iter() {
+                    match v.as_f64() {
+                        Some(v) if v >= 0.0 && v <= 1.0 => { result.insert(k.clone(), v); }
+                        _ => return Err(mismatch(key, "mapping of fractions")),
+                    }
+                }
+                Ok(result)
+            }
+            Some(..) => Err(mismatch(key, "mapping of fractions")),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Returns entries of the list under the given key, empty if absent.
+    pub fn list(&self, key: &str) -> Result<Vec<Config<'a>>, Error> {
+        match self.value.find(key) {
+            Some(&Value::List(ref list)) => Ok(list.iter().map(Config::new).collect()),
+            Some(..) => Err(mismatch(key, "list")),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+fn mismatch(key: &str, expected: &str) -> Error {
+    Error::Invalid(format!("'{}' field must be a {}", key, expected))
+}
+
+/// Reads and parses the pipeline configuration file.
+pub fn load(path: &Path) -> Result<Pipeline, Error> {
+    parse(&try!(read(path)))
+}
+
+/// Reads the pipeline configuration file and checks it without running anything.
+///
+/// Returns all problems found, see `check`.
+pub fn verify(path: &Path) -> Vec<Error> {
+    match read(path) {
+        Ok(content) => check(&content),
+        Err(err) => vec![err],
+    }
+}
+
+/// Builds the optional top-level section of the config, e.g. process-wide settings not making
+/// part of the pipeline.
+pub fn section<T: FromConfig>(content: &str, key: &str) -> Result<Option<T>, Error> {
+    let value = try!(json::from_str(content).map_err(Error::Syntax));
+    match Config::new(&value).find(key) {
+        Some(config) => T::from_config(&config).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Reads the configuration file content.
+pub fn read(path: &Path) -> Result<String, Error> {
+    let mut content = String::new();
+    try!(File::open(path).and_then(|mut file| file.read_to_string(&mut content)).map_err(Error::Io));
+    Ok(content)
+}
+
+/// Builds the pipeline from its JSON description.
+///
+/// Components are looked up by their `type` field in the registry. An optional `elastic` section
+/// makes the main and output channels spill to the disk under pressure, while an optional `unavailable` one
+/// decides what happens to records once no output is able to take them. An optional `source` one
+/// tells which of the input, the peer and the record field identifies the record source. An
+/// optional `oversize` one routes records above its threshold apart from the other ones, while an
+/// optional `transforms` one captures what filters change about records. An optional `lineage`
+/// one records which filters touched which fields in records themselves, filters and outputs
+/// opting in and out of it with their own `lineage` flag. An optional `snapshot` one carries soft
+/// state of components over restarts.
+pub fn parse(content: &str) -> Result<Pipeline, Error> {
+    build(content, false).map_err(|mut errors| errors.remove(0))
+}
+
+/// Builds the pipeline like `parse` does, but also test-binds every input and resolves every
+/// output destination, reporting all problems found instead of stopping at the first one.
+pub fn check(content: &str) -> Vec<Error> {
+    match build(content, true) {
+        Ok(..) => Vec::new(),
+        Err(errors) => errors,
+    }
+}
+
+/// Builds the pipeline out of the new config content, telling what has changed since the
+/// previous one.
+///
+/// Inputs are matched by name, outputs by equal entries and the filter chain is replaced if any
+/// of its entries differs. Changes of other sections, as well as changed or removed inputs, take
+/// effect only after restart.
+pub fn diff(previous: &str, content: &str) -> Result<Reload, Error> {
+    let pipeline = try!(parse(content));
+    let previous = try!(json::from_str(previous).map_err(Error::Syntax));
+    let current = try!(json::from_str(content).map_err(Error::Syntax));
+
+    let mut restart = Vec::new();
+
+    let running: Vec<(String, &Value)> = entries(&previous, "inputs").into_iter().map(|value| (name(value), value)).collect();
+    let mut inputs = Vec::new();
+    for value in entries(&current, "inputs").into_iter() {
+        let name = name(value);
+        match running.iter().find(|&&(ref other, _)| *other == name) {
+            Some(&(_, other)) if other != value => restart.push(format!("'{}' input is changed", name)),
+            Some(..) => {}
+            None => inputs.push(name),
+        }
+    }
+
+    for &(ref name, _) in running.iter() {
+        if !entries(&current, "inputs").into_iter().any(|value| self::name(value) == *name) {
+            restart.push(format!("'{}' input is removed", name));
+        }
+    }
+
+    let mut running: Vec<Option<&Value>> = entries(&previous, "outputs").into_iter().map(Some).collect();
+    let outputs = entries(&current, "outputs").into_iter().map(|value| {
+        let position = running.iter().position(|other| *other == Some(value));
+        if let Some(position) = position {
+            running[position] = None;
+        }
+        position
+    }).collect();
+
+    for key in ["validation", "flush_interval", "dead_letter", "elastic", "oversize", "retention", "source",
+                "unavailable", "projection", "transforms", "warm_up", "quota",
+                "slow_consumer", "lineage", "breaker", "snapshot", "startup_record", "alerts", "scheduling",
+                "deterministic", "thread_prefix", "audit", "poison"].iter() {
+        if previous.find(key) != current.find(key) {
+            restart.push(format!("'{}' section is changed", key));
+        }
+    }
+
+    Ok(Reload {
+        pipeline: pipeline,
+        inputs: inputs,
+        outputs: outputs,
+        filters: previous.find("filters") != current.find("filters"),
+        restart: restart,
+    })
+}
+
+/// Returns entries of the list under the given key, empty if absent or not a list.
+fn entries<'a>(value: &'a Value, key: &str) -> Vec<&'a Value> {
+    match value.find(key) {
+        Some(&Value::List(ref list)) => list.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the input name, defaulting to its type like `build` does.
+fn name(value: &Value) -> String {
+    let config = Config::new(value);
+    let typename = config.typename().unwrap_or(String::new());
+    config.string_or("name", &typename).unwrap_or(typename)
+}
+
+/// Reads the `on_error` policy of the filter or output, if configured.
+fn error_policy<T, F>(config: &Config, from_str: F) -> Result<Option<T>, Error>
+    where F: Fn(&str) -> Option<T>
+{
+    if config.find("on_error").is_none() {
+        return Ok(None);
+    }
+
+    let name = try!(config.string("on_error"));
+    match from_str(&name) {
+        Some(policy) => Ok(Some(policy)),
+        None => Err(Error::Invalid(format!("unknown on_error policy '{}'", name))),
+    }
+}
+
+/// Stores the error, if any, converting the result into an option.
+fn collect<T>(result: Result<T, Error>, errors: &mut Vec<Error>) -> Option<T> {
+    match result {
+        Ok(v) => Some(v),
+        Err(err) => {
+            errors.push(err);
+            None
+        }
+    }
+}
+
+/// Builds raw payload capture settings, either `{"field": "raw"}` or `{"archive": "/path"}` with
+/// an optional `limit` in bytes, archived payloads being keyed by the idempotency id in the
+/// optional `id_field`.
+fn capture(input: &str, config: &Config) -> Result<Capture, Error> {
+    let limit = try!(config.u64_or("limit", 65536)) as usize;
+    let target = match (config.find("field"), config.find("archive")) {
+        (Some(..), None) => Target::Field(try!(config.string("field"))),
+        (None, Some(..)) => {
+            let path = try!(config.string("archive"));
+            Target::Archive(Mutex::new(try!(Archive::open(Path::new(&path)).map_err(Error::Io))))
+        }
+        _ => return Err(Error::Invalid("capture requires either 'field' or 'archive'".to_string())),
+    };
+
+    let capture = Capture::new(input, target, limit);
+    match config.find("id_field") {
+        Some(..) => Ok(capture.field(&try!(config.string("id_field")))),
+        None => Ok(capture),
+    }
+}
+
+/// Builds the policy applied while all outputs are unavailable: `{"policy": "block"}`,
+/// `{"policy": "drop"}` or `{"policy": "spill", "dir": "/path"}` with an optional spool `size` in
+/// bytes.
+fn unavailable(config: &Config) -> Result<Unavailable, Error> {
+    match &try!(config.string("policy"))[..] {
+        "block" => Ok(Unavailable::Block),
+        "drop" => Ok(Unavailable::Drop),
+        "spill" => {
+            let dir = try!(config.string("dir"));
+            let size = try!(config.u64_or("size", 1024 * 1024 * 1024));
+            let spool = try!(Spool::open(Path::new(&dir), size).map_err(Error::Io));
+            Ok(Unavailable::Spill(spool))
+        }
+        policy => Err(Error::Invalid(format!("unknown unavailability policy '{}'", policy))),
+    }
+}
+
+fn build(content: &str, dry: bool) -> Result<Pipeline, Vec<Error>> {
+    let value = try!(json::from_str(content).map_err(|err| vec![Error::Syntax(err)]));
+    let config = Config::new(&value);
+    let mut errors = Vec::new();
+
+    let validation = match config.find("validation") {
+        Some(config) => collect(Validation::from_config(&config), &mut errors).unwrap_or_else(Validation::default),
+        None => Validation::default(),
+    };
+
+    let defaults = validation.clone();
+    let mut builder = PipelineBuilder::new(validation);
+    if let Some(interval) = collect(config.u64_or("flush_interval", 1000), &mut errors) {
+        builder = builder.flush_interval(interval as u32);
+    }
+
+    if let Some(ordered) = collect(config.bool_or("connection_order", true), &mut errors) {
+        builder = builder.connection_order(ordered);
+    }
+
+    match collect(config.string_or("projection", "full"), &mut errors) {
+        Some(ref mode) if mode == "strict" => builder = builder.strict_projection(true),
+        Some(ref mode) if mode == "full" => {}
+        Some(mode) => errors.push(Error::Invalid(format!("unknown projection mode '{}'", mode))),
+        None => {}
+    }
+
+    for config in collect(config.list("inputs"), &mut errors).unwrap_or_else(Vec::new).iter() {
+        let name = match collect(config.typename(), &mut errors) {
+            Some(typename) => collect(config.string_or("name", &typename), &mut errors).unwrap_or(typename),
+            None => continue,
+        };
+
+        let input = collect(registry::input(config), &mut errors);
+        let codec = match config.find("codec") {
+            Some(codec) => collect(registry::codec(&codec), &mut errors),
+            None => {
+                errors.push(Error::Invalid(format!("'{}' input requires codec", name)));
+                None
+            }
+        };
+        let validation = match config.find("validation") {
+            Some(config) => collect(Validation::from_config(&config), &mut errors),
+            None => None,
+        };
+        let normalization = match config.find("normalize") {
+            Some(config) => collect(Normalization::from_config(&config), &mut errors),
+            None => None,
+        };
+
+        let capture = match config.find("capture") {
+            Some(config) => collect(capture(&name, &config), &mut errors),
+            None => None,
+        };
+
+        let projection = match config.find("projection") {
+            Some(config) => collect(Projection::from_config(&config), &mut errors),
+            None => None,
+        };
+
+        let encoding = match config.find("encoding") {
+            Some(config) => collect(Encoding::from_config(&config), &mut errors),
+            None => None,
+        };
+
+        let non_finite = match collect(config.string_or("non_finite", "null"), &mut errors).as_ref().map(|name| &name[..]) {
+            Some("null") | None => NonFinite::Null,
+            Some("drop") => NonFinite::Drop,
+            Some("dead_letter") => NonFinite::DeadLetter,
+            Some(action) => {
+                errors.push(Error::Invalid(format!("unknown '{}' action for non-finite numbers of '{}' input", action, name)));
+                NonFinite::Null
+            }
+        };
+
+        // Failures to decode are either only logged or also emitted as records, with at most the
+        // limit of raw bytes.
+        let decode_errors = match collect(config.string_or("decode_errors", "log"), &mut errors).as_ref().map(|name| &name[..]) {
+            Some("log") | None => None,
+            Some("record") => collect(config.u64_or("decode_error_limit", 1024), &mut errors).map(|limit| limit as usize),
+            Some(mode) => {
+                errors.push(Error::Invalid(format!("unknown '{}' decode error mode of '{}' input", mode, name)));
+                None
+            }
+        };
+
+        if let (Some(codec), Some(..)) = (codec.as_ref(), encoding.as_ref()) {
+            if !codec.text() {
+                errors.push(Error::Invalid(format!("'{}' input: 'encoding' requires a text codec", name)));
+            }
+        }
+
+        // The input is checked even if other parts of its section are broken, so that all
+        // problems are reported at once.
+        if let (true, Some(input)) = (dry, input.as_ref()) {
+            if let Err(reason) = input.check() {
+                errors.push(Error::Invalid(format!("'{}' input: {}", name, reason)));
+            }
+        }
+
+        if let (Some(input), Some(codec)) = (input, codec) {
+            // Required field names go through the same normalization as record keys do.
+            let (codec, validation) = match normalization {
+                Some(normalization) => {
+                    let validation = normalization.validation(validation.as_ref().unwrap_or(&defaults));
+                    (Box::new(Normalized::new(codec, normalization)) as Box<Codec>, Some(validation))
+                }
+                None => (codec, validation),
+            };
+
+            builder = builder.input(&name, input, codec, validation);
+            if let Some(capture) = capture {
+                builder = builder.capture(&name, capture);
+            }
+            if let Some(projection) = projection {
+                builder = builder.projection(&name, projection);
+            }
+            if let Some(encoding) = encoding {
+                builder = builder.encoding(&name, encoding);
+            }
+            if non_finite != NonFinite::default() {
+                builder = builder.non_finite(&name, non_finite);
+            }
+            if let Some(limit) = decode_errors {
+                builder = builder.decode_errors(&name, limit);
+            }
+        }
+    }
+
+    let lineage = match config.find("lineage") {
+        Some(config) => collect(lineage::Settings::from_config(&config), &mut errors),
+        None => None,
+    };
+    let all = lineage.as_ref().map(|settings| settings.all).unwrap_or(false);
+
+    let mut builder = builder.filters();
+    for config in collect(config.list("filters"), &mut errors).unwrap_or_else(Vec::new).iter() {
+        let traced = collect(config.bool_or("lineage", all), &mut errors).unwrap_or(false);
+        let on_error = match collect(error_policy(config, filter::OnError::from_str), &mut errors) {
+            Some(on_error) => on_error,
+            None => continue,
+        };
+
+        if let Some(filter) = collect(registry::filter(config), &mut errors) {
+            // Built, so the type is there.
+            let typename = config.typename().unwrap_or_else(|_| String::new());
+            let filter = match on_error {
+                Some(on_error) => {
+                    let name = collect(config.string_or("name", &typename), &mut errors).unwrap_or(typename.clone());
+                    Box::new(Guard::new(filter, &name, on_error)) as Box<Filter>
+                }
+                None => filter,
+            };
+            if traced {
+                builder = builder.traced_filter(&typename, filter);
+            } else {
+                builder = builder.filter(&typename, filter);
+            }
+        }
+    }
+
+    if let Some(settings) = lineage {
+        builder = builder.lineage(settings);
+    }
+
+    let mut builder = builder.routing();
+    if let Some(config) = config.find("dead_letter") {
+        if let Some(output) = collect(registry::output(&config), &mut errors) {
+            if dry {
+                if let Err(reason) = output.check() {
+                    errors.push(Error::Invalid(format!("dead-letter output: {}", reason)));
+                }
+            }
+
+            builder = builder.dead_letter(output);
+        }
+    }
+
+    if let Some(config) = config.find("transforms") {
+        let attach = collect(config.bool_or("attach", true), &mut errors).unwrap_or(true);
+        let output = match config.find("output") {
+            Some(config) => {
+                let output = collect(registry::output(&config), &mut errors);
+                match output {
+                    Some(ref output) if dry => {
+                        if let Err(reason) = output.check() {
+                            errors.push(Error::Invalid(format!("transforms output: {}", reason)));
+                        }
+                    }
+                    _ => {}
+                }
+                output
+            }
+            None => None,
+        };
+
+        builder = builder.transforms(attach, output);
+    }
+
+    if let Some(config) = config.find("oversize") {
+        if let Some(settings) = collect(oversize::Settings::from_config(&config), &mut errors) {
+            builder = builder.oversize(settings);
+        }
+    }
+
+    if let Some(config) = config.find("unavailable") {
+        if let Some(policy) = collect(unavailable(&config), &mut errors) {
+            builder = builder.unavailable(policy);
+        }
+    }
+
+    // Outputs are identified by their position unless given the identifier.
+    let mut builder = builder.outputs();
+    let mut acknowledging = Vec::new();
+    let mut unacknowledged = false;
+    for (position, config) in collect(config.list("outputs"), &mut errors).unwrap_or_else(Vec::new).iter().enumerate() {
+        let delivery = match collect(config.string_or("delivery", "at_least_once"), &mut errors) {
+            Some(name) => {
+                match Delivery::from_str(&name) {
+                    Some(delivery) => delivery,
+                    None => {
+                        errors.push(Error::Invalid(format!("unknown delivery mode '{}'", name)));
+                        continue;
+                    }
+                }
+            }
+            None => continue,
+        };
+
+        let strip = !collect(config.bool_or("lineage", true), &mut errors).unwrap_or(true);
+        if collect(config.bool_or("acknowledge", true), &mut errors).unwrap_or(true) {
+            acknowledging.push(position);
+        } else {
+            unacknowledged = true;
+        }
+        let on_error = match collect(error_policy(config, output::OnError::from_str), &mut errors) {
+            Some(on_error) => on_error,
+            None => continue,
+        };
+        let id = match collect(config.string_or("id", &position.to_string()), &mut errors) {
+            Some(id) => id,
+            None => continue,
+        };
+
+        if let Some(output) = collect(registry::output(config), &mut errors) {
+            if dry {
+                if let Err(reason) = output.check() {
+                    errors.push(Error::Invalid(format!("'{}' output: {}", output.typename(), reason)));
+                }
+            }
+
+            let output = if strip { Box::new(lineage::Strip::new(output)) as Box<Output> } else { output };
+            builder = builder.output_with(&id, output, delivery, on_error);
+        }
+    }
+
+    if unacknowledged {
+        builder = builder.acknowledging(acknowledging);
+    }
+
+    if let Some(config) = config.find("elastic") {
+        if let Some(settings) = collect(queue::Settings::from_config(&config), &mut errors) {
+            builder = builder.elastic(settings);
+        }
+    }
+
+    if config.find("thread_prefix").is_some() {
+        if let Some(prefix) = collect(config.string("thread_prefix"), &mut errors) {
+            builder = builder.thread_prefix(&prefix);
+        }
+    }
+
+    if let Some(enabled) = collect(config.bool_or("startup_record", false), &mut errors) {
+        builder = builder.startup_record(enabled);
+    }
+
+    if config.find("warm_up").is_some() {
+        if let Some(duration) = collect(config.u64_or("warm_up", 0), &mut errors) {
+            builder = builder.warm_up(duration);
+        }
+    }
+
+    if let Some(config) = config.find("retention") {
+        if let Some(settings) = collect(retention::Settings::from_config(&config), &mut errors) {
+            builder = builder.retention(Retention::new(settings));
+        }
+    }
+
+    if let Some(config) = config.find("source") {
+        if let Some(resolution) = collect(Resolution::from_config(&config), &mut errors) {
+            builder = builder.source(resolution);
+        }
+    }
+
+    if let Some(config) = config.find("quota") {
+        if let Some(settings) = collect(quota::Settings::from_config(&config), &mut errors) {
+            builder = builder.quota(Quota::new(settings));
+        }
+    }
+
+    if let Some(config) = config.find("slow_consumer") {
+        let high = collect(config.u64_or("high", 10000), &mut errors);
+        let interval = collect(config.u64_or("interval", 60000), &mut errors);
+        if let (Some(high), Some(interval)) = (high, interval) {
+            builder = builder.slow_consumer(high as usize, interval);
+        }
+    }
+
+    if let Some(config) = config.find("breaker") {
+        if let Some(settings) = collect(breaker::Settings::from_config(&config), &mut errors) {
+            builder = builder.breaker(settings);
+        }
+    }
+
+    if let Some(config) = config.find("scheduling") {
+        if let Some(settings) = collect(sched::Settings::from_config(&config), &mut errors) {
+            builder = builder.scheduling(settings);
+        }
+    }
+
+    if let Some(config) = config.find("snapshot") {
+        if let Some(settings) = collect(snapshot::Settings::from_config(&config), &mut errors) {
+            builder = builder.snapshot(settings);
+        }
+    }
+
+    if let Some(config) = config.find("audit") {
+        if let Some(true) = collect(config.bool_or("records", true), &mut errors) {
+            builder = builder.audit_records();
+        }
+    }
+
+    if let Some(config) = config.find("alerts") {
+        if let Some(settings) = collect(alert::Settings::from_config(&config), &mut errors) {
+            builder = builder.alerts(settings);
+        }
+    }
+
+    if let Some(config) = config.find("poison") {
+        if let Some(settings) = collect(poison::Settings::from_config(&config), &mut errors) {
+            builder = builder.poison(settings);
+        }
+    }
+
+    if let Some(config) = config.find("deterministic") {
+        if let Some(settings) = collect(determinism::Settings::from_config(&config), &mut errors) {
+            builder = builder.deterministic(settings);
+        }
+    }
+
+    // Components are checked against each other only once all of them are built.
+    match builder.build() {
+        Ok(pipeline) => {
+            if errors.is_empty() {
+                Ok(pipeline)
+            } else {
+                Err(errors)
+            }
+        }
+        Err(problems) => {
+            errors.extend(problems.into_iter());
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::super::json;
+    use super::{Config, Error, check, diff, parse};
+
+    #[test]
+    fn typed_accessors() {
+        let value = json::from_str(r#"{"s": "v", "n": 42, "b": true, "l": ["a", "b"], "m": {"k": "v"}}"#).unwrap();
+        let config = Config::new(&value);
+
+        assert_eq!("v", config.string("s").unwrap());
+        assert_eq!(42, config.u64_or("n", 0).unwrap());
+        assert_eq!(true, config.bool_or("b", false).unwrap());
+        assert_eq!(vec!["a".to_string(), "b".to_string()], config.strings_or("l", Vec::new()).unwrap());
+        assert_eq!("v", config.mapping("m").unwrap()["k"]);
+        assert_eq!(7, config.u64_or("absent", 7).unwrap());
+        assert!(config.string("n").is_err());
+        assert!(config.string("absent").is_err());
+    }
+
+    #[test]
+    fn parse_pipeline() {
+        let content = r#"{
+            "validation": {"required": ["message"], "missing": "pass"},
+            "inputs": [{"type": "tcp", "port": 10053, "codec": "msgpack"}],
+            "filters": [{"type": "escape_reserved", "prefixes": ["_"]}],
+            "outputs": [
+                {"type": "null"},
+                {"type": "failover", "primary": {"type": "elasticsearch"}, "secondary": {"type": "null"}}
+            ]
+        }"#;
+
+        assert!(parse(content).is_ok());
+    }
+
+    #[test]
+    fn parse_on_error_policies() {
+        let content = r#"{
+            "filters": [{"type": "time_bucket", "bucket": "1m", "on_error": "dead_letter"}],
+            "outputs": [{"type": "null", "on_error": "retry_then_dead_letter"}],
+            "dead_letter": {"type": "null"}
+        }"#;
+        assert!(parse(content).is_ok());
+
+        match parse(&content.replace("retry_then_dead_letter", "pass_unmodified")) {
+            Err(Error::Invalid(reason)) => assert_eq!("unknown on_error policy 'pass_unmodified'", reason),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(..) => panic!("expected error"),
+        }
+    }
+
+    #[test]
+    fn refuse_wall_clock_components_in_deterministic_mode() {
+        let content = r#"{
+            "deterministic": {"seed": 7},
+            "filters": [{"type": "level_sample", "rates": {"debug": 0.5}}, {"type": "governor", "budget": 1000}],
+            "outputs": [{"type": "null"}]
+        }"#;
+        match parse(content) {
+            Err(Error::Invalid(reason)) => {
+                assert_eq!("'governor' filter is unavailable in the deterministic mode: it adapts sampling rates to the \
+                    wall-clock throughput", reason);
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(..) => panic!("expected error"),
+        }
+
+        assert!(parse(&content.replace(r#", {"type": "governor", "budget": 1000}"#, "")).is_ok());
+    }
+
+    #[test]
+    fn fail_on_unknown_output() {
+        match parse(r#"{"outputs": [{"type": "unknown"}]}"#) {
+            Err(Error::Invalid(..)) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(..) => panic!("expected error"),
+        }
+    }
+
+    #[test]
+    fn refuse_encoding_of_binary_codec() {
+        let content = r#"{
+            "inputs": [{"type": "tcp", "port": 10053, "codec": "msgpack", "encoding": {"charset": "shift_jis"}}],
+            "outputs": [{"type": "null"}]
+        }"#;
+        match parse(content) {
+            Err(Error::Invalid(reason)) => assert_eq!("'tcp' input: 'encoding' requires a text codec", reason),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(..) => panic!("expected error"),
+        }
+
+        assert!(parse(&content.replace("msgpack", "json")).is_ok());
+    }
+
+    #[test]
+    fn fail_on_syntax_error() {
+        match parse(r#"{"outputs": ["#) {
+            Err(Error::Syntax(..)) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(..) => panic!("expected error"),
+        }
+    }
+
+    #[test]
+    fn check_reports_all_problems() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let content = format!(r#"{{
+            "inputs": [
+                {{"type": "tcp", "host": "127.0.0.1", "port": 0, "codec": "unknown"}},
+                {{"type": "tcp", "host": "127.0.0.1", "port": {}, "codec": "json"}},
+                {{"type": "tcp", "name": "uncoded", "host": "127.0.0.1", "port": {}, "codec": "unknown"}}
+            ],
+            "outputs": [{{"type": "null"}}]
+        }}"#, port, port);
+
+        let errors = check(&content);
+        assert_eq!(4, errors.len());
+        assert!(errors[0].to_string().contains("unknown codec 'unknown'"));
+        assert!(errors[1].to_string().contains("unable to bind"));
+        assert!(errors[2].to_string().contains("unknown codec 'unknown'"));
+        assert!(errors[3].to_string().contains("'uncoded' input: unable to bind"));
+    }
+
+    #[test]
+    fn check_valid_config() {
+        assert!(check(r#"{"inputs": [{"type": "tcp", "host": "127.0.0.1", "port": 0, "codec": "json"}]}"#).is_empty());
+    }
+
+    #[test]
+    fn diff_reloadable_sections() {
+        let previous = r#"{
+            "inputs": [{"type": "tcp", "port": 10053, "codec": "msgpack"}],
+            "filters": [{"type": "coalesce"}],
+            "outputs": [{"type": "null"}, {"type": "file", "path": "/tmp/test.log"}]
+        }"#;
+        let content = r#"{
+            "flush_interval": 500,
+            "inputs": [
+                {"type": "tcp", "port": 10053, "codec": "msgpack"},
+                {"type": "tcp", "name": "json", "port": 10054, "codec": "json"}
+            ],
+            "filters": [{"type": "coalesce", "fields": ["message"]}],
+            "outputs": [{"type": "file", "path": "/tmp/test.log"}, {"type": "null", "delivery": "at_most_once"}]
+        }"#;
+
+        let reload = diff(previous, content).unwrap();
+        assert_eq!(vec!["json".to_string()], reload.inputs);
+        assert_eq!(vec![Some(1), None], reload.outputs);
+        assert!(reload.filters);
+        assert_eq!(vec!["'flush_interval' section is changed".to_string()], reload.restart);
+
+        assert!(!diff(previous, previous).unwrap().filters);
+        assert!(diff(previous, r#"{"outputs": [{"type": "unknown"}]}"#).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::thread::JoinHandle;
+
+use log::LogLevel;
+
+use super::audit;
+use super::codec::MessagePack;
+use super::config::{Config, Error as ConfigError, FromConfig};
+use super::deadletter::Criteria;
+use super::identity;
+use super::input::{Input, Requeue};
+use super::logging;
+use super::metrics;
+use super::pipeline::Handle;
+use super::reload;
+
+/// Where control commands are read from: the standard input and a local TCP address, e.g.
+/// `127.0.0.1:10054`, neither by default.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub stdin: bool,
+    pub listen: Option<String>,
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, ConfigError> {
+        let listen = match config.find("listen") {
+            Some(..) => Some(try!(config.string("listen"))),
+            None => None,
+        };
+
+        Ok(Settings {
+            stdin: try!(config.bool_or("stdin", false)),
+            listen: listen,
+        })
+    }
+}
+
+/// Executes line commands controlling the running process, as an alternative to signals:
+///
+/// - `flush` passes records held back by filters on and flushes outputs;
+/// - `stats` lists all metrics as `<name> <value>` lines;
+/// - `status` lists the instance identity as `instance.id`, `instance.config` and
+///   `instance.version` lines, then output delivery modes as `output.<id>.delivery <mode>` lines
+///   and circuit breaker states as `output.<id>.breaker <state>` lines, followed by alert states
+///   as `alert.<name> <state>` lines;
+/// - `reload` reloads the config, like SIGHUP does;
+/// - `set-level <level>` changes the log level, e.g. to `debug`;
+/// - `requeue <archive> [reason=<text>] [stage=<stage>] [since=<secs>] [until=<secs>]
+///   [max_attempts=<n>] [quarantine=<path>]` starts requeueing records of the dead-letter archive
+///   matching the criteria, see `input::Requeue`, logging the summary once done.
+///
+/// Every command is answered by `ok` once done, or by an `error: <reason>` line, and is recorded
+/// to the audit trail together with its source and outcome.
+#[derive(Clone)]
+pub struct Control {
+    handle: Handle,
+}
+
+impl Control {
+    pub fn new(handle: Handle) -> Control {
+        Control {
+            handle: handle,
+        }
+    }
+
+    /// Executes the command received from the given source, e.g. the peer address, returning the
+    /// reply lines.
+    pub fn execute(&self, line: &str, source: &str) -> Vec<String> {
+        let mut words = line.split(|c: char| c.is_whitespace()).filter(|word| !word.is_empty());
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Vec::new(),
+        };
+        let args: Vec<&str> = words.collect();
+
+        info!(target: "Control", "executing '{}' from {}", line.trim(), source);
+
+        let result = match (command, args.len()) {
+            ("flush", 0) => self.handle.flush().map(|()| Vec::new()),
+            ("stats", 0) => {
+                Ok(metrics::registry().snapshot().into_iter().map(|(name, value)| {
+                    format!("{} {}", name, value)
+                }).collect())
+            }
+            ("status", 0) => {
+                let identity = identity::current();
+                let mut lines = vec![
+                    format!("instance.id {}", identity.instance),
+                    format!("instance.config {}", identity.config),
+                    format!("instance.version {}", identity.version),
+                ];
+                lines.extend(self.handle.deliveries().into_iter().map(|(id, delivery)| {
+                    format!("output.{}.delivery {}", id, delivery.name())
+                }));
+                lines.extend(self.handle.breakers().into_iter().map(|(id, state)| {
+                    format!("output.{}.breaker {}", id, state.name())
+                }));
+                lines.extend(self.handle.alerts().into_iter().map(|(name, state)| {
+                    format!("alert.{} {}", name, state.name())
+                }));
+                Ok(lines)
+            }
+            ("reload", 0) => {
+                reload::request();
+                Ok(Vec::new())
+            }
+            ("set-level", 1) => {
+                match LogLevel::from_str(args[0]) {
+                    Ok(level) => {
+                        logging::set_level(level);
+                        audit::record("log_level", source, vec![("level", args[0].to_lowercase())]);
+                        Ok(Vec::new())
+                    }
+                    Err(()) => Err(format!("unknown level '{}'", args[0])),
+                }
+            }
+            ("requeue", n) if n > 0 => {
+                parse_requeue(&args).and_then(|requeue| {
+                    self.handle.inject("requeue", Box::new(requeue), Box::new(MessagePack::default()))
+                }).map(|()| Vec::new())
+            }
+            ("flush", _) | ("stats", _) | ("status", _) | ("reload", _) | ("set-level", _) | ("requeue", _) => {
+                Err(format!("wrong arguments for '{}'", command))
+            }
+            _ => Err(format!("unknown command '{}'", command)),
+        };
+
+        let outcome = match result {
+            Ok(..) => "ok".to_string(),
+            Err(ref reason) => format!("error: {}", reason),
+        };
+        audit::record("control", source, vec![("command", line.trim().to_string()), ("outcome", outcome)]);
+
+        match result {
+            Ok(mut lines) => {
+                lines.push("ok".to_string());
+                lines
+            }
+            Err(reason) => {
+                warn!(target: "Control", "unable to execute '{}': {}", line.trim(), reason);
+                vec![format!("error: {}", reason)]
+            }
+        }
+    }
+
+    /// Executes commands read line by line, writing replies, until the reader is exhausted.
+    pub fn serve<R: BufRead, W: Write>(&self, rd: R, wr: &mut W, source: &str) -> io::Result<()> {
+        for line in rd.lines() {
+            let line = try!(line);
+            for reply in self.execute(&line, source).iter() {
+                try!(writeln!(wr, "{}", reply));
+            }
+            try!(wr.flush());
+        }
+
+        Ok(())
+    }
+
+    /// Spawns threads reading commands from the configured sources.
+    pub fn listen(self, settings: &Settings) -> io::Result<Vec<JoinHandle<()>>> {
+        let mut threads = Vec::new();
+
+        if let Some(ref addr) = settings.listen {
+            let listener = try!(TcpListener::bind(&addr[..]));
+            info!(target: "Control", "accepting commands on {}", addr);
+
+            let control = self.clone();
+            threads.push(thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let control = control.clone();
+                    match stream.and_then(|stream| stream.try_clone().map(|rd| (rd, stream))) {
+                        Ok((rd, mut wr)) => {
+                            let peer = wr.peer_addr().map(|addr| addr.to_string()).unwrap_or("unknown".to_string());
+                            thread::spawn(move || {
+                                if let Err(err) = control.serve(BufReader::new(rd), &mut wr, &peer) {
+                                    warn!(target: "Control", "control connection failed: {}", err);
+                                }
+                            });
+                        }
+                        Err(err) => warn!(target: "Control", "unable to accept control connection: {}", err),
+                    }
+                }
+            }));
+        }
+
+        if settings.stdin {
+            let control = self.clone();
+            threads.push(thread::spawn(move || {
+                let stdin = io::stdin();
+                let result = control.serve(stdin.lock(), &mut io::stdout(), "stdin");
+                if let Err(err) = result {
+                    warn!(target: "Control", "unable to read commands from stdin: {}", err);
+                }
+            }));
+        }
+
+        Ok(threads)
+    }
+}
+
+/// Builds the requeue out of the archive path followed by `key=value` options.
+fn parse_requeue(args: &[&str]) -> Result<Requeue, String> {
+    let mut criteria = Criteria::default();
+    let mut max_attempts = None;
+    let mut quarantine = None;
+
+    for arg in args[1..].iter() {
+        let mut pair = arg.splitn(2, '=');
+        let (key, value) = match (pair.next(), pair.next()) {
+            (Some(key), Some(value)) if !value.is_empty() => (key, value),
+            _ => return Err(format!("expected 'key=value' option, got '{}'", arg)),
+        };
+        let seconds = || f64::from_str(value).map_err(|_| format!("'{}' must be seconds since the epoch", key));
+
+        match key {
+            "reason" => criteria.reason = Some(value.to_string()),
+            "stage" => criteria.stage = Some(value.to_string()),
+            "since" => criteria.since = Some(try!(seconds())),
+            "until" => criteria.until = Some(try!(seconds())),
+            "max_attempts" => {
+                max_attempts = Some(try!(u64::from_str(value).map_err(|_| "'max_attempts' must be a number".to_string())));
+            }
+            "quarantine" => quarantine = Some(value),
+            _ => return Err(format!("unknown option '{}'", key)),
+        }
+    }
+
+    let mut requeue = Requeue::new(Path::new(args[0]), criteria);
+    if let Some(max_attempts) = max_attempts {
+        requeue = requeue.max_attempts(max_attempts);
+    }
+    if let Some(quarantine) = quarantine {
+        requeue = requeue.quarantine(Path::new(quarantine));
+    }
+    try!(requeue.check());
+    Ok(requeue)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use log::LogLevel;
+
+    use super::super::logging;
+    use super::super::metrics;
+    use super::super::pipeline::Pipeline;
+    use super::super::validation::Validation;
+    use super::Control;
+
+    fn control() -> Control {
+        Control::new(Pipeline::new(Validation::default()).handle())
+    }
+
+    #[test]
+    fn list_metrics_on_stats() {
+        metrics::registry().counter("control.test.stats").add(42);
+
+        let replies = control().execute("stats", "test");
+        assert!(replies.contains(&"control.test.stats 42".to_string()));
+        assert_eq!(Some(&"ok".to_string()), replies.last());
+    }
+
+    #[test]
+    fn change_log_level() {
+        let mut replies = Vec::new();
+        control().serve(Cursor::new(&b"set-level debug\nset-level loud\n"[..]), &mut replies, "test").unwrap();
+
+        assert_eq!("ok\nerror: unknown level 'loud'\n", String::from_utf8(replies).unwrap());
+        assert_eq!(Some(LogLevel::Debug), logging::level());
+    }
+
+    #[test]
+    fn reply_error_on_unknown_command() {
+        assert_eq!(vec!["error: unknown command 'restart'".to_string()], control().execute("restart now", "test"));
+        assert_eq!(vec!["error: wrong arguments for 'stats'".to_string()], control().execute("stats all", "test"));
+        assert!(control().execute("  ", "test").is_empty());
+    }
+
+    #[test]
+    fn refuse_invalid_requeue() {
+        assert_eq!(vec!["error: wrong arguments for 'requeue'".to_string()], control().execute("requeue", "test"));
+        assert_eq!(vec!["error: unknown option 'level'".to_string()], control().execute("requeue /tmp/dead level=3", "test"));
+        assert_eq!(vec!["error: 'since' must be seconds since the epoch".to_string()],
+                   control().execute("requeue /tmp/dead since=yesterday", "test"));
+    }
+}
@@ -0,0 +1,154 @@
+use super::{Record, RecordItem};
+
+/// Field holding the reason the record has been dead-lettered for.
+pub const REASON: &'static str = "_dead_letter_reason";
+
+/// Field holding the pipeline stage the record has been dead-lettered at, one of `input`,
+/// `filter` and `output`.
+pub const STAGE: &'static str = "_dead_letter_stage";
+
+/// Field counting how many times the record has been requeued.
+pub const REQUEUED: &'static str = "_requeue_count";
+
+/// Layout version of archive entries, entries of other versions are refused.
+pub const VERSION: u32 = 1;
+
+/// Dead-lettered record split into the original record and the dead-letter details.
+///
+/// In the archive each letter is kept as the versioned entry, a record holding the `version`, the
+/// original `record`, the `reason`, the `stage` and the `timestamp` it has been archived at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Letter {
+    pub record: Record,
+    pub reason: String,
+    pub stage: String,
+    /// Time the record has been archived at, in seconds since the Unix epoch.
+    pub timestamp: f64,
+}
+
+impl Letter {
+    /// Takes the dead-letter details out of the record, leaving the original one.
+    pub fn new(mut record: Record, timestamp: f64) -> Letter {
+        let reason = take(&mut record, REASON);
+        let stage = take(&mut record, STAGE);
+
+        Letter {
+            record: record,
+            reason: reason,
+            stage: stage,
+            timestamp: timestamp,
+        }
+    }
+
+    /// Unwraps the letter out of the archive entry.
+    pub fn from_entry(entry: &Record) -> Result<Letter, String> {
+        match entry.find("version").and_then(|version| version.as_f64()) {
+            Some(version) if version == VERSION as f64 => {}
+            Some(version) => return Err(format!("entry layout version {} is not {}", version, VERSION)),
+            None => return Err("entry has no layout version".to_string()),
+        }
+
+        let record = match entry.find("record") {
+            Some(&RecordItem::Object(ref record)) => Record::from(record.clone()),
+            Some(..) | None => return Err("entry has no record".to_string()),
+        };
+        let text = |key: &str| entry.find(key).and_then(|value| value.as_str()).unwrap_or("unknown").to_string();
+
+        Ok(Letter {
+            record: record,
+            reason: text("reason"),
+            stage: text("stage"),
+            timestamp: entry.find("timestamp").and_then(|timestamp| timestamp.as_f64()).unwrap_or(0.0),
+        })
+    }
+
+    /// Wraps the letter into the archive entry.
+    pub fn entry(&self) -> Record {
+        let mut entry = Record::new();
+        entry.insert("version".to_string(), RecordItem::F64(VERSION as f64));
+        entry.insert("record".to_string(), RecordItem::Object(self.record.fields().clone()));
+        entry.insert("reason".to_string(), RecordItem::String(self.reason.clone()));
+        entry.insert("stage".to_string(), RecordItem::String(self.stage.clone()));
+        entry.insert("timestamp".to_string(), RecordItem::F64(self.timestamp));
+        entry
+    }
+
+    /// Returns how many times the record has been requeued so far.
+    pub fn requeued(&self) -> u64 {
+        match self.record.find(REQUEUED).and_then(|count| count.as_f64()) {
+            Some(count) if count > 0.0 => count as u64,
+            Some(..) | None => 0,
+        }
+    }
+}
+
+fn take(record: &mut Record, key: &str) -> String {
+    match record.remove(key) {
+        Some(RecordItem::String(value)) => value,
+        Some(RecordItem::Text(value)) => value.as_str().to_string(),
+        Some(..) | None => "unknown".to_string(),
+    }
+}
+
+/// Which letters to requeue, all of them by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Criteria {
+    /// Text the reason must contain.
+    pub reason: Option<String>,
+    pub stage: Option<String>,
+    /// Range of archive timestamps, in seconds since the Unix epoch, both ends included.
+    pub since: Option<f64>,
+    pub until: Option<f64>,
+}
+
+impl Criteria {
+    pub fn matches(&self, letter: &Letter) -> bool {
+        if let Some(ref reason) = self.reason {
+            if !letter.reason.contains(&reason[..]) {
+                return false;
+            }
+        }
+        if let Some(ref stage) = self.stage {
+            if letter.stage != *stage {
+                return false;
+            }
+        }
+
+        self.since.map_or(true, |since| letter.timestamp >= since) &&
+            self.until.map_or(true, |until| letter.timestamp <= until)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::RecordItem;
+    use super::super::testing::{record, string};
+    use super::{Criteria, Letter};
+
+    #[test]
+    fn wrap_and_unwrap_letters() {
+        let dead = record(vec![
+            ("message", string("le message")),
+            ("_requeue_count", RecordItem::U64(2)),
+            ("_dead_letter_reason", string("'X' filter failed: boom")),
+            ("_dead_letter_stage", string("filter")),
+        ]);
+
+        let letter = Letter::new(dead, 1433152800.0);
+        assert_eq!(record(vec![("message", string("le message")), ("_requeue_count", RecordItem::U64(2))]), letter.record);
+        assert_eq!("'X' filter failed: boom", letter.reason);
+        assert_eq!("filter", letter.stage);
+        assert_eq!(2, letter.requeued());
+        assert_eq!(Ok(letter.clone()), Letter::from_entry(&letter.entry()));
+
+        let mut entry = letter.entry();
+        entry.insert("version".to_string(), RecordItem::F64(2.0));
+        assert!(Letter::from_entry(&entry).is_err());
+
+        let criteria = Criteria { reason: Some("boom".to_string()), stage: Some("filter".to_string()), ..Criteria::default() };
+        assert!(criteria.matches(&letter));
+        assert!(!Criteria { stage: Some("input".to_string()), ..Criteria::default() }.matches(&letter));
+        assert!(!Criteria { since: Some(1433152801.0), ..Criteria::default() }.matches(&letter));
+        assert!(Criteria { until: Some(1433152800.0), ..Criteria::default() }.matches(&letter));
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::{Record, RecordItem};
+
+/// Field the diff is attached to, see `Pipeline::set_transforms`.
+pub const FIELD: &'static str = "_transforms";
+
+/// What happened to a value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Added(RecordItem),
+    Removed(RecordItem),
+    /// The previous and the new value.
+    Changed(RecordItem, RecordItem),
+}
+
+/// Change of the value at the path, nested objects being descended into.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub path: Vec<String>,
+    pub change: Change,
+}
+
+impl Entry {
+    /// Describes the change as an object with `op`, dot-separated `path`, the new `value` and
+    /// the `previous` one, whichever apply.
+    pub fn to_item(&self) -> RecordItem {
+        let mut map = HashMap::new();
+        let mut path = String::new();
+        for (id, name) in self.path.iter().enumerate() {
+            if id > 0 {
+                path.push('.');
+            }
+            path.push_str(name);
+        }
+        map.insert("path".to_string(), RecordItem::String(path));
+
+        let (op, value, previous) = match self.change {
+            Change::Added(ref value) => ("added", Some(value), None),
+            Change::Removed(ref previous) => ("removed", None, Some(previous)),
+            Change::Changed(ref previous, ref value) => ("changed", Some(value), Some(previous)),
+        };
+        map.insert("op".to_string(), RecordItem::String(op.to_string()));
+        if let Some(value) = value {
+            map.insert("value".to_string(), value.clone());
+        }
+        if let Some(previous) = previous {
+            map.insert("previous".to_string(), previous.clone());
+        }
+
+        RecordItem::Object(map)
+    }
+}
+
+/// Returns changes turning the first record into the second one, ordered by path.
+///
+/// Values are compared at the leaves of the record walk, so a changed nested field shows up by
+/// its full path, while arrays are compared as a whole.
+pub fn diff(before: &Record, after: &Record) -> Vec<Entry> {
+    let mut previous = BTreeMap::new();
+    before.walk(|path, value| { previous.insert(path.to_vec(), value); });
+
+    let mut current = BTreeMap::new();
+    after.walk(|path, value| { current.insert(path.to_vec(), value); });
+
+    let mut entries = Vec::new();
+    for (path, value) in previous.iter() {
+        match current.get(path) {
+            Some(other) if other == value => {}
+            Some(other) => entries.push((path.clone(), Change::Changed((*value).clone(), (*other).clone()))),
+            None => entries.push((path.clone(), Change::Removed((*value).clone()))),
+        }
+    }
+    for (path, value) in current.iter() {
+        if !previous.contains_key(path) {
+            entries.push((path.clone(), Change::Added((*value).clone())));
+        }
+    }
+    entries.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+
+    entries.into_iter().map(|(path, change)| Entry { path: path, change: change }).collect()
+}
+
+/// Returns the diff as an array of change descriptions, see `Entry::to_item`.
+pub fn to_item(entries: &[Entry]) -> RecordItem {
+    RecordItem::Array(entries.iter().map(|entry| entry.to_item()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::RecordItem;
+    use super::super::testing::{record, string};
+    use super::{Change, Entry, diff};
+
+    fn path(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn diff_added_field() {
+        let before = record(vec![("message", string("hello"))]);
+        let after = record(vec![("message", string("hello")), ("level", string("info"))]);
+
+        let expected = vec![Entry { path: path(&["level"]), change: Change::Added(string("info")) }];
+        assert_eq!(expected, diff(&before, &after));
+    }
+
+    #[test]
+    fn diff_removed_field() {
+        let before = record(vec![("message", string("hello")), ("password", string("secret"))]);
+        let after = record(vec![("message", string("hello"))]);
+
+        let expected = vec![Entry { path: path(&["password"]), change: Change::Removed(string("secret")) }];
+        assert_eq!(expected, diff(&before, &after));
+    }
+
+    #[test]
+    fn diff_changed_fields_by_path() {
+        let mut user = HashMap::new();
+        user.insert("name".to_string(), string("alice"));
+        user.insert("id".to_string(), RecordItem::F64(1.0));
+        let before = record(vec![("user", RecordItem::Object(user.clone())), ("size", RecordItem::F64(10.0))]);
+
+        user.insert("name".to_string(), string("***"));
+        let after = record(vec![("user", RecordItem::Object(user)), ("size", string("10"))]);
+
+        let expected = vec![
+            Entry { path: path(&["size"]), change: Change::Changed(RecordItem::F64(10.0), string("10")) },
+            Entry { path: path(&["user", "name"]), change: Change::Changed(string("alice"), string("***")) },
+        ];
+        assert_eq!(expected, diff(&before, &after));
+    }
+
+    #[test]
+    fn diff_nothing_for_equal_records() {
+        let before = record(vec![("message", string("hello")), ("tags", RecordItem::Array(vec![string("a")]))]);
+        assert!(diff(&before, &before.clone()).is_empty());
+    }
+
+    #[test]
+    fn describe_change() {
+        let entry = Entry { path: path(&["user", "name"]), change: Change::Changed(string("alice"), string("***")) };
+
+        let mut expected = HashMap::new();
+        expected.insert("op".to_string(), string("changed"));
+        expected.insert("path".to_string(), string("user.name"));
+        expected.insert("value".to_string(), string("***"));
+        expected.insert("previous".to_string(), string("alice"));
+        assert_eq!(RecordItem::Object(expected), entry.to_item());
+    }
+}
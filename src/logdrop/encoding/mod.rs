@@ -0,0 +1,415 @@
+use std::char;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use super::config::{Config, Error, FromConfig};
+use super::metrics;
+use super::metrics::Counter;
+
+mod sjis;
+
+/// Size of chunks read from the wrapped stream.
+const CHUNK: usize = 8192;
+
+/// Character undecodable sequences are replaced with.
+const REPLACEMENT: char = '\u{fffd}';
+
+/// Character encoding of text streams.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Charset {
+    Utf8,
+    /// ISO-8859-1, every byte being the code point of the same value.
+    Latin1,
+    /// JIS X 0201 single bytes together with JIS X 0208 double-byte sequences.
+    ShiftJis,
+}
+
+/// Outcome of decoding the character at the front of bytes.
+enum Step {
+    /// The character together with the number of bytes it took.
+    Char(char, usize),
+    /// Number of bytes not forming any character.
+    Invalid(usize),
+    /// The sequence is cut off by the end of bytes.
+    Incomplete,
+}
+
+impl Charset {
+    pub fn from_str(name: &str) -> Option<Charset> {
+        match &name.to_lowercase()[..] {
+            "utf-8" | "utf8" => Some(Charset::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Charset::Latin1),
+            "shift-jis" | "shift_jis" | "sjis" => Some(Charset::ShiftJis),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Charset::Utf8 => "utf-8",
+            Charset::Latin1 => "latin-1",
+            Charset::ShiftJis => "shift-jis",
+        }
+    }
+
+    fn step(&self, bytes: &[u8]) -> Step {
+        match *self {
+            Charset::Utf8 => utf8(bytes),
+            Charset::Latin1 => Step::Char(bytes[0] as char, 1),
+            Charset::ShiftJis => shift_jis(bytes),
+        }
+    }
+}
+
+fn utf8(bytes: &[u8]) -> Step {
+    let len = match bytes[0] {
+        0x00...0x7f => return Step::Char(bytes[0] as char, 1),
+        0xc2...0xdf => 2,
+        0xe0...0xef => 3,
+        0xf0...0xf4 => 4,
+        _ => return Step::Invalid(1),
+    };
+
+    let available = cmp::min(len, bytes.len());
+    for id in 1..available {
+        if bytes[id] & 0xc0 != 0x80 {
+            return Step::Invalid(id);
+        }
+    }
+    if available < len {
+        return Step::Incomplete;
+    }
+
+    match str::from_utf8(&bytes[..len]) {
+        Ok(value) => Step::Char(value.chars().next().unwrap(), len),
+        // Overlong forms and surrogates.
+        Err(..) => Step::Invalid(len),
+    }
+}
+
+fn shift_jis(bytes: &[u8]) -> Step {
+    let lead = bytes[0];
+    let row = match lead {
+        0x00...0x7f => return Step::Char(lead as char, 1),
+        // Half-width katakana.
+        0xa1...0xdf => return Step::Char(char::from_u32(0xff61 + (lead - 0xa1) as u32).unwrap(), 1),
+        0x81...0x9f => (lead - 0x81) as usize,
+        0xe0...0xef => (lead - 0xe0) as usize + 0x1f,
+        _ => return Step::Invalid(1),
+    };
+
+    let trail = match bytes.get(1) {
+        Some(&trail) => trail,
+        None => return Step::Incomplete,
+    };
+    if trail < 0x40 || trail == 0x7f || trail > 0xfc {
+        // The trail byte may well be a character on its own, e.g. a line break.
+        return Step::Invalid(1);
+    }
+
+    match sjis::TABLE[row * sjis::TRAILS + (trail - 0x40) as usize] {
+        0 => Step::Invalid(2),
+        code => Step::Char(char::from_u32(code as u32).unwrap(), 2),
+    }
+}
+
+/// Encoding of an input stream, transcoded into UTF-8 before decoding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Encoding {
+    pub charset: Charset,
+    /// Whether undecodable sequences are dead-lettered rather than replaced.
+    pub strict: bool,
+}
+
+impl FromConfig for Encoding {
+    fn from_config(config: &Config) -> Result<Encoding, Error> {
+        let name = try!(config.string_or("charset", "utf-8"));
+        let charset = match Charset::from_str(&name) {
+            Some(charset) => charset,
+            None => return Err(Error::Invalid(format!("unknown charset '{}'", name))),
+        };
+
+        Ok(Encoding {
+            charset: charset,
+            strict: try!(config.bool_or("strict", false)),
+        })
+    }
+}
+
+/// Runs of undecodable bytes taken out of the stream in strict mode, waiting to be dead-lettered.
+#[derive(Clone)]
+pub struct Rejects(Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+impl Rejects {
+    /// Takes all runs left so far.
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        let mut rejects = self.0.lock().unwrap();
+        let mut result = Vec::new();
+        while let Some(span) = rejects.pop_front() {
+            result.push(span);
+        }
+        result
+    }
+}
+
+/// Read adapter transcoding the stream in the given encoding into UTF-8 on the fly, so that
+/// codecs see valid UTF-8 whatever the producer sends.
+///
+/// Sequences split across reads are carried over to the next one. Undecodable ones are replaced
+/// with U+FFFD and counted in the `input.<name>.encoding.replaced` metric. In strict mode they are
+/// taken out of the stream instead, every run of them being left in `Rejects` and counted in the
+/// `input.<name>.encoding.rejected` metric.
+pub struct Transcoder<R> {
+    rd: R,
+    encoding: Encoding,
+    /// Bytes read, but not transcoded yet.
+    pending: Vec<u8>,
+    output: Vec<u8>,
+    pos: usize,
+    /// Run of undecodable bytes in progress in strict mode.
+    span: Vec<u8>,
+    rejects: Rejects,
+    replaced: Counter,
+    rejected: Counter,
+    eof: bool,
+}
+
+impl<R: Read> Transcoder<R> {
+    pub fn new(rd: R, encoding: Encoding, input: &str) -> Transcoder<R> {
+        let registry = metrics::registry();
+
+        Transcoder {
+            rd: rd,
+            encoding: encoding,
+            pending: Vec::new(),
+            output: Vec::new(),
+            pos: 0,
+            span: Vec::new(),
+            rejects: Rejects(Arc::new(Mutex::new(VecDeque::new()))),
+            replaced: registry.counter(&format!("input.{}.encoding.replaced", input)),
+            rejected: registry.counter(&format!("input.{}.encoding.rejected", input)),
+            eof: false,
+        }
+    }
+
+    pub fn rejects(&self) -> Rejects {
+        self.rejects.clone()
+    }
+
+    /// Transcodes pending bytes, keeping a sequence cut off by their end unless the stream is over.
+    fn transcode(&mut self) {
+        let mut output = String::with_capacity(self.pending.len());
+        let mut pos = 0;
+        while pos < self.pending.len() {
+            let len = match self.encoding.charset.step(&self.pending[pos..]) {
+                Step::Char(ch, len) => {
+                    self.reject();
+                    output.push(ch);
+                    pos += len;
+                    continue;
+                }
+                Step::Invalid(len) => len,
+                Step::Incomplete if self.eof => self.pending.len() - pos,
+                Step::Incomplete => break,
+            };
+
+            if self.encoding.strict {
+                self.span.extend(self.pending[pos..pos + len].iter().cloned());
+            } else {
+                output.push(REPLACEMENT);
+                self.replaced.inc();
+            }
+            pos += len;
+        }
+
+        if self.eof {
+            self.reject();
+        }
+        self.pending = self.pending[pos..].to_vec();
+        self.output = output.into_bytes();
+        self.pos = 0;
+    }
+
+    /// Leaves the run of undecodable bytes in progress, if any, to be dead-lettered.
+    fn reject(&mut self) {
+        if self.span.is_empty() {
+            return;
+        }
+
+        let span = mem::replace(&mut self.span, Vec::new());
+        self.rejected.inc();
+        self.rejects.0.lock().unwrap().push_back(span);
+    }
+}
+
+impl<R: Read> Read for Transcoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.output.len() {
+            if self.eof {
+                return Ok(0);
+            }
+
+            let mut chunk = [0; CHUNK];
+            let size = match self.rd.read(&mut chunk) {
+                Ok(size) => size,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            if size == 0 {
+                self.eof = true;
+            } else {
+                self.pending.extend(chunk[..size].iter().cloned());
+            }
+            self.transcode();
+        }
+
+        let mut size = 0;
+        for (dst, src) in buf.iter_mut().zip(self.output[self.pos..].iter()) {
+            *dst = *src;
+            size += 1;
+        }
+        self.pos += size;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::io;
+    use std::io::{Cursor, Read};
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use super::super::{Origin, RecordItem};
+    use super::super::codec::Json;
+    use super::super::input::Sink;
+    use super::super::metrics;
+    use super::super::numeric::NonFinite;
+    use super::super::testing::{record, string};
+    use super::super::validation::Validation;
+    use super::{Charset, Encoding, Transcoder};
+
+    /// Reader handing the data out a byte at a time, splitting every multi-byte sequence.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = cmp::min(buf.len(), 1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn encoding(charset: Charset, strict: bool) -> Encoding {
+        Encoding {
+            charset: charset,
+            strict: strict,
+        }
+    }
+
+    fn transcode<R: Read>(rd: R, charset: Charset, input: &str) -> String {
+        let mut result = String::new();
+        Transcoder::new(rd, encoding(charset, false), input).read_to_string(&mut result).unwrap();
+        result
+    }
+
+    const SHIFT_JIS: &'static [u8] = b"\x93\xfa\x96\x7b\x8c\xea\x82\xcc\x83\x8d\x83\x4f\x20\xb6\xc0\xb6\xc5";
+
+    #[test]
+    fn decode_fixtures() {
+        let cases: Vec<(Charset, &[u8], &str)> = vec![
+            (Charset::Utf8, "caf\u{e9} \u{65e5}\u{672c}".as_bytes(), "caf\u{e9} \u{65e5}\u{672c}"),
+            (Charset::Latin1, b"caf\xe9 \xfc\xdf\xff", "caf\u{e9} \u{fc}\u{df}\u{ff}"),
+            (Charset::ShiftJis, SHIFT_JIS, "\u{65e5}\u{672c}\u{8a9e}\u{306e}\u{30ed}\u{30b0} \u{ff76}\u{ff80}\u{ff76}\u{ff85}"),
+        ];
+
+        for &(charset, bytes, expected) in cases.iter() {
+            assert_eq!(expected, transcode(Cursor::new(bytes.to_vec()), charset, "encoding_test_fixtures"));
+        }
+    }
+
+    #[test]
+    fn join_sequences_split_across_reads() {
+        let expected = "\u{65e5}\u{672c}\u{8a9e}\u{306e}\u{30ed}\u{30b0} \u{ff76}\u{ff80}\u{ff76}\u{ff85}";
+        assert_eq!(expected, transcode(Trickle(Cursor::new(SHIFT_JIS.to_vec())), Charset::ShiftJis, "encoding_test_split"));
+
+        let expected = "\u{e9}\u{65e5}\u{1f600}";
+        assert_eq!(expected, transcode(Trickle(Cursor::new(expected.as_bytes().to_vec())), Charset::Utf8, "encoding_test_split"));
+        assert_eq!(0, metrics::registry().counter("input.encoding_test_split.encoding.replaced").get());
+    }
+
+    #[test]
+    fn replace_and_count_undecodable_sequences() {
+        // Unmapped lead, unmapped pair, lead before a line break, lead cut off by the end.
+        let bytes = b"a\x80b\x85\x40c\x93\nd\x93".to_vec();
+        assert_eq!("a\u{fffd}b\u{fffd}c\u{fffd}\nd\u{fffd}", transcode(Cursor::new(bytes), Charset::ShiftJis, "encoding_test_replace"));
+
+        // Stray continuation, overlong form, truncated sequence.
+        let bytes = b"a\x80b\xc0\xafc\xe6\x97".to_vec();
+        assert_eq!("a\u{fffd}b\u{fffd}\u{fffd}c\u{fffd}", transcode(Cursor::new(bytes), Charset::Utf8, "encoding_test_replace"));
+
+        assert_eq!(8, metrics::registry().counter("input.encoding_test_replace.encoding.replaced").get());
+    }
+
+    #[test]
+    fn take_undecodable_runs_out_in_strict_mode() {
+        let bytes = b"{\"message\": \"\x83G\x83\x89\x81[\"}\x85\x40\x80{\"id\": 1}".to_vec();
+        let mut transcoder = Transcoder::new(Trickle(Cursor::new(bytes)), encoding(Charset::ShiftJis, true), "encoding_test_strict");
+        let rejects = transcoder.rejects();
+
+        let mut result = String::new();
+        transcoder.read_to_string(&mut result).unwrap();
+        assert_eq!("{\"message\": \"\u{30a8}\u{30e9}\u{30fc}\"}{\"id\": 1}", result);
+        assert_eq!(vec![b"\x85\x40\x80".to_vec()], rejects.take());
+        assert_eq!(1, metrics::registry().counter("input.encoding_test_strict.encoding.rejected").get());
+    }
+
+    #[test]
+    fn dead_letter_undecodable_runs() {
+        let (tx, rx) = channel();
+        let origin = Origin {
+            input: "encoding_test_sink".to_string(),
+            validation: Arc::new(Validation::default()),
+            capture: None,
+            projection: None,
+            encoding: Some(Arc::new(encoding(Charset::Latin1, true))),
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+        let sink = Sink::new(tx, Arc::new(origin));
+
+        let bytes = b"{\"message\": \"caf\xe9\"}".to_vec();
+        sink.consume(&Json::default(), Box::new(Cursor::new(bytes))).unwrap();
+        drop(sink);
+
+        let envelopes: Vec<_> = rx.iter().collect();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(record(vec![("message", string("caf\u{e9}"))]), envelopes[0].record);
+        assert!(envelopes[0].rejected.is_none());
+
+        let (tx, rx) = channel();
+        let origin = Origin {
+            input: "encoding_test_sink".to_string(),
+            validation: Arc::new(Validation::default()),
+            capture: None,
+            projection: None,
+            encoding: Some(Arc::new(encoding(Charset::Utf8, true))),
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+        Sink::new(tx, Arc::new(origin)).consume(&Json::default(), Box::new(Cursor::new(b"{\"id\": 1}\xff\xfe{\"id\": 2}".to_vec()))).unwrap();
+
+        let envelopes: Vec<_> = rx.iter().collect();
+        assert_eq!(3, envelopes.len());
+        let rejected: Vec<_> = envelopes.iter().filter(|envelope| envelope.rejected.is_some()).collect();
+        assert_eq!(1, rejected.len());
+        assert_eq!(Some(&RecordItem::Bytes(b"\xff\xfe".to_vec())), rejected[0].record.find("raw"));
+        assert_eq!(Some("undecodable utf-8 bytes"), rejected[0].rejected.as_ref().map(|reason| &reason[..]));
+    }
+}
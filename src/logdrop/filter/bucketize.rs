@@ -0,0 +1,181 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::numeric;
+use super::{Failure, Filter};
+
+/// What to do with a record lacking the numeric source field.
+///
+/// Records with the field holding anything but a number or null fail the filter instead, if its
+/// `on_error` policy is configured.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Missing {
+    /// Pass the record untouched.
+    Pass,
+    /// Drop the record.
+    Drop,
+    /// Write the given label.
+    Label(String),
+}
+
+/// Writes the label of the bucket a numeric field falls into to the target field.
+///
+/// Boundaries split numbers into half-open ranges, so with `[10, 100]` boundaries and
+/// `["<10", "10-100", ">100"]` labels the number 10 goes to `10-100` and 100 goes to `>100`.
+pub struct Bucketize {
+    field: String,
+    target: String,
+    boundaries: Vec<f64>,
+    labels: Vec<String>,
+    missing: Missing,
+}
+
+impl Bucketize {
+    /// Creates the filter, requiring exactly one label more than there are ascending boundaries.
+    pub fn new(field: &str, target: &str, boundaries: Vec<f64>, labels: Vec<String>, missing: Missing)
+        -> Result<Bucketize, String>
+    {
+        if labels.len() != boundaries.len() + 1 {
+            return Err(format!("{} boundaries require {} labels, found {}", boundaries.len(), boundaries.len() + 1, labels.len()));
+        }
+
+        if boundaries.windows(2).any(|pair| !(pair[0] < pair[1])) {
+            return Err("boundaries must be strictly ascending".to_string());
+        }
+
+        Ok(Bucketize {
+            field: field.to_string(),
+            target: target.to_string(),
+            boundaries: boundaries,
+            labels: labels,
+            missing: missing,
+        })
+    }
+
+    fn label(&self, value: f64) -> &str {
+        let id = self.boundaries.iter().take_while(|&&boundary| value >= boundary).count();
+        &self.labels[id]
+    }
+
+    fn bucketize(&self, mut record: Record, changes: Option<&mut Vec<diff::Entry>>) -> Vec<Record> {
+        let label = match record.find(&self.field).and_then(numeric::finite) {
+            Some(value) => self.label(value).to_string(),
+            _ => {
+                match self.missing {
+                    Missing::Pass => return vec![record],
+                    Missing::Drop => return Vec::new(),
+                    Missing::Label(ref label) => label.clone(),
+                }
+            }
+        };
+
+        super::insert(&mut record, self.target.clone(), RecordItem::String(label), changes);
+        vec![record]
+    }
+
+    /// Fails the record holding anything but a number or null in the source field.
+    fn check(&self, record: Record) -> Result<Record, Failure> {
+        let invalid = match record.find(&self.field) {
+            Some(&RecordItem::Null) | None => false,
+            Some(value) => numeric::finite(value).is_none(),
+        };
+
+        if invalid {
+            let reason = format!("'{}' field is not a finite number", self.field);
+            return Err(Failure::new(record, reason));
+        }
+        Ok(record)
+    }
+}
+
+impl FromConfig for Bucketize {
+    fn from_config(config: &Config) -> Result<Bucketize, Error> {
+        let field = try!(config.string("field"));
+        let target = try!(config.string_or("target", &format!("{}_bucket", field)));
+        let boundaries = try!(config.numbers_or("boundaries", Vec::new()));
+        let labels = try!(config.strings_or("labels", Vec::new()));
+
+        let missing = match &try!(config.string_or("missing", "pass"))[..] {
+            "pass" => Missing::Pass,
+            "drop" => Missing::Drop,
+            "label" => Missing::Label(try!(config.string("missing_label"))),
+            missing => return Err(Error::Invalid(format!("unknown missing field policy '{}'", missing))),
+        };
+
+        Bucketize::new(&field, &target, boundaries, labels, missing).map_err(Error::Invalid)
+    }
+}
+
+impl Filter for Bucketize {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.bucketize(record, None)
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply(record))
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        let records = self.bucketize(record, Some(&mut changes));
+        records.into_iter().map(|record| (record, changes.clone())).collect()
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.field.clone()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{Bucketize, Missing};
+
+    fn filter(missing: Missing) -> Bucketize {
+        let labels = vec!["<10".to_string(), "10-100".to_string(), ">100".to_string()];
+        Bucketize::new("latency", "latency_bucket", vec![10.0, 100.0], labels, missing).unwrap()
+    }
+
+    fn bucket(filter: &mut Bucketize, latency: RecordItem) -> Vec<Record> {
+        filter.apply(record(vec![("latency", latency)]))
+    }
+
+    #[test]
+    fn bucket_values_including_edges() {
+        let mut filter = filter(Missing::Pass);
+        let cases = vec![(-1.0, "<10"), (9.99, "<10"), (10.0, "10-100"), (99.5, "10-100"), (100.0, ">100"), (1e6, ">100")];
+
+        for (latency, label) in cases.into_iter() {
+            let expected = record(vec![("latency", RecordItem::F64(latency)), ("latency_bucket", string(label))]);
+            assert_eq!(vec![expected], bucket(&mut filter, RecordItem::F64(latency)));
+        }
+    }
+
+    #[test]
+    fn apply_missing_policy() {
+        let payload = record(vec![("latency", string("slow"))]);
+        assert_eq!(vec![payload.clone()], bucket(&mut filter(Missing::Pass), string("slow")));
+        assert!(bucket(&mut filter(Missing::Drop), string("slow")).is_empty());
+
+        let expected = record(vec![("latency", string("slow")), ("latency_bucket", string("unknown"))]);
+        assert_eq!(vec![expected], bucket(&mut filter(Missing::Label("unknown".to_string())), string("slow")));
+
+        // Only a value of the wrong type is a failure the `on_error` policy applies to.
+        assert!(filter(Missing::Drop).try_apply(payload.clone()).is_err());
+        assert_eq!(Ok(Vec::new()), filter(Missing::Drop).try_apply(record(vec![])));
+    }
+
+    #[test]
+    fn require_label_per_bucket() {
+        assert!(Bucketize::new("latency", "bucket", vec![10.0], vec!["<10".to_string()], Missing::Pass).is_err());
+        assert!(Bucketize::new("latency", "bucket", vec![100.0, 10.0], vec!["a".to_string(), "b".to_string(), "c".to_string()], Missing::Pass).is_err());
+    }
+}
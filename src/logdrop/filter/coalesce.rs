@@ -0,0 +1,216 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::snapshot::Snapshot;
+use super::Filter;
+
+/// Collapses consecutive equal records into a single one with the `repeated` count, like syslog's
+/// "last message repeated N times".
+///
+/// Records are compared by the configured fields or entirely if none are given. The first record of
+/// a run passes immediately, while its repeats are held back until a different record arrives or
+/// the filter is flushed, to be summarized by the last of them with `repeated` set to the length of
+/// the whole run. A run of repeats is thus summarized at least once per flush interval.
+pub struct Coalesce {
+    fields: Vec<String>,
+    pending: Option<(Record, u64)>,
+}
+
+impl Coalesce {
+    pub fn new(fields: Vec<String>) -> Coalesce {
+        Coalesce {
+            fields: fields,
+            pending: None,
+        }
+    }
+
+    fn same(&self, lhs: &Record, rhs: &Record) -> bool {
+        if self.fields.is_empty() {
+            return lhs == rhs;
+        }
+
+        self.fields.iter().all(|field| lhs.find(field) == rhs.find(field))
+    }
+
+    /// Holds the record back if it repeats the pending one, giving it back otherwise.
+    fn repeat(&mut self, record: Record) -> Option<Record> {
+        let same = match self.pending {
+            Some((ref pending, _)) => self.same(pending, &record),
+            None => false,
+        };
+
+        if same {
+            if let Some((ref mut pending, ref mut count)) = self.pending {
+                *pending = record;
+                *count += 1;
+            }
+            return None;
+        }
+
+        Some(record)
+    }
+
+    /// Takes the last record of the pending run with its count, if the run has repeats.
+    fn summarize(&mut self, changes: Option<&mut Vec<diff::Entry>>) -> Option<Record> {
+        match self.pending.take() {
+            Some((mut record, count)) if count > 1 => {
+                super::insert(&mut record, "repeated".to_string(), RecordItem::F64(count as f64), changes);
+                Some(record)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromConfig for Coalesce {
+    fn from_config(config: &Config) -> Result<Coalesce, Error> {
+        Ok(Coalesce::new(try!(config.strings_or("fields", Vec::new()))))
+    }
+}
+
+impl Filter for Coalesce {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        let record = match self.repeat(record) {
+            Some(record) => record,
+            None => return Vec::new(),
+        };
+
+        let mut result = self.flush();
+        self.pending = Some((record.clone(), 1));
+        result.push(record);
+        result
+    }
+
+    /// The summary of the previous run gets its `repeated` count reported, the record starting
+    /// the next one passes as it is.
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let record = match self.repeat(record) {
+            Some(record) => record,
+            None => return Vec::new(),
+        };
+
+        let mut changes = Vec::new();
+        let summary = self.summarize(Some(&mut changes));
+        let mut result = match summary {
+            Some(summary) => vec![(summary, changes)],
+            None => Vec::new(),
+        };
+        self.pending = Some((record.clone(), 1));
+        result.push((record, Vec::new()));
+        result
+    }
+
+    fn flush(&mut self) -> Vec<Record> {
+        self.summarize(None).into_iter().collect()
+    }
+
+    /// Coalescing whole records compares all their fields.
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        if self.fields.is_empty() {
+            None
+        } else {
+            Some(self.fields.clone())
+        }
+    }
+
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        Some(self)
+    }
+}
+
+/// The last record of the run moves into the snapshot together with its count, so that repeats
+/// arriving after a restart still collapse into it. Only the summary of repeats is held, as the
+/// first record has already passed.
+impl Snapshot for Coalesce {
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn snapshot(&mut self) -> Option<Record> {
+        let (record, count) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return None,
+        };
+
+        let mut state = Record::new();
+        state.insert("fields".to_string(), RecordItem::Array(self.fields.iter().map(|field| {
+            RecordItem::String(field.clone())
+        }).collect()));
+        state.insert("pending".to_string(), RecordItem::Object(record.iter().map(|(name, value)| {
+            (name.clone(), value.clone())
+        }).collect()));
+        state.insert("count".to_string(), RecordItem::F64(count as f64));
+
+        let mut held = Vec::new();
+        if count > 1 {
+            let mut summary = record;
+            summary.insert("repeated".to_string(), RecordItem::F64(count as f64));
+            held.push(RecordItem::Object(summary.iter().map(|(name, value)| {
+                (name.clone(), value.clone())
+            }).collect()));
+        }
+        state.insert("held".to_string(), RecordItem::Array(held));
+        Some(state)
+    }
+
+    fn restore(&mut self, state: &Record) -> Result<(), String> {
+        let fields: Vec<&str> = match state.find("fields") {
+            Some(&RecordItem::Array(ref fields)) => fields.iter().filter_map(|field| field.as_str()).collect(),
+            _ => Vec::new(),
+        };
+        if fields != self.fields.iter().map(|field| &field[..]).collect::<Vec<&str>>() {
+            return Err("compared fields are changed".to_string());
+        }
+
+        match (state.find("pending"), state.find("count")) {
+            (Some(&RecordItem::Object(ref pending)), Some(&RecordItem::F64(count))) => {
+                let mut record = Record::new();
+                for (name, value) in pending.iter() {
+                    record.insert(name.clone(), value.clone());
+                }
+                self.pending = Some((record, count as u64));
+                Ok(())
+            }
+            _ => Err("held record is malformed".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::Coalesce;
+
+    #[test]
+    fn collapse_identical_records() {
+        let mut filter = Coalesce::new(Vec::new());
+
+        let message = record(vec![("message", string("le message"))]);
+        assert_eq!(vec![message.clone()], filter.apply(message.clone()));
+        for _ in 0..4 {
+            assert!(filter.apply(message.clone()).is_empty());
+        }
+
+        let expected = record(vec![("message", string("le message")), ("repeated", RecordItem::F64(5.0))]);
+        assert_eq!(vec![expected], filter.flush());
+        assert!(filter.flush().is_empty());
+    }
+
+    #[test]
+    fn flush_on_different_record() {
+        let mut filter = Coalesce::new(vec!["message".to_string()]);
+
+        assert_eq!(1, filter.apply(record(vec![("message", string("first")), ("id", string("1"))])).len());
+        assert!(filter.apply(record(vec![("message", string("first")), ("id", string("2"))])).is_empty());
+
+        // The repeats are summarized by the last of them.
+        let expected = vec![
+            record(vec![("message", string("first")), ("id", string("2")), ("repeated", RecordItem::F64(2.0))]),
+            record(vec![("message", string("second"))]),
+        ];
+        assert_eq!(expected, filter.apply(record(vec![("message", string("second"))])));
+        assert!(filter.flush().is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::lookup;
+use super::{Failure, Filter};
+
+/// Test of a single field, the path being dotted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Present(Vec<String>),
+    Missing(Vec<String>),
+    /// The value is a string or a number equal to the given one by its decimal form, like keys of
+    /// `Lookup`.
+    Equals(Vec<String>, String),
+}
+
+impl Condition {
+    fn holds(&self, record: &Record) -> bool {
+        match *self {
+            Condition::Present(ref path) => record.find_path(path).is_some(),
+            Condition::Missing(ref path) => record.find_path(path).is_none(),
+            Condition::Equals(ref path, ref expected) => {
+                record.find_path(path).and_then(lookup::key).map_or(false, |value| value == *expected)
+            }
+        }
+    }
+
+    fn path(&self) -> &[String] {
+        match *self {
+            Condition::Present(ref path) => path,
+            Condition::Missing(ref path) => path,
+            Condition::Equals(ref path, _) => path,
+        }
+    }
+}
+
+/// Modification of the record, writing a top-level field.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Writes the value to the field.
+    Set(String, RecordItem),
+    /// Writes the value to the field unless it's present.
+    Default(String, RecordItem),
+    /// Copies the value at the dotted path to the field, unless the path is missing.
+    Copy(Vec<String>, String),
+    /// Writes the value the one at the dotted path maps to in the table to the field, looking it
+    /// up like `Lookup` does. Values missing from the table leave the field alone.
+    Map(Vec<String>, String, HashMap<String, RecordItem>),
+}
+
+/// Modifies records matching all of the conditions, e.g. setting `level` out of `severity` for
+/// records having the latter but missing the former.
+///
+/// Actions are applied in order, each one seeing what the previous ones wrote. Records not
+/// matching are passed untouched.
+pub struct Conditional {
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+}
+
+impl Conditional {
+    pub fn new(conditions: Vec<Condition>, actions: Vec<Action>) -> Conditional {
+        Conditional {
+            conditions: conditions,
+            actions: actions,
+        }
+    }
+
+    fn act(&self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        if !self.conditions.iter().all(|condition| condition.holds(record)) {
+            return;
+        }
+
+        for action in self.actions.iter() {
+            let (field, value) = match *action {
+                Action::Set(ref field, ref value) => (field, Some(value.clone())),
+                Action::Default(ref field, ref value) => {
+                    (field, if record.find(field).is_none() { Some(value.clone()) } else { None })
+                }
+                Action::Copy(ref from, ref to) => (to, record.find_path(from).cloned()),
+                Action::Map(ref from, ref to, ref table) => {
+                    (to, record.find_path(from).and_then(lookup::key).and_then(|key| table.get(&key)).cloned())
+                }
+            };
+
+            if let Some(value) = value {
+                super::insert(record, field.clone(), value, changes.as_mut().map(|changes| &mut **changes));
+            }
+        }
+    }
+}
+
+fn path(field: &str) -> Vec<String> {
+    field.split('.').map(|name| name.to_string()).collect()
+}
+
+fn condition(config: &Config) -> Result<Condition, Error> {
+    if config.find("present").is_some() {
+        Ok(Condition::Present(path(&try!(config.string("present")))))
+    } else if config.find("missing").is_some() {
+        Ok(Condition::Missing(path(&try!(config.string("missing")))))
+    } else if config.find("equals").is_some() {
+        Ok(Condition::Equals(path(&try!(config.string("field"))), try!(config.string("equals"))))
+    } else {
+        Err(Error::Invalid("condition must be one of 'present', 'missing' or 'equals'".to_string()))
+    }
+}
+
+fn action(config: &Config) -> Result<Action, Error> {
+    if config.find("set").is_some() {
+        Ok(Action::Set(try!(config.string("set")), RecordItem::String(try!(config.string("value")))))
+    } else if config.find("default").is_some() {
+        Ok(Action::Default(try!(config.string("default")), RecordItem::String(try!(config.string("value")))))
+    } else if config.find("copy").is_some() {
+        Ok(Action::Copy(path(&try!(config.string("copy"))), try!(config.string("to"))))
+    } else if config.find("map").is_some() {
+        let table = try!(config.mapping("mapping")).into_iter()
+            .map(|(key, value)| (key, RecordItem::String(value)))
+            .collect();
+        Ok(Action::Map(path(&try!(config.string("map"))), try!(config.string("to")), table))
+    } else {
+        Err(Error::Invalid("action must be one of 'set', 'default', 'copy' or 'map'".to_string()))
+    }
+}
+
+impl FromConfig for Conditional {
+    fn from_config(config: &Config) -> Result<Conditional, Error> {
+        let mut conditions = Vec::new();
+        for entry in try!(config.list("when")).iter() {
+            conditions.push(try!(condition(entry)));
+        }
+
+        let mut actions = Vec::new();
+        for entry in try!(config.list("then")).iter() {
+            actions.push(try!(action(entry)));
+        }
+        if actions.is_empty() {
+            return Err(Error::Invalid("conditional requires at least one action".to_string()));
+        }
+
+        Ok(Conditional::new(conditions, actions))
+    }
+}
+
+impl Filter for Conditional {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.act(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.act(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields: Vec<String> = self.conditions.iter().map(|condition| condition.path()[0].clone()).collect();
+        for action in self.actions.iter() {
+            match *action {
+                Action::Set(..) => {}
+                Action::Default(ref field, _) => fields.push(field.clone()),
+                Action::Copy(ref from, _) => fields.push(from[0].clone()),
+                Action::Map(ref from, _, _) => fields.push(from[0].clone()),
+            }
+        }
+
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{Action, Condition, Conditional};
+
+    fn level_from_severity() -> Conditional {
+        let mut table = HashMap::new();
+        table.insert("3".to_string(), string("error"));
+        table.insert("6".to_string(), string("info"));
+
+        let conditions = vec![
+            Condition::Missing(vec!["level".to_string()]),
+            Condition::Present(vec!["severity".to_string()]),
+            Condition::Equals(vec!["source".to_string(), "kind".to_string()], "syslog".to_string()),
+        ];
+        let actions = vec![
+            Action::Map(vec!["severity".to_string()], "level".to_string(), table),
+            Action::Default("level".to_string(), string("unknown")),
+            Action::Copy(vec!["source".to_string(), "host".to_string()], "host".to_string()),
+            Action::Set("derived".to_string(), RecordItem::Bool(true)),
+        ];
+
+        Conditional::new(conditions, actions)
+    }
+
+    fn source(host: &str) -> RecordItem {
+        let mut source = HashMap::new();
+        source.insert("kind".to_string(), string("syslog"));
+        source.insert("host".to_string(), string(host));
+        RecordItem::Object(source)
+    }
+
+    #[test]
+    fn apply_actions_when_conditions_hold() {
+        let mut filter = level_from_severity();
+
+        let payload = record(vec![("severity", RecordItem::I64(3)), ("source", source("box"))]);
+        let expected = record(vec![
+            ("severity", RecordItem::I64(3)),
+            ("source", source("box")),
+            ("level", string("error")),
+            ("host", string("box")),
+            ("derived", RecordItem::Bool(true)),
+        ]);
+        assert_eq!(vec![expected], filter.apply(payload));
+
+        // Unmapped severities fall through to the default.
+        let payload = record(vec![("severity", string("7")), ("source", source("box"))]);
+        let expected = record(vec![
+            ("severity", string("7")),
+            ("source", source("box")),
+            ("level", string("unknown")),
+            ("host", string("box")),
+            ("derived", RecordItem::Bool(true)),
+        ]);
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn pass_records_untouched_otherwise() {
+        let mut filter = level_from_severity();
+
+        let payloads = vec![
+            record(vec![("level", string("warn")), ("severity", RecordItem::I64(3)), ("source", source("box"))]),
+            record(vec![("source", source("box"))]),
+            record(vec![("severity", RecordItem::I64(3)), ("source", string("syslog"))]),
+        ];
+        for payload in payloads {
+            assert_eq!(vec![payload.clone()], filter.apply(payload));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use super::super::Record;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// What to do when the target field is already present.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Existing {
+    Overwrite,
+    Skip,
+}
+
+/// Copies values under other top-level keys, keeping the source fields intact.
+///
+/// Sources are dotted paths, e.g. `request.host`, nested values are copied deeply. A missing
+/// source leaves the record untouched, while a present target is either overwritten or kept as is.
+pub struct CopyFields {
+    pairs: Vec<(Vec<String>, String)>,
+    existing: Existing,
+}
+
+impl CopyFields {
+    /// Creates the filter copying values from each source path to the paired target key.
+    pub fn new(pairs: Vec<(String, String)>, existing: Existing) -> CopyFields {
+        let pairs = pairs.into_iter().map(|(from, to)| {
+            (from.split('.').map(|name| name.to_string()).collect(), to)
+        }).collect();
+
+        CopyFields {
+            pairs: pairs,
+            existing: existing,
+        }
+    }
+
+    fn copy(&self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        for &(ref from, ref to) in self.pairs.iter() {
+            if self.existing == Existing::Skip && record.find(to).is_some() {
+                continue;
+            }
+
+            let value = match record.find_path(from) {
+                Some(value) => value.clone(),
+                None => continue,
+            };
+            super::insert(record, to.clone(), value, changes.as_mut().map(|changes| &mut **changes));
+        }
+    }
+}
+
+impl FromConfig for CopyFields {
+    fn from_config(config: &Config) -> Result<CopyFields, Error> {
+        let mut pairs: Vec<(String, String)> = try!(config.mapping("fields")).into_iter().collect();
+        if pairs.is_empty() {
+            return Err(Error::Invalid("copy requires at least one field".to_string()));
+        }
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let existing = match &try!(config.string_or("existing", "overwrite"))[..] {
+            "overwrite" => Existing::Overwrite,
+            "skip" => Existing::Skip,
+            existing => return Err(Error::Invalid(format!("unknown existing field policy '{}'", existing))),
+        };
+
+        Ok(CopyFields::new(pairs, existing))
+    }
+}
+
+impl Filter for CopyFields {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.copy(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.copy(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields = Vec::new();
+        for &(ref from, ref to) in self.pairs.iter() {
+            fields.push(from[0].clone());
+            if self.existing == Existing::Skip {
+                fields.push(to.clone());
+            }
+        }
+
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::RecordItem;
+    use super::super::super::diff::{Change, Entry};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{CopyFields, Existing};
+
+    fn pairs(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values.iter().map(|&(from, to)| (from.to_string(), to.to_string())).collect()
+    }
+
+    #[test]
+    fn copy_top_level_field() {
+        let mut filter = CopyFields::new(pairs(&[("message", "log.original"), ("absent", "copy")]), Existing::Overwrite);
+
+        let expected = record(vec![("message", string("le message")), ("log.original", string("le message"))]);
+        assert_eq!(vec![expected], filter.apply(record(vec![("message", string("le message"))])));
+    }
+
+    #[test]
+    fn copy_nested_field() {
+        let mut headers = HashMap::new();
+        headers.insert("agent".to_string(), string("curl"));
+        let mut request = HashMap::new();
+        request.insert("headers".to_string(), RecordItem::Object(headers.clone()));
+
+        let mut filter = CopyFields::new(pairs(&[("request.headers", "headers")]), Existing::Overwrite);
+        let payload = record(vec![("request", RecordItem::Object(request))]);
+
+        let mut expected = payload.clone();
+        expected.insert("headers".to_string(), RecordItem::Object(headers));
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn overwrite_or_skip_existing_target() {
+        let payload = record(vec![("message", string("new")), ("original", string("old"))]);
+
+        let mut filter = CopyFields::new(pairs(&[("message", "original")]), Existing::Overwrite);
+        let expected = record(vec![("message", string("new")), ("original", string("new"))]);
+        assert_eq!(vec![expected], filter.apply(payload.clone()));
+
+        let mut filter = CopyFields::new(pairs(&[("message", "original")]), Existing::Skip);
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+    }
+
+    #[test]
+    fn report_copied_fields() {
+        let mut filter = CopyFields::new(pairs(&[("message", "original"), ("message", "copy"), ("absent", "other")]),
+            Existing::Overwrite);
+        let payload = record(vec![("message", string("new")), ("original", string("old"))]);
+
+        let changes = vec![
+            Entry { path: vec!["original".to_string()], change: Change::Changed(string("old"), string("new")) },
+            Entry { path: vec!["copy".to_string()], change: Change::Added(string("new")) },
+        ];
+        let records = filter.apply_traced(payload.clone());
+        assert_eq!(changes, records[0].1);
+        assert_eq!(filter.apply(payload), vec![records[0].0.clone()]);
+    }
+}
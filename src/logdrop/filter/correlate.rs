@@ -0,0 +1,391 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::snapshot::Snapshot;
+use super::Filter;
+
+/// What happens to records still missing parts once the timeout is over.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Incomplete {
+    /// Emit the parts received merged, marked `_partial` and listing them in `_parts`.
+    Partial,
+    Drop,
+}
+
+/// Parts of a record received so far.
+struct Pending {
+    record: Record,
+    parts: Vec<String>,
+    /// When the first part has arrived.
+    started: u64,
+}
+
+/// Joins records emitted in parts, e.g. the begin and the end event of a request, into a single
+/// record by the correlation key field, e.g. the request id.
+///
+/// The part a record is tells the part field, parts merging in the order they arrive, so the
+/// fields of later ones win. The merged record is emitted once all the expected parts have
+/// arrived, without the part field. Records without the key or of an unexpected part pass
+/// untouched.
+///
+/// Records still missing parts the timeout after their first one are emitted partial or dropped,
+/// which is checked whenever a record arrives and on flush. Ones cut short by a reload or a
+/// shutdown are emitted partial whatever the policy, unless they are carried over in the state.
+pub struct Correlate {
+    key: String,
+    part: String,
+    parts: Vec<String>,
+    /// Timeout in milliseconds.
+    timeout: u64,
+    incomplete: Incomplete,
+    clock: Arc<Clock>,
+    pending: HashMap<String, Pending>,
+    /// Keys in the order their first part has arrived, together with the time it has. Keys
+    /// completed meanwhile stay until they are popped.
+    order: VecDeque<(u64, String)>,
+}
+
+impl Correlate {
+    pub fn new(key: &str, part: &str, parts: Vec<String>, timeout: u64, clock: Arc<Clock>) -> Correlate {
+        Correlate {
+            key: key.to_string(),
+            part: part.to_string(),
+            parts: parts,
+            timeout: timeout,
+            incomplete: Incomplete::Partial,
+            clock: clock,
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn incomplete(mut self, incomplete: Incomplete) -> Correlate {
+        self.incomplete = incomplete;
+        self
+    }
+
+    /// Returns records timed out by now, oldest first.
+    fn expire(&mut self) -> Vec<Record> {
+        let now = self.clock.now();
+        let mut result = Vec::new();
+        while let Some(&(started, _)) = self.order.front() {
+            if now.saturating_sub(started) < self.timeout {
+                break;
+            }
+
+            let (started, key) = self.order.pop_front().unwrap();
+            if let Some(pending) = self.take(&key, started) {
+                if self.incomplete == Incomplete::Partial {
+                    result.push(partial(pending));
+                }
+            }
+        }
+        result
+    }
+
+    /// Removes the pending record of the key, unless it's a later one than the given start.
+    fn take(&mut self, key: &str, started: u64) -> Option<Pending> {
+        match self.pending.get(key) {
+            Some(pending) if pending.started == started => {}
+            _ => return None,
+        }
+        self.pending.remove(key)
+    }
+
+    /// Merges the record into the pending one of its key, returning the merged record once all
+    /// the parts have arrived, or the record itself if it's no part at all.
+    fn correlate(&mut self, record: Record, changes: Option<&mut Vec<diff::Entry>>) -> Option<Record> {
+        let key = match record.find(&self.key).and_then(|value| value.as_str()) {
+            Some(key) => key.to_string(),
+            None => return Some(record),
+        };
+        let part = match record.find(&self.part).and_then(|value| value.as_str()) {
+            Some(part) if self.parts.iter().any(|expected| expected == part) => part.to_string(),
+            _ => return Some(record),
+        };
+
+        let now = self.clock.now();
+        if !self.pending.contains_key(&key) {
+            self.order.push_back((now, key.clone()));
+        }
+        let complete = {
+            let pending = self.pending.entry(key.clone()).or_insert_with(|| Pending {
+                record: Record::new(),
+                parts: Vec::new(),
+                started: now,
+            });
+
+            for (name, value) in record.iter() {
+                if name != &self.part {
+                    pending.record.insert(name.clone(), value.clone());
+                }
+            }
+            if !pending.parts.contains(&part) {
+                pending.parts.push(part);
+            }
+
+            pending.parts.len() == self.parts.len()
+        };
+
+        if !complete {
+            return None;
+        }
+
+        let merged = self.pending.remove(&key).unwrap().record;
+        if let Some(changes) = changes {
+            changes.extend(super::replaced(&record, &merged).into_iter());
+        }
+        Some(merged)
+    }
+}
+
+fn partial(pending: Pending) -> Record {
+    let Pending { mut record, parts, .. } = pending;
+    record.insert("_partial".to_string(), RecordItem::Bool(true));
+    record.insert("_parts".to_string(), RecordItem::Array(parts.into_iter().map(RecordItem::String).collect()));
+    record
+}
+
+impl FromConfig for Correlate {
+    fn from_config(config: &Config) -> Result<Correlate, Error> {
+        let key = try!(config.string("key"));
+        let part = try!(config.string_or("part_field", "part"));
+        let parts = try!(config.strings_or("parts", Vec::new()));
+        if parts.len() < 2 {
+            return Err(Error::Invalid("correlate requires at least two 'parts'".to_string()));
+        }
+
+        let timeout = try!(config.u64_or("timeout", 30000));
+        let incomplete = match &try!(config.string_or("on_timeout", "partial"))[..] {
+            "partial" => Incomplete::Partial,
+            "drop" => Incomplete::Drop,
+            incomplete => return Err(Error::Invalid(format!("unknown 'on_timeout' policy '{}'", incomplete))),
+        };
+
+        Ok(Correlate::new(&key, &part, parts, timeout, Arc::new(SystemClock)).incomplete(incomplete))
+    }
+}
+
+impl Filter for Correlate {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        let mut result = self.expire();
+        result.extend(self.correlate(record, None).into_iter());
+        result
+    }
+
+    /// Records timed out meanwhile get no changes reported, while the merged one gets the fields
+    /// merged into the last part reported.
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut result: Vec<_> = self.expire().into_iter().map(|record| (record, Vec::new())).collect();
+        let mut changes = Vec::new();
+        if let Some(record) = self.correlate(record, Some(&mut changes)) {
+            result.push((record, changes));
+        }
+        result
+    }
+
+    fn flush(&mut self) -> Vec<Record> {
+        self.expire()
+    }
+
+    fn close(&mut self) -> Vec<Record> {
+        let mut result = self.expire();
+        while let Some((started, key)) = self.order.pop_front() {
+            if let Some(pending) = self.take(&key, started) {
+                result.push(partial(pending));
+            }
+        }
+        result
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.key.clone(), self.part.clone()])
+    }
+
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        Some(self)
+    }
+}
+
+/// Pending records move into the snapshot together with their parts and the time their first
+/// part has arrived, so that parts arriving after a restart or a reload still merge into them.
+impl Snapshot for Correlate {
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn snapshot(&mut self) -> Option<Record> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let mut pending = Vec::new();
+        let mut held = Vec::new();
+        while let Some((started, key)) = self.order.pop_front() {
+            if let Some(Pending { record, parts, .. }) = self.take(&key, started) {
+                let mut entry = HashMap::new();
+                entry.insert("key".to_string(), RecordItem::String(key));
+                entry.insert("record".to_string(), RecordItem::Object(record.iter().map(|(name, value)| {
+                    (name.clone(), value.clone())
+                }).collect()));
+                entry.insert("parts".to_string(), RecordItem::Array(parts.iter().cloned().map(RecordItem::String).collect()));
+                entry.insert("started".to_string(), RecordItem::F64(started as f64));
+                pending.push(RecordItem::Object(entry));
+
+                let record = partial(Pending { record: record, parts: parts, started: started });
+                held.push(RecordItem::Object(record.iter().map(|(name, value)| {
+                    (name.clone(), value.clone())
+                }).collect()));
+            }
+        }
+
+        let mut state = Record::new();
+        state.insert("key".to_string(), RecordItem::String(self.key.clone()));
+        state.insert("part".to_string(), RecordItem::String(self.part.clone()));
+        state.insert("pending".to_string(), RecordItem::Array(pending));
+        state.insert("held".to_string(), RecordItem::Array(held));
+        Some(state)
+    }
+
+    fn restore(&mut self, state: &Record) -> Result<(), String> {
+        if state.find("key").and_then(|key| key.as_str()) != Some(&self.key[..]) ||
+            state.find("part").and_then(|part| part.as_str()) != Some(&self.part[..]) {
+            return Err("correlation fields are changed".to_string());
+        }
+
+        let entries = match state.find("pending") {
+            Some(&RecordItem::Array(ref entries)) => entries,
+            _ => return Err("pending records are malformed".to_string()),
+        };
+
+        let mut restored = Vec::new();
+        for entry in entries.iter() {
+            match (entry.find("key"), entry.find("record"), entry.find("parts"), entry.find("started")) {
+                (Some(&RecordItem::String(ref key)),
+                 Some(&RecordItem::Object(ref fields)),
+                 Some(&RecordItem::Array(ref parts)),
+                 Some(&RecordItem::F64(started))) => {
+                    let mut record = Record::new();
+                    for (name, value) in fields.iter() {
+                        record.insert(name.clone(), value.clone());
+                    }
+                    let parts = parts.iter().filter_map(|part| part.as_str()).map(|part| part.to_string()).collect();
+                    restored.push((key.clone(), Pending { record: record, parts: parts, started: started as u64 }));
+                }
+                _ => return Err("pending records are malformed".to_string()),
+            }
+        }
+
+        for (key, pending) in restored.into_iter() {
+            self.order.push_back((pending.started, key.clone()));
+            self.pending.insert(key, pending);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::clock::MockClock;
+    use super::super::super::snapshot::Snapshot;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{Correlate, Incomplete};
+
+    fn correlate(clock: &MockClock) -> Correlate {
+        Correlate::new("request_id", "event", vec!["begin".to_string(), "end".to_string()], 1000, Arc::new(clock.clone()))
+    }
+
+    #[test]
+    fn merge_correlated_parts() {
+        let clock = MockClock::new(0);
+        let mut filter = correlate(&clock);
+
+        assert!(filter.apply(record(vec![
+            ("request_id", string("a")),
+            ("event", string("begin")),
+            ("path", string("/index.html")),
+        ])).is_empty());
+        assert!(filter.apply(record(vec![("request_id", string("b")), ("event", string("begin"))])).is_empty());
+
+        clock.advance(200);
+        let merged = filter.apply(record(vec![
+            ("request_id", string("a")),
+            ("event", string("end")),
+            ("status", RecordItem::U64(200)),
+        ]));
+        assert_eq!(vec![record(vec![
+            ("request_id", string("a")),
+            ("path", string("/index.html")),
+            ("status", RecordItem::U64(200)),
+        ])], merged);
+
+        // Records without the key or of other parts pass untouched.
+        let unrelated = record(vec![("request_id", string("b")), ("event", string("retry"))]);
+        assert_eq!(vec![unrelated.clone()], filter.apply(unrelated));
+        assert_eq!(1, filter.pending.len());
+    }
+
+    #[test]
+    fn time_out_incomplete_record() {
+        let clock = MockClock::new(0);
+        let mut filter = correlate(&clock);
+        let begin = record(vec![("request_id", string("a")), ("event", string("begin")), ("path", string("/"))]);
+
+        filter.apply(begin.clone());
+        clock.advance(999);
+        assert_eq!(Vec::<Record>::new(), filter.flush());
+
+        clock.advance(1);
+        assert_eq!(vec![record(vec![
+            ("request_id", string("a")),
+            ("path", string("/")),
+            ("_partial", RecordItem::Bool(true)),
+            ("_parts", RecordItem::Array(vec![string("begin")])),
+        ])], filter.flush());
+
+        // The late part no longer has anything to merge with.
+        assert!(filter.apply(record(vec![("request_id", string("a")), ("event", string("end"))])).is_empty());
+
+        let mut filter = correlate(&clock).incomplete(Incomplete::Drop);
+        filter.apply(begin);
+        clock.advance(1000);
+        assert_eq!(Vec::<Record>::new(), filter.flush());
+        assert!(filter.pending.is_empty());
+    }
+
+    #[test]
+    fn carry_pending_over_snapshot_and_close_the_rest() {
+        let clock = MockClock::new(0);
+        let mut filter = correlate(&clock);
+        filter.apply(record(vec![("request_id", string("a")), ("event", string("begin")), ("path", string("/"))]));
+
+        let state = filter.snapshot().unwrap();
+        assert!(filter.close().is_empty());
+
+        let mut restored = correlate(&clock);
+        restored.restore(&state).unwrap();
+        assert!(Correlate::new("trace_id", "event", Vec::new(), 1000, Arc::new(clock.clone())).restore(&state).is_err());
+
+        clock.advance(200);
+        restored.apply(record(vec![("request_id", string("b")), ("event", string("begin"))]));
+        assert_eq!(vec![record(vec![("request_id", string("a")), ("path", string("/")), ("status", RecordItem::U64(200))])],
+            restored.apply(record(vec![("request_id", string("a")), ("event", string("end")), ("status", RecordItem::U64(200))])));
+
+        // Records not timed out yet are emitted partial on close whatever the policy.
+        let mut restored = restored.incomplete(Incomplete::Drop);
+        assert_eq!(vec![record(vec![
+            ("request_id", string("b")),
+            ("_partial", RecordItem::Bool(true)),
+            ("_parts", RecordItem::Array(vec![string("begin")])),
+        ])], restored.close());
+        assert!(restored.order.is_empty());
+    }
+}
@@ -0,0 +1,604 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::sync::Arc;
+
+use super::super::{Record, RecordItem};
+use super::super::calendar::{Calendar, Windows};
+use super::super::clock::{Clock, SystemClock};
+use super::super::codec::msgpack;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::snapshot::Snapshot;
+use super::super::warmup;
+use super::super::warmup::WarmUp;
+use super::Filter;
+
+/// Number of recent record sizes per band the shedding cutoff is computed from.
+const SAMPLE: usize = 128;
+
+/// Priority band of a record, higher bands go first.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Band {
+    High,
+    Normal,
+    Low,
+}
+
+impl Band {
+    fn index(&self) -> usize {
+        match *self {
+            Band::High => 0,
+            Band::Normal => 1,
+            Band::Low => 2,
+        }
+    }
+}
+
+/// Rolling state of a priority band.
+struct Rate {
+    /// Offered byte rate per second, exponentially averaged over the time constant.
+    offered: f64,
+    sizes: VecDeque<usize>,
+}
+
+impl Rate {
+    fn new() -> Rate {
+        Rate {
+            offered: 0.0,
+            sizes: VecDeque::new(),
+        }
+    }
+
+    /// Returns the size below which records pass unconditionally, so that passing each larger
+    /// record with `cutoff / size` probability keeps the given fraction of bytes.
+    fn cutoff(&self, fraction: f64) -> f64 {
+        let mut sizes: Vec<usize> = self.sizes.iter().cloned().collect();
+        sizes.sort();
+
+        let total = sizes.iter().fold(0, |acc, &size| acc + size) as f64;
+        let target = total * fraction;
+        let mut acc = 0.0;
+        for (id, &size) in sizes.iter().enumerate() {
+            let rest = (sizes.len() - id) as f64;
+            if acc + size as f64 * rest >= target {
+                return (target - acc) / rest;
+            }
+            acc += size as f64;
+        }
+
+        total
+    }
+}
+
+/// Bytes and records passed and shed since the last summary.
+#[derive(Default)]
+struct Summary {
+    passed: usize,
+    shed: usize,
+    passed_bytes: usize,
+    shed_bytes: usize,
+}
+
+/// Keeps the byte rate of records passing it within the budget by shedding records.
+///
+/// Record sizes are estimated by their encoded MessagePack length. Higher priority bands are
+/// served first, so high-priority records are never shed while lower ones are still offered,
+/// unless they alone exceed the budget. Within a band larger records are shed first: those above
+/// the cutoff pass with `cutoff / size` probability. Survivors of shedding are annotated with
+/// the inverse of their pass probability in the weight field, allowing downstream to re-weight.
+///
+/// Rates are averaged over the time constant, which is also roughly the time to converge to the
+/// budget. Every flush emits the `bandwidth_summary` record with bytes passed and shed meanwhile.
+///
+/// With the warm-up phase given, the budget grows from a tenth to the whole of it during the
+/// phase, as rates averaged over no history let bursts through, and the first summary is marked
+/// as covering a `partial` window.
+///
+/// With summary windows aligned to the wall clock, summaries are emitted for each closed window
+/// instead, annotated with its bounds, so that those of instances started at different times can
+/// be summed. The window the governor has started within is marked `partial`.
+pub struct Governor {
+    budget: f64,
+    constant: f64,
+    field: String,
+    high: Vec<String>,
+    low: Vec<String>,
+    weight: String,
+    rates: [Rate; 3],
+    updated: Option<u64>,
+    summary: Summary,
+    seed: u64,
+    clock: Arc<Clock>,
+    warm_up: Option<WarmUp>,
+    /// Whether no summary has been emitted yet.
+    first: bool,
+    windows: Option<Windows>,
+    /// Summaries of closed windows not yet flushed.
+    closed: Vec<Record>,
+}
+
+impl Governor {
+    /// Creates the governor passing at most `budget` bytes per second on average over the time
+    /// constant given in milliseconds.
+    pub fn new(budget: u64, constant: u64, clock: Arc<Clock>) -> Governor {
+        let seed = clock.now() | 1;
+        Governor {
+            budget: budget as f64,
+            constant: constant as f64,
+            field: "priority".to_string(),
+            high: vec!["high".to_string()],
+            low: vec!["low".to_string()],
+            weight: "_sample_weight".to_string(),
+            rates: [Rate::new(), Rate::new(), Rate::new()],
+            updated: None,
+            summary: Summary::default(),
+            seed: seed,
+            clock: clock,
+            warm_up: None,
+            first: true,
+            windows: None,
+            closed: Vec::new(),
+        }
+    }
+
+    /// Sets the priority field together with its values for high and low bands, all the other
+    /// records falling into the normal one.
+    pub fn priority(mut self, field: &str, high: Vec<String>, low: Vec<String>) -> Governor {
+        self.field = field.to_string();
+        self.high = high;
+        self.low = low;
+        self
+    }
+
+    pub fn weight(mut self, field: &str) -> Governor {
+        self.weight = field.to_string();
+        self
+    }
+
+    pub fn warm_up(mut self, warm_up: WarmUp) -> Governor {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    /// Summarizes windows of the calendar rather than periods between flushes.
+    pub fn summary_window(mut self, calendar: Calendar) -> Governor {
+        self.windows = Some(Windows::new(calendar, self.clock.clone()));
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Governor {
+        self.seed = seed | 1;
+        self
+    }
+
+    fn band(&self, record: &Record) -> Band {
+        match record.find(&self.field).and_then(|value| value.as_str()) {
+            Some(value) if self.high.iter().any(|high| high == value) => Band::High,
+            Some(value) if self.low.iter().any(|low| low == value) => Band::Low,
+            _ => Band::Normal,
+        }
+    }
+
+    /// Returns the summary of records passed and shed since the last one, if any.
+    fn summarize(&mut self) -> Option<Record> {
+        if self.summary.passed + self.summary.shed == 0 {
+            return None;
+        }
+
+        let summary = mem::replace(&mut self.summary, Summary::default());
+
+        let mut record = Record::new();
+        record.insert("type".to_string(), RecordItem::String("bandwidth_summary".to_string()));
+        record.insert("passed".to_string(), RecordItem::F64(summary.passed as f64));
+        record.insert("shed".to_string(), RecordItem::F64(summary.shed as f64));
+        record.insert("passed_bytes".to_string(), RecordItem::F64(summary.passed_bytes as f64));
+        record.insert("shed_bytes".to_string(), RecordItem::F64(summary.shed_bytes as f64));
+        if self.warm_up.is_some() {
+            record.insert("partial".to_string(), RecordItem::Bool(self.first));
+        }
+        self.first = false;
+
+        Some(record)
+    }
+
+    /// Stashes the summary of the summary window once it closes.
+    fn roll(&mut self) {
+        let window = match self.windows.as_mut().and_then(|windows| windows.poll()) {
+            Some(window) => window,
+            None => return,
+        };
+
+        if let Some(mut record) = self.summarize() {
+            window.annotate(&mut record);
+            self.closed.push(record);
+        }
+    }
+
+    /// Decays offered rates to the current time.
+    fn decay(&mut self) {
+        let now = self.clock.now();
+        if let Some(updated) = self.updated {
+            let factor = (-(now.saturating_sub(updated) as f64) / self.constant).exp();
+            for rate in self.rates.iter_mut() {
+                rate.offered *= factor;
+            }
+        }
+        self.updated = Some(now);
+    }
+
+    /// Returns the fraction of offered bytes of the band fitting into the budget left by higher
+    /// bands.
+    fn fraction(&self, band: Band) -> f64 {
+        let higher = self.rates[..band.index()].iter().fold(0.0, |acc, rate| acc + rate.offered);
+        let offered = self.rates[band.index()].offered;
+        let budget = match self.warm_up {
+            Some(ref warm_up) => warm_up.scale(self.budget),
+            None => self.budget,
+        };
+        let allowance = (budget - higher).max(0.0);
+
+        if allowance >= offered {
+            1.0
+        } else {
+            allowance / offered
+        }
+    }
+
+    /// Sheds the record or passes it on, weighted if it has survived shedding.
+    fn govern(&mut self, mut record: Record, changes: Option<&mut Vec<diff::Entry>>) -> Vec<Record> {
+        self.roll();
+
+        let size = msgpack::encode(&record).len();
+        let band = self.band(&record);
+
+        self.decay();
+        {
+            let rate = &mut self.rates[band.index()];
+            rate.offered += size as f64 * 1000.0 / self.constant;
+            if rate.sizes.len() == SAMPLE {
+                rate.sizes.pop_front();
+            }
+            rate.sizes.push_back(size);
+        }
+
+        let fraction = self.fraction(band);
+        let probability = if fraction < 1.0 {
+            (self.rates[band.index()].cutoff(fraction) / size as f64).min(1.0)
+        } else {
+            1.0
+        };
+
+        if probability < 1.0 {
+            if super::random(&mut self.seed) >= probability {
+                self.summary.shed += 1;
+                self.summary.shed_bytes += size;
+                return Vec::new();
+            }
+            super::insert(&mut record, self.weight.clone(), RecordItem::F64(1.0 / probability), changes);
+        }
+
+        self.summary.passed += 1;
+        self.summary.passed_bytes += size;
+        vec![record]
+    }
+}
+
+impl FromConfig for Governor {
+    fn from_config(config: &Config) -> Result<Governor, Error> {
+        let budget = try!(config.u64_or("budget", 0));
+        if budget == 0 {
+            return Err(Error::Invalid("governor requires positive 'budget' in bytes per second".to_string()));
+        }
+
+        let constant = try!(config.u64_or("time_constant", 10000));
+        if constant == 0 {
+            return Err(Error::Invalid("'time_constant' must be positive".to_string()));
+        }
+
+        let field = try!(config.string_or("field", "priority"));
+        let high = try!(config.strings_or("high", vec!["high".to_string()]));
+        let low = try!(config.strings_or("low", vec!["low".to_string()]));
+
+        let mut governor = Governor::new(budget, constant, Arc::new(SystemClock))
+            .priority(&field, high, low)
+            .weight(&try!(config.string_or("weight", "_sample_weight")));
+
+        if let Some(window) = config.find("summary_window") {
+            governor = governor.summary_window(try!(Calendar::from_config(&window)));
+        }
+
+        if try!(config.bool_or("warm_up", false)) {
+            Ok(governor.warm_up(warmup::process()))
+        } else {
+            Ok(governor)
+        }
+    }
+}
+
+impl Filter for Governor {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.govern(record, None)
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        let records = self.govern(record, Some(&mut changes));
+        records.into_iter().map(|record| (record, changes.clone())).collect()
+    }
+
+    fn flush(&mut self) -> Vec<Record> {
+        if self.windows.is_some() {
+            self.roll();
+            return mem::replace(&mut self.closed, Vec::new());
+        }
+
+        self.summarize().into_iter().collect()
+    }
+
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        Some(self)
+    }
+}
+
+/// Offered rates are carried over restarts, decaying over the downtime as usual, so that the
+/// budget is not overshot right after one. Record sizes sampled per band are bounded by `SAMPLE`.
+impl Snapshot for Governor {
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn snapshot(&mut self) -> Option<Record> {
+        let updated = match self.updated {
+            Some(updated) => updated,
+            None => return None,
+        };
+
+        let rates = self.rates.iter().map(|rate| {
+            let mut saved = HashMap::new();
+            saved.insert("offered".to_string(), RecordItem::F64(rate.offered));
+            saved.insert("sizes".to_string(), RecordItem::Array(rate.sizes.iter().map(|&size| {
+                RecordItem::F64(size as f64)
+            }).collect()));
+            RecordItem::Object(saved)
+        }).collect();
+
+        let mut state = Record::new();
+        state.insert("budget".to_string(), RecordItem::F64(self.budget));
+        state.insert("time_constant".to_string(), RecordItem::F64(self.constant));
+        state.insert("updated".to_string(), RecordItem::F64(updated as f64));
+        state.insert("rates".to_string(), RecordItem::Array(rates));
+        Some(state)
+    }
+
+    fn restore(&mut self, state: &Record) -> Result<(), String> {
+        match (state.find("budget"), state.find("time_constant")) {
+            (Some(&RecordItem::F64(budget)), Some(&RecordItem::F64(constant)))
+                if budget == self.budget && constant == self.constant => {}
+            _ => return Err("budget or time constant is changed".to_string()),
+        }
+
+        let rates = match state.find("rates") {
+            Some(&RecordItem::Array(ref rates)) if rates.len() == self.rates.len() => rates,
+            _ => return Err("rates are malformed".to_string()),
+        };
+        let updated = match state.find("updated") {
+            Some(&RecordItem::F64(updated)) => updated as u64,
+            _ => return Err("rates are malformed".to_string()),
+        };
+
+        for (rate, saved) in self.rates.iter_mut().zip(rates.iter()) {
+            rate.offered = match saved.find("offered") {
+                Some(&RecordItem::F64(offered)) => offered,
+                _ => 0.0,
+            };
+            rate.sizes = match saved.find("sizes") {
+                Some(&RecordItem::Array(ref sizes)) => {
+                    sizes.iter().rev().take(SAMPLE).filter_map(|size| match *size {
+                        RecordItem::F64(size) => Some(size as usize),
+                        _ => None,
+                    }).collect::<Vec<_>>().into_iter().rev().collect()
+                }
+                _ => VecDeque::new(),
+            };
+        }
+        self.updated = Some(updated);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter;
+    use std::sync::Arc;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::calendar::{Calendar, Zone};
+    use super::super::super::clock::{Clock, MockClock};
+    use super::super::super::codec::msgpack;
+    use super::super::super::testing::{record, string};
+    use super::super::super::warmup::WarmUp;
+    use super::super::Filter;
+    use super::Governor;
+
+    const BUDGET: u64 = 200000;
+
+    /// Totals of a synthetic stream passed through the governor.
+    #[derive(Default)]
+    struct Totals {
+        offered: usize,
+        offered_bytes: usize,
+        passed: usize,
+        /// Bytes passed since the warm-up.
+        passed_bytes: usize,
+        weighted: f64,
+        weighted_bytes: f64,
+        high_offered: usize,
+        high_passed: usize,
+    }
+
+    /// Feeds one record per millisecond for the given number of seconds, sizes cycling from tens
+    /// of bytes to a few kilobytes. Every tenth record is high priority, every third is low, if
+    /// enabled.
+    fn drive(filter: &mut Governor, clock: &MockClock, seconds: u64, warmup: u64, low: bool) -> Totals {
+        let mut totals = Totals::default();
+        for id in 0..seconds * 1000 {
+            clock.advance(1);
+
+            let len = 20 + (id * 7919 % 97) as usize * (id * 104729 % 31) as usize;
+            let priority = if id % 10 == 0 { "high" } else if low && id % 3 == 0 { "low" } else { "normal" };
+            let payload = record(vec![
+                ("message", RecordItem::String(iter::repeat('x').take(len).collect())),
+                ("priority", string(priority)),
+            ]);
+            let size = msgpack::encode(&payload).len();
+
+            totals.offered += 1;
+            totals.offered_bytes += size;
+            if priority == "high" {
+                totals.high_offered += 1;
+            }
+
+            for record in filter.apply(payload).into_iter() {
+                let weight = match record.find("_sample_weight") {
+                    Some(&RecordItem::F64(weight)) => weight,
+                    _ => 1.0,
+                };
+
+                totals.passed += 1;
+                totals.weighted += weight;
+                totals.weighted_bytes += weight * size as f64;
+                if id >= warmup * 1000 {
+                    totals.passed_bytes += size;
+                }
+                if priority == "high" {
+                    totals.high_passed += 1;
+                }
+            }
+        }
+        totals
+    }
+
+    fn governor(clock: &MockClock) -> Governor {
+        Governor::new(BUDGET, 1000, Arc::new(clock.clone())).seed(42)
+    }
+
+    #[test]
+    fn converge_to_budget() {
+        let clock = MockClock::new(0);
+        let mut filter = governor(&clock);
+        let totals = drive(&mut filter, &clock, 20, 5, true);
+
+        assert!(totals.offered_bytes > 20 * 2 * BUDGET as usize);
+        let rate = totals.passed_bytes as f64 / 15.0;
+        assert!((rate - BUDGET as f64).abs() < BUDGET as f64 * 0.1, "passed {} bytes per second", rate);
+    }
+
+    #[test]
+    fn never_shed_high_priority_while_lower_remain() {
+        let clock = MockClock::new(0);
+        let mut filter = governor(&clock);
+        let totals = drive(&mut filter, &clock, 10, 0, true);
+
+        assert!(totals.passed < totals.offered);
+        assert_eq!(totals.high_offered, totals.high_passed);
+    }
+
+    #[test]
+    fn weights_match_shed_rate() {
+        let clock = MockClock::new(0);
+        let mut filter = governor(&clock);
+        // Low-priority records are shed completely, which no weight can account for.
+        let totals = drive(&mut filter, &clock, 20, 0, false);
+
+        assert!(totals.passed < totals.offered / 2);
+
+        let offered = totals.offered as f64;
+        let offered_bytes = totals.offered_bytes as f64;
+        assert!((totals.weighted - offered).abs() < offered * 0.05, "weighted {} of {}", totals.weighted, offered);
+        assert!((totals.weighted_bytes - offered_bytes).abs() < offered_bytes * 0.1,
+            "weighted {} bytes of {}", totals.weighted_bytes, offered_bytes);
+    }
+
+    #[test]
+    fn scale_budget_during_warm_up() {
+        let plain = {
+            let clock = MockClock::new(0);
+            drive(&mut governor(&clock), &clock, 5, 0, true)
+        };
+
+        let clock = MockClock::new(0);
+        let warm_up = WarmUp::new(10000, Arc::new(clock.clone()));
+        warm_up.start();
+        let mut filter = governor(&clock).warm_up(warm_up);
+
+        // Halfway through the phase the budget has grown to a half of the configured one only.
+        let early = drive(&mut filter, &clock, 5, 0, true);
+        assert!(early.passed_bytes < plain.passed_bytes * 7 / 10, "passed {} bytes, {} without warm-up",
+            early.passed_bytes, plain.passed_bytes);
+        assert_eq!(Some(&RecordItem::Bool(true)), filter.flush()[0].find("partial"));
+
+        let late = drive(&mut filter, &clock, 20, 10, true);
+        let rate = late.passed_bytes as f64 / 10.0;
+        assert!((rate - BUDGET as f64).abs() < BUDGET as f64 * 0.1, "passed {} bytes per second", rate);
+        assert_eq!(Some(&RecordItem::Bool(false)), filter.flush()[0].find("partial"));
+    }
+
+    #[test]
+    fn summarize_on_flush() {
+        let clock = MockClock::new(0);
+        let mut filter = governor(&clock);
+        let totals = drive(&mut filter, &clock, 2, 0, true);
+
+        let summary = filter.flush();
+        assert_eq!(1, summary.len());
+        let count = |key: &str| match summary[0].find(key) {
+            Some(&RecordItem::F64(v)) => v as usize,
+            other => panic!("unexpected {}: {:?}", key, other),
+        };
+        assert_eq!(totals.passed, count("passed"));
+        assert_eq!(totals.offered - totals.passed, count("shed"));
+        assert_eq!(totals.offered_bytes, count("passed_bytes") + count("shed_bytes"));
+        assert_eq!(Vec::<Record>::new(), filter.flush());
+    }
+
+    #[test]
+    fn align_summaries_of_instances_started_apart() {
+        // A minute boundary.
+        const START: u64 = 1434326400000;
+
+        let offered = |clock: &MockClock, filter: &mut Governor, until: u64| {
+            let mut count = 0;
+            while clock.now() < until {
+                filter.apply(record(vec![("message", string("le message"))]));
+                clock.advance(100);
+                count += 1;
+            }
+            count
+        };
+        let count = |summary: &Record, key: &str| match summary.find(key) {
+            Some(&RecordItem::F64(v)) => v as u64,
+            other => panic!("unexpected {}: {:?}", key, other),
+        };
+
+        let early = MockClock::new(START + 10000);
+        let late = MockClock::new(START + 35000);
+        let mut first = governor(&early).summary_window(Calendar::new(60000, 0, Zone::utc()));
+        let mut second = governor(&late).summary_window(Calendar::new(60000, 0, Zone::utc()));
+
+        let total = offered(&early, &mut first, START + 60000) + offered(&late, &mut second, START + 60000);
+        assert_eq!(Vec::<Record>::new(), first.flush());
+
+        offered(&early, &mut first, START + 70000);
+        offered(&late, &mut second, START + 70000);
+
+        let (first, second) = (first.flush(), second.flush());
+        assert_eq!((1, 1), (first.len(), second.len()));
+        for key in ["window_start", "window_end"].iter() {
+            assert_eq!(first[0].find(key), second[0].find(key));
+        }
+        assert_eq!(Some(&RecordItem::F64(START as f64 / 1000.0)), first[0].find("window_start"));
+        assert_eq!(Some(&RecordItem::Bool(true)), second[0].find("partial"));
+        assert_eq!(total, count(&first[0], "passed") + count(&second[0], "passed"));
+    }
+}
@@ -0,0 +1,281 @@
+use std::sync::Arc;
+
+use super::super::{Record, RecordItem};
+use super::super::diff::{self, Change};
+use super::super::metrics;
+use super::super::metrics::Counter;
+use super::super::poison::Detector;
+use super::super::snapshot::Snapshot;
+use super::super::unwind;
+use super::{Failure, Filter};
+
+/// Field telling why the record is sent to the dead-letter output.
+const REASON: &'static str = "_dead_letter_reason";
+
+/// What happens to a record the filter fails to process or panics on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OnError {
+    Drop,
+    /// Give the record the `_dead_letter_reason`, sending it to the dead-letter output.
+    DeadLetter,
+    /// Pass the record on as it was given to the filter.
+    PassUnmodified,
+}
+
+impl OnError {
+    pub fn from_str(name: &str) -> Option<OnError> {
+        match name {
+            "drop" => Some(OnError::Drop),
+            "dead_letter" => Some(OnError::DeadLetter),
+            "pass_unmodified" => Some(OnError::PassUnmodified),
+            _ => None,
+        }
+    }
+
+    pub fn all() -> [OnError; 3] {
+        [OnError::Drop, OnError::DeadLetter, OnError::PassUnmodified]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OnError::Drop => "drop",
+            OnError::DeadLetter => "dead_letter",
+            OnError::PassUnmodified => "pass_unmodified",
+        }
+    }
+}
+
+/// Applies the `on_error` policy to records the wrapped filter fails on, overriding what the
+/// filter would do with them on its own.
+///
+/// Panics of the filter are caught and handled like failures. As the record is gone once the
+/// filter panics, policies keeping it clone each record beforehand.
+///
+/// The policy is told by the `filter.<name>.on_error.<policy>` metric being 1, while the other
+/// policies are 0 across reloads. Failures and panics among them are counted in
+/// `filter.<name>.errors` and `filter.<name>.panics`.
+///
+/// Watched by the poison record detector, the guard reports panics of the filter on the record
+/// the chain is processing, see `Detector::enter`, without copying records for it.
+pub struct Guard {
+    filter: Box<Filter>,
+    name: String,
+    on_error: OnError,
+    errors: Counter,
+    panics: Counter,
+    poison: Option<Arc<Detector>>,
+}
+
+impl Guard {
+    pub fn new(filter: Box<Filter>, name: &str, on_error: OnError) -> Guard {
+        let registry = metrics::registry();
+        for policy in OnError::all().iter() {
+            let counter = registry.counter(&format!("filter.{}.on_error.{}", name, policy.name()));
+            counter.set(if *policy == on_error { 1 } else { 0 });
+        }
+
+        Guard {
+            filter: filter,
+            name: name.to_string(),
+            on_error: on_error,
+            errors: registry.counter(&format!("filter.{}.errors", name)),
+            panics: registry.counter(&format!("filter.{}.panics", name)),
+            poison: None,
+        }
+    }
+}
+
+impl Guard {
+    /// Runs the filter, giving back the record it failed or panicked on as the policy has it, if
+    /// kept at all.
+    fn run<T, F>(&mut self, record: Record, apply: F) -> Result<Vec<T>, Option<Record>>
+        where F: FnOnce(&mut Box<Filter>, Record) -> Result<Vec<T>, Failure> + Send,
+              T: Send
+    {
+        let copy = match self.on_error {
+            OnError::Drop => None,
+            OnError::DeadLetter | OnError::PassUnmodified => Some(record.clone()),
+        };
+
+        let filter = &mut self.filter;
+        let result = unwind::catch(|| apply(filter, record));
+        let (record, reason) = match result {
+            Ok(Ok(records)) => return Ok(records),
+            Ok(Err(Failure { record, reason })) => (Some(record), reason),
+            Err(message) => {
+                self.panics.inc();
+                if let Some(ref poison) = self.poison {
+                    if poison.panicked(&format!("filter.{}", self.name), &message) {
+                        return Err(None);
+                    }
+                }
+                (copy, format!("panicked: {}", message))
+            }
+        };
+        self.errors.inc();
+
+        let reason = format!("'{}' filter failed: {}", self.filter.typename(), reason);
+        match (self.on_error, record) {
+            (OnError::DeadLetter, Some(mut record)) => {
+                record.insert(REASON.to_string(), RecordItem::String(reason));
+                Err(Some(record))
+            }
+            (OnError::PassUnmodified, Some(record)) => Err(Some(record)),
+            (OnError::Drop, _) | (_, None) => {
+                debug!(target: "Filter", "dropping record: {}", reason);
+                Err(None)
+            }
+        }
+    }
+}
+
+impl Filter for Guard {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        match self.run(record, |filter, record| filter.try_apply(record)) {
+            Ok(records) => records,
+            Err(record) => record.into_iter().collect(),
+        }
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        match self.run(record, |filter, record| filter.try_apply_traced(record)) {
+            Ok(records) => records,
+            Err(record) => {
+                record.into_iter().map(|record| {
+                    let changes = match (self.on_error, record.find(REASON)) {
+                        (OnError::DeadLetter, Some(reason)) => {
+                            vec![diff::Entry { path: vec![REASON.to_string()], change: Change::Added(reason.clone()) }]
+                        }
+                        _ => Vec::new(),
+                    };
+                    (record, changes)
+                }).collect()
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Vec<Record> {
+        self.filter.flush()
+    }
+
+    fn close(&mut self) -> Vec<Record> {
+        self.filter.close()
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        self.filter.describe_fields()
+    }
+
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        self.filter.state()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.filter.reseed(seed);
+    }
+
+    fn watch(&mut self, poison: Arc<Detector>) {
+        self.poison = Some(poison);
+    }
+
+    fn typename(&self) -> &'static str {
+        self.filter.typename()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::diff::{Change, Entry};
+    use super::super::super::metrics;
+    use super::super::super::testing::{record, string};
+    use super::super::{Failure, Filter};
+    use super::{Guard, OnError};
+
+    /// Filter failing on records with the `fail` field, panicking on ones with `panic` and
+    /// marking others as seen.
+    struct Scripted;
+
+    impl Filter for Scripted {
+        fn apply(&mut self, record: Record) -> Vec<Record> {
+            self.try_apply(record).unwrap_or_else(|_| Vec::new())
+        }
+
+        fn try_apply(&mut self, mut record: Record) -> Result<Vec<Record>, Failure> {
+            if record.find("panic").is_some() {
+                panic!("scripted panic");
+            }
+            if record.find("fail").is_some() {
+                return Err(Failure::new(record, "scripted failure".to_string()));
+            }
+            record.insert("seen".to_string(), RecordItem::Bool(true));
+            Ok(vec![record])
+        }
+    }
+
+    fn apply(on_error: OnError, name: &str) -> Vec<Record> {
+        let mut filter = Guard::new(Box::new(Scripted), name, on_error);
+        let mut records = Vec::new();
+        for &key in ["ok", "fail", "panic"].iter() {
+            records.extend(filter.apply(record(vec![(key, string("value"))])).into_iter());
+        }
+        records
+    }
+
+    fn reason(record: &Record) -> Option<&str> {
+        record.find("_dead_letter_reason").and_then(|reason| reason.as_str())
+    }
+
+    #[test]
+    fn apply_policy_to_failed_records() {
+        let seen = record(vec![("ok", string("value")), ("seen", RecordItem::Bool(true))]);
+
+        assert_eq!(vec![seen.clone()], apply(OnError::Drop, "test_guard_drop"));
+
+        let records = apply(OnError::PassUnmodified, "test_guard_pass");
+        assert_eq!(vec![seen.clone(), record(vec![("fail", string("value"))]), record(vec![("panic", string("value"))])],
+            records);
+
+        let records = apply(OnError::DeadLetter, "test_guard_dead_letter");
+        assert_eq!(3, records.len());
+        assert_eq!(seen, records[0]);
+        assert!(reason(&records[1]).unwrap().ends_with("Scripted' filter failed: scripted failure"));
+        assert!(reason(&records[2]).unwrap().ends_with("filter failed: panicked: scripted panic"));
+        assert_eq!(Some(&string("value")), records[2].find("panic"));
+
+        let registry = metrics::registry();
+        for &(name, policy) in [("test_guard_drop", "drop"), ("test_guard_pass", "pass_unmodified"),
+                                ("test_guard_dead_letter", "dead_letter")].iter() {
+            assert_eq!(1, registry.counter(&format!("filter.{}.on_error.{}", name, policy)).get());
+            assert_eq!(2, registry.counter(&format!("filter.{}.errors", name)).get());
+            assert_eq!(1, registry.counter(&format!("filter.{}.panics", name)).get());
+        }
+    }
+
+    #[test]
+    fn keep_policy_metric_across_reloads() {
+        let registry = metrics::registry();
+        let policy = |name: &str| registry.counter(&format!("filter.test_guard_reload.on_error.{}", name)).get();
+
+        Guard::new(Box::new(Scripted), "test_guard_reload", OnError::Drop);
+        Guard::new(Box::new(Scripted), "test_guard_reload", OnError::Drop);
+        assert_eq!((1, 0), (policy("drop"), policy("dead_letter")));
+
+        Guard::new(Box::new(Scripted), "test_guard_reload", OnError::DeadLetter);
+        assert_eq!((0, 1), (policy("drop"), policy("dead_letter")));
+    }
+
+    #[test]
+    fn report_changes_of_guarded_filter() {
+        let mut filter = Guard::new(Box::new(Scripted), "test_guard_traced", OnError::DeadLetter);
+
+        let seen = Entry { path: vec!["seen".to_string()], change: Change::Added(RecordItem::Bool(true)) };
+        let records = filter.apply_traced(record(vec![("ok", string("value"))]));
+        assert_eq!(vec![seen], records[0].1);
+
+        let records = filter.apply_traced(record(vec![("fail", string("value"))]));
+        let reason = records[0].0.find("_dead_letter_reason").unwrap().clone();
+        let marked = Entry { path: vec!["_dead_letter_reason".to_string()], change: Change::Added(reason) };
+        assert_eq!(vec![marked], records[0].1);
+    }
+}
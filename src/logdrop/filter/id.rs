@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::Read;
+
+use libc;
+
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// How identifiers are generated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Generator {
+    /// Random version 4 UUID, e.g. `1b4e28ba-2fa1-41d2-883f-0016d3cca427`.
+    Uuid,
+    /// Number counting from 1, unique within the filter only, as it starts over on restart.
+    Sequence,
+}
+
+/// Gives records lacking the identifier field, or having it null, a generated identifier, leaving
+/// the ones supplied by producers untouched.
+///
+/// UUIDs are drawn from the generator seeded from `/dev/urandom`, falling back to the start time
+/// and the process id if it can't be read, or by the seed in the deterministic mode, see
+/// `determinism`, so runs replaying the same input agree on them.
+pub struct EnsureId {
+    field: String,
+    generator: Generator,
+    seed: u64,
+    sequence: u64,
+}
+
+impl EnsureId {
+    pub fn new(field: &str, generator: Generator) -> EnsureId {
+        EnsureId {
+            field: field.to_string(),
+            generator: generator,
+            seed: seed() | 1,
+            sequence: 0,
+        }
+    }
+
+    fn generate(&mut self) -> RecordItem {
+        match self.generator {
+            Generator::Uuid => RecordItem::String(uuid(super::next(&mut self.seed), super::next(&mut self.seed))),
+            Generator::Sequence => {
+                self.sequence += 1;
+                RecordItem::U64(self.sequence)
+            }
+        }
+    }
+
+    fn ensure(&mut self, record: &mut Record, changes: Option<&mut Vec<diff::Entry>>) {
+        match record.find(&self.field) {
+            Some(&RecordItem::Null) | None => {}
+            Some(..) => return,
+        }
+
+        let id = self.generate();
+        super::insert(record, self.field.clone(), id, changes);
+    }
+}
+
+/// Reads the seed from `/dev/urandom`, so that instances started together don't generate the same
+/// identifiers.
+fn seed() -> u64 {
+    let mut buf = Vec::new();
+    match File::open("/dev/urandom").and_then(|file| file.take(8).read_to_end(&mut buf)) {
+        Ok(8) => buf.iter().fold(0, |acc, &byte| acc << 8 | byte as u64),
+        result => {
+            let err = result.map(|len| format!("read {} bytes", len)).unwrap_or_else(|err| err.to_string());
+            warn!(target: "Filter::EnsureId", "unable to read /dev/urandom, seeding by time: {}", err);
+            let pid = unsafe { libc::getpid() } as u64;
+            SystemClock.now() ^ pid << 32
+        }
+    }
+}
+
+/// Formats the random bits as the version 4 UUID, overwriting the version and variant ones.
+fn uuid(high: u64, low: u64) -> String {
+    let high = high & !0xf000 | 0x4000;
+    let low = low & !(0xc << 60) | 0x8 << 60;
+    format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32, high >> 16 & 0xffff, high & 0xffff, low >> 48, low & 0xffffffffffff)
+}
+
+impl FromConfig for EnsureId {
+    fn from_config(config: &Config) -> Result<EnsureId, Error> {
+        let generator = match &try!(config.string_or("generator", "uuid"))[..] {
+            "uuid" => Generator::Uuid,
+            "sequence" => Generator::Sequence,
+            generator => return Err(Error::Invalid(format!("unknown identifier generator '{}'", generator))),
+        };
+
+        Ok(EnsureId::new(&try!(config.string_or("field", "id")), generator))
+    }
+}
+
+impl Filter for EnsureId {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.ensure(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.ensure(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.field.clone()])
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.seed = seed | 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{EnsureId, Generator};
+
+    fn well_formed(id: &str) -> bool {
+        let chars: Vec<char> = id.chars().collect();
+        chars.len() == 36 && chars.iter().enumerate().all(|(at, &c)| {
+            match at {
+                8 | 13 | 18 | 23 => c == '-',
+                14 => c == '4',
+                19 => "89ab".contains(c),
+                _ => "0123456789abcdef".contains(c),
+            }
+        })
+    }
+
+    #[test]
+    fn generate_missing_ids() {
+        let mut filter = EnsureId::new("id", Generator::Uuid);
+
+        let mut ids = Vec::new();
+        for payload in vec![record(vec![("message", string("le message"))]), record(vec![("id", RecordItem::Null)])] {
+            let records = filter.apply(payload);
+            let id = records[0].find("id").and_then(|id| id.as_str()).unwrap().to_string();
+            assert!(well_formed(&id), "malformed id '{}'", id);
+            ids.push(id);
+        }
+        assert!(ids[0] != ids[1]);
+
+        let mut filter = EnsureId::new("seq", Generator::Sequence);
+        for expected in 1..4 {
+            assert_eq!(Some(&RecordItem::U64(expected)), filter.apply(record(vec![]))[0].find("seq"));
+        }
+    }
+
+    #[test]
+    fn keep_existing_ids() {
+        let mut filter = EnsureId::new("id", Generator::Uuid);
+
+        let payload = record(vec![("id", string("a1")), ("message", string("le message"))]);
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+        let payload = record(vec![("id", RecordItem::U64(42))]);
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+    }
+
+    #[test]
+    fn draw_same_ids_from_same_seed() {
+        let mut first = EnsureId::new("id", Generator::Uuid);
+        let mut second = EnsureId::new("id", Generator::Uuid);
+        first.reseed(42);
+        second.reseed(42);
+
+        assert_eq!(first.apply(record(vec![])), second.apply(record(vec![])));
+    }
+}
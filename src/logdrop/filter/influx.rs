@@ -0,0 +1,255 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// Renders the record as an InfluxDB line protocol entry, e.g.
+/// `cpu,host=web-01 user=0.25,procs=12i 1434055562000000000`, replacing it by a record holding
+/// the line in the target field, so that outputs are able to write it as is.
+///
+/// Tags are taken from top-level fields holding a string, number or boolean, sorted by name as
+/// the protocol recommends. Field values are numbers, integers suffixed with `i`, booleans and
+/// quoted strings, missing fields and non-finite numbers being skipped. The timestamp is taken
+/// as seconds since the Unix epoch and written in nanoseconds, leaving it to the server if it's
+/// missing.
+///
+/// A record without any field value can't be rendered, failing the filter, which drops it unless
+/// the `on_error` policy is configured.
+pub struct InfluxLine {
+    measurement: String,
+    tags: Vec<String>,
+    fields: Vec<String>,
+    timestamp: String,
+    target: String,
+}
+
+impl InfluxLine {
+    pub fn new(measurement: &str, mut tags: Vec<String>, fields: Vec<String>, timestamp: &str, target: &str)
+        -> InfluxLine
+    {
+        tags.sort();
+
+        InfluxLine {
+            measurement: measurement.to_string(),
+            tags: tags,
+            fields: fields,
+            timestamp: timestamp.to_string(),
+            target: target.to_string(),
+        }
+    }
+
+    /// Renders the line, failing if the record has no field value.
+    fn render(&self, record: &Record) -> Result<String, String> {
+        let mut line = String::new();
+        escape(&self.measurement, ", ", &mut line);
+
+        for tag in self.tags.iter() {
+            let value = match record.find(tag).and_then(tag_value) {
+                Some(ref value) if !value.is_empty() => value.clone(),
+                Some(..) | None => continue,
+            };
+            line.push(',');
+            escape(tag, ",= ", &mut line);
+            line.push('=');
+            escape(&value, ",= ", &mut line);
+        }
+
+        let mut separator = ' ';
+        for field in self.fields.iter() {
+            let value = match record.find(field).and_then(field_value) {
+                Some(value) => value,
+                None => continue,
+            };
+            line.push(separator);
+            escape(field, ",= ", &mut line);
+            line.push('=');
+            line.push_str(&value);
+            separator = ',';
+        }
+        if separator == ' ' {
+            return Err("none of the fields has a value".to_string());
+        }
+
+        if let Some(timestamp) = record.find(&self.timestamp).and_then(nanoseconds) {
+            line.push(' ');
+            line.push_str(&timestamp.to_string());
+        }
+
+        Ok(line)
+    }
+}
+
+/// Writes the value escaping the given characters with a backslash.
+fn escape(value: &str, special: &str, result: &mut String) {
+    for c in value.chars() {
+        if special.contains(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+}
+
+fn tag_value(value: &RecordItem) -> Option<String> {
+    if let Some(value) = value.as_str() {
+        return Some(value.to_string());
+    }
+
+    match *value {
+        RecordItem::Bool(value) => Some(value.to_string()),
+        RecordItem::I64(value) => Some(value.to_string()),
+        RecordItem::U64(value) => Some(value.to_string()),
+        RecordItem::F64(value) if value.is_finite() => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn field_value(value: &RecordItem) -> Option<String> {
+    if let Some(value) = value.as_str() {
+        let mut result = "\"".to_string();
+        escape(value, "\"\\", &mut result);
+        result.push('"');
+        return Some(result);
+    }
+
+    match *value {
+        RecordItem::Bool(value) => Some(value.to_string()),
+        RecordItem::I64(value) => Some(format!("{}i", value)),
+        RecordItem::U64(value) => Some(format!("{}i", value)),
+        RecordItem::F64(value) if value.is_finite() => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Converts the timestamp in seconds into nanoseconds, keeping the precision of the fraction.
+fn nanoseconds(value: &RecordItem) -> Option<i64> {
+    match *value {
+        RecordItem::I64(value) => value.checked_mul(1000000000),
+        RecordItem::U64(value) if value <= i64::max_value() as u64 => (value as i64).checked_mul(1000000000),
+        RecordItem::F64(value) if value.is_finite() && value.abs() < 9.2e9 => {
+            let seconds = value.trunc();
+            Some(seconds as i64 * 1000000000 + ((value - seconds) * 1e9).round() as i64)
+        }
+        _ => None,
+    }
+}
+
+impl FromConfig for InfluxLine {
+    fn from_config(config: &Config) -> Result<InfluxLine, Error> {
+        let measurement = try!(config.string("measurement"));
+        let fields = try!(config.strings_or("fields", Vec::new()));
+        if fields.is_empty() {
+            return Err(Error::Invalid("influx_line requires at least one field".to_string()));
+        }
+
+        Ok(InfluxLine::new(&measurement, try!(config.strings_or("tags", Vec::new())), fields,
+            &try!(config.string_or("timestamp", "timestamp")), &try!(config.string_or("target", "line"))))
+    }
+}
+
+impl Filter for InfluxLine {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.try_apply(record).unwrap_or_else(|failure| {
+            debug!(target: "Filter", "dropping record: {}", failure.reason);
+            Vec::new()
+        })
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        match self.render(&record) {
+            Ok(line) => {
+                let mut result = Record::new();
+                result.insert(self.target.clone(), RecordItem::String(line));
+                Ok(vec![result])
+            }
+            Err(reason) => Err(Failure::new(record, reason)),
+        }
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        self.try_apply_traced(record).unwrap_or_else(|failure| {
+            debug!(target: "Filter", "dropping record: {}", failure.reason);
+            Vec::new()
+        })
+    }
+
+    /// The line record is reported as replacing the given one.
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let line = match self.render(&record) {
+            Ok(line) => line,
+            Err(reason) => return Err(Failure::new(record, reason)),
+        };
+
+        let mut result = Record::new();
+        result.insert(self.target.clone(), RecordItem::String(line));
+        let changes = super::replaced(&record, &result);
+        Ok(vec![(result, changes)])
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields = self.tags.clone();
+        fields.extend(self.fields.iter().cloned());
+        fields.push(self.timestamp.clone());
+
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::super::{Record, RecordItem, Text};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::InfluxLine;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn line(records: Vec<Record>) -> String {
+        records[0].find("line").and_then(|line| line.as_str()).unwrap().to_string()
+    }
+
+    #[test]
+    fn render_tags_and_fields() {
+        let mut filter = InfluxLine::new("cpu load", strings(&["region", "host", "absent"]),
+            strings(&["user", "procs", "busy", "note", "absent"]), "timestamp", "line");
+
+        let payload = record(vec![
+            ("host", string("web 01")),
+            ("region", string("us,east=1")),
+            ("user", RecordItem::F64(0.25)),
+            ("procs", RecordItem::U64(12)),
+            ("busy", RecordItem::Bool(true)),
+            ("note", string(r#"say "hi" \o/"#)),
+            ("timestamp", RecordItem::F64(1434055562.5)),
+        ]);
+        assert_eq!(r#"cpu\ load,host=web\ 01,region=us\,east\=1 user=0.25,procs=12i,busy=true,note="say \"hi\" \\o/" 1434055562500000000"#,
+            line(filter.apply(payload)));
+
+        let payload = record(vec![("host", string("web-01")), ("user", RecordItem::I64(-3))]);
+        assert_eq!("cpu\\ load,host=web-01 user=-3i", line(filter.apply(payload)));
+    }
+
+    #[test]
+    fn render_shared_text_values() {
+        let mut filter = InfluxLine::new("cpu", strings(&["host"]), strings(&["note"]), "timestamp", "line");
+
+        let buf = Arc::new(b"web-01 up".to_vec());
+        let payload = record(vec![
+            ("host", RecordItem::Text(Text::new(buf.clone(), 0, 6).unwrap())),
+            ("note", RecordItem::Text(Text::new(buf, 7, 9).unwrap())),
+        ]);
+        assert_eq!("cpu,host=web-01 note=\"up\"", line(filter.apply(payload)));
+    }
+
+    #[test]
+    fn fail_without_field_values() {
+        let mut filter = InfluxLine::new("cpu", strings(&["host"]), strings(&["user"]), "timestamp", "line");
+
+        let payload = record(vec![("host", string("web-01")), ("user", RecordItem::F64(::std::f64::NAN))]);
+        assert!(filter.try_apply(payload.clone()).is_err());
+        assert!(filter.apply(payload).is_empty());
+    }
+}
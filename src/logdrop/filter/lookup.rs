@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::super::{Record, RecordItem};
+use super::super::config;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::json;
+use super::super::json::Value;
+use super::super::numeric;
+use super::super::reload;
+use super::{Failure, Filter};
+
+/// What to write when the value is not in the table or the source field is missing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Unmatched {
+    /// Leave the target field alone.
+    Skip,
+    /// Write the given value.
+    Default(RecordItem),
+}
+
+/// Format of the table file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TableFormat {
+    /// Object mapping keys to values of any kind.
+    Json,
+    /// Lines of the key and the string value separated by the first comma.
+    Csv,
+}
+
+/// Writes the value the source field maps to in the lookup table to the target field, e.g. the
+/// status text of the status code.
+///
+/// Numbers are looked up by their decimal form, so the `200` number finds the `"200"` key. A
+/// table loaded from a file is loaded again on every config reload, keeping the current one if
+/// the file turns out to be broken.
+pub struct Lookup {
+    field: String,
+    target: String,
+    table: HashMap<String, RecordItem>,
+    unmatched: Unmatched,
+    /// Table file with its format and the reload it was loaded at, if any.
+    source: Option<(PathBuf, TableFormat, usize)>,
+}
+
+impl Lookup {
+    pub fn new(field: &str, target: &str, table: HashMap<String, RecordItem>, unmatched: Unmatched) -> Lookup {
+        Lookup {
+            field: field.to_string(),
+            target: target.to_string(),
+            table: table,
+            unmatched: unmatched,
+            source: None,
+        }
+    }
+
+    /// Creates the filter with the table loaded from the file.
+    pub fn open(field: &str, target: &str, path: &Path, format: TableFormat, unmatched: Unmatched)
+        -> Result<Lookup, Error>
+    {
+        let generation = reload::generation();
+        let table = try!(load(path, format));
+
+        let mut lookup = Lookup::new(field, target, table, unmatched);
+        lookup.source = Some((path.to_path_buf(), format, generation));
+        Ok(lookup)
+    }
+
+    /// Loads the table again if the config has been reloaded since it was loaded.
+    fn refresh(&mut self) {
+        let generation = reload::generation();
+        let (path, format) = match self.source {
+            Some((ref path, format, loaded)) if loaded != generation => (path.clone(), format),
+            _ => return,
+        };
+
+        match load(&path, format) {
+            Ok(table) => {
+                info!(target: "Filter::Lookup", "reloaded {} entries from {:?}", table.len(), path);
+                self.table = table;
+            }
+            Err(err) => warn!(target: "Filter::Lookup", "keeping current table: {}", err),
+        }
+        self.source = Some((path, format, generation));
+    }
+
+    fn lookup(&mut self, record: &mut Record, changes: Option<&mut Vec<diff::Entry>>) {
+        self.refresh();
+
+        let value = match record.find(&self.field).and_then(key).and_then(|key| self.table.get(&key)) {
+            Some(value) => Some(value.clone()),
+            None => {
+                match self.unmatched {
+                    Unmatched::Skip => None,
+                    Unmatched::Default(ref value) => Some(value.clone()),
+                }
+            }
+        };
+
+        if let Some(value) = value {
+            super::insert(record, self.target.clone(), value, changes);
+        }
+    }
+}
+
+/// Returns the key the value is looked up by, if it is a string or a number.
+pub fn key(value: &RecordItem) -> Option<String> {
+    match *value {
+        RecordItem::I64(value) => Some(value.to_string()),
+        RecordItem::U64(value) => Some(value.to_string()),
+        RecordItem::F64(value) => {
+            match numeric::f64_to_i64(value).exact() {
+                Some(value) => Some(value.to_string()),
+                None => Some(value.to_string()),
+            }
+        }
+        _ => value.as_str().map(|value| value.to_string()),
+    }
+}
+
+fn load(path: &Path, format: TableFormat) -> Result<HashMap<String, RecordItem>, Error> {
+    let content = try!(config::read(path));
+
+    let mut table = HashMap::new();
+    match format {
+        TableFormat::Json => {
+            match json::from_str(&content) {
+                Ok(Value::Object(object)) => {
+                    for (key, value) in object.into_iter() {
+                        table.insert(key, From::from(value));
+                    }
+                }
+                Ok(..) => return Err(Error::Invalid(format!("lookup table {:?} must be an object", path))),
+                Err(err) => return Err(Error::Invalid(format!("malformed lookup table {:?}: {:?}", path, err))),
+            }
+        }
+        TableFormat::Csv => {
+            for (id, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let mut parts = line.splitn(2, ',');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) => {
+                        table.insert(key.trim().to_string(), RecordItem::String(value.trim().to_string()));
+                    }
+                    _ => return Err(Error::Invalid(format!("line {} of lookup table {:?} has no comma", id + 1, path))),
+                }
+            }
+        }
+    }
+
+    Ok(table)
+}
+
+impl FromConfig for Lookup {
+    fn from_config(config: &Config) -> Result<Lookup, Error> {
+        let field = try!(config.string("field"));
+        let target = try!(config.string("target"));
+
+        let unmatched = match config.find("default") {
+            Some(..) => Unmatched::Default(RecordItem::String(try!(config.string("default")))),
+            None => Unmatched::Skip,
+        };
+
+        if config.find("mapping").is_some() {
+            let table = try!(config.mapping("mapping")).into_iter()
+                .map(|(key, value)| (key, RecordItem::String(value)))
+                .collect();
+            return Ok(Lookup::new(&field, &target, table, unmatched));
+        }
+
+        let path = PathBuf::from(try!(config.string("table")));
+        let default = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => "csv",
+            _ => "json",
+        };
+        let format = match &try!(config.string_or("format", default))[..] {
+            "json" => TableFormat::Json,
+            "csv" => TableFormat::Csv,
+            format => return Err(Error::Invalid(format!("unknown lookup table format '{}'", format))),
+        };
+
+        Lookup::open(&field, &target, &path, format, unmatched)
+    }
+}
+
+impl Filter for Lookup {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.lookup(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.lookup(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.field.clone()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use super::super::super::RecordItem;
+    use super::super::super::reload;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{Lookup, TableFormat, Unmatched};
+
+    fn status(filter: &mut Lookup, code: RecordItem) -> Option<RecordItem> {
+        filter.apply(record(vec![("status_code", code)])).pop().unwrap().find("status_text").cloned()
+    }
+
+    #[test]
+    fn map_known_and_unknown_codes() {
+        let path = env::temp_dir().join("logdrop-lookup-status.csv");
+        File::create(&path).unwrap().write_all(b"200,OK\n404, Not Found\n\n").unwrap();
+
+        let mut skip = Lookup::open("status_code", "status_text", &path, TableFormat::Csv, Unmatched::Skip).unwrap();
+        assert_eq!(Some(string("OK")), status(&mut skip, RecordItem::I64(200)));
+        assert_eq!(Some(string("Not Found")), status(&mut skip, string("404")));
+        assert_eq!(Some(string("OK")), status(&mut skip, RecordItem::F64(200.0)));
+        assert_eq!(None, status(&mut skip, RecordItem::I64(418)));
+
+        let unknown = Unmatched::Default(string("unknown"));
+        let mut default = Lookup::open("status_code", "status_text", &path, TableFormat::Csv, unknown).unwrap();
+        assert_eq!(Some(string("unknown")), status(&mut default, RecordItem::I64(418)));
+        assert_eq!(Some(string("unknown")), default.apply(record(vec![])).pop().unwrap().find("status_text").cloned());
+    }
+
+    #[test]
+    fn reload_table_with_config() {
+        let path = env::temp_dir().join("logdrop-lookup-reload.json");
+        File::create(&path).unwrap().write_all(br#"{"200": "OK"}"#).unwrap();
+
+        let mut filter = Lookup::open("status_code", "status_text", &path, TableFormat::Json, Unmatched::Skip).unwrap();
+        assert_eq!(None, status(&mut filter, RecordItem::I64(418)));
+
+        File::create(&path).unwrap().write_all(br#"{"200": "OK", "418": "I'm a teapot"}"#).unwrap();
+        reload::advance();
+        assert_eq!(Some(string("I'm a teapot")), status(&mut filter, RecordItem::I64(418)));
+
+        // Broken tables are not taken.
+        File::create(&path).unwrap().write_all(b"{").unwrap();
+        reload::advance();
+        assert_eq!(Some(string("I'm a teapot")), status(&mut filter, RecordItem::I64(418)));
+    }
+}
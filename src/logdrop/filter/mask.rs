@@ -0,0 +1,205 @@
+use std::mem;
+
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff::{self, Change};
+use super::{Failure, Filter};
+
+/// What to do with a value to mask not being a string.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NonString {
+    /// Leave the value untouched.
+    Skip,
+    /// Mask the textual form of numbers and booleans, replacing other values with null.
+    Stringify,
+    /// Replace the value with null.
+    Redact,
+}
+
+/// Masks a string field in place, keeping its length and format, e.g. `************1111` out of a
+/// card number.
+///
+/// The field is a dotted path, so nested values are masked too. Only letters and digits are
+/// masked and counted for the kept prefix and suffix, while separators like dashes or spaces stay
+/// where they are. A value too short to hide anything behind the kept characters is masked
+/// entirely. A missing field leaves the record untouched.
+pub struct MaskFormat {
+    path: Vec<String>,
+    keep_first: usize,
+    keep_last: usize,
+    mask: char,
+    non_string: NonString,
+}
+
+impl MaskFormat {
+    pub fn new(field: &str, keep_first: usize, keep_last: usize) -> MaskFormat {
+        MaskFormat {
+            path: field.split('.').map(|name| name.to_string()).collect(),
+            keep_first: keep_first,
+            keep_last: keep_last,
+            mask: '*',
+            non_string: NonString::Skip,
+        }
+    }
+
+    pub fn mask(mut self, mask: char) -> MaskFormat {
+        self.mask = mask;
+        self
+    }
+
+    pub fn non_string(mut self, non_string: NonString) -> MaskFormat {
+        self.non_string = non_string;
+        self
+    }
+
+    fn masked(&self, value: &str) -> String {
+        let total = value.chars().filter(|c| c.is_alphanumeric()).count();
+        let (first, last) = if self.keep_first + self.keep_last < total {
+            (self.keep_first, total - self.keep_last)
+        } else {
+            (0, 0)
+        };
+
+        let mut position = 0;
+        value.chars().map(|c| {
+            if !c.is_alphanumeric() {
+                return c;
+            }
+
+            let kept = position < first || (last > 0 && position >= last);
+            position += 1;
+            if kept { c } else { self.mask }
+        }).collect()
+    }
+
+    fn redact(&self, record: &mut Record, changes: Option<&mut Vec<diff::Entry>>) {
+        let masked = match record.find_path(&self.path) {
+            Some(value) => match (value.as_str(), self.non_string) {
+                (Some(value), _) => Some(RecordItem::String(self.masked(value))),
+                (None, NonString::Skip) => None,
+                (None, NonString::Redact) => Some(RecordItem::Null),
+                (None, NonString::Stringify) => match *value {
+                    RecordItem::Null => None,
+                    RecordItem::Bool(value) => Some(RecordItem::String(self.masked(&value.to_string()))),
+                    RecordItem::F64(value) => Some(RecordItem::String(self.masked(&value.to_string()))),
+                    RecordItem::I64(value) => Some(RecordItem::String(self.masked(&value.to_string()))),
+                    RecordItem::U64(value) => Some(RecordItem::String(self.masked(&value.to_string()))),
+                    _ => Some(RecordItem::Null),
+                },
+            },
+            None => None,
+        };
+
+        if let Some(masked) = masked {
+            if let Some(value) = record.find_path_mut(&self.path) {
+                let previous = mem::replace(value, masked);
+                match changes {
+                    Some(changes) if previous != *value => {
+                        changes.push(diff::Entry { path: self.path.clone(), change: Change::Changed(previous, value.clone()) });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl FromConfig for MaskFormat {
+    fn from_config(config: &Config) -> Result<MaskFormat, Error> {
+        let field = try!(config.string("field"));
+        let keep_first = try!(config.u64_or("keep_first", 0)) as usize;
+        let keep_last = try!(config.u64_or("keep_last", 0)) as usize;
+
+        let mask = try!(config.string_or("mask", "*"));
+        let mut chars = mask.chars();
+        let mask = match (chars.next(), chars.next()) {
+            (Some(c), None) => c,
+            _ => return Err(Error::Invalid(format!("mask must be a single character, found '{}'", mask))),
+        };
+
+        let non_string = match &try!(config.string_or("non_string", "skip"))[..] {
+            "skip" => NonString::Skip,
+            "stringify" => NonString::Stringify,
+            "redact" => NonString::Redact,
+            policy => return Err(Error::Invalid(format!("unknown non-string value policy '{}'", policy))),
+        };
+
+        Ok(MaskFormat::new(&field, keep_first, keep_last).mask(mask).non_string(non_string))
+    }
+}
+
+impl Filter for MaskFormat {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.redact(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.redact(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.path[0].clone()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{MaskFormat, NonString};
+
+    #[test]
+    fn mask_card_number_keeping_last_four() {
+        let mut filter = MaskFormat::new("card", 0, 4);
+
+        let payload = record(vec![("card", string("4111111111111111"))]);
+        let expected = record(vec![("card", string("************1111"))]);
+        assert_eq!(vec![expected], filter.apply(payload));
+
+        // Separators keep their places and are not counted.
+        let payload = record(vec![("card", string("4111-1111-1111-1111"))]);
+        let expected = record(vec![("card", string("****-****-****-1111"))]);
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn mask_short_string_entirely() {
+        let mut filter = MaskFormat::new("user.pin", 1, 4).mask('#');
+
+        let mut user = HashMap::new();
+        user.insert("pin".to_string(), string("1234"));
+        let payload = record(vec![("user", RecordItem::Object(user))]);
+
+        let mut user = HashMap::new();
+        user.insert("pin".to_string(), string("####"));
+        let expected = record(vec![("user", RecordItem::Object(user))]);
+        assert_eq!(vec![expected], filter.apply(payload));
+
+        let mut filter = MaskFormat::new("pin", 1, 1);
+        assert_eq!(vec![record(vec![("pin", string(""))])], filter.apply(record(vec![("pin", string(""))])));
+    }
+
+    #[test]
+    fn mask_non_string_by_policy() {
+        let payload = record(vec![("card", RecordItem::I64(4111111111111111))]);
+
+        let mut filter = MaskFormat::new("card", 0, 4);
+        assert_eq!(vec![payload.clone()], filter.apply(payload.clone()));
+
+        let mut filter = MaskFormat::new("card", 0, 4).non_string(NonString::Stringify);
+        assert_eq!(vec![record(vec![("card", string("************1111"))])], filter.apply(payload.clone()));
+
+        let mut filter = MaskFormat::new("card", 0, 4).non_string(NonString::Redact);
+        assert_eq!(vec![record(vec![("card", RecordItem::Null)])], filter.apply(payload));
+    }
+}
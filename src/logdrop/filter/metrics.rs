@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::numeric;
+use super::super::source;
+use super::{Failure, Filter};
+
+/// Splits a telemetry record into metric records, one per numeric field.
+///
+/// Each metric record has the dotted field path as `name`, the number as `value` and the
+/// configured tag fields copied into the `tags` object, the `@source` tag meaning the source key
+/// stored as `source`. Missing or non-numeric fields are skipped, so a record without any of
+/// them is dropped, or fails the filter if its `on_error` policy is configured.
+pub struct ToMetrics {
+    fields: Vec<(String, Vec<String>)>,
+    tags: Vec<String>,
+}
+
+impl ToMetrics {
+    /// Creates the filter extracting the given dotted field paths, e.g. `cpu.user`.
+    pub fn new(fields: Vec<String>, tags: Vec<String>) -> ToMetrics {
+        let fields = fields.into_iter().map(|field| {
+            let path = field.split('.').map(|name| name.to_string()).collect();
+            (field, path)
+        }).collect();
+
+        ToMetrics {
+            fields: fields,
+            tags: tags,
+        }
+    }
+}
+
+impl FromConfig for ToMetrics {
+    fn from_config(config: &Config) -> Result<ToMetrics, Error> {
+        let fields = try!(config.strings_or("fields", Vec::new()));
+        if fields.is_empty() {
+            return Err(Error::Invalid("to_metrics requires at least one field".to_string()));
+        }
+
+        Ok(ToMetrics::new(fields, try!(config.strings_or("tags", Vec::new()))))
+    }
+}
+
+impl ToMetrics {
+    fn split(&self, record: &Record) -> Vec<Record> {
+        let mut tags = HashMap::new();
+        for tag in self.tags.iter() {
+            if tag == "@source" {
+                if let Some(key) = source::key(&record) {
+                    tags.insert("source".to_string(), RecordItem::String(key.to_string()));
+                }
+            } else if let Some(value) = record.find(tag) {
+                tags.insert(tag.clone(), value.clone());
+            }
+        }
+
+        self.fields.iter().filter_map(|&(ref name, ref path)| {
+            match record.find_path(path) {
+                // Numbers are copied as they are, so integers keep their precision.
+                Some(value) if numeric::finite(value).is_some() => {
+                    let mut metric = Record::new();
+                    metric.insert("name".to_string(), RecordItem::String(name.clone()));
+                    metric.insert("value".to_string(), value.clone());
+                    metric.insert("tags".to_string(), RecordItem::Object(tags.clone()));
+                    Some(metric)
+                }
+                _ => None,
+            }
+        }).collect()
+    }
+}
+
+impl Filter for ToMetrics {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.split(&record)
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        let metrics = self.split(&record);
+        if metrics.is_empty() {
+            return Err(Failure::new(record, "no numeric fields to split".to_string()));
+        }
+        Ok(metrics)
+    }
+
+    /// Each metric record is reported as replacing the given one.
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        self.split(&record).into_iter().map(|metric| {
+            let changes = super::replaced(&record, &metric);
+            (metric, changes)
+        }).collect()
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let metrics = self.split(&record);
+        if metrics.is_empty() {
+            return Err(Failure::new(record, "no numeric fields to split".to_string()));
+        }
+
+        Ok(metrics.into_iter().map(|metric| {
+            let changes = super::replaced(&record, &metric);
+            (metric, changes)
+        }).collect())
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields: Vec<String> = self.fields.iter().map(|&(_, ref path)| path[0].clone()).collect();
+        for tag in self.tags.iter() {
+            if tag == "@source" {
+                fields.extend(source::fields().into_iter());
+            } else {
+                fields.push(tag.clone());
+            }
+        }
+
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::ToMetrics;
+
+    #[test]
+    fn split_numeric_fields() {
+        let mut filter = ToMetrics::new(vec!["cpu.user".to_string(), "memory".to_string(), "host".to_string()], vec!["host".to_string()]);
+
+        let mut cpu = HashMap::new();
+        cpu.insert("user".to_string(), RecordItem::F64(0.25));
+        let payload = record(vec![
+            ("cpu", RecordItem::Object(cpu)),
+            ("memory", RecordItem::F64(1024.0)),
+            ("host", string("localhost")),
+        ]);
+
+        let mut tags = HashMap::new();
+        tags.insert("host".to_string(), string("localhost"));
+
+        assert_eq!(vec![
+            record(vec![("name", string("cpu.user")), ("value", RecordItem::F64(0.25)), ("tags", RecordItem::Object(tags.clone()))]),
+            record(vec![("name", string("memory")), ("value", RecordItem::F64(1024.0)), ("tags", RecordItem::Object(tags))]),
+        ], filter.apply(payload));
+
+        let payload = record(vec![("memory", string("plenty")), ("host", string("localhost"))]);
+        assert!(filter.apply(payload.clone()).is_empty());
+        assert_eq!(Err(payload.clone()), filter.try_apply(payload).map_err(|failure| failure.record));
+    }
+}
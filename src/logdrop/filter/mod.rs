@@ -0,0 +1,408 @@
+use std;
+use std::sync::Arc;
+
+use super::{Record, RecordItem};
+use super::diff::{self, Change};
+use super::lineage::{self, Lineage};
+use super::poison;
+use super::poison::Detector;
+use super::snapshot::{Snapshot, Store};
+
+/// Failure of a filter to process the record, see `Filter::try_apply`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    /// The record as it was given to the filter.
+    pub record: Record,
+    pub reason: String,
+}
+
+impl Failure {
+    pub fn new(record: Record, reason: String) -> Failure {
+        Failure {
+            record: record,
+            reason: reason,
+        }
+    }
+}
+
+pub trait Filter : Send {
+    /// Processes a single record, returning records that should be passed further.
+    ///
+    /// Returning an empty vector drops the record.
+    fn apply(&mut self, record: Record) -> Vec<Record>;
+
+    /// Processes a single record like `apply`, failing with the record given back unmodified
+    /// where `apply` would decide what happens to it on its own.
+    ///
+    /// Filters able to fail implement `apply` on top of this one with their default disposition,
+    /// which the `on_error` policy overrides, see `Guard`.
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        Ok(self.apply(record))
+    }
+
+    /// Processes a single record like `apply`, returning with each record the changes made to
+    /// it, for its lineage, see `Chain::trace`.
+    ///
+    /// Filters modifying records report the fields they touch on their own. By default records
+    /// are returned with no changes, which only suits filters passing or dropping them as they are.
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        self.apply(record).into_iter().map(|record| (record, Vec::new())).collect()
+    }
+
+    /// Processes a single record like `try_apply`, returning changes like `apply_traced`.
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let records = try!(self.try_apply(record));
+        Ok(records.into_iter().map(|record| (record, Vec::new())).collect())
+    }
+
+    /// Returns records held back by the filter.
+    ///
+    /// Called periodically at the pipeline flush interval and once all inputs are finished.
+    fn flush(&mut self) -> Vec<Record> {
+        Vec::new()
+    }
+
+    /// Returns all records held back by the filter as it's about to be replaced on reload or
+    /// dropped at shutdown, after its state is taken, if any.
+    fn close(&mut self) -> Vec<Record> {
+        self.flush()
+    }
+
+    /// Returns top-level fields the filter reads, for deriving the decode projection.
+    ///
+    /// `None` means that any field may be read, e.g. when the whole record is looked at, which
+    /// rules the strict projection out.
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Returns the soft state carried over restarts, if the filter has any, see `Snapshot`.
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        None
+    }
+
+    /// Makes the filter draw random numbers from the given seed, see `determinism`.
+    fn reseed(&mut self, _: u64) {}
+
+    /// Makes the filter report records it panics on to the poison record detector, if it catches
+    /// panics at all, see `poison`. The watched chain skips quarantined records.
+    fn watch(&mut self, _: Arc<Detector>) {}
+
+    fn typename(&self) -> &'static str {
+        unsafe { std::intrinsics::type_name::<Self>() }
+    }
+}
+
+/// Inserts the value under the top-level key, noting the change for filters tracing what they
+/// touch, see `Filter::apply_traced`.
+fn insert(record: &mut Record, key: String, value: RecordItem, changes: Option<&mut Vec<diff::Entry>>) {
+    let changes = match changes {
+        Some(changes) => changes,
+        None => {
+            record.insert(key, value);
+            return;
+        }
+    };
+
+    let path = vec![key.clone()];
+    let change = match record.insert(key, value.clone()) {
+        Some(ref previous) if *previous == value => return,
+        Some(previous) => Change::Changed(previous, value),
+        None => Change::Added(value),
+    };
+    changes.push(diff::Entry { path: path, change: change });
+}
+
+/// Removes the top-level key, noting the change like `insert`.
+fn remove(record: &mut Record, key: &str, changes: Option<&mut Vec<diff::Entry>>) -> Option<RecordItem> {
+    let value = record.remove(key);
+    if let (Some(changes), Some(value)) = (changes, value.as_ref()) {
+        changes.push(diff::Entry { path: vec![key.to_string()], change: Change::Removed(value.clone()) });
+    }
+    value
+}
+
+/// Returns changes turning the record into the one built in its place, ordered by key, for
+/// filters emitting new records rather than modifying given ones, e.g. `Project`.
+fn replaced(before: &Record, after: &Record) -> Vec<diff::Entry> {
+    let mut changes = Vec::new();
+    for (key, previous) in before.iter() {
+        if after.find(key).is_none() {
+            changes.push(diff::Entry { path: vec![key.clone()], change: Change::Removed(previous.clone()) });
+        }
+    }
+    for (key, value) in after.iter() {
+        let change = match before.find(key) {
+            Some(previous) if previous == value => continue,
+            Some(previous) => Change::Changed(previous.clone(), value.clone()),
+            None => Change::Added(value.clone()),
+        };
+        changes.push(diff::Entry { path: vec![key.clone()], change: change });
+    }
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    changes
+}
+
+/// Ordered sequence of filters, each fed with the output of the previous one.
+pub struct Chain {
+    filters: Vec<Box<Filter>>,
+    /// Lineage together with the names of traced filters, see `Chain::trace`.
+    lineage: Option<(Lineage, Vec<Option<String>>)>,
+    poison: Option<Arc<Detector>>,
+}
+
+impl Chain {
+    pub fn new(filters: Vec<Box<Filter>>) -> Chain {
+        Chain {
+            filters: filters,
+            lineage: None,
+            poison: None,
+        }
+    }
+
+    /// Records what filters change about records in their lineage, under the given names.
+    ///
+    /// Filters without a name are not traced and cost nothing, while traced ones report what they
+    /// touch, see `Filter::apply_traced`. Records emitted by filters on flush get no entry from
+    /// the emitting filter.
+    pub fn trace(&mut self, lineage: Lineage, names: Vec<Option<String>>) {
+        self.lineage = Some((lineage, names));
+    }
+
+    /// Restores states of filters from the snapshot, keyed by their position, returning records
+    /// held in skipped states passed through the rest of the chain.
+    pub fn restore(&mut self, store: &mut Store) -> Vec<Record> {
+        for (id, filter) in self.filters.iter_mut().enumerate() {
+            let typename = filter.typename();
+            if let Some(state) = filter.state() {
+                store.restore(&format!("filter.{}", id), typename, state);
+            }
+        }
+
+        let mut result = Vec::new();
+        for (key, records) in store.held("filter.").into_iter() {
+            let position = match key["filter.".len()..].parse::<usize>() {
+                Ok(id) => id + 1,
+                Err(..) => self.filters.len(),
+            };
+
+            let mut records = records;
+            for id in position..self.filters.len() {
+                records = self.feed(id, records, 0);
+            }
+            result.extend(records.into_iter());
+        }
+
+        result
+    }
+
+    /// Takes states of filters into the snapshot, records held back by them included.
+    pub fn keep(&mut self, store: &mut Store) {
+        for (id, filter) in self.filters.iter_mut().enumerate() {
+            let typename = filter.typename();
+            if let Some(state) = filter.state() {
+                store.keep(&format!("filter.{}", id), typename, state);
+            }
+        }
+    }
+
+    /// Moves states of filters of the previous chain into filters of this one at the same
+    /// position and of the same type, so that records held back by them survive a reload.
+    ///
+    /// A state refused by the new filter is given back to the previous one, leaving the records
+    /// to its close.
+    pub fn inherit(&mut self, previous: &mut Chain) {
+        for (id, (filter, old)) in self.filters.iter_mut().zip(previous.filters.iter_mut()).enumerate() {
+            if filter.typename() != old.typename() {
+                continue;
+            }
+
+            if let (Some(state), Some(old)) = (filter.state(), old.state()) {
+                if state.version() != old.version() {
+                    continue;
+                }
+
+                if let Some(taken) = old.snapshot() {
+                    if let Err(reason) = state.restore(&taken) {
+                        warn!(target: "Filter", "unable to carry 'filter.{}' state over: {}", id, reason);
+                        if let Err(reason) = old.restore(&taken) {
+                            warn!(target: "Filter", "dropping 'filter.{}' state: {}", id, reason);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes records held back by filters through the rest of the chain, closing them if
+    /// they're about to be dropped.
+    fn drain(&mut self, close: bool) -> Vec<Record> {
+        let mut records = Vec::new();
+        for id in 0..self.filters.len() {
+            records = self.feed(id, records, 0);
+            let held = if close {
+                self.filters[id].close()
+            } else {
+                self.filters[id].flush()
+            };
+            records.extend(held.into_iter());
+        }
+
+        records
+    }
+
+    /// Applies filters to the record, logging the processing id it has been given on ingest at
+    /// each one.
+    ///
+    /// Watched by the poison record detector, the chain hashes the record once before the first
+    /// filter, dropping it if quarantined.
+    pub fn process(&mut self, record: Record, processing: u64) -> Vec<Record> {
+        if let Some(ref poison) = self.poison {
+            if !poison.enter(&record) {
+                debug!(target: "Filter", "processing {} dropping quarantined record", processing);
+                return Vec::new();
+            }
+        }
+
+        let mut records = vec![record];
+        for id in 0..self.filters.len() {
+            records = self.feed(id, records, processing);
+        }
+
+        if self.poison.is_some() {
+            poison::leave();
+        }
+        records
+    }
+
+    /// Feeds the records to the filter at the given position, tracing it if requested.
+    fn feed(&mut self, id: usize, records: Vec<Record>, processing: u64) -> Vec<Record> {
+        let filter = &mut self.filters[id];
+        let traced = match self.lineage {
+            Some((ref lineage, ref names)) => {
+                names.get(id).and_then(|name| name.as_ref()).map(|name| (lineage, name))
+            }
+            None => None,
+        };
+
+        let mut result = Vec::new();
+        for record in records.into_iter() {
+            trace!(target: "Filter", "processing {} applying '{}' filter", processing, filter.typename());
+            match traced {
+                Some((lineage, name)) => {
+                    for (mut record, changes) in filter.apply_traced(record).into_iter() {
+                        let changes: Vec<_> = changes.into_iter()
+                            .filter(|entry| entry.path[0] != lineage::FIELD)
+                            .collect();
+                        lineage.note(name, &changes, &mut record);
+                        result.push(record);
+                    }
+                }
+                None => result.extend(filter.apply(record).into_iter()),
+            }
+        }
+
+        result
+    }
+}
+
+impl Filter for Chain {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.process(record, 0)
+    }
+
+    fn flush(&mut self) -> Vec<Record> {
+        self.drain(false)
+    }
+
+    fn close(&mut self) -> Vec<Record> {
+        self.drain(true)
+    }
+
+    fn watch(&mut self, poison: Arc<Detector>) {
+        for filter in self.filters.iter_mut() {
+            filter.watch(poison.clone());
+        }
+        self.poison = Some(poison);
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields = Vec::new();
+        for filter in self.filters.iter() {
+            match filter.describe_fields() {
+                Some(names) => fields.extend(names.into_iter()),
+                None => return None,
+            }
+        }
+
+        Some(fields)
+    }
+}
+
+/// Returns the next number of the xorshift* generator, advancing its state.
+fn next(seed: &mut u64) -> u64 {
+    let mut x = *seed;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *seed = x;
+    x.wrapping_mul(2685821657736338717)
+}
+
+/// Returns a uniformly distributed number in [0; 1), advancing the xorshift* generator state.
+fn random(seed: &mut u64) -> f64 {
+    (next(seed) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+mod bucketize;
+mod coalesce;
+mod conditional;
+mod correlate;
+mod copy;
+mod governor;
+mod guard;
+mod id;
+mod influx;
+mod lookup;
+mod mask;
+mod metrics;
+mod number;
+mod project;
+mod promote;
+mod query;
+mod rate;
+mod render;
+mod reserved;
+mod size;
+mod sample;
+mod skew;
+mod syslog;
+mod time;
+mod truncate;
+
+pub use self::bucketize::{Bucketize, Missing};
+pub use self::coalesce::Coalesce;
+pub use self::conditional::{Action, Condition, Conditional};
+pub use self::correlate::{Correlate, Incomplete};
+pub use self::copy::{CopyFields, Existing};
+pub use self::governor::Governor;
+pub use self::guard::{Guard, OnError};
+pub use self::id::{EnsureId, Generator};
+pub use self::influx::InfluxLine;
+pub use self::lookup::{Lookup, TableFormat, Unmatched};
+pub use self::mask::{MaskFormat, NonString};
+pub use self::metrics::ToMetrics;
+pub use self::number::{NumberNormalize, Numeric, Rounding};
+pub use self::project::{Fill, Project};
+pub use self::promote::Promote;
+pub use self::query::ParseQuery;
+pub use self::rate::RateAnnotate;
+pub use self::render::RenderMessage;
+pub use self::reserved::EscapeReserved;
+pub use self::size::{MaxSize, Oversized};
+pub use self::sample::LevelSample;
+pub use self::skew::{Estimation, SkewFilter};
+pub use self::syslog::SyslogSeverity;
+pub use self::time::{TimeBucket, TimeFormat, Unparsable, timestamp};
+pub use self::truncate::Truncate;
@@ -0,0 +1,269 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff::{self, Change};
+use super::super::numeric;
+use super::super::numeric::Conversion;
+use super::{Failure, Filter};
+
+/// How fractional values become integers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rounding {
+    /// To the nearest integer, halves away from zero.
+    Nearest,
+    Floor,
+    Ceil,
+    /// Towards zero.
+    Truncate,
+}
+
+/// Numeric type values are coerced to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Numeric {
+    Float,
+    Int(Rounding),
+}
+
+/// Coerces top-level fields to a fixed numeric type, so that their type is stable across records
+/// whatever the sender wrote.
+///
+/// Numbers and strings holding a number are converted, other values are left intact unless the
+/// `on_error` policy of the filter is configured, failing the filter while nulls pass. Integers
+/// are `I64` or `U64` values encoded without a fraction, see `numeric`. Conversions losing
+/// precision, i.e. of integers beyond 2^53 to floats and of floats beyond 64 bits to integers,
+/// saturate and list the field in `_numeric_loss`.
+pub struct NumberNormalize {
+    fields: Vec<String>,
+    numeric: Numeric,
+}
+
+impl NumberNormalize {
+    pub fn new(fields: Vec<String>, numeric: Numeric) -> NumberNormalize {
+        NumberNormalize {
+            fields: fields,
+            numeric: numeric,
+        }
+    }
+
+    fn coerce(&self, value: &RecordItem) -> Option<Conversion<RecordItem>> {
+        // Strings holding an integer are parsed exactly, like decoders do.
+        let parsed = value.as_str().map(|value| value.trim()).and_then(|value| {
+            numeric::parse_integer(value).or_else(|| value.parse::<f64>().ok().map(RecordItem::F64))
+        });
+        let value = parsed.as_ref().unwrap_or(value);
+
+        match (self.numeric, value) {
+            (_, item @ &RecordItem::F64(..)) if numeric::finite(item).is_none() => None,
+            (Numeric::Float, value) => numeric::to_f64(value).map(|value| value.map(RecordItem::F64)),
+            (Numeric::Int(..), &RecordItem::I64(..)) | (Numeric::Int(..), &RecordItem::U64(..)) => {
+                Some(Conversion::Exact(value.clone()))
+            }
+            (Numeric::Int(rounding), &RecordItem::F64(value)) => {
+                let value = match rounding {
+                    Rounding::Nearest => value.round(),
+                    Rounding::Floor => value.floor(),
+                    Rounding::Ceil => value.ceil(),
+                    Rounding::Truncate => value.trunc(),
+                };
+
+                if value > 0.0 {
+                    Some(numeric::f64_to_u64(value).map(numeric::integer))
+                } else {
+                    Some(numeric::f64_to_i64(value).map(RecordItem::I64))
+                }
+            }
+            (Numeric::Int(..), _) => None,
+        }
+    }
+
+    fn normalize(&self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        for field in self.fields.iter() {
+            let value = match record.find(field) {
+                Some(value) => self.coerce(value),
+                None => None,
+            };
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if !value.is_exact() {
+                let previous = changes.as_ref().and_then(|_| record.find(numeric::LOSS_FIELD).cloned());
+                numeric::annotate(record, field);
+                if let Some(changes) = changes.as_mut() {
+                    let loss = record.find(numeric::LOSS_FIELD).unwrap().clone();
+                    let change = match previous {
+                        Some(ref previous) if *previous == loss => None,
+                        Some(previous) => Some(Change::Changed(previous, loss)),
+                        None => Some(Change::Added(loss)),
+                    };
+                    if let Some(change) = change {
+                        changes.push(diff::Entry { path: vec![numeric::LOSS_FIELD.to_string()], change: change });
+                    }
+                }
+            }
+            super::insert(record, field.clone(), value.value(), changes.as_mut().map(|changes| &mut **changes));
+        }
+    }
+
+    /// Fails the record holding anything but a number, a numeric string or null in one of the
+    /// fields.
+    fn check(&self, record: Record) -> Result<Record, Failure> {
+        let invalid = self.fields.iter().find(|field| {
+            match record.find(field) {
+                Some(&RecordItem::Null) | None => false,
+                Some(value) => self.coerce(value).is_none(),
+            }
+        }).cloned();
+
+        match invalid {
+            Some(field) => Err(Failure::new(record, format!("'{}' field is not a finite number", field))),
+            None => Ok(record),
+        }
+    }
+}
+
+impl FromConfig for NumberNormalize {
+    fn from_config(config: &Config) -> Result<NumberNormalize, Error> {
+        let fields = try!(config.strings_or("fields", Vec::new()));
+        if fields.is_empty() {
+            return Err(Error::Invalid("number_normalize requires 'fields'".to_string()));
+        }
+
+        let numeric = match &try!(config.string_or("type", "float"))[..] {
+            "float" => Numeric::Float,
+            "int" => {
+                let rounding = match &try!(config.string_or("rounding", "nearest"))[..] {
+                    "nearest" => Rounding::Nearest,
+                    "floor" => Rounding::Floor,
+                    "ceil" => Rounding::Ceil,
+                    "truncate" => Rounding::Truncate,
+                    rounding => return Err(Error::Invalid(format!("unknown rounding '{}'", rounding))),
+                };
+                Numeric::Int(rounding)
+            }
+            numeric => return Err(Error::Invalid(format!("unknown numeric type '{}'", numeric))),
+        };
+
+        Ok(NumberNormalize::new(fields, numeric))
+    }
+}
+
+impl Filter for NumberNormalize {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.normalize(&mut record, None);
+        vec![record]
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply(record))
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.normalize(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(self.fields.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::codec::{Codec, Json};
+    use super::super::super::diff::{Change, Entry};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{NumberNormalize, Numeric, Rounding};
+
+    fn normalize(numeric: Numeric, payload: &str) -> Option<RecordItem> {
+        let mut filter = NumberNormalize::new(vec!["latency".to_string()], numeric);
+        let record = Json::default().decode(Box::new(Cursor::new(payload.as_bytes().to_vec()))).next().unwrap();
+        filter.apply(record).pop().unwrap().find("latency").cloned()
+    }
+
+    #[test]
+    fn normalize_ints_and_floats_to_float() {
+        for payload in [r#"{"latency": 3}"#, r#"{"latency": 3.0}"#, r#"{"latency": "3"}"#].iter() {
+            assert_eq!(Some(RecordItem::F64(3.0)), normalize(Numeric::Float, payload));
+        }
+        assert_eq!(Some(RecordItem::F64(3.5)), normalize(Numeric::Float, r#"{"latency": 3.5}"#));
+    }
+
+    #[test]
+    fn normalize_ints_and_floats_to_int() {
+        for payload in [r#"{"latency": 3}"#, r#"{"latency": 3.0}"#, r#"{"latency": 3.4}"#].iter() {
+            assert_eq!(Some(RecordItem::I64(3)), normalize(Numeric::Int(Rounding::Nearest), payload));
+        }
+        assert_eq!(Some(RecordItem::I64(4)), normalize(Numeric::Int(Rounding::Nearest), r#"{"latency": 3.5}"#));
+        assert_eq!(Some(RecordItem::I64(3)), normalize(Numeric::Int(Rounding::Floor), r#"{"latency": 3.9}"#));
+        assert_eq!(Some(RecordItem::I64(4)), normalize(Numeric::Int(Rounding::Ceil), r#"{"latency": 3.1}"#));
+        assert_eq!(Some(RecordItem::I64(-3)), normalize(Numeric::Int(Rounding::Truncate), r#"{"latency": -3.9}"#));
+    }
+
+    #[test]
+    fn saturate_and_annotate_lossy_conversions() {
+        let apply = |numeric: Numeric, latency: RecordItem| -> Record {
+            let mut filter = NumberNormalize::new(vec!["latency".to_string()], numeric);
+            filter.apply(record(vec![("latency", latency)])).pop().unwrap()
+        };
+        let loss = RecordItem::Array(vec![string("latency")]);
+
+        let normalized = apply(Numeric::Float, RecordItem::I64(9007199254740993));
+        assert_eq!(Some(&RecordItem::F64(9007199254740992.0)), normalized.find("latency"));
+        assert_eq!(Some(&loss), normalized.find("_numeric_loss"));
+
+        let normalized = apply(Numeric::Int(Rounding::Nearest), RecordItem::F64(1e20));
+        match normalized.find("latency") {
+            Some(&RecordItem::U64(18446744073709551615)) => {}
+            value => panic!("expected saturated integer, found {:?}", value),
+        }
+        assert_eq!(Some(&loss), normalized.find("_numeric_loss"));
+
+        // Exact conversions leave no trace.
+        let normalized = apply(Numeric::Int(Rounding::Nearest), string("18446744073709551615"));
+        match normalized.find("latency") {
+            Some(&RecordItem::U64(18446744073709551615)) => {}
+            value => panic!("expected exact integer, found {:?}", value),
+        }
+        assert_eq!(None, normalized.find("_numeric_loss"));
+    }
+
+    #[test]
+    fn keep_non_numeric_values() {
+        let mut filter = NumberNormalize::new(vec!["latency".to_string()], Numeric::Float);
+        let payload = record(vec![("latency", string("slow"))]);
+        assert_eq!(vec![payload.clone()], filter.apply(payload.clone()));
+        assert_eq!(Err(payload), filter.try_apply(record(vec![("latency", string("slow"))])).map_err(|failure| failure.record));
+
+        let payload = record(vec![("latency", RecordItem::Null)]);
+        assert_eq!(Ok(vec![payload.clone()]), filter.try_apply(payload));
+    }
+
+    #[test]
+    fn report_coerced_fields_and_loss() {
+        let mut filter = NumberNormalize::new(vec!["latency".to_string(), "size".to_string()], Numeric::Float);
+        let payload = record(vec![("latency", RecordItem::I64(9007199254740993)), ("size", RecordItem::F64(10.0))]);
+
+        let changes = vec![
+            Entry { path: vec!["_numeric_loss".to_string()], change: Change::Added(RecordItem::Array(vec![string("latency")])) },
+            Entry {
+                path: vec!["latency".to_string()],
+                change: Change::Changed(RecordItem::I64(9007199254740993), RecordItem::F64(9007199254740992.0)),
+            },
+        ];
+        let records = filter.apply_traced(payload.clone());
+        assert_eq!(changes, records[0].1);
+        assert_eq!(filter.apply(payload), vec![records[0].0.clone()]);
+    }
+}
@@ -0,0 +1,225 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::output::Template;
+use super::{Failure, Filter};
+
+/// What missing fields are filled with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fill {
+    Null,
+    /// The empty string.
+    Empty,
+}
+
+/// Projects records onto the fixed ordered set of top-level fields for fixed-schema sinks, e.g.
+/// CSV files: missing fields are filled in, extra ones dropped.
+///
+/// With the row target given, the projected values are also rendered into a single delimited row
+/// in the order of the fields, with the template of the file output. Values containing the
+/// delimiter, quotes or line breaks are quoted, nulls render empty. Records with nested values
+/// can't be rendered, failing the filter, which drops them unless the `on_error` policy is
+/// configured.
+pub struct Project {
+    fields: Vec<String>,
+    fill: Fill,
+    /// Target field together with the template rendering the row, if enabled.
+    row: Option<(String, Template)>,
+    delimiter: char,
+}
+
+impl Project {
+    pub fn new(fields: Vec<String>, fill: Fill) -> Project {
+        Project {
+            fields: fields,
+            fill: fill,
+            row: None,
+            delimiter: ',',
+        }
+    }
+
+    /// Renders the row delimited by the given character into the target field.
+    pub fn row(mut self, target: &str, delimiter: char) -> Result<Project, String> {
+        let delimiter_str = delimiter.to_string();
+        let format = self.fields.iter().map(|field| format!("{{{}}}", field)).collect::<Vec<_>>().join(&delimiter_str);
+
+        self.row = Some((target.to_string(), try!(Template::new(&format))));
+        self.delimiter = delimiter;
+        Ok(self)
+    }
+
+    /// Returns the value escaped for the row.
+    fn escape(&self, value: &RecordItem) -> RecordItem {
+        let value = match *value {
+            RecordItem::Null => return RecordItem::String(String::new()),
+            RecordItem::String(ref value) => &value[..],
+            RecordItem::Text(ref value) => value.as_str(),
+            ref value => return value.clone(),
+        };
+
+        if value.contains(self.delimiter) || value.contains('"') || value.contains('\n') || value.contains('\r') {
+            RecordItem::String(format!("\"{}\"", value.replace('"', "\"\"")))
+        } else {
+            RecordItem::String(value.to_string())
+        }
+    }
+
+    /// Builds the projected record, reporting it as replacing the given one.
+    fn project(&self, record: Record, changes: Option<&mut Vec<diff::Entry>>) -> Result<Record, Failure> {
+        let mut projected = Record::new();
+        for field in self.fields.iter() {
+            let value = match record.find(field) {
+                Some(value) => value.clone(),
+                None => match self.fill {
+                    Fill::Null => RecordItem::Null,
+                    Fill::Empty => RecordItem::String(String::new()),
+                },
+            };
+            projected.insert(field.clone(), value);
+        }
+
+        if let Some((ref target, ref template)) = self.row {
+            let mut escaped = Record::new();
+            for field in self.fields.iter() {
+                escaped.insert(field.clone(), self.escape(projected.find(field).unwrap()));
+            }
+
+            match template.render(&escaped) {
+                Ok(row) => {
+                    projected.insert(target.clone(), RecordItem::String(row));
+                }
+                Err(reason) => return Err(Failure::new(record, reason)),
+            }
+        }
+
+        if let Some(changes) = changes {
+            changes.extend(super::replaced(&record, &projected).into_iter());
+        }
+        Ok(projected)
+    }
+}
+
+impl FromConfig for Project {
+    fn from_config(config: &Config) -> Result<Project, Error> {
+        let fields = try!(config.strings_or("fields", Vec::new()));
+        if fields.is_empty() {
+            return Err(Error::Invalid("project requires non-empty 'fields'".to_string()));
+        }
+
+        let fill = match &try!(config.string_or("fill", "null"))[..] {
+            "null" => Fill::Null,
+            "empty" => Fill::Empty,
+            fill => return Err(Error::Invalid(format!("unknown fill '{}'", fill))),
+        };
+
+        let project = Project::new(fields, fill);
+        match config.find("row") {
+            Some(..) => {
+                let delimiter = try!(config.string_or("delimiter", ","));
+                let mut chars = delimiter.chars();
+                let delimiter = match (chars.next(), chars.next()) {
+                    (Some(delimiter), None) => delimiter,
+                    _ => return Err(Error::Invalid("'delimiter' must be a single character".to_string())),
+                };
+
+                project.row(&try!(config.string("row")), delimiter).map_err(Error::Invalid)
+            }
+            None => Ok(project),
+        }
+    }
+}
+
+impl Filter for Project {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.try_apply(record).unwrap_or_else(|failure| {
+            debug!(target: "Filter", "dropping record: {}", failure.reason);
+            Vec::new()
+        })
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        Ok(vec![try!(self.project(record, None))])
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        self.try_apply_traced(record).unwrap_or_else(|failure| {
+            debug!(target: "Filter", "dropping record: {}", failure.reason);
+            Vec::new()
+        })
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let mut changes = Vec::new();
+        let record = try!(self.project(record, Some(&mut changes)));
+        Ok(vec![(record, changes)])
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(self.fields.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::RecordItem;
+    use super::super::super::diff::{Change, Entry};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{Fill, Project};
+
+    fn schema() -> Vec<String> {
+        vec!["timestamp".to_string(), "level".to_string(), "source".to_string(), "message".to_string()]
+    }
+
+    #[test]
+    fn project_onto_fixed_fields() {
+        let mut filter = Project::new(schema(), Fill::Null);
+
+        let payload = record(vec![
+            ("timestamp", RecordItem::F64(1434326400.5)),
+            ("message", string("le message")),
+            ("host", string("web-1")),
+            ("extra", RecordItem::U64(42)),
+        ]);
+        let expected = record(vec![
+            ("timestamp", RecordItem::F64(1434326400.5)),
+            ("level", RecordItem::Null),
+            ("source", RecordItem::Null),
+            ("message", string("le message")),
+        ]);
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn render_row_in_field_order() {
+        let mut filter = Project::new(schema(), Fill::Empty).row("row", ',').unwrap();
+
+        let payload = record(vec![
+            ("message", string("said \"hi\", left")),
+            ("level", RecordItem::U64(3)),
+            ("timestamp", RecordItem::Null),
+            ("host", string("web-1")),
+        ]);
+        let records = filter.apply(payload);
+        assert_eq!(Some(&string(",3,,\"said \"\"hi\"\", left\"")), records[0].find("row"));
+        assert_eq!(Some(&string("")), records[0].find("source"));
+        assert_eq!(None, records[0].find("host"));
+
+        let nested = record(vec![("message", RecordItem::Array(vec![string("nested")]))]);
+        assert!(filter.try_apply(nested).is_err());
+    }
+
+    #[test]
+    fn report_dropped_and_filled_fields() {
+        let mut filter = Project::new(vec!["level".to_string(), "message".to_string()], Fill::Null);
+        let payload = record(vec![("message", string("le message")), ("host", string("web-1"))]);
+
+        let changes = vec![
+            Entry { path: vec!["host".to_string()], change: Change::Removed(string("web-1")) },
+            Entry { path: vec!["level".to_string()], change: Change::Added(RecordItem::Null) },
+        ];
+        let records = filter.apply_traced(payload.clone());
+        assert_eq!(changes, records[0].1);
+        assert_eq!(filter.apply(payload), vec![records[0].0.clone()]);
+    }
+}
@@ -0,0 +1,207 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff::{self, Change};
+use super::{Existing, Failure, Filter};
+
+/// Moves nested values to top-level keys, the opposite of flattening, e.g. `kubernetes.pod` to
+/// `pod` for simpler queries.
+///
+/// Each nested value goes under the last name of its path unless renamed. Objects left empty by
+/// moving their fields out are removed. A present target key is either overwritten or kept as
+/// is, leaving the nested value where it was.
+pub struct Promote {
+    pairs: Vec<(Vec<String>, String)>,
+    existing: Existing,
+}
+
+impl Promote {
+    /// Creates the filter moving values from each dotted source path to the paired target key.
+    pub fn new(pairs: Vec<(String, String)>, existing: Existing) -> Promote {
+        let pairs = pairs.into_iter().map(|(from, to)| {
+            (from.split('.').map(|name| name.to_string()).collect(), to)
+        }).collect();
+
+        Promote {
+            pairs: pairs,
+            existing: existing,
+        }
+    }
+
+    /// Moves the values, reporting each one as removed from its path and added to its target.
+    fn promote(&self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        for &(ref from, ref to) in self.pairs.iter() {
+            if self.existing == Existing::Skip && record.find(to).is_some() {
+                continue;
+            }
+            if record.find_path(from).is_none() {
+                continue;
+            }
+
+            let mut parent = record.remove(&from[0]).unwrap();
+            let value = if from.len() == 1 {
+                parent
+            } else {
+                let value = take(&mut parent, &from[1..]).unwrap();
+                if !is_empty(&parent) {
+                    record.insert(from[0].clone(), parent);
+                }
+                value
+            };
+
+            if let Some(changes) = changes.as_mut() {
+                changes.push(diff::Entry { path: from.clone(), change: Change::Removed(value.clone()) });
+            }
+            super::insert(record, to.clone(), value, changes.as_mut().map(|changes| &mut **changes));
+        }
+    }
+}
+
+/// Removes the value at the path within the object, removing objects left empty along the way.
+fn take(value: &mut RecordItem, path: &[String]) -> Option<RecordItem> {
+    let map = match *value {
+        RecordItem::Object(ref mut map) => map,
+        _ => return None,
+    };
+
+    if path.len() == 1 {
+        return map.remove(&path[0]);
+    }
+
+    let (taken, empty) = match map.get_mut(&path[0]) {
+        Some(nested) => {
+            let taken = take(nested, &path[1..]);
+            (taken, is_empty(nested))
+        }
+        None => return None,
+    };
+    if taken.is_some() && empty {
+        map.remove(&path[0]);
+    }
+
+    taken
+}
+
+fn is_empty(value: &RecordItem) -> bool {
+    match *value {
+        RecordItem::Object(ref map) => map.is_empty(),
+        _ => false,
+    }
+}
+
+impl FromConfig for Promote {
+    fn from_config(config: &Config) -> Result<Promote, Error> {
+        let mut pairs: Vec<(String, String)> = try!(config.mapping("rename")).into_iter().collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        for path in try!(config.strings_or("fields", Vec::new())).into_iter() {
+            let name = path.rsplit('.').next().unwrap().to_string();
+            pairs.push((path, name));
+        }
+        if pairs.is_empty() {
+            return Err(Error::Invalid("promote requires at least one field".to_string()));
+        }
+
+        let existing = match &try!(config.string_or("existing", "overwrite"))[..] {
+            "overwrite" => Existing::Overwrite,
+            "skip" => Existing::Skip,
+            existing => return Err(Error::Invalid(format!("unknown existing field policy '{}'", existing))),
+        };
+
+        Ok(Promote::new(pairs, existing))
+    }
+}
+
+impl Filter for Promote {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.promote(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.promote(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields = Vec::new();
+        for &(ref from, ref to) in self.pairs.iter() {
+            fields.push(from[0].clone());
+            if self.existing == Existing::Skip {
+                fields.push(to.clone());
+            }
+        }
+
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::diff::{Change, Entry};
+    use super::super::super::testing::{record, string};
+    use super::super::{Existing, Filter};
+    use super::Promote;
+
+    fn pairs(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values.iter().map(|&(from, to)| (from.to_string(), to.to_string())).collect()
+    }
+
+    fn kubernetes(fields: Vec<(&str, RecordItem)>) -> Record {
+        let mut map = HashMap::new();
+        map.insert("pod".to_string(), string("api-7f9c"));
+        map.insert("namespace".to_string(), string("prod"));
+        let mut payload = record(fields);
+        payload.insert("kubernetes".to_string(), RecordItem::Object(map));
+        payload
+    }
+
+    #[test]
+    fn promote_nested_fields() {
+        let mut filter = Promote::new(pairs(&[("kubernetes.pod", "pod"), ("kubernetes.namespace", "ns"), ("absent.field", "field")]),
+            Existing::Overwrite);
+
+        let expected = record(vec![("message", string("ok")), ("pod", string("api-7f9c")), ("ns", string("prod"))]);
+        assert_eq!(vec![expected], filter.apply(kubernetes(vec![("message", string("ok"))])));
+
+        // Objects keeping other fields stay.
+        let mut filter = Promote::new(pairs(&[("kubernetes.pod", "pod")]), Existing::Overwrite);
+        let mut rest = HashMap::new();
+        rest.insert("namespace".to_string(), string("prod"));
+        let expected = record(vec![("pod", string("api-7f9c")), ("kubernetes", RecordItem::Object(rest))]);
+        assert_eq!(vec![expected], filter.apply(kubernetes(vec![])));
+    }
+
+    #[test]
+    fn overwrite_or_skip_existing_target() {
+        let payload = kubernetes(vec![("pod", string("sidecar"))]);
+
+        let mut filter = Promote::new(pairs(&[("kubernetes.pod", "pod")]), Existing::Overwrite);
+        let records = filter.apply(payload.clone());
+        assert_eq!(Some(&string("api-7f9c")), records[0].find("pod"));
+        assert_eq!(None, records[0].find_path(&["kubernetes".to_string(), "pod".to_string()]));
+
+        let mut filter = Promote::new(pairs(&[("kubernetes.pod", "pod")]), Existing::Skip);
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+    }
+
+    #[test]
+    fn report_moved_fields() {
+        let mut filter = Promote::new(pairs(&[("kubernetes.pod", "pod")]), Existing::Overwrite);
+        let payload = kubernetes(vec![("pod", string("sidecar"))]);
+
+        let changes = vec![
+            Entry { path: vec!["kubernetes".to_string(), "pod".to_string()], change: Change::Removed(string("api-7f9c")) },
+            Entry { path: vec!["pod".to_string()], change: Change::Changed(string("sidecar"), string("api-7f9c")) },
+        ];
+        let records = filter.apply_traced(payload.clone());
+        assert_eq!(changes, records[0].1);
+        assert_eq!(filter.apply(payload), vec![records[0].0.clone()]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// Parses the URL query string held by a top-level field into key/value pairs, e.g. `a=1&b=two`.
+///
+/// Keys and values are percent-decoded, with `+` standing for a space. A key without `=` gets an
+/// empty value, while a repeated key gets the array of its values in order. Pairs are inserted
+/// under the target object, or at the top level without one, overwriting existing fields. All
+/// values are strings, as the query string carries no types.
+pub struct ParseQuery {
+    field: String,
+    target: Option<String>,
+}
+
+impl ParseQuery {
+    pub fn new(field: String, target: Option<String>) -> ParseQuery {
+        ParseQuery {
+            field: field,
+            target: target,
+        }
+    }
+
+    fn expand(&self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        let pairs = match record.find(&self.field).and_then(|value| value.as_str()) {
+            Some(query) => parse(query),
+            None => return,
+        };
+
+        match self.target {
+            Some(ref target) => {
+                super::insert(record, target.clone(), RecordItem::Object(pairs), changes);
+            }
+            None => {
+                for (key, value) in pairs.into_iter() {
+                    super::insert(record, key, value, changes.as_mut().map(|changes| &mut **changes));
+                }
+            }
+        }
+    }
+}
+
+/// Decodes percent-encoded octets, leaving malformed escapes as they are.
+fn decode(value: &str) -> String {
+    let hex = |byte: u8| -> Option<u8> {
+        match byte {
+            b'0'...b'9' => Some(byte - b'0'),
+            b'a'...b'f' => Some(byte - b'a' + 10),
+            b'A'...b'F' => Some(byte - b'A' + 10),
+            _ => None,
+        }
+    };
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let escaped = match bytes[pos] {
+            b'%' if pos + 2 < bytes.len() => {
+                match (hex(bytes[pos + 1]), hex(bytes[pos + 2])) {
+                    (Some(high), Some(low)) => Some(high << 4 | low),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                pos += 3;
+            }
+            None => {
+                decoded.push(if bytes[pos] == b'+' { b' ' } else { bytes[pos] });
+                pos += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Splits the query string into decoded pairs, repeated keys collecting their values in order.
+fn parse(query: &str) -> HashMap<String, RecordItem> {
+    let mut pairs = HashMap::new();
+    for pair in query.trim_left_matches('?').split('&').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = decode(parts.next().unwrap_or(""));
+        let value = RecordItem::String(decode(parts.next().unwrap_or("")));
+
+        let repeated = match pairs.get_mut(&key) {
+            Some(&mut RecordItem::Array(ref mut values)) => {
+                values.push(value);
+                continue;
+            }
+            Some(previous) => Some(previous.clone()),
+            None => None,
+        };
+
+        match repeated {
+            Some(previous) => pairs.insert(key, RecordItem::Array(vec![previous, value])),
+            None => pairs.insert(key, value),
+        };
+    }
+
+    pairs
+}
+
+impl FromConfig for ParseQuery {
+    fn from_config(config: &Config) -> Result<ParseQuery, Error> {
+        let field = try!(config.string_or("field", "query"));
+        let target = match config.find("target") {
+            Some(..) => Some(try!(config.string("target"))),
+            None => None,
+        };
+
+        Ok(ParseQuery::new(field, target))
+    }
+}
+
+impl Filter for ParseQuery {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.expand(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.expand(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.field.clone()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::ParseQuery;
+
+    #[test]
+    fn parse_simple_query_at_top_level() {
+        let mut filter = ParseQuery::new("query".to_string(), None);
+        let payload = record(vec![("query", string("a=1&b=two&empty=&flag"))]);
+
+        let mut expected = payload.clone();
+        expected.insert("a".to_string(), string("1"));
+        expected.insert("b".to_string(), string("two"));
+        expected.insert("empty".to_string(), string(""));
+        expected.insert("flag".to_string(), string(""));
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn collect_repeated_key_into_array() {
+        let mut filter = ParseQuery::new("query".to_string(), Some("params".to_string()));
+        let payload = record(vec![("query", string("a=1&b=two&a=3&a=4"))]);
+
+        let mut params = HashMap::new();
+        params.insert("a".to_string(), RecordItem::Array(vec![string("1"), string("3"), string("4")]));
+        params.insert("b".to_string(), string("two"));
+        let mut expected = payload.clone();
+        expected.insert("params".to_string(), RecordItem::Object(params));
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn decode_percent_encoded_values() {
+        let mut filter = ParseQuery::new("query".to_string(), Some("params".to_string()));
+        let payload = record(vec![("query", string("q=caf%C3%A9+au+lait&path=%2Fapi%2fv1&odd=100%&key%3D=x%zz"))]);
+
+        let mut params = HashMap::new();
+        params.insert("q".to_string(), string("café au lait"));
+        params.insert("path".to_string(), string("/api/v1"));
+        params.insert("odd".to_string(), string("100%"));
+        params.insert("key=".to_string(), string("x%zz"));
+        let mut expected = payload.clone();
+        expected.insert("params".to_string(), RecordItem::Object(params));
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// Sliding-window event counter of a single key, kept as counts of consecutive slots.
+struct Counter {
+    /// Start of each slot in milliseconds together with the number of events within it, oldest
+    /// first.
+    slots: VecDeque<(u64, u64)>,
+    /// Number of events within all slots.
+    total: u64,
+    /// When the last event was counted.
+    seen: u64,
+}
+
+impl Counter {
+    fn new() -> Counter {
+        Counter {
+            slots: VecDeque::new(),
+            total: 0,
+            seen: 0,
+        }
+    }
+
+    /// Counts the event happening now, returning the number of events within the window ending
+    /// with it, the event included.
+    fn hit(&mut self, now: u64, window: u64, slot: u64) -> u64 {
+        let start = now - now % slot;
+        while let Some(&(begin, count)) = self.slots.front() {
+            if begin + window > start {
+                break;
+            }
+            self.slots.pop_front();
+            self.total -= count;
+        }
+
+        // A clock going back counts the event in the latest slot.
+        let counted = match self.slots.back_mut() {
+            Some(&mut (begin, ref mut count)) if begin >= start => {
+                *count += 1;
+                true
+            }
+            _ => false,
+        };
+        if !counted {
+            self.slots.push_back((start, 1));
+        }
+
+        self.total += 1;
+        self.seen = now;
+        self.total
+    }
+}
+
+/// Annotates each record with the recent event rate of its key, e.g. of its source, for
+/// detecting anomalies downstream.
+///
+/// Events are counted per string value of the key field within the sliding window, split into
+/// slots so that a key costs memory proportional to their number rather than to its rate. The
+/// rate written to the rate field is in events per second over the whole window, the record
+/// itself included. Records without the key pass unchanged.
+///
+/// Keys seen no events within the window are forgotten, as their rate has fallen to zero
+/// anyway, once a new key arrives. With `max_keys` still tracked the least recently seen one is
+/// forgotten as well.
+pub struct RateAnnotate {
+    key: String,
+    field: String,
+    /// Window length in milliseconds.
+    window: u64,
+    /// Slot length in milliseconds.
+    slot: u64,
+    keys: usize,
+    clock: Arc<Clock>,
+    counters: HashMap<String, Counter>,
+}
+
+impl RateAnnotate {
+    /// Creates the filter counting events within the window given in milliseconds, split into
+    /// the given number of slots.
+    pub fn new(key: &str, field: &str, window: u64, slots: u64, keys: usize, clock: Arc<Clock>) -> RateAnnotate {
+        RateAnnotate {
+            key: key.to_string(),
+            field: field.to_string(),
+            window: window,
+            slot: window / slots,
+            keys: keys,
+            clock: clock,
+            counters: HashMap::new(),
+        }
+    }
+
+    /// Forgets keys idle for the whole window and, if still at the limit, the least recently
+    /// seen one.
+    fn expire(&mut self, now: u64) {
+        let window = self.window;
+        let expired: Vec<String> = self.counters.iter()
+            .filter(|&(_, counter)| now.saturating_sub(counter.seen) >= window)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired.iter() {
+            self.counters.remove(key);
+        }
+
+        if self.counters.len() >= self.keys {
+            let mut oldest: Option<(&String, u64)> = None;
+            for (key, counter) in self.counters.iter() {
+                match oldest {
+                    Some((_, seen)) if seen <= counter.seen => {}
+                    _ => oldest = Some((key, counter.seen)),
+                }
+            }
+
+            if let Some(key) = oldest.map(|(key, _)| key.clone()) {
+                self.counters.remove(&key);
+            }
+        }
+    }
+
+    fn annotate(&mut self, record: &mut Record, changes: Option<&mut Vec<diff::Entry>>) {
+        let key = match record.find(&self.key).and_then(|value| value.as_str()) {
+            Some(key) => key.to_string(),
+            None => return,
+        };
+
+        let now = self.clock.now();
+        if !self.counters.contains_key(&key) {
+            self.expire(now);
+        }
+
+        let count = self.counters.entry(key).or_insert(Counter::new()).hit(now, self.window, self.slot);
+        let rate = RecordItem::F64(count as f64 * 1000.0 / self.window as f64);
+        super::insert(record, self.field.clone(), rate, changes);
+    }
+}
+
+impl FromConfig for RateAnnotate {
+    fn from_config(config: &Config) -> Result<RateAnnotate, Error> {
+        let key = try!(config.string_or("key", "source"));
+        let field = try!(config.string_or("field", "rate"));
+        let window = try!(config.u64_or("window", 60000));
+        let slots = try!(config.u64_or("slots", 10));
+        let keys = try!(config.u64_or("max_keys", 10000)) as usize;
+
+        if window == 0 || slots == 0 || keys == 0 {
+            return Err(Error::Invalid("'window', 'slots' and 'max_keys' must be positive".to_string()));
+        }
+        if slots > window {
+            return Err(Error::Invalid("'slots' must not exceed the 'window' in milliseconds".to_string()));
+        }
+
+        Ok(RateAnnotate::new(&key, &field, window, slots, keys, Arc::new(SystemClock)))
+    }
+}
+
+impl Filter for RateAnnotate {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.annotate(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.annotate(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.key.clone()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::super::RecordItem;
+    use super::super::super::clock::MockClock;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::RateAnnotate;
+
+    fn rate(filter: &mut RateAnnotate, source: &str) -> f64 {
+        match filter.apply(record(vec![("source", string(source))])).pop().unwrap().find("rate") {
+            Some(&RecordItem::F64(rate)) => rate,
+            other => panic!("unexpected rate: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn raise_rate_of_bursting_key_only() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = RateAnnotate::new("source", "rate", 10000, 10, 16, Arc::new(clock.clone()));
+
+        // One record per second from both sources, then a burst of a hundred from one of them.
+        for _ in 0..10 {
+            clock.advance(1000);
+            rate(&mut filter, "burst");
+            rate(&mut filter, "quiet");
+        }
+        for _ in 0..100 {
+            rate(&mut filter, "burst");
+        }
+
+        clock.advance(1000);
+        let burst = rate(&mut filter, "burst");
+        let quiet = rate(&mut filter, "quiet");
+        assert!(burst > 10.0, "burst rate {}", burst);
+        assert!(quiet <= 1.0, "quiet rate {}", quiet);
+
+        // The burst slides out of the window.
+        clock.advance(10000);
+        assert_eq!(0.1, rate(&mut filter, "burst"));
+    }
+
+    #[test]
+    fn pass_records_without_key() {
+        let clock = MockClock::new(0);
+        let mut filter = RateAnnotate::new("source", "rate", 1000, 1, 16, Arc::new(clock));
+
+        let payload = record(vec![("message", string("le message"))]);
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+    }
+
+    #[test]
+    fn evict_idle_and_least_recently_seen_keys() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = RateAnnotate::new("source", "rate", 10000, 10, 2, Arc::new(clock.clone()));
+
+        rate(&mut filter, "idle");
+        clock.advance(10000);
+        rate(&mut filter, "first");
+        assert_eq!(vec!["first"], keys(&filter));
+
+        clock.advance(1000);
+        rate(&mut filter, "second");
+        clock.advance(1000);
+        rate(&mut filter, "third");
+        assert_eq!(vec!["second", "third"], keys(&filter));
+    }
+
+    fn keys(filter: &RateAnnotate) -> Vec<&str> {
+        let mut keys: Vec<&str> = filter.counters.keys().map(|key| &key[..]).collect();
+        keys.sort();
+        keys
+    }
+}
@@ -0,0 +1,105 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::output::Template;
+use super::{Failure, Filter};
+
+/// Renders the template over the record, e.g. `{level} {source}: {msg}`, writing the result to
+/// the target field, `message` by default, for outputs wanting a single rendered string.
+///
+/// The template is the one of the file output, so missing placeholders are handled the same way:
+/// a record lacking one outside of sections can't be rendered, failing the filter, which drops
+/// it unless the `on_error` policy is configured. Defaults are given by negated sections, e.g.
+/// `{?msg}{msg}{/msg}{!msg}-{/msg}`.
+pub struct RenderMessage {
+    template: Template,
+    target: String,
+}
+
+impl RenderMessage {
+    pub fn new(template: Template, target: &str) -> RenderMessage {
+        RenderMessage {
+            template: template,
+            target: target.to_string(),
+        }
+    }
+
+    fn render(&self, mut record: Record, changes: Option<&mut Vec<diff::Entry>>) -> Result<Record, Failure> {
+        match self.template.render(&record) {
+            Ok(message) => {
+                super::insert(&mut record, self.target.clone(), RecordItem::String(message), changes);
+                Ok(record)
+            }
+            Err(reason) => Err(Failure::new(record, reason)),
+        }
+    }
+}
+
+impl FromConfig for RenderMessage {
+    fn from_config(config: &Config) -> Result<RenderMessage, Error> {
+        let template = try!(Template::new(&try!(config.string("template"))).map_err(Error::Invalid));
+        Ok(RenderMessage::new(template, &try!(config.string_or("target", "message"))))
+    }
+}
+
+impl Filter for RenderMessage {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.try_apply(record).unwrap_or_else(|failure| {
+            debug!(target: "Filter", "dropping record: {}", failure.reason);
+            Vec::new()
+        })
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        Ok(vec![try!(self.render(record, None))])
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        self.try_apply_traced(record).unwrap_or_else(|failure| {
+            debug!(target: "Filter", "dropping record: {}", failure.reason);
+            Vec::new()
+        })
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let mut changes = Vec::new();
+        let record = try!(self.render(record, Some(&mut changes)));
+        Ok(vec![(record, changes)])
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(self.template.fields())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::RecordItem;
+    use super::super::super::output::Template;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::RenderMessage;
+
+    #[test]
+    fn render_template_over_record() {
+        let mut filter = RenderMessage::new(Template::new("{level} {source}: {msg}").unwrap(), "message");
+
+        let payload = record(vec![("level", RecordItem::U64(3)), ("source", string("api")), ("msg", string("started"))]);
+        let mut expected = payload.clone();
+        expected.insert("message".to_string(), string("3 api: started"));
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn handle_missing_placeholder() {
+        let template = "{source}: {?msg}{msg}{/msg}{!msg}<empty>{/msg}";
+        let mut filter = RenderMessage::new(Template::new(template).unwrap(), "line");
+
+        let records = filter.apply(record(vec![("source", string("api"))]));
+        assert_eq!(Some(&string("api: <empty>")), records[0].find("line"));
+
+        let payload = record(vec![("msg", string("started"))]);
+        assert!(filter.try_apply(payload.clone()).is_err());
+        assert!(filter.apply(payload).is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use super::super::Record;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// Renames top-level keys that clash with Elasticsearch reserved or meta fields.
+///
+/// Explicit renames are applied first, then configured prefixes are stripped from the remaining
+/// keys, for example `_peer` becomes `peer` with the `_` prefix. A key is left untouched if its
+/// safe name is already taken by another field, unless the `on_error` policy of the filter is
+/// configured, failing the filter with the record kept as it was.
+pub struct EscapeReserved {
+    renames: HashMap<String, String>,
+    prefixes: Vec<String>,
+}
+
+impl EscapeReserved {
+    pub fn new(renames: HashMap<String, String>, prefixes: Vec<String>) -> EscapeReserved {
+        EscapeReserved {
+            renames: renames,
+            prefixes: prefixes,
+        }
+    }
+
+    fn escape(&self, key: &str) -> Option<String> {
+        if let Some(name) = self.renames.get(key) {
+            return Some(name.clone());
+        }
+
+        for prefix in self.prefixes.iter() {
+            if key.starts_with(&prefix[..]) && key.len() > prefix.len() {
+                return Some(key[prefix.len()..].to_string());
+            }
+        }
+
+        None
+    }
+
+    fn rename(&self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        let renames: Vec<(String, String)> = record.keys().filter_map(|key| {
+            self.escape(key).map(|name| (key.clone(), name))
+        }).collect();
+
+        for (key, name) in renames.into_iter() {
+            if record.find(&name).is_some() {
+                warn!(target: "Filter::EscapeReserved", "unable to rename '{}' to '{}': key already exists", key, name);
+                continue;
+            }
+
+            let value = super::remove(record, &key, changes.as_mut().map(|changes| &mut **changes)).unwrap();
+            super::insert(record, name, value, changes.as_mut().map(|changes| &mut **changes));
+        }
+    }
+
+    /// Fails the record with a key whose safe name is already taken.
+    fn check(&self, record: Record) -> Result<Record, Failure> {
+        let taken = record.keys()
+            .filter_map(|key| self.escape(key).map(|name| (key, name)))
+            .find(|&(_, ref name)| record.find(name).is_some())
+            .map(|(key, name)| format!("unable to rename '{}' to '{}': key already exists", key, name));
+
+        match taken {
+            Some(reason) => Err(Failure::new(record, reason)),
+            None => Ok(record),
+        }
+    }
+}
+
+impl FromConfig for EscapeReserved {
+    fn from_config(config: &Config) -> Result<EscapeReserved, Error> {
+        let renames = try!(config.mapping("renames"));
+        let prefixes = try!(config.strings_or("prefixes", Vec::new()));
+        Ok(EscapeReserved::new(renames, prefixes))
+    }
+}
+
+impl Filter for EscapeReserved {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.rename(&mut record, None);
+        vec![record]
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply(record))
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.rename(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply_traced(record))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::EscapeReserved;
+
+    #[test]
+    fn rename_reserved_key() {
+        let mut renames = HashMap::new();
+        renames.insert("_id".to_string(), "source_id".to_string());
+        let mut filter = EscapeReserved::new(renames, Vec::new());
+
+        let payload = record(vec![("_id", string("42")), ("message", string("le message"))]);
+        let expected = record(vec![("source_id", string("42")), ("message", string("le message"))]);
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn strip_prefix() {
+        let mut filter = EscapeReserved::new(HashMap::new(), vec!["_".to_string(), "@".to_string()]);
+
+        let payload = record(vec![("_peer", string("[::1]:42")), ("@input", string("tcp")), ("message", string("le message"))]);
+        let expected = record(vec![("peer", string("[::1]:42")), ("input", string("tcp")), ("message", string("le message"))]);
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn keep_key_on_collision() {
+        let mut filter = EscapeReserved::new(HashMap::new(), vec!["_".to_string()]);
+
+        let payload = record(vec![("_peer", string("[::1]:42")), ("peer", string("localhost"))]);
+        assert_eq!(vec![payload.clone()], filter.apply(payload.clone()));
+        assert_eq!(Err(payload.clone()), filter.try_apply(payload).map_err(|failure| failure.record));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::numeric;
+use super::Filter;
+
+/// Levels never sampled out.
+const KEPT: [&'static str; 2] = ["error", "warn"];
+
+fn kept(name: &str) -> bool {
+    KEPT.iter().any(|&kept| kept == name)
+}
+
+/// Returns the canonical name of the level, mapping syslog severities and common aliases to
+/// `error`, `warn`, `info` and `debug`.
+fn level(value: &RecordItem) -> Option<String> {
+    let name = match value.as_f64() {
+        Some(severity) => {
+            match numeric::f64_to_i64(severity).exact() {
+                Some(0...3) => "error",
+                Some(4) => "warn",
+                Some(5) | Some(6) => "info",
+                Some(7) => "debug",
+                _ => return Some(severity.to_string()),
+            }
+        }
+        None => {
+            let name = match value.as_str() {
+                Some(name) => name.to_lowercase(),
+                None => return None,
+            };
+
+            match &name[..] {
+                "emerg" | "alert" | "crit" | "critical" | "fatal" | "err" | "error" => "error",
+                "warning" | "warn" => "warn",
+                "notice" | "info" => "info",
+                "trace" | "debug" => "debug",
+                _ => return Some(name),
+            }
+        }
+    };
+
+    Some(name.to_string())
+}
+
+/// Samples records by their level, passing each one with the keep-rate of its level.
+///
+/// Levels are read from a string or a numeric syslog severity, so `"WARNING"` and `4` both mean
+/// `warn`. Error and warning records are always kept, records of levels without their own rate
+/// and without the level at all pass with the default rate.
+pub struct LevelSample {
+    field: String,
+    rates: HashMap<String, f64>,
+    default: f64,
+    seed: u64,
+}
+
+impl LevelSample {
+    /// Creates the filter, requiring rates in [0; 1] and no rates for always kept levels.
+    pub fn new(rates: HashMap<String, f64>, default: f64) -> Result<LevelSample, String> {
+        if let Some(rate) = rates.values().chain(Some(&default).into_iter()).find(|&&rate| !(rate >= 0.0 && rate <= 1.0)) {
+            return Err(format!("keep-rate {} is out of [0; 1]", rate));
+        }
+
+        let mut normalized = HashMap::new();
+        for (name, rate) in rates.into_iter() {
+            let name = level(&RecordItem::String(name)).unwrap();
+            if kept(&name) {
+                return Err(format!("'{}' records are always kept", name));
+            }
+            normalized.insert(name, rate);
+        }
+
+        Ok(LevelSample {
+            field: "level".to_string(),
+            rates: normalized,
+            default: default,
+            seed: SystemClock.now() | 1,
+        })
+    }
+
+    pub fn field(mut self, field: &str) -> LevelSample {
+        self.field = field.to_string();
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> LevelSample {
+        self.seed = seed | 1;
+        self
+    }
+
+    fn rate(&self, record: &Record) -> f64 {
+        match record.find(&self.field).and_then(level) {
+            Some(ref name) if kept(name) => 1.0,
+            Some(name) => self.rates.get(&name).cloned().unwrap_or(self.default),
+            None => self.default,
+        }
+    }
+}
+
+impl FromConfig for LevelSample {
+    fn from_config(config: &Config) -> Result<LevelSample, Error> {
+        let rates = try!(config.fractions("rates"));
+        let default = try!(config.f64_or("default", 1.0));
+        let field = try!(config.string_or("field", "level"));
+
+        LevelSample::new(rates, default)
+            .map(|filter| filter.field(&field))
+            .map_err(Error::Invalid)
+    }
+}
+
+impl Filter for LevelSample {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        let rate = self.rate(&record);
+        if rate < 1.0 && super::random(&mut self.seed) >= rate {
+            return Vec::new();
+        }
+
+        vec![record]
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.field.clone()])
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.seed = seed | 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::RecordItem;
+    use super::super::super::json;
+    use super::super::super::config::{Config, FromConfig};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::LevelSample;
+
+    fn filter() -> LevelSample {
+        let mut rates = HashMap::new();
+        rates.insert("info".to_string(), 0.5);
+        rates.insert("debug".to_string(), 0.1);
+        LevelSample::new(rates, 0.25).unwrap().seed(42)
+    }
+
+    /// Returns how many of the given number of records with the level pass the filter.
+    fn passed(filter: &mut LevelSample, level: Option<RecordItem>, count: usize) -> usize {
+        (0..count).filter(|&id| {
+            let mut pairs = vec![("id", RecordItem::F64(id as f64))];
+            pairs.extend(level.clone().map(|level| ("level", level)).into_iter());
+            filter.apply(record(pairs)).len() == 1
+        }).count()
+    }
+
+    #[test]
+    fn always_keep_errors_and_warnings() {
+        let mut filter = filter();
+        for level in vec![string("error"), string("ERR"), string("warning"), RecordItem::F64(3.0), RecordItem::F64(4.0)] {
+            assert_eq!(1000, passed(&mut filter, Some(level), 1000));
+        }
+    }
+
+    #[test]
+    fn sample_debug_at_configured_rate() {
+        let mut filter = filter();
+        let count = passed(&mut filter, Some(string("debug")), 20000);
+        assert!(count > 1800 && count < 2200, "{} of 20000 debug records passed", count);
+
+        let count = passed(&mut filter, Some(RecordItem::F64(7.0)), 20000);
+        assert!(count > 1800 && count < 2200, "{} of 20000 numeric debug records passed", count);
+
+        let count = passed(&mut filter, Some(string("info")), 20000);
+        assert!(count > 9600 && count < 10400, "{} of 20000 info records passed", count);
+    }
+
+    #[test]
+    fn sample_missing_and_unknown_levels_at_default_rate() {
+        let mut filter = filter();
+        let count = passed(&mut filter, None, 20000);
+        assert!(count > 4600 && count < 5400, "{} of 20000 records without level passed", count);
+
+        let count = passed(&mut filter, Some(string("verbose")), 20000);
+        assert!(count > 4600 && count < 5400, "{} of 20000 unknown level records passed", count);
+    }
+
+    #[test]
+    fn refuse_sampling_errors() {
+        let value = json::from_str(r#"{"rates": {"warning": 0.5}}"#).unwrap();
+        assert!(LevelSample::from_config(&Config::new(&value)).is_err());
+
+        let value = json::from_str(r#"{"rates": {"debug": 2}}"#).unwrap();
+        assert!(LevelSample::from_config(&Config::new(&value)).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// What to do with records over the limit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Oversized {
+    Drop,
+    /// Divert the record to the dead-letter output with the reason, dropping it if there is none.
+    DeadLetter,
+}
+
+/// Drops records whose estimated encoded size exceeds the limit in bytes, for outputs rejecting
+/// documents over a hard limit whatever their fields, e.g. Elasticsearch.
+///
+/// The size is the one of `Record::size_hint`, so the limit should leave some headroom.
+pub struct MaxSize {
+    limit: usize,
+    action: Oversized,
+}
+
+impl MaxSize {
+    pub fn new(limit: usize, action: Oversized) -> MaxSize {
+        MaxSize {
+            limit: limit,
+            action: action,
+        }
+    }
+}
+
+impl FromConfig for MaxSize {
+    fn from_config(config: &Config) -> Result<MaxSize, Error> {
+        let limit = match config.find("max") {
+            Some(..) => try!(config.u64_or("max", 0)),
+            None => return Err(Error::Invalid("max_size requires the 'max' limit".to_string())),
+        };
+        if limit == 0 {
+            return Err(Error::Invalid("'max' must be positive".to_string()));
+        }
+
+        let action = match &try!(config.string_or("action", "drop"))[..] {
+            "drop" => Oversized::Drop,
+            "dead_letter" => Oversized::DeadLetter,
+            action => return Err(Error::Invalid(format!("unknown max_size action '{}'", action))),
+        };
+
+        Ok(MaxSize::new(limit as usize, action))
+    }
+}
+
+impl MaxSize {
+    fn enforce(&self, mut record: Record, changes: Option<&mut Vec<diff::Entry>>) -> Vec<Record> {
+        let size = record.size_hint();
+        if size <= self.limit {
+            return vec![record];
+        }
+
+        let reason = format!("estimated size of {} bytes exceeds the limit of {}", size, self.limit);
+        match self.action {
+            Oversized::Drop => {
+                warn!(target: "Filter::MaxSize", "dropping record: {}", reason);
+                Vec::new()
+            }
+            Oversized::DeadLetter => {
+                super::insert(&mut record, "_dead_letter_reason".to_string(), RecordItem::String(reason), changes);
+                vec![record]
+            }
+        }
+    }
+}
+
+impl Filter for MaxSize {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        self.enforce(record, None)
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        let records = self.enforce(record, Some(&mut changes));
+        records.into_iter().map(|record| (record, changes.clone())).collect()
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter;
+
+    use super::super::super::Record;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{MaxSize, Oversized};
+
+    fn payload(len: usize) -> Record {
+        record(vec![("message", string(&iter::repeat("x").take(len).collect::<String>()))])
+    }
+
+    #[test]
+    fn pass_records_within_limit() {
+        let small = payload(10);
+        let limit = small.size_hint();
+
+        assert_eq!(vec![small.clone()], MaxSize::new(limit, Oversized::Drop).apply(small.clone()));
+        assert_eq!(vec![small.clone()], MaxSize::new(limit, Oversized::DeadLetter).apply(small));
+    }
+
+    #[test]
+    fn drop_or_mark_records_over_limit() {
+        let large = payload(100);
+        let size = large.size_hint();
+
+        assert!(MaxSize::new(64, Oversized::Drop).apply(large.clone()).is_empty());
+
+        let mut records = MaxSize::new(64, Oversized::DeadLetter).apply(large.clone());
+        assert_eq!(1, records.len());
+        let mut record = records.pop().unwrap();
+        let reason = format!("estimated size of {} bytes exceeds the limit of 64", size);
+        assert_eq!(Some(&reason[..]), record.remove("_dead_letter_reason").as_ref().and_then(|reason| reason.as_str()));
+        assert_eq!(large, record);
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::super::numeric;
+use super::super::source;
+use super::{Failure, Filter};
+
+/// Settings of the per-source clock offset estimator.
+///
+/// Offsets and bounds are in seconds, matching the record timestamp, while the idle timeout is in
+/// milliseconds like everything else driven by the clock.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Estimation {
+    /// Number of recent samples the offset is averaged over.
+    pub window: usize,
+    /// Samples further than this from the current estimate are clamped to it.
+    pub clamp: f64,
+    /// Samples further than this from the current estimate are considered a clock step.
+    pub step: f64,
+    /// Number of consecutive step samples required to reset the estimate.
+    pub confirm: u32,
+    /// Maximum number of tracked sources.
+    pub sources: usize,
+    /// Source state is forgotten after this many milliseconds without records.
+    pub idle: u64,
+}
+
+impl Default for Estimation {
+    fn default() -> Estimation {
+        Estimation {
+            window: 32,
+            clamp: 30.0,
+            step: 600.0,
+            confirm: 3,
+            sources: 1024,
+            idle: 3600 * 1000,
+        }
+    }
+}
+
+struct Source {
+    samples: VecDeque<f64>,
+    steps: VecDeque<f64>,
+    seen: u64,
+}
+
+impl Source {
+    fn new() -> Source {
+        Source {
+            samples: VecDeque::new(),
+            steps: VecDeque::new(),
+            seen: 0,
+        }
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            Some(self.samples.iter().fold(0.0, |acc, v| acc + v) / self.samples.len() as f64)
+        }
+    }
+
+    /// Feeds the observed offset, returning the updated estimate.
+    fn update(&mut self, sample: f64, estimation: &Estimation) -> f64 {
+        let sample = match self.estimate() {
+            Some(estimate) if (sample - estimate).abs() > estimation.step => {
+                self.steps.push_back(sample);
+                if self.steps.len() < estimation.confirm as usize {
+                    return estimate;
+                }
+
+                // The source clock has stepped, the history describes a clock that's gone.
+                self.samples.clear();
+                while let Some(sample) = self.steps.pop_front() {
+                    self.samples.push_back(sample);
+                }
+                return self.estimate().unwrap();
+            }
+            Some(estimate) => {
+                self.steps.clear();
+                sample.max(estimate - estimation.clamp).min(estimate + estimation.clamp)
+            }
+            None => sample,
+        };
+
+        self.samples.push_back(sample);
+        while self.samples.len() > estimation.window {
+            self.samples.pop_front();
+        }
+
+        self.estimate().unwrap()
+    }
+}
+
+/// Compensates timestamps of records coming from sources with known bad clocks.
+///
+/// For records whose source key, as told by `source::key`, equals one of the configured sources
+/// the clock offset is estimated as a moving average of the difference between the arrival time
+/// and the record timestamp, which assumes the network latency is negligible compared with the
+/// skew. The timestamp is then corrected by the estimate, keeping the original one in
+/// `original_timestamp` and the applied correction in `skew_correction`.
+///
+/// Occasional delayed batches are clamped to stay near the estimate, while several consecutive
+/// samples far away from it mean the source clock has stepped and reset the estimate.
+///
+/// Records of other sources or without the timestamp pass untouched, as do ones of the configured
+/// sources whose timestamp is not a finite number unless the `on_error` policy of the filter is
+/// configured.
+///
+/// The `key` option naming the field the source is read from is deprecated in favour of the
+/// pipeline `source` section, being still honoured with a warning.
+pub struct SkewFilter {
+    /// Field the source is read from instead of `source::key`, if configured the deprecated way.
+    key: Option<String>,
+    values: Vec<String>,
+    field: String,
+    estimation: Estimation,
+    clock: Arc<Clock>,
+    sources: HashMap<String, Source>,
+}
+
+impl SkewFilter {
+    pub fn new(values: Vec<String>, field: &str, estimation: Estimation, clock: Arc<Clock>) -> SkewFilter {
+        SkewFilter {
+            key: None,
+            values: values,
+            field: field.to_string(),
+            estimation: estimation,
+            clock: clock,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Reads the source from the given field instead of the key the pipeline resolves.
+    pub fn key(mut self, key: &str) -> SkewFilter {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    fn source<'r>(&self, record: &'r Record) -> Option<&'r str> {
+        match self.key {
+            Some(ref key) => record.find(key).and_then(|value| value.as_str()),
+            None => source::key(record),
+        }
+    }
+
+    /// Forgets idle sources and, if still at the limit, the least recently seen one.
+    fn expire(&mut self, now: u64) {
+        let idle = self.estimation.idle;
+        let expired: Vec<String> = self.sources.iter()
+            .filter(|&(_, source)| now.saturating_sub(source.seen) >= idle)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in expired.iter() {
+            self.sources.remove(name);
+        }
+
+        if self.sources.len() >= self.estimation.sources {
+            let mut oldest: Option<(&String, u64)> = None;
+            for (name, source) in self.sources.iter() {
+                match oldest {
+                    Some((_, seen)) if seen <= source.seen => {}
+                    _ => oldest = Some((name, source.seen)),
+                }
+            }
+
+            if let Some(name) = oldest.map(|(name, _)| name.clone()) {
+                self.sources.remove(&name);
+            }
+        }
+    }
+
+    fn correct(&mut self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        let name = match self.source(record) {
+            Some(name) if self.values.iter().any(|value| value == name) => Some(name.to_string()),
+            _ => None,
+        };
+
+        let timestamp = record.find(&self.field).and_then(numeric::finite);
+
+        let (name, timestamp) = match (name, timestamp) {
+            (Some(name), Some(timestamp)) => (name, timestamp),
+            _ => return,
+        };
+
+        let now = self.clock.now();
+        let idle = match self.sources.get(&name) {
+            Some(source) => now.saturating_sub(source.seen) >= self.estimation.idle,
+            None => true,
+        };
+
+        if idle {
+            self.sources.remove(&name);
+            self.expire(now);
+            self.sources.insert(name.clone(), Source::new());
+        }
+
+        let offset = {
+            let source = self.sources.get_mut(&name).unwrap();
+            source.seen = now;
+            source.update(now as f64 / 1000.0 - timestamp, &self.estimation)
+        };
+
+        super::insert(record, self.field.clone(), RecordItem::F64(timestamp + offset), changes.as_mut().map(|changes| &mut **changes));
+        super::insert(record, "original_timestamp".to_string(), RecordItem::F64(timestamp), changes.as_mut().map(|changes| &mut **changes));
+        super::insert(record, "skew_correction".to_string(), RecordItem::F64(offset), changes);
+    }
+
+    /// Fails the record of a known source holding anything but a number or null in the
+    /// timestamp field.
+    fn check(&self, record: Record) -> Result<Record, Failure> {
+        let known = match self.source(&record) {
+            Some(name) => self.values.iter().any(|value| value == name),
+            None => false,
+        };
+
+        let invalid = match record.find(&self.field) {
+            Some(&RecordItem::Null) | None => false,
+            Some(value) => numeric::finite(value).is_none(),
+        };
+
+        if known && invalid {
+            return Err(Failure::new(record, format!("'{}' field is not a finite number", self.field)));
+        }
+        Ok(record)
+    }
+}
+
+impl FromConfig for SkewFilter {
+    fn from_config(config: &Config) -> Result<SkewFilter, Error> {
+        let default = Estimation::default();
+        let estimation = Estimation {
+            window: try!(config.u64_or("window", default.window as u64)) as usize,
+            clamp: try!(config.f64_or("clamp", default.clamp)),
+            step: try!(config.f64_or("step", default.step)),
+            confirm: try!(config.u64_or("confirm", default.confirm as u64)) as u32,
+            sources: try!(config.u64_or("max_sources", default.sources as u64)) as usize,
+            idle: try!(config.u64_or("idle", default.idle)),
+        };
+
+        if estimation.window == 0 || estimation.sources == 0 {
+            return Err(Error::Invalid("'window' and 'max_sources' must be positive".to_string()));
+        }
+
+        let values = try!(config.strings_or("sources", Vec::new()));
+        let field = try!(config.string_or("field", "timestamp"));
+
+        let filter = SkewFilter::new(values, &field, estimation, Arc::new(SystemClock));
+        match config.find("key") {
+            Some(..) => {
+                let key = try!(config.string("key"));
+                warn!(target: "Filter", "'key' option of the skew filter is deprecated, configure the pipeline 'source' section instead");
+                Ok(filter.key(&key))
+            }
+            None => Ok(filter),
+        }
+    }
+}
+
+impl Filter for SkewFilter {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.correct(&mut record, None);
+        vec![record]
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply(record))
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.correct(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let record = try!(self.check(record));
+        Ok(self.apply_traced(record))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields = match self.key {
+            Some(ref key) => vec![key.clone()],
+            None => source::fields(),
+        };
+        fields.push(self.field.clone());
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::clock::MockClock;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{Estimation, SkewFilter};
+
+    const SKEW: f64 = 300.0;
+
+    fn filter(clock: &MockClock) -> SkewFilter {
+        let estimation = Estimation {
+            window: 16,
+            clamp: 5.0,
+            step: 120.0,
+            confirm: 3,
+            sources: 4,
+            idle: 60000,
+        };
+
+        SkewFilter::new(vec!["appliance".to_string()], "timestamp", estimation, Arc::new(clock.clone()))
+    }
+
+    fn timestamp(record: &Record) -> f64 {
+        match record.find("timestamp") {
+            Some(&RecordItem::F64(v)) => v,
+            other => panic!("unexpected timestamp: {:?}", other),
+        }
+    }
+
+    /// Sends a record stamped by a clock lagging by the given number of seconds plus jitter,
+    /// returning the corrected timestamp and the arrival time.
+    fn send(filter: &mut SkewFilter, clock: &MockClock, id: u64, lag: f64) -> (f64, f64) {
+        clock.advance(1000);
+        let jitter = ((id * 7919) % 11) as f64 / 10.0 - 0.5;
+        let now = filter.clock.now() as f64 / 1000.0;
+        let payload = record(vec![("source", string("appliance")), ("timestamp", RecordItem::F64(now - lag + jitter))]);
+
+        let result = filter.apply(payload);
+        assert_eq!(1, result.len());
+        (timestamp(&result[0]), now)
+    }
+
+    #[test]
+    fn converge_on_constant_skew() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = filter(&clock);
+
+        for id in 0..32 {
+            send(&mut filter, &clock, id, SKEW);
+        }
+
+        let (corrected, now) = send(&mut filter, &clock, 32, SKEW);
+        assert!((corrected - now).abs() < 1.0, "{} vs {}", corrected, now);
+    }
+
+    #[test]
+    fn clamp_delayed_batch() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = filter(&clock);
+
+        for id in 0..32 {
+            send(&mut filter, &clock, id, SKEW);
+        }
+
+        // A batch delayed by a minute would otherwise drag the estimate by ~4 seconds.
+        let (corrected, now) = send(&mut filter, &clock, 32, SKEW + 60.0);
+        assert!((corrected - (now - 60.0)).abs() < 1.5, "{} vs {}", corrected, now - 60.0);
+
+        let (corrected, now) = send(&mut filter, &clock, 33, SKEW);
+        assert!((corrected - now).abs() < 1.5, "{} vs {}", corrected, now);
+    }
+
+    #[test]
+    fn reset_on_clock_step() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = filter(&clock);
+
+        for id in 0..32 {
+            send(&mut filter, &clock, id, SKEW);
+        }
+
+        // The source clock is fixed, the first samples after the step are not trusted yet.
+        let (corrected, now) = send(&mut filter, &clock, 32, 0.0);
+        assert!((corrected - (now + SKEW)).abs() < 2.0);
+        send(&mut filter, &clock, 33, 0.0);
+
+        let (corrected, now) = send(&mut filter, &clock, 34, 0.0);
+        assert!((corrected - now).abs() < 1.0, "{} vs {}", corrected, now);
+    }
+
+    #[test]
+    fn pass_other_sources() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = filter(&clock);
+
+        let payload = record(vec![("source", string("server")), ("timestamp", RecordItem::F64(42.0))]);
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+
+        let payload = record(vec![("source", string("server")), ("timestamp", string("yesterday"))]);
+        assert_eq!(Ok(vec![payload.clone()]), filter.try_apply(payload).map_err(|failure| failure.record));
+    }
+
+    #[test]
+    fn fail_on_non_numeric_timestamp() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = filter(&clock);
+
+        let payload = record(vec![("source", string("appliance")), ("timestamp", string("yesterday"))]);
+        assert_eq!(Err(payload.clone()), filter.try_apply(payload.clone()).map_err(|failure| failure.record));
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+    }
+
+    #[test]
+    fn read_source_from_deprecated_key() {
+        let clock = MockClock::new(1000000000);
+        let mut filter = filter(&clock).key("host");
+
+        let payload = record(vec![("host", string("appliance")), ("source", string("server")), ("timestamp", RecordItem::F64(42.0))]);
+        assert!(filter.apply(payload)[0].find("skew_correction").is_some());
+
+        let payload = record(vec![("source", string("appliance")), ("timestamp", RecordItem::F64(42.0))]);
+        assert_eq!(vec![payload.clone()], filter.apply(payload));
+    }
+}
@@ -0,0 +1,192 @@
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// Severity names by their code, as listed by RFC 5424.
+const SEVERITIES: [&'static str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+/// Facility names by their code, as listed by RFC 5424.
+const FACILITIES: [&'static str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp",
+    "ntp", "security", "console", "solaris-cron", "local0", "local1", "local2", "local3", "local4", "local5",
+    "local6", "local7",
+];
+
+/// Highest valid priority, i.e. `local7.debug`.
+const MAX_PRIORITY: u64 = 191;
+
+/// Derives the severity and facility names from the numeric syslog priority, e.g. `level` of
+/// `err` and `facility` of `mail` out of the priority 19.
+///
+/// The priority is taken from the first of the source fields present, as a number or a string
+/// holding one. Records with a missing or invalid priority get names of the default one, if
+/// configured. Otherwise records missing it are passed untouched, as are ones with an invalid
+/// priority unless the `on_error` policy of the filter is configured.
+pub struct SyslogSeverity {
+    fields: Vec<String>,
+    level: String,
+    facility: String,
+    default: Option<u64>,
+}
+
+impl SyslogSeverity {
+    /// Creates the filter, requiring the default priority, if any, to be a valid one.
+    pub fn new(fields: Vec<String>, level: &str, facility: &str, default: Option<u64>)
+        -> Result<SyslogSeverity, String>
+    {
+        match default {
+            Some(default) if default > MAX_PRIORITY => {
+                return Err(format!("default priority {} is out of range", default));
+            }
+            Some(..) | None => {}
+        }
+
+        Ok(SyslogSeverity {
+            fields: fields,
+            level: level.to_string(),
+            facility: facility.to_string(),
+            default: default,
+        })
+    }
+
+    /// Returns the priority taken from the first of the source fields present, if any, failing if
+    /// it's not a valid one.
+    fn priority(&self, record: &Record) -> Result<Option<u64>, String> {
+        let value = match self.fields.iter().filter_map(|field| record.find(field)).next() {
+            Some(value) if value.as_f64().is_some() => value.as_f64().unwrap(),
+            Some(value) => {
+                match value.as_str().and_then(|value| value.trim().parse().ok()) {
+                    Some(value) => value,
+                    None => return Err("priority is not a number".to_string()),
+                }
+            }
+            None => return Ok(None),
+        };
+
+        if value >= 0.0 && value <= MAX_PRIORITY as f64 && value.fract() == 0.0 {
+            Ok(Some(value as u64))
+        } else {
+            Err(format!("priority {} is out of range", value))
+        }
+    }
+
+    fn classify(&self, mut record: Record, mut changes: Option<&mut Vec<diff::Entry>>) -> Result<Record, Failure> {
+        let priority = match (self.priority(&record), self.default) {
+            (Ok(Some(priority)), _) => priority,
+            (Ok(None), Some(priority)) | (Err(..), Some(priority)) => priority,
+            (Ok(None), None) => return Ok(record),
+            (Err(reason), None) => return Err(Failure::new(record, reason)),
+        };
+
+        let severity = RecordItem::String(SEVERITIES[(priority & 7) as usize].to_string());
+        let facility = RecordItem::String(FACILITIES[(priority >> 3) as usize].to_string());
+        super::insert(&mut record, self.level.clone(), severity, changes.as_mut().map(|changes| &mut **changes));
+        super::insert(&mut record, self.facility.clone(), facility, changes);
+        Ok(record)
+    }
+}
+
+impl FromConfig for SyslogSeverity {
+    fn from_config(config: &Config) -> Result<SyslogSeverity, Error> {
+        let fields = try!(config.strings_or("fields", vec!["priority".to_string(), "pri".to_string()]));
+        let level = try!(config.string_or("level", "level"));
+        let facility = try!(config.string_or("facility", "facility"));
+        let default = match config.find("default") {
+            Some(..) => Some(try!(config.u64_or("default", 0))),
+            None => None,
+        };
+
+        SyslogSeverity::new(fields, &level, &facility, default).map_err(Error::Invalid)
+    }
+}
+
+impl Filter for SyslogSeverity {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        match self.try_apply(record) {
+            Ok(records) => records,
+            Err(failure) => vec![failure.record],
+        }
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        Ok(vec![try!(self.classify(record, None))])
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        match self.try_apply_traced(record) {
+            Ok(records) => records,
+            Err(failure) => vec![(failure.record, Vec::new())],
+        }
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let mut changes = Vec::new();
+        let record = try!(self.classify(record, Some(&mut changes)));
+        Ok(vec![(record, changes)])
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(self.fields.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::SyslogSeverity;
+
+    fn filter(default: Option<u64>) -> SyslogSeverity {
+        SyslogSeverity::new(vec!["priority".to_string(), "pri".to_string()], "level", "facility", default).unwrap()
+    }
+
+    fn names(record: &Record) -> (Option<&str>, Option<&str>) {
+        (record.find("level").and_then(|level| level.as_str()),
+         record.find("facility").and_then(|facility| facility.as_str()))
+    }
+
+    #[test]
+    fn map_known_priorities() {
+        let mut filter = filter(None);
+
+        let cases = [(0.0, "emerg", "kern"), (13.0, "notice", "user"), (19.0, "err", "mail"),
+                     (86.0, "info", "authpriv"), (165.0, "notice", "local4"), (191.0, "debug", "local7")];
+        for &(priority, level, facility) in cases.iter() {
+            let records = filter.apply(record(vec![("priority", RecordItem::F64(priority))]));
+            assert_eq!((Some(level), Some(facility)), names(&records[0]));
+        }
+
+        let records = filter.apply(record(vec![("pri", string("34"))]));
+        assert_eq!((Some("crit"), Some("auth")), names(&records[0]));
+    }
+
+    #[test]
+    fn pass_missing_or_invalid_priority_without_default() {
+        let mut filter = filter(None);
+
+        for payload in vec![record(vec![]), record(vec![("priority", RecordItem::F64(192.0))]),
+                            record(vec![("priority", RecordItem::F64(-1.0))]), record(vec![("pri", string("high"))])] {
+            assert_eq!(vec![payload.clone()], filter.apply(payload));
+        }
+
+        // Only an invalid priority is a failure the `on_error` policy applies to.
+        assert!(filter.try_apply(record(vec![])).is_ok());
+        let payload = record(vec![("pri", string("high"))]);
+        assert_eq!(Err(payload.clone()), filter.try_apply(payload).map_err(|failure| failure.record));
+    }
+
+    #[test]
+    fn apply_default_to_missing_or_invalid_priority() {
+        let mut filter = filter(Some(14));
+
+        let records = filter.apply(record(vec![("priority", RecordItem::F64(13.5))]));
+        assert_eq!((Some("info"), Some("user")), names(&records[0]));
+
+        let records = filter.apply(record(vec![]));
+        assert_eq!((Some("info"), Some("user")), names(&records[0]));
+
+        assert!(SyslogSeverity::new(Vec::new(), "level", "facility", Some(192)).is_err());
+    }
+}
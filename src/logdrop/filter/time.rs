@@ -0,0 +1,275 @@
+use std::i64;
+
+use chrono::{DateTime, NaiveDateTime};
+
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// Field the reason is written to under the `mark` policy.
+const ERROR_FIELD: &'static str = "_time_bucket_error";
+
+/// How the floored timestamp is written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimeFormat {
+    /// Seconds since the Unix epoch, like the record timestamp.
+    Epoch,
+    /// RFC 3339 time in UTC, e.g. `2015-06-01T12:34:00Z`.
+    Iso,
+}
+
+/// What to do with a record whose timestamp is missing or can't be parsed, unless the `on_error`
+/// policy of the filter is configured.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Unparsable {
+    /// Pass the record untouched.
+    Pass,
+    /// Drop the record.
+    Drop,
+    /// Pass the record with the reason written to the `_time_bucket_error` field.
+    Mark,
+}
+
+/// Writes the timestamp floored to the bucket boundary to the target field, so that records are
+/// grouped by it cheaply downstream.
+///
+/// The timestamp is taken as seconds since the Unix epoch, either a number or a string holding
+/// one, or as an RFC 3339 string. Buckets are aligned to the epoch, hence to UTC midnight for
+/// buckets dividing a day.
+pub struct TimeBucket {
+    field: String,
+    bucket: u64,
+    target: String,
+    format: TimeFormat,
+    unparsable: Unparsable,
+}
+
+impl TimeBucket {
+    /// Creates the filter with the bucket size in seconds, requiring it to be positive.
+    pub fn new(field: &str, bucket: u64, target: &str, format: TimeFormat, unparsable: Unparsable)
+        -> Result<TimeBucket, String>
+    {
+        if bucket == 0 {
+            return Err("bucket size must be positive".to_string());
+        }
+
+        Ok(TimeBucket {
+            field: field.to_string(),
+            bucket: bucket,
+            target: target.to_string(),
+            format: format,
+            unparsable: unparsable,
+        })
+    }
+
+    /// Floors the timestamp, failing if it's out of the range of the ISO format.
+    fn floor(&self, timestamp: f64) -> Result<RecordItem, String> {
+        let bucket = self.bucket as f64;
+        let floored = (timestamp / bucket).floor() * bucket;
+
+        match self.format {
+            TimeFormat::Epoch => Ok(RecordItem::F64(floored)),
+            TimeFormat::Iso => {
+                if floored < i64::MIN as f64 || floored >= i64::MAX as f64 {
+                    return Err(format!("'{}' field is out of the time range", self.field));
+                }
+                match NaiveDateTime::from_timestamp_opt(floored as i64, 0) {
+                    Some(time) => Ok(RecordItem::String(time.format("%Y-%m-%dT%H:%M:%SZ").to_string())),
+                    None => Err(format!("'{}' field is out of the time range", self.field)),
+                }
+            }
+        }
+    }
+
+    fn stamp(&self, mut record: Record, changes: Option<&mut Vec<diff::Entry>>) -> Result<Record, Failure> {
+        match timestamp(record.find(&self.field), &self.field).and_then(|timestamp| self.floor(timestamp)) {
+            Ok(floored) => {
+                super::insert(&mut record, self.target.clone(), floored, changes);
+                Ok(record)
+            }
+            Err(reason) => Err(Failure::new(record, reason)),
+        }
+    }
+
+    /// Applies the unparsable timestamp policy to the failed record.
+    fn fail(&self, failure: Failure, changes: Option<&mut Vec<diff::Entry>>) -> Option<Record> {
+        let Failure { mut record, reason } = failure;
+        match self.unparsable {
+            Unparsable::Pass => {}
+            Unparsable::Drop => return None,
+            Unparsable::Mark => super::insert(&mut record, ERROR_FIELD.to_string(), RecordItem::String(reason), changes),
+        }
+        Some(record)
+    }
+}
+
+/// Parses the value of the timestamp field, seconds since the Unix epoch given either as a number
+/// or as a string holding one, or an RFC 3339 string.
+pub fn timestamp(value: Option<&RecordItem>, field: &str) -> Result<f64, String> {
+    let timestamp = match value {
+        Some(value) if value.as_f64().is_some() => value.as_f64().unwrap(),
+        Some(value) => {
+            let value = match value.as_str() {
+                Some(value) => value.trim(),
+                None => return Err(format!("'{}' field is neither a number nor a string", field)),
+            };
+
+            match value.parse::<f64>() {
+                Ok(value) => value,
+                Err(..) => {
+                    match DateTime::parse_from_rfc3339(value) {
+                        Ok(time) => time.timestamp() as f64,
+                        Err(err) => return Err(format!("unable to parse '{}' field: {}", field, err)),
+                    }
+                }
+            }
+        }
+        None => return Err(format!("'{}' field is missing", field)),
+    };
+
+    if timestamp.is_finite() {
+        Ok(timestamp)
+    } else {
+        Err(format!("'{}' field is not a finite number", field))
+    }
+}
+
+/// Parses the bucket size given as a number followed by a unit, e.g. `30s`, `5m`, `1h` or `1d`,
+/// into seconds.
+fn bucket(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let number = value.trim_right_matches(|c: char| c.is_alphabetic());
+    let unit = &value[number.len()..];
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("bucket size '{}' must end with one of 's', 'm', 'h' or 'd'", value)),
+    };
+
+    match number.parse::<u64>() {
+        Ok(number) => Ok(number * multiplier),
+        Err(..) => Err(format!("bucket size '{}' must start with a whole number", value)),
+    }
+}
+
+impl FromConfig for TimeBucket {
+    fn from_config(config: &Config) -> Result<TimeBucket, Error> {
+        let field = try!(config.string_or("field", "timestamp"));
+        let size = try!(bucket(&try!(config.string("bucket"))).map_err(Error::Invalid));
+        let target = try!(config.string_or("target", &format!("{}_bucket", field)));
+
+        let format = match &try!(config.string_or("format", "epoch"))[..] {
+            "epoch" => TimeFormat::Epoch,
+            "iso" => TimeFormat::Iso,
+            format => return Err(Error::Invalid(format!("unknown time format '{}'", format))),
+        };
+
+        let unparsable = match &try!(config.string_or("unparsable", "pass"))[..] {
+            "pass" => Unparsable::Pass,
+            "drop" => Unparsable::Drop,
+            "mark" => Unparsable::Mark,
+            unparsable => return Err(Error::Invalid(format!("unknown unparsable timestamp policy '{}'", unparsable))),
+        };
+
+        TimeBucket::new(&field, size, &target, format, unparsable).map_err(Error::Invalid)
+    }
+}
+
+impl Filter for TimeBucket {
+    fn apply(&mut self, record: Record) -> Vec<Record> {
+        match self.stamp(record, None) {
+            Ok(record) => vec![record],
+            Err(failure) => self.fail(failure, None).into_iter().collect(),
+        }
+    }
+
+    fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+        Ok(vec![try!(self.stamp(record, None))])
+    }
+
+    fn apply_traced(&mut self, record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        let result = self.stamp(record, Some(&mut changes));
+        let record = match result {
+            Ok(record) => Some(record),
+            Err(failure) => self.fail(failure, Some(&mut changes)),
+        };
+        record.into_iter().map(|record| (record, changes.clone())).collect()
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        let mut changes = Vec::new();
+        let record = try!(self.stamp(record, Some(&mut changes)));
+        Ok(vec![(record, changes)])
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(vec![self.field.clone()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::{TimeBucket, TimeFormat, Unparsable, bucket};
+
+    fn floored(filter: &mut TimeBucket, timestamp: RecordItem) -> Option<RecordItem> {
+        let records = filter.apply(record(vec![("timestamp", timestamp)]));
+        records[0].find("timestamp_bucket").cloned()
+    }
+
+    #[test]
+    fn floor_to_minute_and_hour() {
+        let mut minute = TimeBucket::new("timestamp", 60, "timestamp_bucket", TimeFormat::Epoch, Unparsable::Pass).unwrap();
+        // 2015-06-01T12:34:56.789Z
+        assert_eq!(Some(RecordItem::F64(1433162040.0)), floored(&mut minute, RecordItem::F64(1433162096.789)));
+        assert_eq!(Some(RecordItem::F64(1433162040.0)), floored(&mut minute, RecordItem::F64(1433162040.0)));
+        assert_eq!(Some(RecordItem::F64(1433162040.0)), floored(&mut minute, string("1433162099.999")));
+
+        let mut hour = TimeBucket::new("timestamp", 3600, "timestamp_bucket", TimeFormat::Iso, Unparsable::Pass).unwrap();
+        assert_eq!(Some(string("2015-06-01T12:00:00Z")), floored(&mut hour, RecordItem::F64(1433162096.789)));
+        assert_eq!(Some(string("2015-06-01T12:00:00Z")), floored(&mut hour, string("2015-06-01T15:59:59+03:00")));
+        assert_eq!(Some(string("2015-06-01T13:00:00Z")), floored(&mut hour, string("2015-06-01T13:00:00Z")));
+    }
+
+    #[test]
+    fn apply_unparsable_policy() {
+        let payload = record(vec![("timestamp", string("yesterday"))]);
+
+        let mut filter = TimeBucket::new("timestamp", 60, "timestamp_bucket", TimeFormat::Epoch, Unparsable::Pass).unwrap();
+        assert_eq!(vec![payload.clone()], filter.apply(payload.clone()));
+        assert_eq!(Err(payload.clone()), filter.try_apply(payload.clone()).map_err(|failure| failure.record));
+
+        let mut filter = TimeBucket::new("timestamp", 60, "timestamp_bucket", TimeFormat::Epoch, Unparsable::Drop).unwrap();
+        assert_eq!(Vec::<Record>::new(), filter.apply(payload.clone()));
+
+        let mut filter = TimeBucket::new("timestamp", 60, "timestamp_bucket", TimeFormat::Epoch, Unparsable::Mark).unwrap();
+        let records = filter.apply(record(vec![]));
+        assert_eq!(Some("'timestamp' field is missing"), records[0].find("_time_bucket_error").and_then(|value| value.as_str()));
+        assert!(records[0].find("timestamp_bucket").is_none());
+
+        // Timestamps the ISO time can't represent are unparsable too.
+        let mut filter = TimeBucket::new("timestamp", 60, "timestamp_bucket", TimeFormat::Iso, Unparsable::Mark).unwrap();
+        let records = filter.apply(record(vec![("timestamp", RecordItem::F64(1e300))]));
+        assert_eq!(Some("'timestamp' field is out of the time range"),
+            records[0].find("_time_bucket_error").and_then(|value| value.as_str()));
+        let records = filter.apply(record(vec![("timestamp", RecordItem::F64(1e17))]));
+        assert!(records[0].find("_time_bucket_error").is_some());
+    }
+
+    #[test]
+    fn parse_bucket_size() {
+        assert_eq!(Ok(30), bucket("30s"));
+        assert_eq!(Ok(300), bucket("5m"));
+        assert_eq!(Ok(3600), bucket("1h"));
+        assert_eq!(Ok(86400), bucket("1d"));
+        assert!(bucket("1w").is_err());
+        assert!(bucket("m").is_err());
+        assert!(TimeBucket::new("timestamp", 0, "timestamp_bucket", TimeFormat::Epoch, Unparsable::Pass).is_err());
+    }
+}
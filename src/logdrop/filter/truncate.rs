@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use super::super::{Record, RecordItem};
+use super::super::config::{Config, Error, FromConfig};
+use super::super::diff;
+use super::{Failure, Filter};
+
+/// Truncates oversized top-level string values, keeping at most the configured number of bytes.
+///
+/// Values are cut on a UTF-8 character boundary and the marker is appended to them. Fields
+/// without their own limit fall back to the global one, if any. Optionally each truncated field
+/// gets a `<field>_truncated: true` companion flag.
+pub struct Truncate {
+    limits: HashMap<String, usize>,
+    global: Option<usize>,
+    marker: String,
+    flag: bool,
+}
+
+impl Truncate {
+    pub fn new(limits: HashMap<String, usize>, global: Option<usize>) -> Truncate {
+        Truncate {
+            limits: limits,
+            global: global,
+            marker: "...".to_string(),
+            flag: false,
+        }
+    }
+
+    pub fn marker(mut self, marker: &str) -> Truncate {
+        self.marker = marker.to_string();
+        self
+    }
+
+    /// Adds the `<field>_truncated` flag to each truncated field.
+    pub fn flag(mut self, flag: bool) -> Truncate {
+        self.flag = flag;
+        self
+    }
+
+    fn limit(&self, key: &str) -> Option<usize> {
+        self.limits.get(key).cloned().or(self.global)
+    }
+
+    fn truncate(&self, record: &mut Record, mut changes: Option<&mut Vec<diff::Entry>>) {
+        let oversized: Vec<(String, String)> = record.iter().filter_map(|(key, value)| {
+            match (value.as_str(), self.limit(key)) {
+                (Some(value), Some(limit)) if value.len() > limit => {
+                    let mut truncated = prefix(value, limit).to_string();
+                    truncated.push_str(&self.marker);
+                    Some((key.clone(), truncated))
+                }
+                _ => None,
+            }
+        }).collect();
+
+        for (key, value) in oversized.into_iter() {
+            if self.flag {
+                let flag = format!("{}_truncated", key);
+                super::insert(record, flag, RecordItem::Bool(true), changes.as_mut().map(|changes| &mut **changes));
+            }
+            super::insert(record, key, RecordItem::String(value), changes.as_mut().map(|changes| &mut **changes));
+        }
+    }
+}
+
+/// Returns the longest prefix of the value not exceeding the limit in bytes without splitting a
+/// character.
+fn prefix(value: &str, limit: usize) -> &str {
+    let mut end = limit;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+impl FromConfig for Truncate {
+    fn from_config(config: &Config) -> Result<Truncate, Error> {
+        let limits = try!(config.sizes("fields")).into_iter().map(|(k, v)| (k, v as usize)).collect();
+        let global = match config.find("max") {
+            Some(..) => Some(try!(config.u64_or("max", 0)) as usize),
+            None => None,
+        };
+
+        let filter = Truncate::new(limits, global)
+            .marker(&try!(config.string_or("marker", "...")))
+            .flag(try!(config.bool_or("flag", false)));
+
+        if filter.limits.is_empty() && filter.global.is_none() {
+            return Err(Error::Invalid("truncate requires either 'fields' or 'max' limit".to_string()));
+        }
+
+        Ok(filter)
+    }
+}
+
+impl Filter for Truncate {
+    fn apply(&mut self, mut record: Record) -> Vec<Record> {
+        self.truncate(&mut record, None);
+        vec![record]
+    }
+
+    fn apply_traced(&mut self, mut record: Record) -> Vec<(Record, Vec<diff::Entry>)> {
+        let mut changes = Vec::new();
+        self.truncate(&mut record, Some(&mut changes));
+        vec![(record, changes)]
+    }
+
+    fn try_apply_traced(&mut self, record: Record) -> Result<Vec<(Record, Vec<diff::Entry>)>, Failure> {
+        Ok(self.apply_traced(record))
+    }
+
+    /// Only fields present are truncated, so none has to be decoded for the filter.
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, string};
+    use super::super::Filter;
+    use super::Truncate;
+
+    #[test]
+    fn truncate_ascii_string() {
+        let mut limits = HashMap::new();
+        limits.insert("message".to_string(), 8);
+        let mut filter = Truncate::new(limits, None).flag(true);
+
+        let payload = record(vec![("message", string("a rather long message")), ("source", string("a rather long source"))]);
+        let expected = record(vec![
+            ("message", string("a rather...")),
+            ("message_truncated", RecordItem::Bool(true)),
+            ("source", string("a rather long source")),
+        ]);
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+
+    #[test]
+    fn truncate_multibyte_string_on_char_boundary() {
+        let mut filter = Truncate::new(HashMap::new(), Some(5)).marker("…");
+
+        // Each Cyrillic letter takes two bytes, so only two of them fit.
+        let payload = record(vec![("message", string("привет")), ("short", string("ok"))]);
+        let expected = record(vec![("message", string("пр…")), ("short", string("ok"))]);
+        assert_eq!(vec![expected], filter.apply(payload));
+    }
+}
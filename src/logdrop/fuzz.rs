@@ -0,0 +1,339 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, TryRecvError};
+use std::thread;
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::codec::{Codec, Projection};
+use super::codec::json;
+use super::codec::msgpack;
+
+/// Maximum time a single input may take to pass through all decode paths, in milliseconds.
+const DEADLINE: u64 = 1000;
+
+/// Characters generated strings are made of, including ones that must be escaped in JSON and
+/// ones taking several bytes in UTF-8.
+const ALPHABET: [char; 17] = ['a', 'b', 'z', '0', '9', ' ', '"', '\\', '/', '\n', '\t', '\x01', '\x7f', 'é', 'ж', '日', '😀'];
+
+/// Control bytes inserted by `Mutation::InsertControl`.
+const CONTROL: [u8; 6] = [0x00, 0x01, 0x0a, 0x1b, 0x1f, 0x7f];
+
+/// Format of generated documents.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    /// Returns the name of the corpus directory holding inputs of the format.
+    fn name(&self) -> &'static str {
+        match *self {
+            Format::Json => "json",
+            Format::MessagePack => "msgpack",
+        }
+    }
+
+    /// Encodes the record with keys ordered by name, so that documents are reproducible from the
+    /// seed.
+    pub fn encode(&self, record: &Record) -> Vec<u8> {
+        match *self {
+            Format::Json => json::encode_sorted(record).into_bytes(),
+            Format::MessagePack => msgpack::encode_sorted(record),
+        }
+    }
+}
+
+/// Byte-level damage applied to a valid document.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mutation {
+    /// Cuts the document at a random position.
+    Truncate,
+    /// Flips a random bit.
+    FlipBit,
+    /// Repeats a random chunk of up to 16 bytes right after itself.
+    Duplicate,
+    /// Inserts a control byte at a random position.
+    InsertControl,
+}
+
+/// Deterministic generator of valid documents and their mutations, so that every failure is
+/// reproducible from the seed.
+///
+/// Generated records survive encoding and decoding intact: JSON ones carry neither bytes nor
+/// fractional numbers, as neither is preserved by the format.
+pub struct Generator {
+    seed: u64,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {
+            seed: seed.wrapping_mul(0x9e3779b97f4a7c15) | 1,
+        }
+    }
+
+    /// Advances the xorshift* state.
+    fn next(&mut self) -> u64 {
+        let mut x = self.seed;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.seed = x;
+        x.wrapping_mul(2685821657736338717)
+    }
+
+    /// Returns a number in [0; bound).
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    /// Returns a random record representable in the format.
+    pub fn record(&mut self, format: Format) -> Record {
+        let mut record = Record::new();
+        for _ in 0..self.below(6) {
+            let key = self.string();
+            let value = self.item(format, 3);
+            record.insert(key, value);
+        }
+        record
+    }
+
+    /// Returns a random valid document of the format together with the record it holds.
+    pub fn document(&mut self, format: Format) -> (Record, Vec<u8>) {
+        let record = self.record(format);
+        let data = format.encode(&record);
+        (record, data)
+    }
+
+    /// Returns the data damaged by one to three random mutations.
+    pub fn mutate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for _ in 0..1 + self.below(3) {
+            let mutation = match self.below(4) {
+                0 => Mutation::Truncate,
+                1 => Mutation::FlipBit,
+                2 => Mutation::Duplicate,
+                _ => Mutation::InsertControl,
+            };
+            data = self.apply(mutation, data);
+        }
+        data
+    }
+
+    /// Returns the given number of mutated documents of the format.
+    pub fn cases(&mut self, format: Format, count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|_| {
+            let (_, data) = self.document(format);
+            self.mutate(&data)
+        }).collect()
+    }
+
+    pub fn apply(&mut self, mutation: Mutation, mut data: Vec<u8>) -> Vec<u8> {
+        if data.is_empty() && mutation != Mutation::InsertControl {
+            return data;
+        }
+
+        match mutation {
+            Mutation::Truncate => {
+                let len = self.below(data.len());
+                data.truncate(len);
+            }
+            Mutation::FlipBit => {
+                let pos = self.below(data.len());
+                let bit = self.below(8);
+                data[pos] ^= 1 << bit;
+            }
+            Mutation::Duplicate => {
+                let start = self.below(data.len());
+                let end = start + 1 + self.below(cmp::min(16, data.len() - start));
+                let mut result = data[..end].to_vec();
+                result.extend(data[start..].iter().cloned());
+                data = result;
+            }
+            Mutation::InsertControl => {
+                let pos = self.below(data.len() + 1);
+                let byte = CONTROL[self.below(CONTROL.len())];
+                let mut result = data[..pos].to_vec();
+                result.push(byte);
+                result.extend(data[pos..].iter().cloned());
+                data = result;
+            }
+        }
+
+        data
+    }
+
+    fn item(&mut self, format: Format, depth: usize) -> RecordItem {
+        let kinds = if depth == 0 { 5 } else { 7 };
+        match self.below(kinds) {
+            0 => RecordItem::Null,
+            1 => RecordItem::Bool(self.below(2) == 1),
+            2 => RecordItem::F64(self.below(2000001) as f64 - 1000000.0),
+            3 => RecordItem::String(self.string()),
+            4 if format == Format::MessagePack => {
+                let len = self.below(8);
+                RecordItem::Bytes((0..len).map(|_| self.next() as u8).collect())
+            }
+            4 => RecordItem::String(self.string()),
+            5 => {
+                let len = self.below(4);
+                RecordItem::Array((0..len).map(|_| self.item(format, depth - 1)).collect())
+            }
+            _ => {
+                let mut object = HashMap::new();
+                for _ in 0..self.below(4) {
+                    let key = self.string();
+                    let value = self.item(format, depth - 1);
+                    object.insert(key, value);
+                }
+                RecordItem::Object(object)
+            }
+        }
+    }
+
+    fn string(&mut self) -> String {
+        let len = self.below(12);
+        (0..len).map(|_| ALPHABET[self.below(ALPHABET.len())]).collect()
+    }
+}
+
+/// Runs the input through every decode path of the codec, draining each stream.
+fn decode(codec: &Codec, input: &[u8]) {
+    let _ = codec.decode_one(input);
+
+    for _ in codec.decode(Box::new(Cursor::new(input.to_vec()))) {}
+
+    if let Ok(records) = codec.decode_raw(Box::new(Cursor::new(input.to_vec()))) {
+        for _ in records {}
+    }
+
+    let mut fields = HashSet::new();
+    fields.insert("a".to_string());
+    for _ in codec.decode_projected(Box::new(Cursor::new(input.to_vec())), Arc::new(Projection::Allow(fields))) {}
+}
+
+/// Runs every input through every decode path of the codec on a separate thread.
+///
+/// Fails with the first input making the codec panic or taking longer than the deadline, as
+/// neither is allowed whatever the input: malformed input must end streams and be reported as
+/// errors by `decode_one`. A stuck decoding thread is left behind, as there is no way to stop it.
+pub fn check(codec: &Codec, inputs: Vec<Vec<u8>>) -> Result<(), (Vec<u8>, String)> {
+    let inputs = Arc::new(inputs);
+    let (tx, rx) = channel();
+
+    let codec = codec.new();
+    let shared = inputs.clone();
+    thread::spawn(move || {
+        for input in shared.iter() {
+            decode(&*codec, input);
+            tx.send(()).unwrap();
+        }
+    });
+
+    let clock = SystemClock;
+    for input in inputs.iter() {
+        let started = clock.now();
+        loop {
+            match rx.try_recv() {
+                Ok(()) => break,
+                Err(TryRecvError::Empty) if clock.now() - started > DEADLINE => {
+                    return Err((input.clone(), format!("not decoded within {} ms", DEADLINE)));
+                }
+                Err(TryRecvError::Empty) => thread::sleep_ms(1),
+                Err(TryRecvError::Disconnected) => return Err((input.clone(), "decoding panicked".to_string())),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns inputs of the regression corpus of the format together with their paths.
+///
+/// The corpus lives in the `corpus/<format>` directory of the crate, one input per file. Every
+/// input that ever made a codec fail goes there, to be replayed by the test suite from then on.
+pub fn corpus(format: Format) -> Vec<(PathBuf, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("corpus").join(format.name());
+
+    let mut inputs = Vec::new();
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let mut data = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        inputs.push((path, data));
+    }
+    inputs.sort_by(|a, b| a.0.cmp(&b.0));
+    inputs
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::codec::{Codec, Json, MessagePack, SharedMessagePack};
+    use super::{Format, Generator, check, corpus};
+
+    /// Number of mutated documents per codec and test run.
+    const CASES: usize = 2000;
+
+    fn fuzz(codec: &Codec, format: Format, seed: u64) {
+        let inputs = Generator::new(seed).cases(format, CASES);
+        if let Err((input, reason)) = check(codec, inputs) {
+            panic!("{:?} input {:?}: {}, add it to the corpus", format, input, reason);
+        }
+    }
+
+    fn replay(codec: &Codec, format: Format) {
+        let inputs = corpus(format);
+        assert!(!inputs.is_empty());
+
+        for (path, input) in inputs.into_iter() {
+            if let Err((_, reason)) = check(codec, vec![input]) {
+                panic!("{}: {}", path.display(), reason);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_generated_documents() {
+        let mut generator = Generator::new(42);
+        for _ in 0..200 {
+            let (record, data) = generator.document(Format::Json);
+            assert_eq!(Ok(Some(record)), Json::default().decode_one(&data));
+
+            let (record, data) = generator.document(Format::MessagePack);
+            assert_eq!(Ok(Some(record.clone())), MessagePack::default().decode_one(&data));
+            assert_eq!(Ok(Some(record)), SharedMessagePack::default().decode_one(&data));
+        }
+    }
+
+    #[test]
+    fn fuzz_json() {
+        fuzz(&Json::default(), Format::Json, 1);
+    }
+
+    #[test]
+    fn fuzz_msgpack() {
+        fuzz(&MessagePack::default(), Format::MessagePack, 2);
+    }
+
+    #[test]
+    fn fuzz_shared_msgpack() {
+        fuzz(&SharedMessagePack::default(), Format::MessagePack, 3);
+    }
+
+    #[test]
+    fn replay_json_corpus() {
+        replay(&Json::default(), Format::Json);
+    }
+
+    #[test]
+    fn replay_msgpack_corpus() {
+        replay(&MessagePack::default(), Format::MessagePack);
+        replay(&SharedMessagePack::default(), Format::MessagePack);
+    }
+}
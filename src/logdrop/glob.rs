@@ -0,0 +1,347 @@
+use std::fmt;
+use std::mem;
+
+/// Glob compilation error, with the character offset of the offending class.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GlobError {
+    /// Class never closed by `]`.
+    Unclosed(usize),
+    /// Range with the end preceding the start, e.g. `[z-a]`.
+    Range(char, char, usize),
+}
+
+impl fmt::Display for GlobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GlobError::Unclosed(position) => write!(f, "class opened at {} is never closed", position),
+            GlobError::Range(start, end, position) => write!(f, "reversed range '{}-{}' at {}", start, end, position),
+        }
+    }
+}
+
+/// Character class, e.g. `[a-z_]` or `[!0-9]`.
+#[derive(Clone, Debug, PartialEq)]
+struct Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl Class {
+    fn contains(&self, c: char, insensitive: bool) -> bool {
+        let within = |c: char| self.ranges.iter().any(|&(start, end)| start <= c && c <= end);
+        let found = within(c) || insensitive && (within(lower(c)) || within(upper(c)));
+        found != self.negated
+    }
+}
+
+/// State of the compiled automaton, each consuming a single character.
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    Char(char),
+    /// `?`, any character.
+    Any,
+    Class(Class),
+    /// `*`, any run of characters, looping in place and skippable.
+    Star,
+    /// `**` of path globs, like `*` but crossing separators.
+    Deep,
+    /// `**/` of path globs, any number of whole directories: its state is at a directory boundary
+    /// and skippable, while the following `Dir` one is within a directory name.
+    Dirs,
+    Dir,
+}
+
+/// Glob pattern compiled into the automaton, matching in time linear to the input for any pattern,
+/// as all states are tracked at once instead of backtracking.
+///
+/// Supports `*` matching any run of characters, `?` matching any single one and classes like
+/// `[abc]`, `[a-z]` or negated `[!a-z]`, also written as `[^a-z]`. The `]` and `-` are literal
+/// first in a class, `-` last in it too, so `[*]` is the way to match the literal `*`. Input is
+/// matched character by character, not byte by byte.
+///
+/// Path globs don't let `*`, `?` and classes cross the `/`, which is matched by `**` instead, while
+/// `**/` matches any number of whole directories, including none.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glob {
+    states: Vec<State>,
+    separator: Option<char>,
+    insensitive: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, GlobError> {
+        compile(pattern, None)
+    }
+
+    /// Compiles the path glob, e.g. `app/**/*.json`.
+    pub fn path(pattern: &str) -> Result<Glob, GlobError> {
+        compile(pattern, Some('/'))
+    }
+
+    /// Makes the glob ignore the case of letters.
+    pub fn case_insensitive(mut self, insensitive: bool) -> Glob {
+        self.insensitive = insensitive;
+        self
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        let mut active = vec![false; self.states.len() + 1];
+        let mut next = vec![false; self.states.len() + 1];
+        active[0] = true;
+        self.close(&mut active);
+
+        for c in value.chars() {
+            for slot in next.iter_mut() {
+                *slot = false;
+            }
+
+            let separator = self.separator == Some(c);
+            let mut any = false;
+            for (at, state) in self.states.iter().enumerate() {
+                if !active[at] {
+                    continue;
+                }
+                any = true;
+
+                match *state {
+                    State::Char(expected) => {
+                        if expected == c || self.insensitive && lower(expected) == lower(c) {
+                            next[at + 1] = true;
+                        }
+                    }
+                    State::Any => {
+                        if !separator {
+                            next[at + 1] = true;
+                        }
+                    }
+                    State::Class(ref class) => {
+                        if !separator && class.contains(c, self.insensitive) {
+                            next[at + 1] = true;
+                        }
+                    }
+                    State::Star => {
+                        if !separator {
+                            next[at] = true;
+                        }
+                    }
+                    State::Deep => next[at] = true,
+                    // Back to the boundary once the directory name is over.
+                    State::Dirs | State::Dir => {
+                        let boundary = if *state == State::Dirs { at } else { at - 1 };
+                        if separator {
+                            next[boundary] = true;
+                        } else {
+                            next[boundary + 1] = true;
+                        }
+                    }
+                }
+            }
+            if !any {
+                return false;
+            }
+
+            self.close(&mut next);
+            mem::swap(&mut active, &mut next);
+        }
+
+        active[self.states.len()]
+    }
+
+    /// Activates states reachable without consuming anything, which are always further on.
+    fn close(&self, active: &mut [bool]) {
+        for (at, state) in self.states.iter().enumerate() {
+            if !active[at] {
+                continue;
+            }
+            match *state {
+                State::Star | State::Deep => active[at + 1] = true,
+                State::Dirs => active[at + 2] = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn compile(pattern: &str, separator: Option<char>) -> Result<Glob, GlobError> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut states = Vec::new();
+    let mut at = 0;
+
+    while at < chars.len() {
+        match chars[at] {
+            '*' if separator.is_some() && chars.get(at + 1) == Some(&'*') => {
+                if chars.get(at + 2) == separator.as_ref() {
+                    states.push(State::Dirs);
+                    states.push(State::Dir);
+                    at += 3;
+                } else {
+                    states.push(State::Deep);
+                    at += 2;
+                }
+                continue;
+            }
+            '*' => {
+                // Consecutive stars are the same as a single one.
+                if states.last() != Some(&State::Star) {
+                    states.push(State::Star);
+                }
+            }
+            '?' => states.push(State::Any),
+            '[' => {
+                let (class, end) = try!(parse_class(&chars, at));
+                states.push(State::Class(class));
+                at = end;
+            }
+            c => states.push(State::Char(c)),
+        }
+        at += 1;
+    }
+
+    Ok(Glob {
+        states: states,
+        separator: separator,
+        insensitive: false,
+    })
+}
+
+/// Parses the class opened at the given offset, returning it with the offset of its `]`.
+fn parse_class(chars: &[char], open: usize) -> Result<(Class, usize), GlobError> {
+    let mut at = open + 1;
+    let negated = match chars.get(at) {
+        Some(&'!') | Some(&'^') => {
+            at += 1;
+            true
+        }
+        _ => false,
+    };
+
+    let mut ranges = Vec::new();
+    let first = at;
+    loop {
+        let start = match chars.get(at) {
+            Some(&']') if at > first => break,
+            Some(&c) => c,
+            None => return Err(GlobError::Unclosed(open)),
+        };
+
+        match (chars.get(at + 1), chars.get(at + 2)) {
+            (Some(&'-'), Some(&end)) if end != ']' => {
+                if end < start {
+                    return Err(GlobError::Range(start, end, at));
+                }
+                ranges.push((start, end));
+                at += 3;
+            }
+            _ => {
+                ranges.push((start, start));
+                at += 1;
+            }
+        }
+    }
+
+    let class = Class {
+        negated: negated,
+        ranges: ranges,
+    };
+    Ok((class, at))
+}
+
+fn lower(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn upper(c: char) -> char {
+    c.to_uppercase().next().unwrap_or(c)
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::repeat;
+
+    use super::{Glob, GlobError};
+
+    fn glob(pattern: &str) -> Glob {
+        Glob::new(pattern).unwrap()
+    }
+
+    #[test]
+    fn match_wildcards() {
+        assert!(glob("web-*").matches("web-1"));
+        assert!(glob("web-*").matches("web-"));
+        assert!(!glob("web-*").matches("api-1"));
+        assert!(glob("*/health").matches("/v1/health"));
+        assert!(!glob("*/health").matches("/v1/healthz"));
+        assert!(glob("a?c").matches("abc"));
+        assert!(!glob("a?c").matches("ac"));
+        assert!(glob("*").matches(""));
+        assert!(!glob("").matches("a"));
+    }
+
+    #[test]
+    fn match_pathological_patterns_in_linear_time() {
+        let input: String = repeat('a').take(10000).collect();
+        let pattern = format!("{}b", repeat("a*").take(100).collect::<String>());
+        assert!(!glob(&pattern).matches(&input));
+        assert!(glob(&pattern).matches(&format!("{}b", input)));
+
+        let stars: String = repeat('*').take(1000).collect();
+        assert!(glob(&format!("{}x{}", stars, stars)).matches(&format!("{}x{}", input, input)));
+        assert!(!glob(&format!("{}x{}", stars, stars)).matches(&input));
+    }
+
+    #[test]
+    fn match_classes() {
+        assert!(glob("[abc]").matches("b"));
+        assert!(!glob("[abc]").matches("d"));
+        assert!(glob("v[0-9]").matches("v7"));
+        assert!(!glob("v[!0-9]").matches("v7"));
+        assert!(glob("v[^0-9]").matches("vx"));
+        assert!(glob("[]]").matches("]"));
+        assert!(glob("[!]]").matches("a"));
+        assert!(glob("[-a]").matches("-"));
+        assert!(glob("[a-]").matches("-"));
+        assert!(glob("[*]").matches("*"));
+        assert!(!glob("[*]").matches("x"));
+        assert!(glob("[a-cx-z]").matches("y"));
+    }
+
+    #[test]
+    fn refuse_malformed_classes() {
+        assert_eq!(Err(GlobError::Unclosed(4)), Glob::new("web-[a-z"));
+        assert_eq!(Err(GlobError::Unclosed(0)), Glob::new("[]"));
+        assert_eq!(Err(GlobError::Unclosed(0)), Glob::new("[!"));
+        assert_eq!(Err(GlobError::Range('z', 'a', 3)), Glob::new("ab[z-a]"));
+        assert_eq!("reversed range 'z-a' at 3", format!("{}", GlobError::Range('z', 'a', 3)));
+    }
+
+    #[test]
+    fn match_characters_not_bytes() {
+        assert!(glob("h?llo").matches("héllo"));
+        assert!(glob("[а-я]*").matches("привет"));
+        assert!(!glob("??").matches("é"));
+        assert!(glob("*ё").matches("всё"));
+    }
+
+    #[test]
+    fn ignore_case_if_asked() {
+        assert!(!glob("WEB-*").matches("web-1"));
+        assert!(glob("WEB-*").case_insensitive(true).matches("web-1"));
+        assert!(glob("[A-Z]x").case_insensitive(true).matches("qX"));
+        assert!(glob("ПРИВЕТ").case_insensitive(true).matches("привет"));
+    }
+
+    #[test]
+    fn keep_path_globs_within_directories() {
+        let path = |pattern: &str| Glob::path(pattern).unwrap();
+
+        assert!(path("*.json").matches("a.json"));
+        assert!(!path("*.json").matches("app/a.json"));
+        assert!(path("**/*.json").matches("a.json"));
+        assert!(path("**/*.json").matches("app/2015/a.json"));
+        assert!(!path("**/*.json").matches("appa.jsonl"));
+        assert!(path("app/**").matches("app/2015/a.log"));
+        assert!(!path("app/?.log").matches("app/ab.log"));
+        assert!(!path("app/[!x]og").matches("app//og"));
+        assert!(glob("*.json").matches("app/a.json"));
+    }
+}
@@ -0,0 +1,397 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::thread::JoinHandle;
+
+use libc::c_int;
+
+use super::config::{Config, Error as ConfigError, FromConfig};
+use super::pipeline::{Handle, Pipeline};
+use super::sys;
+use super::sys::Socket;
+
+/// Handover protocol version, both processes must speak the same one.
+pub const VERSION: u32 = 1;
+
+/// Environment variable telling the new process the descriptor of its handover socket.
+pub const SOCKET: &'static str = "LOGDROP_HANDOVER";
+
+const MAGIC: &'static [u8] = b"LDHO";
+const READY: u8 = b'R';
+
+static UPGRADE: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The peer does not speak the handover protocol at all.
+    Protocol,
+    /// The peer speaks the given incompatible protocol version.
+    Incompatible(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "handover failed: {}", err),
+            Error::Protocol => write!(f, "handover failed: peer does not speak the protocol"),
+            Error::Incompatible(version) => {
+                write!(f, "handover failed: incompatible protocol version {}, expected {}", version, VERSION)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// Upgrade settings: the binary to spawn, the running one by default, and the period in
+/// milliseconds accepted connections have to finish before the old process exits.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub binary: Option<PathBuf>,
+    pub drain: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            binary: None,
+            drain: 30000,
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, ConfigError> {
+        let binary = match config.find("binary") {
+            Some(..) => Some(PathBuf::from(try!(config.string("binary")))),
+            None => None,
+        };
+
+        Ok(Settings {
+            binary: binary,
+            drain: try!(config.u64_or("drain", Settings::default().drain as u64)) as u32,
+        })
+    }
+}
+
+fn hello(socket: &mut Socket, version: u32) -> Result<(), Error> {
+    let mut message = MAGIC.to_vec();
+    message.extend([(version >> 24) as u8, (version >> 16) as u8, (version >> 8) as u8, version as u8].iter().cloned());
+    try!(socket.write_all(&message));
+    Ok(())
+}
+
+/// Reads the peer hello, returning its protocol version.
+fn greeting(socket: &mut Socket) -> Result<u32, Error> {
+    let mut message = [0; 8];
+    try!(socket.recv_fd(&mut message));
+    if &message[..4] != MAGIC {
+        return Err(Error::Protocol);
+    }
+
+    Ok(message[4..].iter().fold(0, |version, &byte| (version << 8) | byte as u32))
+}
+
+/// Passes listening sockets of the running inputs to the new process.
+///
+/// Nothing is committed unless the peer speaks the same protocol version and confirms it has
+/// taken all the sockets over.
+pub fn send(socket: &mut Socket, listeners: &[(String, RawFd)]) -> Result<(), Error> {
+    try!(hello(socket, VERSION));
+    let version = try!(greeting(socket));
+    if version != VERSION {
+        return Err(Error::Incompatible(version));
+    }
+
+    for &(ref name, fd) in listeners.iter() {
+        let length = name.len() as u32;
+        try!(socket.send_fd(&[(length >> 8) as u8, length as u8], fd));
+        try!(socket.write_all(name.as_bytes()));
+    }
+    try!(socket.write_all(&[0, 0]));
+
+    let mut ready = [0];
+    try!(socket.read(&mut ready));
+    if ready[0] != READY {
+        return Err(Error::Protocol);
+    }
+
+    Ok(())
+}
+
+/// Receives listening sockets from the old process, returning them by input name.
+pub fn receive(socket: &mut Socket) -> Result<Vec<(String, RawFd)>, Error> {
+    accept(socket, VERSION)
+}
+
+fn accept(socket: &mut Socket, version: u32) -> Result<Vec<(String, RawFd)>, Error> {
+    let peer = try!(greeting(socket));
+    try!(hello(socket, version));
+    if peer != version {
+        return Err(Error::Incompatible(peer));
+    }
+
+    let mut listeners = Vec::new();
+    loop {
+        let mut length = [0; 2];
+        let fd = try!(socket.recv_fd(&mut length));
+        let length = (length[0] as usize) << 8 | length[1] as usize;
+        let fd = match fd {
+            Some(fd) => fd,
+            None if length == 0 => return Ok(listeners),
+            None => return Err(Error::Protocol),
+        };
+
+        let mut name = vec![0; length];
+        if length > 0 {
+            try!(socket.recv_fd(&mut name));
+        }
+        match String::from_utf8(name) {
+            Ok(name) => listeners.push((name, fd)),
+            Err(..) => {
+                sys::close_fd(fd);
+                return Err(Error::Protocol);
+            }
+        }
+    }
+}
+
+/// Confirms the sockets have been taken over, letting the old process go.
+pub fn ready(socket: &mut Socket) -> Result<(), Error> {
+    try!(socket.write_all(&[READY]));
+    Ok(())
+}
+
+/// Returns the handover socket passed by the old process, if this process is an upgrade.
+pub fn inherited() -> Option<Socket> {
+    let fd = env::var(SOCKET).ok().and_then(|fd| fd.parse::<c_int>().ok());
+    env::remove_var(SOCKET);
+    fd.map(|fd| unsafe { Socket::from_raw_fd(fd) })
+}
+
+/// Takes listening sockets over from the old process into the pipeline inputs of the same names.
+pub fn adopt(mut socket: Socket, pipeline: &mut Pipeline) -> Result<(), Error> {
+    for (name, fd) in try!(receive(&mut socket)).into_iter() {
+        match pipeline.inherit(&name, fd) {
+            Ok(()) => info!(target: "Handover", "'{}' input inherits listening socket", name),
+            Err(reason) => {
+                warn!(target: "Handover", "closing inherited socket of '{}' input: {}", name, reason);
+                sys::close_fd(fd);
+            }
+        }
+    }
+
+    ready(&mut socket)
+}
+
+/// Spawns the new process and hands listening sockets over to it, stopping the running pipeline
+/// once it confirms. The pipeline is left intact on failure.
+pub fn upgrade(settings: &Settings, handle: &Handle) -> Result<(), Error> {
+    let binary = match settings.binary {
+        Some(ref binary) => binary.clone(),
+        None => try!(env::current_exe()),
+    };
+
+    let (mut socket, peer) = try!(Socket::pair());
+    try!(peer.set_inheritable(true));
+
+    info!(target: "Handover", "spawning {:?} to hand listening sockets over", binary);
+    let mut child = try!(Command::new(&binary)
+        .args(&env::args().skip(1).collect::<Vec<String>>())
+        .env(SOCKET, &peer.as_raw_fd().to_string())
+        .spawn());
+    drop(peer);
+
+    match send(&mut socket, &handle.listeners()) {
+        Ok(()) => {
+            info!(target: "Handover", "listening sockets are handed over, draining");
+            handle.stop(settings.drain);
+            Ok(())
+        }
+        Err(err) => {
+            let _ = child.kill();
+            Err(err)
+        }
+    }
+}
+
+/// Only raises the flag, as nothing else is safe to do within a signal handler.
+extern fn upgrade_requested(_: c_int) {
+    UPGRADE.store(true, Ordering::SeqCst);
+}
+
+/// Upgrades the running process on SIGUSR2.
+pub struct Upgrader {
+    settings: Settings,
+    handle: Handle,
+}
+
+impl Upgrader {
+    pub fn new(settings: Settings, handle: Handle) -> Upgrader {
+        Upgrader {
+            settings: settings,
+            handle: handle,
+        }
+    }
+
+    /// Installs the SIGUSR2 handler, spawning a thread upgrading the process on the signal.
+    pub fn listen(self) -> JoinHandle<()> {
+        sys::on_signal(sys::SIGUSR2, upgrade_requested);
+
+        thread::spawn(move || {
+            loop {
+                thread::sleep_ms(100);
+                if UPGRADE.swap(false, Ordering::SeqCst) {
+                    match upgrade(&self.settings, &self.handle) {
+                        Ok(()) => return,
+                        Err(err) => error!(target: "Handover", "{}, keeping running", err),
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::thread;
+
+    use super::super::codec::MessagePack;
+    use super::super::codec::msgpack;
+    use super::super::input::TcpInput;
+    use super::super::pipeline::Pipeline;
+    use super::super::sys::Socket;
+    use super::super::testing::{Collector, record, string};
+    use super::super::validation::{Missing, Validation};
+    use super::{Error, VERSION, accept, adopt, ready, send};
+
+    fn wait_for(collector: &Collector, count: usize) {
+        for _ in 0..200 {
+            if collector.records().len() >= count {
+                return;
+            }
+            thread::sleep_ms(10);
+        }
+    }
+
+    fn pipeline(collector: &Collector, port: u16) -> Pipeline {
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("tcp", Box::new(TcpInput::new("127.0.0.1".to_string(), port)), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline
+    }
+
+    fn write(stream: &mut TcpStream, id: &str) {
+        stream.write_all(&msgpack::encode(&record(vec![("id", string(id))]))).unwrap();
+    }
+
+    #[test]
+    fn hand_listener_over_while_draining() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let old = Collector::new();
+        let mut running = pipeline(&old, port);
+        let handle = running.handle();
+        let running = thread::spawn(move || running.run());
+        thread::sleep_ms(100);
+
+        let mut draining = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write(&mut draining, "before");
+        wait_for(&old, 1);
+
+        let (mut parent, child) = Socket::pair().unwrap();
+        let new = Collector::new();
+        let collector = new.clone();
+        let upgraded = thread::spawn(move || {
+            let mut upgraded = pipeline(&collector, port);
+            let handle = upgraded.handle();
+            adopt(child, &mut upgraded).unwrap();
+            (handle, thread::spawn(move || upgraded.run()))
+        });
+
+        send(&mut parent, &handle.listeners()).unwrap();
+        handle.stop(200);
+        let (upgraded, upgraded_run) = upgraded.join().unwrap();
+        thread::sleep_ms(100);
+
+        // The old process keeps serving accepted connections within the drain period, while new
+        // ones are accepted by the new process on the same socket.
+        write(&mut draining, "draining");
+        let mut fresh = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write(&mut fresh, "after");
+
+        running.join().unwrap();
+        assert_eq!(vec![record(vec![("id", string("before"))]), record(vec![("id", string("draining"))])], old.records());
+
+        wait_for(&new, 1);
+        assert_eq!(vec![record(vec![("id", string("after"))])], new.records());
+
+        drop(fresh);
+        upgraded.stop(0);
+        upgraded_run.join().unwrap();
+    }
+
+    #[test]
+    fn construct_input_from_raw_fd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut parent, mut child) = Socket::pair().unwrap();
+
+        let sender = thread::spawn(move || {
+            send(&mut parent, &[("tcp".to_string(), listener.as_raw_fd())]).unwrap();
+        });
+        let listeners = accept(&mut child, VERSION).unwrap();
+        ready(&mut child).unwrap();
+        sender.join().unwrap();
+
+        assert_eq!(1, listeners.len());
+        assert_eq!("tcp", listeners[0].0);
+
+        let collector = Collector::new();
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        let input = unsafe { TcpInput::from_raw_fd(listeners[0].1) };
+        pipeline.add_input("tcp", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write(&mut stream, "inherited");
+        wait_for(&collector, 1);
+        drop(stream);
+
+        handle.stop(0);
+        pipeline.join().unwrap();
+        assert_eq!(vec![record(vec![("id", string("inherited"))])], collector.records());
+    }
+
+    #[test]
+    fn refuse_incompatible_version() {
+        let (mut parent, mut child) = Socket::pair().unwrap();
+        let receiver = thread::spawn(move || accept(&mut child, VERSION + 1));
+
+        match send(&mut parent, &[]) {
+            Err(Error::Incompatible(version)) => assert_eq!(VERSION + 1, version),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match receiver.join().unwrap() {
+            Err(Error::Incompatible(version)) => assert_eq!(VERSION, version),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
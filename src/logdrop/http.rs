@@ -0,0 +1,76 @@
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+/// Response to an HTTP request: the status code and the whole body.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+/// Failed HTTP request, telling whether the server could have seen it.
+#[derive(Debug)]
+pub enum Error {
+    /// The connection was not established, so nothing was sent.
+    Connect(io::Error),
+    /// The request was, at least partially, sent before the failure.
+    Transfer(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Connect(ref err) => write!(f, "unable to connect: {}", err),
+            Error::Transfer(ref err) => write!(f, "transfer failed: {}", err),
+        }
+    }
+}
+
+/// Performs a single HTTP/1.0 request over the connection freshly established to the given
+/// `host:port` address, closing it afterwards.
+///
+/// This is intentionally minimal: no keep-alive, no chunked encoding, no redirects, just what
+/// is required to talk with Elasticsearch-like JSON APIs.
+pub fn request(stream: TcpStream, host: &str, method: &str, path: &str, body: &[u8]) -> Result<Response, Error> {
+    exchange(stream, host, method, path, body).map_err(Error::Transfer)
+}
+
+fn exchange(mut stream: TcpStream, addr: &str, method: &str, path: &str, body: &[u8]) -> io::Result<Response> {
+    try!(write!(stream, "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        method, path, addr, body.len()));
+    try!(stream.write_all(body));
+    try!(stream.flush());
+
+    let mut rd = BufReader::new(stream);
+    let mut line = String::new();
+    try!(rd.read_line(&mut line));
+
+    let status = match line.split(' ').nth(1).and_then(|status| status.parse().ok()) {
+        Some(status) => status,
+        None => return Err(io::Error::new(io::ErrorKind::Other, "malformed HTTP status line")),
+    };
+
+    loop {
+        line.clear();
+        try!(rd.read_line(&mut line));
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut body = String::new();
+    try!(rd.read_to_string(&mut body));
+
+    Ok(Response {
+        status: status,
+        body: body,
+    })
+}
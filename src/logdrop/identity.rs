@@ -0,0 +1,281 @@
+//! Identity of the running instance and provenance of records passing through instances.
+//!
+//! The instance id is generated once and kept in the state directory, so that it survives
+//! restarts, while the config hash changes with every revision of the config. Together with the
+//! build version they tell which instance running which config has touched a record.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+
+use chrono;
+use chrono::Timelike;
+use libc;
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error as ConfigError, FromConfig};
+use super::digest;
+use super::metrics;
+use super::metrics::Counter;
+use super::version;
+
+/// Name of the file the instance id is kept in within the state directory.
+const FILE: &'static str = "instance.id";
+
+/// Field holding the provenance of a record, see `Provenance`.
+pub const FIELD: &'static str = "_provenance";
+
+/// Where the instance id is kept, if anywhere.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// Durable state directory, without which the id is generated anew on every start.
+    pub dir: Option<PathBuf>,
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, ConfigError> {
+        let dir = match config.find("dir") {
+            Some(..) => Some(PathBuf::from(try!(config.string("dir")))),
+            None => None,
+        };
+
+        Ok(Settings {
+            dir: dir,
+        })
+    }
+}
+
+/// Stable instance id together with the hash of the config it runs and its build version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub instance: String,
+    /// Leading hex digits of the SHA-256 digest of the config content.
+    pub config: String,
+    pub version: String,
+}
+
+impl Identity {
+    pub fn new(instance: &str, config: &str) -> Identity {
+        Identity {
+            instance: instance.to_string(),
+            config: digest::hex(&digest::sha256(config.as_bytes())[..6]),
+            version: version::VERSION.to_string(),
+        }
+    }
+
+    /// Loads the instance id from the state directory, generating and saving it there if it's
+    /// missing, or generates the one for this run only without the directory.
+    pub fn load(settings: &Settings, config: &str) -> io::Result<Identity> {
+        let dir = match settings.dir {
+            Some(ref dir) => dir,
+            None => return Ok(Identity::new(&generate(), config)),
+        };
+
+        let path = dir.join(FILE);
+        let mut instance = String::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                try!(file.read_to_string(&mut instance));
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let instance = instance.trim().to_string();
+        if !instance.is_empty() {
+            return Ok(Identity::new(&instance, config));
+        }
+
+        let instance = generate();
+        try!(fs::create_dir_all(dir));
+        let temporary = dir.join(format!("{}.tmp", FILE));
+        {
+            let mut file = try!(File::create(&temporary));
+            try!(file.write_all(instance.as_bytes()));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&temporary, &path));
+
+        info!(target: "Identity", "generated instance id {} in {:?}", instance, dir);
+        Ok(Identity::new(&instance, config))
+    }
+
+    /// Returns the compact stamp, e.g. `3f2a9c0d41b7e655/9b1d04c2e8aa/0.1.0`.
+    pub fn stamp(&self) -> String {
+        format!("{}/{}/{}", self.instance, self.config, self.version)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instance {}, config {}, version {}", self.instance, self.config, self.version)
+    }
+}
+
+/// Generates the random instance id out of the current time and the process id.
+fn generate() -> String {
+    let now = chrono::UTC::now();
+    let seed = format!("{}.{}-{}", now.timestamp(), now.nanosecond(), unsafe { libc::getpid() });
+    digest::hex(&digest::sha256(seed.as_bytes())[..8])
+}
+
+fn slot() -> &'static Mutex<Option<Arc<Identity>>> {
+    static mut SLOT: *const Mutex<Option<Arc<Identity>>> = 0 as *const Mutex<Option<Arc<Identity>>>;
+    static INIT: Once = ONCE_INIT;
+
+    unsafe {
+        INIT.call_once(|| {
+            SLOT = mem::transmute(Box::new(Mutex::new(None::<Arc<Identity>>)));
+        });
+
+        &*SLOT
+    }
+}
+
+/// Makes the identity the one of the process, exposing it in the
+/// `instance.<id>.config.<hash>` metric.
+pub fn install(identity: Identity) {
+    metrics::registry().counter(&format!("instance.{}.config.{}", identity.instance, identity.config)).inc();
+    *slot().lock().unwrap() = Some(Arc::new(identity));
+}
+
+/// Returns the identity of the process, generating the one for this run only if none has been
+/// installed, e.g. in tests.
+pub fn current() -> Arc<Identity> {
+    let mut slot = slot().lock().unwrap();
+    if slot.is_none() {
+        *slot = Some(Arc::new(Identity::new(&generate(), "")));
+    }
+    slot.as_ref().unwrap().clone()
+}
+
+/// Appends forwarding hops to the `_provenance` array of records an instance receives, each
+/// hop carrying the stamp of the instance and the time it has received the record at in seconds.
+/// The first hop is the one of the edge instance the record has entered through.
+///
+/// Hops are appended by the receiving side, so a sender can't pass a record off as having gone
+/// through another instance without that instance appending its own hop. A field of any other
+/// type is replaced. Past the hop limit the edge hop is kept while the oldest of the following
+/// ones is dropped, which is counted in the `pipeline.provenance.capped` metric.
+#[derive(Clone)]
+pub struct Provenance {
+    /// Fixed identity, the one of the process at the time of stamping if `None`.
+    identity: Option<Arc<Identity>>,
+    hops: usize,
+    clock: Arc<Clock>,
+    capped: Counter,
+}
+
+impl Provenance {
+    pub fn new(identity: Arc<Identity>, hops: usize) -> Provenance {
+        Provenance {
+            identity: Some(identity),
+            .. Provenance::current(hops)
+        }
+    }
+
+    /// Stamps records with the identity of the process, see `current`, so that records received
+    /// once the config is reloaded carry the hash of the reloaded one.
+    pub fn current(hops: usize) -> Provenance {
+        Provenance {
+            identity: None,
+            hops: hops,
+            clock: Arc::new(SystemClock),
+            capped: metrics::registry().counter("pipeline.provenance.capped"),
+        }
+    }
+
+    pub fn clock(mut self, clock: Arc<Clock>) -> Provenance {
+        self.clock = clock;
+        self
+    }
+
+    pub fn stamp(&self, record: &mut Record) {
+        let stamp = match self.identity {
+            Some(ref identity) => identity.stamp(),
+            None => current().stamp(),
+        };
+
+        let mut hop = HashMap::new();
+        hop.insert("instance".to_string(), RecordItem::String(stamp));
+        hop.insert("received".to_string(), RecordItem::F64(self.clock.now() as f64 / 1000.0));
+
+        let mut hops = match record.remove(FIELD) {
+            Some(RecordItem::Array(hops)) => hops,
+            _ => Vec::new(),
+        };
+        while !hops.is_empty() && hops.len() >= self.hops {
+            let oldest = if hops.len() > 1 { 1 } else { 0 };
+            hops.remove(oldest);
+            self.capped.inc();
+        }
+        hops.push(RecordItem::Object(hop));
+
+        record.insert(FIELD.to_string(), RecordItem::Array(hops));
+    }
+}
+
+/// Reads the maximum number of `max_hops` kept, stamping records with the process identity.
+impl FromConfig for Provenance {
+    fn from_config(config: &Config) -> Result<Provenance, ConfigError> {
+        let hops = try!(config.u64_or("max_hops", 8));
+        if hops == 0 {
+            return Err(ConfigError::Invalid("'max_hops' must be positive".to_string()));
+        }
+
+        Ok(Provenance::current(hops as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use super::super::{Record, RecordItem};
+    use super::{FIELD, Identity, Provenance, Settings, current, install};
+
+    #[test]
+    fn keep_instance_id_across_restarts() {
+        let dir = env::temp_dir().join("logdrop-identity-restart");
+        let _ = fs::remove_dir_all(&dir);
+        let settings = Settings { dir: Some(dir.clone()) };
+
+        let first = Identity::load(&settings, "{\"outputs\": []}").unwrap();
+        let restarted = Identity::load(&settings, "{\"outputs\": [{\"type\": \"null\"}]}").unwrap();
+        assert_eq!(16, first.instance.len());
+        assert_eq!(first.instance, restarted.instance);
+        assert!(first.config != restarted.config);
+
+        let other = Identity::load(&Settings::default(), "{\"outputs\": []}").unwrap();
+        assert!(other.instance != first.instance);
+        assert_eq!(first.config, other.config);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stamp_identity_installed_by_reload() {
+        let provenance = Provenance::current(8);
+        let instance = current().instance.clone();
+
+        install(Identity::new(&instance, "{\"outputs\": [{\"type\": \"reloaded\"}]}"));
+        let mut record = Record::new();
+        provenance.stamp(&mut record);
+
+        let expected = Identity::new(&instance, "{\"outputs\": [{\"type\": \"reloaded\"}]}").stamp();
+        match record.find(FIELD) {
+            Some(&RecordItem::Array(ref hops)) => {
+                assert_eq!(Some(&RecordItem::String(expected)), hops[0].find("instance"));
+            }
+            other => panic!("expected hops, found {:?}", other),
+        }
+    }
+}
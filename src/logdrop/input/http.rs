@@ -0,0 +1,580 @@
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::io;
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::{Input, Sink};
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::codec::Codec;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::naming;
+use super::super::numeric;
+use super::super::sys;
+
+/// Type of access records.
+pub const ACCESS: &'static str = "ingest_access";
+
+/// Default maximum body length in bytes.
+const MAX_BODY: u64 = 1 << 20;
+
+/// Maximum length of the request line and headers together.
+const MAX_HEAD: usize = 8192;
+
+/// Default time in milliseconds a connection may stall reading the request or writing the response.
+const TIMEOUT: u64 = 30000;
+
+/// Default maximum number of connections served at once.
+const MAX_CONNECTIONS: usize = 256;
+
+/// Request as far as it has been handled, described by the access record.
+#[derive(Debug, Default)]
+struct Exchange {
+    path: String,
+    content_type: Option<String>,
+    bytes: usize,
+    records: usize,
+    errors: usize,
+    /// Whether every record of the body is an access record itself.
+    access_only: bool,
+}
+
+/// Accepts records POSTed over HTTP, one connection per request, the body being decoded with the
+/// input codec.
+///
+/// Requests without the `Content-Length` header are answered with 411, the ones with longer
+/// bodies with 413 before reading them and the ones of peers over their hard quota with 429.
+/// Connections stalling for the timeout while sending the request or reading the response are
+/// closed, while ones accepted over the connection limit are answered with 503 right away.
+///
+/// With the access log enabled, every request is described by a record of the `ingest_access`
+/// type sent down the pipeline like any other one: peer, path, content type, body bytes, records
+/// decoded, values failing to decode, status and handling duration in milliseconds. Only one in `sample`
+/// successful requests is logged, while failed ones always are. Requests carrying nothing but
+/// access records are not logged, so that access records sent back to the input never loop.
+pub struct HttpInput {
+    host: String,
+    port: u16,
+    handler: Handler,
+    max_connections: usize,
+    /// Connections being served.
+    active: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl HttpInput {
+    pub fn new(host: String, port: u16) -> HttpInput {
+        HttpInput {
+            host: host,
+            port: port,
+            handler: Handler {
+                max_body: MAX_BODY,
+                timeout: TIMEOUT,
+                sample: None,
+                clock: Arc::new(SystemClock),
+                successes: Arc::new(AtomicUsize::new(0)),
+            },
+            max_connections: MAX_CONNECTIONS,
+            active: Arc::new(AtomicUsize::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Limits request bodies to the given number of bytes.
+    pub fn max_body(mut self, max_body: u64) -> HttpInput {
+        self.handler.max_body = max_body;
+        self
+    }
+
+    /// Closes connections stalling for the given time in milliseconds.
+    pub fn timeout(mut self, timeout: u64) -> HttpInput {
+        self.handler.timeout = timeout;
+        self
+    }
+
+    /// Limits the number of connections served at once.
+    pub fn max_connections(mut self, max_connections: usize) -> HttpInput {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Emits access records for one in `sample` successful requests and all failed ones.
+    pub fn access(mut self, sample: usize) -> HttpInput {
+        self.handler.sample = Some(sample);
+        self
+    }
+}
+
+/// Request handling shared by connections.
+#[derive(Clone)]
+struct Handler {
+    max_body: u64,
+    /// Read and write timeout of connections in milliseconds.
+    timeout: u64,
+    /// Sampling of access records, disabled if `None`.
+    sample: Option<usize>,
+    clock: Arc<Clock>,
+    /// Successful requests so far, for sampling.
+    successes: Arc<AtomicUsize>,
+}
+
+impl Handler {
+    fn serve(&self, stream: TcpStream, tx: Sink, codec: &Codec) {
+        let started = self.clock.now();
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer.ip().to_string(),
+            Err(..) => return,
+        };
+        let tx = tx.with_peer(&peer);
+
+        let timeout = Some(Duration::from_millis(self.timeout));
+        if let Err(err) = stream.set_read_timeout(timeout).and_then(|()| stream.set_write_timeout(timeout)) {
+            warn!(target: "Input::HTTP", "dropping connection of {}: {}", peer, err);
+            return;
+        }
+
+        let mut exchange = Exchange::default();
+        let mut rd = BufReader::new(&stream);
+        let status = self.handle(&mut rd, &tx, codec, &mut exchange);
+
+        // Sent before responding, so that the client sees the request fully handled.
+        if !exchange.access_only && self.sampled(status) {
+            let record = access(&peer, &exchange, status, self.clock.now().saturating_sub(started));
+            if let Err(..) = tx.send(record) {
+                debug!(target: "Input::HTTP", "pipeline is gone, dropping access record");
+            }
+        }
+
+        if let Err(err) = respond(&stream, status) {
+            debug!(target: "Input::HTTP", "unable to respond to {}: {}", peer, err);
+        }
+    }
+
+    /// Handles the request, filling the exchange in as far as it gets, and returns the status.
+    fn handle(&self, rd: &mut BufRead, tx: &Sink, codec: &Codec, exchange: &mut Exchange) -> u16 {
+        let length = match head(rd, exchange) {
+            Ok(length) => length,
+            Err(status) => return status,
+        };
+
+        let length = match length {
+            Some(length) => length,
+            None => return 411,
+        };
+
+        if length > self.max_body {
+            return 413;
+        }
+
+        if tx.is_blocked() {
+            return 429;
+        }
+
+        let mut body = Vec::new();
+        match Read::take(rd, length).read_to_end(&mut body) {
+            Ok(len) if len as u64 == length => {}
+            Ok(..) | Err(..) => return 400,
+        }
+        exchange.bytes = body.len();
+
+        // Codecs unable to tell values apart skip malformed ones they can resynchronize after,
+        // so only a body without any record is known to be malformed.
+        let records: Box<Iterator<Item=Result<Record, ()>>> = match codec.decode_checked(Box::new(Cursor::new(body))) {
+            Ok(values) => Box::new(values.map(|value| value.map_err(|_| ()))),
+            Err(rd) => Box::new(codec.decode(rd).map(Ok)),
+        };
+
+        let mut access_only = true;
+        for record in records {
+            let record = match record {
+                Ok(record) => record,
+                Err(()) => {
+                    exchange.errors += 1;
+                    continue;
+                }
+            };
+
+            access_only &= record.find("type").and_then(|ty| ty.as_str()) == Some(ACCESS);
+            exchange.records += 1;
+            if let Err(..) = tx.send(record) {
+                return 503;
+            }
+        }
+        exchange.access_only = access_only && exchange.records > 0 && exchange.errors == 0;
+
+        if exchange.records == 0 && exchange.bytes > 0 {
+            exchange.errors = cmp::max(exchange.errors, 1);
+            return 400;
+        }
+
+        200
+    }
+
+    fn sampled(&self, status: u16) -> bool {
+        match self.sample {
+            Some(..) if status >= 400 => true,
+            Some(sample) => self.successes.fetch_add(1, Ordering::SeqCst) % sample == 0,
+            None => false,
+        }
+    }
+}
+
+/// Reads the request line and headers, returning the body length if declared.
+fn head(rd: &mut BufRead, exchange: &mut Exchange) -> Result<Option<u64>, u16> {
+    let mut consumed = 0;
+    let mut line = String::new();
+    let mut next = |rd: &mut BufRead, line: &mut String| -> Result<(), u16> {
+        line.clear();
+        match Read::take(rd, (MAX_HEAD - consumed) as u64).read_line(line) {
+            Ok(0) => Err(400),
+            Ok(len) if !line.ends_with("\n") => Err(if consumed + len >= MAX_HEAD { 431 } else { 400 }),
+            Ok(len) => {
+                consumed += len;
+                Ok(())
+            }
+            Err(..) => Err(400),
+        }
+    };
+
+    try!(next(&mut *rd, &mut line));
+    let (method, path) = {
+        let mut parts = line.trim_right().split(' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), Some(..)) => (method.to_string(), path.to_string()),
+            _ => return Err(400),
+        }
+    };
+    exchange.path = path;
+
+    let mut length = None;
+    loop {
+        try!(next(&mut *rd, &mut line));
+        let header = line.trim_right();
+        if header.is_empty() {
+            break;
+        }
+
+        let mut parts = header.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => return Err(400),
+        };
+
+        if name.eq_ignore_ascii_case("content-length") {
+            match value.parse::<u64>() {
+                Ok(value) => length = Some(value),
+                Err(..) => return Err(400),
+            }
+        } else if name.eq_ignore_ascii_case("content-type") {
+            exchange.content_type = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // Chunked bodies are not supported, the length must be declared.
+            return Err(411);
+        }
+    }
+
+    if method != "POST" {
+        return Err(405);
+    }
+
+    Ok(length)
+}
+
+fn respond(mut stream: &TcpStream, status: u16) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        _ => "Service Unavailable",
+    };
+
+    try!(write!(stream, "HTTP/1.0 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status, reason));
+    stream.flush()
+}
+
+fn access(peer: &str, exchange: &Exchange, status: u16, duration: u64) -> Record {
+    let mut record = Record::new();
+    record.insert("type".to_string(), RecordItem::String(ACCESS.to_string()));
+    record.insert("peer".to_string(), RecordItem::String(peer.to_string()));
+    record.insert("path".to_string(), RecordItem::String(exchange.path.clone()));
+    record.insert("content_type".to_string(), match exchange.content_type {
+        Some(ref content_type) => RecordItem::String(content_type.clone()),
+        None => RecordItem::Null,
+    });
+    record.insert("bytes".to_string(), numeric::integer(exchange.bytes as u64));
+    record.insert("records".to_string(), numeric::integer(exchange.records as u64));
+    record.insert("errors".to_string(), numeric::integer(exchange.errors as u64));
+    record.insert("status".to_string(), RecordItem::I64(status as i64));
+    record.insert("duration_ms".to_string(), numeric::integer(duration));
+    record
+}
+
+impl FromConfig for HttpInput {
+    fn from_config(config: &Config) -> Result<HttpInput, Error> {
+        let host = try!(config.string_or("host", "::"));
+        let port = try!(config.u64_or("port", 8080));
+        let max_body = try!(config.u64_or("max_body", MAX_BODY));
+        let timeout = try!(config.u64_or("timeout", TIMEOUT));
+        let max_connections = try!(config.u64_or("max_connections", MAX_CONNECTIONS as u64));
+        if timeout == 0 || max_connections == 0 {
+            return Err(Error::Invalid("'timeout' and 'max_connections' must be positive".to_string()));
+        }
+
+        let input = HttpInput::new(host, port as u16)
+            .max_body(max_body)
+            .timeout(timeout)
+            .max_connections(max_connections as usize);
+
+        if !try!(config.bool_or("access_log", false)) {
+            return Ok(input);
+        }
+
+        match try!(config.u64_or("access_sample", 1)) {
+            0 => Err(Error::Invalid("'access_sample' must be positive".to_string())),
+            sample => Ok(input.access(sample as usize)),
+        }
+    }
+}
+
+impl Input for HttpInput {
+    fn check(&self) -> Result<(), String> {
+        let host: &str = &self.host;
+        match TcpListener::bind((host, self.port)) {
+            Ok(..) => Ok(()),
+            Err(err) => Err(format!("unable to bind [{}]:{}: {}", self.host, self.port, err)),
+        }
+    }
+
+    fn label(&self) -> Option<String> {
+        Some(self.port.to_string())
+    }
+
+    fn run(&self, tx: Sink, codec: Box<Codec>) {
+        const POLL: u32 = 50;
+
+        info!(target: "Input::HTTP", "running HTTP listener at [{}]:{}", self.host, self.port);
+
+        let host: &str = &self.host;
+        let listener = match TcpListener::bind((host, self.port)) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!(target: "Input::HTTP", "unable to bind: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = sys::set_nonblocking(listener.as_raw_fd(), true) {
+            error!(target: "Input::HTTP", "unable to poll listening socket: {}", err);
+            return;
+        }
+
+        let handler = Arc::new(self.handler.clone());
+        while !self.stopped.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(err) = sys::set_nonblocking(stream.as_raw_fd(), false) {
+                        warn!(target: "Input::HTTP", "dropping accepted connection: {}", err);
+                        continue;
+                    }
+
+                    if self.active.load(Ordering::SeqCst) >= self.max_connections {
+                        debug!(target: "Input::HTTP", "refusing connection of {}: {} connections are served", peer, self.max_connections);
+                        let timeout = Some(Duration::from_millis(handler.timeout));
+                        let _ = stream.set_write_timeout(timeout).and_then(|()| respond(&stream, 503));
+                        continue;
+                    }
+
+                    self.active.fetch_add(1, Ordering::SeqCst);
+                    let active = self.active.clone();
+                    let handler = handler.clone();
+                    let tx = tx.clone();
+                    let codec = codec.new();
+                    naming::spawn(&format!("conn-{}", peer), move || {
+                        handler.serve(stream, tx, &*codec);
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => thread::sleep_ms(POLL),
+                Err(err) => {
+                    warn!(target: "Input::HTTP", "error occured while accepting connection: {}", err);
+                }
+            }
+        }
+
+        info!(target: "Input::HTTP", "HTTP listener has been stopped");
+    }
+
+    fn stop(&self, _: u32) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    use super::super::super::{Envelope, Origin, Record, RecordItem};
+    use super::super::super::codec::Json;
+    use super::super::super::numeric::NonFinite;
+    use super::super::super::testing::{record, string};
+    use super::super::super::validation::Validation;
+    use super::super::{Input, Sink};
+    use super::{ACCESS, HttpInput};
+
+    fn start(input: HttpInput) -> (Arc<HttpInput>, u16, Receiver<Envelope>) {
+        // Nobody listens on the port once the listener is dropped.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let input = Arc::new(HttpInput { port: port, ..input });
+
+        let (tx, rx) = channel();
+        let origin = Origin {
+            input: "http".to_string(),
+            validation: Arc::new(Validation::default()),
+            capture: None,
+            projection: None,
+            encoding: None,
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+        let sink = Sink::new(tx, Arc::new(origin));
+        let running = input.clone();
+        thread::spawn(move || running.run(sink, Box::new(Json::default())));
+        thread::sleep_ms(100);
+
+        (input, port, rx)
+    }
+
+    /// Sends the request, returning the response status once the connection is closed.
+    fn post(port: u16, head: &str, body: &[u8]) -> u16 {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        let _ = stream.write_all(body);
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.split(' ').nth(1).unwrap().parse().unwrap()
+    }
+
+    fn request(body: &str) -> String {
+        format!("POST /ingest HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    /// Splits records received so far into regular and access ones.
+    fn received(rx: &Receiver<Envelope>) -> (Vec<Record>, Vec<Record>) {
+        let mut records = Vec::new();
+        while let Ok(envelope) = rx.try_recv() {
+            records.push(envelope.record);
+        }
+        records.into_iter().partition(|record| record.find("type").and_then(|ty| ty.as_str()) != Some(ACCESS))
+    }
+
+    fn field(record: &Record, key: &str) -> RecordItem {
+        record.find(key).cloned().unwrap_or(RecordItem::Null)
+    }
+
+    #[test]
+    fn log_access_of_every_request() {
+        let (input, port, rx) = start(HttpInput::new("127.0.0.1".to_string(), 0).max_body(64).access(1));
+
+        assert_eq!(200, post(port, &request(r#"{"seq": 1} {"seq": 2}"#), b""));
+        let (records, access) = received(&rx);
+        assert_eq!(2, records.len());
+        assert_eq!(1, access.len());
+        assert_eq!(RecordItem::String("127.0.0.1".to_string()), field(&access[0], "peer"));
+        assert_eq!(RecordItem::String("/ingest".to_string()), field(&access[0], "path"));
+        assert_eq!(RecordItem::String("application/json".to_string()), field(&access[0], "content_type"));
+        assert_eq!(RecordItem::I64(21), field(&access[0], "bytes"));
+        assert_eq!(RecordItem::I64(2), field(&access[0], "records"));
+        assert_eq!(RecordItem::I64(0), field(&access[0], "errors"));
+        assert_eq!(RecordItem::I64(200), field(&access[0], "status"));
+        assert!(field(&access[0], "duration_ms").as_f64().is_some());
+
+        // Rejected before reading the body.
+        let head = "POST /ingest HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 1000\r\n\r\n";
+        assert_eq!(413, post(port, head, b""));
+        let (records, access) = received(&rx);
+        assert!(records.is_empty());
+        assert_eq!(RecordItem::I64(413), field(&access[0], "status"));
+        assert_eq!(RecordItem::String("/ingest".to_string()), field(&access[0], "path"));
+        assert_eq!(RecordItem::I64(0), field(&access[0], "bytes"));
+
+        assert_eq!(400, post(port, &request("[1, 2, 3]"), b""));
+        let (records, access) = received(&rx);
+        assert!(records.is_empty());
+        assert_eq!(RecordItem::I64(400), field(&access[0], "status"));
+        assert_eq!(RecordItem::I64(9), field(&access[0], "bytes"));
+        assert_eq!(RecordItem::I64(0), field(&access[0], "records"));
+        assert_eq!(RecordItem::I64(1), field(&access[0], "errors"));
+
+        // Values failing to decode are counted while the rest is accepted.
+        assert_eq!(200, post(port, &request(r#"{"seq": 1} [1] {"seq": 2}"#), b""));
+        let (records, access) = received(&rx);
+        assert_eq!(2, records.len());
+        assert_eq!(RecordItem::I64(2), field(&access[0], "records"));
+        assert_eq!(RecordItem::I64(1), field(&access[0], "errors"));
+
+        // Access records sent back to the input are delivered without being logged again.
+        assert_eq!(200, post(port, &request(r#"{"type": "ingest_access", "status": 200}"#), b""));
+        let (records, access) = received(&rx);
+        assert!(records.is_empty());
+        assert_eq!(vec![record(vec![("type", string(ACCESS)), ("status", RecordItem::I64(200))])], access);
+
+        input.stop(0);
+    }
+
+    #[test]
+    fn sample_successful_requests_only() {
+        let (input, port, rx) = start(HttpInput::new("127.0.0.1".to_string(), 0).max_body(64).access(3));
+
+        for seq in 0..6 {
+            assert_eq!(200, post(port, &request(&format!(r#"{{"seq": {}}}"#, seq)), b""));
+        }
+        assert_eq!(400, post(port, &request("garbage"), b""));
+        assert_eq!(400, post(port, &request("garbage"), b""));
+
+        let (records, access) = received(&rx);
+        assert_eq!(6, records.len());
+        let statuses: Vec<RecordItem> = access.iter().map(|record| field(record, "status")).collect();
+        assert_eq!(vec![RecordItem::I64(200), RecordItem::I64(200), RecordItem::I64(400), RecordItem::I64(400)], statuses);
+
+        input.stop(0);
+    }
+
+    /// Connects without sending anything, returning the response once the connection is closed.
+    fn stall(port: u16) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn cap_connections_and_close_stalled_ones() {
+        let (input, port, rx) = start(HttpInput::new("127.0.0.1".to_string(), 0).timeout(300).max_connections(1));
+
+        let stalled = thread::spawn(move || stall(port));
+        thread::sleep_ms(100);
+        assert!(stall(port).starts_with("HTTP/1.0 503 "));
+        assert!(stalled.join().unwrap().starts_with("HTTP/1.0 400 "));
+
+        thread::sleep_ms(100);
+        assert_eq!(200, post(port, &request(r#"{"seq": 1}"#), b""));
+        assert_eq!(1, received(&rx).0.len());
+
+        input.stop(0);
+    }
+}
@@ -0,0 +1,798 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, SendError};
+use std::thread;
+
+use super::{Input, Sink};
+use super::super::{Envelope, Record, RecordItem};
+use super::super::ack::{Ack, Tracker};
+use super::super::clock::{Clock, SystemClock};
+use super::super::codec::Codec;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::glob::Glob;
+use super::super::numeric;
+use super::super::registry;
+use super::super::spool::{Reader, Writer};
+
+/// Default number of records between progress saves within a file.
+const CHECKPOINT: u64 = 1000;
+
+/// Files matching the pattern and the codec they are decoded with.
+pub struct Rule {
+    /// Path glob over paths relative to the root, see `Glob::path`.
+    pattern: Glob,
+    /// Codec of the rule, the input one if `None`.
+    codec: Option<Box<Codec>>,
+    /// Field the path of the file relative to the root is written to, if any.
+    origin: Option<String>,
+}
+
+impl Rule {
+    pub fn new(pattern: Glob, codec: Option<Box<Codec>>, origin: Option<&str>) -> Rule {
+        Rule {
+            pattern: pattern,
+            codec: codec,
+            origin: origin.map(|origin| origin.to_string()),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.pattern.matches(path)
+    }
+}
+
+/// Migration progress of a single file.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Entry {
+    /// Inode of the file the records are counted in, telling a replaced file apart.
+    inode: u64,
+    /// Records delivered so far, skipped when resuming.
+    records: u64,
+    done: bool,
+    /// Reason of the last failure, if any.
+    error: Option<String>,
+}
+
+/// Outcome of the migration, accumulated across resumes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Number of files migrated completely.
+    pub files: usize,
+    /// Records delivered from all files.
+    pub records: u64,
+    /// Failures by the path of the file, for files left incomplete.
+    pub errors: Vec<(String, String)>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} files done, {} records, {} files failed", self.files, self.records, self.errors.len())
+    }
+}
+
+/// Settings of the migration, see `MigrationInput`.
+pub struct Settings {
+    pub root: PathBuf,
+    pub rules: Vec<Rule>,
+    /// File the progress is kept in.
+    pub progress: PathBuf,
+    /// Maximum records per second, unlimited if `None`.
+    pub rate: Option<u64>,
+    /// Number of records between progress saves within a file.
+    pub checkpoint: u64,
+    /// Field the cursor of each record is written to, if any.
+    pub cursor: Option<String>,
+    /// Whether progress only counts records outputs have confirmed, see `ack`.
+    pub acknowledged: bool,
+}
+
+/// Migration of the tree of files, resumed from the progress file.
+pub struct Migration<'a> {
+    settings: &'a Settings,
+    clock: Arc<Clock>,
+    progress: HashMap<String, Entry>,
+    /// Files of the current listing left to migrate, with the index of their rule.
+    queue: VecDeque<(String, usize)>,
+    /// Files attempted by this run, not retried until the next one.
+    attempted: HashSet<String>,
+    /// Records delivered by this run and the time it started at, for pacing.
+    sent: u64,
+    started: u64,
+    /// Set once the migration is to stop, checked before each record.
+    stopped: Arc<AtomicBool>,
+}
+
+impl<'a> Migration<'a> {
+    pub fn open(settings: &'a Settings, clock: Arc<Clock>) -> Migration<'a> {
+        let mut progress = HashMap::new();
+        match Reader::open(&settings.progress).and_then(|mut rd| rd.next()) {
+            Ok(Some(record)) => {
+                if let Some(&RecordItem::Object(ref files)) = record.find("files") {
+                    for (path, entry) in files.iter() {
+                        progress.insert(path.clone(), restore(entry));
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!(target: "Input::Migration", "unable to restore progress from {:?}: {}", settings.progress, err),
+        }
+
+        let started = clock.now();
+        Migration {
+            settings: settings,
+            clock: clock,
+            progress: progress,
+            queue: VecDeque::new(),
+            attempted: HashSet::new(),
+            sent: 0,
+            started: started,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stops the migration before the next record once the flag is set, saving the progress.
+    pub fn with_stop(mut self, stopped: Arc<AtomicBool>) -> Migration<'a> {
+        self.stopped = stopped;
+        self
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Returns files left to migrate with the index of their rule, in path order.
+    fn pending(&self) -> io::Result<Vec<(String, usize)>> {
+        let mut files = Vec::new();
+        try!(walk(&self.settings.root, &mut files));
+
+        let root = self.settings.root.to_string_lossy().into_owned();
+        let mut pending: Vec<(String, usize)> = files.into_iter().filter_map(|path| {
+            let path = path.to_string_lossy().into_owned();
+            let relative = path[root.len()..].trim_left_matches('/').to_string();
+            match self.settings.rules.iter().position(|rule| rule.matches(&relative)) {
+                Some(rule) => Some((relative, rule)),
+                None => None,
+            }
+        }).filter(|&(ref path, _)| {
+            !self.attempted.contains(path) && !self.progress.get(path).map(|entry| entry.done).unwrap_or(false)
+        }).collect();
+
+        pending.sort();
+        Ok(pending)
+    }
+
+    /// Migrates the next pending file, returning its path, or `None` once nothing is left.
+    ///
+    /// The tree is listed anew once all files of the previous listing are migrated, so that
+    /// files appearing meanwhile are picked up at the end.
+    pub fn step(&mut self, sink: &Sink, codec: &Codec) -> Result<Option<String>, SendError<Envelope>> {
+        if self.queue.is_empty() {
+            match self.pending() {
+                Ok(pending) => self.queue.extend(pending.into_iter()),
+                Err(err) => {
+                    warn!(target: "Input::Migration", "unable to list {:?}: {}", self.settings.root, err);
+                    return Ok(None);
+                }
+            }
+        }
+
+        let (path, rule) = match self.queue.pop_front() {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+
+        self.attempted.insert(path.clone());
+        let settings = self.settings;
+        let rule = &settings.rules[rule];
+        let codec = match rule.codec {
+            Some(ref codec) => &**codec,
+            None => codec,
+        };
+
+        let result = self.migrate(&path, rule, codec, sink);
+        self.save();
+        result.map(|()| Some(path))
+    }
+
+    fn migrate(&mut self, path: &str, rule: &Rule, codec: &Codec, sink: &Sink) -> Result<(), SendError<Envelope>> {
+        let file = match File::open(self.settings.root.join(path)) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!(target: "Input::Migration", "unable to open '{}': {}", path, err);
+                self.progress.entry(path.to_string()).or_insert(Entry::default()).error = Some(err.to_string());
+                return Ok(());
+            }
+        };
+
+        let inode = match file.metadata() {
+            Ok(metadata) => metadata.ino(),
+            Err(err) => {
+                warn!(target: "Input::Migration", "unable to stat '{}': {}", path, err);
+                self.progress.entry(path.to_string()).or_insert(Entry::default()).error = Some(err.to_string());
+                return Ok(());
+            }
+        };
+
+        let skipped = match self.progress.get(path) {
+            Some(entry) if entry.records > 0 && entry.inode != inode => {
+                warn!(target: "Input::Migration", "'{}' has been replaced, migrating it from the start", path);
+                0
+            }
+            Some(entry) => entry.records,
+            None => 0,
+        };
+        if skipped > 0 {
+            info!(target: "Input::Migration", "resuming '{}' after {} records", path, skipped);
+        }
+
+        let rd = Box::new(BufReader::new(file));
+        let records = match sink.origin().projection {
+            Some(ref projection) => codec.decode_projected(rd, projection.clone()),
+            None => codec.decode(rd),
+        };
+
+        // Progress only ever counts records the pipeline has taken, or outputs have confirmed if
+        // acknowledged, so a crash before a save delivers at most the records since the previous
+        // one again, with the same cursors.
+        let (outcomes, rx) = channel();
+        let mut tracker = Tracker::new(rx, skipped);
+        let mut delivered = skipped;
+        let mut interrupted = false;
+        for mut record in records.skip(skipped as usize) {
+            if self.is_stopped() {
+                interrupted = true;
+                break;
+            }
+            if let Some(ref origin) = rule.origin {
+                record.insert(origin.clone(), RecordItem::String(path.to_string()));
+            }
+            if let Some(ref cursor) = self.settings.cursor {
+                record.insert(cursor.clone(), RecordItem::String(format!("{}:{}", inode, delivered)));
+            }
+            if self.settings.acknowledged {
+                try!(sink.send_acked(record, Ack::new(delivered, outcomes.clone())));
+            } else {
+                try!(sink.send(record));
+            }
+            delivered += 1;
+            self.pace();
+
+            if (delivered - skipped) % self.settings.checkpoint == 0 {
+                let records = if self.settings.acknowledged { tracker.poll() } else { delivered };
+                self.progress.insert(path.to_string(), Entry { inode: inode, records: records, done: false, error: None });
+                self.save();
+            }
+        }
+        drop(outcomes);
+
+        // Stopped within the file, which is resumed after the records delivered, or confirmed so
+        // far if acknowledged, by the next run.
+        if interrupted {
+            let records = if self.settings.acknowledged { tracker.poll() } else { delivered };
+            info!(target: "Input::Migration", "stopped '{}' after {} records", path, records);
+            self.progress.insert(path.to_string(), Entry { inode: inode, records: records, done: false, error: None });
+            return Ok(());
+        }
+
+        // The file is done once outputs have confirmed all its records, being left to the next
+        // run after the last confirmed one otherwise.
+        let entry = if self.settings.acknowledged {
+            let confirmed = tracker.wait(delivered);
+            let error = tracker.failed().map(|id| format!("record {} is not delivered", id));
+            Entry { inode: inode, records: confirmed, done: error.is_none(), error: error }
+        } else {
+            Entry { inode: inode, records: delivered, done: true, error: None }
+        };
+        self.progress.insert(path.to_string(), entry);
+        Ok(())
+    }
+
+    /// Sleeps while records are sent faster than the rate allows.
+    fn pace(&mut self) {
+        self.sent += 1;
+        if let Some(rate) = self.settings.rate {
+            let due = self.started + self.sent * 1000 / rate;
+            let now = self.clock.now();
+            if due > now {
+                thread::sleep_ms((due - now) as u32);
+            }
+        }
+    }
+
+    /// Migrates all pending files, returning the summary of the whole migration.
+    pub fn run(&mut self, sink: &Sink, codec: &Codec) -> Result<Summary, SendError<Envelope>> {
+        while !self.is_stopped() {
+            match try!(self.step(sink, codec)) {
+                Some(path) => debug!(target: "Input::Migration", "migrated '{}'", path),
+                None => break,
+            }
+        }
+
+        Ok(self.summary())
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary::default();
+        for (path, entry) in self.progress.iter() {
+            summary.records += entry.records;
+            if entry.done {
+                summary.files += 1;
+            } else if let Some(ref error) = entry.error {
+                summary.errors.push((path.clone(), error.clone()));
+            }
+        }
+
+        summary.errors.sort();
+        summary
+    }
+
+    /// Writes the progress aside and renames it over the previous one, so that a crash leaves
+    /// either of them intact.
+    fn save(&self) {
+        let files = self.progress.iter().map(|(path, entry)| {
+            let mut item = HashMap::new();
+            item.insert("inode".to_string(), numeric::integer(entry.inode));
+            item.insert("records".to_string(), numeric::integer(entry.records));
+            item.insert("done".to_string(), RecordItem::Bool(entry.done));
+            if let Some(ref error) = entry.error {
+                item.insert("error".to_string(), RecordItem::String(error.clone()));
+            }
+            (path.clone(), RecordItem::Object(item))
+        }).collect();
+
+        let mut record = Record::new();
+        record.insert("files".to_string(), RecordItem::Object(files));
+
+        let temporary = self.settings.progress.with_extension("tmp");
+        let result = Writer::create(&temporary)
+            .and_then(|mut wr| wr.append(&record).and_then(|_| wr.sync()))
+            .and_then(|()| fs::rename(&temporary, &self.settings.progress));
+
+        if let Err(err) = result {
+            warn!(target: "Input::Migration", "unable to save progress to {:?}: {}", self.settings.progress, err);
+        }
+    }
+}
+
+fn restore(entry: &RecordItem) -> Entry {
+    let number = |key: &str| {
+        match entry.find(key) {
+            Some(&RecordItem::U64(value)) => value,
+            Some(value) => value.as_f64().map(|value| numeric::f64_to_u64(value).value()).unwrap_or(0),
+            None => 0,
+        }
+    };
+
+    Entry {
+        inode: number("inode"),
+        records: number("records"),
+        done: match entry.find("done") {
+            Some(&RecordItem::Bool(done)) => done,
+            _ => false,
+        },
+        error: entry.find("error").and_then(|error| error.as_str()).map(|error| error.to_string()),
+    }
+}
+
+/// Collects all files within the directory, recursively.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if try!(fs::metadata(&path)).is_dir() {
+            try!(walk(&path, files));
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Migrates historical files under the root directory into the pipeline once, then stays idle.
+///
+/// Each file is decoded with the codec of the first rule matching its path, or skipped if none
+/// does, files being taken in path order. The progress file records how many records of each
+/// file are delivered, so that an interrupted migration resumes at the file and record it
+/// stopped at. Progress is saved after each file and every `checkpoint` records within one,
+/// records delivered since the last save being delivered again after a crash. Stopping the input
+/// saves the progress at the record it stopped at, so that shutdown doesn't wait for the file to
+/// finish. A file replaced meanwhile, i.e. having another inode, is migrated from the start.
+///
+/// With the `cursor_field` set, each record carries its cursor `<inode>:<index>`, the index of the
+/// record within the file. Records delivered again carry the same cursors, so an idempotency id
+/// based deduplication downstream, see `Dedup`, drops them.
+///
+/// With `acknowledged` set, progress only advances past records outputs have confirmed, each file
+/// being done once all its records are. A file with a record outputs have failed is left to the
+/// next run, resuming right after the last record confirmed before it.
+///
+/// Records the codec skips as malformed are not counted, while files that can't be read are
+/// reported in the summary logged at the end and retried by the next run.
+pub struct MigrationInput {
+    settings: Settings,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MigrationInput {
+    pub fn new(settings: Settings) -> MigrationInput {
+        MigrationInput {
+            settings: settings,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl FromConfig for MigrationInput {
+    fn from_config(config: &Config) -> Result<MigrationInput, Error> {
+        let root = PathBuf::from(try!(config.string("root")));
+        let progress = PathBuf::from(try!(config.string("progress")));
+
+        let rate = match try!(config.u64_or("rate", 0)) {
+            0 => None,
+            rate => Some(rate),
+        };
+
+        let checkpoint = try!(config.u64_or("checkpoint", CHECKPOINT));
+        if checkpoint == 0 {
+            return Err(Error::Invalid("'checkpoint' must be positive".to_string()));
+        }
+
+        let cursor = match config.find("cursor_field") {
+            Some(..) => Some(try!(config.string("cursor_field"))),
+            None => None,
+        };
+        let acknowledged = try!(config.bool_or("acknowledged", false));
+
+        let mut rules = Vec::new();
+        for rule in try!(config.list("rules")).iter() {
+            let codec = match rule.find("codec") {
+                Some(codec) => Some(try!(registry::codec(&codec))),
+                None => None,
+            };
+            let origin = match rule.find("origin_field") {
+                Some(..) => Some(try!(rule.string("origin_field"))),
+                None => None,
+            };
+            let pattern = try!(rule.string("pattern"));
+            let glob = try!(Glob::path(&pattern).map_err(|err| {
+                Error::Invalid(format!("invalid pattern '{}': {}", pattern, err))
+            }));
+            let glob = glob.case_insensitive(try!(rule.bool_or("case_insensitive", false)));
+            rules.push(Rule::new(glob, codec, origin.as_ref().map(|origin| &origin[..])));
+        }
+
+        if rules.is_empty() {
+            return Err(Error::Invalid("migration requires at least one rule".to_string()));
+        }
+
+        Ok(MigrationInput::new(Settings {
+            root: root,
+            rules: rules,
+            progress: progress,
+            rate: rate,
+            checkpoint: checkpoint,
+            cursor: cursor,
+            acknowledged: acknowledged,
+        }))
+    }
+}
+
+impl Input for MigrationInput {
+    fn check(&self) -> Result<(), String> {
+        match fs::metadata(&self.settings.root) {
+            Ok(ref metadata) if metadata.is_dir() => Ok(()),
+            Ok(..) => Err(format!("{:?} is not a directory", self.settings.root)),
+            Err(err) => Err(format!("unable to access {:?}: {}", self.settings.root, err)),
+        }
+    }
+
+    fn run(&self, tx: Sink, codec: Box<Codec>) {
+        let mut migration = Migration::open(&self.settings, Arc::new(SystemClock)).with_stop(self.stopped.clone());
+        loop {
+            if migration.is_stopped() {
+                info!(target: "Input::Migration", "migration stopped: {}", migration.summary());
+                return;
+            }
+
+            match migration.step(&tx, &*codec) {
+                Ok(Some(..)) => {}
+                Ok(None) => break,
+                Err(..) => return,
+            }
+        }
+
+        let summary = migration.summary();
+        info!(target: "Input::Migration", "migration finished: {}", summary);
+        for &(ref path, ref error) in summary.errors.iter() {
+            warn!(target: "Input::Migration", "'{}' is not migrated: {}", path, error);
+        }
+    }
+
+    fn stop(&self, _: u32) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    use super::super::super::{Envelope, Origin, Record, RecordItem};
+    use super::super::super::clock::MockClock;
+    use super::super::super::codec::{Json, MessagePack};
+    use super::super::super::codec::msgpack;
+    use super::super::super::glob::Glob;
+    use super::super::super::numeric::NonFinite;
+    use super::super::super::testing::{record, string};
+    use super::super::super::validation::Validation;
+    use super::super::Sink;
+    use super::{Entry, Migration, Rule, Settings, Summary};
+
+    fn write(path: &Path, data: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    fn sink() -> (Sink, Receiver<Envelope>) {
+        let origin = Origin {
+            input: "migration".to_string(),
+            validation: Arc::new(Validation::default()),
+            capture: None,
+            projection: None,
+            encoding: None,
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+
+        let (tx, rx) = channel();
+        (Sink::new(tx, Arc::new(origin)), rx)
+    }
+
+    #[test]
+    fn match_glob_patterns() {
+        let rule = |pattern: &str| Rule::new(Glob::path(pattern).unwrap(), None, None);
+
+        assert!(rule("*.json").matches("a.json"));
+        assert!(!rule("*.json").matches("app/a.json"));
+        assert!(rule("**/*.json").matches("a.json"));
+        assert!(rule("**/*.json").matches("app/2015/a.json"));
+        assert!(!rule("**/*.json").matches("appa.jsonl"));
+        assert!(rule("app/**").matches("app/2015/a.log"));
+        assert!(rule("app/?.log").matches("app/a.log"));
+        assert!(!rule("app/?.log").matches("app/ab.log"));
+    }
+
+    #[test]
+    fn resume_interrupted_migration() {
+        let dir = env::temp_dir().join("logdrop-migration-resume");
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("tree");
+
+        // Three formats: concatenated JSON, NDJSON and MessagePack, the latter in a subdirectory.
+        write(&root.join("a.json"), br#"{"seq": 1} {"seq": 2}"#);
+        write(&root.join("b.ndjson"), b"{\"seq\": 3}\n{\"seq\": 4}\n{\"seq\": 5}\n");
+        let mut data = msgpack::encode(&record(vec![("seq", RecordItem::I64(6))]));
+        data.extend(msgpack::encode(&record(vec![("seq", RecordItem::I64(7))])).into_iter());
+        write(&root.join("old/c.mp"), &data);
+        write(&root.join("README"), b"not migrated");
+
+        let settings = Settings {
+            root: root.clone(),
+            rules: vec![
+                Rule::new(Glob::path("*.json").unwrap(), None, Some("origin_file")),
+                Rule::new(Glob::path("*.ndjson").unwrap(), Some(Box::new(Json::default())), Some("origin_file")),
+                Rule::new(Glob::path("**/*.mp").unwrap(), Some(Box::new(MessagePack::default())), Some("origin_file")),
+            ],
+            progress: dir.join("migration.progress"),
+            rate: None,
+            checkpoint: 2,
+            cursor: None,
+            acknowledged: false,
+        };
+        let clock = Arc::new(MockClock::new(0));
+        let codec = Json::default();
+
+        // Interrupted after the first file.
+        let (sink, rx) = sink();
+        {
+            let mut migration = Migration::open(&settings, clock.clone());
+            assert_eq!(Some("a.json".to_string()), migration.step(&sink, &codec).unwrap());
+        }
+
+        let mut migration = Migration::open(&settings, clock.clone());
+        assert_eq!(Some("b.ndjson".to_string()), migration.step(&sink, &codec).unwrap());
+
+        // Appears in the middle of the migration, hence picked up at the end.
+        write(&root.join("new/d.mp"), &msgpack::encode(&record(vec![("seq", RecordItem::I64(8))])));
+        let summary = migration.run(&sink, &codec).unwrap();
+        drop(sink);
+
+        let records: Vec<Record> = rx.iter().map(|envelope| envelope.record).collect();
+        let origins: Vec<(Option<RecordItem>, Option<RecordItem>)> = records.iter()
+            .map(|record| (record.find("seq").cloned(), record.find("origin_file").cloned()))
+            .collect();
+        assert_eq!(vec![
+            (Some(RecordItem::I64(1)), Some(string("a.json"))),
+            (Some(RecordItem::I64(2)), Some(string("a.json"))),
+            (Some(RecordItem::I64(3)), Some(string("b.ndjson"))),
+            (Some(RecordItem::I64(4)), Some(string("b.ndjson"))),
+            (Some(RecordItem::I64(5)), Some(string("b.ndjson"))),
+            (Some(RecordItem::I64(6)), Some(string("old/c.mp"))),
+            (Some(RecordItem::I64(7)), Some(string("old/c.mp"))),
+            (Some(RecordItem::I64(8)), Some(string("new/d.mp"))),
+        ], origins);
+
+        assert_eq!(Summary { files: 4, records: 8, errors: Vec::new() }, summary);
+
+        // Nothing is left for the next run.
+        let (sink, rx) = sink();
+        let mut migration = Migration::open(&settings, clock);
+        assert_eq!(summary, migration.run(&sink, &codec).unwrap());
+        drop(sink);
+        assert_eq!(0, rx.iter().count());
+    }
+
+    #[test]
+    fn redeliver_records_since_checkpoint_after_crash() {
+        let dir = env::temp_dir().join("logdrop-migration-crash");
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("tree");
+        write(&root.join("a.json"), br#"{"seq": 1} {"seq": 2} {"seq": 3} {"seq": 4} {"seq": 5}"#);
+
+        let settings = Settings {
+            root: root.clone(),
+            rules: vec![Rule::new(Glob::path("*.json").unwrap(), None, None)],
+            progress: dir.join("migration.progress"),
+            rate: None,
+            checkpoint: 2,
+            cursor: Some("cursor".to_string()),
+            acknowledged: false,
+        };
+        let clock = Arc::new(MockClock::new(0));
+        let codec = Json::default();
+        let cursors = |rx: Receiver<Envelope>| -> Vec<(Option<RecordItem>, Option<RecordItem>)> {
+            rx.iter().map(|envelope| (envelope.record.find("seq").cloned(), envelope.record.find("cursor").cloned())).collect()
+        };
+
+        let (sink, rx) = sink();
+        let inode = {
+            let mut migration = Migration::open(&settings, clock.clone());
+            migration.run(&sink, &codec).unwrap();
+
+            // Crashed after handing the last record over, before saving the progress, which is
+            // left as of the checkpoint after the fourth record.
+            let inode = migration.progress.get("a.json").unwrap().inode;
+            migration.progress.insert("a.json".to_string(), Entry { inode: inode, records: 4, done: false, error: None });
+            migration.save();
+            inode
+        };
+        drop(sink);
+        let first = cursors(rx);
+        assert_eq!(5, first.len());
+        assert_eq!(Some(string(&format!("{}:0", inode))), first[0].1);
+
+        let (sink, rx) = sink();
+        let mut migration = Migration::open(&settings, clock);
+        assert_eq!(Summary { files: 1, records: 5, errors: Vec::new() }, migration.run(&sink, &codec).unwrap());
+        drop(sink);
+        let second = cursors(rx);
+
+        // Nothing is lost, while the duplicate is bounded by the checkpoint and keeps its cursor.
+        assert_eq!(vec![first[4].clone()], second);
+        assert!(second.len() as u64 <= settings.checkpoint);
+    }
+
+    #[test]
+    fn resume_mid_file_after_stop() {
+        let dir = env::temp_dir().join("logdrop-migration-stop");
+        let _ = fs::remove_dir_all(&dir);
+        write(&dir.join("tree/a.json"), br#"{"seq": 1} {"seq": 2} {"seq": 3} {"seq": 4} {"seq": 5}"#);
+
+        let settings = |dir: &Path, rate: Option<u64>| Settings {
+            root: dir.join("tree"),
+            rules: vec![Rule::new(Glob::path("*.json").unwrap(), None, None)],
+            progress: dir.join("migration.progress"),
+            rate: rate,
+            checkpoint: 2,
+            cursor: None,
+            acknowledged: false,
+        };
+        let seqs = |envelopes: Vec<Envelope>| -> Vec<Option<RecordItem>> {
+            envelopes.iter().map(|envelope| envelope.record.find("seq").cloned()).collect()
+        };
+
+        // Paced to a record per 100ms, so that the stop lands between the third and fourth one,
+        // past the checkpoint after the second.
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (sink, rx) = sink();
+        let running = {
+            let settings = settings(&dir, Some(10));
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut migration = Migration::open(&settings, Arc::new(MockClock::new(0))).with_stop(stopped);
+                migration.run(&sink, &Json::default()).unwrap()
+            })
+        };
+
+        let first: Vec<Envelope> = rx.iter().take(3).collect();
+        stopped.store(true, Ordering::SeqCst);
+        let summary = running.join().unwrap();
+        assert_eq!(Summary { files: 0, records: 3, errors: Vec::new() }, summary);
+        assert_eq!(0, rx.iter().count());
+        assert_eq!(vec![Some(RecordItem::I64(1)), Some(RecordItem::I64(2)), Some(RecordItem::I64(3))], seqs(first));
+
+        let settings = settings(&dir, None);
+        let (sink, rx) = sink();
+        let mut migration = Migration::open(&settings, Arc::new(MockClock::new(0)));
+        assert_eq!(Summary { files: 1, records: 5, errors: Vec::new() }, migration.run(&sink, &Json::default()).unwrap());
+        drop(sink);
+        assert_eq!(vec![Some(RecordItem::I64(4)), Some(RecordItem::I64(5))], seqs(rx.iter().collect()));
+    }
+
+    #[test]
+    fn advance_progress_past_confirmed_records_only() {
+        let dir = env::temp_dir().join("logdrop-migration-acknowledged");
+        let _ = fs::remove_dir_all(&dir);
+        write(&dir.join("tree/a.json"), br#"{"seq": 1} {"seq": 2} {"seq": 3}"#);
+
+        let settings = |dir: &Path| Settings {
+            root: dir.join("tree"),
+            rules: vec![Rule::new(Glob::path("*.json").unwrap(), None, None)],
+            progress: dir.join("migration.progress"),
+            rate: None,
+            checkpoint: 2,
+            cursor: None,
+            acknowledged: true,
+        };
+        let clock = Arc::new(MockClock::new(0));
+        let progress = |dir: &Path| {
+            let settings = settings(dir);
+            let migration = Migration::open(&settings, clock.clone());
+            migration.progress.get("a.json").cloned()
+        };
+
+        let (sink, rx) = sink();
+        let running = {
+            let settings = settings(&dir);
+            thread::spawn(move || {
+                let mut migration = Migration::open(&settings, Arc::new(MockClock::new(0)));
+                migration.run(&sink, &Json::default()).unwrap()
+            })
+        };
+
+        // All records are taken by the pipeline, none confirmed by the time of the checkpoint.
+        let envelopes: Vec<Envelope> = rx.iter().take(3).collect();
+        assert_eq!(Some(0), progress(&dir).map(|entry| entry.records));
+
+        // Outputs confirm the first two records, failing the last one.
+        let mut envelopes = envelopes.into_iter();
+        for envelope in envelopes.by_ref().take(2) {
+            envelope.ack.unwrap().confirm();
+        }
+        drop(envelopes);
+
+        let summary = running.join().unwrap();
+        assert_eq!(2, summary.records);
+        assert_eq!(vec![("a.json".to_string(), "record 2 is not delivered".to_string())], summary.errors);
+
+        let entry = progress(&dir).unwrap();
+        assert_eq!((2, false), (entry.records, entry.done));
+    }
+}
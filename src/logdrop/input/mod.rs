@@ -1,17 +1,421 @@
 use std;
-use std::sync::mpsc::Sender;
+use std::cmp;
+use std::io::Read;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{SendError, Sender};
+use std::thread;
 
-use super::codec::Codec;
-use super::Record;
+use super::ack::Ack;
+use super::codec::{Codec, Malformed};
+use super::encoding::{Rejects, Transcoder};
+use super::identity::Provenance;
+use super::metrics;
+use super::metrics::Counter;
+use super::quota::Quota;
+use super::sched::Scheduler;
+use super::{Envelope, Origin, Record, RecordItem};
+
+/// Field of records reporting values failing to decode holding the error, the raw bytes going to
+/// the `message` field.
+pub const DECODE_ERROR_FIELD: &'static str = "_decode_error";
+
+/// Field of records reporting values failing to decode holding the peer sent them, if known.
+pub const PEER_FIELD: &'static str = "_peer";
+
+/// Decoded bytes and records counters.
+#[derive(Clone)]
+struct Volume {
+    bytes: Counter,
+    records: Counter,
+}
+
+impl Volume {
+    fn new(prefix: &str) -> Volume {
+        Volume {
+            bytes: metrics::registry().counter(&format!("{}.bytes", prefix)),
+            records: metrics::registry().counter(&format!("{}.records", prefix)),
+        }
+    }
+
+    fn add(&self, bytes: usize) {
+        self.bytes.add(bytes);
+        self.records.inc();
+    }
+}
+
+/// Sending half of the pipeline channel, tagging each record with the input origin.
+///
+/// Decoded bytes and records are accounted in the `input.<name>.bytes` and
+/// `input.<name>.records` metrics and per connection in the `input.<name>.peer.<peer>.*` ones.
+#[derive(Clone)]
+pub struct Sink {
+    tx: Sender<Envelope>,
+    origin: Arc<Origin>,
+    peer: Option<Arc<String>>,
+    volume: Volume,
+    connection: Option<Volume>,
+    quota: Option<Quota>,
+    scheduler: Option<Arc<Scheduler>>,
+    /// Number of records sent, shared with whoever watches the stream for activity.
+    sent: Option<Arc<AtomicUsize>>,
+    /// Set once the stream is to be closed at the next record boundary.
+    closing: Option<Arc<AtomicBool>>,
+    /// Set while the pipeline refuses records, see `Sink::with_pause`.
+    paused: Option<Arc<AtomicBool>>,
+    provenance: Option<Arc<Provenance>>,
+}
+
+impl Sink {
+    pub fn new(tx: Sender<Envelope>, origin: Arc<Origin>) -> Sink {
+        let volume = Volume::new(&format!("input.{}", origin.input));
+
+        Sink {
+            tx: tx,
+            origin: origin,
+            peer: None,
+            volume: volume,
+            connection: None,
+            quota: None,
+            scheduler: None,
+            sent: None,
+            closing: None,
+            paused: None,
+            provenance: None,
+        }
+    }
+
+    /// Returns the sink tagging records with the given connection peer address as well.
+    pub fn with_peer(&self, peer: &str) -> Sink {
+        Sink {
+            tx: self.tx.clone(),
+            origin: self.origin.clone(),
+            peer: Some(Arc::new(peer.to_string())),
+            volume: self.volume.clone(),
+            connection: Some(Volume::new(&format!("input.{}.peer.{}", self.origin.input, peer))),
+            quota: self.quota.clone(),
+            scheduler: self.scheduler.clone(),
+            sent: self.sent.clone(),
+            closing: self.closing.clone(),
+            paused: self.paused.clone(),
+            provenance: self.provenance.clone(),
+        }
+    }
+
+    /// Returns the sink counting records it sends in the given counter as well.
+    pub fn with_sent(mut self, sent: Arc<AtomicUsize>) -> Sink {
+        self.sent = Some(sent);
+        self
+    }
+
+    /// Returns the sink that stops decoding the stream after the record it's decoding once the
+    /// given flag is set.
+    pub fn with_closing(mut self, closing: Arc<AtomicBool>) -> Sink {
+        self.closing = Some(closing);
+        self
+    }
+
+    /// Returns the sink holding records back while the given flag is set, so that inputs stop
+    /// reading and push back on senders while the pipeline refuses records.
+    pub fn with_pause(mut self, paused: Arc<AtomicBool>) -> Sink {
+        self.paused = Some(paused);
+        self
+    }
+
+    /// Returns the sink that stops decoding connections the quota blocks.
+    pub fn with_quota(mut self, quota: Quota) -> Sink {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Returns the sink scheduling threads consuming streams as data-path ones, pausing them
+    /// between records while the process is over the CPU budget.
+    pub fn with_scheduler(mut self, scheduler: Arc<Scheduler>) -> Sink {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Returns the sink appending the hop of this instance to the provenance of records it sends.
+    pub fn with_provenance(mut self, provenance: Arc<Provenance>) -> Sink {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+
+    /// Sends the record once the input policy on NaN and infinite floats is applied to it, which
+    /// may dead-letter it instead.
+    pub fn send(&self, record: Record) -> Result<(), SendError<Envelope>> {
+        self.deliver(record, None)
+    }
+
+    /// Sends the record like `send`, telling the input through the token once outputs have
+    /// accepted it, see `ack`.
+    pub fn send_acked(&self, record: Record, ack: Ack) -> Result<(), SendError<Envelope>> {
+        self.deliver(record, Some(ack))
+    }
+
+    /// Sends the record straight to the dead-letter output for the given reason.
+    pub fn reject(&self, record: Record, reason: String) -> Result<(), SendError<Envelope>> {
+        self.dead_letter(record, reason, None)
+    }
+
+    fn deliver(&self, record: Record, ack: Option<Ack>) -> Result<(), SendError<Envelope>> {
+        self.pause();
+
+        let mut record = match self.origin.non_finite.apply(record) {
+            Ok(record) => record,
+            Err((record, reason)) => return self.dead_letter(record, reason, ack),
+        };
+        if let Some(ref provenance) = self.provenance {
+            provenance.stamp(&mut record);
+        }
+
+        let size = record.size_hint();
+        self.volume.add(size);
+        if let Some(ref connection) = self.connection {
+            connection.add(size);
+        }
+        if let Some(ref sent) = self.sent {
+            sent.fetch_add(1, Ordering::SeqCst);
+        }
+
+        self.tx.send(Envelope {
+            record: record,
+            origin: self.origin.clone(),
+            peer: self.peer.clone(),
+            rejected: None,
+            ack: ack,
+        })
+    }
+
+    fn dead_letter(&self, record: Record, reason: String, ack: Option<Ack>) -> Result<(), SendError<Envelope>> {
+        self.pause();
+        self.tx.send(Envelope {
+            record: record,
+            origin: self.origin.clone(),
+            peer: self.peer.clone(),
+            rejected: Some(reason),
+            ack: ack,
+        })
+    }
+
+    /// Dead-letters runs of bytes the transcoder has been unable to decode so far, as records
+    /// holding them in the `raw` field.
+    fn reject_undecodable(&self, rejects: &Option<Rejects>) -> Result<(), SendError<Envelope>> {
+        let (rejects, encoding) = match (rejects.as_ref(), self.origin.encoding.as_ref()) {
+            (Some(rejects), Some(encoding)) => (rejects, encoding),
+            _ => return Ok(()),
+        };
+
+        for span in rejects.take().into_iter() {
+            let mut record = Record::new();
+            record.insert("raw".to_string(), RecordItem::Bytes(span));
+            record.insert("charset".to_string(), RecordItem::String(encoding.charset.name().to_string()));
+            try!(self.reject(record, format!("undecodable {} bytes", encoding.charset.name())));
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the stream and sends all its records, capturing their raw payloads if enabled
+    /// for the input. Decoding stops once the quota blocks the peer and pauses while the process
+    /// is over the CPU budget.
+    ///
+    /// Fields are projected while decoding if the input has a projection, captured payloads
+    /// staying intact. Streams of inputs with the declared encoding are transcoded into UTF-8
+    /// first, runs of undecodable bytes being dead-lettered in strict mode.
+    ///
+    /// Values failing to decode are sent as records reporting them if the input asks for that,
+    /// see `Sink::decode_error`, unless payloads are captured.
+    pub fn consume(&self, codec: &Codec, rd: Box<Read>) -> Result<(), SendError<Envelope>> {
+        if let Some(ref scheduler) = self.scheduler {
+            scheduler.enter();
+        }
+
+        let (rd, rejects) = match self.origin.encoding {
+            Some(ref encoding) => {
+                let transcoder = Transcoder::new(rd, (**encoding).clone(), &self.origin.input);
+                let rejects = if encoding.strict { Some(transcoder.rejects()) } else { None };
+                (Box::new(transcoder) as Box<Read>, rejects)
+            }
+            None => (rd, None),
+        };
+
+        let rd = match self.origin.capture {
+            Some(ref capture) => {
+                match codec.decode_raw(rd) {
+                    Ok(records) => {
+                        for (record, raw) in records {
+                            let mut record = match self.origin.projection {
+                                Some(ref projection) => projection.apply(record),
+                                None => record,
+                            };
+                            capture.attach(&mut record, raw);
+                            try!(self.reject_undecodable(&rejects));
+                            try!(self.send(record));
+                            self.throttle();
+                            if self.is_blocked() {
+                                return Ok(());
+                            }
+                        }
+                        return self.reject_undecodable(&rejects);
+                    }
+                    Err(rd) => {
+                        warn!(target: "Input", "codec of '{}' input is unable to capture raw payloads", self.origin.input);
+                        rd
+                    }
+                }
+            }
+            None => rd,
+        };
+
+        let rd = match self.origin.decode_errors {
+            Some(limit) => {
+                match codec.decode_checked(rd) {
+                    Ok(results) => {
+                        for result in results {
+                            let record = match result {
+                                Ok(record) => {
+                                    match self.origin.projection {
+                                        Some(ref projection) => projection.apply(record),
+                                        None => record,
+                                    }
+                                }
+                                Err(malformed) => self.decode_error(malformed, limit),
+                            };
+                            try!(self.reject_undecodable(&rejects));
+                            try!(self.send(record));
+                            self.throttle();
+                            if self.is_blocked() {
+                                return Ok(());
+                            }
+                        }
+                        return self.reject_undecodable(&rejects);
+                    }
+                    Err(rd) => {
+                        warn!(target: "Input", "codec of '{}' input is unable to report decode errors", self.origin.input);
+                        rd
+                    }
+                }
+            }
+            None => rd,
+        };
+
+        let records = match self.origin.projection {
+            Some(ref projection) => codec.decode_projected(rd, projection.clone()),
+            None => codec.decode(rd),
+        };
+
+        for record in records {
+            try!(self.reject_undecodable(&rejects));
+            try!(self.send(record));
+            self.throttle();
+            if self.is_blocked() {
+                return Ok(());
+            }
+        }
+
+        self.reject_undecodable(&rejects)
+    }
+
+    /// Builds the record reporting the value failing to decode, with at most the limit of its bytes
+    /// as the message.
+    fn decode_error(&self, malformed: Malformed, limit: usize) -> Record {
+        warn!(target: "Input", "'{}' input is unable to decode {} bytes: {}", self.origin.input, malformed.raw.len(), malformed.error);
+
+        let raw = &malformed.raw[..cmp::min(limit, malformed.raw.len())];
+        let mut record = Record::new();
+        record.insert("message".to_string(), RecordItem::String(String::from_utf8_lossy(raw).into_owned()));
+        record.insert(DECODE_ERROR_FIELD.to_string(), RecordItem::String(malformed.error.to_string()));
+        if let Some(ref peer) = self.peer {
+            record.insert(PEER_FIELD.to_string(), RecordItem::String((**peer).clone()));
+        }
+        record
+    }
+
+    /// Waits while the pipeline refuses records, see `Sink::with_pause`.
+    fn pause(&self) {
+        const STEP: u32 = 50;
+
+        if let Some(ref paused) = self.paused {
+            while paused.load(Ordering::SeqCst) {
+                thread::sleep_ms(STEP);
+            }
+        }
+    }
+
+    fn throttle(&self) {
+        if let Some(ref scheduler) = self.scheduler {
+            scheduler.throttle();
+        }
+    }
+
+    /// Returns whether the connection must be closed as its peer sends records over a hard quota
+    /// or it's asked to close, see `Sink::with_closing`.
+    pub fn is_blocked(&self) -> bool {
+        if let Some(ref closing) = self.closing {
+            if closing.load(Ordering::SeqCst) {
+                return true;
+            }
+        }
+
+        match (self.quota.as_ref(), self.peer.as_ref()) {
+            (Some(quota), Some(peer)) if quota.is_blocked(peer) => {
+                warn!(target: "Input", "closing connection from {} to '{}' input: over the hard quota", peer, self.origin.input);
+                true
+            }
+            _ => false,
+        }
+    }
+}
 
 pub trait Input : Sync + Send {
-    fn run(&self, tx: Sender<Record>, codec: Box<Codec>);
+    fn run(&self, tx: Sink, codec: Box<Codec>);
+
+    /// Verifies that the input is able to start, e.g. that its address can be bound, without
+    /// accepting anything.
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Tells the input apart from others of its type in thread names, e.g. by the port.
+    fn label(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the descriptor of the listening socket once bound, for handing it over to another
+    /// process.
+    fn listener(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Makes the input accept on the inherited listening socket instead of binding its own one.
+    fn inherit(&self, _: RawFd) -> Result<(), String> {
+        Err("input has no listening socket".to_string())
+    }
+
+    /// Stops accepting new connections, giving accepted ones the drain period in milliseconds to
+    /// finish. The listening socket is left open.
+    fn stop(&self, _: u32) {}
+
+    /// Makes the input draw random numbers from the given seed, see `determinism`.
+    fn reseed(&mut self, _: u64) {}
 
     fn typename(&self) -> &'static str {
         unsafe { std::intrinsics::type_name::<Self>() }
     }
 }
 
+mod http;
+mod migration;
+mod requeue;
 mod tcp;
 
+pub use self::http::HttpInput;
+pub use self::migration::MigrationInput;
+pub use self::requeue::Requeue;
 pub use self::tcp::TcpInput;
@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Input, Sink};
+use super::super::RecordItem;
+use super::super::codec::Codec;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::deadletter::{Criteria, Letter, REQUEUED};
+use super::super::metrics;
+use super::super::spool::{Reader, Writer};
+
+/// Default number of times a record may be requeued before it's quarantined.
+const MAX_ATTEMPTS: u64 = 3;
+
+/// Number of entries read between progress reports.
+const PROGRESS: u64 = 1000;
+
+/// Outcome of the requeue.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Entries read from the archive.
+    pub read: u64,
+    pub requeued: u64,
+    /// Entries not matching the criteria.
+    pub skipped: u64,
+    /// Entries requeued too many times already.
+    pub quarantined: u64,
+    /// Entries not being dead letters of the known layout.
+    pub malformed: u64,
+    /// Entries requeued or quarantined by earlier runs.
+    pub marked: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} read, {} requeued, {} skipped, {} quarantined, {} malformed, {} marked",
+            self.read, self.requeued, self.skipped, self.quarantined, self.malformed, self.marked)
+    }
+}
+
+/// Reads the dead-letter archive written by `output::Archive`, sending original records of letters
+/// matching the criteria into the pipeline again, like any input does, and finishes.
+///
+/// Requeued records carry the number of times they have been requeued in `_requeue_count`, so a
+/// record dead-lettered again keeps it in the archive. Once it reaches the maximum number of
+/// attempts the letter is appended to the quarantine archive instead, which is the archive path
+/// with `.quarantine` appended by default, so that records failing persistently don't circle
+/// forever.
+///
+/// The archive may be the one the pipeline dead-letters into, so only entries present when the
+/// requeue starts are read, and records failing again are left for the next run rather than
+/// circling within this one. Offsets of the entries requeued or quarantined are appended to the
+/// archive path with `.requeued` appended, and such entries are skipped by later runs, leaving
+/// the archive itself to its writer.
+///
+/// Progress is logged every thousand entries and counted in `requeue.requeued`,
+/// `requeue.skipped`, `requeue.quarantined` and `requeue.malformed`, the summary being logged
+/// once done.
+pub struct Requeue {
+    path: PathBuf,
+    criteria: Criteria,
+    max_attempts: u64,
+    quarantine: PathBuf,
+    marks: PathBuf,
+    stopped: AtomicBool,
+}
+
+impl Requeue {
+    pub fn new(path: &Path, criteria: Criteria) -> Requeue {
+        let mut quarantine = path.as_os_str().to_os_string();
+        quarantine.push(".quarantine");
+        let mut marks = path.as_os_str().to_os_string();
+        marks.push(".requeued");
+
+        Requeue {
+            path: path.to_path_buf(),
+            criteria: criteria,
+            max_attempts: MAX_ATTEMPTS,
+            quarantine: PathBuf::from(quarantine),
+            marks: PathBuf::from(marks),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn max_attempts(mut self, attempts: u64) -> Requeue {
+        self.max_attempts = attempts;
+        self
+    }
+
+    pub fn quarantine(mut self, path: &Path) -> Requeue {
+        self.quarantine = path.to_path_buf();
+        self
+    }
+
+    /// Reads offsets of the entries handled by earlier runs.
+    fn marked(&self) -> Result<HashSet<u64>, String> {
+        let file = match File::open(&self.marks) {
+            Ok(file) => file,
+            Err(..) if !self.marks.exists() => return Ok(HashSet::new()),
+            Err(err) => return Err(format!("unable to open '{}': {}", self.marks.display(), err)),
+        };
+
+        let mut marked = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let line = try!(line.map_err(|err| format!("unable to read '{}': {}", self.marks.display(), err)));
+            match line.trim().parse() {
+                Ok(offset) => { marked.insert(offset); }
+                Err(..) => warn!(target: "Input::Requeue", "ignoring malformed mark '{}'", line),
+            }
+        }
+        Ok(marked)
+    }
+
+    /// Requeues letters matching the criteria through the sink, failing if the archive can't be
+    /// opened, the quarantine or the marks can't be written to or the pipeline is gone.
+    pub fn requeue(&self, tx: &Sink) -> Result<Summary, String> {
+        let end = try!(fs::metadata(&self.path).map(|meta| meta.len()).map_err(|err| {
+            format!("unable to open '{}': {}", self.path.display(), err)
+        }));
+        let mut reader = try!(Reader::open(&self.path).map_err(|err| {
+            format!("unable to open '{}': {}", self.path.display(), err)
+        })).until(end);
+        let marked = try!(self.marked());
+        let mut marks = try!(OpenOptions::new().append(true).create(true).open(&self.marks).map_err(|err| {
+            format!("unable to open '{}': {}", self.marks.display(), err)
+        }));
+        let mut mark = |offset: u64| -> Result<(), String> {
+            writeln!(marks, "{}", offset).map_err(|err| format!("unable to mark entry: {}", err))
+        };
+        let mut quarantine = None;
+        let mut summary = Summary::default();
+        let registry = metrics::registry();
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            let offset = reader.offset();
+            let entry = match reader.next() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(target: "Input::Requeue", "stopped reading '{}': {}", self.path.display(), err);
+                    break;
+                }
+            };
+
+            summary.read += 1;
+            if summary.read % PROGRESS == 0 {
+                info!(target: "Input::Requeue", "requeue of '{}' in progress: {}", self.path.display(), summary);
+            }
+
+            if marked.contains(&offset) {
+                summary.marked += 1;
+                continue;
+            }
+
+            let letter = match Letter::from_entry(&entry) {
+                Ok(letter) => letter,
+                Err(reason) => {
+                    warn!(target: "Input::Requeue", "skipping entry {}: {}", summary.read, reason);
+                    summary.malformed += 1;
+                    registry.counter("requeue.malformed").inc();
+                    continue;
+                }
+            };
+
+            if !self.criteria.matches(&letter) {
+                summary.skipped += 1;
+                registry.counter("requeue.skipped").inc();
+                continue;
+            }
+
+            let attempts = letter.requeued();
+            if attempts >= self.max_attempts {
+                if quarantine.is_none() {
+                    quarantine = Some(try!(Writer::open(&self.quarantine).map_err(|err| {
+                        format!("unable to open quarantine '{}': {}", self.quarantine.display(), err)
+                    })));
+                }
+                try!(quarantine.as_mut().unwrap().append(&entry).map_err(|err| {
+                    format!("unable to quarantine: {}", err)
+                }));
+                try!(mark(offset));
+                summary.quarantined += 1;
+                registry.counter("requeue.quarantined").inc();
+                continue;
+            }
+
+            let mut record = letter.record;
+            record.insert(REQUEUED.to_string(), RecordItem::U64(attempts + 1));
+            if tx.send(record).is_err() {
+                return Err("pipeline is gone".to_string());
+            }
+            try!(mark(offset));
+            summary.requeued += 1;
+            registry.counter("requeue.requeued").inc();
+        }
+
+        if let Some(mut quarantine) = quarantine {
+            if let Err(err) = quarantine.sync() {
+                warn!(target: "Input::Requeue", "unable to sync quarantine '{}': {}", self.quarantine.display(), err);
+            }
+        }
+        Ok(summary)
+    }
+}
+
+impl FromConfig for Requeue {
+    fn from_config(config: &Config) -> Result<Requeue, Error> {
+        let path = try!(config.string("archive"));
+        let optional = |key: &str| -> Result<Option<String>, Error> {
+            match config.find(key) {
+                Some(..) => config.string(key).map(Some),
+                None => Ok(None),
+            }
+        };
+        let time = |key: &str| -> Result<Option<f64>, Error> {
+            match config.find(key) {
+                Some(..) => config.f64_or(key, 0.0).map(Some),
+                None => Ok(None),
+            }
+        };
+
+        let criteria = Criteria {
+            reason: try!(optional("reason")),
+            stage: try!(optional("stage")),
+            since: try!(time("since")),
+            until: try!(time("until")),
+        };
+
+        let requeue = Requeue::new(Path::new(&path), criteria)
+            .max_attempts(try!(config.u64_or("max_attempts", MAX_ATTEMPTS)));
+        match try!(optional("quarantine")) {
+            Some(quarantine) => Ok(requeue.quarantine(Path::new(&quarantine))),
+            None => Ok(requeue),
+        }
+    }
+}
+
+impl Input for Requeue {
+    fn check(&self) -> Result<(), String> {
+        Reader::open(&self.path).map(|_| ()).map_err(|err| format!("unable to open '{}': {}", self.path.display(), err))
+    }
+
+    fn run(&self, tx: Sink, _: Box<Codec>) {
+        info!(target: "Input::Requeue", "requeueing dead letters from '{}'", self.path.display());
+        match self.requeue(&tx) {
+            Ok(summary) => info!(target: "Input::Requeue", "requeue of '{}' finished: {}", self.path.display(), summary),
+            Err(reason) => error!(target: "Input::Requeue", "requeue of '{}' failed: {}", self.path.display(), reason),
+        }
+    }
+
+    fn stop(&self, _: u32) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use super::super::super::{Origin, Record, RecordItem};
+    use super::super::super::codec::MessagePack;
+    use super::super::super::deadletter::{Criteria, Letter};
+    use super::super::super::filter::{Failure, Filter, Guard, OnError};
+    use super::super::super::numeric::NonFinite;
+    use super::super::super::output::Archive;
+    use super::super::super::pipeline::Pipeline;
+    use super::super::super::spool::Reader;
+    use super::super::super::testing::{Collector, Feeder, record, string};
+    use super::super::super::validation::{Missing, Validation};
+    use super::super::Sink;
+    use super::{Requeue, Summary};
+
+    /// Filter refusing records of the given kinds.
+    struct Refuse(Vec<&'static str>);
+
+    impl Filter for Refuse {
+        fn apply(&mut self, record: Record) -> Vec<Record> {
+            self.try_apply(record).unwrap_or_else(|_| Vec::new())
+        }
+
+        fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+            let kind = record.find("kind").and_then(|kind| kind.as_str()).unwrap_or("").to_string();
+            if self.0.contains(&&kind[..]) {
+                return Err(Failure::new(record, format!("kind '{}' refused", kind)));
+            }
+            Ok(vec![record])
+        }
+    }
+
+    /// Runs the pipeline of the refusing filter over records of the input, archiving dead letters.
+    fn run(input: Box<super::super::Input>, refuse: Vec<&'static str>, archive: &Path) -> Collector {
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("test", input, Box::new(MessagePack::default()), None);
+        pipeline.add_filter(Box::new(Guard::new(Box::new(Refuse(refuse)), "test_requeue", OnError::DeadLetter)));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_dead_letter(Box::new(Archive::open(archive).unwrap()));
+        pipeline.run();
+
+        collector
+    }
+
+    fn letters(path: &Path) -> Vec<Letter> {
+        let mut reader = Reader::open(path).unwrap();
+        let mut letters = Vec::new();
+        while let Some(entry) = reader.next().unwrap() {
+            letters.push(Letter::from_entry(&entry).unwrap());
+        }
+        letters
+    }
+
+    #[test]
+    fn requeue_fixed_records_and_quarantine_failing_ones() {
+        let dir = env::temp_dir().join("logdrop-requeue");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("archive");
+
+        let (input, tx) = Feeder::new();
+        for &kind in ["a", "b", "c"].iter() {
+            tx.send(record(vec![("kind", string(kind))])).unwrap();
+        }
+        drop(tx);
+        let collector = run(Box::new(input), vec!["a", "b"], &archive);
+        assert_eq!(vec![record(vec![("kind", string("c"))])], collector.records());
+
+        let archived = letters(&archive);
+        assert_eq!(2, archived.len());
+        assert!(archived.iter().all(|letter| letter.stage == "filter"));
+        assert!(archived[0].reason.ends_with("kind 'a' refused"));
+
+        // Once the filter is fixed for "a", only those records are requeued, the pipeline
+        // dead-lettering into the archive being requeued from.
+        let criteria = Criteria { reason: Some("kind 'a'".to_string()), ..Criteria::default() };
+        let collector = run(Box::new(Requeue::new(&archive, criteria)), vec!["b"], &archive);
+        let expected = record(vec![("kind", string("a")), ("_requeue_count", RecordItem::U64(1))]);
+        assert_eq!(vec![expected], collector.records());
+        assert_eq!(2, letters(&archive).len());
+
+        // Records still failing are archived again, carrying the count, but not requeued again
+        // within the same run.
+        let criteria = Criteria { reason: Some("kind 'b'".to_string()), ..Criteria::default() };
+        let collector = run(Box::new(Requeue::new(&archive, criteria)), vec!["b"], &archive);
+        assert!(collector.records().is_empty());
+        let archived = letters(&archive);
+        assert_eq!(3, archived.len());
+        assert_eq!(1, archived[2].requeued());
+
+        // Entries requeued already are skipped, the failing one is quarantined at last.
+        let origin = Origin {
+            input: "requeue".to_string(),
+            validation: Arc::new(Validation::default()),
+            capture: None,
+            projection: None,
+            encoding: None,
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+        let (tx, rx) = channel();
+        let requeue = Requeue::new(&archive, Criteria::default()).max_attempts(1);
+        let summary = requeue.requeue(&Sink::new(tx, Arc::new(origin))).unwrap();
+
+        assert_eq!(Summary { read: 3, quarantined: 1, marked: 2, ..Summary::default() }, summary);
+        assert!(rx.try_recv().is_err());
+        let quarantined = letters(&dir.join("archive.quarantine"));
+        assert_eq!(1, quarantined.len());
+        assert_eq!(Some(&string("b")), quarantined[0].record.find("kind"));
+    }
+}
@@ -1,69 +1,664 @@
 use std::collections::HashMap;
-use std::io::{BufReader, Read};
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc::Sender;
+use std::io::{BufReader, ErrorKind, Read};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 
-use super::Input;
-use super::super::Record;
+use super::{Input, Sink};
+use super::super::clock::{Clock, SystemClock};
 use super::super::codec::Codec;
-use super::super::json::Builder;
+use super::super::config::{Config, Error, FromConfig};
+use super::super::identity::Provenance;
+use super::super::metrics;
+use super::super::naming;
+use super::super::sys;
 
+/// Default time in milliseconds a connection past its lifetime is given to close after the
+/// goodbye, see `TcpInput::grace`.
+const GRACE: u64 = 10000;
+
+/// Accepted connection, shut down once the drain period after stop is over, past its lifetime
+/// or idle for too long.
+struct Connection {
+    stream: TcpStream,
+    /// Time the connection is asked to go away at, if its lifetime is limited.
+    deadline: Option<u64>,
+    /// Whether the goodbye has been sent.
+    expired: Arc<AtomicBool>,
+    /// Whether the connection is to be closed at the next record boundary.
+    closing: Arc<AtomicBool>,
+    /// Number of records decoded from the connection so far.
+    sent: Arc<AtomicUsize>,
+    /// Number of records seen at the last check and the time it has been seen changing at,
+    /// which is the accept time until the first record.
+    seen: (usize, u64),
+    /// Whether the connection has been closed for being idle.
+    idle: Arc<AtomicBool>,
+}
+
+/// Accepts connections decoding records from each of them in its own thread.
+///
+/// The listening socket is polled, so the input can stop accepting without closing it, as it may
+/// be shared with another process after a handover.
+///
+/// With the magic header set, each connection must start with it before any record, otherwise
+/// it is closed without decoding anything.
+///
+/// With the lifetime limited, connections older than it plus a random jitter of their own are
+/// sent the goodbye, i.e. the write side is shut down, so that producers see the clean EOF and
+/// reconnect, possibly to another instance, while records they have sent meanwhile are still
+/// decoded. Connections of producers ignoring it for the grace period are closed by their
+/// serving threads once the record being decoded is complete, so that no record is cut in the
+/// middle, and only ones sending no complete record for another grace period have the read side
+/// shut down. Connections closed this way are counted in `input.<name>.connections.expired`, apart from
+/// ones failing the handshake counted in `input.<name>.connections.failed`.
+///
+/// With the idle timeout set, connections sending no records for that long, e.g. ones kept open
+/// by keep-alive probes only, are closed to reclaim their threads, which is counted in
+/// `input.<name>.connections.idle`. Unlike a read timeout, bytes that never make up a record
+/// don't keep the connection alive.
+///
+/// With provenance enabled, the hop of this instance is appended to the `_provenance` array of
+/// every record received, so that records forwarded between instances tell which ones they have
+/// passed through, see `identity::Provenance`.
 pub struct TcpInput {
     host: String,
     port: u16,
+    /// Listening socket inherited from another process, taken once running.
+    inherited: Mutex<Option<TcpListener>>,
+    /// Descriptor of the listening socket while accepting.
+    listener: Mutex<Option<RawFd>>,
+    stopped: Arc<AtomicBool>,
+    /// Accepted connections by id.
+    connections: Arc<Mutex<HashMap<usize, Connection>>>,
+    magic: Option<Arc<Vec<u8>>>,
+    /// Maximum lifetime of connections and the maximum jitter added to it, in milliseconds.
+    lifetime: Option<(u64, u64)>,
+    /// Time in milliseconds a connection past its lifetime is given to close after the goodbye.
+    grace: u64,
+    /// Time in milliseconds a connection may stay open without sending records.
+    idle_timeout: Option<u64>,
+    clock: Arc<Clock>,
+    /// Seed of lifetime jitters, taken from the clock at start unless set.
+    seed: Option<u64>,
+    provenance: Option<Arc<Provenance>>,
 }
 
 impl TcpInput {
     pub fn new(host: String, port: u16) -> TcpInput {
         TcpInput {
             host: host,
-            port: port
+            port: port,
+            inherited: Mutex::new(None),
+            listener: Mutex::new(None),
+            stopped: Arc::new(AtomicBool::new(false)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            magic: None,
+            lifetime: None,
+            grace: GRACE,
+            idle_timeout: None,
+            clock: Arc::new(SystemClock),
+            seed: None,
+            provenance: None,
         }
     }
 
-    fn serve(stream: TcpStream, tx: Sender<Record>, codec: Box<Codec>) {
-        debug!(target: "Input::TCP", "connection accepted from {}", stream.peer_addr().unwrap());
+    /// Requires connections to start with the given header.
+    pub fn magic(mut self, magic: &[u8]) -> TcpInput {
+        self.magic = Some(Arc::new(magic.to_vec()));
+        self
+    }
+
+    /// Limits the lifetime of connections to the given one plus up to the jitter, picked for
+    /// each connection, in milliseconds.
+    pub fn lifetime(mut self, lifetime: u64, jitter: u64) -> TcpInput {
+        self.lifetime = Some((lifetime, jitter));
+        self
+    }
 
-        let rd = BufReader::new(stream);
-        let mut codec = codec.decode(Box::new(rd));
-//        let mut codec = Builder::new(rd.chars().map(|x| x.unwrap()));
+    /// Gives connections past their lifetime the given time in milliseconds to close after the
+    /// goodbye, before they are closed at the next record boundary.
+    pub fn grace(mut self, grace: u64) -> TcpInput {
+        self.grace = grace;
+        self
+    }
 
+    /// Closes connections sending no records for the given time in milliseconds.
+    pub fn idle_timeout(mut self, timeout: u64) -> TcpInput {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Appends hops of this instance to the provenance of received records.
+    pub fn provenance(mut self, provenance: Provenance) -> TcpInput {
+        self.provenance = Some(Arc::new(provenance));
+        self
+    }
 
-        for record in codec {
-            tx.send(record).unwrap();
+    pub fn clock(mut self, clock: Arc<Clock>) -> TcpInput {
+        self.clock = clock;
+        self
+    }
+
+    fn serve(stream: TcpStream, tx: Sink, codec: Box<Codec>, magic: Option<Arc<Vec<u8>>>, expired: Arc<AtomicBool>,
+             idle: Arc<AtomicBool>) {
+        let peer = stream.peer_addr().unwrap();
+        debug!(target: "Input::TCP", "connection accepted from {}", peer);
+
+        let input = tx.origin().input.clone();
+        let tx = tx.with_peer(&peer.ip().to_string());
+        let mut rd = BufReader::new(stream);
+        if let Some(ref magic) = magic {
+            if let Err(reason) = TcpInput::handshake(&mut rd, magic) {
+                warn!(target: "Input::TCP", "closing connection from {}: {}", peer, reason);
+                metrics::registry().counter(&format!("input.{}.connections.failed", input)).inc();
+                return;
+            }
         }
+        tx.consume(&*codec, Box::new(rd)).unwrap();
 
+        if expired.load(Ordering::SeqCst) {
+            debug!(target: "Input::TCP", "closed connection from {} past its lifetime", peer);
+            metrics::registry().counter(&format!("input.{}.connections.expired", input)).inc();
+        } else if idle.load(Ordering::SeqCst) {
+            debug!(target: "Input::TCP", "closed idle connection from {}", peer);
+            metrics::registry().counter(&format!("input.{}.connections.idle", input)).inc();
+        }
         debug!(target: "Input::TCP", "stopped serving TCP connection");
     }
-}
 
-impl Input for TcpInput {
-    fn run(&self, tx: Sender<Record>, codec: Box<Codec>) {
+    /// Sends the goodbye to connections past their lifetime, asking ones still open after the
+    /// grace period to close at the next record boundary and shutting down the read side of ones
+    /// still open after another one, and closes ones idle for longer than the timeout.
+    fn expire(&self, now: u64) {
+        for connection in self.connections.lock().unwrap().values_mut() {
+            if let Some(timeout) = self.idle_timeout {
+                let sent = connection.sent.load(Ordering::SeqCst);
+                if sent != connection.seen.0 {
+                    connection.seen = (sent, now);
+                } else if now >= connection.seen.1 + timeout && !connection.idle.swap(true, Ordering::SeqCst) {
+                    let _ = connection.stream.shutdown(Shutdown::Both);
+                }
+            }
+
+            let deadline = match connection.deadline {
+                Some(deadline) if deadline <= now => deadline,
+                _ => continue,
+            };
+
+            if !connection.expired.swap(true, Ordering::SeqCst) {
+                let _ = connection.stream.shutdown(Shutdown::Write);
+            } else if now >= deadline + 2 * self.grace {
+                let _ = connection.stream.shutdown(Shutdown::Read);
+            } else if now >= deadline + self.grace {
+                connection.closing.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Reads the header the connection starts with, failing if it differs from the magic one.
+    fn handshake<R: Read>(rd: &mut R, magic: &[u8]) -> Result<(), String> {
+        let mut header = vec![0; magic.len()];
+        let mut filled = 0;
+        while filled < header.len() {
+            match rd.read(&mut header[filled..]) {
+                Ok(0) => return Err(format!("connection closed after {} bytes of the header", filled)),
+                Ok(len) => filled += len,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(format!("unable to read the header: {}", err)),
+            }
+        }
+
+        if &header[..] != magic {
+            return Err(format!("header {:?} does not match the magic one", header));
+        }
+
+        Ok(())
+    }
+
+    fn listen(&self) -> Option<TcpListener> {
+        if let Some(listener) = self.inherited.lock().unwrap().take() {
+            info!(target: "Input::TCP", "accepting TCP connections on inherited socket at [{}]:{}", self.host, self.port);
+            return Some(listener);
+        }
+
         info!(target: "Input::TCP", "running TCP listener at [{}]:{}", self.host, self.port);
 
         let host: &str = &self.host;
+        match TcpListener::bind((host, self.port)) {
+            Ok(listener) => Some(listener),
+            Err(err) => {
+                error!(target: "Input::TCP", "unable to bind: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl FromConfig for TcpInput {
+    fn from_config(config: &Config) -> Result<TcpInput, Error> {
+        let host = try!(config.string_or("host", "::"));
+        let port = try!(config.u64_or("port", 10053));
+        let mut input = TcpInput::new(host, port as u16);
+
+        if config.find("max_lifetime").is_some() {
+            let lifetime = try!(config.u64_or("max_lifetime", 0));
+            if lifetime == 0 {
+                return Err(Error::Invalid("'max_lifetime' must be positive".to_string()));
+            }
+            input = input.lifetime(lifetime, try!(config.u64_or("lifetime_jitter", lifetime / 10)))
+                .grace(try!(config.u64_or("lifetime_grace", GRACE)));
+        }
+
+        if config.find("idle_timeout").is_some() {
+            let timeout = try!(config.u64_or("idle_timeout", 0));
+            if timeout == 0 {
+                return Err(Error::Invalid("'idle_timeout' must be positive".to_string()));
+            }
+            input = input.idle_timeout(timeout);
+        }
 
+        if let Some(provenance) = config.find("provenance") {
+            input = input.provenance(try!(Provenance::from_config(&provenance)));
+        }
+
+        match config.find("magic") {
+            Some(..) => {
+                let magic = try!(config.string("magic"));
+                if magic.is_empty() {
+                    return Err(Error::Invalid("'magic' header must not be empty".to_string()));
+                }
+                Ok(input.magic(magic.as_bytes()))
+            }
+            None => Ok(input),
+        }
+    }
+}
+
+impl TcpInput {
+    /// Creates the input accepting on the already bound listening socket, which takes connections
+    /// even before the input runs.
+    pub fn from_listener(listener: TcpListener) -> TcpInput {
+        let input = match listener.local_addr() {
+            Ok(addr) => TcpInput::new(addr.ip().to_string(), addr.port()),
+            Err(..) => TcpInput::new("::".to_string(), 0),
+        };
+
+        *input.inherited.lock().unwrap() = Some(listener);
+        input
+    }
+}
+
+impl FromRawFd for TcpInput {
+    /// Creates the input accepting on the inherited listening socket.
+    unsafe fn from_raw_fd(fd: RawFd) -> TcpInput {
+        TcpInput::from_listener(TcpListener::from_raw_fd(fd))
+    }
+}
+
+impl Input for TcpInput {
+    fn check(&self) -> Result<(), String> {
+        if self.inherited.lock().unwrap().is_some() {
+            return Ok(());
+        }
+
+        let host: &str = &self.host;
         match TcpListener::bind((host, self.port)) {
-            Ok(listener) => {
-                for stream in listener.incoming() {
-                    match stream {
-                        Ok(stream) => {
-                            let tx = tx.clone();
-                            let codec = codec.new();
-                            thread::spawn(move || TcpInput::serve(stream, tx, codec));
-                        },
-                        Err(err) => {
-                            warn!(target: "Input::TCP", "error occured while accepting connection: {}", err);
-                        }
+            Ok(..) => Ok(()),
+            Err(err) => Err(format!("unable to bind [{}]:{}: {}", self.host, self.port, err)),
+        }
+    }
+
+    fn run(&self, tx: Sink, codec: Box<Codec>) {
+        const POLL: u32 = 50;
+
+        let listener = match self.listen() {
+            Some(listener) => listener,
+            None => return,
+        };
+
+        if let Err(err) = sys::set_nonblocking(listener.as_raw_fd(), true) {
+            error!(target: "Input::TCP", "unable to poll listening socket: {}", err);
+            return;
+        }
+        *self.listener.lock().unwrap() = Some(listener.as_raw_fd());
+
+        let mut seed = self.seed.unwrap_or_else(|| self.clock.now() | 1);
+        let mut id = 0;
+        while !self.stopped.load(Ordering::SeqCst) {
+            self.expire(self.clock.now());
+
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(err) = sys::set_nonblocking(stream.as_raw_fd(), false) {
+                        warn!(target: "Input::TCP", "dropping accepted connection: {}", err);
+                        continue;
                     }
+
+                    id += 1;
+                    let expired = Arc::new(AtomicBool::new(false));
+                    let closing = Arc::new(AtomicBool::new(false));
+                    let idle = Arc::new(AtomicBool::new(false));
+                    let sent = Arc::new(AtomicUsize::new(0));
+                    if let Ok(clone) = stream.try_clone() {
+                        // Xorshift is enough to keep connections accepted together apart.
+                        let deadline = self.lifetime.map(|(lifetime, jitter)| {
+                            seed ^= seed << 13;
+                            seed ^= seed >> 7;
+                            seed ^= seed << 17;
+                            self.clock.now() + lifetime + seed % (jitter + 1)
+                        });
+
+                        self.connections.lock().unwrap().insert(id, Connection {
+                            stream: clone,
+                            deadline: deadline,
+                            expired: expired.clone(),
+                            closing: closing.clone(),
+                            sent: sent.clone(),
+                            seen: (0, self.clock.now()),
+                            idle: idle.clone(),
+                        });
+                    }
+
+                    let tx = tx.clone().with_sent(sent).with_closing(closing);
+                    let tx = match self.provenance {
+                        Some(ref provenance) => tx.with_provenance(provenance.clone()),
+                        None => tx,
+                    };
+                    let codec = codec.new();
+                    let connections = self.connections.clone();
+                    let connection = id;
+                    let magic = self.magic.clone();
+                    naming::spawn(&format!("conn-{}", peer), move || {
+                        TcpInput::serve(stream, tx, codec, magic, expired, idle);
+                        connections.lock().unwrap().remove(&connection);
+                    });
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => thread::sleep_ms(POLL),
+                Err(err) => {
+                    warn!(target: "Input::TCP", "error occured while accepting connection: {}", err);
                 }
-            },
-            Err(err) => {
-                error!(target: "Input::TCP", "unable to bind: {}", err);
             }
         }
 
+        *self.listener.lock().unwrap() = None;
         info!(target: "Input::TCP", "TCP listener has been stopped");
     }
+
+    fn label(&self) -> Option<String> {
+        Some(self.port.to_string())
+    }
+
+    fn listener(&self) -> Option<RawFd> {
+        *self.listener.lock().unwrap()
+    }
+
+    fn inherit(&self, fd: RawFd) -> Result<(), String> {
+        *self.inherited.lock().unwrap() = Some(unsafe { TcpListener::from_raw_fd(fd) });
+        Ok(())
+    }
+
+    fn stop(&self, drain: u32) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        let connections = self.connections.clone();
+        thread::spawn(move || {
+            thread::sleep_ms(drain);
+            for (_, connection) in connections.lock().unwrap().iter() {
+                let _ = connection.stream.shutdown(Shutdown::Read);
+            }
+        });
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.seed = Some(seed | 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::{Receiver, channel};
+    use std::thread;
+
+    use super::super::super::{Envelope, Origin, Record, RecordItem};
+    use super::super::super::clock::MockClock;
+    use super::super::super::codec::{MessagePack, msgpack};
+    use super::super::super::identity::{FIELD, Identity, Provenance};
+    use super::super::super::metrics;
+    use super::super::super::numeric::NonFinite;
+    use super::super::super::testing::{record, string};
+    use super::super::super::validation::Validation;
+    use super::super::{Input, Sink};
+    use super::TcpInput;
+
+    fn sink(name: &str) -> (Sink, Receiver<Envelope>) {
+        let (tx, rx) = channel();
+        let origin = Origin {
+            input: name.to_string(),
+            validation: Arc::new(Validation::default()),
+            capture: None,
+            projection: None,
+            encoding: None,
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+        (Sink::new(tx, Arc::new(origin)), rx)
+    }
+
+    /// Creates the input on a listener bound to a free port, so that connections are taken before
+    /// it runs.
+    fn bound() -> (TcpInput, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (TcpInput::from_listener(listener), port)
+    }
+
+    /// Waits for the accepting loop to bring about the condition, giving up after two seconds.
+    fn settle<F: Fn() -> bool>(done: F) -> bool {
+        for _ in 0..40 {
+            if done() {
+                return true;
+            }
+            thread::sleep_ms(50);
+        }
+        done()
+    }
+
+    #[test]
+    fn require_magic_header() {
+        let (input, port) = bound();
+        let input = Arc::new(input.magic(b"LDv1"));
+
+        let (sink, rx) = sink("tcp");
+        let running = input.clone();
+        thread::spawn(move || running.run(sink, Box::new(MessagePack::default())));
+
+        let mut stray = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stray.write_all(b"GET ").unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(0, stray.read(&mut buf).unwrap());
+
+        let expected = record(vec![("message", string("hello"))]);
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"LDv1").unwrap();
+        client.write_all(&msgpack::encode(&expected)).unwrap();
+
+        assert_eq!(expected, rx.recv().unwrap().record);
+        input.stop(0);
+    }
+
+    fn seq(id: i64) -> Vec<u8> {
+        msgpack::encode(&record(vec![("seq", RecordItem::I64(id))]))
+    }
+
+    #[test]
+    fn send_goodbye_to_connections_past_lifetime() {
+        let (input, port) = bound();
+        let clock = MockClock::new(0);
+        let input = Arc::new(input.lifetime(1000, 1000).grace(500).clock(Arc::new(clock.clone())));
+
+        let (sink, rx) = sink("lifetime");
+        let running = input.clone();
+        thread::spawn(move || running.run(sink, Box::new(MessagePack::default())));
+
+        let mut clients: Vec<TcpStream> = (0..4).map(|id| {
+            let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            client.write_all(&seq(id)).unwrap();
+            assert_eq!(Some(&RecordItem::I64(id)), rx.recv().unwrap().record.find("seq"));
+            client
+        }).collect();
+
+        // Connections are accepted at the same time, but go away at different ones.
+        let deadlines: Vec<u64> = {
+            let connections = input.connections.lock().unwrap();
+            (1..5).map(|id| connections[&id].deadline.unwrap()).collect()
+        };
+        assert!(deadlines.iter().all(|&deadline| deadline >= 1000 && deadline <= 2000));
+        let first = *deadlines.iter().min().unwrap();
+        assert!(deadlines.iter().any(|&deadline| deadline != first));
+
+        clock.set(first);
+        assert!(settle(|| {
+            input.connections.lock().unwrap().iter().all(|(id, connection)| {
+                (deadlines[*id - 1] <= first) == connection.expired.load(Ordering::SeqCst)
+            })
+        }));
+
+        // Producers see the clean EOF, while records they send meanwhile are still decoded.
+        clock.set(2000);
+        let mut buf = [0; 16];
+        for (id, client) in clients.iter_mut().enumerate().skip(1) {
+            assert_eq!(0, client.read(&mut buf).unwrap());
+            client.write_all(&seq(10 + id as i64)).unwrap();
+        }
+        clients.truncate(1);
+
+        let mut records: Vec<i64> = (0..3).map(|_| rx.recv().unwrap().record.find("seq").unwrap().as_f64().unwrap() as i64).collect();
+        records.sort();
+        assert_eq!(vec![11, 12, 13], records);
+
+        // The producer ignoring the goodbye is closed at the first record boundary after the grace
+        // period, the record being decoded intact.
+        clock.set(2500);
+        assert!(settle(|| {
+            let connections = input.connections.lock().unwrap();
+            connections.len() == 1 && connections.values().all(|connection| connection.closing.load(Ordering::SeqCst))
+        }));
+        let record = seq(5);
+        clients[0].write_all(&record[..2]).unwrap();
+        clients[0].write_all(&record[2..]).unwrap();
+        assert_eq!(Some(&RecordItem::I64(5)), rx.recv().unwrap().record.find("seq"));
+        assert!(settle(|| input.connections.lock().unwrap().is_empty()));
+        assert_eq!(4, metrics::registry().counter("input.lifetime.connections.expired").get());
+        assert_eq!(0, metrics::registry().counter("input.lifetime.connections.failed").get());
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(&seq(20)).unwrap();
+        assert_eq!(Some(&RecordItem::I64(20)), rx.recv().unwrap().record.find("seq"));
+        assert!(rx.try_recv().is_err());
+
+        input.stop(0);
+    }
+
+    #[test]
+    fn close_connections_idle_past_timeout() {
+        let (input, port) = bound();
+        let clock = MockClock::new(0);
+        let input = Arc::new(input.idle_timeout(1000).clock(Arc::new(clock.clone())));
+
+        let (sink, rx) = sink("idle");
+        let running = input.clone();
+        thread::spawn(move || running.run(sink, Box::new(MessagePack::default())));
+
+        let mut silent = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        silent.write_all(&seq(1)).unwrap();
+        assert_eq!(Some(&RecordItem::I64(1)), rx.recv().unwrap().record.find("seq"));
+
+        let mut chatty = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        chatty.write_all(&seq(2)).unwrap();
+        assert_eq!(Some(&RecordItem::I64(2)), rx.recv().unwrap().record.find("seq"));
+
+        clock.set(600);
+        chatty.write_all(&seq(3)).unwrap();
+        assert_eq!(Some(&RecordItem::I64(3)), rx.recv().unwrap().record.find("seq"));
+        assert!(settle(|| input.connections.lock().unwrap()[&2].seen == (2, 600)));
+
+        // Only the connection silent since its first record is closed.
+        clock.set(1100);
+        let mut buf = [0; 16];
+        assert_eq!(0, silent.read(&mut buf).unwrap());
+        assert!(settle(|| input.connections.lock().unwrap().len() == 1));
+        assert_eq!(1, metrics::registry().counter("input.idle.connections.idle").get());
+
+        chatty.write_all(&seq(4)).unwrap();
+        assert_eq!(Some(&RecordItem::I64(4)), rx.recv().unwrap().record.find("seq"));
+
+        input.stop(0);
+    }
+
+    /// Runs the input, returning the receiver of records it decodes.
+    fn start(input: TcpInput, name: &str) -> (Arc<TcpInput>, Receiver<Envelope>) {
+        let input = Arc::new(input);
+        let (sink, rx) = sink(name);
+        let running = input.clone();
+        thread::spawn(move || running.run(sink, Box::new(MessagePack::default())));
+        (input, rx)
+    }
+
+    /// Sends the record to the input listening on the port, returning what it has decoded.
+    fn forward(port: u16, rx: &Receiver<Envelope>, record: &Record) -> Record {
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(&msgpack::encode(record)).unwrap();
+        rx.recv().unwrap().record
+    }
+
+    fn hops(record: &Record) -> Vec<(String, f64)> {
+        match record.find(FIELD) {
+            Some(&RecordItem::Array(ref hops)) => hops.iter().map(|hop| {
+                (hop.find("instance").unwrap().as_str().unwrap().to_string(), hop.find("received").unwrap().as_f64().unwrap())
+            }).collect(),
+            other => panic!("unexpected provenance: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn append_provenance_hops_of_forwarding_instances() {
+        let edge = Identity::new("edge", "{}");
+        let central = Identity::new("central", "{\"outputs\": []}");
+        let capped = metrics::registry().counter("pipeline.provenance.capped");
+
+        let ((edge_input, edge_port), (central_input, central_port), (plain_input, plain_port)) = (bound(), bound(), bound());
+        let provenance = |identity: &Identity, at: u64| {
+            Provenance::new(Arc::new(identity.clone()), 2).clock(Arc::new(MockClock::new(at)))
+        };
+        let (first, first_rx) = start(edge_input.provenance(provenance(&edge, 1000)), "edge");
+        let (second, second_rx) = start(central_input.provenance(provenance(&central, 2000)), "central");
+        let (plain, plain_rx) = start(plain_input, "plain");
+
+        // The sender's own idea of the provenance is no more than a hop it has appended.
+        let payload = record(vec![("message", string("hello"))]);
+        let received = forward(edge_port, &first_rx, &payload);
+        let received = forward(central_port, &second_rx, &received);
+        assert_eq!(vec![(edge.stamp(), 1.0), (central.stamp(), 2.0)], hops(&received));
+        assert_eq!(Some(&string("hello")), received.find("message"));
+
+        // Past the cap the edge hop stays, while the following ones make room for the last.
+        let before = capped.get();
+        let looped = forward(central_port, &second_rx, &received);
+        assert_eq!(vec![(edge.stamp(), 1.0), (central.stamp(), 2.0)], hops(&looped));
+        assert_eq!(before + 1, capped.get());
+
+        // Without provenance records pass untouched.
+        assert_eq!(payload, forward(plain_port, &plain_rx, &payload));
+
+        for input in vec![first, second, plain].into_iter() {
+            input.stop(0);
+        }
+    }
 }
@@ -1,11 +1,23 @@
 use std::char;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::i64;
+
+/// Maximum nesting of arrays and objects built into values.
+const MAX_DEPTH: usize = 128;
+
+/// Exponent digits beyond this value make any number overflow anyway.
+const MAX_EXPONENT: usize = 1000;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Null,
     Bool(bool),
     F64(f64),
+    /// Integer written without a fraction nor an exponent, kept exactly.
+    I64(i64),
+    /// Integer above `i64::MAX`.
+    U64(u64),
     String(String),
     List(Vec<Value>),
     Object(BTreeMap<String, Value>),
@@ -18,6 +30,26 @@ impl Value {
             _ => None
         }
     }
+
+    /// Returns the number of any representation as a float.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::F64(v) => Some(v),
+            Value::I64(v) => Some(v as f64),
+            Value::U64(v) => Some(v as f64),
+            _ => None,
+        }
+    }
+
+    /// Returns the number if it's a non-negative integer, whatever its representation.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::F64(v) if v >= 0.0 && v.fract() == 0.0 && v < 18446744073709551616.0 => Some(v as u64),
+            Value::I64(v) if v >= 0 => Some(v as u64),
+            Value::U64(v) => Some(v),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -36,6 +68,7 @@ pub enum Error {
     InvalidUnicodeCodePoint,
     LoneLeadingSurrogateInHexEscape,
     UnexpectedEndOfHexEscape,
+    NestingTooDeep,                     // Arrays and objects are nested deeper than allowed.
     ToDo,
 }
 
@@ -74,6 +107,8 @@ pub enum JsonEvent { // TODO: Rename to Event.
     NullValue,
     BooleanValue(bool),
     NumberValue(f64),
+    I64Value(i64),
+    U64Value(u64),
     StringValue(String),
     ArrayBegin,
     ArrayEnd,
@@ -115,6 +150,7 @@ impl<T: Iterator<Item = char>> Parser<T> {
     fn parse(&mut self) -> Option<JsonEvent> {
         match self.state {
             ParserState::Undefined => {
+                self.whitespaces();
                 if self.eof() {
                     None
                 } else {
@@ -244,7 +280,7 @@ impl<T: Iterator<Item = char>> Parser<T> {
 
     fn parse_number(&mut self) -> JsonEvent {
         match self.parse_number_impl() {
-            Ok(result) => { JsonEvent::NumberValue(result) }
+            Ok(result) => result,
             Err(error) => {
                 self.state = ParserState::Broken;
                 JsonEvent::Error(error)
@@ -252,7 +288,9 @@ impl<T: Iterator<Item = char>> Parser<T> {
         }
     }
 
-    fn parse_number_impl(&mut self) -> Result<f64, ParserError> {
+    /// Parses the number, keeping integers without a fraction nor an exponent exact, as long as
+    /// they fit 64 bits.
+    fn parse_number_impl(&mut self) -> Result<JsonEvent, ParserError> {
         let negative = if self.char() == '-' {
             self.bump();
             true
@@ -261,7 +299,9 @@ impl<T: Iterator<Item = char>> Parser<T> {
         };
 
         // Parse integer values until EOF or non-integer value found.
-        let mut integer = 0;
+        let mut integer = 0u64;
+        let mut overflow = false;
+        let mut approximate = 0.0;
         match self.char() {
             '0' => {
                 self.bump();
@@ -275,8 +315,12 @@ impl<T: Iterator<Item = char>> Parser<T> {
                 while !self.eof() {
                     match self.char() {
                         c @ '0'...'9' => {
-                            integer *= 10;
-                            integer += ((c as isize) - ('0' as isize)) as u64;
+                            let digit = ((c as isize) - ('0' as isize)) as u64;
+                            match integer.checked_mul(10).and_then(|integer| integer.checked_add(digit)) {
+                                Some(value) => integer = value,
+                                None => overflow = true,
+                            }
+                            approximate = approximate * 10.0 + digit as f64;
                         }
                         _ => break,
                     }
@@ -290,6 +334,8 @@ impl<T: Iterator<Item = char>> Parser<T> {
             }
         };
 
+        let exact = !overflow && !(self.char() == '.' || self.char() == 'e' || self.char() == 'E');
+
         // Parse decimal.
         let mut decimal = 0.0;
         if self.char() == '.' {
@@ -314,10 +360,10 @@ impl<T: Iterator<Item = char>> Parser<T> {
             }
         }
 
-        let mantissa = integer as f64 + decimal;
+        let mantissa = if overflow { approximate } else { integer as f64 } + decimal;
 
         // Parse exponent.
-        let mut exponent = 0;
+        let mut exponent = 0usize;
 //        let mut negative_exponent = false;
 
         match self.char() {
@@ -341,8 +387,7 @@ impl<T: Iterator<Item = char>> Parser<T> {
                 while !self.eof() {
                     match self.char() {
                         c @ '0'...'9' => {
-                            exponent *= 10;
-                            exponent += (c as usize) - ('0' as usize);
+                            exponent = cmp::min(exponent * 10 + (c as usize) - ('0' as usize), MAX_EXPONENT);
                         }
                         _ => break
                     }
@@ -364,10 +409,26 @@ impl<T: Iterator<Item = char>> Parser<T> {
             }
         }
 
-        return Ok(match negative {
+        if exact {
+            if !negative {
+                return Ok(match integer {
+                    v if v > i64::MAX as u64 => JsonEvent::U64Value(v),
+                    v => JsonEvent::I64Value(v as i64),
+                });
+            }
+
+            if integer <= i64::MAX as u64 {
+                return Ok(JsonEvent::I64Value(-(integer as i64)));
+            }
+            if integer == i64::MAX as u64 + 1 {
+                return Ok(JsonEvent::I64Value(i64::MIN));
+            }
+        }
+
+        return Ok(JsonEvent::NumberValue(match negative {
             true  => -result,
             false => result
-        });
+        }));
     }
 
     fn parse_string(&mut self) -> JsonEvent {
@@ -446,6 +507,96 @@ impl<T: Iterator<Item = char>> Parser<T> {
         }
     }
 
+    /// Skips the value of the object key just parsed without building it.
+    ///
+    /// Nested arrays and objects are skipped by counting brackets and strings by looking for the
+    /// closing quote, so neither scalars nor the bracket kinds are validated.
+    pub fn skip_value(&mut self) -> Result<(), ParserError> {
+        if self.state != ParserState::ParseObjectPair {
+            return Err(ParserError::BrokenParser);
+        }
+
+        if self.handled {
+            self.handled = false;
+            self.bump();
+        }
+
+        self.whitespaces();
+        if self.eof() {
+            return Err(self.fail(Error::EOFWhileParsingObjectColon));
+        }
+        if self.char() != ':' {
+            return Err(self.fail(Error::ExpectedColon));
+        }
+
+        self.bump();
+        self.whitespaces();
+
+        let mut depth = 0usize;
+        let mut scanned = false;
+        loop {
+            if self.eof() {
+                return Err(self.fail(Error::EOFWhileParsingObjectValue));
+            }
+
+            match self.char() {
+                '"' => {
+                    try!(self.skip_string());
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                '[' | '{' => depth += 1,
+                ']' | '}' if depth > 0 => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                ',' | '}' | ']' | ' ' | '\n' | '\t' | '\r' if depth == 0 => {
+                    if !scanned {
+                        return Err(self.fail(Error::ExpectedValue));
+                    }
+
+                    // The character ends the scalar, but belongs to the enclosing object.
+                    self.state = ParserState::ParseObjectMaybe;
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            scanned = true;
+            self.bump();
+        }
+
+        self.state = ParserState::ParseObjectMaybe;
+        self.handled = true;
+        Ok(())
+    }
+
+    /// Moves to the closing quote of the string starting at the current character.
+    fn skip_string(&mut self) -> Result<(), ParserError> {
+        self.bump();
+        loop {
+            if self.eof() {
+                return Err(self.fail(Error::EOFWhileParsingString));
+            }
+
+            match self.char() {
+                '"' => return Ok(()),
+                '\\' => self.bump(),
+                _ => {}
+            }
+
+            self.bump();
+        }
+    }
+
+    fn fail(&mut self, error: Error) -> ParserError {
+        self.state = ParserState::Broken;
+        ParserError::SyntaxError(error)
+    }
+
     fn complete(&mut self, ident: &str, value: JsonEvent) -> JsonEvent {
         if ident.chars().all(|c| Some(c) == self.next_char()) {
             self.handled = true;
@@ -526,16 +677,19 @@ impl<T: Iterator<Item = char>> Iterator for Parser<T> {
     }
 }
 
+/// Iterator over top-level values of the stream.
+///
+/// Values are built with the nesting limited, as everything else here comes from untrusted input.
 pub struct Builder<T> {
     parser: Parser<T>,
-    arrays: Vec<bool>
+    finished: bool,
 }
 
 impl<T: Iterator<Item = char>> Builder<T> {
     pub fn new(src: T) -> Builder<T> {
         Builder {
             parser: Parser::new(src),
-            arrays: Vec::new()
+            finished: false,
         }
     }
 }
@@ -544,55 +698,141 @@ impl<T: Iterator<Item = char>> Iterator for Builder<T> {
     type Item = Value;
 
     fn next(&mut self) -> Option<Value> {
-        match self.parser.next() {
-            Some(JsonEvent::NullValue) => Some(Value::Null),
-            Some(JsonEvent::BooleanValue(v)) => Some(Value::Bool(v)),
-            Some(JsonEvent::NumberValue(v)) => Some(Value::F64(v)),
-            Some(JsonEvent::StringValue(v)) => Some(Value::String(v)),
-            Some(JsonEvent::ArrayBegin) => {
-                let mut array = Vec::new();
-                self.arrays.push(false);
-                loop {
-                    let element = match self.next() {
-                        Some(v) => v,
-                        None => {
-                            if *self.arrays.last().unwrap() {
-                                self.arrays.pop();
-                                return Some(Value::List(array));
-                            } else {
-                                return None;
-                            }
-                        }
-                    };
-                    array.push(element);
-                }
+        if self.finished {
+            return None;
+        }
+
+        // Malformed input ends the stream, as there is no way to resynchronize.
+        let value = match self.parser.next() {
+            Some(event) => build(event, &mut self.parser, 0).ok(),
+            None => None,
+        };
+        self.finished = value.is_none();
+        value
+    }
+}
+
+/// Parses a single JSON document, failing on syntax errors and trailing data instead of panicking.
+pub fn from_str(src: &str) -> Result<Value, ParserError> {
+    let mut parser = Parser::new(src.chars());
+    let value = match parser.next() {
+        Some(event) => try!(build(event, &mut parser, 0)),
+        None => return Err(ParserError::SyntaxError(Error::ExpectedValue)),
+    };
+
+    match parser.next() {
+        None => Ok(value),
+        Some(JsonEvent::Error(err)) => Err(err),
+        Some(..) => Err(ParserError::SyntaxError(Error::ToDo)),
+    }
+}
+
+/// Parses the first JSON document of the source, ignoring anything after it.
+///
+/// Returns `None` if the source holds nothing but whitespace.
+pub fn first(src: &str) -> Result<Option<Value>, ParserError> {
+    let mut parser = Parser::new(src.chars());
+    match parser.next() {
+        Some(event) => build(event, &mut parser, 0).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Parses the next top-level value of the stream, building only the members of a top-level
+/// object the callback keeps and skipping the others.
+///
+/// Returns `None` once the stream is over.
+pub fn project<T, F>(parser: &mut Parser<T>, keep: F) -> Option<Result<Value, ParserError>>
+    where T: Iterator<Item = char>,
+          F: Fn(&str) -> bool
+{
+    match parser.next() {
+        Some(JsonEvent::ObjectBegin) => {}
+        Some(event) => return Some(build(event, parser, 0)),
+        None => return None,
+    }
+
+    let mut object = BTreeMap::new();
+    loop {
+        let key = match parser.next() {
+            Some(JsonEvent::ObjectEnd) => return Some(Ok(Value::Object(object))),
+            Some(JsonEvent::StringValue(key)) => key,
+            Some(JsonEvent::Error(err)) => return Some(Err(err)),
+            Some(..) => return Some(Err(ParserError::SyntaxError(Error::ExpectedKeyOrObjectEnd))),
+            None => return Some(Err(ParserError::SyntaxError(Error::EOFWhileParsingObject))),
+        };
+
+        if !keep(&key) {
+            if let Err(err) = parser.skip_value() {
+                return Some(Err(err));
+            }
+            continue;
+        }
+
+        let value = match parser.next() {
+            Some(event) => build(event, parser, 1),
+            None => Err(ParserError::SyntaxError(Error::EOFWhileParsingObjectValue)),
+        };
+
+        match value {
+            Ok(value) => {
+                object.insert(key, value);
             }
-            Some(JsonEvent::ObjectBegin) => {
-                let mut object = BTreeMap::new();
-                loop {
-                    let key = match self.parser.next().unwrap() {
-                        JsonEvent::StringValue(v) => v,
-                        JsonEvent::ObjectEnd => return Some(Value::Object(object)),
-                        _ => panic!("parse error - must be key or object end")
-                    };
-                    let value = self.next().unwrap();
-                    object.insert(key, value);
+            Err(err) => return Some(Err(err)),
+        }
+    }
+}
+
+fn build<T: Iterator<Item = char>>(event: JsonEvent, parser: &mut Parser<T>, depth: usize) -> Result<Value, ParserError> {
+    if depth > MAX_DEPTH {
+        return Err(ParserError::SyntaxError(Error::NestingTooDeep));
+    }
+
+    match event {
+        JsonEvent::NullValue => Ok(Value::Null),
+        JsonEvent::BooleanValue(v) => Ok(Value::Bool(v)),
+        JsonEvent::NumberValue(v) => Ok(Value::F64(v)),
+        JsonEvent::I64Value(v) => Ok(Value::I64(v)),
+        JsonEvent::U64Value(v) => Ok(Value::U64(v)),
+        JsonEvent::StringValue(v) => Ok(Value::String(v)),
+        JsonEvent::ArrayBegin => {
+            let mut list = Vec::new();
+            loop {
+                match parser.next() {
+                    Some(JsonEvent::ArrayEnd) => return Ok(Value::List(list)),
+                    Some(event) => list.push(try!(build(event, parser, depth + 1))),
+                    None => return Err(ParserError::SyntaxError(Error::EOFWhileParsingArray)),
                 }
             }
-            Some(JsonEvent::ArrayEnd) => {
-                *self.arrays.last_mut().unwrap() = true;
-                return None;
+        }
+        JsonEvent::ObjectBegin => {
+            let mut object = BTreeMap::new();
+            loop {
+                let key = match parser.next() {
+                    Some(JsonEvent::ObjectEnd) => return Ok(Value::Object(object)),
+                    Some(JsonEvent::StringValue(key)) => key,
+                    Some(JsonEvent::Error(err)) => return Err(err),
+                    Some(..) => return Err(ParserError::SyntaxError(Error::ExpectedKeyOrObjectEnd)),
+                    None => return Err(ParserError::SyntaxError(Error::EOFWhileParsingObject)),
+                };
+
+                let value = match parser.next() {
+                    Some(event) => try!(build(event, parser, depth + 1)),
+                    None => return Err(ParserError::SyntaxError(Error::EOFWhileParsingObjectValue)),
+                };
+                object.insert(key, value);
             }
-            Some(JsonEvent::ObjectEnd) => unreachable!(),
-            Some(JsonEvent::Error(err)) => panic!(err),
-            None => None
         }
+        JsonEvent::ArrayEnd | JsonEvent::ObjectEnd => Err(ParserError::SyntaxError(Error::ExpectedValue)),
+        JsonEvent::Error(err) => Err(err),
     }
 }
 
 #[cfg(test)]
 mod testing {
 
+use std::collections::BTreeMap;
+
 use super::*;
 
 #[test]
@@ -1008,6 +1248,50 @@ fn parse_null() {
 //    assert_eq!(Some(Error(BrokenParser)), parser.next());
 //}
 
+#[test]
+fn parse_surrounded_by_whitespaces() {
+    let mut parser = Parser::new(" \n null \n".chars());
+
+    assert_eq!(Some(JsonEvent::NullValue), parser.next());
+    assert_eq!(None, parser.next());
+}
+
+#[test]
+fn from_str_object() {
+    let mut expected = BTreeMap::new();
+    expected.insert("k1".to_string(), Value::List(vec![Value::I64(42), Value::Bool(true)]));
+    expected.insert("k2".to_string(), Value::String("v2".to_string()));
+
+    assert_eq!(Ok(Value::Object(expected)), from_str(r#"{"k1": [42, true], "k2": "v2"}"#));
+}
+
+#[test]
+fn parse_integers_exactly() {
+    assert_eq!(Ok(Value::I64(9007199254740993)), from_str("9007199254740993"));
+    assert_eq!(Ok(Value::I64(-9223372036854775808)), from_str("-9223372036854775808"));
+    assert_eq!(Ok(Value::U64(18446744073709551615)), from_str("18446744073709551615"));
+    // Only integers fitting 64 bits are kept exactly.
+    match from_str("18446744073709551616") {
+        Ok(Value::F64(..)) => {}
+        value => panic!("expected float, found {:?}", value),
+    }
+    assert_eq!(Ok(Value::F64(-9223372036854775809.0)), from_str("-9223372036854775809"));
+    assert_eq!(Ok(Value::F64(42.0)), from_str("42.0"));
+    assert_eq!(Ok(Value::F64(4200.0)), from_str("42e2"));
+}
+
+#[test]
+fn from_str_fails_on_truncated_input() {
+    assert!(from_str(r#"{"k1": [42"#).is_err());
+    assert!(from_str(r#"{"k1""#).is_err());
+    assert!(from_str("").is_err());
+}
+
+#[test]
+fn from_str_fails_on_trailing_data() {
+    assert!(from_str("null null").is_err());
+}
+
 // Builder test case.
 
 #[test]
@@ -0,0 +1,203 @@
+//! Records decoded lazily, a field at a time.
+//!
+//! Routes and filters often look at two or three fields of records carrying dozens of them. A
+//! lazy record keeps the payload it came in together with an index of its top-level fields,
+//! decoding a field only once it's looked up through `Record::find` and caching it. Anything
+//! iterating over all fields decodes them all at once, while modifying the record materializes it
+//! into the decoded form first, after which it is like any other record.
+//!
+//! The index is built by a single pass over the payload checking everything decoding would, so
+//! fields never fail to decode on access. The same pass computes the size hint and looks for
+//! non-finite floats, so that records are judged on arrival without decoding them. Payloads the
+//! index can't describe exactly, e.g. maps with duplicate keys, are decoded eagerly instead.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use super::RecordItem;
+use super::codec::msgpack;
+use super::codec::msgpack::Extensions;
+
+/// Format of the payload together with the settings its values are decoded with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// MessagePack map, with strings kept within the payload if shared, see `Text`.
+    MessagePack(Extensions, bool),
+}
+
+/// Top-level field of the payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    /// Byte range of the name, being valid UTF-8.
+    pub name: (usize, usize),
+    /// Byte range of the value.
+    pub value: (usize, usize),
+    /// Size hint of the decoded value, see `RecordItem::size_hint`.
+    pub hint: usize,
+    /// Whether the value holds no NaN or infinite float.
+    pub finite: bool,
+}
+
+/// Payload of a record with its fields indexed, see the module documentation.
+pub struct Lazy {
+    payload: Arc<Vec<u8>>,
+    format: Format,
+    fields: Vec<Field>,
+    /// Values decoded so far by the position of their field.
+    ///
+    /// Boxed values are never replaced nor dropped before the record is either dropped or
+    /// materialized, which both take it by value, so references to them outlive the lock.
+    values: Mutex<Vec<Option<Box<RecordItem>>>>,
+    /// All fields decoded at once, with the same guarantee as for values.
+    all: Mutex<Option<Box<HashMap<String, RecordItem>>>>,
+}
+
+impl Lazy {
+    /// Wraps the payload with its fields indexed, which must have been checked to decode
+    /// successfully and to have unique names.
+    pub fn new(payload: Arc<Vec<u8>>, format: Format, fields: Vec<Field>) -> Lazy {
+        let values = fields.iter().map(|_| None).collect();
+        Lazy {
+            payload: payload,
+            format: format,
+            fields: fields,
+            values: Mutex::new(values),
+            all: Mutex::new(None),
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Returns the size hint of the decoded record, see `Record::size_hint`.
+    pub fn size_hint(&self) -> usize {
+        self.fields.iter().fold(5, |size, field| size + field.name.1 - field.name.0 + 5 + field.hint)
+    }
+
+    /// Returns whether no value holds NaN or infinite floats.
+    pub fn is_finite(&self) -> bool {
+        self.fields.iter().all(|field| field.finite)
+    }
+
+    /// Returns the value of the field, decoding it on the first access.
+    pub fn find(&self, name: &str) -> Option<&RecordItem> {
+        let id = match self.fields.iter().position(|field| self.name(field) == name.as_bytes()) {
+            Some(id) => id,
+            None => return None,
+        };
+
+        let mut values = self.values.lock().unwrap();
+        if values[id].is_none() {
+            values[id] = Some(Box::new(self.decode(&self.fields[id])));
+        }
+
+        let value: *const RecordItem = &**values[id].as_ref().unwrap();
+        Some(unsafe { &*value })
+    }
+
+    /// Returns all fields, decoding them on the first call.
+    pub fn all(&self) -> &HashMap<String, RecordItem> {
+        let mut all = self.all.lock().unwrap();
+        if all.is_none() {
+            let values = self.values.lock().unwrap();
+            let fields = self.fields.iter().zip(values.iter()).map(|(field, value)| {
+                let value = match *value {
+                    Some(ref value) => (**value).clone(),
+                    None => self.decode(field),
+                };
+                (String::from_utf8_lossy(self.name(field)).into_owned(), value)
+            }).collect();
+            *all = Some(Box::new(fields));
+        }
+
+        let all: *const HashMap<String, RecordItem> = &**all.as_ref().unwrap();
+        unsafe { &*all }
+    }
+
+    /// Decodes the record, reusing values decoded so far.
+    pub fn into_fields(self) -> HashMap<String, RecordItem> {
+        if let Some(all) = self.all.lock().unwrap().take() {
+            return *all;
+        }
+
+        let values = mem::replace(&mut *self.values.lock().unwrap(), Vec::new());
+        self.fields.iter().zip(values.into_iter()).map(|(field, value)| {
+            let value = match value {
+                Some(value) => *value,
+                None => self.decode(field),
+            };
+            (String::from_utf8_lossy(self.name(field)).into_owned(), value)
+        }).collect()
+    }
+
+    fn name(&self, field: &Field) -> &[u8] {
+        &self.payload[field.name.0..field.name.1]
+    }
+
+    fn decode(&self, field: &Field) -> RecordItem {
+        match self.format {
+            Format::MessagePack(extensions, shared) => msgpack::field(&self.payload, field.value, &extensions, shared),
+        }
+    }
+}
+
+impl Clone for Lazy {
+    fn clone(&self) -> Lazy {
+        Lazy {
+            payload: self.payload.clone(),
+            format: self.format,
+            fields: self.fields.clone(),
+            values: Mutex::new(self.values.lock().unwrap().clone()),
+            all: Mutex::new(self.all.lock().unwrap().clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::super::{Record, RecordItem};
+    use super::super::codec::{Codec, MessagePack};
+    use super::super::codec::msgpack;
+    use super::super::testing::string;
+
+    fn lazy(record: &Record) -> Record {
+        let codec = MessagePack::default().lazy(true);
+        codec.decode(Box::new(Cursor::new(msgpack::encode(record)))).next().unwrap()
+    }
+
+    #[test]
+    fn materialize_lazy_record_on_modification() {
+        let mut eager = Record::new();
+        eager.insert("message".to_string(), string("le message"));
+        eager.insert("status".to_string(), RecordItem::I64(200));
+        eager.insert("latency".to_string(), RecordItem::F64(0.25));
+
+        let mut record = lazy(&eager);
+        assert!(record.payload().is_some());
+        assert_eq!(Some(&RecordItem::I64(200)), record.find("status"));
+        assert_eq!(None, record.find("missing"));
+        assert_eq!(3, record.len());
+        assert_eq!(eager.size_hint(), record.size_hint());
+
+        // Iterating decodes everything, still keeping the payload.
+        assert_eq!(eager, record);
+        assert!(record.payload().is_some());
+
+        let copy = record.clone();
+        record.insert("status".to_string(), RecordItem::I64(404));
+        assert_eq!(None, record.payload());
+        assert_eq!(Some(&RecordItem::I64(404)), record.find("status"));
+        assert_eq!(Some(&string("le message")), record.find("message"));
+
+        assert!(copy.payload().is_some());
+        assert_eq!(eager, copy);
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{Record, RecordItem};
+use super::clock::Clock;
+use super::config::{Config, Error as ConfigError, FromConfig};
+use super::diff::{self, Change};
+use super::metrics;
+use super::metrics::Counter;
+use super::output::{Error, Output};
+use super::snapshot::Snapshot;
+
+/// Field holding the lineage of a record, see `Pipeline::set_lineage`.
+pub const FIELD: &'static str = "_lineage";
+
+/// Limits keeping the lineage small whatever records go through.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Whether every filter is traced, rather than only the ones opted in.
+    pub all: bool,
+    /// Maximum number of entries per record, further changes are only counted.
+    pub entries: usize,
+    /// Maximum number of fields listed per entry.
+    pub fields: usize,
+    /// Maximum length of previous string values kept, longer ones are left out.
+    pub value: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            all: false,
+            entries: 16,
+            fields: 8,
+            value: 64,
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, ConfigError> {
+        let default = Settings::default();
+        Ok(Settings {
+            all: try!(config.bool_or("all", default.all)),
+            entries: try!(config.u64_or("entries", default.entries as u64)) as usize,
+            fields: try!(config.u64_or("fields", default.fields as u64)) as usize,
+            value: try!(config.u64_or("value", default.value as u64)) as usize,
+        })
+    }
+}
+
+/// Appends entries telling which step touched which fields of a record to its `_lineage` array.
+///
+/// An entry is an object with the step `name`, the dot-separated paths of the touched `fields`
+/// and the `previous` values of changed or removed ones, as long as they are scalars short enough
+/// to be worth keeping. Fields over the per-entry limit are only counted in `more`, while entries
+/// over the per-record limit are dropped and counted in the `pipeline.lineage.capped` metric.
+#[derive(Clone)]
+pub struct Lineage {
+    settings: Settings,
+    capped: Counter,
+}
+
+impl Lineage {
+    pub fn new(settings: Settings) -> Lineage {
+        Lineage {
+            settings: settings,
+            capped: metrics::registry().counter("pipeline.lineage.capped"),
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Appends the entry for changes the step has made to the record.
+    pub fn note(&self, name: &str, changes: &[diff::Entry], record: &mut Record) {
+        if changes.is_empty() {
+            return;
+        }
+
+        let mut lineage = match record.remove(FIELD) {
+            Some(RecordItem::Array(lineage)) => lineage,
+            Some(..) | None => Vec::new(),
+        };
+
+        if lineage.len() >= self.settings.entries {
+            self.capped.inc();
+        } else {
+            lineage.push(self.entry(name, changes));
+        }
+        record.insert(FIELD.to_string(), RecordItem::Array(lineage));
+    }
+
+    fn entry(&self, name: &str, changes: &[diff::Entry]) -> RecordItem {
+        let mut fields = Vec::new();
+        let mut previous = HashMap::new();
+        for change in changes.iter().take(self.settings.fields) {
+            let mut path = String::new();
+            for (id, name) in change.path.iter().enumerate() {
+                if id > 0 {
+                    path.push('.');
+                }
+                path.push_str(name);
+            }
+            match change.change {
+                Change::Changed(ref value, _) | Change::Removed(ref value) if self.small(value) => {
+                    previous.insert(path.clone(), value.clone());
+                }
+                _ => {}
+            }
+            fields.push(RecordItem::String(path));
+        }
+
+        let mut entry = HashMap::new();
+        entry.insert("name".to_string(), RecordItem::String(name.to_string()));
+        entry.insert("fields".to_string(), RecordItem::Array(fields));
+        if !previous.is_empty() {
+            entry.insert("previous".to_string(), RecordItem::Object(previous));
+        }
+        if changes.len() > self.settings.fields {
+            entry.insert("more".to_string(), RecordItem::F64((changes.len() - self.settings.fields) as f64));
+        }
+
+        RecordItem::Object(entry)
+    }
+
+    fn small(&self, value: &RecordItem) -> bool {
+        match *value {
+            RecordItem::Null | RecordItem::Bool(..) | RecordItem::F64(..) | RecordItem::I64(..) | RecordItem::U64(..) => true,
+            RecordItem::String(..) | RecordItem::Text(..) => value.as_str().unwrap().len() <= self.settings.value,
+            RecordItem::Bytes(..) | RecordItem::Array(..) | RecordItem::Object(..) => false,
+        }
+    }
+}
+
+/// Output wrapper removing the lineage from records before feeding the wrapped output, for
+/// destinations not meant to see it.
+pub struct Strip {
+    output: Box<Output>,
+}
+
+impl Strip {
+    pub fn new(output: Box<Output>) -> Strip {
+        Strip {
+            output: output,
+        }
+    }
+}
+
+impl Output for Strip {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        if payload.find(FIELD).is_none() {
+            return self.output.feed(payload);
+        }
+
+        let mut payload = payload.clone();
+        payload.remove(FIELD);
+        self.output.feed(&payload)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.output.flush()
+    }
+
+    fn discard(&mut self) -> usize {
+        self.output.discard()
+    }
+
+    fn probe(&mut self) -> bool {
+        self.output.probe()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.output.check()
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        self.output.describe_fields()
+    }
+
+    fn target(&self) -> Option<String> {
+        self.output.target()
+    }
+
+    fn oversize(&self) -> bool {
+        self.output.oversize()
+    }
+
+    fn ordered(&self) -> bool {
+        self.output.ordered()
+    }
+
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        self.output.state()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.output.reseed(seed);
+    }
+
+    fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.output.set_clock(clock);
+    }
+
+    fn sort_keys(&mut self) {
+        self.output.sort_keys();
+    }
+
+    fn typename(&self) -> &'static str {
+        self.output.typename()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::super::{Record, RecordItem};
+    use super::super::codec::json;
+    use super::super::filter::{Chain, CopyFields, EscapeReserved, Existing, Filter, NumberNormalize, Numeric, Rounding};
+    use super::super::output::Output;
+    use super::super::testing::{Collector, record, string};
+    use super::{FIELD, Lineage, Settings, Strip};
+
+    fn filters() -> Vec<Box<Filter>> {
+        let mut renames = HashMap::new();
+        renames.insert("_id".to_string(), "source_id".to_string());
+
+        vec![
+            Box::new(EscapeReserved::new(renames, Vec::new())) as Box<Filter>,
+            Box::new(NumberNormalize::new(vec!["size".to_string()], Numeric::Int(Rounding::Nearest))) as Box<Filter>,
+            Box::new(CopyFields::new(vec![("source_id".to_string(), "key".to_string())], Existing::Overwrite)) as Box<Filter>,
+        ]
+    }
+
+    fn names(names: &[Option<&str>]) -> Vec<Option<String>> {
+        names.iter().map(|name| name.map(|name| name.to_string())).collect()
+    }
+
+    fn entry(name: &str, fields: &[&str], previous: Vec<(&str, RecordItem)>) -> RecordItem {
+        let mut entry = HashMap::new();
+        entry.insert("name".to_string(), string(name));
+        entry.insert("fields".to_string(), RecordItem::Array(fields.iter().map(|field| string(field)).collect()));
+        if !previous.is_empty() {
+            let previous = previous.into_iter().map(|(path, value)| (path.to_string(), value)).collect();
+            entry.insert("previous".to_string(), RecordItem::Object(previous));
+        }
+        RecordItem::Object(entry)
+    }
+
+    fn payload() -> Record {
+        record(vec![("_id", string("a1")), ("size", string("10.4")), ("message", string("le message"))])
+    }
+
+    #[test]
+    fn trace_rename_coerce_and_derive() {
+        let mut chain = Chain::new(filters());
+        chain.trace(Lineage::new(Settings::default()),
+            names(&[Some("escape_reserved"), Some("number_normalize"), Some("copy_fields")]));
+
+        let mut expected = record(vec![
+            ("source_id", string("a1")), ("size", RecordItem::F64(10.0)), ("message", string("le message")),
+            ("key", string("a1")),
+        ]);
+        expected.insert(FIELD.to_string(), RecordItem::Array(vec![
+            entry("escape_reserved", &["_id", "source_id"], vec![("_id", string("a1"))]),
+            entry("number_normalize", &["size"], vec![("size", string("10.4"))]),
+            entry("copy_fields", &["key"], Vec::new()),
+        ]));
+        assert_eq!(vec![expected], chain.apply(payload()));
+    }
+
+    #[test]
+    fn cap_entries_and_fields() {
+        let settings = Settings { entries: 2, fields: 1, value: 2, ..Settings::default() };
+        let mut chain = Chain::new(filters());
+        chain.trace(Lineage::new(settings),
+            names(&[Some("escape_reserved"), Some("number_normalize"), Some("copy_fields")]));
+
+        let mut rename = HashMap::new();
+        rename.insert("name".to_string(), string("escape_reserved"));
+        rename.insert("fields".to_string(), RecordItem::Array(vec![string("_id")]));
+        rename.insert("previous".to_string(), RecordItem::Object(vec![("_id".to_string(), string("a1"))].into_iter().collect()));
+        rename.insert("more".to_string(), RecordItem::F64(1.0));
+
+        let records = chain.apply(payload());
+        let expected = RecordItem::Array(vec![
+            RecordItem::Object(rename),
+            // Too long a previous value to be kept.
+            entry("number_normalize", &["size"], Vec::new()),
+        ]);
+        assert_eq!(Some(&expected), records[0].find(FIELD));
+    }
+
+    #[test]
+    fn leave_records_intact_without_tracing() {
+        let mut plain = Chain::new(filters());
+        let mut untraced = Chain::new(filters());
+        untraced.trace(Lineage::new(Settings::default()), names(&[None, None, None]));
+
+        let payload = payload();
+        let expected = json::encode_sorted(&plain.apply(payload.clone())[0]);
+        assert_eq!(expected, json::encode_sorted(&untraced.apply(payload)[0]));
+    }
+
+    #[test]
+    fn strip_lineage_before_output() {
+        let collector = Collector::new();
+        let mut output = Strip::new(Box::new(collector.clone()));
+
+        let mut payload = record(vec![("message", string("le message"))]);
+        payload.insert(FIELD.to_string(), RecordItem::Array(Vec::new()));
+        output.feed(&payload).unwrap();
+
+        assert_eq!(vec![record(vec![("message", string("le message"))])], collector.records());
+    }
+}
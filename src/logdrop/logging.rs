@@ -1,18 +1,16 @@
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
 use chrono;
 use log;
-use log::{LogRecord, LogLevel, LogMetadata, SetLoggerError};
+use log::{LogRecord, LogLevel, LogMetadata, MaxLogLevelFilter, SetLoggerError};
 
-struct Logger {
-    level: LogLevel,
-}
+/// Current level as `LogLevel` discriminant, zero while logging is not initialized.
+static LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
 
-impl Logger {
-    fn new(level: LogLevel) -> Logger {
-        Logger {
-            level: level,
-        }
-    }
-}
+/// Lets the level be changed at runtime, see `set_level`.
+static mut FILTER: Option<MaxLogLevelFilter> = None;
+
+struct Logger;
 
 fn verbosity<'r>(level: LogLevel) -> &'r str {
     match level {
@@ -26,7 +24,7 @@ fn verbosity<'r>(level: LogLevel) -> &'r str {
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &LogMetadata) -> bool {
-        metadata.level() <= self.level
+        metadata.level() as usize <= LEVEL.load(Ordering::Relaxed)
     }
 
     fn log(&self, record: &LogRecord) {
@@ -44,7 +42,28 @@ impl log::Log for Logger {
 
 pub fn init(level: LogLevel) -> Result<(), SetLoggerError> {
     log::set_logger(|max| {
-        max.set(level.to_log_level_filter());
-        Box::new(Logger::new(level))
+        unsafe {
+            FILTER = Some(max);
+        }
+        set_level(level);
+        Box::new(Logger)
     })
 }
+
+/// Returns the current level, if logging is initialized or the level has been set.
+pub fn level() -> Option<LogLevel> {
+    let level = LEVEL.load(Ordering::Relaxed);
+    [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace].iter()
+        .find(|&&candidate| candidate as usize == level)
+        .cloned()
+}
+
+/// Changes the level of messages logged from now on.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    unsafe {
+        if let Some(ref filter) = FILTER {
+            filter.set(level.to_log_level_filter());
+        }
+    }
+}
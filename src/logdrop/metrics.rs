@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Monotonically increasing counter. Clones share the same value.
+#[derive(Clone, Debug)]
+pub struct Counter(Arc<AtomicUsize>);
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter(Arc::new(AtomicUsize::new(0)))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: usize) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Sets the value, for counters telling a state rather than counting events, e.g. the policy
+    /// a component runs with, which must not add up as the component is made again on reload.
+    pub fn set(&self, value: usize) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Named collection of counters.
+pub struct Registry {
+    counters: Mutex<HashMap<String, Counter>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the counter registered under the given name, creating it on first access.
+    pub fn counter(&self, name: &str) -> Counter {
+        let mut counters = self.counters.lock().unwrap();
+        counters.entry(name.to_string()).or_insert_with(Counter::new).clone()
+    }
+
+    /// Returns the current values of all counters, sorted by name.
+    pub fn snapshot(&self) -> Vec<(String, usize)> {
+        let counters = self.counters.lock().unwrap();
+        let mut result: Vec<(String, usize)> = counters.iter().map(|(name, counter)| {
+            (name.clone(), counter.get())
+        }).collect();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+}
+
+/// Returns the process-wide registry.
+pub fn registry() -> &'static Registry {
+    static mut REGISTRY: *const Registry = 0 as *const Registry;
+    static INIT: Once = ONCE_INIT;
+
+    unsafe {
+        INIT.call_once(|| {
+            REGISTRY = mem::transmute(Box::new(Registry::new()));
+        });
+
+        &*REGISTRY
+    }
+}
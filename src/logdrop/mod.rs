@@ -1,28 +1,427 @@
 use std::collections::HashMap;
+use std::collections::hash_map::{Iter, Keys};
+use std::fmt;
+use std::ops::Deref;
+use std::str;
+use std::str::Utf8Error;
+use std::sync::Arc;
+
+use self::ack::Ack;
+use self::capture::Capture;
+use self::codec::Projection;
+use self::encoding::Encoding;
+use self::lazy::Lazy;
+use self::numeric::NonFinite;
+use self::validation::Validation;
 
 pub mod logging;
+pub mod ack;
+pub mod alert;
+pub mod audit;
+pub mod breaker;
+pub mod builder;
+pub mod calendar;
+pub mod capture;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod deadletter;
+pub mod determinism;
+pub mod diff;
+pub mod digest;
+pub mod encoding;
+pub mod glob;
+pub mod handover;
+pub mod identity;
+pub mod lazy;
+pub mod metrics;
+pub mod naming;
+pub mod numeric;
+pub mod poison;
+pub mod registry;
+pub mod reload;
+pub mod sched;
 
 pub mod input;
 pub mod codec;
+pub mod filter;
+pub mod lineage;
 pub mod output;
+pub mod oversize;
+pub mod pipeline;
+pub mod queue;
+pub mod quota;
+pub mod retention;
+pub mod snapshot;
+pub mod source;
+pub mod spool;
+pub mod unwind;
+pub mod validation;
+pub mod version;
+pub mod warmup;
 
+mod http;
 mod json;
+mod resp;
+mod sys;
 
-#[derive(Debug, Clone)]
-pub struct Record(HashMap<String, RecordItem>);
+#[cfg(test)]
+pub mod fuzz;
+#[cfg(test)]
+pub mod testing;
+
+/// Fields of a record, either decoded or kept within the payload until accessed, see `lazy`.
+#[derive(Clone)]
+pub struct Record {
+    fields: HashMap<String, RecordItem>,
+    /// Payload fields are decoded from on access, until the record is materialized.
+    lazy: Option<Box<Lazy>>,
+}
 
+/// Records compare strings by content, whether they are owned or shared.
 #[derive(Debug, Clone)]
 pub enum RecordItem {
     Null,
     Bool(bool),
     F64(f64),
+    /// Integer, decoded exactly rather than rounded to a float, see `numeric`.
+    I64(i64),
+    /// Integer above `i64::MAX`.
+    U64(u64),
     String(String),
+    /// String borrowed from the buffer shared by the values of a record, produced by decoders
+    /// avoiding an allocation per value. Filters replacing strings produce owned ones.
+    Text(Text),
+    Bytes(Vec<u8>),
     Array(Vec<RecordItem>),
     Object(HashMap<String, RecordItem>),
 }
 
+/// Describes where a record came from and how it should be judged on arrival.
+///
+/// Resolved once per input at pipeline construction and shared by all its records.
+#[derive(Debug)]
+pub struct Origin {
+    pub input: String,
+    pub validation: Arc<Validation>,
+    /// Raw payload capture settings, if enabled for the input.
+    pub capture: Option<Arc<Capture>>,
+    /// Top-level fields kept while decoding, if not all of them.
+    pub projection: Option<Arc<Projection>>,
+    /// Encoding the input stream is transcoded from, if declared.
+    pub encoding: Option<Arc<Encoding>>,
+    /// What to do with NaN and infinite floats of records on arrival.
+    pub non_finite: NonFinite,
+    /// Number of raw bytes kept in records reporting values failing to decode, if such records
+    /// are emitted rather than failures only logged, see `Sink::consume`.
+    pub decode_errors: Option<usize>,
+}
+
+/// Record travelling from an input to the pipeline together with its metadata.
+#[derive(Debug)]
+pub struct Envelope {
+    pub record: Record,
+    pub origin: Arc<Origin>,
+    /// Address of the connection peer, for connection-oriented inputs.
+    pub peer: Option<Arc<String>>,
+    /// Reason the input has rejected the record for, sending it to the dead-letter output
+    /// without validation.
+    pub rejected: Option<String>,
+    /// Token of the record the input is told of its outcome through, if it asks for that.
+    pub ack: Option<Ack>,
+}
+
 impl Record {
+    pub fn new() -> Record {
+        Record::from(HashMap::new())
+    }
+
+    /// Wraps the payload with its fields indexed, decoding them on access.
+    pub fn lazy(lazy: Lazy) -> Record {
+        Record {
+            fields: HashMap::new(),
+            lazy: Some(Box::new(lazy)),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&RecordItem> {
+        match self.lazy {
+            Some(ref lazy) => lazy.find(name),
+            None => self.fields.get(name),
+        }
+    }
+
+    /// Looks up a nested value by walking through objects along the given path.
+    pub fn find_path(&self, path: &[String]) -> Option<&RecordItem> {
+        let mut current = match path.first().and_then(|name| self.find(name)) {
+            Some(value) => value,
+            None => return None,
+        };
+
+        for name in path[1..].iter() {
+            current = match current.find(name) {
+                Some(value) => value,
+                None => return None,
+            };
+        }
+
+        Some(current)
+    }
+
+    /// Looks up a nested value for modification, see `find_path`.
+    pub fn find_path_mut(&mut self, path: &[String]) -> Option<&mut RecordItem> {
+        let mut current = match path.first().and_then(|name| self.fields_mut().get_mut(name)) {
+            Some(value) => value,
+            None => return None,
+        };
+
+        for name in path[1..].iter() {
+            let parent = current;
+            current = match *parent {
+                RecordItem::Object(ref mut map) => match map.get_mut(name) {
+                    Some(value) => value,
+                    None => return None,
+                },
+                _ => return None,
+            };
+        }
+
+        Some(current)
+    }
+
+    /// Visits every value not being a non-empty object together with its path, descending into
+    /// nested objects. Arrays are visited as a whole.
+    pub fn walk<'a, F>(&'a self, mut f: F)
+        where F: FnMut(&[String], &'a RecordItem)
+    {
+        fn visit<'a, F>(path: &mut Vec<String>, value: &'a RecordItem, f: &mut F)
+            where F: FnMut(&[String], &'a RecordItem)
+        {
+            match *value {
+                RecordItem::Object(ref map) if !map.is_empty() => {
+                    for (name, value) in map.iter() {
+                        path.push(name.clone());
+                        visit(path, value, f);
+                        path.pop();
+                    }
+                }
+                _ => f(path, value),
+            }
+        }
+
+        let mut path = Vec::new();
+        for (name, value) in self.fields().iter() {
+            path.push(name.clone());
+            visit(&mut path, value, &mut f);
+            path.pop();
+        }
+    }
+
+    pub fn insert(&mut self, name: String, value: RecordItem) -> Option<RecordItem> {
+        self.fields_mut().insert(name, value)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<RecordItem> {
+        self.fields_mut().remove(name)
+    }
+
+    pub fn keys(&self) -> Keys<String, RecordItem> {
+        self.fields().keys()
+    }
+
+    pub fn iter(&self) -> Iter<String, RecordItem> {
+        self.fields().iter()
+    }
+
+    /// Returns fields ordered by name, for renderings that must not depend on the hash order.
+    pub fn sorted(&self) -> Vec<(&String, &RecordItem)> {
+        sorted(self.fields())
+    }
+
+    pub fn len(&self) -> usize {
+        match self.lazy {
+            Some(ref lazy) => lazy.len(),
+            None => self.fields.len(),
+        }
+    }
+
+    /// Estimates the encoded size of the record in bytes without encoding it.
+    pub fn size_hint(&self) -> usize {
+        match self.lazy {
+            Some(ref lazy) => lazy.size_hint(),
+            None => self.fields.iter().fold(5, |size, (name, value)| size + name.len() + 5 + value.size_hint()),
+        }
+    }
+
+    /// Returns the payload of the lazy record, as long as it's not modified, for outputs able to
+    /// forward it as is. Keys keep the order of the producer, unlike encoded records.
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.lazy.as_ref().map(|lazy| lazy.payload())
+    }
+
+    /// Returns whether the record is known to hold no NaN nor infinite floats without looking at
+    /// its values, which lazy records are checked for while indexing.
+    pub fn is_known_finite(&self) -> bool {
+        self.lazy.as_ref().map(|lazy| lazy.is_finite()).unwrap_or(false)
+    }
+
+    /// Returns fields, decoding all of them if the record is lazy.
+    fn fields(&self) -> &HashMap<String, RecordItem> {
+        match self.lazy {
+            Some(ref lazy) => lazy.all(),
+            None => &self.fields,
+        }
+    }
+
+    /// Returns fields for modification, materializing the lazy record into the decoded form.
+    fn fields_mut(&mut self) -> &mut HashMap<String, RecordItem> {
+        if let Some(lazy) = self.lazy.take() {
+            self.fields = lazy.into_fields();
+        }
+        &mut self.fields
+    }
+
+    fn into_fields(self) -> HashMap<String, RecordItem> {
+        match self.lazy {
+            Some(lazy) => lazy.into_fields(),
+            None => self.fields,
+        }
+    }
+}
+
+impl From<HashMap<String, RecordItem>> for Record {
+    fn from(fields: HashMap<String, RecordItem>) -> Record {
+        Record {
+            fields: fields,
+            lazy: None,
+        }
+    }
+}
+
+/// Lazy records compare and print like decoded ones.
+impl PartialEq for Record {
+    fn eq(&self, other: &Record) -> bool {
+        self.fields() == other.fields()
+    }
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Record({:?})", self.fields())
+    }
+}
+
+/// Returns entries of the object ordered by key.
+pub fn sorted(map: &HashMap<String, RecordItem>) -> Vec<(&String, &RecordItem)> {
+    let mut entries: Vec<(&String, &RecordItem)> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+impl RecordItem {
     pub fn find(&self, name: &str) -> Option<&RecordItem> {
-        self.0.get(name)
+        match *self {
+            RecordItem::Object(ref map) => map.get(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the string value, either owned or shared.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            RecordItem::String(ref value) => Some(&value[..]),
+            RecordItem::Text(ref value) => Some(value.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of any representation as a float, rounding integers beyond 2^53. See
+    /// `numeric` for conversions telling whether they were exact.
+    pub fn as_f64(&self) -> Option<f64> {
+        numeric::to_f64(self).map(|number| number.value())
+    }
+
+    /// Estimates the encoded size of the value in bytes, see `Record::size_hint`.
+    pub fn size_hint(&self) -> usize {
+        match *self {
+            RecordItem::Null | RecordItem::Bool(..) => 1,
+            RecordItem::F64(..) | RecordItem::I64(..) | RecordItem::U64(..) => 9,
+            RecordItem::String(ref value) => value.len() + 5,
+            RecordItem::Text(ref value) => value.len() + 5,
+            RecordItem::Bytes(ref value) => value.len() + 5,
+            RecordItem::Array(ref values) => values.iter().fold(5, |size, value| size + value.size_hint()),
+            RecordItem::Object(ref map) => {
+                map.iter().fold(5, |size, (name, value)| size + name.len() + 5 + value.size_hint())
+            }
+        }
+    }
+}
+
+/// Values compare strings by content, whether they are owned or shared, and numbers by value,
+/// whatever their representation.
+impl PartialEq for RecordItem {
+    fn eq(&self, other: &RecordItem) -> bool {
+        match (self, other) {
+            (&RecordItem::Null, &RecordItem::Null) => true,
+            (&RecordItem::Bool(lhs), &RecordItem::Bool(rhs)) => lhs == rhs,
+            (&RecordItem::Bytes(ref lhs), &RecordItem::Bytes(ref rhs)) => lhs == rhs,
+            (&RecordItem::Array(ref lhs), &RecordItem::Array(ref rhs)) => lhs == rhs,
+            (&RecordItem::Object(ref lhs), &RecordItem::Object(ref rhs)) => lhs == rhs,
+            _ => {
+                if let Some(equal) = numeric::equal(self, other) {
+                    return equal;
+                }
+
+                match (self.as_str(), other.as_str()) {
+                    (Some(lhs), Some(rhs)) => lhs == rhs,
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// UTF-8 range of a buffer shared by all string values of a record, so that decoding them takes
+/// no allocation per value.
+///
+/// The buffer lives as long as any value of the record does.
+#[derive(Clone)]
+pub struct Text {
+    buf: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl Text {
+    /// Refers to the range of the buffer, failing if it is not valid UTF-8.
+    pub fn new(buf: Arc<Vec<u8>>, start: usize, end: usize) -> Result<Text, Utf8Error> {
+        try!(str::from_utf8(&buf[start..end]));
+
+        let text = Text {
+            buf: buf,
+            start: start,
+            end: end,
+        };
+
+        Ok(text)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // The range has been validated on construction and the buffer is never changed.
+        unsafe { str::from_utf8_unchecked(&self.buf[self.start..self.end]) }
+    }
+}
+
+impl Deref for Text {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
     }
 }
@@ -0,0 +1,67 @@
+use std::mem;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::thread;
+use std::thread::JoinHandle;
+
+/// Default prefix of thread names.
+pub const PREFIX: &'static str = "logdrop";
+
+fn slot() -> &'static Mutex<String> {
+    static mut SLOT: *const Mutex<String> = 0 as *const Mutex<String>;
+    static INIT: Once = ONCE_INIT;
+
+    unsafe {
+        INIT.call_once(|| {
+            SLOT = mem::transmute(Box::new(Mutex::new(PREFIX.to_string())));
+        });
+
+        &*SLOT
+    }
+}
+
+/// Returns the prefix of names of threads spawned by the process.
+pub fn prefix() -> String {
+    slot().lock().unwrap().clone()
+}
+
+/// Changes the prefix of names of threads spawned from now on, e.g. to tell processes apart.
+pub fn set_prefix(prefix: &str) {
+    *slot().lock().unwrap() = prefix.to_string();
+}
+
+/// Spawns the thread named `<prefix>-<name>`, e.g. `logdrop-output-file-0`, so that it can be
+/// told apart in `top -H`, perf and panic messages. Note that the system may keep only the first
+/// 15 bytes of it.
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    thread::Builder::new().name(format!("{}-{}", prefix(), name)).spawn(f).ok().expect("unable to spawn thread")
+}
+
+/// Shortens the type name to its lowercase last segment without the `Output` or `Input` suffix,
+/// e.g. `logdrop::output::files::FileOutput` to `file`.
+pub fn short(typename: &str) -> String {
+    let name = typename.rsplit("::").next().unwrap_or(typename);
+    let name = if name.len() > 6 && name.ends_with("Output") {
+        &name[..name.len() - 6]
+    } else if name.len() > 5 && name.ends_with("Input") {
+        &name[..name.len() - 5]
+    } else {
+        name
+    };
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::short;
+
+    #[test]
+    fn shorten_type_names() {
+        assert_eq!("file", short("logdrop::output::files::FileOutput"));
+        assert_eq!("tcp", short("logdrop::input::tcp::TcpInput"));
+        assert_eq!("null", short("logdrop::output::null::Null"));
+        assert_eq!("output", short("Output"));
+    }
+}
@@ -0,0 +1,531 @@
+//! Checked conversions between the numeric representations of record values.
+//!
+//! Records carry integers as `I64`, or as `U64` when above `i64::MAX`, and everything else as
+//! `F64`, which holds integers exactly only up to 2^53. Conversions never panic nor wrap: values
+//! out of the target range saturate to its nearest bound, NaN becomes zero and fractions are
+//! truncated towards zero, each conversion telling whether it was exact.
+//!
+//! Components writing a lossy conversion result into a record saturate as above and annotate the
+//! record by listing the field in the `_numeric_loss` array, see `annotate`.
+//!
+//! NaN and infinite floats, which msgpack carries but JSON can't, are handled once on arrival by
+//! the input policy, see `NonFinite`. Components aggregating numbers take them through `finite`,
+//! so that the ones slipping through, e.g. produced by a filter, never enter their state. The JSON
+//! encoder writing them as null is the last line of defense only.
+
+use std::{i64, u64};
+use std::mem;
+
+use super::{Record, RecordItem};
+use super::metrics;
+
+/// Field listing fields whose value lost precision on the way.
+pub const LOSS_FIELD: &'static str = "_numeric_loss";
+
+/// Field listing paths of non-finite floats replaced with null.
+pub const NON_FINITE_FIELD: &'static str = "_non_finite";
+
+/// Largest integer magnitude all smaller ones of which a float holds exactly, i.e. 2^53.
+pub const MAX_EXACT: u64 = 1 << 53;
+
+/// 2^63 as a float, the first one beyond `i64::MAX`.
+const I64_END: f64 = 9223372036854775808.0;
+
+/// 2^64 as a float, the first one beyond `u64::MAX`.
+const U64_END: f64 = 18446744073709551616.0;
+
+/// Conversion result, either exact or the nearest value the target holds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Conversion<T> {
+    Exact(T),
+    Lossy(T),
+}
+
+impl<T> Conversion<T> {
+    pub fn value(self) -> T {
+        match self {
+            Conversion::Exact(value) | Conversion::Lossy(value) => value,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        match *self {
+            Conversion::Exact(..) => true,
+            Conversion::Lossy(..) => false,
+        }
+    }
+
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Conversion<U> {
+        match self {
+            Conversion::Exact(value) => Conversion::Exact(f(value)),
+            Conversion::Lossy(value) => Conversion::Lossy(f(value)),
+        }
+    }
+
+    /// Returns the value only if the conversion was exact.
+    pub fn exact(self) -> Option<T> {
+        match self {
+            Conversion::Exact(value) => Some(value),
+            Conversion::Lossy(..) => None,
+        }
+    }
+}
+
+pub fn i64_to_f64(value: i64) -> Conversion<f64> {
+    let result = value as f64;
+    // Values near `i64::MAX` round up to 2^63, which doesn't convert back.
+    if result < I64_END && result as i64 == value {
+        Conversion::Exact(result)
+    } else {
+        Conversion::Lossy(result)
+    }
+}
+
+pub fn u64_to_f64(value: u64) -> Conversion<f64> {
+    let result = value as f64;
+    if result < U64_END && result as u64 == value {
+        Conversion::Exact(result)
+    } else {
+        Conversion::Lossy(result)
+    }
+}
+
+pub fn f64_to_i64(value: f64) -> Conversion<i64> {
+    if value.is_nan() {
+        Conversion::Lossy(0)
+    } else if value >= I64_END {
+        Conversion::Lossy(i64::MAX)
+    } else if value < -I64_END {
+        Conversion::Lossy(i64::MIN)
+    } else if value.fract() == 0.0 {
+        Conversion::Exact(value as i64)
+    } else {
+        Conversion::Lossy(value.trunc() as i64)
+    }
+}
+
+pub fn f64_to_u64(value: f64) -> Conversion<u64> {
+    if value.is_nan() || value <= -1.0 {
+        Conversion::Lossy(0)
+    } else if value >= U64_END {
+        Conversion::Lossy(u64::MAX)
+    } else if value.fract() == 0.0 {
+        Conversion::Exact(value as u64)
+    } else {
+        Conversion::Lossy(value.trunc() as u64)
+    }
+}
+
+pub fn u64_to_i64(value: u64) -> Conversion<i64> {
+    if value > i64::MAX as u64 {
+        Conversion::Lossy(i64::MAX)
+    } else {
+        Conversion::Exact(value as i64)
+    }
+}
+
+pub fn i64_to_u64(value: i64) -> Conversion<u64> {
+    if value < 0 {
+        Conversion::Lossy(0)
+    } else {
+        Conversion::Exact(value as u64)
+    }
+}
+
+/// Returns the number held by the value as a float, or None if it's not a number.
+pub fn to_f64(value: &RecordItem) -> Option<Conversion<f64>> {
+    match *value {
+        RecordItem::F64(value) => Some(Conversion::Exact(value)),
+        RecordItem::I64(value) => Some(i64_to_f64(value)),
+        RecordItem::U64(value) => Some(u64_to_f64(value)),
+        _ => None,
+    }
+}
+
+/// Returns the integer value of the exact integer, `I64` unless it's above `i64::MAX`.
+pub fn integer(value: u64) -> RecordItem {
+    match u64_to_i64(value) {
+        Conversion::Exact(value) => RecordItem::I64(value),
+        Conversion::Lossy(..) => RecordItem::U64(value),
+    }
+}
+
+/// Parses the integer exactly, the way decoders do, returning None for anything else.
+pub fn parse_integer(value: &str) -> Option<RecordItem> {
+    if let Ok(value) = value.parse::<i64>() {
+        return Some(RecordItem::I64(value));
+    }
+
+    value.parse::<u64>().ok().map(RecordItem::U64)
+}
+
+/// Tells whether the numbers are equal by value, whatever their representations, returning None
+/// if either value is not a number.
+pub fn equal(lhs: &RecordItem, rhs: &RecordItem) -> Option<bool> {
+    let result = match (lhs, rhs) {
+        (&RecordItem::F64(lhs), &RecordItem::F64(rhs)) => lhs == rhs,
+        (&RecordItem::I64(lhs), &RecordItem::I64(rhs)) => lhs == rhs,
+        (&RecordItem::U64(lhs), &RecordItem::U64(rhs)) => lhs == rhs,
+        (&RecordItem::I64(lhs), &RecordItem::U64(rhs)) | (&RecordItem::U64(rhs), &RecordItem::I64(lhs)) => {
+            i64_to_u64(lhs) == Conversion::Exact(rhs)
+        }
+        (&RecordItem::I64(lhs), &RecordItem::F64(rhs)) | (&RecordItem::F64(rhs), &RecordItem::I64(lhs)) => {
+            f64_to_i64(rhs) == Conversion::Exact(lhs)
+        }
+        (&RecordItem::U64(lhs), &RecordItem::F64(rhs)) | (&RecordItem::F64(rhs), &RecordItem::U64(lhs)) => {
+            f64_to_u64(rhs) == Conversion::Exact(lhs)
+        }
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// What to do with NaN and infinite floats found in decoded records, anywhere in them.
+///
+/// Records having any are counted in the `numeric.non_finite.found` metric.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NonFinite {
+    /// Replace them with null, listing their dotted paths in the `_non_finite` field.
+    Null,
+    /// Remove them, be they object members or array items.
+    Drop,
+    /// Dead-letter the whole record.
+    DeadLetter,
+}
+
+impl Default for NonFinite {
+    fn default() -> NonFinite {
+        NonFinite::Null
+    }
+}
+
+impl NonFinite {
+    /// Applies the policy to the record, giving it back with the reason if it must be
+    /// dead-lettered instead.
+    pub fn apply(&self, mut record: Record) -> Result<Record, (Record, String)> {
+        // Lazy records are checked while indexing, so they are not decoded just for that.
+        if record.is_known_finite() {
+            return Ok(record);
+        }
+        // Records are scanned first, as only a few ever carry non-finite numbers, sparing the
+        // rest from copying keys and formatting paths.
+        if !record.iter().any(|(_, value)| non_finite(value)) {
+            return Ok(record);
+        }
+
+        let mut found = Vec::new();
+        let keys: Vec<String> = record.keys().cloned().collect();
+        for key in keys.into_iter() {
+            let remove = scrub(record.fields_mut().get_mut(&key).unwrap(), &key, *self, &mut found);
+            if remove {
+                record.remove(&key);
+            }
+        }
+
+        if found.is_empty() {
+            return Ok(record);
+        }
+        metrics::registry().counter("numeric.non_finite.found").inc();
+
+        match *self {
+            NonFinite::Null => {
+                record.insert(NON_FINITE_FIELD.to_string(), RecordItem::Array(found));
+                Ok(record)
+            }
+            NonFinite::Drop => Ok(record),
+            NonFinite::DeadLetter => {
+                let paths: Vec<&str> = found.iter().filter_map(|path| path.as_str()).collect();
+                let reason = format!("non-finite numbers at {:?}", paths);
+                Err((record, reason))
+            }
+        }
+    }
+}
+
+/// Returns true if the value is or contains a non-finite float.
+fn non_finite(value: &RecordItem) -> bool {
+    match *value {
+        RecordItem::F64(number) => !number.is_finite(),
+        RecordItem::Array(ref items) => items.iter().any(non_finite),
+        RecordItem::Object(ref map) => map.values().any(non_finite),
+        _ => false,
+    }
+}
+
+/// Handles non-finite floats within the value according to the policy, collecting their paths,
+/// and returns true if the value itself must be removed.
+fn scrub(value: &mut RecordItem, path: &str, policy: NonFinite, found: &mut Vec<RecordItem>) -> bool {
+    let non_finite = match *value {
+        RecordItem::F64(number) => !number.is_finite(),
+        _ => false,
+    };
+    if non_finite {
+        found.push(RecordItem::String(path.to_string()));
+        if policy == NonFinite::Null {
+            *value = RecordItem::Null;
+        }
+        return policy == NonFinite::Drop;
+    }
+
+    match *value {
+        RecordItem::Array(ref mut items) => {
+            let mut kept = Vec::new();
+            for (id, mut item) in mem::replace(items, Vec::new()).into_iter().enumerate() {
+                if !scrub(&mut item, &format!("{}.{}", path, id), policy, found) {
+                    kept.push(item);
+                }
+            }
+            *items = kept;
+        }
+        RecordItem::Object(ref mut map) => {
+            let keys: Vec<String> = map.keys().cloned().collect();
+            for key in keys.into_iter() {
+                let remove = scrub(map.get_mut(&key).unwrap(), &format!("{}.{}", path, key), policy, found);
+                if remove {
+                    map.remove(&key);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    false
+}
+
+/// Returns the number held by the value as a float if it is a finite one.
+///
+/// Non-finite floats are counted in the `numeric.non_finite.rejected` metric, as anything
+/// aggregating numbers takes them through here.
+pub fn finite(value: &RecordItem) -> Option<f64> {
+    match value.as_f64() {
+        Some(number) if number.is_finite() => Some(number),
+        Some(..) => {
+            metrics::registry().counter("numeric.non_finite.rejected").inc();
+            None
+        }
+        None => None,
+    }
+}
+
+/// Lists the field in the `_numeric_loss` array of the record, once.
+pub fn annotate(record: &mut Record, field: &str) {
+    let mut fields = match record.remove(LOSS_FIELD) {
+        Some(RecordItem::Array(fields)) => fields,
+        Some(..) | None => Vec::new(),
+    };
+
+    if !fields.iter().any(|name| name.as_str() == Some(field)) {
+        fields.push(RecordItem::String(field.to_string()));
+    }
+    record.insert(LOSS_FIELD.to_string(), RecordItem::Array(fields));
+}
+
+#[cfg(test)]
+mod test {
+    use std::{f64, i64, u64};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use super::super::{Record, RecordItem};
+    use super::super::codec::{Codec, Json, MessagePack};
+    use super::super::codec::json;
+    use super::super::codec::msgpack;
+    use super::super::metrics;
+    use super::super::testing::{record, string};
+    use super::Conversion::{Exact, Lossy};
+    use super::{MAX_EXACT, NonFinite, annotate, equal, f64_to_i64, f64_to_u64, finite, i64_to_f64, i64_to_u64, u64_to_f64,
+                u64_to_i64};
+
+    #[test]
+    fn convert_integers_to_floats_exactly_up_to_2_53() {
+        assert_eq!(Exact(9007199254740992.0), i64_to_f64(MAX_EXACT as i64));
+        assert_eq!(Exact(-9007199254740992.0), i64_to_f64(-(MAX_EXACT as i64)));
+        assert_eq!(Lossy(9007199254740992.0), i64_to_f64(MAX_EXACT as i64 + 1));
+        assert_eq!(Lossy(-9007199254740992.0), i64_to_f64(-(MAX_EXACT as i64) - 1));
+        // Large powers of two are held exactly, unlike their neighbours.
+        assert_eq!(Exact(-9223372036854775808.0), i64_to_f64(i64::MIN));
+        assert!(!i64_to_f64(i64::MAX).is_exact());
+
+        assert_eq!(Exact(9007199254740992.0), u64_to_f64(MAX_EXACT));
+        assert_eq!(Lossy(9007199254740992.0), u64_to_f64(MAX_EXACT + 1));
+        assert!(!u64_to_f64(u64::MAX).is_exact());
+        assert_eq!(Exact(9223372036854775808.0), u64_to_f64(1 << 63));
+    }
+
+    #[test]
+    fn saturate_floats_to_integers() {
+        assert_eq!(Exact(42), f64_to_i64(42.0));
+        assert_eq!(Lossy(42), f64_to_i64(42.9));
+        assert_eq!(Lossy(-42), f64_to_i64(-42.9));
+        assert_eq!(Lossy(i64::MAX), f64_to_i64(9223372036854775808.0));
+        assert_eq!(Exact(i64::MIN), f64_to_i64(-9223372036854775808.0));
+        assert_eq!(Lossy(i64::MIN), f64_to_i64(-1e19));
+        assert_eq!(Lossy(i64::MAX), f64_to_i64(f64::INFINITY));
+        assert_eq!(Lossy(0), f64_to_i64(f64::NAN));
+
+        assert_eq!(Exact(1 << 63), f64_to_u64(9223372036854775808.0));
+        assert_eq!(Lossy(u64::MAX), f64_to_u64(18446744073709551616.0));
+        assert_eq!(Lossy(0), f64_to_u64(-1.0));
+        assert_eq!(Lossy(0), f64_to_u64(-0.5));
+        assert_eq!(Exact(0), f64_to_u64(-0.0));
+        assert_eq!(Lossy(0), f64_to_u64(f64::NAN));
+    }
+
+    #[test]
+    fn saturate_between_integers() {
+        assert_eq!(Exact(i64::MAX), u64_to_i64(i64::MAX as u64));
+        assert_eq!(Lossy(i64::MAX), u64_to_i64(u64::MAX));
+        assert_eq!(Exact(0), i64_to_u64(0));
+        assert_eq!(Lossy(0), i64_to_u64(i64::MIN));
+    }
+
+    #[test]
+    fn compare_numbers_by_value() {
+        assert_eq!(Some(true), equal(&RecordItem::I64(42), &RecordItem::F64(42.0)));
+        assert_eq!(Some(true), equal(&RecordItem::U64(42), &RecordItem::I64(42)));
+        assert_eq!(Some(false), equal(&RecordItem::I64(-1), &RecordItem::U64(u64::MAX)));
+        assert_eq!(Some(false), equal(&RecordItem::I64(MAX_EXACT as i64 + 1), &RecordItem::F64(9007199254740992.0)));
+        assert_eq!(Some(false), equal(&RecordItem::F64(42.5), &RecordItem::I64(42)));
+        assert_eq!(None, equal(&RecordItem::F64(42.0), &string("42")));
+    }
+
+    #[test]
+    fn annotate_field_once() {
+        let mut record = Record::new();
+        annotate(&mut record, "size");
+        annotate(&mut record, "size");
+        annotate(&mut record, "id");
+        assert_eq!(Some(&RecordItem::Array(vec![string("size"), string("id")])), record.find("_numeric_loss"));
+    }
+
+    /// Passes boundary integers through MessagePack, JSON and MessagePack again, requiring every
+    /// one of them to keep both its value and integer representation.
+    #[test]
+    fn round_trip_boundary_integers() {
+        let values = vec![
+            RecordItem::I64(MAX_EXACT as i64 - 1), RecordItem::I64(MAX_EXACT as i64), RecordItem::I64(MAX_EXACT as i64 + 1),
+            RecordItem::I64(-(MAX_EXACT as i64) - 1), RecordItem::I64(i64::MIN), RecordItem::I64(i64::MAX),
+            RecordItem::U64(i64::MAX as u64 + 1), RecordItem::U64(u64::MAX), RecordItem::I64(0), RecordItem::I64(-1),
+        ];
+
+        for value in values.into_iter() {
+            let mut record = Record::new();
+            record.insert("value".to_string(), value.clone());
+            record.insert("list".to_string(), RecordItem::Array(vec![value.clone()]));
+
+            let decoded: Vec<Record> = MessagePack::default().decode(Box::new(Cursor::new(msgpack::encode(&record)))).collect();
+            assert_eq!(1, decoded.len());
+            let encoded = json::encode(&decoded[0]).into_bytes();
+            let decoded: Vec<Record> = Json::default().decode(Box::new(Cursor::new(encoded))).collect();
+            assert_eq!(1, decoded.len());
+            let decoded = msgpack::decode(&msgpack::encode(&decoded[0])).unwrap();
+
+            let item = match decoded.find("list") {
+                Some(&RecordItem::Array(ref items)) if items.len() == 1 => items[0].clone(),
+                list => panic!("expected single item list, found {:?}", list),
+            };
+            for found in vec![decoded.find("value").cloned(), Some(item)].into_iter() {
+                match (&value, found) {
+                    (&RecordItem::I64(expected), Some(RecordItem::I64(actual))) => assert_eq!(expected, actual),
+                    (&RecordItem::U64(expected), Some(RecordItem::U64(actual))) => assert_eq!(expected, actual),
+                    (expected, actual) => panic!("expected {:?}, found {:?}", expected, actual),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn emit_integers_in_json_without_fraction() {
+        let mut record = Record::new();
+        record.insert("id".to_string(), RecordItem::U64(u64::MAX));
+        assert_eq!("{\"id\":18446744073709551615}", json::encode(&record));
+
+        let mut record = Record::new();
+        record.insert("id".to_string(), RecordItem::I64(i64::MIN));
+        assert_eq!("{\"id\":-9223372036854775808}", json::encode(&record));
+    }
+
+    /// Encodes the record with NaN and infinities at the top level, in a nested object and in an
+    /// array, with msgpack, which carries them as they are.
+    fn non_finite() -> Record {
+        let mut nested = HashMap::new();
+        nested.insert("p99".to_string(), RecordItem::F64(f64::INFINITY));
+        nested.insert("p50".to_string(), RecordItem::F64(1.5));
+        let fixture = record(vec![
+            ("latency", RecordItem::F64(f64::NAN)),
+            ("stats", RecordItem::Object(nested)),
+            ("samples", RecordItem::Array(vec![RecordItem::F64(1.0), RecordItem::F64(f64::NEG_INFINITY)])),
+            ("message", string("le message")),
+        ]);
+
+        msgpack::decode(&msgpack::encode(&fixture)).unwrap()
+    }
+
+    #[test]
+    fn apply_non_finite_policy() {
+        let found = metrics::registry().counter("numeric.non_finite.found");
+        let before = found.get();
+
+        let mut scrubbed = NonFinite::Null.apply(non_finite()).unwrap();
+        let mut paths = match scrubbed.remove("_non_finite") {
+            Some(RecordItem::Array(paths)) => paths.into_iter().filter_map(|path| path.as_str().map(|path| path.to_string())).collect::<Vec<String>>(),
+            paths => panic!("expected paths, found {:?}", paths),
+        };
+        paths.sort();
+        assert_eq!(vec!["latency", "samples.1", "stats.p99"], paths);
+        assert_eq!(Some(&RecordItem::Null), scrubbed.find("latency"));
+        assert_eq!(Some(&RecordItem::Null), scrubbed.find("stats").and_then(|stats| stats.find("p99")));
+        assert_eq!(Some(&RecordItem::Array(vec![RecordItem::F64(1.0), RecordItem::Null])), scrubbed.find("samples"));
+
+        let scrubbed = NonFinite::Drop.apply(non_finite()).unwrap();
+        assert_eq!(None, scrubbed.find("latency"));
+        assert_eq!(None, scrubbed.find("stats").and_then(|stats| stats.find("p99")));
+        assert_eq!(Some(&RecordItem::F64(1.5)), scrubbed.find("stats").and_then(|stats| stats.find("p50")));
+        assert_eq!(Some(&RecordItem::Array(vec![RecordItem::F64(1.0)])), scrubbed.find("samples"));
+        assert_eq!(None, scrubbed.find("_non_finite"));
+
+        match NonFinite::DeadLetter.apply(non_finite()) {
+            Err((record, reason)) => {
+                assert!(reason.contains("latency"));
+                assert!(record.find("latency").and_then(|latency| latency.as_f64()).unwrap().is_nan());
+            }
+            Ok(record) => panic!("expected dead letter, found {:?}", record),
+        }
+
+        let plain = record(vec![("latency", RecordItem::F64(1.0))]);
+        assert_eq!(Ok(plain.clone()), NonFinite::DeadLetter.apply(plain.clone()).map_err(|(_, reason)| reason));
+        assert!(found.get() >= before + 3);
+    }
+
+    #[test]
+    fn reject_non_finite_numbers_for_aggregation() {
+        let rejected = metrics::registry().counter("numeric.non_finite.rejected");
+        let before = rejected.get();
+
+        assert_eq!(Some(1.5), finite(&RecordItem::F64(1.5)));
+        assert_eq!(Some(42.0), finite(&RecordItem::I64(42)));
+        assert_eq!(None, finite(&string("42")));
+        assert_eq!(before, rejected.get());
+
+        assert_eq!(None, finite(&RecordItem::F64(f64::NAN)));
+        assert_eq!(None, finite(&RecordItem::F64(f64::INFINITY)));
+        assert_eq!(None, finite(&RecordItem::F64(f64::NEG_INFINITY)));
+        assert!(rejected.get() >= before + 3);
+    }
+
+    #[test]
+    fn never_emit_non_finite_numbers_in_json() {
+        for policy in vec![None, Some(NonFinite::Null), Some(NonFinite::Drop), Some(NonFinite::DeadLetter)].into_iter() {
+            let record = match policy.map(|policy| policy.apply(non_finite())) {
+                Some(Ok(record)) | Some(Err((record, _))) => record,
+                None => non_finite(),
+            };
+
+            let encoded = json::encode(&record);
+            for token in ["NaN", "nan", "inf", "Infinity"].iter() {
+                assert!(!encoded.contains(token), "{} in {}", token, encoded);
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::super::Record;
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::super::deadletter::Letter;
+use super::super::spool::Writer;
+use super::{Error, Output};
+
+/// Dead-letter output keeping records in the archive they can be requeued from, see
+/// `input::Requeue`.
+///
+/// The archive is a spool segment of versioned entries, each holding the original record apart
+/// from the reason, the stage and the time it has been archived at, see `deadletter::Letter`.
+/// It's opened for appending, so that it grows over restarts.
+pub struct Archive {
+    path: PathBuf,
+    writer: Writer,
+    clock: Arc<Clock>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> io::Result<Archive> {
+        Ok(Archive {
+            path: path.to_path_buf(),
+            writer: try!(Writer::open(path)),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    pub fn clock(mut self, clock: Arc<Clock>) -> Archive {
+        self.clock = clock;
+        self
+    }
+}
+
+impl FromConfig for Archive {
+    fn from_config(config: &Config) -> Result<Archive, ConfigError> {
+        let path = try!(config.string("path"));
+        Archive::open(Path::new(&path)).map_err(|err| {
+            ConfigError::Invalid(format!("unable to open dead-letter archive '{}': {}", path, err))
+        })
+    }
+}
+
+impl Output for Archive {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        let letter = Letter::new(payload.clone(), self.clock.now() as f64 / 1000.0);
+        match self.writer.append(&letter.entry()) {
+            Ok(..) => Ok(()),
+            Err(err) => Err(Error::Retryable(format!("unable to append to '{}' - {}", self.path.display(), err))),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.sync().map_err(|err| Error::Retryable(format!("unable to sync '{}' - {}", self.path.display(), err)))
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(self.path.display().to_string())
+    }
+
+    fn sort_keys(&mut self) {
+        self.writer.sort_keys();
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::super::{Record, RecordItem};
+use super::super::clock::Clock;
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::super::metrics;
+use super::super::metrics::Counter;
+use super::super::numeric;
+use super::super::numeric::Conversion;
+use super::super::registry;
+use super::super::snapshot::Snapshot;
+use super::super::spool::{Reader, Writer};
+use super::{Error, Output};
+
+/// Field holding the idempotency id of a record unless configured otherwise.
+pub const FIELD: &'static str = "idempotency_id";
+
+/// Settings of the deduplication window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Name telling deduplicated outputs apart in the `output.dedup.<name>.suppressed` metric.
+    pub name: String,
+    /// Number of the last written records remembered.
+    pub capacity: usize,
+    /// Field holding the idempotency id, records without it are keyed by their content hash.
+    pub field: String,
+    /// File the window is kept in across restarts, if any.
+    pub state: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            name: "dedup".to_string(),
+            capacity: 100000,
+            field: FIELD.to_string(),
+            state: None,
+        }
+    }
+}
+
+/// Key a record is remembered by.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Id(String),
+    Content(u64),
+}
+
+impl Key {
+    fn of(record: &Record, field: &str) -> Key {
+        match record.find(field) {
+            Some(&RecordItem::F64(id)) => Key::Id(id.to_string()),
+            Some(&RecordItem::I64(id)) => Key::Id(id.to_string()),
+            Some(&RecordItem::U64(id)) => Key::Id(id.to_string()),
+            Some(id) if id.as_str().is_some() => Key::Id(id.as_str().unwrap().to_string()),
+            _ => {
+                let mut hasher = SipHasher::new();
+                digest_record(record.iter(), &mut hasher);
+                Key::Content(hasher.finish())
+            }
+        }
+    }
+
+    fn to_item(&self) -> RecordItem {
+        match *self {
+            Key::Id(ref id) => RecordItem::String(id.clone()),
+            Key::Content(hash) => RecordItem::Bytes((0..8).map(|i| (hash >> (56 - 8 * i)) as u8).collect()),
+        }
+    }
+
+    fn from_item(item: &RecordItem) -> Option<Key> {
+        match *item {
+            RecordItem::Bytes(ref bytes) if bytes.len() == 8 => {
+                Some(Key::Content(bytes.iter().fold(0, |hash, &byte| hash << 8 | byte as u64)))
+            }
+            _ => item.as_str().map(|id| Key::Id(id.to_string())),
+        }
+    }
+}
+
+/// Hashes the fields in the order of their names, so that equal records hash equally whatever
+/// order their maps iterate in.
+fn digest_record<'a, I, H>(fields: I, hasher: &mut H)
+    where I: Iterator<Item=(&'a String, &'a RecordItem)>, H: Hasher
+{
+    let mut fields: Vec<_> = fields.collect();
+    fields.sort_by(|a, b| a.0.cmp(b.0));
+    fields.len().hash(hasher);
+    for (name, value) in fields.into_iter() {
+        name.hash(hasher);
+        digest(value, hasher);
+    }
+}
+
+fn digest<H: Hasher>(item: &RecordItem, hasher: &mut H) {
+    match *item {
+        RecordItem::Null => 0u8.hash(hasher),
+        RecordItem::Bool(value) => {
+            1u8.hash(hasher);
+            value.hash(hasher);
+        }
+        RecordItem::F64(value) => {
+            2u8.hash(hasher);
+            unsafe { mem::transmute::<f64, u64>(value) }.hash(hasher);
+        }
+        // Integers a float holds exactly hash like that float, as they compare equal to it.
+        RecordItem::I64(value) => {
+            match numeric::i64_to_f64(value) {
+                Conversion::Exact(value) => digest(&RecordItem::F64(value), hasher),
+                Conversion::Lossy(..) => {
+                    7u8.hash(hasher);
+                    value.hash(hasher);
+                }
+            }
+        }
+        RecordItem::U64(value) => {
+            match numeric::u64_to_f64(value) {
+                Conversion::Exact(value) => digest(&RecordItem::F64(value), hasher),
+                Conversion::Lossy(..) => {
+                    8u8.hash(hasher);
+                    value.hash(hasher);
+                }
+            }
+        }
+        RecordItem::String(..) | RecordItem::Text(..) => {
+            3u8.hash(hasher);
+            item.as_str().unwrap().hash(hasher);
+        }
+        RecordItem::Bytes(ref bytes) => {
+            4u8.hash(hasher);
+            bytes.hash(hasher);
+        }
+        RecordItem::Array(ref items) => {
+            5u8.hash(hasher);
+            items.len().hash(hasher);
+            for item in items.iter() {
+                digest(item, hasher);
+            }
+        }
+        RecordItem::Object(ref map) => {
+            6u8.hash(hasher);
+            digest_record(map.iter(), hasher);
+        }
+    }
+}
+
+/// Output wrapper suppressing re-writes of records the wrapped output has already written, so
+/// that retries after ambiguous failures don't leave duplicates in sinks unable to deduplicate
+/// on their own.
+///
+/// Records are remembered by their idempotency id, or by their content hash when it's absent,
+/// within a window of the last written ones. Fed records are passed to the wrapped output right
+/// away, while their keys are remembered only once it's flushed, so that records fed again after
+/// a failed flush are not mistaken for written ones. Repeats fed before the flush are suppressed
+/// too, unless the flush has failed since. Suppressed records are counted in the
+/// `output.dedup.<name>.suppressed` metric rather than as drops.
+///
+/// With the state file configured the window is saved after every flush and restored on
+/// construction, covering batches fed again after a restart.
+pub struct Dedup {
+    output: Box<Output>,
+    settings: Settings,
+    window: VecDeque<Key>,
+    written: HashSet<Key>,
+    /// Keys of records fed since the last successful flush, remembered by the next one.
+    pending: Vec<Key>,
+    /// Keys of records fed since the last flush, successful or not.
+    fed: HashSet<Key>,
+    suppressed: u64,
+    counter: Counter,
+}
+
+impl Dedup {
+    pub fn new(output: Box<Output>, settings: Settings) -> Dedup {
+        let counter = metrics::registry().counter(&format!("output.dedup.{}.suppressed", settings.name));
+        let mut dedup = Dedup {
+            output: output,
+            settings: settings,
+            window: VecDeque::new(),
+            written: HashSet::new(),
+            pending: Vec::new(),
+            fed: HashSet::new(),
+            suppressed: 0,
+            counter: counter,
+        };
+
+        if let Some(path) = dedup.settings.state.clone() {
+            match Reader::open(&path).and_then(|mut rd| rd.next()) {
+                Ok(Some(record)) => dedup.recall(&record),
+                Ok(None) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => warn!(target: "Output::Dedup", "unable to restore window from '{}': {}", path.display(), err),
+            }
+        }
+
+        dedup
+    }
+
+    /// Returns the number of records suppressed so far.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    fn suppress(&mut self) {
+        self.suppressed += 1;
+        self.counter.inc();
+    }
+
+    fn remember(&mut self, key: Key) {
+        if self.settings.capacity == 0 {
+            return;
+        }
+
+        if self.window.len() == self.settings.capacity {
+            if let Some(oldest) = self.window.pop_front() {
+                self.written.remove(&oldest);
+            }
+        }
+
+        self.written.insert(key.clone());
+        self.window.push_back(key);
+    }
+
+    fn recall(&mut self, record: &Record) {
+        if let Some(&RecordItem::Array(ref keys)) = record.find("written") {
+            for key in keys.iter().filter_map(Key::from_item) {
+                if !self.written.contains(&key) {
+                    self.remember(key);
+                }
+            }
+        }
+    }
+
+    fn to_record(&self) -> Record {
+        let mut record = Record::new();
+        record.insert("field".to_string(), RecordItem::String(self.settings.field.clone()));
+        record.insert("written".to_string(), RecordItem::Array(self.window.iter().map(Key::to_item).collect()));
+        record
+    }
+
+    /// Writes the window aside and renames it over the previous one, so that a crash leaves
+    /// either of them intact.
+    fn save(&self) -> io::Result<()> {
+        let path = match self.settings.state {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let record = self.to_record();
+        let temporary = path.with_extension("tmp");
+        {
+            let mut wr = try!(Writer::create(&temporary));
+            try!(wr.append(&record));
+            try!(wr.sync());
+        }
+        fs::rename(&temporary, path)
+    }
+}
+
+impl FromConfig for Dedup {
+    fn from_config(config: &Config) -> Result<Dedup, ConfigError> {
+        let inner = match config.find("output") {
+            Some(inner) => inner,
+            None => return Err(ConfigError::Invalid("dedup requires 'output'".to_string())),
+        };
+        let output = try!(registry::output(&inner));
+
+        let name = try!(config.string_or("name", &try!(inner.typename())));
+        let state = match config.find("dir") {
+            Some(..) => Some(PathBuf::from(try!(config.string("dir"))).join(format!("dedup.{}.state", name))),
+            None => None,
+        };
+
+        let default = Settings::default();
+        let settings = Settings {
+            name: name,
+            capacity: try!(config.u64_or("capacity", default.capacity as u64)) as usize,
+            field: try!(config.string_or("field", &default.field)),
+            state: state,
+        };
+
+        Ok(Dedup::new(output, settings))
+    }
+}
+
+impl Output for Dedup {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        let key = Key::of(payload, &self.settings.field);
+        if self.written.contains(&key) || self.fed.contains(&key) {
+            self.suppress();
+            return Ok(());
+        }
+
+        try!(self.output.feed(payload));
+        self.fed.insert(key.clone());
+        self.pending.push(key);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Err(err) = self.output.flush() {
+            self.fed.clear();
+            return Err(err);
+        }
+        self.fed.clear();
+
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        for key in mem::replace(&mut self.pending, Vec::new()).into_iter() {
+            if !self.written.contains(&key) {
+                self.remember(key);
+            }
+        }
+
+        if let Err(err) = self.save() {
+            warn!(target: "Output::Dedup", "unable to save window: {}", err);
+        }
+
+        Ok(())
+    }
+
+    fn discard(&mut self) -> usize {
+        self.pending.clear();
+        self.fed.clear();
+        self.output.discard()
+    }
+
+    fn probe(&mut self) -> bool {
+        self.output.probe()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.output.check()
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        self.output.describe_fields()
+    }
+
+    fn target(&self) -> Option<String> {
+        self.output.target()
+    }
+
+    fn oversize(&self) -> bool {
+        self.output.oversize()
+    }
+
+    fn ordered(&self) -> bool {
+        self.output.ordered()
+    }
+
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        Some(self)
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.output.reseed(seed);
+    }
+
+    fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.output.set_clock(clock);
+    }
+
+    fn sort_keys(&mut self) {
+        self.output.sort_keys();
+    }
+
+    fn typename(&self) -> &'static str {
+        self.output.typename()
+    }
+}
+
+/// The window is carried over restarts as a part of the pipeline snapshot too, for setups without
+/// the state file.
+impl Snapshot for Dedup {
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn snapshot(&mut self) -> Option<Record> {
+        Some(self.to_record())
+    }
+
+    fn restore(&mut self, state: &Record) -> Result<(), String> {
+        match state.find("field").and_then(|field| field.as_str()) {
+            Some(field) if field == self.settings.field => {}
+            Some(..) | None => return Err("idempotency id field is changed".to_string()),
+        }
+
+        self.recall(state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::mem;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::metrics;
+    use super::super::super::testing::{Collector, record, string};
+    use super::super::{Error, Output};
+    use super::{Dedup, Settings};
+
+    /// Output writing records on flush, losing the ones it holds to an ambiguous failure of the
+    /// first one.
+    struct Unflushed {
+        collector: Collector,
+        held: Vec<Record>,
+        flushed: usize,
+    }
+
+    impl Output for Unflushed {
+        fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+            self.held.push(payload.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.flushed += 1;
+            let held = mem::replace(&mut self.held, Vec::new());
+            if self.flushed == 1 {
+                return Err(Error::Ambiguous("connection lost".to_string()));
+            }
+            for record in held.iter() {
+                try!(self.collector.feed(record));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn suppress_records_by_content_without_ids() {
+        let collector = Collector::new();
+        let mut dedup = Dedup::new(Box::new(collector.clone()), Settings::default());
+
+        let first = record(vec![("message", string("le message")), ("level", RecordItem::F64(1.0))]);
+        let second = record(vec![("message", string("le message")), ("level", RecordItem::F64(2.0))]);
+        for payload in [&first, &second, &first].iter() {
+            dedup.feed(payload).unwrap();
+        }
+        dedup.flush().unwrap();
+        dedup.feed(&record(vec![("level", RecordItem::F64(2.0)), ("message", string("le message"))])).unwrap();
+        dedup.flush().unwrap();
+
+        assert_eq!(vec![first, second], collector.records());
+        assert_eq!(2, dedup.suppressed());
+    }
+
+    #[test]
+    fn forget_records_past_capacity() {
+        let collector = Collector::new();
+        let settings = Settings { capacity: 2, ..Settings::default() };
+        let mut dedup = Dedup::new(Box::new(collector.clone()), settings);
+
+        for id in ["a", "b", "c", "a"].iter() {
+            dedup.feed(&record(vec![("idempotency_id", string(id))])).unwrap();
+            dedup.flush().unwrap();
+        }
+
+        assert_eq!(4, collector.records().len());
+        assert_eq!(0, dedup.suppressed());
+    }
+
+    #[test]
+    fn keep_window_across_restart() {
+        let dir = env::temp_dir().join("logdrop-dedup-restart");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let collector = Collector::new();
+        let settings = Settings { state: Some(dir.join("dedup.collector.state")), ..Settings::default() };
+
+        let batch: Vec<_> = (0..4).map(|id| record(vec![("idempotency_id", string(&id.to_string()))])).collect();
+        let mut dedup = Dedup::new(Box::new(collector.clone()), settings.clone());
+        for payload in batch[..2].iter() {
+            dedup.feed(payload).unwrap();
+        }
+        dedup.flush().unwrap();
+        drop(dedup);
+
+        let mut dedup = Dedup::new(Box::new(collector.clone()), settings);
+        for payload in batch.iter() {
+            dedup.feed(payload).unwrap();
+        }
+        dedup.flush().unwrap();
+
+        assert_eq!(batch, collector.records());
+        assert_eq!(2, dedup.suppressed());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pass_records_fed_again_after_ambiguous_failure() {
+        let collector = Collector::new();
+        let output = Unflushed { collector: collector.clone(), held: Vec::new(), flushed: 0 };
+        let settings = Settings { name: "test_ambiguous".to_string(), ..Settings::default() };
+        let mut dedup = Dedup::new(Box::new(output), settings);
+
+        let batch: Vec<_> = (0..4).map(|id| record(vec![("idempotency_id", string(&id.to_string()))])).collect();
+        for payload in batch.iter() {
+            dedup.feed(payload).unwrap();
+        }
+        assert!(dedup.flush().is_err());
+
+        // Fed again as a whole, e.g. replayed by the sender, once before and once after written.
+        for _ in 0..2 {
+            for payload in batch.iter() {
+                dedup.feed(payload).unwrap();
+            }
+            dedup.flush().unwrap();
+        }
+
+        assert_eq!(batch, collector.records());
+        assert_eq!(4, dedup.suppressed());
+        assert_eq!(4, metrics::registry().counter("output.dedup.test_ambiguous.suppressed").get());
+    }
+}
@@ -1,138 +1,746 @@
-use time;
-
-use std::io::timer::Timer;
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
 use std::time::Duration;
 
-use logdrop::Payload;
-use logdrop::logger::{Debug, Info, Warn};
+use super::super::Record;
+use super::super::clock::{Clock, SystemClock};
+use super::super::codec::json;
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::super::http;
+use super::super::metrics;
+use super::super::spool::Spool;
+use super::{Endpoint, Error, Output};
 
-use url::Url;
-use http::client::RequestWriter;
-use http::method::Post;
+/// Elasticsearch output indexes records using the bulk API.
+///
+/// Records are accumulated in memory and sent either when the queue reaches the limit or on
+/// flush. A failed bulk request keeps the queue intact, so it is retried on the next attempt.
+///
+/// With the spool configured, batches failed to be sent are moved to the disk instead and are
+/// replayed oldest first before anything else, including after a restart.
+///
+/// Requests go to the address the last one went to until the host name no longer resolves to
+/// it or it refuses the connection, resolving the name again then, see `Endpoint`.
+///
+/// With the parallelism above one, up to that many bulk requests are in flight at once, each
+/// sealed batch being sent by its own slot while the worker goes on queueing records, so that the
+/// request latency no longer caps the throughput. Batches are then indexed in the order their
+/// responses arrive rather than the order records were fed in, see `Output::ordered`. Feeding
+/// blocks while all slots are busy or the outstanding-record cap is reached, counting records in
+/// flight and those of failed batches. Failed batches are kept, or spooled if retryable and the
+/// spool is configured, so that a failure of one request never affects the others in flight. The
+/// failure is returned by the next flush rather than by feeding records unrelated to it, and the
+/// batch is sent again only by the calls following it, leaving the pipeline to decide whether
+/// it's retried or discarded, e.g. when the failure is ambiguous in at-most-once mode. In-flight requests are the difference of
+/// `output.elasticsearch.requests.sent` and `.done` counters, and each slot accumulates its
+/// latency in `output.elasticsearch.slot.<n>.latency_ms`.
+pub struct ElasticsearchOutput {
+    addr: String,
+    endpoint: Endpoint,
+    index: String,
+    kind: String,
+    limit: usize,
+    queue: Vec<Record>,
+    spool: Option<Spool>,
+    oversize: bool,
+    /// Per-request timeout in milliseconds, if any.
+    timeout: Option<u64>,
+    parallelism: usize,
+    /// Maximum number of records in flight and of failed batches together.
+    outstanding: usize,
+    flight: Flight,
+    clock: Arc<Clock>,
+    /// Whether object keys are encoded ordered by name, see `Output::sort_keys`.
+    sorted: bool,
+}
 
-use super::Output;
+/// Outcome of a bulk request sent by a slot.
+struct Completion {
+    slot: usize,
+    records: Vec<Record>,
+    result: Result<(), Error>,
+    latency: u64,
+}
 
-enum Event {
-    Chunk(String),
-    Timeout,
+/// Batch failed to be sent.
+struct Failed {
+    records: Vec<Record>,
+    err: Error,
+    /// Whether the failure has been returned by a feed or flush, after which the batch may be
+    /// sent again.
+    reported: bool,
 }
 
-pub struct ElasticsearchOutput {
-    tx: Sender<Event>,
+/// Bulk requests in flight, dispatched to a limited number of slots.
+///
+/// Channel ends are locked only for the output to stay `Sync`, as it's used by a single worker.
+struct Flight {
+    tx: Mutex<Sender<Completion>>,
+    rx: Mutex<Receiver<Completion>>,
+    /// Slots no request is in flight in.
+    free: Vec<usize>,
+    /// Number of records in flight.
+    records: usize,
+    /// Batches failed to be sent, oldest first.
+    failed: VecDeque<Failed>,
 }
 
-impl ElasticsearchOutput {
-    pub fn new(host: &str, port: u16) -> ElasticsearchOutput {
+impl Flight {
+    fn new(parallelism: usize) -> Flight {
         let (tx, rx) = channel();
-        let output = ElasticsearchOutput {
-            tx: tx.clone(),
-        };
+        Flight {
+            tx: Mutex::new(tx),
+            rx: Mutex::new(rx),
+            free: (0..parallelism).rev().collect(),
+            records: 0,
+            failed: VecDeque::new(),
+        }
+    }
 
-        let (timer_tx, timer_rx) = channel();
-        spawn(proc(){
-            let duration = Duration::milliseconds(3000);
-            let mut timer = Timer::new().unwrap();
-            loop {
-                log!(Debug, "Output::ES" -> "waiting for {}ms timeout", 3000u32);
-                let timeout = timer.oneshot(duration);
-
-                select! {
-                    () = timer_rx.recv() => {},
-                    () = timeout.recv()  => { tx.send(Timeout); }
-                }
-            }
-        });
+    /// Returns the number of records of failed batches.
+    fn retained(&self) -> usize {
+        self.failed.iter().fold(0, |acc, failed| acc + failed.records.len())
+    }
 
-        let base = format!("{}:{}", host, port);
-        spawn(proc(){
-            let base = base;
-            let limit = 100;
-            let mut queue: Vec<String> = Vec::new();
-
-            // All settings.
-            loop {
-                match rx.recv() {
-                    Chunk(chunk) => {
-                        queue.push(chunk);
-                        if queue.len() >= limit {
-                            timer_tx.send(());
-
-                            ElasticsearchOutput::send(base.as_slice(), ElasticsearchOutput::make_body(&queue));
-                            queue.clear();
-                        }
-                    }
-                    Timeout      => {
-                        log!(Debug, "Output::ES" -> "timed out");
-                        ElasticsearchOutput::send(base.as_slice(), ElasticsearchOutput::make_body(&queue));
-                        queue.clear();
-                    }
-                }
+    /// Marks failures as reported, returning the oldest one not reported yet, if any.
+    fn report(&mut self) -> Option<Error> {
+        let mut result = None;
+        for failed in self.failed.iter_mut().filter(|failed| !failed.reported) {
+            failed.reported = true;
+            if result.is_none() {
+                result = Some(failed.err.clone());
             }
-        });
+        }
+        result
+    }
+}
+
+impl ElasticsearchOutput {
+    pub fn new(host: &str, port: u16) -> ElasticsearchOutput {
+        ElasticsearchOutput {
+            addr: format!("{}:{}", host, port),
+            endpoint: Endpoint::new("elasticsearch", host, port),
+            index: "logs".to_string(),
+            kind: "log".to_string(),
+            limit: 100,
+            queue: Vec::new(),
+            spool: None,
+            oversize: false,
+            timeout: None,
+            parallelism: 1,
+            outstanding: usize::max_value(),
+            flight: Flight::new(1),
+            clock: Arc::new(SystemClock),
+            sorted: false,
+        }
+    }
 
-        output
+    pub fn index(mut self, index: &str, kind: &str) -> ElasticsearchOutput {
+        self.index = index.to_string();
+        self.kind = kind.to_string();
+        self
     }
 
-    fn make_body(queue: &Vec<String>) -> Arc<String> {
+    /// Replaces the endpoint, e.g. to resolve its name differently.
+    pub fn endpoint(mut self, endpoint: Endpoint) -> ElasticsearchOutput {
+        self.endpoint = endpoint;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> ElasticsearchOutput {
+        self.limit = limit;
+        self
+    }
+
+    /// Fails requests not answered within the given number of milliseconds.
+    pub fn timeout(mut self, timeout: u64) -> ElasticsearchOutput {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends up to the given number of bulk requests at once, with at most `outstanding` records
+    /// in flight or in failed batches.
+    pub fn parallelism(mut self, parallelism: usize, outstanding: usize) -> ElasticsearchOutput {
+        self.parallelism = parallelism;
+        self.outstanding = outstanding;
+        self.flight = Flight::new(parallelism);
+        self
+    }
+
+    /// Returns the number of bulk requests in flight.
+    pub fn in_flight(&self) -> usize {
+        self.parallelism - self.flight.free.len()
+    }
+
+    /// Makes the output accept oversize records intact, each one sent by its own bulk request.
+    pub fn accept_oversize(mut self, oversize: bool) -> ElasticsearchOutput {
+        self.oversize = oversize;
+        self
+    }
+
+    /// Spools failed batches in the given directory, keeping at most `size` bytes there.
+    pub fn spool(mut self, dir: &Path, size: u64) -> io::Result<ElasticsearchOutput> {
+        let spool = try!(Spool::open(dir, size));
+        if spool.len() > 0 {
+            info!(target: "Output::ES", "found {} spooled records in {:?}", spool.len(), dir);
+        }
+
+        self.spool = Some(spool);
+        Ok(self)
+    }
+
+    fn make_body(records: &[Record], sorted: bool) -> String {
         let mut data = String::new();
-        for item in queue.iter() {
+        for record in records.iter() {
             data.push_str("{\"index\":{}}\n");
-            data.push_str(item.as_slice());
+            data.push_str(&json::encode_record(record, sorted));
             data.push_str("\n");
         }
-        Arc::new(data)
+        data
     }
 
-    fn send(base: &str, data: Arc<String>) {
-        if data.is_empty() {
-            return
+    /// Connects to the address of the last request, or to the one the host name resolves to now.
+    fn connect(&mut self) -> Result<TcpStream, http::Error> {
+        match self.endpoint.reconnect() {
+            Some(stream) => Ok(stream),
+            None => {
+                self.endpoint.connect().map_err(|reason| http::Error::Connect(io::Error::new(io::ErrorKind::Other, reason)))
+            }
         }
+    }
 
-        log!(Debug, "Output::ES" -> "emitting");
+    fn send(&mut self, records: &[Record]) -> Result<(), Error> {
+        let path = format!("/{}/{}/_bulk", self.index, self.kind);
+        debug!(target: "Output::ES", "sending bulk index request with {} records at {}{}", records.len(), self.addr, path);
 
-        let url = format!("http://{}/logs/log3/_bulk", base);
-        let url = match Url::parse(url.as_slice()) {
-            Ok(url)  => url,
+        let body = ElasticsearchOutput::make_body(records, self.sorted);
+        match self.connect() {
+            Ok(stream) => bulk(stream, &self.addr, &path, body.as_bytes(), self.timeout),
+            Err(err) => outcome(Err(err)),
+        }
+    }
+
+    /// Sends the sealed batch by a free slot, waiting for one if all are busy.
+    fn dispatch(&mut self, records: Vec<Record>) {
+        while self.flight.free.is_empty() {
+            self.complete(true);
+        }
+
+        let stream = match self.connect() {
+            Ok(stream) => stream,
             Err(err) => {
-                log!(Warn, "Output::ES" -> "failed to parse '{}' - {}", url, err);
-                return;
+                let err = outcome(Err(err)).unwrap_err();
+                return self.fail(records, err);
             }
         };
 
-        log!(Debug, "Output::ES" -> "sending bulk index request at {}", url);
-        spawn(proc(){
-            let mut request: RequestWriter = match RequestWriter::new(Post, url) {
-                Ok(request) => request,
-                Err(err)    => {
-                    log!(Warn, "Output::ES" -> "failed to build POST request - {}", err);
-                    return;
-                }
-            };
+        let slot = self.flight.free.pop().unwrap();
+        let path = format!("/{}/{}/_bulk", self.index, self.kind);
+        debug!(target: "Output::ES", "sending bulk index request with {} records at {}{} by slot {}",
+            records.len(), self.addr, path, slot);
 
-            request.headers.content_length = Some(data.len());
-            match request.write(data.as_bytes()) {
-                Ok(())   => {}
-                Err(err) => {
-                    log!(Warn, "Output::ES" -> "failed to write payload - {}", err);
+        self.flight.records += records.len();
+        metrics::registry().counter("output.elasticsearch.requests.sent").inc();
+
+        let addr = self.addr.clone();
+        let timeout = self.timeout;
+        let clock = self.clock.clone();
+        let sorted = self.sorted;
+        let tx = self.flight.tx.lock().unwrap().clone();
+        thread::spawn(move || {
+            let start = clock.now();
+            let body = ElasticsearchOutput::make_body(&records, sorted);
+            let result = bulk(stream, &addr, &path, body.as_bytes(), timeout);
+            let latency = clock.now().saturating_sub(start);
+            let _ = tx.send(Completion { slot: slot, records: records, result: result, latency: latency });
+        });
+    }
+
+    /// Handles the next completed request, waiting for one if told to, returning whether there
+    /// was any.
+    fn complete(&mut self, wait: bool) -> bool {
+        let completion = {
+            let rx = self.flight.rx.lock().unwrap();
+            if wait { rx.recv().ok() } else { rx.try_recv().ok() }
+        };
+
+        let completion = match completion {
+            Some(completion) => completion,
+            None => return false,
+        };
+
+        let registry = metrics::registry();
+        registry.counter("output.elasticsearch.requests.done").inc();
+        registry.counter(&format!("output.elasticsearch.slot.{}.latency_ms", completion.slot)).add(completion.latency as usize);
+
+        self.flight.free.push(completion.slot);
+        self.flight.records -= completion.records.len();
+        match completion.result {
+            Ok(()) => debug!(target: "Output::ES", "ok - {} records by slot {}", completion.records.len(), completion.slot),
+            Err(err) => self.fail(completion.records, err),
+        }
+        true
+    }
+
+    /// Keeps the failed batch to be sent again, spooling it instead if the failure is retryable
+    /// and the spool is configured.
+    fn fail(&mut self, records: Vec<Record>, err: Error) {
+        if let (&Error::Retryable(ref reason), Some(spool)) = (&err, self.spool.as_mut()) {
+            match spool.push(&records) {
+                Ok(dropped) => {
+                    warn!(target: "Output::ES", "spooled {} records - {}", records.len(), reason);
+                    if dropped > 0 {
+                        warn!(target: "Output::ES", "spool is full, dropped {} oldest records", dropped);
+                        metrics::registry().counter("output.elasticsearch.spool.dropped").add(dropped);
+                    }
                     return;
                 }
+                Err(err) => error!(target: "Output::ES", "failed to spool records: {}", err),
             }
+        }
 
-            let response = match request.read_response() {
-                Ok(response)  => response,
-                Err((_, err)) => {
-                    log!(Warn, "Output::ES" -> "failed to perform POST request - {}", err);
-                    return;
+        warn!(target: "Output::ES", "failed to send {} records - {}", records.len(), err);
+        self.flight.failed.push_back(Failed { records: records, err: err, reported: false });
+    }
+
+    /// Sends failed batches whose failure has been reported again, each one once.
+    fn resend(&mut self) {
+        let (reported, pending) = mem::replace(&mut self.flight.failed, VecDeque::new())
+            .into_iter()
+            .partition(|failed| failed.reported);
+        self.flight.failed = pending;
+        for failed in reported.into_iter() {
+            self.dispatch(failed.records);
+        }
+    }
+
+    /// Queues the record while requests are sent concurrently, sealing the batch once full.
+    ///
+    /// Never fails, as failures of requests completed meanwhile belong to their own batches and
+    /// are returned by the next flush. While failed batches keep the outstanding cap reached, the
+    /// sealed batch stays queued until then.
+    fn feed_concurrently(&mut self, payload: &Record) -> Result<(), Error> {
+        while self.complete(false) {}
+
+        self.queue.push(payload.clone());
+        if self.queue.len() < self.limit {
+            return Ok(());
+        }
+
+        self.resend();
+
+        // Backpressure: wait for requests in flight until the sealed batch fits the cap.
+        while self.flight.records + self.flight.retained() + self.queue.len() > self.outstanding && self.in_flight() > 0 {
+            self.complete(true);
+        }
+
+        if self.flight.records + self.flight.retained() + self.queue.len() > self.outstanding {
+            debug!(target: "Output::ES", "keeping {} records queued: {} records of failed batches outstanding",
+                self.queue.len(), self.flight.retained());
+            return Ok(());
+        }
+
+        let queue = mem::replace(&mut self.queue, Vec::new());
+        self.dispatch(queue);
+        Ok(())
+    }
+
+    /// Sends the queue and failed batches, waiting for all requests in flight to complete.
+    fn flush_concurrently(&mut self) -> Result<(), Error> {
+        if let Err(err) = self.replay() {
+            if let Err(err) = self.spill(err) {
+                return Err(err);
+            }
+        }
+
+        while self.complete(false) {}
+        if let Some(err) = self.flight.report() {
+            return Err(err);
+        }
+
+        self.resend();
+        if !self.queue.is_empty() {
+            let queue = mem::replace(&mut self.queue, Vec::new());
+            self.dispatch(queue);
+        }
+
+        while self.in_flight() > 0 {
+            self.complete(true);
+        }
+
+        self.flight.report();
+        match self.flight.failed.front() {
+            Some(failed) => Err(failed.err.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends spooled batches, oldest first, until the spool is empty or a request fails.
+    fn replay(&mut self) -> Result<(), Error> {
+        loop {
+            let records = match self.spool.as_ref().and_then(|spool| spool.peek()) {
+                Some(Ok(records)) => records,
+                Some(Err(err)) => {
+                    let count = self.spool.as_mut().unwrap().pop().unwrap_or(0);
+                    error!(target: "Output::ES", "dropping {} spooled records: {}", count, err);
+                    metrics::registry().counter("output.elasticsearch.spool.dropped").add(count);
+                    continue;
                 }
+                None => return Ok(()),
             };
-            log!(Debug, "Output::ES" -> "ok - {}", response.status);
-        });
+
+            try!(self.send(&records));
+            self.spool.as_mut().unwrap().pop();
+            info!(target: "Output::ES", "replayed {} spooled records", records.len());
+        }
+    }
+
+    /// Moves the queue to the spool on a definite failure, so it no longer has to be retried.
+    fn spill(&mut self, err: Error) -> Result<(), Error> {
+        let reason = match (err, self.spool.is_some()) {
+            (Error::Retryable(reason), true) => reason,
+            (err, _) => return Err(err),
+        };
+
+        if self.queue.is_empty() {
+            debug!(target: "Output::ES", "spool replay postponed - {}", reason);
+            return Ok(());
+        }
+
+        match self.spool.as_mut().unwrap().push(&self.queue) {
+            Ok(dropped) => {
+                warn!(target: "Output::ES", "spooled {} records - {}", self.queue.len(), reason);
+                if dropped > 0 {
+                    warn!(target: "Output::ES", "spool is full, dropped {} oldest records", dropped);
+                    metrics::registry().counter("output.elasticsearch.spool.dropped").add(dropped);
+                }
+                self.queue.clear();
+                Ok(())
+            }
+            Err(err) => {
+                error!(target: "Output::ES", "failed to spool records: {}", err);
+                Err(Error::Retryable(reason))
+            }
+        }
+    }
+}
+
+/// Performs the bulk request over the connection, failing it if not answered within the timeout.
+fn bulk(stream: TcpStream, addr: &str, path: &str, body: &[u8], timeout: Option<u64>) -> Result<(), Error> {
+    if let Some(timeout) = timeout {
+        let timeout = Some(Duration::from_millis(timeout));
+        if let Err(err) = stream.set_read_timeout(timeout).and_then(|()| stream.set_write_timeout(timeout)) {
+            return outcome(Err(http::Error::Connect(err)));
+        }
+    }
+
+    outcome(http::request(stream, addr, "POST", path, body))
+}
+
+fn outcome(result: Result<http::Response, http::Error>) -> Result<(), Error> {
+    match result {
+        Ok(ref response) if response.is_success() => {
+            debug!(target: "Output::ES", "ok - {}", response.status);
+            Ok(())
+        }
+        Ok(response) => {
+            Err(Error::Retryable(format!("bulk request failed with {} status - {}", response.status, response.body)))
+        }
+        Err(err @ http::Error::Connect(..)) => {
+            Err(Error::Retryable(format!("failed to perform bulk request - {}", err)))
+        }
+        Err(err @ http::Error::Transfer(..)) => {
+            Err(Error::Ambiguous(format!("failed to perform bulk request - {}", err)))
+        }
+    }
+}
+
+/// Reads request `parallelism`, `max_outstanding` records and the `request_timeout` in
+/// milliseconds besides the usual settings.
+impl FromConfig for ElasticsearchOutput {
+    fn from_config(config: &Config) -> Result<ElasticsearchOutput, ConfigError> {
+        let host = try!(config.string_or("host", "localhost"));
+        let port = try!(config.u64_or("port", 9200));
+        let index = try!(config.string_or("index", "logs"));
+        let kind = try!(config.string_or("kind", "log"));
+        let limit = try!(config.u64_or("limit", 100));
+        let oversize = try!(config.bool_or("oversize", false));
+
+        let parallelism = try!(config.u64_or("parallelism", 1));
+        if parallelism == 0 {
+            return Err(ConfigError::Invalid("'parallelism' must be positive".to_string()));
+        }
+
+        let outstanding = try!(config.u64_or("max_outstanding", limit * parallelism));
+        if outstanding < limit {
+            return Err(ConfigError::Invalid("'max_outstanding' must not be less than the 'limit'".to_string()));
+        }
+
+        let endpoint = try!(Endpoint::new("elasticsearch", &host, port as u16).configure(config));
+        let mut output = ElasticsearchOutput::new(&host, port as u16)
+            .endpoint(endpoint)
+            .index(&index, &kind)
+            .limit(limit as usize)
+            .accept_oversize(oversize)
+            .parallelism(parallelism as usize, outstanding as usize);
+
+        if config.find("request_timeout").is_some() {
+            output = output.timeout(try!(config.u64_or("request_timeout", 0)));
+        }
+
+        match config.find("spool") {
+            Some(spool) => {
+                let dir = try!(spool.string("dir"));
+                let size = try!(spool.u64_or("size", 1024 * 1024 * 1024));
+                output.spool(Path::new(&dir), size).map_err(ConfigError::Io)
+            }
+            None => Ok(output),
+        }
     }
 }
 
 impl Output for ElasticsearchOutput {
-    fn feed(&mut self, payload: &Payload) {
-        self.tx.send(Chunk(payload.to_string()));
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        if self.parallelism > 1 {
+            return self.feed_concurrently(payload);
+        }
+
+        self.queue.push(payload.clone());
+
+        if self.queue.len() >= self.limit {
+            let result = self.flush();
+            if result.is_err() {
+                // The record is not retained on failure, it's up to the caller to feed it again.
+                self.queue.pop();
+            }
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.parallelism > 1 {
+            return self.flush_concurrently();
+        }
+
+        if let Err(err) = self.replay() {
+            return self.spill(err);
+        }
+
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+
+        let queue = mem::replace(&mut self.queue, Vec::new());
+        let result = self.send(&queue);
+        self.queue = queue;
+        match result {
+            Ok(()) => {
+                self.queue.clear();
+                Ok(())
+            }
+            Err(err) => self.spill(err),
+        }
+    }
+
+    fn probe(&mut self) -> bool {
+        let result = match self.connect() {
+            Ok(stream) => http::request(stream, &self.addr, "GET", "/", &[]),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(response) => response.is_success(),
+            Err(..) => false,
+        }
+    }
+
+    fn discard(&mut self) -> usize {
+        let count = self.queue.len() + self.flight.retained();
+        self.queue.clear();
+        self.flight.failed.clear();
+        count
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.endpoint.check()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.endpoint.reseed(seed);
+    }
+
+    fn sort_keys(&mut self) {
+        self.sorted = true;
+    }
+
+    fn oversize(&self) -> bool {
+        self.oversize
+    }
+
+    /// Records of a connection are indexed in order only with the parallelism of one.
+    fn ordered(&self) -> bool {
+        self.parallelism == 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+
+    use super::super::super::Record;
+    use super::super::super::json;
+    use super::super::super::config::{Config, FromConfig};
+    use super::super::super::testing::{delayed_http_server, http_server, record, string};
+    use super::super::Output;
+    use super::ElasticsearchOutput;
+
+    fn records(count: usize) -> Vec<Record> {
+        (0..count).map(|id| record(vec![("message", string(&format!("record-{}", id)))])).collect()
+    }
+
+    #[test]
+    fn replay_spool_after_restart() {
+        let dir = env::temp_dir().join("logdrop-es-spool");
+        let _ = fs::remove_dir_all(&dir);
+
+        // Nobody listens on the port once the listener is dropped.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let records = vec![
+            record(vec![("message", string("first"))]),
+            record(vec![("message", string("second"))]),
+            record(vec![("message", string("third"))]),
+        ];
+
+        {
+            let mut output = ElasticsearchOutput::new("127.0.0.1", port).limit(2).spool(&dir, 1 << 20).unwrap();
+            for record in records.iter() {
+                output.feed(record).unwrap();
+            }
+            output.flush().unwrap();
+        }
+
+        let bodies = http_server(TcpListener::bind(("127.0.0.1", port)).unwrap());
+
+        let mut output = ElasticsearchOutput::new("127.0.0.1", port).limit(2).spool(&dir, 1 << 20).unwrap();
+        output.flush().unwrap();
+
+        assert_eq!(ElasticsearchOutput::make_body(&records[..2], false), bodies.recv().unwrap());
+        assert_eq!(ElasticsearchOutput::make_body(&records[2..], false), bodies.recv().unwrap());
+        assert_eq!(0, output.spool.as_ref().unwrap().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_requests_in_flight_concurrently() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bodies = delayed_http_server(listener, 100, |_| 200);
+
+        // Requests are answered long after the whole cap is fed, so all slots fill up.
+        let mut output = ElasticsearchOutput::new("127.0.0.1", port).limit(10).parallelism(4, 40);
+        let mut peak = 0;
+        for record in records(80).iter() {
+            output.feed(record).unwrap();
+            peak = cmp::max(peak, output.in_flight());
+        }
+        output.flush().unwrap();
+
+        assert_eq!(4, peak);
+        assert_eq!(0, output.in_flight());
+        assert_eq!(8, bodies.iter().take(8).filter(|&(status, _)| status == 200).count());
+    }
+
+    #[test]
+    fn engage_backpressure_at_outstanding_cap() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bodies = delayed_http_server(listener, 50, |_| 200);
+
+        // Four slots, but records for two batches only.
+        let mut output = ElasticsearchOutput::new("127.0.0.1", port).limit(10).parallelism(4, 20);
+        for record in records(60).iter() {
+            output.feed(record).unwrap();
+            assert!(output.in_flight() <= 2, "{} requests in flight", output.in_flight());
+            assert!(output.flight.records <= 20, "{} records outstanding", output.flight.records);
+        }
+        output.flush().unwrap();
+
+        assert_eq!(0, output.in_flight());
+        assert_eq!(6, bodies.iter().take(6).count());
+    }
+
+    #[test]
+    fn retain_failed_batches_under_concurrent_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bodies = delayed_http_server(listener, 50, |id| if id == 1 || id == 2 { 503 } else { 200 });
+
+        let records = records(8);
+        let mut output = ElasticsearchOutput::new("127.0.0.1", port).limit(2).parallelism(4, 100);
+        for record in records.iter() {
+            output.feed(record).unwrap();
+        }
+
+        assert!(output.flush().is_err());
+        assert_eq!(4, output.flight.retained());
+        output.flush().unwrap();
+        assert_eq!(0, output.flight.retained());
+
+        // Every record is indexed exactly once, whichever requests have failed.
+        let indexed: Vec<String> = bodies.iter().take(6).filter(|&(status, _)| status == 200).map(|(_, body)| body).collect();
+        for id in 0..records.len() {
+            let needle = format!("\"record-{}\"", id);
+            assert_eq!(1, indexed.iter().filter(|body| body.contains(&needle)).count(), "record {}", id);
+        }
+    }
+
+    #[test]
+    fn report_failed_batch_from_next_flush() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bodies = delayed_http_server(listener, 10, |id| if id == 0 { 503 } else { 200 });
+
+        let records = records(3);
+        let mut output = ElasticsearchOutput::new("127.0.0.1", port).limit(2).parallelism(2, 100);
+        output.feed(&records[0]).unwrap();
+        output.feed(&records[1]).unwrap();
+        while output.in_flight() > 0 {
+            output.complete(true);
+        }
+
+        // Feeding an unrelated record never fails for the batch. The failure is returned once by
+        // the flush, the batch being sent again only after that.
+        output.feed(&records[2]).unwrap();
+        assert_eq!(2, output.flight.retained());
+        assert!(output.flush().is_err());
+        output.flush().unwrap();
+
+        let indexed: Vec<String> = bodies.iter().take(3).filter(|&(status, _)| status == 200).map(|(_, body)| body).collect();
+        assert_eq!(2, indexed.len());
+        assert!(indexed.iter().any(|body| body.contains("\"record-0\"") && body.contains("\"record-1\"")));
+        assert_eq!(1, indexed.iter().filter(|body| body.contains("\"record-2\"")).count());
+    }
+
+    #[test]
+    fn report_parallel_output_unordered() {
+        let value = json::from_str(r#"{"parallelism": 4}"#).unwrap();
+        assert!(!ElasticsearchOutput::from_config(&Config::new(&value)).unwrap().ordered());
+
+        let value = json::from_str(r#"{"parallelism": 1}"#).unwrap();
+        assert!(ElasticsearchOutput::from_config(&Config::new(&value)).unwrap().ordered());
+
+        let value = json::from_str(r#"{"parallelism": 4, "max_outstanding": 10}"#).unwrap();
+        assert!(ElasticsearchOutput::from_config(&Config::new(&value)).is_err());
     }
 }
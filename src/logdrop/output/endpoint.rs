@@ -0,0 +1,270 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error as ConfigError};
+use super::super::metrics;
+
+/// Resolves host names into addresses, allowing tests to script the answers.
+pub trait Resolver : Sync + Send {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolver asking the system, i.e. `getaddrinfo`.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        (host, port).to_socket_addrs().map(|addrs| addrs.collect())
+    }
+}
+
+/// Order resolved addresses are tried in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Order {
+    /// As returned by the resolver.
+    Resolved,
+    /// Shuffled on every resolution, spreading outputs over the addresses.
+    Random,
+}
+
+/// Network destination given by a host name, which may resolve to different addresses over time.
+///
+/// The name is resolved on every connection attempt, trying the addresses one by one until one
+/// accepts the connection. While connected it's also resolved again once the interval passes,
+/// telling whether the connection should be moved to one of the new addresses. Every established
+/// connection is counted in the `output.<name>.connections.<address>` metrics.
+pub struct Endpoint {
+    name: &'static str,
+    host: String,
+    port: u16,
+    resolver: Arc<Resolver>,
+    clock: Arc<Clock>,
+    /// Re-resolution interval while connected in milliseconds, zero meaning never.
+    interval: u64,
+    order: Order,
+    resolved: u64,
+    current: Option<SocketAddr>,
+    seed: u64,
+}
+
+impl Endpoint {
+    pub fn new(name: &'static str, host: &str, port: u16) -> Endpoint {
+        let clock = Arc::new(SystemClock);
+        let now = clock.now();
+
+        Endpoint {
+            name: name,
+            host: host.to_string(),
+            port: port,
+            resolver: Arc::new(SystemResolver),
+            clock: clock,
+            interval: 60000,
+            order: Order::Resolved,
+            resolved: now,
+            current: None,
+            seed: now | 1,
+        }
+    }
+
+    pub fn resolver(mut self, resolver: Arc<Resolver>, clock: Arc<Clock>) -> Endpoint {
+        self.resolved = clock.now();
+        self.resolver = resolver;
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the re-resolution interval while connected in milliseconds, zero disabling it.
+    pub fn interval(mut self, interval: u64) -> Endpoint {
+        self.interval = interval;
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> Endpoint {
+        self.order = order;
+        self
+    }
+
+    /// Makes the random address order follow the given seed.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed | 1;
+    }
+
+    /// Applies the `resolve_interval` and `address_order` settings of the output config.
+    pub fn configure(self, config: &Config) -> Result<Endpoint, ConfigError> {
+        let interval = try!(config.u64_or("resolve_interval", 60000));
+        let order = match &try!(config.string_or("address_order", "resolved"))[..] {
+            "resolved" => Order::Resolved,
+            "random" => Order::Random,
+            order => return Err(ConfigError::Invalid(format!("unknown address order '{}'", order))),
+        };
+
+        Ok(self.interval(interval).order(order))
+    }
+
+    /// Returns the address of the last established connection.
+    pub fn current(&self) -> Option<SocketAddr> {
+        self.current
+    }
+
+    /// Verifies that the name resolves, without connecting.
+    pub fn check(&self) -> Result<(), String> {
+        match self.resolver.resolve(&self.host, self.port) {
+            Ok(ref addrs) if addrs.is_empty() => Err(format!("'{}' resolves to no addresses", self)),
+            Ok(..) => Ok(()),
+            Err(err) => Err(format!("unable to resolve '{}': {}", self, err)),
+        }
+    }
+
+    /// Resolves the name and connects to the first address accepting the connection.
+    pub fn connect(&mut self) -> Result<TcpStream, String> {
+        let addrs = try!(self.resolve());
+
+        let mut reason = format!("'{}' resolves to no addresses", self);
+        for addr in addrs.into_iter() {
+            match TcpStream::connect(addr) {
+                Ok(stream) => {
+                    if self.current != Some(addr) {
+                        info!(target: "Output::Endpoint", "connected to {} at {}", self, addr);
+                    }
+                    self.established(addr);
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    warn!(target: "Output::Endpoint", "unable to connect to {} at {} - {}", self, addr, err);
+                    reason = format!("unable to connect to {}: {}", addr, err);
+                }
+            }
+        }
+
+        self.current = None;
+        Err(reason)
+    }
+
+    /// Connects to the address of the last established connection again, unless it's gone
+    /// stale, for outputs opening a connection per request.
+    pub fn reconnect(&mut self) -> Option<TcpStream> {
+        let addr = match self.current {
+            Some(..) if self.stale() => return None,
+            Some(addr) => addr,
+            None => return None,
+        };
+
+        match TcpStream::connect(addr) {
+            Ok(stream) => {
+                self.established(addr);
+                Some(stream)
+            }
+            Err(err) => {
+                warn!(target: "Output::Endpoint", "unable to connect to {} at {} - {}", self, addr, err);
+                self.current = None;
+                None
+            }
+        }
+    }
+
+    /// Resolves the name again once the interval has passed, returning true if the address
+    /// connected to is no longer among the resolved ones.
+    ///
+    /// Resolution failures keep the current address, as the name is probably just unavailable
+    /// for a moment.
+    pub fn stale(&mut self) -> bool {
+        let addr = match self.current {
+            Some(addr) => addr,
+            None => return false,
+        };
+
+        if self.interval == 0 || self.clock.now().saturating_sub(self.resolved) < self.interval {
+            return false;
+        }
+
+        match self.resolve() {
+            Ok(ref addrs) if !addrs.contains(&addr) => {
+                info!(target: "Output::Endpoint", "{} no longer resolves to {}", self, addr);
+                metrics::registry().counter(&format!("output.{}.migrations", self.name)).inc();
+                true
+            }
+            Ok(..) | Err(..) => false,
+        }
+    }
+
+    fn resolve(&mut self) -> Result<Vec<SocketAddr>, String> {
+        self.resolved = self.clock.now();
+
+        let mut addrs = match self.resolver.resolve(&self.host, self.port) {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                metrics::registry().counter(&format!("output.{}.resolve.failures", self.name)).inc();
+                return Err(format!("unable to resolve '{}': {}", self, err));
+            }
+        };
+
+        if self.order == Order::Random {
+            // Fisher-Yates driven by xorshift, as nothing has to be unpredictable here.
+            for id in (1..addrs.len()).rev() {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                addrs.swap(id, (self.seed % (id as u64 + 1)) as usize);
+            }
+        }
+
+        Ok(addrs)
+    }
+
+    fn established(&mut self, addr: SocketAddr) {
+        self.current = Some(addr);
+        metrics::registry().counter(&format!("output.{}.connections.{}", self.name, addr)).inc();
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use super::super::super::clock::MockClock;
+    use super::super::super::testing::Dns;
+    use super::Endpoint;
+
+    #[test]
+    fn resolve_again_once_interval_passes() {
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dns = Dns::new(vec![first.local_addr().unwrap()]);
+        let clock = MockClock::new(0);
+
+        let mut endpoint = Endpoint::new("test_endpoint", "collector", 0)
+            .resolver(Arc::new(dns.clone()), Arc::new(clock.clone()))
+            .interval(1000);
+        endpoint.connect().unwrap();
+        assert_eq!(Some(first.local_addr().unwrap()), endpoint.current());
+
+        dns.set(vec![second.local_addr().unwrap()]);
+        assert!(!endpoint.stale());
+        assert!(endpoint.reconnect().is_some());
+
+        clock.advance(1000);
+        assert!(endpoint.stale());
+        endpoint.connect().unwrap();
+        assert_eq!(Some(second.local_addr().unwrap()), endpoint.current());
+    }
+
+    #[test]
+    fn fail_without_panic_when_unresolvable() {
+        let mut endpoint = Endpoint::new("test_endpoint_unresolvable", "collector", 0)
+            .resolver(Arc::new(Dns::failing()), Arc::new(MockClock::new(0)));
+
+        assert!(endpoint.connect().is_err());
+        assert!(endpoint.check().is_err());
+        assert_eq!(None, endpoint.current());
+    }
+}
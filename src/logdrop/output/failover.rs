@@ -0,0 +1,535 @@
+use std::sync::Arc;
+
+use super::super::Record;
+use super::super::audit;
+use super::super::breaker;
+use super::super::breaker::Breaker;
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::super::metrics;
+use super::super::registry;
+use super::{Error, Output};
+
+/// Settings deciding when a failover pair switches between its targets.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Policy {
+    /// Number of consecutive retryable errors after which the primary is considered dead.
+    pub failures: u32,
+    /// Interval between primary health probes in milliseconds.
+    pub interval: u64,
+    /// Number of consecutive successful probes required to switch back to the primary.
+    pub recoveries: u32,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            failures: 3,
+            interval: 5000,
+            recoveries: 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    Primary,
+    Secondary,
+}
+
+/// Output pair that sends records to the primary output until it fails, then switches to the
+/// secondary one.
+///
+/// Records the primary fails to accept are handed to the secondary one, even before enough
+/// failures in a row trip the pair.
+///
+/// The primary is probed at the configured interval using its own `probe` implementation, both
+/// between records and on flushes, which come on the pipeline flush ticks while no records
+/// arrive. A failed probe trips the pair immediately, while switching back requires several
+/// successful probes in a row, so a flapping primary can't bounce the traffic.
+///
+/// With the circuit breaker set, an open breaker trips the pair too, for example once the primary
+/// gets slow rather than failing. The primary is not probed until the breaker half-opens, a failed
+/// probe opening it again for a longer cool-down.
+pub struct Failover {
+    primary: Box<Output>,
+    secondary: Box<Output>,
+    policy: Policy,
+    clock: Arc<Clock>,
+    active: Target,
+    failures: u32,
+    recoveries: u32,
+    probed: u64,
+    transitions: u64,
+    breaker: Option<Breaker>,
+}
+
+impl Failover {
+    pub fn new(primary: Box<Output>, secondary: Box<Output>, policy: Policy, clock: Arc<Clock>) -> Failover {
+        let now = clock.now();
+
+        Failover {
+            primary: primary,
+            secondary: secondary,
+            policy: policy,
+            clock: clock,
+            active: Target::Primary,
+            failures: 0,
+            recoveries: 0,
+            probed: now,
+            transitions: 0,
+            breaker: None,
+        }
+    }
+
+    /// Guards the primary by the circuit breaker.
+    pub fn breaker(mut self, breaker: Breaker) -> Failover {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    pub fn active(&self) -> Target {
+        self.active
+    }
+
+    /// Returns the number of switches between targets made so far.
+    pub fn transitions(&self) -> u64 {
+        self.transitions
+    }
+
+    fn switch(&mut self, target: Target) {
+        info!(target: "Output::Failover", "switching from {:?} to {:?} output", self.active, target);
+        audit::record("failover", "internal", vec![
+            ("from", format!("{:?}", self.active).to_lowercase()),
+            ("to", format!("{:?}", target).to_lowercase()),
+        ]);
+
+        self.active = target;
+        self.failures = 0;
+        self.recoveries = 0;
+        self.probed = self.clock.now();
+        self.transitions += 1;
+        metrics::registry().counter("output.failover.transitions").inc();
+    }
+
+    fn probe_primary(&mut self) {
+        let now = self.clock.now();
+        if now.saturating_sub(self.probed) < self.policy.interval {
+            return;
+        }
+
+        if self.active == Target::Secondary {
+            if let Some(ref mut guard) = self.breaker {
+                if !guard.allow() {
+                    return;
+                }
+            }
+        }
+
+        self.probed = now;
+        let healthy = self.primary.probe();
+        debug!(target: "Output::Failover", "primary probe result: {}", healthy);
+
+        if let Some(ref mut guard) = self.breaker {
+            if guard.state() == breaker::State::HalfOpen {
+                if healthy {
+                    guard.success(0);
+                } else {
+                    guard.failure(0);
+                }
+            }
+        }
+
+        match (self.active, healthy) {
+            (Target::Primary, true) => {}
+            (Target::Primary, false) => {
+                warn!(target: "Output::Failover", "primary output probe failed");
+                self.switch(Target::Secondary);
+            }
+            (Target::Secondary, true) => {
+                self.recoveries += 1;
+                if self.recoveries >= self.policy.recoveries {
+                    self.switch(Target::Primary);
+                }
+            }
+            (Target::Secondary, false) => {
+                self.recoveries = 0;
+            }
+        }
+    }
+}
+
+impl FromConfig for Failover {
+    fn from_config(config: &Config) -> Result<Failover, ConfigError> {
+        let primary = match config.find("primary") {
+            Some(config) => try!(registry::output(&config)),
+            None => return Err(ConfigError::Invalid("failover requires 'primary' output".to_string())),
+        };
+        let secondary = match config.find("secondary") {
+            Some(config) => try!(registry::output(&config)),
+            None => return Err(ConfigError::Invalid("failover requires 'secondary' output".to_string())),
+        };
+
+        let default = Policy::default();
+        let policy = Policy {
+            failures: try!(config.u64_or("failures", default.failures as u64)) as u32,
+            interval: try!(config.u64_or("interval", default.interval)),
+            recoveries: try!(config.u64_or("recoveries", default.recoveries as u64)) as u32,
+        };
+
+        let failover = Failover::new(primary, secondary, policy, Arc::new(SystemClock));
+        match config.find("breaker") {
+            Some(config) => {
+                let settings = try!(breaker::Settings::from_config(&config));
+                Ok(failover.breaker(Breaker::new("output.failover", settings, Arc::new(SystemClock))))
+            }
+            None => Ok(failover),
+        }
+    }
+}
+
+impl Output for Failover {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        self.probe_primary();
+
+        match self.active {
+            Target::Primary => {
+                let allowed = match self.breaker {
+                    Some(ref mut guard) => guard.allow(),
+                    None => true,
+                };
+                if !allowed {
+                    warn!(target: "Output::Failover", "primary output breaker is open");
+                    self.switch(Target::Secondary);
+                    return self.secondary.feed(payload);
+                }
+
+                let started = self.clock.now();
+                let result = self.primary.feed(payload);
+                let open = match self.breaker {
+                    Some(ref mut guard) => {
+                        let latency = self.clock.now().saturating_sub(started);
+                        match result {
+                            Ok(()) => guard.success(latency),
+                            Err(Error::Retryable(..)) | Err(Error::Ambiguous(..)) => guard.failure(latency),
+                            Err(Error::Fatal(..)) => {}
+                        }
+                        guard.state() == breaker::State::Open
+                    }
+                    None => false,
+                };
+
+                match result {
+                    Ok(()) if open => {
+                        warn!(target: "Output::Failover", "primary output is too slow");
+                        self.switch(Target::Secondary);
+                        Ok(())
+                    }
+                    Ok(()) => {
+                        self.failures = 0;
+                        Ok(())
+                    }
+                    Err(Error::Retryable(reason)) => {
+                        self.failures += 1;
+                        warn!(target: "Output::Failover", "primary output failed ({} in a row) - {}", self.failures, reason);
+
+                        if open || self.failures >= self.policy.failures {
+                            self.switch(Target::Secondary);
+                        }
+                        self.secondary.feed(payload)
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Target::Secondary => self.secondary.feed(payload),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.probe_primary();
+
+        match self.active {
+            Target::Primary => self.primary.flush(),
+            Target::Secondary => self.secondary.flush(),
+        }
+    }
+
+    fn probe(&mut self) -> bool {
+        match self.active {
+            Target::Primary => self.primary.probe(),
+            Target::Secondary => self.secondary.probe(),
+        }
+    }
+
+    fn discard(&mut self) -> usize {
+        match self.active {
+            Target::Primary => self.primary.discard(),
+            Target::Secondary => self.secondary.discard(),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        try!(self.primary.check().map_err(|err| format!("primary: {}", err)));
+        self.secondary.check().map_err(|err| format!("secondary: {}", err))
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        match (self.primary.describe_fields(), self.secondary.describe_fields()) {
+            (Some(mut primary), Some(secondary)) => {
+                primary.extend(secondary.into_iter());
+                Some(primary)
+            }
+            _ => None,
+        }
+    }
+
+    fn oversize(&self) -> bool {
+        self.primary.oversize() && self.secondary.oversize()
+    }
+
+    fn ordered(&self) -> bool {
+        self.primary.ordered() && self.secondary.ordered()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.primary.reseed(seed);
+        self.secondary.reseed(seed ^ 0x9e3779b97f4a7c15);
+    }
+
+    fn set_clock(&mut self, clock: Arc<Clock>) {
+        if let Some(ref mut guard) = self.breaker {
+            guard.set_clock(clock.clone());
+        }
+        self.primary.set_clock(clock.clone());
+        self.secondary.set_clock(clock.clone());
+        self.probed = clock.now();
+        self.clock = clock;
+    }
+
+    fn sort_keys(&mut self) {
+        self.primary.sort_keys();
+        self.secondary.sort_keys();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::super::super::Record;
+    use super::super::super::breaker::{Breaker, Settings};
+    use super::super::super::clock::{MockClock, SystemClock};
+    use super::super::super::testing::{Collector, record, string};
+    use super::super::{Error, Output};
+    use super::{Failover, Policy, Target};
+
+    struct Scripted {
+        healthy: Arc<AtomicBool>,
+    }
+
+    impl Output for Scripted {
+        fn feed(&mut self, _: &Record) -> Result<(), Error> {
+            if self.healthy.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err(Error::Retryable("connection refused".to_string()))
+            }
+        }
+
+        fn probe(&mut self) -> bool {
+            self.healthy.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Accepts records, taking the given time for each one.
+    struct Slow {
+        clock: MockClock,
+        delay: Arc<AtomicUsize>,
+    }
+
+    impl Output for Slow {
+        fn feed(&mut self, _: &Record) -> Result<(), Error> {
+            self.clock.advance(self.delay.load(Ordering::SeqCst) as u64);
+            Ok(())
+        }
+    }
+
+    fn policy() -> Policy {
+        Policy {
+            failures: 3,
+            interval: 1000,
+            recoveries: 2,
+        }
+    }
+
+    fn failover(healthy: Arc<AtomicBool>, secondary: Collector, clock: MockClock) -> Failover {
+        let primary = Scripted { healthy: healthy };
+        Failover::new(Box::new(primary), Box::new(secondary), policy(), Arc::new(clock))
+    }
+
+    #[test]
+    fn switch_after_consecutive_failures() {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let collector = Collector::new();
+        let mut output = failover(healthy.clone(), collector.clone(), MockClock::new(0));
+
+        let payload = record(vec![("message", string("le message"))]);
+        assert_eq!(Ok(()), output.feed(&payload));
+        assert_eq!(Ok(()), output.feed(&payload));
+        assert_eq!(Target::Primary, output.active());
+
+        assert_eq!(Ok(()), output.feed(&payload));
+        assert_eq!(Target::Secondary, output.active());
+        assert_eq!(1, output.transitions());
+
+        // Records failed before the switch are handed to the secondary too.
+        assert_eq!(Ok(()), output.feed(&payload));
+        assert_eq!(4, collector.records().len());
+    }
+
+    #[test]
+    fn switch_on_failed_probe() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let collector = Collector::new();
+        let clock = MockClock::new(0);
+        let mut output = failover(healthy.clone(), collector.clone(), clock.clone());
+
+        let payload = record(vec![("message", string("le message"))]);
+        output.feed(&payload).unwrap();
+        assert_eq!(0, collector.records().len());
+
+        healthy.store(false, Ordering::SeqCst);
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+
+        assert_eq!(Target::Secondary, output.active());
+        assert_eq!(vec![payload], collector.records());
+    }
+
+    #[test]
+    fn switch_back_after_consecutive_recoveries() {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let collector = Collector::new();
+        let clock = MockClock::new(0);
+        let mut output = failover(healthy.clone(), collector.clone(), clock.clone());
+
+        let payload = record(vec![("message", string("le message"))]);
+        for _ in 0..3 {
+            let _ = output.feed(&payload);
+        }
+        assert_eq!(Target::Secondary, output.active());
+
+        // The primary flaps: a single successful probe is not enough.
+        healthy.store(true, Ordering::SeqCst);
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+        healthy.store(false, Ordering::SeqCst);
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+        healthy.store(true, Ordering::SeqCst);
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+        assert_eq!(Target::Secondary, output.active());
+
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+        assert_eq!(Target::Primary, output.active());
+        assert_eq!(2, output.transitions());
+
+        // Records sent while the primary was down all reached the secondary.
+        assert_eq!(6, collector.records().len());
+    }
+
+    #[test]
+    fn probe_is_not_sent_before_interval() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let collector = Collector::new();
+        let clock = MockClock::new(0);
+        let mut output = failover(healthy.clone(), collector.clone(), clock.clone());
+
+        healthy.store(false, Ordering::SeqCst);
+        clock.advance(999);
+        output.flush().unwrap();
+        output.feed(&record(vec![])).unwrap();
+        assert_eq!(Target::Primary, output.active());
+        assert_eq!(1, collector.records().len());
+    }
+
+    #[test]
+    fn probe_primary_on_flush_without_records() {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let collector = Collector::new();
+        let clock = MockClock::new(0);
+        let mut output = failover(healthy.clone(), collector.clone(), clock.clone());
+
+        healthy.store(false, Ordering::SeqCst);
+        clock.advance(1000);
+        output.flush().unwrap();
+        assert_eq!(Target::Secondary, output.active());
+
+        healthy.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            clock.advance(1000);
+            output.flush().unwrap();
+        }
+        assert_eq!(Target::Primary, output.active());
+        assert!(collector.records().is_empty());
+    }
+
+    #[test]
+    fn switch_on_open_breaker() {
+        let clock = MockClock::new(0);
+        let delay = Arc::new(AtomicUsize::new(30000));
+        let primary = Slow { clock: clock.clone(), delay: delay.clone() };
+        let collector = Collector::new();
+        let settings = Settings { latency: Some(1000), window: 2, cooldown: 60000, ..Settings::default() };
+        let breaker = Breaker::new("output.failover.test", settings, Arc::new(clock.clone()));
+        let mut output = Failover::new(Box::new(primary), Box::new(collector.clone()), policy(), Arc::new(clock.clone()))
+            .breaker(breaker);
+
+        let payload = record(vec![("message", string("le message"))]);
+        output.feed(&payload).unwrap();
+        assert_eq!(Target::Primary, output.active());
+        output.feed(&payload).unwrap();
+        assert_eq!(Target::Secondary, output.active());
+
+        // Not probed until the breaker half-opens, then switched back after recoveries.
+        delay.store(0, Ordering::SeqCst);
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+        assert_eq!(vec![payload.clone()], collector.records());
+
+        clock.advance(59000);
+        output.feed(&payload).unwrap();
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+        assert_eq!(Target::Primary, output.active());
+        assert_eq!(2, collector.records().len());
+    }
+    #[test]
+    fn follow_injected_clock() {
+        let clock = MockClock::new(0);
+        let delay = Arc::new(AtomicUsize::new(30000));
+        let primary = Slow { clock: clock.clone(), delay: delay.clone() };
+        let collector = Collector::new();
+        let settings = Settings { latency: Some(1000), window: 2, cooldown: 60000, ..Settings::default() };
+        let breaker = Breaker::new("output.failover.test_clock", settings, Arc::new(SystemClock));
+        let mut output = Failover::new(Box::new(primary), Box::new(collector.clone()), policy(), Arc::new(SystemClock))
+            .breaker(breaker);
+        output.set_clock(Arc::new(clock.clone()));
+
+        // Latencies are measured by the injected clock, opening the breaker.
+        let payload = record(vec![("message", string("le message"))]);
+        output.feed(&payload).unwrap();
+        output.feed(&payload).unwrap();
+        assert_eq!(Target::Secondary, output.active());
+
+        delay.store(0, Ordering::SeqCst);
+        clock.advance(60000);
+        output.feed(&payload).unwrap();
+        clock.advance(1000);
+        output.feed(&payload).unwrap();
+        assert_eq!(Target::Primary, output.active());
+    }
+}
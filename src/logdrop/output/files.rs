@@ -1,12 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::fs;
 use std::fs::{File, OpenOptions, PathExt};
-use std::io::Write;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::sync::Arc;
 
-use libc;
+use chrono::NaiveDateTime;
+use chrono::format::{Item, StrftimeItems};
 
-use super::super::Record;
-use super::Output;
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::super::filter;
+use super::super::metrics;
+use super::super::snapshot::Snapshot;
+use super::super::source;
+use super::{Error, Output};
+
+/// Prefix of time placeholders, set apart from field names that may contain colons themselves.
+const TIME: &'static str = "@time:";
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum ParserError {
@@ -17,6 +34,13 @@ enum ParserError {
 enum ParserEvent {
     Literal(String),
     Placeholder(Vec<String>),
+    /// Timestamp at the path formatted in UTC, `{@time:path:format}`, e.g.
+    /// `{@time:timestamp:%Y-%m-%d-%H}`.
+    Time(Vec<String>, String),
+    /// Opening tag of a conditional section, `{?path}`, or of a negated one, `{!path}`.
+    Open(Vec<String>, bool),
+    /// Closing tag of a section, `{/path}`.
+    Close(Vec<String>),
     Error(ParserError),
 }
 
@@ -30,18 +54,35 @@ enum ParserState {
 struct FormatParser<T> {
     reader: T,
     state: ParserState,
+    /// Number of characters read so far.
+    offset: usize,
+    /// Character offset of the last opening brace, i.e. of the last placeholder or tag.
+    opened: usize,
 }
 
 impl<T: Iterator<Item = char>> FormatParser<T> {
     fn new(reader: T) -> FormatParser<T> {
         FormatParser {
             reader: reader,
-            state: ParserState::Undefined
+            state: ParserState::Undefined,
+            offset: 0,
+            opened: 0,
+        }
+    }
+
+    fn read(&mut self) -> Option<char> {
+        let ch = self.reader.next();
+        if let Some('{') = ch {
+            self.opened = self.offset;
+        }
+        if ch.is_some() {
+            self.offset += 1;
         }
+        ch
     }
 
     fn parse(&mut self) -> Option<ParserEvent> {
-        match self.reader.next() {
+        match self.read() {
             Some('{') => { self.parse_placeholder() }
             Some(ch)  => { self.parse_literal(ch) }
             None      => { None }
@@ -53,7 +94,7 @@ impl<T: Iterator<Item = char>> FormatParser<T> {
         result.push(ch);
 
         loop {
-            match self.reader.next() {
+            match self.read() {
                 Some('{') => {
                     self.state = ParserState::ParsePlaceholder;
                     break
@@ -69,14 +110,30 @@ impl<T: Iterator<Item = char>> FormatParser<T> {
     fn parse_placeholder(&mut self) -> Option<ParserEvent> {
         let mut result = String::new();
 
+        let split = |path: &str| -> Vec<String> {
+            path.split('/').map(|v| v.to_string()).collect()
+        };
+
         loop {
-            match self.reader.next() {
+            match self.read() {
                 Some('}') => {
                     self.state = ParserState::Undefined;
-                    let result = result.split('/').map(|v| {
-                        v.to_string()
-                    }).collect();
-                    return Some(ParserEvent::Placeholder(result));
+                    let event = if result.starts_with('?') {
+                        ParserEvent::Open(split(&result[1..]), false)
+                    } else if result.starts_with('!') {
+                        ParserEvent::Open(split(&result[1..]), true)
+                    } else if result.starts_with('/') {
+                        ParserEvent::Close(split(&result[1..]))
+                    } else if result.starts_with(TIME) {
+                        let spec = &result[TIME.len()..];
+                        match spec.find(':') {
+                            Some(colon) => ParserEvent::Time(split(&spec[..colon]), spec[colon + 1..].to_string()),
+                            None => ParserEvent::Time(split(spec), String::new()),
+                        }
+                    } else {
+                        ParserEvent::Placeholder(split(&result))
+                    };
+                    return Some(event);
                 }
                 Some(c) => { result.push(c) }
                 None    => {
@@ -100,144 +157,688 @@ impl<T: Iterator<Item = char>> Iterator for FormatParser<T> {
     }
 }
 
+/// Compiled template element.
+#[derive(Debug, PartialEq)]
+enum Node {
+    Token(ParserEvent),
+    /// Nodes rendered only when the path resolves to a non-null value, or only when it doesn't
+    /// for a negated section.
+    Section {
+        path: Vec<String>,
+        negated: bool,
+        nodes: Vec<Node>,
+    },
+}
+
+/// Template compilation error, with the character offset of the offending placeholder or tag.
+#[derive(Debug, PartialEq)]
+enum TemplateError {
+    Syntax(ParserError, usize),
+    /// Section never closed.
+    Unclosed(String, usize),
+    /// Closing tag not matching the innermost open section.
+    Unmatched(String, usize),
+    /// Time format missing or with an unknown specifier.
+    TimeFormat(String, usize),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TemplateError::Syntax(err, position) => write!(f, "{:?} at {}", err, position),
+            TemplateError::Unclosed(ref path, position) => write!(f, "section '{}' opened at {} is never closed", path, position),
+            TemplateError::Unmatched(ref path, position) => write!(f, "closing tag '{{/{}}}' at {} matches no open section", path, position),
+            TemplateError::TimeFormat(ref format, position) => write!(f, "invalid time format '{}' at {}", format, position),
+        }
+    }
+}
+
+/// Compiles the template into a tree of nodes, sections holding their contents.
+fn compile(format: &str) -> Result<Vec<Node>, TemplateError> {
+    let mut parser = FormatParser::new(format.chars());
+    // Open sections, innermost last, together with the nodes of their parents.
+    let mut open: Vec<(Vec<String>, bool, usize, Vec<Node>)> = Vec::new();
+    let mut nodes = Vec::new();
+
+    while let Some(event) = parser.next() {
+        let position = parser.opened;
+        match event {
+            ParserEvent::Error(err) => return Err(TemplateError::Syntax(err, position)),
+            ParserEvent::Open(path, negated) => {
+                let parent = mem::replace(&mut nodes, Vec::new());
+                open.push((path, negated, position, parent));
+            }
+            ParserEvent::Close(path) => {
+                let matched = match open.last() {
+                    Some(&(ref opened, _, _, _)) => *opened == path,
+                    None => false,
+                };
+                if !matched {
+                    return Err(TemplateError::Unmatched(path.join("/"), position));
+                }
+
+                let (path, negated, _, parent) = open.pop().unwrap();
+                let section = Node::Section {
+                    path: path,
+                    negated: negated,
+                    nodes: mem::replace(&mut nodes, parent),
+                };
+                nodes.push(section);
+            }
+            ParserEvent::Time(_, ref format) if format.is_empty() || StrftimeItems::new(format).any(|item| item == Item::Error) => {
+                return Err(TemplateError::TimeFormat(format.clone(), position));
+            }
+            event => nodes.push(Node::Token(event)),
+        }
+    }
+
+    match open.pop() {
+        Some((path, _, position, _)) => Err(TemplateError::Unclosed(path.join("/"), position)),
+        None => Ok(nodes),
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum TokenError<'r> {
     KeyNotFound(&'r str),
     TypeMismatch,
     SyntaxError(ParserError),
+    /// Timestamp missing, unparsable or out of range.
+    Timestamp(String),
+}
+
+/// Returns the timestamp at the path as the time in UTC.
+fn time(path: &[String], payload: &Record) -> Result<NaiveDateTime, String> {
+    let seconds = try!(filter::timestamp(payload.find_path(path), &path.join("/")));
+    let whole = seconds.floor();
+    let nanos = ((seconds - whole) * 1e9) as u32;
+    if whole.abs() > i64::max_value() as f64 {
+        return Err(format!("timestamp {} out of range", seconds));
+    }
+
+    NaiveDateTime::from_timestamp_opt(whole as i64, nanos).ok_or(format!("timestamp {} out of range", seconds))
 }
 
-fn consume<'r>(event: &'r ParserEvent, payload: &Record) -> Result<String, TokenError<'r>> {
+/// Renders the token, formatting the time given in seconds since the Unix epoch for timestamps
+/// the record lacks, if any.
+fn consume<'r>(event: &'r ParserEvent, payload: &Record, now: Option<f64>) -> Result<String, TokenError<'r>> {
     match *event {
         ParserEvent::Literal(ref value) => { Ok(value.clone()) }
+        ParserEvent::Placeholder(ref placeholders) if placeholders.len() == 1 && placeholders[0] == "@source" => {
+            source::key(payload).map(|key| key.to_string()).ok_or(TokenError::KeyNotFound(&placeholders[0]))
+        }
         ParserEvent::Placeholder(ref placeholders) => {
-            let mut current = payload;
+            let mut current = None;
             for key in placeholders.iter() {
-                match current.find(key) {
-                    Some(v) => { current = v; }
+                let value = match current {
+                    None => payload.find(key),
+                    Some(current) => RecordItem::find(current, key),
+                };
+
+                match value {
+                    Some(v) => { current = Some(v); }
                     None    => { return Err(TokenError::KeyNotFound(&key)); }
                 }
             }
 
-            match *current {
+            match *current.unwrap() {
+                RecordItem::Null => Ok("null".to_string()),
+                RecordItem::Bool(v) => Ok(v.to_string()),
+                RecordItem::F64(v) => Ok(v.to_string()),
+                RecordItem::I64(v) => Ok(v.to_string()),
+                RecordItem::U64(v) => Ok(v.to_string()),
                 RecordItem::String(ref v) => Ok(v.clone()),
+                RecordItem::Text(ref v) => Ok(v.as_str().to_string()),
+                RecordItem::Bytes(..) => Err(TokenError::TypeMismatch),
                 RecordItem::Array(..) => Err(TokenError::TypeMismatch),
                 RecordItem::Object(..) => Err(TokenError::TypeMismatch),
-                ref other => Ok(format!("{:?}", other)),
             }
         }
+        ParserEvent::Time(ref path, ref format) => {
+            let time = match (time(path, payload), now) {
+                (Ok(time), _) => time,
+                (Err(..), Some(now)) => NaiveDateTime::from_timestamp(now as i64, 0),
+                (Err(reason), None) => return Err(TokenError::Timestamp(reason)),
+            };
+            Ok(time.format(format).to_string())
+        }
         ParserEvent::Error(err) => { Err(TokenError::SyntaxError(err)) }
+        // Compiled into sections.
+        ParserEvent::Open(..) | ParserEvent::Close(..) => { Ok(String::new()) }
+    }
+}
+
+/// Returns true if the path resolves to a non-null value.
+fn present(path: &[String], payload: &Record) -> bool {
+    if path.len() == 1 && path[0] == "@source" {
+        return source::key(payload).is_some();
+    }
+
+    match payload.find_path(path) {
+        Some(&RecordItem::Null) | None => false,
+        Some(..) => true,
+    }
+}
+
+fn render<'r>(nodes: &'r [Node], payload: &Record, now: Option<f64>, result: &mut String)
+    -> Result<(), TokenError<'r>>
+{
+    for node in nodes.iter() {
+        match *node {
+            Node::Token(ref event) => result.push_str(&try!(consume(event, payload, now))),
+            Node::Section { ref path, negated, ref nodes } => {
+                if present(path, payload) != negated {
+                    try!(render(nodes, payload, now, result));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn describe_path(path: &[String], fields: &mut Vec<String>) {
+    if path.len() == 1 && path[0] == "@source" {
+        fields.extend(source::fields().into_iter());
+    } else {
+        fields.extend(path.first().cloned().into_iter());
+    }
+}
+
+/// Collects top-level fields read by the nodes, including the ones sections depend on.
+fn describe(nodes: &[Node], fields: &mut Vec<String>) {
+    for node in nodes.iter() {
+        match *node {
+            Node::Token(ParserEvent::Placeholder(ref path)) |
+            Node::Token(ParserEvent::Time(ref path, _)) => describe_path(path, fields),
+            Node::Section { ref path, ref nodes, .. } => {
+                describe_path(path, fields);
+                describe(nodes, fields);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Compiled template, the same the file output renders paths and lines by, e.g.
+/// `{level} {source}: {msg}`, for other components to render records with.
+///
+/// Rendering fails if any placeholder outside of sections is missing, so that defaults are given
+/// by negated sections, e.g. `{?msg}{msg}{/msg}{!msg}-{/msg}`.
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn new(format: &str) -> Result<Template, String> {
+        match compile(format) {
+            Ok(nodes) => Ok(Template { nodes: nodes }),
+            Err(err) => Err(format!("invalid template: {}", err)),
+        }
+    }
+
+    pub fn render(&self, payload: &Record) -> Result<String, String> {
+        let mut result = String::new();
+        match render(&self.nodes, payload, None, &mut result) {
+            Ok(()) => Ok(result),
+            Err(err) => Err(format!("unable to render template - {:?}", err)),
+        }
+    }
+
+    /// Returns top-level fields the template reads.
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields = Vec::new();
+        describe(&self.nodes, &mut fields);
+        fields
     }
 }
 
 /// File output will write log events to files on disk.
 ///
 /// Path can contain placeholders. For example: test.log, {source}.log, {source/host}.log
-/// It creates directories and files (with append mode) automatically.
+/// The special {@source} placeholder is replaced with the record source key.
+/// It creates directories and files (with append mode) automatically, unless told not to create
+/// directories. Records the templates can't be rendered for fail fatally, as do ones whose file
+/// can't be created or opened, since the path is particular to the record and retrying it would
+/// only take the whole output down. Failing to write is retryable. Either way it's left to the
+/// pipeline what happens to the record, see `OnError`.
 /// Log format: {timestamp} {message} by default. Can contain any attributes.
-/// If attribute not found - drop event and warn.
+///
+/// Conditional sections render their contents only when the field is present and not null, e.g.
+/// `{message}{?request_id} [{request_id}]{/request_id}`, while negated ones, `{!field}...{/field}`,
+/// render them only when it's absent. Sections may be nested.
+///
+/// Time placeholders, `{@time:field:format}`, format the timestamp the record carries in the field
+/// in UTC using `strftime` specifiers, e.g. `{@time:timestamp:%Y-%m-%d-%H}.log` buckets records
+/// into hourly files by their own time rather than the arrival one, so that replayed or delayed
+/// records land where they belong. Such paths are partitioned by time already. Records whose
+/// timestamp is missing or can't be parsed either take the current time or fail, see
+/// `MissingTimestamp`. Other placeholders containing colons, e.g. `{host:port}`, stay field names.
+///
+/// As replays touch many buckets, the number of files kept open may be capped, the least recently
+/// written one being closed to open another, which is counted in `output.file.evicted`.
+///
+/// Each line is written by a single call into a file opened in append mode, so that the kernel
+/// keeps lines of concurrent writers, other processes included, from interleaving. That holds for
+/// lines up to the atomic limit only, longer ones are either written anyway, risking interleaving,
+/// or rejected, see `NonAtomic`. A line written partially, e.g. as the disk is full, is terminated
+/// by the repair marker and retried as a whole.
+///
+/// With tail repair enabled, files are checked when opened and, as the output carries the list of
+/// files it owns over restarts, once restored from the snapshot. A file whose last line lacks the
+/// newline, e.g. after a crash mid-write, gets the repair marker and the newline appended, so that
+/// line-oriented parsers resynchronize.
 pub struct FileOutput {
-    path: Vec<ParserEvent>,
-    message: Vec<ParserEvent>,
+    path: Vec<Node>,
+    message: Vec<Node>,
     files: HashMap<u64, File>,
+    oversize: bool,
+    create_dirs: bool,
+    atomic_limit: usize,
+    non_atomic: NonAtomic,
+    /// Repair marker, if tails are repaired.
+    repair: Option<String>,
+    marker: String,
+    /// Paths of files written to, carried over restarts to be repaired.
+    owned: HashSet<String>,
+    missing_timestamp: MissingTimestamp,
+    clock: Arc<Clock>,
+    max_open: Option<usize>,
+    /// Tick each open file has been written at last, by inode.
+    used: HashMap<u64, u64>,
+    tick: u64,
+}
+
+/// What to do with a record lacking the timestamp a time placeholder needs, or having one that
+/// can't be parsed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MissingTimestamp {
+    /// Format the current time instead.
+    Now,
+    /// Fail the record fatally, so that the `on_error` policy of the output decides what happens
+    /// to it, e.g. dead-letters it.
+    Reject,
+}
+
+/// What to do with a line longer than the atomic limit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NonAtomic {
+    /// Write the line anyway, counting it in `output.file.non_atomic.written`.
+    Write,
+    /// Fail the record fatally, counting it in `output.file.non_atomic.rejected`, so that the
+    /// `on_error` policy of the output decides what happens to it.
+    Reject,
+}
+
+/// Length in bytes appends of which are expected to be atomic, i.e. `PIPE_BUF` on Linux.
+const ATOMIC_LIMIT: usize = 4096;
+
+const REPAIR_MARKER: &'static str = " [truncated]";
+
+/// Appends the marker and the newline by a single write if the file isn't empty and lacks the
+/// newline at the end, returning whether it did.
+fn repair(file: &mut File, marker: &str) -> io::Result<bool> {
+    if try!(file.metadata()).len() == 0 {
+        return Ok(false);
+    }
+
+    let mut last = [0; 1];
+    try!(file.seek(SeekFrom::End(-1)));
+    if try!(file.read(&mut last)) == 1 && last[0] == b'\n' {
+        return Ok(false);
+    }
+
+    let mut tail = marker.as_bytes().to_vec();
+    tail.push(b'\n');
+    try!(file.write(&tail));
+    metrics::registry().counter("output.file.repaired").inc();
+    Ok(true)
+}
+
+/// Creates the directory together with its parents, tolerating other threads or processes
+/// creating any of them concurrently.
+fn create_dirs(dir: &Path) -> io::Result<()> {
+    const ATTEMPTS: u32 = 3;
+
+    let mut attempt = 1;
+    loop {
+        match fs::create_dir_all(dir) {
+            Ok(()) => return Ok(()),
+            Err(..) if dir.is_dir() => return Ok(()),
+            // Somebody else has just created one of the parents, making us fail halfway.
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && attempt < ATTEMPTS => attempt += 1,
+            Err(err) => return Err(err),
+        }
+    }
 }
 
 impl FileOutput {
-    pub fn new(path: &str, format: &str) -> FileOutput {
-        FileOutput {
-            path: FormatParser::new(path.chars()).collect(),
-            message: FormatParser::new(format.chars()).collect(),
+    /// Compiles the path and message templates, failing with the description of the first error.
+    pub fn new(path: &str, format: &str) -> Result<FileOutput, String> {
+        let path = try!(compile(path).map_err(|err| format!("invalid path template: {}", err)));
+        let message = try!(compile(format).map_err(|err| format!("invalid message format: {}", err)));
+
+        let output = FileOutput {
+            path: path,
+            message: message,
             files: HashMap::new(),
+            oversize: false,
+            create_dirs: true,
+            atomic_limit: ATOMIC_LIMIT,
+            non_atomic: NonAtomic::Write,
+            repair: None,
+            marker: REPAIR_MARKER.to_string(),
+            owned: HashSet::new(),
+            missing_timestamp: MissingTimestamp::Now,
+            clock: Arc::new(SystemClock),
+            max_open: None,
+            used: HashMap::new(),
+            tick: 0,
+        };
+
+        Ok(output)
+    }
+
+    /// Sets the length in bytes, newline included, past which lines are handled by the policy.
+    pub fn atomic_limit(mut self, limit: usize, non_atomic: NonAtomic) -> FileOutput {
+        self.atomic_limit = limit;
+        self.non_atomic = non_atomic;
+        self
+    }
+
+    /// Makes the output repair tails of its files with the marker, which also terminates lines
+    /// written partially.
+    pub fn repair_tail(mut self, marker: &str) -> FileOutput {
+        self.repair = Some(marker.to_string());
+        self.marker = marker.to_string();
+        self
+    }
+
+    /// Repairs the tail of the file at the path if it exists, logging failures.
+    fn repair_path(&self, path: &str) {
+        let marker = match self.repair {
+            Some(ref marker) => marker,
+            None => return,
+        };
+
+        let result = OpenOptions::new().read(true).append(true).open(path).and_then(|mut file| {
+            repair(&mut file, marker)
+        });
+        match result {
+            Ok(true) => warn!(target: "Output::File", "repaired the truncated last line of '{}'", path),
+            Ok(false) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!(target: "Output::File", "unable to repair '{}' - {}", path, err),
+        }
+    }
+
+    /// Makes the output accept oversize records intact instead of their stubs.
+    pub fn accept_oversize(mut self, oversize: bool) -> FileOutput {
+        self.oversize = oversize;
+        self
+    }
+
+    /// Makes the output create missing parent directories of resolved paths, which is the default.
+    pub fn create_dirs(mut self, create: bool) -> FileOutput {
+        self.create_dirs = create;
+        self
+    }
+
+    /// Sets what happens to records time placeholders can't be rendered for, taking the current
+    /// time by default.
+    pub fn missing_timestamp(mut self, policy: MissingTimestamp) -> FileOutput {
+        self.missing_timestamp = policy;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<Clock>) -> FileOutput {
+        self.clock = clock;
+        self
+    }
+
+    /// Caps the number of files kept open, closing the least recently written one beyond it.
+    pub fn max_open(mut self, max: usize) -> FileOutput {
+        self.max_open = Some(max);
+        self
+    }
+
+    /// Closes the least recently written files until there is room for another one.
+    fn evict(&mut self) {
+        let max = match self.max_open {
+            Some(max) => max,
+            None => return,
+        };
+
+        while self.files.len() >= max {
+            let mut oldest = None;
+            for (&inode, &tick) in self.used.iter() {
+                match oldest {
+                    Some((_, oldest)) if oldest <= tick => {}
+                    _ => oldest = Some((inode, tick)),
+                }
+            }
+            let inode = match oldest {
+                Some((inode, _)) => inode,
+                None => return,
+            };
+            self.used.remove(&inode);
+            self.files.remove(&inode);
+            debug!(target: "Output::File", "closed the least recently written file with inode {}", inode);
+            metrics::registry().counter("output.file.evicted").inc();
+        }
+    }
+}
+
+impl FromConfig for FileOutput {
+    fn from_config(config: &Config) -> Result<FileOutput, ConfigError> {
+        let path = try!(config.string("path"));
+        let format = try!(config.string_or("format", "{timestamp} {message}"));
+        let oversize = try!(config.bool_or("oversize", false));
+        let create = try!(config.bool_or("create_dirs", true));
+        let limit = try!(config.u64_or("atomic_limit", ATOMIC_LIMIT as u64)) as usize;
+        let non_atomic = match &try!(config.string_or("non_atomic", "write"))[..] {
+            "write" => NonAtomic::Write,
+            "reject" => NonAtomic::Reject,
+            policy => return Err(ConfigError::Invalid(format!("unknown non-atomic line policy '{}'", policy))),
+        };
+        let missing = match &try!(config.string_or("missing_timestamp", "now"))[..] {
+            "now" => MissingTimestamp::Now,
+            "reject" => MissingTimestamp::Reject,
+            policy => return Err(ConfigError::Invalid(format!("unknown missing timestamp policy '{}'", policy))),
+        };
+
+        let output = try!(FileOutput::new(&path, &format).map_err(ConfigError::Invalid));
+        let mut output = output.accept_oversize(oversize).create_dirs(create).atomic_limit(limit, non_atomic)
+            .missing_timestamp(missing);
+        if config.find("max_open_files").is_some() {
+            let max = try!(config.u64_or("max_open_files", 0));
+            if max == 0 {
+                return Err(ConfigError::Invalid("'max_open_files' must be positive".to_string()));
+            }
+            output = output.max_open(max as usize);
+        }
+        if try!(config.bool_or("repair_tail", false)) {
+            Ok(output.repair_tail(&try!(config.string_or("repair_marker", REPAIR_MARKER))))
+        } else {
+            Ok(output)
         }
     }
 }
 
 impl Output for FileOutput {
-    fn feed(&mut self, payload: &Record) {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        let now = match self.missing_timestamp {
+            MissingTimestamp::Now => Some(self.clock.now() as f64 / 1000.0),
+            MissingTimestamp::Reject => None,
+        };
+
         let mut path = String::new();
-        for token in self.path.iter() {
-            match consume(token, payload) {
-                Ok(token) => path.push_str(&token),
-                Err(err) => {
-                    warn!(target: "Output::File", "dropping {:?} while parsing path format - {:?}", payload, err);
-                    return;
+        if let Err(err) = render(&self.path, payload, now, &mut path) {
+            return Err(Error::Fatal(format!("unable to render path - {:?}", err)));
+        }
+
+        let path = Path::new(&path);
+        match path.parent() {
+            Some(dir) if self.create_dirs && dir != Path::new("") => {
+                if let Err(err) = create_dirs(dir) {
+                    return Err(Error::Fatal(format!("unable to create '{}' directory - {}", dir.display(), err)));
                 }
             }
+            _ => {}
         }
 
-        let path = Path::new(&path);
-        let mut stat = libc::stat {
-            st_dev: 0,
-            st_ino: 0,
-            st_nlink: 0,
-            st_mode: 0,
-            st_uid: 0,
-            st_gid: 0,
-            st_rdev: 0,
-            st_size: 0,
-            st_blksize: 0,
-            st_blocks: 0,
-            st_atime: 0,
-            st_atime_nsec: 0,
-            st_mtime: 0,
-            st_mtime_nsec: 0,
-            st_ctime: 0,
-            st_ctime_nsec: 0,
-            st_birthtime: 0,
-            st_birthtime_nsec: 0,
-            st_flags: 0,
-            st_gen: 0,
-            st_lspare: 0,
-            st_qspare: [0, 2],
+        // Neither truncates nor fails if the file is created concurrently.
+        if let Err(err) = OpenOptions::new().append(true).create(true).open(path) {
+            return Err(Error::Fatal(format!("unable to create '{}' - {}", path.display(), err)));
+        }
+
+        let inode = match fs::metadata(path) {
+            Ok(metadata) => metadata.ino(),
+            Err(err) => return Err(Error::Fatal(format!("unable to get inode - {}", err))),
         };
 
-        if !path.exists() {
-            File::create(path).unwrap();
+        let mut message = String::new();
+        if let Err(err) = render(&self.message, payload, now, &mut message) {
+            return Err(Error::Fatal(format!("unable to render message - {:?}", err)));
         }
+        message.push('\n');
 
-        unsafe {
-            if libc::stat(path.as_os_str().to_cstring().unwrap().as_ptr(), &mut stat) != 0 {
-                warn!(target: "Output::File", "unable to get inode, dropping");
-                return;
+        if message.len() > self.atomic_limit {
+            match self.non_atomic {
+                NonAtomic::Write => metrics::registry().counter("output.file.non_atomic.written").inc(),
+                NonAtomic::Reject => {
+                    metrics::registry().counter("output.file.non_atomic.rejected").inc();
+                    return Err(Error::Fatal(format!("line of {} bytes exceeds the atomic limit of {} bytes",
+                        message.len(), self.atomic_limit)));
+                }
             }
         }
 
-        let file = self.files.entry(stat.st_ino).or_insert_with(|| {
-            info!(target: "Output::File", "opening file '{}' for writing in append mode", path.display());
-            OpenOptions::new().append(true).write(true).open(&path).unwrap()
-        });
+        if !self.files.contains_key(&inode) {
+            self.evict();
+        }
+        self.tick += 1;
+        self.used.insert(inode, self.tick);
+
+        let file = match self.files.entry(inode) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                info!(target: "Output::File", "opening file '{}' for writing in append mode", path.display());
+                let mut file = match OpenOptions::new().read(true).append(true).open(&path) {
+                    Ok(file) => file,
+                    Err(err) => {
+                        return Err(Error::Fatal(format!("unable to open '{}' - {}", path.display(), err)));
+                    }
+                };
+                if let Some(ref marker) = self.repair {
+                    match repair(&mut file, marker) {
+                        Ok(true) => warn!(target: "Output::File", "repaired the truncated last line of '{}'", path.display()),
+                        Ok(false) => {}
+                        Err(err) => {
+                            return Err(Error::Retryable(format!("unable to repair '{}' - {}", path.display(), err)));
+                        }
+                    }
+                    self.owned.insert(path.to_string_lossy().into_owned());
+                }
+                entry.insert(file)
+            }
+        };
 
-        let mut message = String::new();
-        for token in self.message.iter() {
-            let token = match consume(token, payload) {
-                Ok(token) => token,
-                Err(err) => {
-                    warn!(target: "Output::File", "dropping {:?} while parsing message format - {:?}", payload, err);
-                    return;
+        // A single write keeps the line whole, as the kernel appends it atomically.
+        match file.write(message.as_bytes()) {
+            Ok(written) if written == message.len() => {
+                debug!(target: "Output::File", "{} bytes written", written);
+                Ok(())
+            }
+            Ok(written) => {
+                metrics::registry().counter("output.file.partial_writes").inc();
+                let mut tail = self.marker.as_bytes().to_vec();
+                tail.push(b'\n');
+                if let Err(err) = file.write(&tail) {
+                    warn!(target: "Output::File", "unable to terminate the partially written line - {}", err);
                 }
-            };
-            message.push_str(&token);
+                Err(Error::Retryable(format!("partial write of {} bytes out of {}", written, message.len())))
+            }
+            Err(err) => Err(Error::Retryable(format!("writing error - {}", err)))
         }
-        message.push('\n');
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        let mut fields = Vec::new();
+        describe(&self.path, &mut fields);
+        describe(&self.message, &mut fields);
+
+        Some(fields)
+    }
+
+    fn oversize(&self) -> bool {
+        self.oversize
+    }
 
-        match file.write_all(message.as_bytes()) {
-            Ok(())   => debug!(target: "Output::File", "{} bytes written", message.len()),
-            Err(err) => warn!(target: "Output::File", "writing error - {}", err)
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        match self.repair {
+            Some(..) => Some(self),
+            None => None,
         }
     }
 }
 
-#[cfg(test)]
-mod test {
-    extern crate test;
+impl Snapshot for FileOutput {
+    fn version(&self) -> u32 {
+        1
+    }
 
-    use std::collections::TreeMap;
+    fn snapshot(&mut self) -> Option<Record> {
+        if self.owned.is_empty() {
+            return None;
+        }
 
-    use serialize::json::{Null, Boolean, U64, I64, F64, String, List, Object};
+        let mut paths: Vec<&String> = self.owned.iter().collect();
+        paths.sort();
+        let mut state = Record::new();
+        let files = paths.into_iter().map(|path| RecordItem::String(path.clone())).collect();
+        state.insert("files".to_string(), RecordItem::Array(files));
+        Some(state)
+    }
 
-    use super::{FormatParser, Literal, Placeholder, Error, EOFWhileParsingPlaceholder};
-    use super::{TypeMismatch, KeyNotFound};
-    use super::consume;
+    /// Repairs tails of the files owned before the restart.
+    fn restore(&mut self, state: &Record) -> Result<(), String> {
+        let files = match state.find("files") {
+            Some(&RecordItem::Array(ref files)) => files,
+            Some(..) | None => return Err("list of files is missing".to_string()),
+        };
+
+        for path in files.iter().filter_map(|path| path.as_str()) {
+            self.repair_path(path);
+            self.owned.insert(path.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::iter::repeat;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::super::super::{Record, RecordItem};
+    use super::super::super::clock::MockClock;
+    use super::super::super::metrics;
+    use super::super::super::snapshot::Snapshot;
+    use super::super::super::testing::{record, string};
+    use super::super::{Error, Output};
+    use super::{FormatParser, ParserEvent, ParserError, TemplateError, TokenError};
+    use super::{FileOutput, MissingTimestamp, NonAtomic, compile, consume, render};
+
+    use super::ParserEvent::{Literal, Placeholder};
 
     #[test]
     fn parse_empty_path() {
@@ -295,135 +896,442 @@ mod test {
     fn break_parser_on_eof_while_parsing_placeholder() {
         let mut parser = FormatParser::new("/directory/{path".chars());
         assert_eq!(Some(Literal("/directory/".to_string())), parser.next());
-        assert_eq!(Some(Error(EOFWhileParsingPlaceholder)), parser.next());
-        assert_eq!(Some(Error(EOFWhileParsingPlaceholder)), parser.next());
+        assert_eq!(Some(ParserEvent::Error(ParserError::EOFWhileParsingPlaceholder)), parser.next());
+        assert_eq!(Some(ParserEvent::Error(ParserError::EOFWhileParsingPlaceholder)), parser.next());
     }
 
     #[test]
     fn literal_token() {
-        let payload = Object(TreeMap::new());
+        let payload = Record::new();
         let token = Literal("/directory".to_string());
-        assert_eq!("/directory".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("/directory".to_string(), consume(&token, &payload, None).unwrap());
     }
 
     #[test]
     fn placeholder_token_null() {
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), Null);
-
-        let payload = Object(o);
+        let payload = record(vec![("k1", RecordItem::Null)]);
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!("null".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("null".to_string(), consume(&token, &payload, None).unwrap());
     }
 
     #[test]
     fn placeholder_token_bool() {
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), Boolean(true));
-        o.insert("k2".to_string(), Boolean(false));
-
-        let payload = Object(o);
+        let payload = record(vec![("k1", RecordItem::Bool(true)), ("k2", RecordItem::Bool(false))]);
 
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!("true".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("true".to_string(), consume(&token, &payload, None).unwrap());
 
         let token = Placeholder(
             vec!["k2".to_string()],
         );
-        assert_eq!("false".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("false".to_string(), consume(&token, &payload, None).unwrap());
     }
 
     #[test]
-    fn placeholder_token_uint() {
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), U64(42u64));
-
-        let payload = Object(o);
+    fn placeholder_token_number() {
+        let payload = record(vec![("k1", RecordItem::F64(42.0))]);
 
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!("42".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("42".to_string(), consume(&token, &payload, None).unwrap());
     }
 
     #[test]
-    fn placeholder_token_int() {
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), I64(-42i64));
-
-        let payload = Object(o);
+    fn placeholder_token_negative_number() {
+        let payload = record(vec![("k1", RecordItem::F64(-42.0))]);
 
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!("-42".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("-42".to_string(), consume(&token, &payload, None).unwrap());
     }
 
     #[test]
     fn placeholder_token_float() {
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), F64(3.1415f64));
-
-        let payload = Object(o);
+        let payload = record(vec![("k1", RecordItem::F64(3.1415))]);
 
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!("3.1415".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("3.1415".to_string(), consume(&token, &payload, None).unwrap());
     }
 
     #[test]
     fn placeholder_token_string() {
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), String("v1".to_string()));
-
-        let payload = Object(o);
+        let payload = record(vec![("k1", string("v1"))]);
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!("v1".to_string(), consume(&token, &payload).unwrap());
+        assert_eq!("v1".to_string(), consume(&token, &payload, None).unwrap());
     }
 
     #[test]
     fn placeholder_token_fails_on_array_key() {
-        let d = Vec::new();
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), List(d));
-
-        let payload = Object(o);
+        let payload = record(vec![("k1", RecordItem::Array(Vec::new()))]);
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!(Err(TypeMismatch), consume(&token, &payload));
+        assert_eq!(Err(TokenError::TypeMismatch), consume(&token, &payload, None));
     }
 
     #[test]
     fn placeholder_token_fails_on_object_key() {
-        let d = TreeMap::new();
-        let mut o = TreeMap::new();
-        o.insert("k1".to_string(), Object(d));
-
-        let payload = Object(o);
+        let payload = record(vec![("k1", RecordItem::Object(HashMap::new()))]);
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!(Err(TypeMismatch), consume(&token, &payload));
+        assert_eq!(Err(TokenError::TypeMismatch), consume(&token, &payload, None));
     }
 
     #[test]
     fn placeholder_token_fails_on_absent_key() {
-        let o = TreeMap::new();
-
-        let payload = Object(o);
+        let payload = Record::new();
         let token = Placeholder(
             vec!["k1".to_string()],
         );
-        assert_eq!(Err(KeyNotFound("k1")), consume(&token, &payload));
+        assert_eq!(Err(TokenError::KeyNotFound("k1")), consume(&token, &payload, None));
+    }
+
+    #[test]
+    fn placeholder_token_nested() {
+        let mut o = HashMap::new();
+        o.insert("k2".to_string(), string("v2"));
+
+        let payload = record(vec![("k1", RecordItem::Object(o))]);
+        let token = Placeholder(
+            vec!["k1".to_string(), "k2".to_string()],
+        );
+        assert_eq!("v2".to_string(), consume(&token, &payload, None).unwrap());
+    }
+
+    #[test]
+    fn placeholder_token_source_key() {
+        let token = Placeholder(vec!["@source".to_string()]);
+        assert_eq!("app".to_string(), consume(&token, &record(vec![("source", string("app"))]), None).unwrap());
+
+        let payload = record(vec![("source", string("app")), ("_source", string("10.0.0.1"))]);
+        assert_eq!("10.0.0.1".to_string(), consume(&token, &payload, None).unwrap());
+        assert_eq!(Err(TokenError::KeyNotFound("@source")), consume(&token, &Record::new(), None));
+    }
+
+    fn format(template: &str, payload: &Record) -> String {
+        let nodes = compile(template).unwrap();
+        let mut result = String::new();
+        render(&nodes, payload, None, &mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn parse_section_tags() {
+        let mut parser = FormatParser::new("{?a/b}{!c}{/a/b}".chars());
+        assert_eq!(Some(ParserEvent::Open(vec!["a".to_string(), "b".to_string()], false)), parser.next());
+        assert_eq!(Some(ParserEvent::Open(vec!["c".to_string()], true)), parser.next());
+        assert_eq!(Some(ParserEvent::Close(vec!["a".to_string(), "b".to_string()])), parser.next());
+        assert_eq!(None, parser.next());
+    }
+
+    #[test]
+    fn parse_time_placeholder_apart_from_field_with_colon() {
+        let mut parser = FormatParser::new("{@time:a/b:%H:%M}{host:port}".chars());
+        assert_eq!(Some(ParserEvent::Time(vec!["a".to_string(), "b".to_string()], "%H:%M".to_string())), parser.next());
+        assert_eq!(Some(ParserEvent::Placeholder(vec!["host:port".to_string()])), parser.next());
+        assert_eq!(None, parser.next());
+    }
+
+    #[test]
+    fn render_section_for_present_field() {
+        let template = "{message}{?request_id} [{request_id}]{/request_id}";
+        let payload = record(vec![("message", string("le message")), ("request_id", string("42"))]);
+        assert_eq!("le message [42]", format(template, &payload));
+    }
+
+    #[test]
+    fn skip_section_for_absent_or_null_field() {
+        let template = "{message}{?request_id} [{request_id}]{/request_id}";
+        assert_eq!("le message", format(template, &record(vec![("message", string("le message"))])));
+
+        let payload = record(vec![("message", string("le message")), ("request_id", RecordItem::Null)]);
+        assert_eq!("le message", format(template, &payload));
+    }
+
+    #[test]
+    fn render_negated_section_for_absent_field() {
+        let template = "{message}{!request_id} [untracked]{/request_id}";
+        assert_eq!("le message [untracked]", format(template, &record(vec![("message", string("le message"))])));
+
+        let payload = record(vec![("message", string("le message")), ("request_id", string("42"))]);
+        assert_eq!("le message", format(template, &payload));
+    }
+
+    #[test]
+    fn render_nested_sections() {
+        let template = "{?request}req{?request/id} {request/id}{/request/id}{!request/id} anonymous{/request/id}{/request}";
+
+        let mut request = HashMap::new();
+        request.insert("id".to_string(), string("42"));
+        assert_eq!("req 42", format(template, &record(vec![("request", RecordItem::Object(request))])));
+        assert_eq!("req anonymous", format(template, &record(vec![("request", RecordItem::Object(HashMap::new()))])));
+        assert_eq!("", format(template, &Record::new()));
+    }
+
+    #[test]
+    fn fail_on_missing_placeholder_inside_rendered_section() {
+        let nodes = compile("{?request_id}{trace_id}{/request_id}").unwrap();
+        let payload = record(vec![("request_id", string("42"))]);
+        assert_eq!(Err(TokenError::KeyNotFound("trace_id")), render(&nodes, &payload, None, &mut String::new()));
     }
 
-// TODO: fn placeholder_token_nested() {
+    #[test]
+    fn fail_to_compile_unmatched_tags() {
+        assert_eq!(Err(TemplateError::Unclosed("request_id".to_string(), 4)), compile("log {?request_id} [{request_id}]"));
+        assert_eq!(Err(TemplateError::Unmatched("request_id".to_string(), 9)), compile("{message}{/request_id}"));
+        assert_eq!(Err(TemplateError::Unmatched("a".to_string(), 8)), compile("{?a}{?b}{/a}{/b}"));
+        assert_eq!(Err(TemplateError::Syntax(ParserError::EOFWhileParsingPlaceholder, 4)), compile("{?a}{a"));
+    }
+
+    fn read(path: &Path) -> String {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn create_nested_directories() {
+        let dir = env::temp_dir().join("logdrop-files-nested");
+        let _ = fs::remove_dir_all(&dir);
+
+        let template = format!("{}/{{app}}/{{host}}/app.log", dir.display());
+        let mut output = FileOutput::new(&template, "{message}").unwrap();
+        output.feed(&record(vec![("app", string("api")), ("host", string("h1")), ("message", string("le message"))])).unwrap();
+
+        assert_eq!("le message\n", read(&dir.join("api/h1/app.log")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn create_same_directories_concurrently() {
+        let dir = env::temp_dir().join("logdrop-files-race");
+        let _ = fs::remove_dir_all(&dir);
+
+        let threads: Vec<_> = (0..8).map(|id| {
+            let template = format!("{}/a/b/c/d/{{id}}.log", dir.display());
+            thread::spawn(move || {
+                let mut output = FileOutput::new(&template, "{id}").unwrap();
+                output.feed(&record(vec![("id", RecordItem::F64(id as f64))])).unwrap();
+            })
+        }).collect();
+        for handle in threads.into_iter() {
+            handle.join().unwrap();
+        }
+
+        for id in 0..8 {
+            assert_eq!(format!("{}\n", id), read(&dir.join(format!("a/b/c/d/{}.log", id))));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fail_on_missing_directory_when_not_creating_them() {
+        let dir = env::temp_dir().join("logdrop-files-missing");
+        let _ = fs::remove_dir_all(&dir);
+
+        let template = format!("{}/{{app}}/app.log", dir.display());
+        let mut output = FileOutput::new(&template, "{message}").unwrap().create_dirs(false);
+        match output.feed(&record(vec![("app", string("api")), ("message", string("le message"))])) {
+            Err(Error::Fatal(..)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert!(fs::metadata(&dir).is_err());
+    }
+
+    #[test]
+    fn keep_lines_whole_under_concurrent_writers() {
+        const LINES: usize = 1000;
+
+        let path = env::temp_dir().join("logdrop-files-atomic.log");
+        let _ = fs::remove_file(&path);
+
+        let writers: Vec<_> = (0..2).map(|writer| {
+            let path = path.to_str().unwrap().to_string();
+            thread::spawn(move || {
+                let mut output = FileOutput::new(&path, "{line}").unwrap();
+                for seq in 0..LINES {
+                    let padding: String = repeat('x').take(seq * 37 % 3000).collect();
+                    let line = format!("{}-{}-{}", writer, seq, padding);
+                    output.feed(&record(vec![("line", string(&line))])).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers.into_iter() {
+            writer.join().unwrap();
+        }
+
+        let content = read(&path);
+        let mut count = 0;
+        for line in content.lines() {
+            let parts: Vec<&str> = line.splitn(3, '-').collect();
+            let seq: usize = parts[1].parse().unwrap();
+            assert!(parts[0] == "0" || parts[0] == "1");
+            assert_eq!(seq * 37 % 3000, parts[2].len());
+            assert!(parts[2].chars().all(|c| c == 'x'));
+            count += 1;
+        }
+        assert_eq!(2 * LINES, count);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn apply_non_atomic_policy() {
+        let path = env::temp_dir().join("logdrop-files-non-atomic.log");
+        let _ = fs::remove_file(&path);
+        let long = record(vec![("message", string("longer than sixteen bytes"))]);
+
+        let mut output = FileOutput::new(path.to_str().unwrap(), "{message}").unwrap().atomic_limit(16, NonAtomic::Reject);
+        output.feed(&record(vec![("message", string("short"))])).unwrap();
+        match output.feed(&long) {
+            Err(Error::Fatal(..)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!("short\n", read(&path));
+
+        let mut output = FileOutput::new(path.to_str().unwrap(), "{message}").unwrap().atomic_limit(16, NonAtomic::Write);
+        output.feed(&long).unwrap();
+        assert_eq!("short\nlonger than sixteen bytes\n", read(&path));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn repair_truncated_tails() {
+        let dir = env::temp_dir().join("logdrop-files-repair");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let owned = dir.join("owned.log");
+        let opened = dir.join("opened.log");
+        File::create(&owned).unwrap().write_all(b"complete\npartial").unwrap();
+        File::create(&opened).unwrap().write_all(b"half").unwrap();
+
+        let template = format!("{}/{{name}}.log", dir.display());
+        let mut output = FileOutput::new(&template, "{message}").unwrap().repair_tail(" [truncated]");
+
+        // Files owned before the restart are repaired once restored, even if not written again.
+        let mut state = Record::new();
+        state.insert("files".to_string(), RecordItem::Array(vec![string(owned.to_str().unwrap())]));
+        for _ in 0..2 {
+            output.state().unwrap().restore(&state).unwrap();
+        }
+        assert_eq!("complete\npartial [truncated]\n", read(&owned));
+
+        // Other ones are repaired when opened.
+        output.feed(&record(vec![("name", string("opened")), ("message", string("next"))])).unwrap();
+        assert_eq!("half [truncated]\nnext\n", read(&opened));
+
+        let files = output.state().unwrap().snapshot().unwrap();
+        let mut expected = vec![string(opened.to_str().unwrap()), string(owned.to_str().unwrap())];
+        expected.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+        assert_eq!(Some(&RecordItem::Array(expected)), files.find("files"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Record of the message at the time in seconds past 2015-06-01T10:00:00Z.
+    fn at(offset: f64, message: &str) -> Record {
+        record(vec![("timestamp", RecordItem::F64(1433152800.0 + offset)), ("message", string(message))])
+    }
+
+    #[test]
+    fn refuse_invalid_time_format() {
+        assert_eq!(Err(TemplateError::TimeFormat("%Y-%".to_string(), 4)), compile("app-{@time:timestamp:%Y-%}.log"));
+        assert_eq!(Err(TemplateError::TimeFormat("".to_string(), 4)), compile("app-{@time:timestamp}.log"));
+    }
+
+    #[test]
+    fn bucket_files_by_record_time() {
+        let dir = env::temp_dir().join("logdrop-files-buckets");
+        let _ = fs::remove_dir_all(&dir);
+
+        let template = format!("{}/{{@time:timestamp:%Y-%m-%d/%H}}.log", dir.display());
+        let mut output = FileOutput::new(&template, "{message}").unwrap();
+        output.feed(&at(7200.0 + 59.0, "12 first")).unwrap();
+        output.feed(&at(3599.5, "10 last")).unwrap();
+        output.feed(&at(3600.0, "11 first")).unwrap();
+        output.feed(&record(vec![("timestamp", string("2015-06-01T10:30:00Z")), ("message", string("10 rfc"))])).unwrap();
+        output.feed(&record(vec![("timestamp", string("1433160000")), ("message", string("12 string"))])).unwrap();
+
+        assert_eq!("10 last\n10 rfc\n", read(&dir.join("2015-06-01/10.log")));
+        assert_eq!("11 first\n", read(&dir.join("2015-06-01/11.log")));
+        assert_eq!("12 first\n12 string\n", read(&dir.join("2015-06-01/12.log")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_missing_timestamp_policy() {
+        let dir = env::temp_dir().join("logdrop-files-missing-timestamp");
+        let _ = fs::remove_dir_all(&dir);
+        let template = format!("{}/{{@time:timestamp:%H}}.log", dir.display());
+        let untimed = record(vec![("message", string("untimed"))]);
+        let unparsable = record(vec![("timestamp", string("yesterday")), ("message", string("unparsable"))]);
+
+        // 2015-06-01T13:30:00Z.
+        let clock = MockClock::new(1433165400000);
+        let mut output = FileOutput::new(&template, "{message}").unwrap().clock(Arc::new(clock));
+        output.feed(&untimed).unwrap();
+        output.feed(&unparsable).unwrap();
+        assert_eq!("untimed\nunparsable\n", read(&dir.join("13.log")));
+
+        let mut output = FileOutput::new(&template, "{message}").unwrap().missing_timestamp(MissingTimestamp::Reject);
+        for payload in [untimed, unparsable].iter() {
+            match output.feed(payload) {
+                Err(Error::Fatal(..)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        output.feed(&at(0.0, "timed")).unwrap();
+        assert_eq!("timed\n", read(&dir.join("10.log")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evict_least_recently_written_files() {
+        let dir = env::temp_dir().join("logdrop-files-evict");
+        let _ = fs::remove_dir_all(&dir);
+        let inode = |hour: &str| fs::metadata(dir.join(format!("{}.log", hour))).unwrap().ino();
+
+        let template = format!("{}/{{@time:timestamp:%H}}.log", dir.display());
+        let mut output = FileOutput::new(&template, "{message}").unwrap().max_open(2);
+        output.feed(&at(0.0, "a")).unwrap();
+        output.feed(&at(3600.0, "b")).unwrap();
+        output.feed(&at(60.0, "c")).unwrap();
+        output.feed(&at(7200.0, "d")).unwrap();
+
+        let mut open: Vec<u64> = output.files.keys().cloned().collect();
+        open.sort();
+        let mut expected = vec![inode("10"), inode("12")];
+        expected.sort();
+        assert_eq!(expected, open);
+
+        // Evicted buckets are opened again once written to.
+        output.feed(&at(3660.0, "e")).unwrap();
+        assert_eq!(2, output.files.len());
+        assert!(output.files.contains_key(&inode("11")));
+        assert!(!output.files.contains_key(&inode("10")));
+        assert_eq!(2, metrics::registry().counter("output.file.evicted").get());
+
+        assert_eq!("a\nc\n", read(&dir.join("10.log")));
+        assert_eq!("b\ne\n", read(&dir.join("11.log")));
+        assert_eq!("d\n", read(&dir.join("12.log")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
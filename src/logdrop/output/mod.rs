@@ -1,9 +1,170 @@
 use std;
+use std::fmt;
+use std::sync::Arc;
 
 use super::Record;
+use super::clock::Clock;
+use super::snapshot::Snapshot;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The record was not delivered, but the same call may succeed later, e.g. on connection loss.
+    Retryable(String),
+    /// The record may or may not have been delivered, e.g. the connection was lost after the
+    /// request had been sent.
+    Ambiguous(String),
+    /// The record can never be delivered by this output.
+    Fatal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Retryable(ref reason) => write!(f, "retryable error - {}", reason),
+            Error::Ambiguous(ref reason) => write!(f, "ambiguous error - {}", reason),
+            Error::Fatal(ref reason) => write!(f, "fatal error - {}", reason),
+        }
+    }
+}
+
+/// Delivery guarantee the pipeline provides for an output on ambiguous failures.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Delivery {
+    /// Never retry a possibly delivered record, counting it as possibly lost instead.
+    AtMostOnce,
+    /// Retry a possibly delivered record, counting it as possibly duplicated.
+    AtLeastOnce,
+}
+
+impl Delivery {
+    pub fn from_str(name: &str) -> Option<Delivery> {
+        match name {
+            "at_most_once" => Some(Delivery::AtMostOnce),
+            "at_least_once" => Some(Delivery::AtLeastOnce),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Delivery::AtMostOnce => "at_most_once",
+            Delivery::AtLeastOnce => "at_least_once",
+        }
+    }
+}
+
+/// What happens to a record the output fails to accept or panics on.
+///
+/// Without the policy the record is retried and dropped once attempts are exhausted.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OnError {
+    /// Drop the record after the first failure.
+    Drop,
+    /// Send the record to the dead-letter output after the first failure.
+    DeadLetter,
+    /// Retry the record, sending it to the dead-letter output once attempts are exhausted.
+    RetryThenDeadLetter,
+}
+
+impl OnError {
+    pub fn from_str(name: &str) -> Option<OnError> {
+        match name {
+            "drop" => Some(OnError::Drop),
+            "dead_letter" => Some(OnError::DeadLetter),
+            "retry_then_dead_letter" => Some(OnError::RetryThenDeadLetter),
+            _ => None,
+        }
+    }
+
+    pub fn all() -> [OnError; 3] {
+        [OnError::Drop, OnError::DeadLetter, OnError::RetryThenDeadLetter]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OnError::Drop => "drop",
+            OnError::DeadLetter => "dead_letter",
+            OnError::RetryThenDeadLetter => "retry_then_dead_letter",
+        }
+    }
+}
 
 pub trait Output : Sync + Send {
-    fn feed(&mut self, payload: &Record);
+    /// Sends or buffers the record.
+    ///
+    /// On failure the record is not retained by the output, so retrying means feeding it again.
+    fn feed(&mut self, payload: &Record) -> Result<(), Error>;
+
+    /// Delivers records buffered by the output, if any.
+    ///
+    /// Called periodically by the pipeline and once more before the output is destroyed. On
+    /// failure buffered records are kept to be sent by the next flush.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Drops records buffered by the output without delivering them, returning their number.
+    fn discard(&mut self) -> usize {
+        0
+    }
+
+    /// Checks whether the destination is able to accept records without sending any of them.
+    fn probe(&mut self) -> bool {
+        true
+    }
+
+    /// Verifies the output configuration, e.g. that its destination address resolves, without
+    /// connecting to it.
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Returns top-level fields the output reads to name or route records, e.g. in path
+    /// templates, for deriving the decode projection. Fields merely written out do not count.
+    ///
+    /// `None` means that any field may be read, which rules the strict projection out.
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Returns the local file the output writes to, for refusing components writing over each
+    /// other's files, see `PipelineBuilder::build`.
+    ///
+    /// Outputs appending whole lines in single writes, like `FileOutput`, share files safely and
+    /// claim none.
+    fn target(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns true if the output accepts oversize records intact, see `Pipeline::set_oversize`.
+    ///
+    /// Such records reach the output through a dedicated channel and are flushed one by one,
+    /// while other outputs receive their stubs instead.
+    fn oversize(&self) -> bool {
+        false
+    }
+
+    /// Returns false if the output may deliver records in another order than they're fed in, e.g.
+    /// sending batches concurrently, which breaks the order of records of a connection and is
+    /// refused by `PipelineBuilder::build` unless the order is waived.
+    fn ordered(&self) -> bool {
+        true
+    }
+
+    /// Returns the soft state carried over restarts, if the output has any, see `Snapshot`.
+    fn state(&mut self) -> Option<&mut Snapshot> {
+        None
+    }
+
+    /// Makes the output draw random numbers from the given seed, see `determinism`.
+    fn reseed(&mut self, _: u64) {}
+
+    /// Makes the output follow the given clock rather than the wall one, see `determinism`.
+    fn set_clock(&mut self, _: Arc<Clock>) {}
+
+    /// Makes the output encode object keys ordered by name, so that equal records encode into the
+    /// same bytes, see `determinism`.
+    fn sort_keys(&mut self) {}
 
     fn typename(&self) -> &'static str {
         unsafe { std::intrinsics::type_name::<Self>() }
@@ -11,7 +172,21 @@ pub trait Output : Sync + Send {
 }
 
 mod null;
-//mod files;
+mod archive;
+mod dedup;
+mod elasticsearch;
+mod endpoint;
+mod failover;
+mod files;
+mod otlp;
+mod redis;
 
-//pub use self::files::FileOutput;
+pub use self::archive::Archive;
+pub use self::dedup::{Dedup, Settings as DedupSettings, FIELD as IDEMPOTENCY_FIELD};
+pub use self::elasticsearch::ElasticsearchOutput;
+pub use self::endpoint::{Endpoint, Order, Resolver, SystemResolver};
+pub use self::failover::{Failover, Policy};
+pub use self::files::{FileOutput, Template};
 pub use self::null::Null;
+pub use self::otlp::OtlpOutput;
+pub use self::redis::{Mode as RedisMode, RedisOutput};
@@ -1,8 +1,21 @@
 use super::super::Record;
-use super::Output;
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::{Error, Output};
 
 pub struct Null;
 
+impl FromConfig for Null {
+    fn from_config(_: &Config) -> Result<Null, ConfigError> {
+        Ok(Null)
+    }
+}
+
 impl Output for Null {
-    fn feed(&mut self, _: &Record) {}
+    fn feed(&mut self, _: &Record) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        Some(Vec::new())
+    }
 }
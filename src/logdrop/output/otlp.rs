@@ -0,0 +1,488 @@
+use std::io;
+use std::mem;
+use std::net::TcpStream;
+
+use super::super::{Record, RecordItem, sorted};
+use super::super::codec::json::{encode_base64, encode_string};
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::super::filter;
+use super::super::http;
+use super::{Endpoint, Error, Output};
+
+/// OpenTelemetry severity numbers and texts by the level names they are mapped from, syslog
+/// severities included.
+const SEVERITIES: [(&'static str, u32, &'static str); 15] = [
+    ("trace", 1, "TRACE"),
+    ("debug", 5, "DEBUG"),
+    ("info", 9, "INFO"),
+    ("notice", 10, "INFO2"),
+    ("warn", 13, "WARN"),
+    ("warning", 13, "WARN"),
+    ("err", 17, "ERROR"),
+    ("error", 17, "ERROR"),
+    ("crit", 18, "ERROR2"),
+    ("critical", 18, "ERROR2"),
+    ("alert", 19, "ERROR3"),
+    ("emerg", 21, "FATAL"),
+    ("emergency", 21, "FATAL"),
+    ("fatal", 21, "FATAL"),
+    ("panic", 21, "FATAL"),
+];
+
+/// Level names of numeric syslog severities, see `SEVERITIES`.
+const SYSLOG: [&'static str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+/// Returns the OpenTelemetry severity number and text of the level, given either by name or as a
+/// numeric syslog severity.
+///
+/// Unknown names keep their text with the number left unspecified, while other values have no
+/// severity at all.
+fn severity(value: &RecordItem) -> Option<(Option<u32>, String)> {
+    let name = match *value {
+        RecordItem::I64(code @ 0...7) => SYSLOG[code as usize].to_string(),
+        RecordItem::U64(code @ 0...7) => SYSLOG[code as usize].to_string(),
+        _ => match value.as_str() {
+            Some(name) => name.to_string(),
+            None => return None,
+        },
+    };
+
+    let lowercase = name.to_lowercase();
+    match SEVERITIES.iter().find(|&&(level, _, _)| level == lowercase) {
+        Some(&(_, number, text)) => Some((Some(number), text.to_string())),
+        None => Some((None, name)),
+    }
+}
+
+/// Returns the time in nanoseconds since the Unix epoch, integral seconds being converted
+/// exactly.
+fn nanos(value: &RecordItem, field: &str) -> Option<u64> {
+    match *value {
+        RecordItem::I64(seconds) if seconds >= 0 => (seconds as u64).checked_mul(1_000_000_000),
+        RecordItem::U64(seconds) => seconds.checked_mul(1_000_000_000),
+        _ => match filter::timestamp(Some(value), field) {
+            Ok(seconds) if seconds >= 0.0 && seconds < (u64::max_value() / 1_000_000_000) as f64 => {
+                let whole = seconds.floor();
+                Some(whole as u64 * 1_000_000_000 + ((seconds - whole) * 1e9).round() as u64)
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Appends the value as an `AnyValue` of the OTLP JSON encoding, null being the empty one.
+fn encode_value(item: &RecordItem, result: &mut String) {
+    match *item {
+        RecordItem::Null => result.push_str("{}"),
+        RecordItem::Bool(v) => result.push_str(if v { "{\"boolValue\":true}" } else { "{\"boolValue\":false}" }),
+        RecordItem::F64(v) if v.is_nan() => result.push_str("{\"doubleValue\":\"NaN\"}"),
+        RecordItem::F64(v) if v.is_infinite() && v > 0.0 => result.push_str("{\"doubleValue\":\"Infinity\"}"),
+        RecordItem::F64(v) if v.is_infinite() => result.push_str("{\"doubleValue\":\"-Infinity\"}"),
+        RecordItem::F64(v) => result.push_str(&format!("{{\"doubleValue\":{}}}", v)),
+        // 64-bit integers are strings in the JSON encoding of protobuf.
+        RecordItem::I64(v) => result.push_str(&format!("{{\"intValue\":\"{}\"}}", v)),
+        // Not fitting into the signed integer, so kept exact as a string instead.
+        RecordItem::U64(v) => result.push_str(&format!("{{\"stringValue\":\"{}\"}}", v)),
+        RecordItem::String(ref v) => encode_string_value(v, result),
+        RecordItem::Text(ref v) => encode_string_value(v, result),
+        RecordItem::Bytes(ref v) => {
+            result.push_str("{\"bytesValue\":\"");
+            encode_base64(v, result);
+            result.push_str("\"}");
+        }
+        RecordItem::Array(ref v) => {
+            result.push_str("{\"arrayValue\":{\"values\":[");
+            for (id, item) in v.iter().enumerate() {
+                if id > 0 {
+                    result.push(',');
+                }
+                encode_value(item, result);
+            }
+            result.push_str("]}}");
+        }
+        RecordItem::Object(ref v) => {
+            result.push_str("{\"kvlistValue\":{\"values\":");
+            encode_attributes(sorted(v).into_iter(), result);
+            result.push_str("}}");
+        }
+    }
+}
+
+fn encode_string_value(v: &str, result: &mut String) {
+    result.push_str("{\"stringValue\":");
+    encode_string(v, result);
+    result.push('}');
+}
+
+/// Appends the pairs as an array of `KeyValue`.
+fn encode_attributes<'a, I>(pairs: I, result: &mut String)
+    where I: Iterator<Item=(&'a String, &'a RecordItem)>
+{
+    result.push('[');
+    for (id, (key, value)) in pairs.enumerate() {
+        if id > 0 {
+            result.push(',');
+        }
+        result.push_str("{\"key\":");
+        encode_string(key, result);
+        result.push_str(",\"value\":");
+        encode_value(value, result);
+        result.push('}');
+    }
+    result.push(']');
+}
+
+/// OTLP output exports records as OpenTelemetry logs, posting them to an OTLP/HTTP collector in
+/// the JSON encoding.
+///
+/// Each record becomes a log record: the timestamp field its time, the level field its severity
+/// and the message field its body, while other fields, and these ones failed to be mapped, become
+/// its attributes. The host name and the service name, if configured, are attributes of the
+/// resource all records are exported for.
+///
+/// Records are accumulated in memory and exported either when the queue reaches the limit or on
+/// flush. A failed export request keeps the queue intact, so it is retried on the next attempt.
+pub struct OtlpOutput {
+    addr: String,
+    endpoint: Endpoint,
+    path: String,
+    hostname: Option<String>,
+    service: Option<String>,
+    timestamp: String,
+    level: String,
+    message: String,
+    limit: usize,
+    queue: Vec<Record>,
+}
+
+impl OtlpOutput {
+    pub fn new(host: &str, port: u16) -> OtlpOutput {
+        OtlpOutput {
+            addr: format!("{}:{}", host, port),
+            endpoint: Endpoint::new("otlp", host, port),
+            path: "/v1/logs".to_string(),
+            hostname: None,
+            service: None,
+            timestamp: "timestamp".to_string(),
+            level: "level".to_string(),
+            message: "message".to_string(),
+            limit: 100,
+            queue: Vec::new(),
+        }
+    }
+
+    /// Replaces the endpoint, e.g. to resolve its name differently.
+    pub fn endpoint(mut self, endpoint: Endpoint) -> OtlpOutput {
+        self.endpoint = endpoint;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> OtlpOutput {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Sets the `host.name` and `service.name` resource attributes.
+    pub fn resource(mut self, hostname: Option<&str>, service: Option<&str>) -> OtlpOutput {
+        self.hostname = hostname.map(|hostname| hostname.to_string());
+        self.service = service.map(|service| service.to_string());
+        self
+    }
+
+    /// Sets the fields mapped to the time, the severity and the body of log records.
+    pub fn fields(mut self, timestamp: &str, level: &str, message: &str) -> OtlpOutput {
+        self.timestamp = timestamp.to_string();
+        self.level = level.to_string();
+        self.message = message.to_string();
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> OtlpOutput {
+        self.limit = limit;
+        self
+    }
+
+    /// Appends the record as a `LogRecord`.
+    fn encode_record(&self, record: &Record, result: &mut String) {
+        let time = record.find(&self.timestamp).and_then(|value| nanos(value, &self.timestamp));
+        let severity = record.find(&self.level).and_then(severity);
+        let body = record.find(&self.message);
+
+        result.push('{');
+        if let Some(time) = time {
+            result.push_str(&format!("\"timeUnixNano\":\"{}\",", time));
+        }
+        if let Some((number, ref text)) = severity {
+            if let Some(number) = number {
+                result.push_str(&format!("\"severityNumber\":{},", number));
+            }
+            result.push_str("\"severityText\":");
+            encode_string(text, result);
+            result.push(',');
+        }
+        if let Some(body) = body {
+            result.push_str("\"body\":");
+            encode_value(body, result);
+            result.push(',');
+        }
+
+        let attributes = record.sorted().into_iter().filter(|&(key, _)| {
+            !(time.is_some() && *key == self.timestamp) &&
+            !(severity.is_some() && *key == self.level) &&
+            *key != self.message
+        });
+        result.push_str("\"attributes\":");
+        encode_attributes(attributes, result);
+        result.push('}');
+    }
+
+    /// Builds the `ExportLogsServiceRequest` carrying the records.
+    fn make_body(&self, records: &[Record]) -> String {
+        let mut resource = Vec::new();
+        if let Some(ref hostname) = self.hostname {
+            resource.push(("host.name".to_string(), RecordItem::String(hostname.clone())));
+        }
+        if let Some(ref service) = self.service {
+            resource.push(("service.name".to_string(), RecordItem::String(service.clone())));
+        }
+
+        let mut data = String::new();
+        data.push_str("{\"resourceLogs\":[{\"resource\":{\"attributes\":");
+        encode_attributes(resource.iter().map(|&(ref key, ref value)| (key, value)), &mut data);
+        data.push_str("},\"scopeLogs\":[{\"scope\":{\"name\":\"logdrop\"},\"logRecords\":[");
+        for (id, record) in records.iter().enumerate() {
+            if id > 0 {
+                data.push(',');
+            }
+            self.encode_record(record, &mut data);
+        }
+        data.push_str("]}]}]}");
+        data
+    }
+
+    /// Connects to the address of the last request, or to the one the host name resolves to now.
+    fn connect(&mut self) -> Result<TcpStream, http::Error> {
+        match self.endpoint.reconnect() {
+            Some(stream) => Ok(stream),
+            None => {
+                self.endpoint.connect().map_err(|reason| http::Error::Connect(io::Error::new(io::ErrorKind::Other, reason)))
+            }
+        }
+    }
+
+    fn send(&mut self, records: &[Record]) -> Result<(), Error> {
+        debug!(target: "Output::OTLP", "exporting {} records to {}{}", records.len(), self.addr, self.path);
+
+        let body = self.make_body(records);
+        let path = self.path.clone();
+        let result = match self.connect() {
+            Ok(stream) => http::request(stream, &self.addr, "POST", &path, body.as_bytes()),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(ref response) if response.is_success() => {
+                debug!(target: "Output::OTLP", "ok - {}", response.status);
+                Ok(())
+            }
+            // The collector rejects malformed requests with 400, which no retry fixes.
+            Ok(ref response) if response.status == 400 => {
+                Err(Error::Fatal(format!("export request rejected - {}", response.body)))
+            }
+            Ok(response) => {
+                Err(Error::Retryable(format!("export request failed with {} status - {}", response.status, response.body)))
+            }
+            Err(err @ http::Error::Connect(..)) => {
+                Err(Error::Retryable(format!("failed to perform export request - {}", err)))
+            }
+            Err(err @ http::Error::Transfer(..)) => {
+                Err(Error::Ambiguous(format!("failed to perform export request - {}", err)))
+            }
+        }
+    }
+}
+
+impl FromConfig for OtlpOutput {
+    fn from_config(config: &Config) -> Result<OtlpOutput, ConfigError> {
+        let host = try!(config.string_or("host", "localhost"));
+        let port = try!(config.u64_or("port", 4318));
+        let path = try!(config.string_or("path", "/v1/logs"));
+        let hostname = match config.find("hostname") {
+            Some(..) => Some(try!(config.string("hostname"))),
+            None => None,
+        };
+        let service = match config.find("service_name") {
+            Some(..) => Some(try!(config.string("service_name"))),
+            None => None,
+        };
+        let timestamp = try!(config.string_or("timestamp_field", "timestamp"));
+        let level = try!(config.string_or("level_field", "level"));
+        let message = try!(config.string_or("message_field", "message"));
+        let limit = try!(config.u64_or("limit", 100));
+
+        let endpoint = try!(Endpoint::new("otlp", &host, port as u16).configure(config));
+        Ok(OtlpOutput::new(&host, port as u16)
+            .endpoint(endpoint)
+            .path(&path)
+            .resource(hostname.as_ref().map(|hostname| &hostname[..]), service.as_ref().map(|service| &service[..]))
+            .fields(&timestamp, &level, &message)
+            .limit(limit as usize))
+    }
+}
+
+impl Output for OtlpOutput {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        self.queue.push(payload.clone());
+
+        if self.queue.len() >= self.limit {
+            let result = self.flush();
+            if result.is_err() {
+                // The record is not retained on failure, it's up to the caller to feed it again.
+                self.queue.pop();
+            }
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+
+        let queue = mem::replace(&mut self.queue, Vec::new());
+        let result = self.send(&queue);
+        self.queue = queue;
+        if result.is_ok() {
+            self.queue.clear();
+        }
+        result
+    }
+
+    fn discard(&mut self) -> usize {
+        let count = self.queue.len();
+        self.queue.clear();
+        count
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.endpoint.check()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.endpoint.reseed(seed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    use super::super::super::RecordItem;
+    use super::super::super::testing::{record, scripted_http_server, string};
+    use super::super::{Error, Output};
+    use super::{OtlpOutput, severity};
+
+    #[test]
+    fn encode_every_record_item_as_otlp() {
+        let output = OtlpOutput::new("127.0.0.1", 4318).resource(Some("box"), Some("api"));
+
+        let mut object = HashMap::new();
+        object.insert("b".to_string(), RecordItem::Null);
+        object.insert("a".to_string(), RecordItem::Array(vec![RecordItem::I64(1), string("x")]));
+
+        let payload = record(vec![
+            ("timestamp", RecordItem::F64(1433162096.5)),
+            ("level", string("warning")),
+            ("message", string("le \"message\"")),
+            ("null", RecordItem::Null),
+            ("bool", RecordItem::Bool(true)),
+            ("f64", RecordItem::F64(0.25)),
+            ("nan", RecordItem::F64(::std::f64::NAN)),
+            ("i64", RecordItem::I64(-42)),
+            ("u64", RecordItem::U64(18446744073709551615)),
+            ("bytes", RecordItem::Bytes(vec![1, 2, 3, 4])),
+            ("object", RecordItem::Object(object)),
+        ]);
+
+        // The golden file holds the request body exactly as sent, followed by a newline.
+        let mut expected = String::new();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("golden/otlp/every-record-item.json");
+        File::open(&path).unwrap().read_to_string(&mut expected).unwrap();
+        assert_eq!(expected.trim_right(), output.make_body(&[payload]));
+    }
+
+    #[test]
+    fn map_levels_to_otlp_severities() {
+        let cases = vec![
+            (string("trace"), Some((Some(1), "TRACE"))),
+            (string("DEBUG"), Some((Some(5), "DEBUG"))),
+            (string("info"), Some((Some(9), "INFO"))),
+            (string("notice"), Some((Some(10), "INFO2"))),
+            (string("Warning"), Some((Some(13), "WARN"))),
+            (string("error"), Some((Some(17), "ERROR"))),
+            (string("crit"), Some((Some(18), "ERROR2"))),
+            (string("alert"), Some((Some(19), "ERROR3"))),
+            (string("fatal"), Some((Some(21), "FATAL"))),
+            (RecordItem::I64(0), Some((Some(21), "FATAL"))),
+            (RecordItem::I64(3), Some((Some(17), "ERROR"))),
+            (RecordItem::U64(6), Some((Some(9), "INFO"))),
+            (string("verbose"), Some((None, "verbose"))),
+            (RecordItem::I64(8), None),
+            (RecordItem::Bool(true), None),
+        ];
+
+        for (value, expected) in cases {
+            let expected = expected.map(|(number, text)| (number, text.to_string()));
+            assert_eq!(expected, severity(&value));
+        }
+    }
+
+    #[test]
+    fn batch_records_into_single_export_request() {
+        let output = OtlpOutput::new("127.0.0.1", 4318).fields("ts", "severity", "msg");
+
+        let records = vec![
+            record(vec![("msg", string("first")), ("ts", RecordItem::I64(1)), ("severity", string("info"))]),
+            record(vec![("msg", string("second")), ("ts", string("never")), ("host", string("box"))]),
+        ];
+
+        let expected = concat!(
+            r#"{"resourceLogs":[{"resource":{"attributes":[]},"#,
+            r#""scopeLogs":[{"scope":{"name":"logdrop"},"logRecords":["#,
+            r#"{"timeUnixNano":"1000000000","severityNumber":9,"severityText":"INFO","#,
+            r#""body":{"stringValue":"first"},"attributes":[]},"#,
+            r#"{"body":{"stringValue":"second"},"attributes":["#,
+            r#"{"key":"host","value":{"stringValue":"box"}},{"key":"ts","value":{"stringValue":"never"}}]}"#,
+            r#"]}]}]}"#);
+
+        assert_eq!(expected, output.make_body(&records));
+    }
+
+    #[test]
+    fn retry_export_until_collector_accepts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bodies = scripted_http_server(listener, |id| if id == 0 { 503 } else { 200 });
+
+        let mut output = OtlpOutput::new("127.0.0.1", port).limit(2);
+        output.feed(&record(vec![("message", string("first"))])).unwrap();
+        match output.feed(&record(vec![("message", string("second"))])) {
+            Err(Error::Retryable(..)) => {}
+            result => panic!("expected retryable error, found {:?}", result),
+        }
+        assert_eq!(1, output.queue.len());
+
+        // Fed again, like the pipeline does after the backoff.
+        output.feed(&record(vec![("message", string("second"))])).unwrap();
+        assert_eq!(0, output.queue.len());
+
+        let first = bodies.recv().unwrap();
+        assert_eq!(first, bodies.recv().unwrap());
+        assert!(first.contains(r#""body":{"stringValue":"second"}"#));
+    }
+}
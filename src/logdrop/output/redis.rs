@@ -0,0 +1,674 @@
+use std::cmp;
+use std::io;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+
+use super::super::{Record, RecordItem};
+use super::super::clock::{Clock, SystemClock};
+use super::super::codec::json;
+use super::super::config::{Config, Error as ConfigError, FromConfig};
+use super::super::metrics;
+use super::super::registry;
+use super::super::resp;
+use super::super::resp::Reply;
+use super::{Endpoint, Error, Output};
+
+/// Where records are pushed to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mode {
+    /// Appends JSON-encoded records to the list, trimming it to the given number of last entries
+    /// after each batch if bounded.
+    List { key: String, trim: Option<u64> },
+    /// Adds records flattened into field-value pairs to the stream, approximately capped at the
+    /// given length if bounded.
+    Stream { key: String, maxlen: Option<u64> },
+}
+
+/// Connection with authentication and database selection done.
+struct Connection {
+    wr: TcpStream,
+    rd: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(endpoint: &mut Endpoint, password: Option<&str>, db: Option<u64>) -> Result<Connection, String> {
+        let stream = try!(endpoint.connect());
+        let rd = try!(stream.try_clone().map_err(|err| format!("unable to connect: {}", err)));
+
+        let mut conn = Connection {
+            wr: stream,
+            rd: BufReader::new(rd),
+        };
+
+        if let Some(password) = password {
+            try!(conn.call(&[&b"AUTH"[..], password.as_bytes()]));
+        }
+
+        if let Some(db) = db {
+            try!(conn.call(&[&b"SELECT"[..], db.to_string().as_bytes()]));
+        }
+
+        Ok(conn)
+    }
+
+    /// Sends the command and waits for its reply, turning error replies into errors.
+    fn call(&mut self, args: &[&[u8]]) -> Result<Reply, String> {
+        let name = String::from_utf8_lossy(args[0]).into_owned();
+        match self.exchange(&resp::command(args)).and_then(|()| resp::read(&mut self.rd)) {
+            Ok(Reply::Error(reason)) => Err(format!("{} failed: {}", name, reason)),
+            Ok(reply) => Ok(reply),
+            Err(err) => Err(format!("{} failed: {}", name, err)),
+        }
+    }
+
+    fn exchange(&mut self, data: &[u8]) -> io::Result<()> {
+        try!(self.wr.write_all(data));
+        self.wr.flush()
+    }
+}
+
+/// Redis output pushes records into a list or a stream, speaking the protocol directly.
+///
+/// Records are accumulated in memory and sent as pipelined commands either when the queue
+/// reaches the limit or on flush. Commands of a batch are written at once and their replies read
+/// afterwards, so records acknowledged before the connection is lost are not sent again, while
+/// the rest of the batch is kept for the next attempt.
+///
+/// Each (re)connection resolves the host name again, authenticates and selects the database if
+/// configured. Failed connection attempts are spaced by the exponential backoff. The connection is
+/// moved between batches once the name no longer resolves to its address, see `Endpoint`. Records rejected by error replies are counted
+/// in `output.redis.rejected` and passed to the dead-letter output, if any.
+pub struct RedisOutput {
+    endpoint: Endpoint,
+    mode: Mode,
+    password: Option<String>,
+    db: Option<u64>,
+    limit: usize,
+    queue: Vec<Record>,
+    conn: Option<Connection>,
+    dead_letter: Option<Box<Output>>,
+    /// Initial and maximum delay between connection attempts in milliseconds.
+    backoff: (u64, u64),
+    delay: u64,
+    retry_at: u64,
+    /// Whether object keys are encoded ordered by name, see `Output::sort_keys`.
+    sorted: bool,
+}
+
+impl RedisOutput {
+    pub fn new(host: &str, port: u16, mode: Mode) -> RedisOutput {
+        RedisOutput {
+            endpoint: Endpoint::new("redis", host, port),
+            mode: mode,
+            password: None,
+            db: None,
+            limit: 100,
+            queue: Vec::new(),
+            conn: None,
+            dead_letter: None,
+            backoff: (100, 10000),
+            delay: 0,
+            retry_at: 0,
+            sorted: false,
+        }
+    }
+
+    pub fn auth(mut self, password: &str) -> RedisOutput {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    pub fn select(mut self, db: u64) -> RedisOutput {
+        self.db = Some(db);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> RedisOutput {
+        self.limit = limit;
+        self
+    }
+
+    /// Sets the initial and the maximum delay between connection attempts in milliseconds.
+    pub fn backoff(mut self, initial: u64, max: u64) -> RedisOutput {
+        self.backoff = (initial, max);
+        self
+    }
+
+    /// Replaces the endpoint, e.g. to resolve its name differently.
+    pub fn endpoint(mut self, endpoint: Endpoint) -> RedisOutput {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// Sets the output receiving records rejected by error replies.
+    pub fn dead_letter(mut self, output: Box<Output>) -> RedisOutput {
+        self.dead_letter = Some(output);
+        self
+    }
+
+    /// Returns pipelined commands pushing the records, one per record, followed by the trimming
+    /// one if the list is bounded.
+    fn commands(&self, records: &[Record]) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self.mode {
+            Mode::List { ref key, trim } => {
+                for record in records.iter() {
+                    data.extend(resp::command(&[&b"RPUSH"[..], key.as_bytes(), json::encode_record(record, self.sorted).as_bytes()]));
+                }
+
+                if let Some(trim) = trim {
+                    let start = format!("-{}", trim);
+                    data.extend(resp::command(&[&b"LTRIM"[..], key.as_bytes(), start.as_bytes(), &b"-1"[..]]));
+                }
+            }
+            Mode::Stream { ref key, maxlen } => {
+                let maxlen = maxlen.map(|maxlen| maxlen.to_string());
+                for record in records.iter() {
+                    let pairs = flatten(record);
+
+                    let mut args: Vec<&[u8]> = vec![&b"XADD"[..], key.as_bytes()];
+                    if let Some(ref maxlen) = maxlen {
+                        args.push(&b"MAXLEN"[..]);
+                        args.push(&b"~"[..]);
+                        args.push(maxlen.as_bytes());
+                    }
+                    args.push(&b"*"[..]);
+                    for &(ref name, ref value) in pairs.iter() {
+                        args.push(name.as_bytes());
+                        args.push(value);
+                    }
+
+                    data.extend(resp::command(&args));
+                }
+            }
+        }
+
+        data
+    }
+
+    /// Returns the established connection or establishes a new one, unless the backoff delay
+    /// since the last failed attempt is still running.
+    fn connect(&mut self) -> Result<Connection, Error> {
+        if let Some(conn) = self.conn.take() {
+            return Ok(conn);
+        }
+
+        let now = SystemClock.now();
+        if now < self.retry_at {
+            return Err(Error::Retryable(format!("reconnecting to {} in {} ms", self.endpoint, self.retry_at - now)));
+        }
+
+        let password = self.password.as_ref().map(|password| &password[..]);
+        match Connection::open(&mut self.endpoint, password, self.db) {
+            Ok(conn) => {
+                info!(target: "Output::Redis", "connected to {}", self.endpoint);
+                self.delay = 0;
+                Ok(conn)
+            }
+            Err(reason) => {
+                self.delay = cmp::min(cmp::max(self.delay * 2, self.backoff.0), self.backoff.1);
+                self.retry_at = now + self.delay;
+                Err(Error::Retryable(format!("failed to connect to {} - {}", self.endpoint, reason)))
+            }
+        }
+    }
+
+    /// Sends queued records, keeping the ones not acknowledged on failure.
+    fn send(&mut self) -> Result<(), Error> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+
+        // Nothing is in flight between batches, so the connection is moved without losing records.
+        if self.conn.is_some() && self.endpoint.stale() {
+            info!(target: "Output::Redis", "reconnecting to {} as its address has changed", self.endpoint);
+            self.conn = None;
+        }
+
+        let mut conn = try!(self.connect());
+        debug!(target: "Output::Redis", "sending {} records to {}", self.queue.len(), self.endpoint);
+
+        if let Err(err) = conn.exchange(&self.commands(&self.queue)) {
+            return Err(Error::Ambiguous(format!("failed to send {} records - {}", self.queue.len(), err)));
+        }
+
+        let mut rejected = Vec::new();
+        for id in 0..self.queue.len() {
+            match resp::read(&mut conn.rd) {
+                Ok(Reply::Error(reason)) => rejected.push((id, reason)),
+                Ok(..) => {}
+                Err(err) => {
+                    self.reject(&rejected);
+                    self.queue = self.queue[id..].to_vec();
+                    return Err(Error::Ambiguous(format!("connection lost, {} records unacknowledged - {}",
+                        self.queue.len(), err)));
+                }
+            }
+        }
+
+        if let Mode::List { trim: Some(..), .. } = self.mode {
+            match resp::read(&mut conn.rd) {
+                Ok(Reply::Error(reason)) => warn!(target: "Output::Redis", "failed to trim the list - {}", reason),
+                Ok(..) => {}
+                Err(err) => {
+                    // Records are delivered, so only the trimming is lost until the next batch.
+                    self.reject(&rejected);
+                    self.queue.clear();
+                    warn!(target: "Output::Redis", "failed to trim the list - {}", err);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.reject(&rejected);
+        self.queue.clear();
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    /// Passes records rejected by error replies to the dead-letter output.
+    fn reject(&mut self, rejected: &[(usize, String)]) {
+        if rejected.is_empty() {
+            return;
+        }
+
+        metrics::registry().counter("output.redis.rejected").add(rejected.len());
+        for &(id, ref reason) in rejected.iter() {
+            warn!(target: "Output::Redis", "record rejected - {}", reason);
+
+            let result = match self.dead_letter {
+                Some(ref mut output) => output.feed(&self.queue[id]),
+                None => Ok(()),
+            };
+
+            if let Err(err) = result {
+                warn!(target: "Output::Redis", "dropping rejected record: dead-letter output failed - {}", err);
+            }
+        }
+    }
+}
+
+/// Flattens the record into field-value pairs ordered by field name, naming nested values by
+/// their dot-separated paths.
+fn flatten(record: &Record) -> Vec<(String, Vec<u8>)> {
+    fn walk(name: String, value: &RecordItem, pairs: &mut Vec<(String, Vec<u8>)>) {
+        let value = match *value {
+            RecordItem::Null => Vec::new(),
+            RecordItem::Bool(value) => value.to_string().into_bytes(),
+            RecordItem::F64(value) => value.to_string().into_bytes(),
+            RecordItem::I64(value) => value.to_string().into_bytes(),
+            RecordItem::U64(value) => value.to_string().into_bytes(),
+            RecordItem::String(ref value) => value.as_bytes().to_vec(),
+            RecordItem::Text(ref value) => value.as_bytes().to_vec(),
+            RecordItem::Bytes(ref value) => value.clone(),
+            RecordItem::Array(ref values) => {
+                for (id, value) in values.iter().enumerate() {
+                    walk(format!("{}.{}", name, id), value, pairs);
+                }
+                return;
+            }
+            RecordItem::Object(ref map) => {
+                for (key, value) in map.iter() {
+                    walk(format!("{}.{}", name, key), value, pairs);
+                }
+                return;
+            }
+        };
+
+        pairs.push((name, value));
+    }
+
+    let mut pairs = Vec::new();
+    for (name, value) in record.iter() {
+        walk(name.clone(), value, &mut pairs);
+    }
+    pairs.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+
+    pairs
+}
+
+impl FromConfig for RedisOutput {
+    fn from_config(config: &Config) -> Result<RedisOutput, ConfigError> {
+        let host = try!(config.string_or("host", "localhost"));
+        let port = try!(config.u64_or("port", 6379));
+        let key = try!(config.string_or("key", "logs"));
+
+        let bound = |name: &str| -> Result<Option<u64>, ConfigError> {
+            match config.find(name) {
+                Some(..) => Ok(Some(try!(config.u64_or(name, 0)))),
+                None => Ok(None),
+            }
+        };
+
+        let mode = match &try!(config.string_or("mode", "list"))[..] {
+            "list" => Mode::List { key: key, trim: try!(bound("trim")) },
+            "stream" => Mode::Stream { key: key, maxlen: try!(bound("maxlen")) },
+            mode => return Err(ConfigError::Invalid(format!("unknown redis mode '{}'", mode))),
+        };
+
+        let endpoint = try!(Endpoint::new("redis", &host, port as u16).configure(config));
+        let mut output = RedisOutput::new(&host, port as u16, mode)
+            .endpoint(endpoint)
+            .limit(try!(config.u64_or("limit", 100)) as usize)
+            .backoff(try!(config.u64_or("backoff", 100)), try!(config.u64_or("max_backoff", 10000)));
+
+        if config.find("password").is_some() {
+            output = output.auth(&try!(config.string("password")));
+        }
+
+        if config.find("db").is_some() {
+            output = output.select(try!(config.u64_or("db", 0)));
+        }
+
+        match config.find("dead_letter") {
+            Some(config) => Ok(output.dead_letter(try!(registry::output(&config)))),
+            None => Ok(output),
+        }
+    }
+}
+
+impl Output for RedisOutput {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        self.queue.push(payload.clone());
+
+        if self.queue.len() >= self.limit {
+            let result = self.send();
+            if result.is_err() {
+                // The record is not retained on failure, it's up to the caller to feed it again.
+                self.queue.pop();
+            }
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let result = self.send();
+
+        if let Some(ref mut output) = self.dead_letter {
+            if let Err(err) = output.flush() {
+                warn!(target: "Output::Redis", "failed to flush dead-letter output - {}", err);
+            }
+        }
+
+        result
+    }
+
+    fn discard(&mut self) -> usize {
+        let count = self.queue.len();
+        self.queue.clear();
+        count
+    }
+
+    fn probe(&mut self) -> bool {
+        let mut conn = match self.connect() {
+            Ok(conn) => conn,
+            Err(..) => return false,
+        };
+
+        match conn.call(&[&b"PING"[..]]) {
+            Ok(..) => {
+                self.conn = Some(conn);
+                true
+            }
+            Err(reason) => {
+                debug!(target: "Output::Redis", "probe failed - {}", reason);
+                false
+            }
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.endpoint.check()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.endpoint.reseed(seed);
+    }
+
+    fn sort_keys(&mut self) {
+        self.sorted = true;
+        if let Some(ref mut output) = self.dead_letter {
+            output.sort_keys();
+        }
+    }
+
+    fn describe_fields(&self) -> Option<Vec<String>> {
+        match self.dead_letter {
+            Some(ref output) => output.describe_fields(),
+            None => Some(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::mpsc::Receiver;
+
+    use super::super::super::RecordItem;
+    use super::super::super::clock::MockClock;
+    use super::super::super::codec::json;
+    use super::super::super::resp;
+    use super::super::super::testing::{Collector, Dns, record, redis_server, string};
+    use super::super::{Endpoint, Error, Output};
+    use super::{Mode, RedisOutput};
+
+    fn list(trim: Option<u64>) -> Mode {
+        Mode::List { key: "events".to_string(), trim: trim }
+    }
+
+    /// Starts the scripted server, returning the output connected to it.
+    fn start<F>(mode: Mode, script: F) -> (RedisOutput, Receiver<(usize, Vec<String>)>)
+        where F: Fn(usize, &[String]) -> Option<String> + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = redis_server(listener, script);
+        (RedisOutput::new("127.0.0.1", port, mode).backoff(0, 0), commands)
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn frame_list_commands() {
+        let output = RedisOutput::new("localhost", 6379, list(Some(1000)));
+        let records = vec![record(vec![("message", string("first"))]), record(vec![("message", string("second"))])];
+
+        let mut expected = Vec::new();
+        for record in records.iter() {
+            expected.extend(resp::command(&[&b"RPUSH"[..], &b"events"[..], json::encode(record).as_bytes()]));
+        }
+        expected.extend(b"*4\r\n$5\r\nLTRIM\r\n$6\r\nevents\r\n$5\r\n-1000\r\n$2\r\n-1\r\n".iter().cloned());
+
+        assert_eq!(expected, output.commands(&records));
+    }
+
+    #[test]
+    fn frame_stream_commands() {
+        let mode = Mode::Stream { key: "audit".to_string(), maxlen: Some(5000) };
+        let output = RedisOutput::new("localhost", 6379, mode);
+
+        let mut user = record(vec![("id", RecordItem::F64(42.0))]);
+        user.insert("roles".to_string(), RecordItem::Array(vec![string("admin"), RecordItem::Bool(true)]));
+        let record = record(vec![
+            ("action", string("login")),
+            ("user", RecordItem::Object(user.iter().map(|(k, v)| (k.clone(), v.clone())).collect())),
+        ]);
+
+        let expected = b"*12\r\n$4\r\nXADD\r\n$5\r\naudit\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n$4\r\n5000\r\n$1\r\n*\r\n\
+            $6\r\naction\r\n$5\r\nlogin\r\n$7\r\nuser.id\r\n$2\r\n42\r\n\
+            $12\r\nuser.roles.0\r\n$5\r\nadmin\r\n$12\r\nuser.roles.1\r\n$4\r\ntrue\r\n";
+        assert_eq!(expected.to_vec(), output.commands(&[record]));
+    }
+
+    #[test]
+    fn pipeline_batches_after_auth_and_select() {
+        let (output, commands) = start(list(None), |_, args| {
+            match &args[0][..] {
+                "RPUSH" => Some(":1\r\n".to_string()),
+                _ => Some("+OK\r\n".to_string()),
+            }
+        });
+        let mut output = output.auth("secret").select(3).limit(3);
+
+        output.feed(&record(vec![("id", RecordItem::F64(1.0))])).unwrap();
+        output.feed(&record(vec![("id", RecordItem::F64(2.0))])).unwrap();
+        assert!(commands.try_recv().is_err());
+
+        output.feed(&record(vec![("id", RecordItem::F64(3.0))])).unwrap();
+        assert_eq!((0, strings(&["AUTH", "secret"])), commands.recv().unwrap());
+        assert_eq!((0, strings(&["SELECT", "3"])), commands.recv().unwrap());
+        for id in 1..4 {
+            let body = format!(r#"{{"id":{}}}"#, id);
+            assert_eq!((0, strings(&["RPUSH", "events", &body[..]])), commands.recv().unwrap());
+        }
+
+        output.feed(&record(vec![("id", RecordItem::F64(4.0))])).unwrap();
+        output.flush().unwrap();
+        assert_eq!((0, strings(&["RPUSH", "events", r#"{"id":4}"#])), commands.recv().unwrap());
+    }
+
+    #[test]
+    fn bound_list_and_stream() {
+        let (mut output, commands) = start(list(Some(10)), |_, args| {
+            match &args[0][..] {
+                "RPUSH" => Some(":1\r\n".to_string()),
+                _ => Some("+OK\r\n".to_string()),
+            }
+        });
+
+        output.feed(&record(vec![("id", RecordItem::F64(1.0))])).unwrap();
+        output.flush().unwrap();
+        assert_eq!((0, strings(&["RPUSH", "events", r#"{"id":1}"#])), commands.recv().unwrap());
+        assert_eq!((0, strings(&["LTRIM", "events", "-10", "-1"])), commands.recv().unwrap());
+
+        let mode = Mode::Stream { key: "audit".to_string(), maxlen: Some(100) };
+        let (mut output, commands) = start(mode, |_, _| Some("$3\r\n1-0\r\n".to_string()));
+
+        output.feed(&record(vec![("id", RecordItem::F64(1.0))])).unwrap();
+        output.flush().unwrap();
+        assert_eq!((0, strings(&["XADD", "audit", "MAXLEN", "~", "100", "*", "id", "1"])), commands.recv().unwrap());
+    }
+
+    #[test]
+    fn dead_letter_rejected_records() {
+        let (output, _commands) = start(list(None), |_, args| {
+            if args[2].contains("bad") {
+                Some("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string())
+            } else {
+                Some(":1\r\n".to_string())
+            }
+        });
+        let dead_letter = Collector::new();
+        let mut output = output.dead_letter(Box::new(dead_letter.clone()));
+
+        let records = vec![
+            record(vec![("message", string("good"))]),
+            record(vec![("message", string("bad"))]),
+            record(vec![("message", string("fine"))]),
+        ];
+        for record in records.iter() {
+            output.feed(record).unwrap();
+        }
+        output.flush().unwrap();
+
+        assert_eq!(vec![records[1].clone()], dead_letter.records());
+        assert!(output.queue.is_empty());
+    }
+
+    #[test]
+    fn replay_unacknowledged_records_after_reconnect() {
+        // The first connection is lost after acknowledging the first record only.
+        let (mut output, commands) = start(list(None), |conn, args| {
+            if conn == 0 && !args[2].contains("first") {
+                None
+            } else {
+                Some(":1\r\n".to_string())
+            }
+        });
+
+        for message in ["first", "second", "third"].iter() {
+            output.feed(&record(vec![("message", string(message))])).unwrap();
+        }
+
+        match output.flush() {
+            Err(Error::Ambiguous(..)) => {}
+            other => panic!("expected ambiguous failure, got {:?}", other),
+        }
+        assert_eq!(2, output.queue.len());
+
+        output.flush().unwrap();
+        assert!(output.queue.is_empty());
+
+        let sent: Vec<(usize, String)> = commands.iter().take(4).map(|(conn, args)| (conn, args[2].clone())).collect();
+        let expected = vec![
+            (0, r#"{"message":"first"}"#.to_string()),
+            (0, r#"{"message":"second"}"#.to_string()),
+            (1, r#"{"message":"second"}"#.to_string()),
+            (1, r#"{"message":"third"}"#.to_string()),
+        ];
+        assert_eq!(expected, sent);
+    }
+
+    fn pushing(_: usize, _: &[String]) -> Option<String> {
+        Some(":1\r\n".to_string())
+    }
+
+    #[test]
+    fn move_to_new_address_between_batches() {
+        let old = TcpListener::bind("127.0.0.1:0").unwrap();
+        let new = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (old_addr, new_addr) = (old.local_addr().unwrap(), new.local_addr().unwrap());
+        let old_commands = redis_server(old, pushing);
+        let new_commands = redis_server(new, pushing);
+
+        let dns = Dns::new(vec![old_addr]);
+        let clock = MockClock::new(0);
+        let endpoint = Endpoint::new("redis", "collector", 6379)
+            .resolver(Arc::new(dns.clone()), Arc::new(clock.clone()))
+            .interval(1000);
+        let mut output = RedisOutput::new("collector", 6379, list(None)).endpoint(endpoint).backoff(0, 0);
+
+        output.feed(&record(vec![("id", RecordItem::F64(1.0))])).unwrap();
+        output.flush().unwrap();
+
+        // The name moves while the first connection is still alive.
+        dns.set(vec![new_addr]);
+        output.feed(&record(vec![("id", RecordItem::F64(2.0))])).unwrap();
+        output.flush().unwrap();
+
+        clock.advance(1000);
+        output.feed(&record(vec![("id", RecordItem::F64(3.0))])).unwrap();
+        output.flush().unwrap();
+
+        assert_eq!((0, strings(&["RPUSH", "events", r#"{"id":1}"#])), old_commands.recv().unwrap());
+        assert_eq!((0, strings(&["RPUSH", "events", r#"{"id":2}"#])), old_commands.recv().unwrap());
+        assert_eq!((0, strings(&["RPUSH", "events", r#"{"id":3}"#])), new_commands.recv().unwrap());
+        assert!(old_commands.try_recv().is_err());
+        assert_eq!(Some(new_addr), output.endpoint.current());
+    }
+
+    #[test]
+    fn fall_back_to_next_address_on_refusal() {
+        // Nobody listens on the port once the listener is dropped.
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let commands = redis_server(listener, pushing);
+
+        let endpoint = Endpoint::new("redis", "collector", 6379)
+            .resolver(Arc::new(Dns::new(vec![refused, addr])), Arc::new(MockClock::new(0)));
+        let mut output = RedisOutput::new("collector", 6379, list(None)).endpoint(endpoint).backoff(0, 0);
+
+        output.feed(&record(vec![("id", RecordItem::F64(1.0))])).unwrap();
+        output.flush().unwrap();
+
+        assert_eq!((0, strings(&["RPUSH", "events", r#"{"id":1}"#])), commands.recv().unwrap());
+        assert_eq!(Some(addr), output.endpoint.current());
+    }
+}
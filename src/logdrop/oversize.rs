@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error, FromConfig};
+
+/// Field holding the reference id shared by an oversize record and its stubs.
+pub const ID: &'static str = "_oversize_id";
+/// Field holding the estimated size of the original record in stubs.
+pub const SIZE: &'static str = "_oversize_size";
+
+/// Oversize record path settings, see `Pipeline::set_oversize`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Estimated size in bytes above which a record is oversize.
+    pub threshold: usize,
+    /// Number of oversize records an output channel holds, further ones going the usual way.
+    pub depth: usize,
+    /// Fields copied into stubs sent to outputs not accepting oversize records.
+    pub canonical: Vec<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            threshold: 1024 * 1024,
+            depth: 4,
+            canonical: vec!["timestamp", "type", "level", "host", "source", "_source"].into_iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let default = Settings::default();
+        let threshold = try!(config.u64_or("threshold", default.threshold as u64)) as usize;
+        let depth = try!(config.u64_or("depth", default.depth as u64)) as usize;
+
+        if threshold == 0 || depth == 0 {
+            return Err(Error::Invalid("oversize 'threshold' and 'depth' must be positive".to_string()));
+        }
+
+        Ok(Settings {
+            threshold: threshold,
+            depth: depth,
+            canonical: try!(config.strings_or("canonical", default.canonical)),
+        })
+    }
+}
+
+/// Tells oversize records apart, stamping each one with the reference id.
+pub struct Oversize {
+    pub settings: Settings,
+    /// Time the process started in milliseconds, keeping ids unique across restarts.
+    epoch: u64,
+    next: AtomicUsize,
+}
+
+impl Oversize {
+    pub fn new(settings: Settings) -> Oversize {
+        Oversize::with_epoch(settings, SystemClock.now())
+    }
+
+    /// Creates the path stamping ids starting from the given epoch, e.g. a fixed one in the
+    /// deterministic mode.
+    pub fn with_epoch(settings: Settings, epoch: u64) -> Oversize {
+        Oversize {
+            settings: settings,
+            epoch: epoch,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns `None` for a record within the threshold, otherwise its copy stamped with the
+    /// reference id together with its stub.
+    pub fn check(&self, record: &Record) -> Option<(Record, Record)> {
+        let size = record.size_hint();
+        if size <= self.settings.threshold {
+            return None;
+        }
+
+        let id = format!("{:x}-{}", self.epoch, self.next.fetch_add(1, Ordering::SeqCst));
+        debug!(target: "Pipeline", "record '{}' of {} bytes is oversize", id, size);
+
+        let mut record = record.clone();
+        record.insert(ID.to_string(), RecordItem::String(id));
+        let stub = stub(&record, size, &self.settings.canonical);
+        Some((record, stub))
+    }
+}
+
+/// Makes the stub of the stamped oversize record, keeping only its canonical fields, the
+/// reference id and the original size.
+pub fn stub(record: &Record, size: usize, canonical: &[String]) -> Record {
+    let mut stub = Record::new();
+    for name in canonical.iter().map(|name| &name[..]).chain(Some(ID).into_iter()) {
+        if let Some(value) = record.find(name) {
+            stub.insert(name.to_string(), value.clone());
+        }
+    }
+    stub.insert(SIZE.to_string(), RecordItem::F64(size as f64));
+
+    stub
+}
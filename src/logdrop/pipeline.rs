@@ -0,0 +1,3942 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::thread;
+use std::thread::JoinHandle;
+
+use super::{Envelope, Origin, Record, RecordItem};
+use super::ack::Ack;
+use super::alert;
+use super::alert::Alerting;
+use super::audit;
+use super::breaker;
+use super::breaker::Breaker;
+use super::capture::Capture;
+use super::clock::{Clock, LogicalClock, SystemClock};
+use super::codec::{Codec, Projection};
+use super::determinism;
+use super::diff;
+use super::encoding::Encoding;
+use super::filter::{Chain, Filter};
+use super::input::{Input, Sink};
+use super::lineage;
+use super::lineage::Lineage;
+use super::metrics;
+use super::metrics::Counter;
+use super::naming;
+use super::numeric::NonFinite;
+use super::output::{Delivery, Error, OnError, Output};
+use super::oversize;
+use super::oversize::Oversize;
+use super::poison;
+use super::poison::Detector;
+use super::queue;
+use super::queue::{ElasticReceiver, ElasticSender, Received};
+use super::quota;
+use super::quota::Quota;
+use super::retention::Retention;
+use super::sched;
+use super::sched::Scheduler;
+use super::snapshot;
+use super::snapshot::Store;
+use super::source;
+use super::source::Resolution;
+use super::spool::Spool;
+use super::unwind;
+use super::validation::{Validation, Verdict};
+use super::version;
+use super::version::Summary;
+use super::warmup;
+use super::warmup::WarmUp;
+
+/// Message for an output worker thread.
+enum Event {
+    /// Record together with the copy of its token the worker confirms once the output accepts it
+    /// and its processing id, see `processing_id`.
+    Record(Record, Option<Ack>, u64),
+    Flush,
+    /// Wakes the worker to deliver records waiting in its oversize channel.
+    Oversize,
+}
+
+/// Sending end of an output worker channel.
+enum Channel {
+    Direct(Sender<Event>),
+    Elastic(ElasticSender<Record>),
+}
+
+/// Receiving end of the channel from inputs to the dispatch loop.
+enum Inbox {
+    Direct(Receiver<Envelope>),
+    Elastic(ElasticReceiver<Envelope>),
+}
+
+impl Inbox {
+    fn recv(&self) -> Option<Envelope> {
+        match *self {
+            Inbox::Direct(ref rx) => rx.recv().ok(),
+            Inbox::Elastic(ref rx) => rx.recv(),
+        }
+    }
+}
+
+/// Settings of the slow-consumer warning, see `Pipeline::set_slow_consumer`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct SlowConsumer {
+    high: usize,
+    interval: u64,
+}
+
+/// Backlog of an output channel: records sent to the worker minus records it has handled.
+struct Backlog {
+    name: String,
+    sent: Arc<AtomicUsize>,
+    handled: Arc<AtomicUsize>,
+    /// When the last warning was logged, while the backlog stays above the high-water mark.
+    warned: Option<u64>,
+    warnings: Counter,
+    recoveries: Counter,
+}
+
+impl Backlog {
+    fn new(id: usize, name: &str, handled: Arc<AtomicUsize>) -> Backlog {
+        let registry = metrics::registry();
+        Backlog {
+            name: name.to_string(),
+            sent: Arc::new(AtomicUsize::new(0)),
+            handled: handled,
+            warned: None,
+            warnings: registry.counter(&format!("output.{}.backlog.warnings", id)),
+            recoveries: registry.counter(&format!("output.{}.backlog.recoveries", id)),
+        }
+    }
+
+    fn pending(&self) -> usize {
+        let handled = self.handled.load(Ordering::SeqCst);
+        self.sent.load(Ordering::SeqCst).saturating_sub(handled)
+    }
+
+    /// Warns if the backlog exceeds the high-water mark, unless already warned within the
+    /// interval, or tells it has recovered after a warning.
+    fn sample(&mut self, settings: &SlowConsumer, now: u64) {
+        let pending = self.pending();
+        if pending > settings.high {
+            let due = match self.warned {
+                Some(warned) => now.saturating_sub(warned) >= settings.interval,
+                None => true,
+            };
+
+            if due {
+                warn!(target: "Pipeline", "'{}' output falls behind: {} records pending, above {}", self.name, pending, settings.high);
+                self.warned = Some(now);
+                self.warnings.inc();
+            }
+        } else if self.warned.is_some() {
+            info!(target: "Pipeline", "'{}' output has caught up: {} records pending", self.name, pending);
+            self.warned = None;
+            self.recoveries.inc();
+        }
+    }
+}
+
+/// Channels to an output worker.
+struct Route {
+    channel: Channel,
+    /// Channel of oversize records, if the output accepts them intact.
+    oversize: Option<SyncSender<(Record, Option<Ack>)>>,
+    /// Number of oversize records sent through the usual channel, as theirs was full.
+    overflowed: Counter,
+    backlog: Backlog,
+    /// State of the output circuit breaker, if any.
+    breaker: Option<breaker::Status>,
+    /// Delivery guarantee of the output.
+    delivery: Delivery,
+    /// Whether the output is required to confirm records sent with a token, see `ack`.
+    acked: bool,
+}
+
+impl Route {
+    /// Sends the record with the copy of its token, if the output is required to confirm it.
+    ///
+    /// Elastic channels carry no tokens nor processing ids, confirming records once queued instead.
+    fn send(&self, record: Record, ack: Option<&Ack>, processing: u64) {
+        let ack = self.token(ack);
+        self.push(record, ack, processing);
+    }
+
+    /// Sends the record with its own token through the usual channel.
+    fn push(&self, record: Record, ack: Option<Ack>, processing: u64) {
+        self.backlog.sent.fetch_add(1, Ordering::SeqCst);
+        match self.channel {
+            Channel::Direct(ref tx) => tx.send(Event::Record(record, ack, processing)).unwrap(),
+            Channel::Elastic(ref tx) => {
+                match tx.send(record) {
+                    Ok(()) => {
+                        if let Some(ack) = ack {
+                            ack.confirm();
+                        }
+                    }
+                    Err(err) => error!(target: "Pipeline", "dropping record: failed to spill: {}", err),
+                }
+            }
+        }
+    }
+
+    /// Sends the oversize record through its own channel, or the stub through the usual one if
+    /// the output does not accept the record.
+    ///
+    /// The record goes through the usual channel too while its own one is full, rather than
+    /// stalling the dispatch to every output, counted in `output.<id>.oversize.overflowed`.
+    fn send_oversize(&self, record: Record, stub: Record, ack: Option<&Ack>, processing: u64) {
+        let tx = match self.oversize {
+            Some(ref tx) => tx,
+            None => return self.send(stub, ack, processing),
+        };
+
+        match tx.try_send((record, self.token(ack))) {
+            Ok(()) => {
+                if let Channel::Direct(ref tx) = self.channel {
+                    tx.send(Event::Oversize).unwrap();
+                }
+            }
+            Err(TrySendError::Full((record, ack))) => {
+                debug!(target: "Pipeline", "processing {} oversize channel is full, sending record inline", processing);
+                self.overflowed.inc();
+                self.push(record, ack, processing);
+            }
+            Err(TrySendError::Disconnected(..)) => {
+                error!(target: "Pipeline", "dropping oversize record: output worker is gone");
+            }
+        }
+    }
+
+    fn token(&self, ack: Option<&Ack>) -> Option<Ack> {
+        if self.acked { ack.cloned() } else { None }
+    }
+}
+
+/// What to do with records while every output is unavailable.
+pub enum Unavailable {
+    /// Stop reading inputs until an output recovers, pushing back on senders.
+    Block,
+    /// Drop records, counting them in `pipeline.unavailable.dropped`.
+    Drop,
+    /// Write records to the spool, replaying them to the first output that recovers.
+    Spill(Spool),
+}
+
+impl Unavailable {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Unavailable::Block => "block",
+            Unavailable::Drop => "drop",
+            Unavailable::Spill(..) => "spill",
+        }
+    }
+}
+
+/// Where the diff of records changed by the filter chain goes, see `Pipeline::set_transforms`.
+struct Transforms {
+    attach: bool,
+    output: Option<Sender<Event>>,
+}
+
+/// Filter chain together with the output routes it feeds, shared with the ticker to flush
+/// records held back by filters.
+///
+/// With the unavailability policy set, it also tracks whether any output is healthy, emitting a
+/// `logdrop_error` record on each transition.
+struct Dispatch {
+    chain: Chain,
+    routes: Vec<Route>,
+    health: Vec<Arc<AtomicBool>>,
+    policy: Option<Unavailable>,
+    unavailable: bool,
+    /// Records kept in memory while unavailable, until spilled or replayed.
+    held: Vec<Record>,
+    /// Set while records are refused, pausing inputs, see `Sink::with_pause`.
+    paused: Arc<AtomicBool>,
+    /// Set once the pipeline is stopped through its handle, after which records are no longer
+    /// refused but left to the policy.
+    stopping: Arc<AtomicBool>,
+    dropped: Counter,
+    retention: Option<Retention>,
+    oversize: Option<Oversize>,
+    transforms: Option<Transforms>,
+    slow_consumer: Option<SlowConsumer>,
+    lineage: Option<Lineage>,
+    /// Channel of the dead-letter output, taking records filters gave the `_dead_letter_reason`.
+    dead_letter: Option<Sender<Event>>,
+}
+
+impl Dispatch {
+    fn new(chain: Chain, routes: Vec<Route>, health: Vec<Arc<AtomicBool>>, policy: Option<Unavailable>,
+           retention: Option<Retention>) -> Dispatch
+    {
+        Dispatch {
+            chain: chain,
+            routes: routes,
+            health: health,
+            policy: policy,
+            unavailable: false,
+            held: Vec::new(),
+            paused: Arc::new(AtomicBool::new(false)),
+            stopping: Arc::new(AtomicBool::new(false)),
+            dropped: metrics::registry().counter("pipeline.unavailable.dropped"),
+            retention: retention,
+            oversize: None,
+            transforms: None,
+            slow_consumer: None,
+            lineage: None,
+            dead_letter: None,
+        }
+    }
+
+    fn apply(&mut self, record: Record) {
+        self.apply_acked(record, None, 0)
+    }
+
+    /// Passes the record through the filter chain to outputs, confirming it on behalf of the
+    /// dispatch once every output required to has got a copy of the token. Records filters drop,
+    /// hold back or dead-letter are thus confirmed once the chain is done with them, while ones
+    /// held as outputs are unavailable are not.
+    ///
+    /// The processing id of the record is logged at each stage, see `processing_id`.
+    fn apply_acked(&mut self, record: Record, ack: Option<Ack>, processing: u64) {
+        // Records having the reason already, e.g. replayed dead letters, are not diverted again.
+        let marked = record.find("_dead_letter_reason").is_some();
+        let records = match self.transforms {
+            Some(..) => {
+                let before = record.clone();
+                let records = self.chain.process(record, processing);
+                self.transform(&before, records)
+            }
+            None => self.chain.process(record, processing),
+        };
+        let records = if marked { records } else { self.divert(records) };
+        self.send(records, ack.as_ref(), processing);
+
+        if let (false, Some(ack)) = (self.unavailable, ack) {
+            ack.confirm();
+        }
+    }
+
+    /// Sends records given the dead-letter reason by the filter chain to the dead-letter output,
+    /// returning the rest.
+    fn divert(&self, records: Vec<Record>) -> Vec<Record> {
+        let (diverted, records): (Vec<Record>, Vec<Record>) = records.into_iter()
+            .partition(|record| record.find("_dead_letter_reason").is_some());
+
+        for mut record in diverted.into_iter() {
+            if record.find("_dead_letter_stage").is_none() {
+                record.insert("_dead_letter_stage".to_string(), RecordItem::String("filter".to_string()));
+            }
+            match self.dead_letter {
+                Some(ref tx) => tx.send(Event::Record(record, None, 0)).unwrap(),
+                None => warn!(target: "Pipeline", "dropping record: no dead-letter output configured"),
+            }
+        }
+        records
+    }
+
+    /// Captures what the filter chain changed about records coming out of the given one.
+    fn transform(&self, before: &Record, records: Vec<Record>) -> Vec<Record> {
+        let transforms = match self.transforms {
+            Some(ref transforms) => transforms,
+            None => return records,
+        };
+
+        records.into_iter().map(|mut record| {
+            let entries = diff::diff(before, &record);
+            if entries.is_empty() {
+                return record;
+            }
+
+            let changes = diff::to_item(&entries);
+            if let Some(ref tx) = transforms.output {
+                let mut event = Record::new();
+                event.insert("type".to_string(), RecordItem::String("record_diff".to_string()));
+                if let Some(timestamp) = record.find("timestamp") {
+                    event.insert("timestamp".to_string(), timestamp.clone());
+                }
+                event.insert("changes".to_string(), changes.clone());
+                tx.send(Event::Record(event, None, 0)).unwrap();
+            }
+
+            if transforms.attach {
+                record.insert(diff::FIELD.to_string(), changes);
+            }
+            record
+        }).collect()
+    }
+
+    fn flush(&mut self) {
+        let records = self.chain.flush();
+        self.send(records, None, 0);
+        self.spill();
+    }
+
+    /// Flushes all records held back by filters, as the chain is about to be dropped.
+    fn close(&mut self) {
+        let records = self.chain.close();
+        self.send(records, None, 0);
+        self.spill();
+    }
+
+    /// Samples backlogs of output channels, if the slow-consumer warning is enabled.
+    fn watch(&mut self, now: u64) {
+        if let Some(ref settings) = self.slow_consumer {
+            for route in self.routes.iter_mut() {
+                route.backlog.sample(settings, now);
+            }
+        }
+    }
+
+    /// Returns the index of the first healthy output route.
+    fn healthy(&self) -> Option<usize> {
+        self.health.iter().position(|healthy| healthy.load(Ordering::SeqCst))
+    }
+
+    /// Returns true if records must not be taken from inputs for now.
+    fn blocked(&self) -> bool {
+        match self.policy {
+            Some(Unavailable::Block) => !self.routes.is_empty() && self.healthy().is_none(),
+            _ => false,
+        }
+    }
+
+    fn send(&mut self, records: Vec<Record>, ack: Option<&Ack>, processing: u64) {
+        // Outputs take records by value, so the retention ring keeps copies of its own.
+        if let Some(ref retention) = self.retention {
+            for record in records.iter() {
+                retention.push(Arc::new(record.clone()));
+            }
+        }
+
+        if self.policy.is_none() || self.routes.is_empty() {
+            return self.route(records, ack, processing);
+        }
+
+        let available = self.healthy().is_some();
+        if available == self.unavailable {
+            self.unavailable = !available;
+
+            let (error, message) = if available {
+                info!(target: "Pipeline", "outputs are available again");
+                ("outputs_available", "outputs are available again".to_string())
+            } else {
+                let policy = self.policy.as_ref().unwrap().name();
+                warn!(target: "Pipeline", "all outputs are unavailable, applying '{}' policy", policy);
+                ("all_outputs_unavailable", format!("all outputs are unavailable, applying '{}' policy", policy))
+            };
+
+            let mut event = Record::new();
+            event.insert("type".to_string(), RecordItem::String("logdrop_error".to_string()));
+            event.insert("error".to_string(), RecordItem::String(error.to_string()));
+            event.insert("message".to_string(), RecordItem::String(message));
+
+            // The event is kept even by the drop policy, while the recovery one follows records
+            // kept meanwhile.
+            if available {
+                self.replay();
+                self.route(vec![event], None, 0);
+            } else {
+                self.held.push(event);
+            }
+        }
+
+        if available {
+            self.replay();
+            self.route(records, ack, processing);
+        } else {
+            for record in records.into_iter() {
+                self.hold(record);
+            }
+        }
+    }
+
+    fn route(&self, records: Vec<Record>, ack: Option<&Ack>, processing: u64) {
+        for record in records.into_iter() {
+            trace!(target: "Pipeline", "processing {} dispatched to {} outputs", processing, self.routes.len());
+            if let Some((mut record, stub)) = self.oversize.as_ref().and_then(|oversize| oversize.check(&record)) {
+                if let Some(ref lineage) = self.lineage {
+                    let id = record.find(oversize::ID).cloned().unwrap();
+                    let stamp = diff::Entry {
+                        path: vec![oversize::ID.to_string()],
+                        change: diff::Change::Added(id),
+                    };
+                    lineage.note("oversize", &[stamp], &mut record);
+                }
+                for route in self.routes.iter() {
+                    route.send_oversize(record.clone(), stub.clone(), ack, processing);
+                }
+                continue;
+            }
+
+            for route in self.routes.iter() {
+                route.send(record.clone(), ack, processing);
+            }
+        }
+    }
+
+    /// Keeps the record according to the policy while unavailable, routing it otherwise.
+    fn hold(&mut self, record: Record) {
+        const BATCH: usize = 256;
+
+        if !self.unavailable {
+            return self.route(vec![record], None, 0);
+        }
+
+        match self.policy {
+            Some(Unavailable::Drop) => self.dropped.inc(),
+            Some(Unavailable::Spill(..)) => {
+                self.held.push(record);
+                if self.held.len() >= BATCH {
+                    self.spill();
+                }
+            }
+            Some(Unavailable::Block) | None => self.held.push(record),
+        }
+    }
+
+    /// Moves records held in memory to the spool.
+    fn spill(&mut self) {
+        if self.held.is_empty() {
+            return;
+        }
+
+        if let Some(Unavailable::Spill(ref mut spool)) = self.policy {
+            match spool.push(&self.held) {
+                Ok(dropped) => {
+                    debug!(target: "Pipeline", "spooled {} records", self.held.len());
+                    if dropped > 0 {
+                        warn!(target: "Pipeline", "spool is full, dropped {} oldest records", dropped);
+                        self.dropped.add(dropped);
+                    }
+                }
+                Err(err) => {
+                    error!(target: "Pipeline", "dropping {} records: failed to spool: {}", self.held.len(), err);
+                    self.dropped.add(self.held.len());
+                }
+            }
+            self.held.clear();
+        }
+    }
+
+    /// Sends spooled and held records, oldest first, to every output like any other record, once
+    /// any of them is healthy.
+    fn replay(&mut self) {
+        if self.healthy().is_none() {
+            return;
+        }
+
+        loop {
+            let records = match self.policy {
+                Some(Unavailable::Spill(ref mut spool)) => {
+                    let records = match spool.peek() {
+                        Some(Ok(records)) => {
+                            info!(target: "Pipeline", "replaying {} spooled records", records.len());
+                            records
+                        }
+                        Some(Err(err)) => {
+                            error!(target: "Pipeline", "dropping spooled records: {}", err);
+                            Vec::new()
+                        }
+                        None => break,
+                    };
+                    spool.pop();
+                    records
+                }
+                _ => break,
+            };
+            self.route(records, None, 0);
+        }
+
+        let held = mem::replace(&mut self.held, Vec::new());
+        self.route(held, None, 0);
+    }
+}
+
+/// Routes records from inputs through the validation stage and the filter chain to outputs.
+///
+/// Records from the same input connection keep their relative order within each output: a
+/// connection is decoded sequentially by a single thread, records are dispatched by the single
+/// loop in `run` and every output is fed by its own worker through a FIFO channel, elastic or
+/// not. Filters and output failures may drop records, but never reorder them. Outputs reordering
+/// records themselves are refused by `PipelineBuilder::build` unless the order is waived.
+pub struct Pipeline {
+    validation: Arc<Validation>,
+    flush_interval: u32,
+    inputs: Vec<(Arc<Origin>, Box<Input>, Box<Codec>)>,
+    filters: Vec<Box<Filter>>,
+    /// Names filters are traced under in the lineage, if they are.
+    traced: Vec<Option<String>>,
+    outputs: Vec<(Box<Output>, Delivery, Option<OnError>)>,
+    /// Positions of outputs confirming records to inputs, if not all of them.
+    acknowledging: Option<Vec<usize>>,
+    dead_letter: Option<Box<Output>>,
+    elastic: Option<queue::Settings>,
+    unavailable: Option<Unavailable>,
+    retention: Option<Retention>,
+    source: Option<Resolution>,
+    quota: Option<Quota>,
+    strict: bool,
+    oversize: Option<oversize::Settings>,
+    transforms: Option<(bool, Option<Box<Output>>)>,
+    slow_consumer: Option<SlowConsumer>,
+    lineage: Option<lineage::Settings>,
+    breaker: Option<breaker::Settings>,
+    snapshot: Option<snapshot::Settings>,
+    alerts: Option<alert::Settings>,
+    /// Audit entries to send through filters to outputs once it runs.
+    audit: Option<Receiver<Record>>,
+    poison: Option<poison::Settings>,
+    scheduling: Option<sched::Settings>,
+    deterministic: Option<determinism::Settings>,
+    /// Whether the startup record is sent through the pipeline once it runs.
+    startup: bool,
+    warm_up: WarmUp,
+    /// Prefix of names of threads spawned once it runs, if not the default one.
+    thread_prefix: Option<String>,
+    handle: Option<Arc<Mutex<Option<Runtime>>>>,
+}
+
+impl Pipeline {
+    /// Creates an empty pipeline judging records by the given default validation rules.
+    ///
+    /// Deprecated for assembling pipelines in favour of `PipelineBuilder`, which enforces the
+    /// stage order and checks components against each other. Kept working until the next release.
+    pub fn new(validation: Validation) -> Pipeline {
+        Pipeline {
+            validation: Arc::new(validation),
+            flush_interval: 1000,
+            inputs: Vec::new(),
+            filters: Vec::new(),
+            traced: Vec::new(),
+            outputs: Vec::new(),
+            acknowledging: None,
+            dead_letter: None,
+            elastic: None,
+            unavailable: None,
+            retention: None,
+            source: None,
+            quota: None,
+            strict: false,
+            oversize: None,
+            transforms: None,
+            slow_consumer: None,
+            lineage: None,
+            breaker: None,
+            snapshot: None,
+            alerts: None,
+            audit: None,
+            poison: None,
+            scheduling: None,
+            deterministic: None,
+            startup: false,
+            warm_up: warmup::process(),
+            thread_prefix: None,
+            handle: None,
+        }
+    }
+
+    /// Adds a named input, optionally overriding the default validation rules for its records.
+    pub fn add_input(&mut self, name: &str, input: Box<Input>, codec: Box<Codec>, validation: Option<Validation>) {
+        let validation = match validation {
+            Some(validation) => Arc::new(validation),
+            None => self.validation.clone(),
+        };
+
+        let origin = Origin {
+            input: name.to_string(),
+            validation: validation,
+            capture: None,
+            projection: None,
+            encoding: None,
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+
+        self.inputs.push((Arc::new(origin), input, codec));
+    }
+
+    /// Makes the named input accept on the inherited listening socket instead of binding its own.
+    pub fn inherit(&mut self, name: &str, fd: RawFd) -> Result<(), String> {
+        match self.inputs.iter().find(|&&(ref origin, _, _)| origin.input == name) {
+            Some(&(_, ref input, _)) => input.inherit(fd),
+            None => Err(format!("no '{}' input", name)),
+        }
+    }
+
+    /// Enables raw payload capture for the named input.
+    pub fn set_capture(&mut self, input: &str, capture: Capture) {
+        let capture = Arc::new(capture);
+        for &mut (ref mut origin, _, _) in self.inputs.iter_mut() {
+            if origin.input == input {
+                *origin = Arc::new(Origin {
+                    input: origin.input.clone(),
+                    validation: origin.validation.clone(),
+                    capture: Some(capture.clone()),
+                    projection: origin.projection.clone(),
+                    encoding: origin.encoding.clone(),
+                    non_finite: origin.non_finite,
+                    decode_errors: origin.decode_errors,
+                });
+            }
+        }
+    }
+
+    /// Makes the named input decode only the top-level fields the projection keeps.
+    pub fn set_projection(&mut self, input: &str, projection: Projection) {
+        let projection = Arc::new(projection);
+        for &mut (ref mut origin, _, _) in self.inputs.iter_mut() {
+            if origin.input == input {
+                *origin = Arc::new(Origin {
+                    input: origin.input.clone(),
+                    validation: origin.validation.clone(),
+                    capture: origin.capture.clone(),
+                    projection: Some(projection.clone()),
+                    encoding: origin.encoding.clone(),
+                    non_finite: origin.non_finite,
+                    decode_errors: origin.decode_errors,
+                });
+            }
+        }
+    }
+
+    /// Makes the named input transcode its stream from the encoding into UTF-8 before decoding.
+    pub fn set_encoding(&mut self, input: &str, encoding: Encoding) {
+        let encoding = Arc::new(encoding);
+        for &mut (ref mut origin, _, _) in self.inputs.iter_mut() {
+            if origin.input == input {
+                *origin = Arc::new(Origin {
+                    input: origin.input.clone(),
+                    validation: origin.validation.clone(),
+                    capture: origin.capture.clone(),
+                    projection: origin.projection.clone(),
+                    encoding: Some(encoding.clone()),
+                    non_finite: origin.non_finite,
+                    decode_errors: origin.decode_errors,
+                });
+            }
+        }
+    }
+
+    /// Sets what the named input does with NaN and infinite floats of its records.
+    pub fn set_non_finite(&mut self, input: &str, non_finite: NonFinite) {
+        for &mut (ref mut origin, _, _) in self.inputs.iter_mut() {
+            if origin.input == input {
+                *origin = Arc::new(Origin {
+                    input: origin.input.clone(),
+                    validation: origin.validation.clone(),
+                    capture: origin.capture.clone(),
+                    projection: origin.projection.clone(),
+                    encoding: origin.encoding.clone(),
+                    non_finite: non_finite,
+                    decode_errors: origin.decode_errors,
+                });
+            }
+        }
+    }
+
+    /// Makes the named input emit records reporting values failing to decode, keeping at most the
+    /// limit of their bytes, instead of only logging the failures.
+    pub fn set_decode_errors(&mut self, input: &str, limit: usize) {
+        for &mut (ref mut origin, _, _) in self.inputs.iter_mut() {
+            if origin.input == input {
+                *origin = Arc::new(Origin {
+                    input: origin.input.clone(),
+                    validation: origin.validation.clone(),
+                    capture: origin.capture.clone(),
+                    projection: origin.projection.clone(),
+                    encoding: origin.encoding.clone(),
+                    non_finite: origin.non_finite,
+                    decode_errors: Some(limit),
+                });
+            }
+        }
+    }
+
+    /// Makes every input without its own projection decode only the fields read by the pipeline:
+    /// by validation rules, the source resolution, filters and outputs.
+    ///
+    /// Fields are collected once the pipeline runs, so components added on reload must not read
+    /// anything new. A component able to read any field rules the projection out.
+    pub fn set_strict_projection(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn add_filter(&mut self, filter: Box<Filter>) {
+        self.filters.push(filter);
+        self.traced.push(None);
+    }
+
+    /// Adds a filter recording what it changes about records in their lineage under the given
+    /// name, see `Pipeline::set_lineage`.
+    pub fn add_traced_filter(&mut self, filter: Box<Filter>, name: &str) {
+        self.filters.push(filter);
+        self.traced.push(Some(name.to_string()));
+    }
+
+    /// Adds an output with at-least-once delivery.
+    pub fn add_output(&mut self, output: Box<Output>) {
+        self.add_output_with_delivery(output, Delivery::AtLeastOnce);
+    }
+
+    pub fn add_output_with_delivery(&mut self, output: Box<Output>, delivery: Delivery) {
+        self.outputs.push((output, delivery, None));
+    }
+
+    /// Adds an output applying the policy to records it fails to accept or panics on, instead of
+    /// retrying and dropping them.
+    ///
+    /// The policy is told by the `output.<id>.on_error.<policy>` metric being 1, while the other
+    /// policies are 0 across reloads. Failures and panics are counted in `output.<id>.errors` and
+    /// `output.<id>.panics`.
+    pub fn add_guarded_output(&mut self, output: Box<Output>, delivery: Delivery, on_error: OnError) {
+        self.outputs.push((output, delivery, Some(on_error)));
+    }
+
+    /// Makes only outputs at the given positions confirm records inputs send with a token, rather
+    /// than all of them, see `ack`.
+    pub fn set_acknowledging(&mut self, outputs: Vec<usize>) {
+        self.acknowledging = Some(outputs);
+    }
+
+    /// Sets the interval in milliseconds between periodic output flushes.
+    pub fn set_flush_interval(&mut self, interval: u32) {
+        self.flush_interval = interval;
+    }
+
+    /// Sets the output receiving records rejected by the validation stage.
+    pub fn set_dead_letter(&mut self, output: Box<Output>) {
+        self.dead_letter = Some(output);
+    }
+
+    /// Captures what the filter chain changes about each record, attaching the diff to changed
+    /// records as the `_transforms` field if `attach` is set and sending `record_diff` records
+    /// with it to the audit output, if any.
+    ///
+    /// Records emitted by filters on flush are not diffed, as there is nothing to compare them to.
+    pub fn set_transforms(&mut self, attach: bool, output: Option<Box<Output>>) {
+        self.transforms = Some((attach, output));
+    }
+
+    /// Warns when an output falls behind, i.e. when more than `high` records sent to it are not
+    /// handled yet, repeating the warning at most every `interval` milliseconds while it lasts and
+    /// telling once the output catches up.
+    ///
+    /// Backlogs are sampled at the flush interval. Warnings and recoveries are counted in the
+    /// `output.<id>.backlog.warnings` and `output.<id>.backlog.recoveries` metrics.
+    pub fn set_slow_consumer(&mut self, high: usize, interval: u64) {
+        self.slow_consumer = Some(SlowConsumer { high: high, interval: interval });
+    }
+
+    /// Guards every output by the circuit breaker, which stops feeding an output failing or slowing
+    /// down past the thresholds for a cool-down, so that its worker doesn't spend it in retries
+    /// and timeouts.
+    ///
+    /// Records reaching an output while its breaker is open are diverted to the dead-letter output
+    /// if there is one and dropped otherwise, counted in the `output.<id>.breaker.diverted` metric.
+    /// The output counts as unavailable meanwhile, so with the spill policy set the spool takes
+    /// records once no output is left, to be replayed when a probe closes the breaker again.
+    pub fn set_breaker(&mut self, settings: breaker::Settings) {
+        self.breaker = Some(settings);
+    }
+
+    /// Carries soft state of filters and outputs over restarts, e.g. deduplication windows and
+    /// records held back by filters, in the snapshot taken at shutdown and restored at start.
+    ///
+    /// Components are matched by their position in the chain or among outputs, so reordering them
+    /// loses their state. Records held back by snapshotted filters move into the snapshot instead
+    /// of being passed on at shutdown.
+    pub fn set_snapshot(&mut self, settings: snapshot::Settings) {
+        self.snapshot = Some(settings);
+    }
+
+    /// Evaluates alert rules over metrics at the configured interval while running, logging rules
+    /// firing and resolving and sending records announcing them through filters to outputs.
+    pub fn set_alerts(&mut self, settings: alert::Settings) {
+        self.alerts = Some(settings);
+    }
+
+    /// Sends records of audit entries appended from now on through filters to outputs once it
+    /// runs, for central collection, see `audit::record`.
+    pub fn set_audit_records(&mut self) {
+        self.audit = Some(audit::subscribe());
+    }
+
+    /// Quarantines records that guarded filters and outputs panic on too many times, sending
+    /// records announcing them through filters to outputs, see `poison`.
+    ///
+    /// Only panics caught by the `on_error` policy are told, so components without one are not
+    /// watched.
+    pub fn set_poison(&mut self, settings: poison::Settings) {
+        self.poison = Some(settings);
+    }
+
+    /// Schedules threads decoding inputs and feeding outputs apart from control ones, lowering
+    /// their priority, pinning them to CPUs and pausing decoding while the process is over the
+    /// CPU budget, see `sched::Scheduler`.
+    pub fn set_scheduling(&mut self, settings: sched::Settings) {
+        self.scheduling = Some(settings);
+    }
+
+    /// Makes the same records produce the same output on every run.
+    ///
+    /// Components draw random numbers from seeds derived from the given one, the oversize path and
+    /// archive captures stamp ids from a fixed epoch, outputs are told to sort object keys,
+    /// and output breakers follow the logical clock driven by record timestamps. Filters are
+    /// flushed only once inputs are finished rather than at the flush interval, so that records
+    /// they hold back come out at the same place. Records of different inputs still interleave in
+    /// the order they arrive in.
+    ///
+    /// Components tied to the wall clock are refused by the config, see `determinism`.
+    pub fn set_deterministic(&mut self, settings: determinism::Settings) {
+        self.deterministic = Some(settings);
+    }
+
+    /// Records which filters touched which fields of records in the `_lineage` array, together
+    /// with the quota marking and the oversize stamping.
+    ///
+    /// Filters added with `add_traced_filter` are traced whether the lineage is set or not, the
+    /// default limits applying then. Outputs not meant to see the lineage are wrapped in
+    /// `lineage::Strip`.
+    pub fn set_lineage(&mut self, settings: lineage::Settings) {
+        self.lineage = Some(settings);
+    }
+
+    /// Makes the main channel and output channels elastic, spilling records to the disk while
+    /// filters or an output fall behind.
+    ///
+    /// Records from inputs are relayed into the elastic main queue as they come, keeping their
+    /// tokens in memory while spilled, so that inputs are never blocked by the dispatch loop.
+    pub fn set_elastic(&mut self, settings: queue::Settings) {
+        self.elastic = Some(settings);
+    }
+
+    /// Makes records estimated to take more than the threshold bypass output channels.
+    ///
+    /// Outputs accepting such records intact receive them through dedicated channels of low
+    /// depth, so they take no room in the usual ones, and flush them one by one, while ones
+    /// overflowing the depth go through the usual channels. Other outputs receive stubs made of
+    /// the canonical fields, the reference id and the original size.
+    ///
+    /// Oversize records keep no order relative to the other ones.
+    pub fn set_oversize(&mut self, settings: oversize::Settings) {
+        self.oversize = Some(settings);
+    }
+
+    /// Sets what happens to records while every output is unavailable.
+    ///
+    /// Without the policy records keep being routed to outputs, which drop them once retries are
+    /// exhausted.
+    pub fn set_unavailable(&mut self, policy: Unavailable) {
+        self.unavailable = Some(policy);
+    }
+
+    /// Keeps the last records leaving the filter chain in the in-memory ring.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = Some(retention);
+    }
+
+    /// Derives the source of every accepted record by the given policy, stamping its canonical
+    /// key into the `_source` field and counting records and decoded bytes per source in the
+    /// `pipeline.source.<key>.records` and `pipeline.source.<key>.bytes` metrics, up to the
+    /// number of sources the policy meters, see `source::Meter`.
+    ///
+    /// Source-keyed components look the key up with `source::key`, so all of them agree.
+    pub fn set_source(&mut self, resolution: Resolution) {
+        self.source = Some(resolution);
+    }
+
+    /// Enforces quotas on decoded bytes per source key, the input name standing for records
+    /// without one.
+    ///
+    /// Records over the soft quota are marked with the `over_quota` field and the first of them
+    /// within the window is preceded by the `quota_notification` record. Records over the hard
+    /// quota are dropped, closing connections they arrive on if the quota says so.
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = Some(quota);
+    }
+
+    /// Returns top-level fields read past the validation stage, or the component able to read any
+    /// field.
+    pub fn describe_fields(&self) -> Result<HashSet<String>, String> {
+        let mut fields = HashSet::new();
+        for filter in self.filters.iter() {
+            match filter.describe_fields() {
+                Some(names) => fields.extend(names.into_iter()),
+                None => return Err(format!("'{}' filter", filter.typename())),
+            }
+        }
+
+        let outputs = self.outputs.iter().map(|&(ref output, _, _)| output).chain(self.dead_letter.iter());
+        for output in outputs {
+            match output.describe_fields() {
+                Some(names) => fields.extend(names.into_iter()),
+                None => return Err(format!("'{}' output", output.typename())),
+            }
+        }
+
+        if let Some(ref resolution) = self.source {
+            fields.insert(resolution.field_name().to_string());
+        }
+
+        if self.quota.is_some() {
+            fields.extend(source::fields().into_iter());
+        }
+
+        Ok(fields)
+    }
+
+    /// Makes the pipeline send the record announcing the start of the process, its version and
+    /// components through filters to outputs once it runs, see `version::startup`.
+    pub fn set_startup_record(&mut self, enabled: bool) {
+        self.startup = enabled;
+    }
+
+    /// Returns the names of inputs together with the types of filters and outputs.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            inputs: self.inputs.iter().map(|&(ref origin, _, _)| origin.input.clone()).collect(),
+            filters: self.filters.iter().map(|filter| filter.typename().to_string()).collect(),
+            outputs: self.outputs.iter().map(|&(ref output, _, _)| output.typename().to_string()).collect(),
+        }
+    }
+
+    /// Sets the duration of the warm-up phase following the start in milliseconds.
+    pub fn set_warm_up(&mut self, duration: u64) {
+        self.warm_up.set_duration(duration);
+    }
+
+    /// Sets the prefix of names of threads spawned by the process, see `naming::spawn`.
+    pub fn set_thread_prefix(&mut self, prefix: &str) {
+        self.thread_prefix = Some(prefix.to_string());
+    }
+
+    /// Returns the warm-up phase started once the pipeline runs, for components opting in to
+    /// behave more conservatively meanwhile.
+    pub fn warm_up(&self) -> WarmUp {
+        self.warm_up.clone()
+    }
+
+    /// Returns the handle of the retention ring, if any, for querying it while running.
+    pub fn retention(&self) -> Option<Retention> {
+        self.retention.clone()
+    }
+
+    /// Returns the handle for reloading the pipeline while it runs.
+    ///
+    /// A reloadable pipeline keeps running after all its inputs are finished, until the handle is
+    /// closed.
+    pub fn handle(&mut self) -> Handle {
+        let runtime = Arc::new(Mutex::new(None));
+        self.handle = Some(runtime.clone());
+
+        Handle {
+            runtime: runtime,
+        }
+    }
+
+    /// Runs the pipeline until all inputs are finished.
+    pub fn run(self) {
+        let (tx, rx) = channel();
+        self.warm_up.start();
+        if let Some(ref prefix) = self.thread_prefix {
+            naming::set_prefix(prefix);
+        }
+
+        let inbox = match self.elastic {
+            Some(ref settings) => Inbox::Elastic(relay(rx, settings)),
+            None => Inbox::Direct(rx),
+        };
+
+        let fields = if self.strict {
+            match self.describe_fields() {
+                Ok(fields) => Some(fields),
+                Err(component) => {
+                    warn!(target: "Pipeline", "decoding all fields, as {} may read any of them", component);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let startup = if self.startup { Some(version::startup(&self.summary())) } else { None };
+
+        let reloadable = self.handle.is_some();
+        let slot = self.handle.unwrap_or_else(|| Arc::new(Mutex::new(None)));
+
+        let store = self.snapshot.map(|settings| Arc::new(Mutex::new(Store::open(settings))));
+        let scheduler = self.scheduling.map(|settings| Arc::new(Scheduler::system(settings)));
+
+        let deterministic = self.deterministic;
+        let logical = deterministic.as_ref().map(|_| LogicalClock::new());
+        let seed = |component: &str| deterministic.as_ref().map(|settings| determinism::seed(settings.seed, component));
+        if let Some(ref settings) = deterministic {
+            warn!(target: "Pipeline", "running in DETERMINISTIC mode with seed {}: filters are flushed only at shutdown and \
+                time follows record timestamps", settings.seed);
+        }
+
+        let (poisoned, poison) = match self.poison {
+            Some(settings) => {
+                let (tx, rx) = channel();
+                let clock: Arc<Clock> = match logical {
+                    Some(ref clock) => Arc::new(clock.clone()),
+                    None => Arc::new(SystemClock),
+                };
+                (Some(rx), Some(Arc::new(Detector::with_clock(settings, tx, clock))))
+            }
+            None => (None, None),
+        };
+
+        let mut filters = self.filters;
+        for (id, filter) in filters.iter_mut().enumerate() {
+            if let Some(seed) = seed(&format!("filter.{}", id)) {
+                filter.reseed(seed);
+            }
+        }
+
+        let lineage = self.lineage.map(Lineage::new);
+        let mut chain = chain(filters, self.traced, lineage.clone());
+        if let Some(ref poison) = poison {
+            chain.watch(poison.clone());
+        }
+        let held = match store {
+            Some(ref store) => chain.restore(&mut store.lock().unwrap()),
+            None => Vec::new(),
+        };
+        let mut dispatch = Dispatch::new(chain, Vec::new(), Vec::new(), self.unavailable, self.retention);
+        dispatch.oversize = self.oversize.clone().map(|settings| {
+            match deterministic {
+                Some(..) => Oversize::with_epoch(settings, 0),
+                None => Oversize::new(settings),
+            }
+        });
+        dispatch.slow_consumer = self.slow_consumer;
+        dispatch.lineage = lineage.clone();
+        let (paused, stopping) = (dispatch.paused.clone(), dispatch.stopping.clone());
+        let dispatch = Arc::new(Mutex::new(dispatch));
+        let tickable = Arc::new(Mutex::new(Vec::new()));
+
+        let mut runtime = Runtime {
+            tx: if reloadable { Some(tx.clone()) } else { None },
+            dispatch: dispatch.clone(),
+            ids: Vec::new(),
+            next: 0,
+            tickable: tickable.clone(),
+            workers: Vec::new(),
+            inputs: Vec::new(),
+            elastic: self.elastic,
+            oversize: self.oversize.map(|settings| settings.depth),
+            quota: self.quota.clone(),
+            paused: paused,
+            stopping: stopping,
+            validation: self.validation.clone(),
+            flush_interval: self.flush_interval,
+            breaker: self.breaker,
+            dead_letter: None,
+            snapshot: store.clone(),
+            alerting: None,
+            poison: poison,
+            scheduler: scheduler.clone(),
+            clock: match logical {
+                Some(ref clock) => Arc::new(clock.clone()),
+                None => Arc::new(SystemClock),
+            },
+        };
+
+        let dead_letter = self.dead_letter.map(|mut output| {
+            if let Some(seed) = seed("dead_letter") {
+                output.reseed(seed);
+            }
+            if let Some(ref clock) = logical {
+                output.set_clock(Arc::new(clock.clone()));
+            }
+            if deterministic.is_some() {
+                output.sort_keys();
+            }
+            let mut worker = Worker::new("dead_letter", output, Delivery::AtLeastOnce);
+            worker.scheduler = scheduler.clone();
+            let (tx, worker) = spawn(worker);
+            runtime.workers.push(worker);
+            tickable.lock().unwrap().push((DEAD_LETTER, tx.clone()));
+            tx
+        });
+        runtime.dead_letter = dead_letter.clone();
+        dispatch.lock().unwrap().dead_letter = dead_letter.clone();
+
+        for (id, (mut output, delivery, on_error)) in self.outputs.into_iter().enumerate() {
+            if let Some(seed) = seed(&format!("output.{}", id)) {
+                output.reseed(seed);
+            }
+            if let Some(ref clock) = logical {
+                output.set_clock(Arc::new(clock.clone()));
+            }
+            if deterministic.is_some() {
+                output.sort_keys();
+            }
+            if let Some(ref store) = store {
+                let typename = output.typename();
+                if let Some(state) = output.state() {
+                    store.lock().unwrap().restore(&format!("output.{}", id), typename, state);
+                }
+            }
+
+            let acked = acknowledged(&self.acknowledging, id);
+            let (id, mut route, healthy) = runtime.start(output, delivery, on_error);
+            route.acked = acked;
+            runtime.ids.push(id);
+
+            let mut dispatch = dispatch.lock().unwrap();
+            dispatch.routes.push(route);
+            dispatch.health.push(healthy);
+        }
+
+        if let Some((attach, output)) = self.transforms {
+            let output = output.map(|mut output| {
+                if let Some(seed) = seed("transforms") {
+                    output.reseed(seed);
+                }
+                if let Some(ref clock) = logical {
+                    output.set_clock(Arc::new(clock.clone()));
+                }
+                if deterministic.is_some() {
+                    output.sort_keys();
+                }
+                let mut worker = Worker::new("transforms", output, Delivery::AtLeastOnce);
+                worker.scheduler = scheduler.clone();
+                let (tx, worker) = spawn(worker);
+                runtime.workers.push(worker);
+                tickable.lock().unwrap().push((TRANSFORMS, tx.clone()));
+                tx
+            });
+
+            dispatch.lock().unwrap().transforms = Some(Transforms {
+                attach: attach,
+                output: output,
+            });
+        }
+
+        if let Some(record) = startup {
+            dispatch.lock().unwrap().apply(record);
+        }
+        // Records held in skipped states of filters have been through them already.
+        if !held.is_empty() {
+            dispatch.lock().unwrap().send(held, None, 0);
+        }
+
+        for (origin, mut input, codec) in self.inputs.into_iter() {
+            if let Some(seed) = seed(&format!("input.{}", origin.input)) {
+                input.reseed(seed);
+                if let Some(ref capture) = origin.capture {
+                    capture.set_epoch(0);
+                }
+            }
+
+            let origin = match fields {
+                Some(ref fields) if origin.projection.is_none() => strict(origin, fields),
+                _ => origin,
+            };
+
+            trace!(target: "Pipeline", "starting '{}' input", input.typename());
+            runtime.launch(Sink::new(tx.clone(), origin), input, codec);
+        }
+        drop(tx);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let alerts = self.alerts.map(|settings| {
+            let alerting = Arc::new(Mutex::new(Alerting::new(settings.rules)));
+            runtime.alerting = Some(alerting.clone());
+            alert(dispatch.clone(), alerting, settings.interval, running.clone())
+        });
+        let audited = self.audit.map(|rx| audited(dispatch.clone(), rx, running.clone()));
+        let poisoned = poisoned.map(|rx| audited(dispatch.clone(), rx, running.clone()));
+
+        *slot.lock().unwrap() = Some(runtime);
+
+        let ticker = ticker(dispatch.clone(), tickable.clone(), self.flush_interval, deterministic.is_none(), running.clone());
+
+        let dropped = metrics::registry().counter("pipeline.validation.dropped");
+        let mut meter = self.source.as_ref().map(|resolution| resolution.meter());
+
+        while let Some(Envelope { record, origin, peer, rejected, ack }) = inbox.recv() {
+            let processing = processing_id();
+            trace!(target: "Pipeline", "processing {} from '{}' input: {:?}", processing, origin.input, record);
+
+            let verdict = match rejected {
+                Some(reason) => Verdict::DeadLetter(record, reason),
+                None => origin.validation.check(record),
+            };
+            let record = match verdict {
+                Verdict::Accept(record) => {
+                    if let Some(ref clock) = logical {
+                        clock.observe(&record);
+                    }
+                    record
+                }
+                Verdict::Drop(reason) => {
+                    warn!(target: "Pipeline", "dropping record from '{}' input: {}", origin.input, reason);
+                    dropped.inc();
+                    confirm(ack);
+                    continue;
+                }
+                Verdict::DeadLetter(mut record, reason) => {
+                    match dead_letter {
+                        Some(ref tx) => {
+                            record.insert("_dead_letter_reason".to_string(), RecordItem::String(reason));
+                            record.insert("_dead_letter_stage".to_string(), RecordItem::String("input".to_string()));
+                            tx.send(Event::Record(record, ack, processing)).unwrap();
+                        }
+                        None => {
+                            warn!(target: "Pipeline", "dropping record from '{}' input: {}, no dead-letter output configured", origin.input, reason);
+                            dropped.inc();
+                            confirm(ack);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let record = match self.source {
+                Some(ref resolution) => {
+                    let mut record = record;
+                    let source = resolution.resolve(&origin.input, peer.as_ref().map(|peer| &peer[..]), &record);
+                    if let Some(ref mut meter) = meter {
+                        meter.count(source.key(), record.size_hint());
+                    }
+                    record.insert(source::FIELD.to_string(), RecordItem::String(source.key().to_string()));
+                    record
+                }
+                None => record,
+            };
+
+            let record = match self.quota {
+                Some(ref quota) => {
+                    let key = source::key(&record).unwrap_or(&origin.input[..]).to_string();
+                    match quota.charge(&key, peer.as_ref().map(|peer| &peer[..]), record.size_hint() as u64) {
+                        quota::Verdict::Pass => record,
+                        quota::Verdict::Over(notification) => {
+                            if let Some(notification) = notification {
+                                wait(&dispatch);
+                                dispatch.lock().unwrap().apply(notification);
+                            }
+                            let mut record = record;
+                            let previous = record.insert(quota::FIELD.to_string(), RecordItem::Bool(true));
+                            if let (Some(lineage), None) = (lineage.as_ref(), previous) {
+                                let mark = diff::Entry {
+                                    path: vec![quota::FIELD.to_string()],
+                                    change: diff::Change::Added(RecordItem::Bool(true)),
+                                };
+                                lineage.note("quota", &[mark], &mut record);
+                            }
+                            record
+                        }
+                        quota::Verdict::Exceeded => {
+                            confirm(ack);
+                            continue;
+                        }
+                    }
+                }
+                None => record,
+            };
+
+            wait(&dispatch);
+            dispatch.lock().unwrap().apply_acked(record, ack, processing);
+        }
+
+        running.store(false, Ordering::SeqCst);
+        ticker.join().unwrap();
+        if let Some(alerts) = alerts {
+            alerts.join().unwrap();
+        }
+        if let Some(audited) = audited {
+            audited.join().unwrap();
+        }
+        if let Some(poisoned) = poisoned {
+            poisoned.join().unwrap();
+        }
+
+        if let Some(ref quota) = self.quota {
+            if let Err(err) = quota.save() {
+                warn!(target: "Pipeline", "unable to save quota usage: {}", err);
+            }
+        }
+
+        let mut runtime = slot.lock().unwrap().take().unwrap();
+
+        let mut dispatch = dispatch.lock().unwrap();
+        if let Some(ref store) = store {
+            dispatch.chain.keep(&mut store.lock().unwrap());
+        }
+        dispatch.close();
+        if dispatch.held.len() > 0 {
+            warn!(target: "Pipeline", "dropping {} records held while outputs are unavailable", dispatch.held.len());
+        }
+        dispatch.routes.clear();
+        dispatch.transforms = None;
+        drop(dispatch);
+        drop(dead_letter);
+        drop(runtime.dead_letter.take());
+        tickable.lock().unwrap().clear();
+        for worker in runtime.workers.into_iter() {
+            worker.join().unwrap();
+        }
+
+        if let Some(ref store) = store {
+            if let Err(err) = store.lock().unwrap().save() {
+                warn!(target: "Pipeline", "unable to save snapshot: {}", err);
+            }
+        }
+
+        info!(target: "Pipeline", "all inputs are finished");
+    }
+}
+
+/// Returns the next processing id, following a record ingested from an input in trace logs
+/// through the filter chain to output workers.
+///
+/// Ids are unique within the process, across pipelines and reloads. Records the pipeline makes
+/// itself, e.g. ones filters emit on flush, are logged with zero.
+fn processing_id() -> u64 {
+    static PROCESSING: AtomicUsize = ATOMIC_USIZE_INIT;
+    PROCESSING.fetch_add(1, Ordering::Relaxed) as u64 + 1
+}
+
+/// Returns whether the output at the given position confirms records to inputs.
+fn acknowledged(acknowledging: &Option<Vec<usize>>, position: usize) -> bool {
+    acknowledging.as_ref().map_or(true, |outputs| outputs.contains(&position))
+}
+
+/// Confirms the record the pipeline is done with without any output, e.g. as it's dropped on
+/// purpose, since delivering it again would change nothing.
+fn confirm(ack: Option<Ack>) {
+    if let Some(ack) = ack {
+        ack.confirm();
+    }
+}
+
+/// Returns the origin decoding only the given fields together with ones its validation requires.
+fn strict(origin: Arc<Origin>, fields: &HashSet<String>) -> Arc<Origin> {
+    let mut fields = fields.clone();
+    fields.extend(origin.validation.required.iter().cloned());
+
+    {
+        let mut names: Vec<&String> = fields.iter().collect();
+        names.sort();
+        info!(target: "Pipeline", "decoding only {:?} fields of '{}' input", names, origin.input);
+    }
+
+    Arc::new(Origin {
+        input: origin.input.clone(),
+        validation: origin.validation.clone(),
+        capture: origin.capture.clone(),
+        projection: Some(Arc::new(Projection::Allow(fields))),
+        encoding: origin.encoding.clone(),
+        non_finite: origin.non_finite,
+        decode_errors: origin.decode_errors,
+    })
+}
+
+/// Output worker identifier reserved for the dead-letter output.
+const DEAD_LETTER: usize = !0;
+
+/// Output worker identifier reserved for the audit output of record diffs.
+const TRANSFORMS: usize = !0 - 1;
+
+/// Parts of the running pipeline changed by reloads.
+struct Runtime {
+    /// Keeps the pipeline channel open for inputs started by reloads, until the handle is closed.
+    tx: Option<Sender<Envelope>>,
+    dispatch: Arc<Mutex<Dispatch>>,
+    /// Output worker identifiers in route order.
+    ids: Vec<usize>,
+    next: usize,
+    /// Channels of workers not draining elastic queues, flushed by the ticker.
+    tickable: Arc<Mutex<Vec<(usize, Sender<Event>)>>>,
+    workers: Vec<JoinHandle<()>>,
+    /// Running inputs by name.
+    inputs: Vec<(String, Arc<Box<Input>>)>,
+    elastic: Option<queue::Settings>,
+    /// Depth of oversize channels, if the oversize path is enabled.
+    oversize: Option<usize>,
+    /// Quota blocking connections of inputs, if any.
+    quota: Option<Quota>,
+    /// Flag pausing inputs while the dispatch refuses records.
+    paused: Arc<AtomicBool>,
+    /// Flag telling the dispatch to stop refusing records, set once stopped.
+    stopping: Arc<AtomicBool>,
+    /// Default validation rules, applied to records of injected inputs.
+    validation: Arc<Validation>,
+    flush_interval: u32,
+    /// Circuit breaker settings of outputs, if they are guarded.
+    breaker: Option<breaker::Settings>,
+    /// Channel of the dead-letter output, taking records diverted by open breakers.
+    dead_letter: Option<Sender<Event>>,
+    /// Snapshot output states are taken into at shutdown, if enabled.
+    snapshot: Option<Arc<Mutex<Store>>>,
+    /// Alert rules evaluator, if any rules are set.
+    alerting: Option<Arc<Mutex<Alerting>>>,
+    /// Poison record detector watching filters and outputs, if enabled.
+    poison: Option<Arc<Detector>>,
+    /// Scheduler of input and output threads, if scheduling is set.
+    scheduler: Option<Arc<Scheduler>>,
+    /// Clock of output breakers, the logical one in the deterministic mode.
+    clock: Arc<Clock>,
+}
+
+impl Runtime {
+    fn launch(&mut self, tx: Sink, input: Box<Input>, codec: Box<Codec>) {
+        let tx = tx.with_pause(self.paused.clone());
+        let tx = match self.quota {
+            Some(ref quota) => tx.with_quota(quota.clone()),
+            None => tx,
+        };
+        let tx = match self.scheduler {
+            Some(ref scheduler) => tx.with_scheduler(scheduler.clone()),
+            None => tx,
+        };
+        let input = Arc::new(input);
+        let name = match input.label() {
+            Some(label) => format!("input-{}-{}", tx.origin().input, label),
+            None => format!("input-{}", tx.origin().input),
+        };
+        self.inputs.push((tx.origin().input.clone(), input.clone()));
+        naming::spawn(&name, move || {
+            input.run(tx, codec)
+        });
+    }
+
+    /// Spawns the worker feeding the output, returning its identifier, route and health flag.
+    fn start(&mut self, output: Box<Output>, delivery: Delivery, on_error: Option<OnError>)
+        -> (usize, Route, Arc<AtomicBool>)
+    {
+        let id = self.next;
+        self.next += 1;
+
+        let (side, oversize) = match self.oversize {
+            Some(depth) if output.oversize() => {
+                let (tx, rx) = sync_channel(depth);
+                (Some(tx), Some(rx))
+            }
+            _ => (None, None),
+        };
+
+        let mut worker = Worker::new(&id.to_string(), output, delivery);
+        worker.oversize = oversize;
+        if let Some(ref settings) = self.breaker {
+            let breaker = Breaker::new(&format!("output.{}", id), settings.clone(), self.clock.clone());
+            worker.guard(breaker, self.dead_letter.clone());
+        }
+        if let Some(on_error) = on_error {
+            worker.recover(&id.to_string(), on_error, self.dead_letter.clone());
+        }
+        worker.poison = self.poison.clone();
+        worker.snapshot = self.snapshot.clone().map(|store| (format!("output.{}", id), store));
+        worker.scheduler = self.scheduler.clone();
+        let healthy = worker.healthy.clone();
+        let status = worker.breaker.as_ref().map(|guard| guard.status());
+        let backlog = Backlog::new(id, worker.output.typename(), worker.handled.clone());
+        let channel = match self.elastic {
+            Some(ref settings) => {
+                let (tx, rx) = queue::elastic(settings, &format!("output-{}", id));
+                self.workers.push(spawn_elastic(worker, rx, self.flush_interval));
+                Channel::Elastic(tx)
+            }
+            None => {
+                let (tx, worker) = spawn(worker);
+                self.workers.push(worker);
+                self.tickable.lock().unwrap().push((id, tx.clone()));
+                Channel::Direct(tx)
+            }
+        };
+
+        let route = Route {
+            channel: channel,
+            oversize: side,
+            overflowed: metrics::registry().counter(&format!("output.{}.oversize.overflowed", id)),
+            backlog: backlog,
+            breaker: status,
+            delivery: delivery,
+            acked: true,
+        };
+
+        (id, route, healthy)
+    }
+}
+
+/// Changes applied to the running pipeline by a reload, see `Handle::reload`.
+pub struct Reload {
+    /// Pipeline built out of the new config, providing new components.
+    pub pipeline: Pipeline,
+    /// Names of the inputs to start.
+    pub inputs: Vec<String>,
+    /// For each output of the new pipeline, the position of the running output to keep instead.
+    pub outputs: Vec<Option<usize>>,
+    /// Whether to replace the filter chain.
+    pub filters: bool,
+    /// Changes taking effect only after restart.
+    pub restart: Vec<String>,
+}
+
+/// Handle reloading the running pipeline, see `Pipeline::handle`.
+#[derive(Clone)]
+pub struct Handle {
+    runtime: Arc<Mutex<Option<Runtime>>>,
+}
+
+impl Handle {
+    /// Returns circuit breaker states of outputs guarded by one, by output identifiers.
+    pub fn breakers(&self) -> Vec<(usize, breaker::State)> {
+        let slot = self.runtime.lock().unwrap();
+        let runtime = match *slot {
+            Some(ref runtime) => runtime,
+            None => return Vec::new(),
+        };
+
+        let dispatch = runtime.dispatch.lock().unwrap();
+        runtime.ids.iter().zip(dispatch.routes.iter()).filter_map(|(id, route)| {
+            route.breaker.as_ref().map(|status| (*id, status.get()))
+        }).collect()
+    }
+
+    /// Returns delivery guarantees of outputs by output identifiers.
+    pub fn deliveries(&self) -> Vec<(usize, Delivery)> {
+        let slot = self.runtime.lock().unwrap();
+        let runtime = match *slot {
+            Some(ref runtime) => runtime,
+            None => return Vec::new(),
+        };
+
+        let dispatch = runtime.dispatch.lock().unwrap();
+        runtime.ids.iter().zip(dispatch.routes.iter()).map(|(id, route)| (*id, route.delivery)).collect()
+    }
+
+    /// Returns alert states by rule name.
+    pub fn alerts(&self) -> Vec<(String, alert::State)> {
+        match *self.runtime.lock().unwrap() {
+            Some(Runtime { alerting: Some(ref alerting), .. }) => alerting.lock().unwrap().states(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Passes records held back by filters to outputs and asks outputs to flush, like it happens
+    /// at the flush interval. Outputs behind elastic channels flush on their own.
+    pub fn flush(&self) -> Result<(), String> {
+        let slot = self.runtime.lock().unwrap();
+        let runtime = match *slot {
+            Some(ref runtime) => runtime,
+            None => return Err("pipeline is not running".to_string()),
+        };
+
+        runtime.dispatch.lock().unwrap().flush();
+        for &(_, ref tx) in runtime.tickable.lock().unwrap().iter() {
+            let _ = tx.send(Event::Flush);
+        }
+
+        Ok(())
+    }
+
+    /// Applies the changes with minimal disruption.
+    ///
+    /// New inputs are started alongside the running ones. Outputs are rearranged in the new
+    /// order, spawning new ones and stopping the ones gone after they drain their channels.
+    /// States of filters are carried over to the new chain where they're of the same type at
+    /// the same position, while records held back by the old chain otherwise are passed to
+    /// outputs before the new chain replaces it, all without any record passing the dispatch meanwhile.
+    pub fn reload(&self, reload: Reload) -> Result<(), String> {
+        let mut slot = self.runtime.lock().unwrap();
+        let runtime = match *slot {
+            Some(ref mut runtime) => runtime,
+            None => return Err("pipeline is not running".to_string()),
+        };
+
+        let tx = match runtime.tx {
+            Some(ref tx) => tx.clone(),
+            None => return Err("pipeline is closed".to_string()),
+        };
+
+        let Reload { pipeline, inputs, outputs, filters, .. } = reload;
+        let Pipeline { inputs: candidates, filters: replacement, traced, outputs: replacements, acknowledging, .. } = pipeline;
+
+        for (origin, input, codec) in candidates.into_iter() {
+            if inputs.contains(&origin.input) {
+                info!(target: "Pipeline", "starting '{}' input", origin.input);
+                runtime.launch(Sink::new(tx.clone(), origin), input, codec);
+            }
+        }
+
+        let shared = runtime.dispatch.clone();
+        let mut dispatch = shared.lock().unwrap();
+        if filters {
+            let mut replacement = chain(replacement, traced, dispatch.lineage.clone());
+            if let Some(ref poison) = runtime.poison {
+                replacement.watch(poison.clone());
+            }
+            replacement.inherit(&mut dispatch.chain);
+            let records = dispatch.chain.close();
+            dispatch.send(records, None, 0);
+            dispatch.chain = replacement;
+        }
+
+        let routes = mem::replace(&mut dispatch.routes, Vec::new());
+        let health = mem::replace(&mut dispatch.health, Vec::new());
+        let ids = mem::replace(&mut runtime.ids, Vec::new());
+        let mut running: Vec<Option<(usize, Route, Arc<AtomicBool>)>> = ids.into_iter()
+            .zip(routes.into_iter().zip(health.into_iter()))
+            .map(|(id, (route, healthy))| Some((id, route, healthy)))
+            .collect();
+
+        let replacements = outputs.into_iter().zip(replacements.into_iter()).enumerate();
+        for (index, (position, (output, delivery, on_error))) in replacements {
+            let kept = match position {
+                Some(position) if position < running.len() => running[position].take(),
+                _ => None,
+            };
+
+            let (id, mut route, healthy) = match kept {
+                Some(kept) => kept,
+                None => {
+                    info!(target: "Pipeline", "starting '{}' output", output.typename());
+                    runtime.start(output, delivery, on_error)
+                }
+            };
+            route.acked = acknowledged(&acknowledging, index);
+
+            runtime.ids.push(id);
+            dispatch.routes.push(route);
+            dispatch.health.push(healthy);
+        }
+
+        // Dropping the route closes the channel, letting the worker finish once it is drained.
+        for (id, _, _) in running.into_iter().filter_map(|gone| gone) {
+            info!(target: "Pipeline", "stopping output {}", id);
+            runtime.tickable.lock().unwrap().retain(|&(other, _)| other != id);
+        }
+
+        Ok(())
+    }
+
+    /// Starts the input alongside the running ones, e.g. the requeue of dead letters, its records
+    /// validated by the default rules. It's stopped together with the others.
+    pub fn inject(&self, name: &str, input: Box<Input>, codec: Box<Codec>) -> Result<(), String> {
+        let mut slot = self.runtime.lock().unwrap();
+        let runtime = match *slot {
+            Some(ref mut runtime) => runtime,
+            None => return Err("pipeline is not running".to_string()),
+        };
+
+        let tx = match runtime.tx {
+            Some(ref tx) => tx.clone(),
+            None => return Err("pipeline is closed".to_string()),
+        };
+
+        let origin = Origin {
+            input: name.to_string(),
+            validation: runtime.validation.clone(),
+            capture: None,
+            projection: None,
+            encoding: None,
+            non_finite: NonFinite::default(),
+            decode_errors: None,
+        };
+
+        info!(target: "Pipeline", "starting '{}' input", name);
+        runtime.launch(Sink::new(tx, Arc::new(origin)), input, codec);
+        Ok(())
+    }
+
+    /// Returns listening socket descriptors of running inputs by input name.
+    pub fn listeners(&self) -> Vec<(String, RawFd)> {
+        match *self.runtime.lock().unwrap() {
+            Some(ref runtime) => {
+                runtime.inputs.iter().filter_map(|&(ref name, ref input)| {
+                    input.listener().map(|fd| (name.clone(), fd))
+                }).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Stops all inputs from accepting new connections, giving accepted ones the drain period in
+    /// milliseconds to finish, and closes the handle, so the pipeline flushes and finishes then.
+    ///
+    /// Inputs paused while all outputs are unavailable are resumed, so that they are able to
+    /// finish, records they send meanwhile being left to the unavailability policy.
+    pub fn stop(&self, drain: u32) {
+        if let Some(ref runtime) = *self.runtime.lock().unwrap() {
+            runtime.stopping.store(true, Ordering::SeqCst);
+            runtime.paused.store(false, Ordering::SeqCst);
+            for &(_, ref input) in runtime.inputs.iter() {
+                input.stop(drain);
+            }
+        }
+        self.close();
+    }
+
+    /// Stops accepting reloads, letting the pipeline finish once all its inputs are finished.
+    pub fn close(&self) {
+        if let Some(ref mut runtime) = *self.runtime.lock().unwrap() {
+            runtime.tx = None;
+        }
+    }
+}
+
+/// Feeds an output, retrying failures according to its delivery guarantee.
+///
+/// Definite failures are retried in both modes. Ambiguous ones are retried only in at-least-once
+/// mode, counting each retried call as possibly duplicated, while in at-most-once mode the record
+/// and everything buffered by the output are discarded and counted as possibly lost.
+///
+/// Once attempts are exhausted the output is considered unavailable and is only probed on flush
+/// until it responds again.
+struct Worker {
+    /// Output identifier, naming its metrics and thread.
+    name: String,
+    output: Box<Output>,
+    delivery: Delivery,
+    healthy: Arc<AtomicBool>,
+    lost: Counter,
+    duplicated: Counter,
+    /// Channel of oversize records, if the output accepts them intact.
+    oversize: Option<Receiver<(Record, Option<Ack>)>>,
+    /// Number of records taken from the channel and handled.
+    handled: Arc<AtomicUsize>,
+    breaker: Option<Breaker>,
+    /// Channel records are diverted to while the breaker is open, if any.
+    divert: Option<Sender<Event>>,
+    diverted: Counter,
+    /// Policy applied to records the output fails to accept, together with the dead-letter
+    /// channel, if the policy is set.
+    on_error: Option<(OnError, Option<Sender<Event>>)>,
+    errors: Counter,
+    panics: Counter,
+    /// Poison record detector told of records the output panics on, if enabled.
+    poison: Option<Arc<Detector>>,
+    /// Key of the output state together with the snapshot it's taken into once the worker stops.
+    snapshot: Option<(String, Arc<Mutex<Store>>)>,
+    /// Scheduler the worker thread is set up by, if any.
+    scheduler: Option<Arc<Scheduler>>,
+    /// Tokens of records the output has accepted, confirmed once it's flushed, as batching
+    /// outputs merely queue records on feed.
+    acks: Vec<Ack>,
+}
+
+impl Worker {
+    fn new(name: &str, output: Box<Output>, delivery: Delivery) -> Worker {
+        let registry = metrics::registry();
+
+        Worker {
+            name: name.to_string(),
+            output: output,
+            delivery: delivery,
+            healthy: Arc::new(AtomicBool::new(true)),
+            lost: registry.counter(&format!("output.{}.delivery.possibly_lost", name)),
+            duplicated: registry.counter(&format!("output.{}.delivery.possibly_duplicated", name)),
+            oversize: None,
+            handled: Arc::new(AtomicUsize::new(0)),
+            breaker: None,
+            divert: None,
+            diverted: registry.counter(&format!("output.{}.breaker.diverted", name)),
+            on_error: None,
+            errors: registry.counter(&format!("output.{}.errors", name)),
+            panics: registry.counter(&format!("output.{}.panics", name)),
+            poison: None,
+            snapshot: None,
+            scheduler: None,
+            acks: Vec::new(),
+        }
+    }
+
+    /// Guards the output by the circuit breaker, diverting records to the given channel while it's
+    /// open.
+    fn guard(&mut self, breaker: Breaker, divert: Option<Sender<Event>>) {
+        self.breaker = Some(breaker);
+        self.divert = divert;
+    }
+
+    /// Applies the policy to records the output fails to accept or panics on, sending them to the
+    /// given dead-letter channel if told to.
+    fn recover(&mut self, name: &str, on_error: OnError, dead_letter: Option<Sender<Event>>) {
+        for policy in OnError::all().iter() {
+            let counter = metrics::registry().counter(&format!("output.{}.on_error.{}", name, policy.name()));
+            counter.set(if *policy == on_error { 1 } else { 0 });
+        }
+        self.on_error = Some((on_error, dead_letter));
+    }
+
+    /// Returns true unless the breaker is open, marking the output unavailable while it is.
+    fn admit(&mut self) -> bool {
+        let allowed = match self.breaker {
+            Some(ref mut breaker) => breaker.allow(),
+            None => return true,
+        };
+
+        if !allowed && self.healthy.swap(false, Ordering::SeqCst) {
+            warn!(target: "Pipeline", "'{}' output is unavailable", self.output.typename());
+        }
+        allowed
+    }
+
+    /// Feeds the record, returning true once the output has accepted it.
+    fn feed(&mut self, record: &Record) -> bool {
+        if !self.admit() {
+            self.diverted.inc();
+            match self.divert {
+                Some(ref tx) => {
+                    let mut record = record.clone();
+                    let reason = format!("'{}' output breaker is open", self.output.typename());
+                    record.insert("_dead_letter_reason".to_string(), RecordItem::String(reason));
+                    record.insert("_dead_letter_stage".to_string(), RecordItem::String("output".to_string()));
+                    let _ = tx.send(Event::Record(record, None, 0));
+                }
+                None => warn!(target: "Pipeline", "dropping record: '{}' output breaker is open", self.output.typename()),
+            }
+            return false;
+        }
+
+        if let Some(ref poison) = self.poison {
+            if poison.excluded(record) {
+                debug!(target: "Pipeline", "dropping quarantined record before '{}' output", self.output.typename());
+                return false;
+            }
+        }
+
+        let attempts = match self.on_error {
+            Some((OnError::Drop, _)) | Some((OnError::DeadLetter, _)) => 1,
+            Some((OnError::RetryThenDeadLetter, _)) | None => ATTEMPTS,
+        };
+        let delivered = match self.on_error {
+            Some(..) => unwind::catch(|| self.deliver("feed", attempts, 1, |output| output.feed(record))),
+            None => Ok(self.deliver("feed", attempts, 1, |output| output.feed(record))),
+        };
+
+        let reason = match delivered {
+            Ok(true) => return true,
+            Ok(false) => format!("'{}' output failed to accept it", self.output.typename()),
+            Err(message) => {
+                self.panics.inc();
+                if let Some(ref poison) = self.poison {
+                    if poison.failure(record, &format!("output.{}", self.name), &message) {
+                        return false;
+                    }
+                }
+                format!("'{}' output panicked: {}", self.output.typename(), message)
+            }
+        };
+        self.errors.inc();
+
+        match self.on_error {
+            Some((OnError::DeadLetter, Some(ref tx))) | Some((OnError::RetryThenDeadLetter, Some(ref tx))) => {
+                let mut record = record.clone();
+                record.insert("_dead_letter_reason".to_string(), RecordItem::String(reason));
+                record.insert("_dead_letter_stage".to_string(), RecordItem::String("output".to_string()));
+                let _ = tx.send(Event::Record(record, None, 0));
+            }
+            Some((OnError::DeadLetter, None)) | Some((OnError::RetryThenDeadLetter, None)) => {
+                warn!(target: "Pipeline", "dropping record: {}, no dead-letter output configured", reason);
+            }
+            Some((OnError::Drop, _)) | None => warn!(target: "Pipeline", "dropping record: {}", reason),
+        }
+        false
+    }
+
+    /// Feeds the record, keeping the copy of its token, if any, to be confirmed by the flush
+    /// following once the output accepts it.
+    fn feed_acked(&mut self, record: &Record, ack: Option<Ack>) {
+        if let (true, Some(ack)) = (self.feed(record), ack) {
+            self.acks.push(ack);
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.admit() {
+            debug!(target: "Pipeline", "'{}' output breaker is open", self.output.typename());
+            return;
+        }
+
+        if !self.healthy.load(Ordering::SeqCst) {
+            if !self.output.probe() {
+                debug!(target: "Pipeline", "'{}' output is still unavailable", self.output.typename());
+                if let Some(ref mut guard) = self.breaker {
+                    if guard.state() == breaker::State::HalfOpen {
+                        guard.failure(0);
+                    }
+                }
+                return;
+            }
+            info!(target: "Pipeline", "'{}' output is available again", self.output.typename());
+            self.healthy.store(true, Ordering::SeqCst);
+        }
+
+        if self.deliver("flush", ATTEMPTS, 0, |output| output.flush()) {
+            for ack in mem::replace(&mut self.acks, Vec::new()).into_iter() {
+                ack.confirm();
+            }
+        }
+    }
+
+    /// Delivers records waiting in the oversize channel, flushing the output before and after each
+    /// one, so that batching outputs send it alone.
+    fn drain_oversize(&mut self) {
+        loop {
+            let (record, ack) = match self.oversize {
+                Some(ref rx) => {
+                    match rx.try_recv() {
+                        Ok(oversize) => oversize,
+                        Err(..) => return,
+                    }
+                }
+                None => return,
+            };
+
+            self.flush();
+            self.feed_acked(&record, ack);
+            self.flush();
+        }
+    }
+
+    /// Takes the output state into the snapshot, if enabled.
+    fn keep(&mut self) {
+        if let Some((ref key, ref store)) = self.snapshot {
+            let typename = self.output.typename();
+            if let Some(state) = self.output.state() {
+                store.lock().unwrap().keep(key, typename, state);
+            }
+        }
+    }
+
+    /// Performs the action until it succeeds or attempts are exhausted.
+    ///
+    /// The `retained` argument is the number of records lost together with the ones buffered by
+    /// the output if the action fails ambiguously in at-most-once mode.
+    fn deliver<F>(&mut self, action: &str, attempts: u32, retained: usize, f: F) -> bool
+        where F: Fn(&mut Box<Output>) -> Result<(), Error>
+    {
+        const BACKOFF: u32 = 10;
+
+        for attempt in 1..attempts + 1 {
+            let started = self.breaker.as_ref().map(|guard| guard.now());
+            let result = f(&mut self.output);
+            if let (Some(guard), Some(started)) = (self.breaker.as_mut(), started) {
+                let latency = guard.now().saturating_sub(started);
+                match result {
+                    Ok(()) => guard.success(latency),
+                    Err(Error::Fatal(..)) => {}
+                    Err(..) => guard.failure(latency),
+                }
+            }
+
+            let err = match result {
+                Ok(()) => {
+                    self.healthy.store(true, Ordering::SeqCst);
+                    return true;
+                }
+                Err(err) => err,
+            };
+
+            warn!(target: "Pipeline", "failed to {} '{}' output (attempt {} of {}): {}",
+                action, self.output.typename(), attempt, attempts, err);
+
+            let open = match self.breaker {
+                Some(ref guard) => guard.state() == breaker::State::Open,
+                None => false,
+            };
+            let retry = attempt < attempts && !open;
+            match err {
+                Error::Fatal(..) => return false,
+                Error::Ambiguous(..) if self.delivery == Delivery::AtMostOnce => {
+                    let lost = self.output.discard() + retained;
+                    warn!(target: "Pipeline", "not retrying ambiguous failure, {} records possibly lost", lost);
+                    self.lost.add(lost);
+                    // Records discarded are not delivered, failing their tokens.
+                    self.acks.clear();
+                    return true;
+                }
+                Error::Ambiguous(..) if retry => self.duplicated.inc(),
+                Error::Ambiguous(..) | Error::Retryable(..) => {}
+            }
+
+            if retry {
+                thread::sleep_ms(BACKOFF * attempt);
+            } else {
+                break;
+            }
+        }
+
+        if self.healthy.swap(false, Ordering::SeqCst) {
+            warn!(target: "Pipeline", "'{}' output is unavailable", self.output.typename());
+        }
+
+        false
+    }
+}
+
+/// Number of times the worker performs an action on the output before giving up.
+const ATTEMPTS: u32 = 3;
+
+/// Makes the filter chain, tracing the named filters in the lineage.
+fn chain(filters: Vec<Box<Filter>>, traced: Vec<Option<String>>, lineage: Option<Lineage>) -> Chain {
+    let mut chain = Chain::new(filters);
+    if traced.iter().any(|name| name.is_some()) {
+        chain.trace(lineage.unwrap_or_else(|| Lineage::new(lineage::Settings::default())), traced);
+    }
+    chain
+}
+
+fn spawn(mut worker: Worker) -> (Sender<Event>, JoinHandle<()>) {
+    let (tx, rx) = channel();
+    let name = format!("output-{}-{}", naming::short(worker.output.typename()), worker.name);
+    let handle = naming::spawn(&name, move || {
+        trace!(target: "Pipeline", "starting '{}' output", worker.output.typename());
+        if let Some(ref scheduler) = worker.scheduler {
+            scheduler.enter();
+        }
+
+        for event in rx.iter() {
+            worker.drain_oversize();
+            match event {
+                Event::Record(record, ack, processing) => {
+                    trace!(target: "Pipeline", "processing {} feeding '{}' output", processing, worker.output.typename());
+                    worker.feed_acked(&record, ack);
+                    worker.handled.fetch_add(1, Ordering::SeqCst);
+                }
+                Event::Flush => worker.flush(),
+                Event::Oversize => {}
+            }
+        }
+
+        worker.drain_oversize();
+        worker.flush();
+        worker.keep();
+    });
+
+    (tx, handle)
+}
+
+/// Spawns an output worker draining an elastic queue, flushing the output every given interval.
+///
+/// The queue cannot wake the worker for oversize records, so they are polled for every step.
+fn spawn_elastic(mut worker: Worker, rx: ElasticReceiver<Record>, interval: u32) -> JoinHandle<()> {
+    const STEP: u32 = 50;
+
+    let name = format!("output-{}-{}", naming::short(worker.output.typename()), worker.name);
+    naming::spawn(&name, move || {
+        trace!(target: "Pipeline", "starting '{}' output", worker.output.typename());
+        if let Some(ref scheduler) = worker.scheduler {
+            scheduler.enter();
+        }
+
+        let clock = SystemClock;
+        let mut flushed = clock.now();
+        loop {
+            let elapsed = clock.now().saturating_sub(flushed);
+            let timeout = if elapsed < interval as u64 { interval - elapsed as u32 } else { 0 };
+            let timeout = if worker.oversize.is_some() { cmp::min(timeout, STEP) } else { timeout };
+
+            worker.drain_oversize();
+            match rx.recv_timeout(timeout) {
+                Received::Record(record) => {
+                    worker.feed(&record);
+                    worker.handled.fetch_add(1, Ordering::SeqCst);
+                }
+                Received::Timeout => {}
+                Received::Closed => break,
+            }
+
+            if clock.now().saturating_sub(flushed) >= interval as u64 {
+                flushed = clock.now();
+                worker.flush();
+            }
+        }
+
+        worker.drain_oversize();
+        worker.flush();
+        worker.keep();
+    })
+}
+
+/// Moves envelopes from inputs into the elastic main queue, see `Pipeline::set_elastic`.
+fn relay(rx: Receiver<Envelope>, settings: &queue::Settings) -> ElasticReceiver<Envelope> {
+    let (tx, queue) = queue::elastic(settings, "pipeline");
+    naming::spawn("relay", move || {
+        for envelope in rx.iter() {
+            if let Err(err) = tx.send(envelope) {
+                error!(target: "Pipeline", "dropping record: failed to spill: {}", err);
+            }
+        }
+    });
+
+    queue
+}
+
+/// Waits while the dispatch refuses records, re-checking outputs health periodically, until the
+/// pipeline is stopped.
+///
+/// Inputs are paused meanwhile, as records would pile up in the pipeline channel otherwise.
+fn wait(dispatch: &Mutex<Dispatch>) {
+    const STEP: u32 = 50;
+
+    loop {
+        {
+            let mut dispatch = dispatch.lock().unwrap();
+            let blocked = dispatch.blocked() && !dispatch.stopping.load(Ordering::SeqCst);
+            dispatch.paused.store(blocked, Ordering::SeqCst);
+            if !blocked {
+                return;
+            }
+            // Tracks the transition even if the ticker has not noticed it yet.
+            dispatch.send(Vec::new(), None, 0);
+        }
+        thread::sleep_ms(STEP);
+    }
+}
+
+/// Spawns a thread evaluating alert rules at the given interval while running, passing records
+/// announcing transitions through the dispatch.
+fn alert(dispatch: Arc<Mutex<Dispatch>>, alerting: Arc<Mutex<Alerting>>, interval: u64,
+         running: Arc<AtomicBool>) -> JoinHandle<()>
+{
+    const STEP: u32 = 50;
+
+    naming::spawn("alerts", move || {
+        let mut elapsed = 0;
+        while running.load(Ordering::SeqCst) {
+            thread::sleep_ms(STEP);
+            elapsed += STEP as u64;
+
+            if elapsed >= interval {
+                elapsed = 0;
+                let records = alerting.lock().unwrap().tick();
+                let mut dispatch = dispatch.lock().unwrap();
+                for record in records.into_iter() {
+                    dispatch.apply(record);
+                }
+            }
+        }
+    })
+}
+
+/// Spawns a thread passing records of audit entries through the dispatch while running, including
+/// the ones appended by the time it stops.
+fn audited(dispatch: Arc<Mutex<Dispatch>>, rx: Receiver<Record>, running: Arc<AtomicBool>) -> JoinHandle<()> {
+    const STEP: u32 = 50;
+
+    naming::spawn("audit", move || {
+        loop {
+            let stopped = !running.load(Ordering::SeqCst);
+            while let Ok(record) = rx.try_recv() {
+                dispatch.lock().unwrap().apply(record);
+            }
+
+            if stopped {
+                break;
+            }
+            thread::sleep_ms(STEP);
+        }
+    })
+}
+
+/// Spawns a thread flushing filters unless told otherwise, requesting all outputs to flush and
+/// sampling their backlogs at the given interval while running.
+fn ticker(dispatch: Arc<Mutex<Dispatch>>, channels: Arc<Mutex<Vec<(usize, Sender<Event>)>>>, interval: u32,
+          filters: bool, running: Arc<AtomicBool>) -> JoinHandle<()>
+{
+    const STEP: u32 = 50;
+
+    naming::spawn("ticker", move || {
+        let clock = SystemClock;
+        let mut elapsed = 0;
+        while running.load(Ordering::SeqCst) {
+            thread::sleep_ms(STEP);
+            elapsed += STEP;
+
+            if elapsed >= interval {
+                elapsed = 0;
+                {
+                    let mut dispatch = dispatch.lock().unwrap();
+                    if filters {
+                        dispatch.flush();
+                    } else {
+                        dispatch.spill();
+                    }
+                    dispatch.watch(clock.now());
+                }
+                for &(_, ref tx) in channels.lock().unwrap().iter() {
+                    let _ = tx.send(Event::Flush);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::env;
+    use std::f64;
+    use std::fs;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::iter::repeat;
+    use std::mem;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    use super::super::{Record, RecordItem};
+    use super::super::ack::{Ack, Outcome, Tracker};
+    use super::super::breaker;
+    use super::super::codec::{Codec, Json, MessagePack};
+    use super::super::clock::SystemClock;
+    use super::super::codec::msgpack;
+    use super::super::determinism;
+    use super::super::filter;
+    use super::super::diff;
+    use super::super::filter::{Bucketize, Coalesce, Correlate, CopyFields, Existing, Failure, Filter, Guard, LevelSample,
+                               MaxSize, Oversized};
+    use super::super::input::{DECODE_ERROR_FIELD, Input, PEER_FIELD, Sink, TcpInput};
+    use super::super::metrics;
+    use super::super::numeric::NonFinite;
+    use super::super::output::{Dedup, DedupSettings, Delivery, Error, FileOutput, OnError, Output};
+    use super::super::oversize;
+    use super::super::poison;
+    use super::super::queue::Settings;
+    use super::super::quota;
+    use super::super::quota::Quota;
+    use super::super::snapshot;
+    use super::super::source;
+    use super::super::source::{Authority, Resolution};
+    use super::super::spool::{Reader, Spool};
+    use super::super::testing::{Collector, Feeder, Flaky, Scripted, capture, record, string};
+    use super::super::validation::{Missing, Validation};
+    use super::super::version;
+    use super::{Event, Pipeline, Reload, Unavailable, Worker};
+
+    #[test]
+    fn judge_records_by_input_rules() {
+        let (strict, strict_tx) = Feeder::new();
+        let (metrics, metrics_tx) = Feeder::new();
+        let collector = Collector::new();
+        let dead_letter = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::default());
+        pipeline.add_input("strict", Box::new(strict), Box::new(MessagePack::default()),
+            Some(Validation::new(vec!["message".to_string()], Missing::DeadLetter)));
+        pipeline.add_input("metrics", Box::new(metrics), Box::new(MessagePack::default()),
+            Some(Validation::new(vec!["message".to_string()], Missing::Synthesize)));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_dead_letter(Box::new(dead_letter.clone()));
+
+        strict_tx.send(record(vec![("source", string("app"))])).unwrap();
+        strict_tx.send(record(vec![("message", string("le message"))])).unwrap();
+        metrics_tx.send(record(vec![("cpu", string("42"))])).unwrap();
+        drop(strict_tx);
+        drop(metrics_tx);
+
+        pipeline.run();
+
+        let records = collector.records();
+        assert_eq!(2, records.len());
+        assert!(records.contains(&record(vec![("message", string("le message"))])));
+        assert!(records.contains(&record(vec![("cpu", string("42")), ("message", string("cpu=42"))])));
+
+        let expected = record(vec![
+            ("source", string("app")),
+            ("_dead_letter_reason", string("[\"message\"] field required")),
+            ("_dead_letter_stage", string("input")),
+        ]);
+        assert_eq!(vec![expected], dead_letter.records());
+    }
+
+    #[test]
+    fn apply_non_finite_policy_of_inputs() {
+        let (lenient, lenient_tx) = Feeder::new();
+        let (strict, strict_tx) = Feeder::new();
+        let collector = Collector::new();
+        let dead_letter = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::default());
+        pipeline.add_input("lenient", Box::new(lenient), Box::new(MessagePack::default()), None);
+        pipeline.add_input("strict", Box::new(strict), Box::new(MessagePack::default()), None);
+        pipeline.set_non_finite("strict", NonFinite::DeadLetter);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_dead_letter(Box::new(dead_letter.clone()));
+
+        let payload = msgpack::decode(&msgpack::encode(&record(vec![("latency", RecordItem::F64(f64::NAN))]))).unwrap();
+        lenient_tx.send(payload.clone()).unwrap();
+        strict_tx.send(payload).unwrap();
+        drop(lenient_tx);
+        drop(strict_tx);
+
+        pipeline.run();
+
+        let expected = record(vec![
+            ("latency", RecordItem::Null),
+            ("_non_finite", RecordItem::Array(vec![string("latency")])),
+        ]);
+        assert_eq!(vec![expected], collector.records());
+
+        let records = dead_letter.records();
+        assert_eq!(1, records.len());
+        assert!(records[0].find("latency").and_then(|latency| latency.as_f64()).unwrap().is_nan());
+        assert_eq!(Some("non-finite numbers at [\"latency\"]"), records[0].find("_dead_letter_reason").and_then(|reason| reason.as_str()));
+    }
+
+    #[test]
+    fn emit_decode_errors_as_records() {
+        // The bound listener takes the connection before the input starts accepting.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::default());
+        pipeline.add_input("tcp", Box::new(TcpInput::from_listener(listener)), Box::new(Json::default()), None);
+        pipeline.set_decode_errors("tcp", 8);
+        pipeline.add_output(Box::new(collector.clone()));
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"{\"message\": oops}\n{\"message\": \"fine\"}\n").unwrap();
+        drop(stream);
+
+        wait_for(&collector, 2);
+        handle.stop(0);
+        pipeline.join().unwrap();
+
+        let records = collector.records();
+        assert_eq!(2, records.len());
+        assert_eq!(Some("{\"messag"), records[0].find("message").and_then(|message| message.as_str()));
+        assert!(records[0].find(DECODE_ERROR_FIELD).and_then(|error| error.as_str()).unwrap().starts_with("malformed input"));
+        assert_eq!(Some("127.0.0.1"), records[0].find(PEER_FIELD).and_then(|peer| peer.as_str()));
+        assert_eq!(Some("fine"), records[1].find("message").and_then(|message| message.as_str()));
+        assert_eq!(None, records[1].find(DECODE_ERROR_FIELD));
+    }
+
+    #[test]
+    fn divert_records_dead_lettered_by_filters() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let dead_letter = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::default());
+        pipeline.add_input("default", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_filter(Box::new(MaxSize::new(64, Oversized::DeadLetter)));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_dead_letter(Box::new(dead_letter.clone()));
+
+        let large = record(vec![("message", string(&repeat("x").take(100).collect::<String>()))]);
+        let replayed = record(vec![("message", string("replayed")), ("_dead_letter_reason", string("earlier"))]);
+        tx.send(record(vec![("message", string("small"))])).unwrap();
+        tx.send(large.clone()).unwrap();
+        tx.send(replayed.clone()).unwrap();
+        drop(tx);
+
+        pipeline.run();
+
+        assert_eq!(vec![record(vec![("message", string("small"))]), replayed], collector.records());
+
+        let records = dead_letter.records();
+        assert_eq!(1, records.len());
+        assert_eq!(large.find("message"), records[0].find("message"));
+        assert!(records[0].find("_dead_letter_reason").is_some());
+    }
+
+    /// Output remembering names of threads it's fed from.
+    struct Named(Arc<Mutex<Vec<String>>>);
+
+    impl Output for Named {
+        fn feed(&mut self, _: &Record) -> Result<(), Error> {
+            self.0.lock().unwrap().push(thread::current().name().unwrap_or("").to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn name_output_worker_threads() {
+        let (input, tx) = Feeder::new();
+        let names = Arc::new(Mutex::new(Vec::new()));
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("feeder", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Collector::new()));
+        pipeline.add_output(Box::new(Named(names.clone())));
+
+        tx.send(record(vec![("message", string("le message"))])).unwrap();
+        drop(tx);
+        pipeline.run();
+
+        assert_eq!(vec!["logdrop-output-named-1".to_string()], *names.lock().unwrap());
+    }
+
+    #[test]
+    fn log_processing_id_at_each_stage() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::default());
+        pipeline.add_input("traced", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_filter(Box::new(MaxSize::new(1024, Oversized::DeadLetter)));
+        pipeline.add_output(Box::new(collector.clone()));
+
+        let capture = capture();
+        tx.send(record(vec![("message", string("traced record"))])).unwrap();
+        drop(tx);
+        pipeline.run();
+        let lines = capture.lines();
+        drop(capture);
+
+        // Other pipelines may log meanwhile, while ids are unique within the process.
+        let ingested = lines.iter()
+            .find(|line| line.contains("from 'traced' input") && line.contains("traced record"))
+            .unwrap();
+        let id = ingested.split(' ').nth(3).unwrap();
+        assert!(id.parse::<u64>().unwrap() > 0);
+
+        for stage in ["applying", "dispatched", "feeding"].iter() {
+            let prefix = format!("processing {} {} ", id, stage);
+            assert!(lines.iter().any(|line| line.contains(&prefix)), "no '{}' line in {:?}", prefix, lines);
+        }
+        assert_eq!(1, collector.records().len());
+    }
+
+    #[test]
+    fn apply_default_rules_without_override() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::default());
+        pipeline.add_input("default", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+
+        tx.send(record(vec![("value", RecordItem::Null)])).unwrap();
+        tx.send(record(vec![("message", string("le message"))])).unwrap();
+        drop(tx);
+
+        pipeline.run();
+
+        assert_eq!(vec![record(vec![("message", string("le message"))])], collector.records());
+    }
+
+    #[test]
+    fn deliver_in_order_through_elastic_channel() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("burst", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_elastic(Settings { capacity: 8, low: 2, grace: 2, dir: env::temp_dir() });
+
+        let expected: Vec<_> = (0..100).map(|id| record(vec![("id", RecordItem::F64(id as f64))])).collect();
+        for record in expected.iter() {
+            tx.send(record.clone()).unwrap();
+        }
+        drop(tx);
+
+        pipeline.run();
+
+        assert_eq!(expected, collector.records());
+    }
+
+    #[test]
+    fn enforce_quotas_per_source() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut settings = quota::Settings::default();
+        settings.soft.insert("metered-app".to_string(), 50);
+        settings.hard.insert("metered-app".to_string(), 100);
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("metered", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_quota(Quota::new(settings));
+
+        // Each record takes 44 bytes.
+        let records: Vec<Record> = (0..3).map(|id| {
+            record(vec![("source", string("metered-app")), ("id", RecordItem::F64(id as f64))])
+        }).collect();
+        for record in records.iter() {
+            tx.send(record.clone()).unwrap();
+        }
+        tx.send(record(vec![("id", RecordItem::F64(3.0))])).unwrap();
+        drop(tx);
+        pipeline.run();
+
+        let records = collector.records();
+        assert_eq!(4, records.len());
+        assert_eq!(Some(&string("quota_notification")), records[1].find("type"));
+        assert_eq!(Some(&string("metered-app")), records[1].find("source"));
+
+        let mut over = record(vec![("source", string("metered-app")), ("id", RecordItem::F64(1.0))]);
+        over.insert(quota::FIELD.to_string(), RecordItem::Bool(true));
+        assert_eq!(record(vec![("source", string("metered-app")), ("id", RecordItem::F64(0.0))]), records[0]);
+        assert_eq!(over, records[2]);
+        assert_eq!(record(vec![("id", RecordItem::F64(3.0))]), records[3]);
+
+        let registry = metrics::registry();
+        assert_eq!(4, registry.counter("input.metered.records").get());
+        assert_eq!(44 * 3 + 21, registry.counter("input.metered.bytes").get());
+        assert_eq!(1, registry.counter("pipeline.quota.metered-app.dropped").get());
+    }
+
+    /// Output blocking on the lock while the test holds it.
+    struct Stalled {
+        lock: Arc<Mutex<()>>,
+    }
+
+    impl Output for Stalled {
+        fn feed(&mut self, _payload: &Record) -> Result<(), Error> {
+            let _guard = self.lock.lock().unwrap();
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn warn_about_slow_consumer() {
+        let (input, tx) = Feeder::new();
+        let lock = Arc::new(Mutex::new(()));
+        let guard = lock.lock().unwrap();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("stalled", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Stalled { lock: lock.clone() }));
+        pipeline.set_flush_interval(50);
+        pipeline.set_slow_consumer(10, 60000);
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        for id in 0..20 {
+            tx.send(record(vec![("id", RecordItem::F64(id as f64))])).unwrap();
+        }
+        // Several samples are taken while stalled, only the first one warns.
+        thread::sleep_ms(300);
+        drop(guard);
+        thread::sleep_ms(300);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        let registry = metrics::registry();
+        assert_eq!(1, registry.counter("output.0.backlog.warnings").get());
+        assert_eq!(1, registry.counter("output.0.backlog.recoveries").get());
+    }
+
+    #[test]
+    fn capture_transforms_of_changed_records() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let audit = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("audited", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_filter(Box::new(CopyFields::new(vec![("host".to_string(), "origin".to_string())], Existing::Skip)));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_transforms(true, Some(Box::new(audit.clone())));
+
+        tx.send(record(vec![("host", string("web")), ("timestamp", RecordItem::F64(1.0))])).unwrap();
+        tx.send(record(vec![("message", string("untouched"))])).unwrap();
+        drop(tx);
+        pipeline.run();
+
+        let changes = diff::to_item(&[diff::Entry { path: vec!["origin".to_string()], change: diff::Change::Added(string("web")) }]);
+        let expected = vec![
+            record(vec![("host", string("web")), ("timestamp", RecordItem::F64(1.0)), ("origin", string("web")),
+                (diff::FIELD, changes.clone())]),
+            record(vec![("message", string("untouched"))]),
+        ];
+        assert_eq!(expected, collector.records());
+
+        let expected = record(vec![("type", string("record_diff")), ("timestamp", RecordItem::F64(1.0)), ("changes", changes)]);
+        assert_eq!(vec![expected], audit.records());
+    }
+
+    /// Output collecting records in batches delimited by flushes, stalling on oversize records
+    /// until released.
+    struct Batching {
+        batch: Vec<Record>,
+        batches: Arc<Mutex<Vec<Vec<Record>>>>,
+        released: Arc<AtomicBool>,
+    }
+
+    impl Output for Batching {
+        fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+            while payload.find(oversize::ID).is_some() && !self.released.load(Ordering::SeqCst) {
+                thread::sleep_ms(10);
+            }
+
+            self.batch.push(payload.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            if !self.batch.is_empty() {
+                self.batches.lock().unwrap().push(mem::replace(&mut self.batch, Vec::new()));
+            }
+            Ok(())
+        }
+
+        fn oversize(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn route_oversize_records_apart() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let released = Arc::new(AtomicBool::new(false));
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("large", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Batching { batch: Vec::new(), batches: batches.clone(), released: released.clone() }));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_flush_interval(60000);
+        pipeline.set_oversize(oversize::Settings { threshold: 1024, depth: 1, canonical: strings(&["timestamp", "host"]) });
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        let message: String = repeat('x').take(4096).collect();
+        let large = record(vec![("timestamp", RecordItem::F64(1.0)), ("host", string("web")), ("message", string(&message))]);
+        let normal: Vec<_> = (0..100).map(|id| record(vec![("id", RecordItem::F64(id as f64))])).collect();
+        for record in normal[..50].iter().chain(Some(&large).into_iter()).chain(normal[50..].iter()) {
+            tx.send(record.clone()).unwrap();
+        }
+
+        // Normal records and the stub keep flowing to the other output while the opted-in one is
+        // stuck writing the oversize record.
+        wait_for(&collector, 101);
+        assert_eq!(101, collector.records().len());
+        released.store(true, Ordering::SeqCst);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        let mut records = collector.records();
+        let stub = records.remove(50);
+        let id = stub.find(oversize::ID).cloned().unwrap();
+        let expected = record(vec![("timestamp", RecordItem::F64(1.0)), ("host", string("web")), (oversize::ID, id.clone()),
+            (oversize::SIZE, RecordItem::F64(large.size_hint() as f64))]);
+        assert_eq!(expected, stub);
+        assert_eq!(normal, records);
+
+        let mut intact = large.clone();
+        intact.insert(oversize::ID.to_string(), id);
+        let batches = batches.lock().unwrap().clone();
+        assert!(batches.contains(&vec![intact.clone()]));
+        assert_eq!(1, batches.iter().filter(|batch| batch.contains(&intact)).count());
+
+        let delivered: Vec<_> = batches.into_iter()
+            .flat_map(|batch| batch.into_iter())
+            .filter(|record| record.find(oversize::ID).is_none())
+            .collect();
+        assert_eq!(normal, delivered);
+    }
+
+    #[test]
+    fn send_oversize_records_over_depth_inline() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let released = Arc::new(AtomicBool::new(false));
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("large", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Batching { batch: Vec::new(), batches: batches.clone(), released: released.clone() }));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_flush_interval(60000);
+        pipeline.set_oversize(oversize::Settings { threshold: 1024, depth: 1, canonical: strings(&["id"]) });
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        let message: String = repeat('x').take(4096).collect();
+        let large: Vec<_> = (0..4).map(|id| record(vec![("id", RecordItem::F64(id as f64)), ("message", string(&message))])).collect();
+        let normal: Vec<_> = (0..10).map(|id| record(vec![("id", RecordItem::F64(id as f64))])).collect();
+        for record in large.iter().chain(normal.iter()) {
+            tx.send(record.clone()).unwrap();
+        }
+
+        // The dispatch does not stall while the opted-in output is stuck with more oversize
+        // records than its channel holds.
+        wait_for(&collector, 14);
+        assert_eq!(14, collector.records().len());
+        released.store(true, Ordering::SeqCst);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        let delivered: Vec<_> = batches.lock().unwrap().iter()
+            .flat_map(|batch| batch.iter())
+            .filter(|record| record.find(oversize::ID).is_some())
+            .map(|record| record.find("id").cloned().unwrap())
+            .collect();
+        assert_eq!(4, delivered.len());
+        for record in large.iter() {
+            assert!(delivered.contains(record.find("id").unwrap()));
+        }
+        assert!(metrics::registry().counter("output.0.oversize.overflowed").get() >= 1);
+    }
+
+    fn ambiguous() -> Error {
+        Error::Ambiguous("timed out".to_string())
+    }
+
+    #[test]
+    fn at_most_once_never_retries_ambiguous_failure() {
+        let collector = Collector::new();
+        let output = Scripted::new(vec![ambiguous()], collector.clone());
+        let mut worker = Worker::new("test_at_most_once", Box::new(output), Delivery::AtMostOnce);
+
+        worker.feed(&record(vec![("message", string("first"))]));
+        worker.feed(&record(vec![("message", string("second"))]));
+
+        assert_eq!(vec![record(vec![("message", string("first"))]), record(vec![("message", string("second"))])], collector.records());
+
+        let registry = metrics::registry();
+        assert_eq!(1, registry.counter("output.test_at_most_once.delivery.possibly_lost").get());
+        assert_eq!(0, registry.counter("output.test_at_most_once.delivery.possibly_duplicated").get());
+    }
+
+    #[test]
+    fn at_least_once_retries_ambiguous_failure() {
+        let collector = Collector::new();
+        let output = Scripted::new(vec![ambiguous(), Error::Retryable("refused".to_string())], collector.clone());
+        let mut worker = Worker::new("test_at_least_once", Box::new(output), Delivery::AtLeastOnce);
+
+        worker.feed(&record(vec![("message", string("le message"))]));
+
+        // Delivered by the ambiguous attempt and by the third one.
+        assert_eq!(vec![record(vec![("message", string("le message"))]); 2], collector.records());
+
+        let registry = metrics::registry();
+        assert_eq!(0, registry.counter("output.test_at_least_once.delivery.possibly_lost").get());
+        assert_eq!(1, registry.counter("output.test_at_least_once.delivery.possibly_duplicated").get());
+    }
+
+    #[test]
+    fn retry_definite_failure_in_at_most_once_mode() {
+        let collector = Collector::new();
+        let output = Scripted::new(vec![Error::Retryable("refused".to_string())], collector.clone());
+        let mut worker = Worker::new("test_at_most_once_retryable", Box::new(output), Delivery::AtMostOnce);
+
+        worker.feed(&record(vec![("message", string("le message"))]));
+
+        assert_eq!(vec![record(vec![("message", string("le message"))])], collector.records());
+        assert_eq!(0, metrics::registry().counter("output.test_at_most_once_retryable.delivery.possibly_lost").get());
+    }
+
+    #[test]
+    fn report_delivery_modes_of_outputs() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("delivered", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output_with_delivery(Box::new(collector.clone()), Delivery::AtMostOnce);
+        pipeline.add_output(Box::new(Collector::new()));
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        tx.send(record(vec![("message", string("le message"))])).unwrap();
+        wait_for(&collector, 1);
+        assert_eq!(vec![(0, Delivery::AtMostOnce), (1, Delivery::AtLeastOnce)], handle.deliveries());
+
+        drop(tx);
+        pipeline.join().unwrap();
+        assert!(handle.deliveries().is_empty());
+    }
+
+    /// Output failing on records with the `fail` field, panicking on ones with `panic` and
+    /// delivering others into the collector, counting attempts.
+    struct Refusing {
+        collector: Collector,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    impl Output for Refusing {
+        fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            if payload.find("panic").is_some() {
+                panic!("scripted panic");
+            }
+            if payload.find("fail").is_some() {
+                return Err(Error::Retryable("refused".to_string()));
+            }
+            self.collector.feed(payload)
+        }
+    }
+
+    /// Feeds the refusing output with a good, a failing and a panicking record under the policy,
+    /// returning delivered and dead-lettered records together with the number of attempts.
+    fn refuse(name: &str, on_error: OnError) -> (Vec<Record>, Vec<Record>, usize) {
+        let collector = Collector::new();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = channel();
+
+        let output = Refusing { collector: collector.clone(), attempts: attempts.clone() };
+        let mut worker = Worker::new(name, Box::new(output), Delivery::AtLeastOnce);
+        worker.recover(name, on_error, Some(tx));
+        for &key in ["ok", "fail", "panic"].iter() {
+            worker.feed(&record(vec![(key, string("value"))]));
+        }
+        drop(worker);
+
+        let dead = rx.iter().map(|event| {
+            match event {
+                Event::Record(record, _, _) => record,
+                _ => panic!("unexpected event"),
+            }
+        }).collect();
+        (collector.records(), dead, attempts.load(Ordering::SeqCst))
+    }
+
+    fn reason(record: &Record) -> &str {
+        record.find("_dead_letter_reason").and_then(|reason| reason.as_str()).unwrap()
+    }
+
+    #[test]
+    fn apply_on_error_policy_of_output() {
+        let ok = record(vec![("ok", string("value"))]);
+
+        let (delivered, dead, attempts) = refuse("test_on_error_drop", OnError::Drop);
+        assert_eq!(vec![ok.clone()], delivered);
+        assert!(dead.is_empty());
+        assert_eq!(3, attempts);
+
+        let (delivered, dead, attempts) = refuse("test_on_error_dead_letter", OnError::DeadLetter);
+        assert_eq!(vec![ok.clone()], delivered);
+        assert_eq!(2, dead.len());
+        assert_eq!(3, attempts);
+
+        // Panics are not retried.
+        let (delivered, retried, attempts) = refuse("test_on_error_retry", OnError::RetryThenDeadLetter);
+        assert_eq!(vec![ok.clone()], delivered);
+        assert_eq!(dead, retried);
+        assert_eq!(5, attempts);
+
+        assert_eq!(Some(&string("value")), dead[0].find("fail"));
+        assert!(reason(&dead[0]).ends_with("Refusing' output failed to accept it"));
+        assert_eq!(Some(&string("value")), dead[1].find("panic"));
+        assert!(reason(&dead[1]).ends_with("Refusing' output panicked: scripted panic"));
+
+        let registry = metrics::registry();
+        for &(name, policy) in [("test_on_error_drop", "drop"), ("test_on_error_dead_letter", "dead_letter"),
+                                ("test_on_error_retry", "retry_then_dead_letter")].iter() {
+            assert_eq!(1, registry.counter(&format!("output.{}.on_error.{}", name, policy)).get());
+            assert_eq!(2, registry.counter(&format!("output.{}.errors", name)).get());
+            assert_eq!(1, registry.counter(&format!("output.{}.panics", name)).get());
+        }
+    }
+
+    /// Input sending records with tokens of their positions, see `ack`.
+    struct Acking {
+        records: Mutex<Option<(Vec<Record>, Sender<Outcome>)>>,
+        /// Channel keeping the input running once records are sent until it's closed, if any.
+        hold: Mutex<Option<Receiver<()>>>,
+    }
+
+    impl Input for Acking {
+        fn run(&self, tx: Sink, _: Box<Codec>) {
+            let (records, outcomes) = self.records.lock().unwrap().take().unwrap();
+            for (id, record) in records.into_iter().enumerate() {
+                tx.send_acked(record, Ack::new(id as u64, outcomes.clone())).unwrap();
+            }
+            if let Some(hold) = self.hold.lock().unwrap().take() {
+                let _ = hold.recv();
+            }
+        }
+    }
+
+    /// Output holding each record until told whether to accept it.
+    struct Gated {
+        verdicts: Mutex<Receiver<bool>>,
+    }
+
+    impl Output for Gated {
+        fn feed(&mut self, _: &Record) -> Result<(), Error> {
+            match self.verdicts.lock().unwrap().recv() {
+                Ok(true) => Ok(()),
+                _ => Err(Error::Fatal("refused".to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn acknowledge_records_once_outputs_accept_them() {
+        let (outcomes, rx) = channel();
+        let mut tracker = Tracker::new(rx, 0);
+        let records = (0..3).map(|seq| record(vec![("seq", RecordItem::I64(seq))])).collect();
+        let input = Acking { records: Mutex::new(Some((records, outcomes))), hold: Mutex::new(None) };
+        let (verdicts, rx) = channel();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("acking", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.add_output(Box::new(Gated { verdicts: Mutex::new(rx) }));
+        let running = thread::spawn(move || pipeline.run());
+
+        // Delivered by the other output meanwhile, yet not confirmed by the gated one.
+        while collector.records().len() < 3 {
+            thread::sleep_ms(10);
+        }
+        assert_eq!(0, tracker.poll());
+
+        verdicts.send(true).unwrap();
+        assert_eq!(1, tracker.wait(1));
+
+        verdicts.send(false).unwrap();
+        verdicts.send(true).unwrap();
+        running.join().unwrap();
+
+        // The refused record holds the ones after it back, although they are confirmed.
+        assert_eq!(1, tracker.wait(3));
+        assert_eq!(Some(1), tracker.failed());
+    }
+
+    #[test]
+    fn acknowledge_records_by_chosen_outputs_only() {
+        let (outcomes, rx) = channel();
+        let mut tracker = Tracker::new(rx, 0);
+        let records = (0..2).map(|seq| record(vec![("seq", RecordItem::I64(seq))])).collect();
+        let input = Acking { records: Mutex::new(Some((records, outcomes))), hold: Mutex::new(None) };
+        let (verdicts, rx) = channel();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("acking", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Gated { verdicts: Mutex::new(rx) }));
+        pipeline.add_output(Box::new(Collector::new()));
+        pipeline.set_acknowledging(vec![1]);
+        let running = thread::spawn(move || pipeline.run());
+
+        assert_eq!(2, tracker.wait(2));
+        drop(verdicts);
+        running.join().unwrap();
+        assert_eq!(None, tracker.failed());
+    }
+
+    /// Output queueing records until flushed, the flush succeeding as told.
+    struct Buffering {
+        queued: Arc<AtomicUsize>,
+        verdicts: Mutex<Receiver<bool>>,
+    }
+
+    impl Output for Buffering {
+        fn feed(&mut self, _: &Record) -> Result<(), Error> {
+            self.queued.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            match self.verdicts.lock().unwrap().recv() {
+                Ok(false) => Err(Error::Fatal("refused".to_string())),
+                Ok(true) | Err(..) => {
+                    self.queued.store(0, Ordering::SeqCst);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn acknowledge_records_once_buffering_output_flushes_them() {
+        let (outcomes, rx) = channel();
+        let mut tracker = Tracker::new(rx, 0);
+        let records = (0..2).map(|seq| record(vec![("seq", RecordItem::I64(seq))])).collect();
+        let (release, hold) = channel();
+        let input = Acking { records: Mutex::new(Some((records, outcomes))), hold: Mutex::new(Some(hold)) };
+        let queued = Arc::new(AtomicUsize::new(0));
+        let (verdicts, rx) = channel();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("acking", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Buffering { queued: queued.clone(), verdicts: Mutex::new(rx) }));
+        pipeline.set_flush_interval(60000);
+        let handle = pipeline.handle();
+        let running = thread::spawn(move || pipeline.run());
+
+        // Accepted records are merely queued, so they are not confirmed until a flush succeeds.
+        while queued.load(Ordering::SeqCst) < 2 {
+            thread::sleep_ms(10);
+        }
+        verdicts.send(false).unwrap();
+        handle.flush().unwrap();
+        thread::sleep_ms(100);
+        assert_eq!(0, tracker.poll());
+
+        verdicts.send(true).unwrap();
+        handle.flush().unwrap();
+        assert_eq!(2, tracker.wait(2));
+        assert_eq!(None, tracker.failed());
+
+        drop(verdicts);
+        drop(release);
+        running.join().unwrap();
+    }
+
+    /// Filter failing on records with the `fail` field and panicking on ones with `panic`.
+    struct Unreliable;
+
+    impl Filter for Unreliable {
+        fn apply(&mut self, record: Record) -> Vec<Record> {
+            self.try_apply(record).unwrap_or_else(|_| Vec::new())
+        }
+
+        fn try_apply(&mut self, record: Record) -> Result<Vec<Record>, Failure> {
+            if record.find("panic").is_some() {
+                panic!("scripted panic");
+            }
+            if record.find("fail").is_some() {
+                return Err(Failure::new(record, "scripted failure".to_string()));
+            }
+            Ok(vec![record])
+        }
+    }
+
+    #[test]
+    fn dead_letter_records_filter_fails_on() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let dead = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("unreliable", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_filter(Box::new(Guard::new(Box::new(Unreliable), "test_on_error_pipeline", filter::OnError::DeadLetter)));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_dead_letter(Box::new(dead.clone()));
+
+        for &key in ["ok", "fail", "panic"].iter() {
+            tx.send(record(vec![(key, string("value"))])).unwrap();
+        }
+        drop(tx);
+        pipeline.run();
+
+        assert_eq!(vec![record(vec![("ok", string("value"))])], collector.records());
+        let dead = dead.records();
+        assert_eq!(2, dead.len());
+        assert!(reason(&dead[0]).ends_with("Unreliable' filter failed: scripted failure"));
+        assert!(reason(&dead[1]).ends_with("Unreliable' filter failed: panicked: scripted panic"));
+        assert_eq!(Some(&string("filter")), dead[0].find("_dead_letter_stage"));
+
+        let registry = metrics::registry();
+        assert_eq!(1, registry.counter("filter.test_on_error_pipeline.on_error.dead_letter").get());
+        assert_eq!(2, registry.counter("filter.test_on_error_pipeline.errors").get());
+        assert_eq!(1, registry.counter("filter.test_on_error_pipeline.panics").get());
+    }
+
+    #[test]
+    fn quarantine_poison_record_crashing_filter() {
+        let path = env::temp_dir().join("logdrop-poison-pipeline");
+        let _ = fs::remove_file(&path);
+
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let dead = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("poisoned", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_filter(Box::new(Guard::new(Box::new(Unreliable), "test_poison_pipeline", filter::OnError::DeadLetter)));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_dead_letter(Box::new(dead.clone()));
+        pipeline.set_poison(poison::Settings::new(&path));
+
+        let blob: String = repeat('x').take(1000).collect();
+        let payload = record(vec![("panic", string(&blob)), ("message", string("poison"))]);
+
+        // The poison record comes back among others, like a requeued dead letter does.
+        for id in 0..20 {
+            if id % 4 == 0 {
+                let mut requeued = payload.clone();
+                requeued.insert("_requeue_count".to_string(), RecordItem::U64(id / 4));
+                tx.send(requeued).unwrap();
+            }
+            tx.send(record(vec![("id", RecordItem::U64(id))])).unwrap();
+        }
+        drop(tx);
+        pipeline.run();
+
+        let (alerts, records): (Vec<Record>, Vec<Record>) = collector.records().into_iter().partition(|record| {
+            record.find("type") == Some(&string("logdrop_alert"))
+        });
+        let expected: Vec<Record> = (0..20).map(|id| record(vec![("id", RecordItem::U64(id))])).collect();
+        assert_eq!(expected, records);
+
+        // Panicked twice before being quarantined, never reaching the filter afterwards.
+        assert_eq!(2, dead.records().len());
+        assert_eq!(3, metrics::registry().counter("filter.test_poison_pipeline.panics").get());
+
+        let mut reader = Reader::open(&path).unwrap();
+        let entry = reader.next().unwrap().unwrap();
+        assert_eq!(Some(&string("poison")), entry.find("message"));
+        assert_eq!(Some(&string("filter.test_poison_pipeline")), entry.find(poison::COMPONENT));
+        assert_eq!(Some(&RecordItem::U64(3)), entry.find(poison::FAILURES));
+        assert_eq!(None, reader.next().unwrap());
+
+        assert_eq!(1, alerts.len());
+        assert_eq!(Some(&string(poison::ALERT)), alerts[0].find("alert"));
+        let sample = match alerts[0].find("sample") {
+            Some(&RecordItem::Object(ref sample)) => sample.clone(),
+            other => panic!("expected sample, found {:?}", other),
+        };
+        assert_eq!(Some(&string("poison")), sample.get("message"));
+        let truncated: String = repeat('x').take(256).chain("...".chars()).collect();
+        assert_eq!(Some(&string(&truncated)), sample.get("panic"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Output writing records one by one, losing the connection once after writing half a batch.
+    struct HalfWritten {
+        collector: Collector,
+        fed: usize,
+    }
+
+    impl Output for HalfWritten {
+        fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+            self.fed += 1;
+            if self.fed == 3 {
+                return Err(ambiguous());
+            }
+            self.collector.feed(payload)
+        }
+    }
+
+    #[test]
+    fn suppress_rewrites_of_retried_batch() {
+        let collector = Collector::new();
+        let output = Dedup::new(Box::new(HalfWritten { collector: collector.clone(), fed: 0 }), DedupSettings::default());
+        let mut worker = Worker::new("test_dedup", Box::new(output), Delivery::AtLeastOnce);
+
+        let batch: Vec<_> = (0..4).map(|id| record(vec![("idempotency_id", string(&id.to_string()))])).collect();
+        for payload in batch.iter() {
+            worker.feed(payload);
+        }
+        worker.flush();
+        // Fed again as a whole, e.g. replayed by the sender.
+        for payload in batch.iter() {
+            worker.feed(payload);
+        }
+        worker.flush();
+
+        assert_eq!(batch, collector.records());
+        assert_eq!(1, metrics::registry().counter("output.test_dedup.delivery.possibly_duplicated").get());
+    }
+
+    /// Runs a pipeline with a single output under the policy, making the output unavailable with
+    /// the first record sent.
+    fn run_unavailable(policy: Unavailable) -> (Sender<Record>, Arc<AtomicBool>, Collector, JoinHandle<()>) {
+        run_flaky(move |pipeline| pipeline.set_unavailable(policy))
+    }
+
+    /// Runs a pipeline with a single output configured further by the function, making the output
+    /// unavailable with the first record sent.
+    fn run_flaky<F>(configure: F) -> (Sender<Record>, Arc<AtomicBool>, Collector, JoinHandle<()>)
+        where F: FnOnce(&mut Pipeline)
+    {
+        run_flaky_input("flaky", configure)
+    }
+
+    /// Runs a flaky pipeline like `run_flaky`, with the input of the given name.
+    fn run_flaky_input<F>(name: &str, configure: F) -> (Sender<Record>, Arc<AtomicBool>, Collector, JoinHandle<()>)
+        where F: FnOnce(&mut Pipeline)
+    {
+        let (input, tx) = Feeder::new();
+        let up = Arc::new(AtomicBool::new(false));
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input(name, Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Flaky::new(up.clone(), collector.clone())));
+        pipeline.set_flush_interval(50);
+        configure(&mut pipeline);
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        tx.send(record(vec![("id", string("lost"))])).unwrap();
+        thread::sleep_ms(200);
+
+        (tx, up, collector, pipeline)
+    }
+
+    /// Describes records by their `id`, or by the `error` for pipeline events.
+    fn describe(collector: &Collector) -> Vec<String> {
+        collector.records().iter().map(|record| {
+            match (record.find("id"), record.find("error")) {
+                (Some(&RecordItem::String(ref id)), _) => id.clone(),
+                (_, Some(&RecordItem::String(ref error))) => error.clone(),
+                other => panic!("unexpected record: {:?}", other),
+            }
+        }).collect()
+    }
+
+    /// Waits up to a couple of seconds for the collector to receive the given number of records.
+    fn wait_for(collector: &Collector, count: usize) {
+        for _ in 0..200 {
+            if collector.records().len() >= count {
+                return;
+            }
+            thread::sleep_ms(10);
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn drop_records_while_all_outputs_unavailable() {
+        let dropped = metrics::registry().counter("pipeline.unavailable.dropped");
+        let before = dropped.get();
+
+        let (tx, up, collector, pipeline) = run_unavailable(Unavailable::Drop);
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        tx.send(record(vec![("id", string("second"))])).unwrap();
+        thread::sleep_ms(100);
+        assert_eq!(before + 2, dropped.get());
+
+        up.store(true, Ordering::SeqCst);
+        wait_for(&collector, 2);
+        tx.send(record(vec![("id", string("third"))])).unwrap();
+        drop(tx);
+        pipeline.join().unwrap();
+
+        assert_eq!(strings(&["all_outputs_unavailable", "outputs_available", "third"]), describe(&collector));
+    }
+
+    #[test]
+    fn spill_records_while_all_outputs_unavailable() {
+        let dir = env::temp_dir().join("logdrop-pipeline-unavailable");
+        let _ = fs::remove_dir_all(&dir);
+
+        let spool = Spool::open(&dir, 1 << 20).unwrap();
+        let (tx, up, collector, pipeline) = run_unavailable(Unavailable::Spill(spool));
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        thread::sleep_ms(100);
+        tx.send(record(vec![("id", string("second"))])).unwrap();
+        thread::sleep_ms(100);
+        assert!(Spool::open(&dir, 1 << 20).unwrap().len() > 0);
+        assert!(collector.records().is_empty());
+
+        up.store(true, Ordering::SeqCst);
+        wait_for(&collector, 4);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        assert_eq!(strings(&["all_outputs_unavailable", "first", "second", "outputs_available"]), describe(&collector));
+        assert_eq!(0, Spool::open(&dir, 1 << 20).unwrap().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replay_held_records_to_every_output() {
+        let dir = env::temp_dir().join("logdrop-pipeline-replay");
+        let _ = fs::remove_dir_all(&dir);
+
+        let spool = Spool::open(&dir, 1 << 20).unwrap();
+        let other_up = Arc::new(AtomicBool::new(false));
+        let other = Collector::new();
+        let (up_other, fed_other) = (other_up.clone(), other.clone());
+        let (tx, up, collector, pipeline) = run_flaky(move |pipeline| {
+            pipeline.add_output(Box::new(Flaky::new(up_other, fed_other)));
+            pipeline.set_unavailable(Unavailable::Spill(spool));
+        });
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        thread::sleep_ms(100);
+
+        other_up.store(true, Ordering::SeqCst);
+        up.store(true, Ordering::SeqCst);
+        wait_for(&collector, 3);
+        wait_for(&other, 3);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        let expected = strings(&["all_outputs_unavailable", "first", "outputs_available"]);
+        assert_eq!(expected, describe(&collector));
+        assert_eq!(expected, describe(&other));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn guard(cooldown: u64) -> breaker::Settings {
+        breaker::Settings { failures: 1, cooldown: cooldown, max_cooldown: cooldown, ..breaker::Settings::default() }
+    }
+
+    #[test]
+    fn divert_records_while_breaker_open() {
+        let dead_letter = Collector::new();
+        let diverted = dead_letter.clone();
+        let (tx, _, collector, pipeline) = run_flaky(move |pipeline| {
+            pipeline.set_breaker(guard(60000));
+            pipeline.set_dead_letter(Box::new(diverted));
+        });
+
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        tx.send(record(vec![("id", string("second"))])).unwrap();
+        drop(tx);
+        pipeline.join().unwrap();
+
+        assert!(collector.records().is_empty());
+        assert_eq!(strings(&["first", "second"]), describe(&dead_letter));
+        for record in dead_letter.records().iter() {
+            let reason = record.find("_dead_letter_reason").and_then(|reason| reason.as_str()).unwrap();
+            assert!(reason.ends_with("output breaker is open"));
+            assert_eq!(Some(&string("output")), record.find("_dead_letter_stage"));
+        }
+    }
+
+    #[test]
+    fn replay_spool_once_breaker_closes() {
+        let dir = env::temp_dir().join("logdrop-pipeline-breaker");
+        let _ = fs::remove_dir_all(&dir);
+
+        let spool = Spool::open(&dir, 1 << 20).unwrap();
+        let (tx, up, collector, pipeline) = run_flaky(move |pipeline| {
+            pipeline.set_breaker(guard(200));
+            pipeline.set_unavailable(Unavailable::Spill(spool));
+        });
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        tx.send(record(vec![("id", string("second"))])).unwrap();
+        thread::sleep_ms(100);
+        assert!(collector.records().is_empty());
+
+        // Not fed before the cool-down passes and the probe closes the breaker.
+        up.store(true, Ordering::SeqCst);
+        wait_for(&collector, 4);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        assert_eq!(strings(&["all_outputs_unavailable", "first", "second", "outputs_available"]), describe(&collector));
+        assert_eq!(0, Spool::open(&dir, 1 << 20).unwrap().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn block_inputs_while_all_outputs_unavailable() {
+        let (tx, up, collector, pipeline) = run_flaky_input("test_block", |pipeline| pipeline.set_unavailable(Unavailable::Block));
+        let sent = metrics::registry().counter("input.test_block.records");
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        thread::sleep_ms(200);
+        let before = sent.get();
+        tx.send(record(vec![("id", string("second"))])).unwrap();
+        tx.send(record(vec![("id", string("third"))])).unwrap();
+        thread::sleep_ms(200);
+        assert!(collector.records().is_empty());
+        // The input is held back rather than piling records up in the pipeline.
+        assert_eq!(before, sent.get());
+
+        up.store(true, Ordering::SeqCst);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        assert_eq!(strings(&["all_outputs_unavailable", "outputs_available", "first", "second", "third"]), describe(&collector));
+    }
+
+    #[test]
+    fn resume_blocked_inputs_once_stopped() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("test_stop_blocked", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(Flaky::new(Arc::new(AtomicBool::new(false)), collector.clone())));
+        pipeline.set_flush_interval(50);
+        pipeline.set_unavailable(Unavailable::Block);
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        for id in ["lost", "first", "second"].iter() {
+            tx.send(record(vec![("id", string(id))])).unwrap();
+            thread::sleep_ms(200);
+        }
+
+        // The input is paused while sending the last record, until the pipeline is stopped.
+        handle.stop(0);
+        drop(tx);
+        pipeline.join().unwrap();
+
+        assert!(collector.records().is_empty());
+    }
+
+    #[test]
+    fn preserve_connection_order_in_file_output() {
+        const CONNECTIONS: usize = 4;
+        const RECORDS: usize = 500;
+
+        let path = env::temp_dir().join("logdrop-pipeline-order.log");
+        let _ = fs::remove_file(&path);
+
+        // The bound listener takes connections before the input starts accepting.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("tcp", Box::new(TcpInput::from_listener(listener)), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(FileOutput::new(path.to_str().unwrap(), "{connection} {seq}").unwrap()));
+        let dir = env::temp_dir().join("logdrop-pipeline-order");
+        fs::create_dir_all(&dir).unwrap();
+        pipeline.set_elastic(Settings { capacity: 16, low: 4, grace: 4, dir: dir });
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        let senders: Vec<_> = (0..CONNECTIONS).map(|connection| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                for seq in 0..RECORDS {
+                    let payload = record(vec![
+                        ("connection", string(&connection.to_string())),
+                        ("seq", string(&seq.to_string())),
+                    ]);
+                    stream.write_all(&msgpack::encode(&payload)).unwrap();
+                }
+            })
+        }).collect();
+        for sender in senders.into_iter() {
+            sender.join().unwrap();
+        }
+
+        let mut content = String::new();
+        for _ in 0..500 {
+            content.clear();
+            File::open(&path).and_then(|mut file| file.read_to_string(&mut content)).unwrap_or(0);
+            if content.lines().count() >= CONNECTIONS * RECORDS {
+                break;
+            }
+            thread::sleep_ms(10);
+        }
+        handle.stop(0);
+        pipeline.join().unwrap();
+
+        let mut last = vec![None; CONNECTIONS];
+        for line in content.lines() {
+            let mut parts = line.split(' ');
+            let connection: usize = parts.next().unwrap().parse().unwrap();
+            let seq: usize = parts.next().unwrap().parse().unwrap();
+            assert!(last[connection].map_or(true, |last| last < seq), "connection {} reordered at {}", connection, seq);
+            last[connection] = Some(seq);
+        }
+        assert_eq!(vec![Some(RECORDS - 1); CONNECTIONS], last);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Runs records with and without the source field through an input with a peer and through a
+    /// peerless one, writing them into `{@source}` files as well.
+    ///
+    /// Returns the `_source` key of each record as one of `app`, `peer`, `remote` and `local`,
+    /// checking that the per-source metrics and files agree with it. Actual names are unique per
+    /// policy, keeping counters apart while tests run in parallel.
+    fn run_sourced(authority: Authority) -> Vec<(String, String)> {
+        let tag = format!("{:?}", authority).to_lowercase();
+        let names = vec![
+            ("app", format!("{}-app", tag)),
+            ("peer", format!("{}-peer", tag)),
+            ("remote", format!("{}-remote", tag)),
+            ("local", format!("{}-local", tag)),
+        ];
+
+        let dir = env::temp_dir().join(format!("logdrop-pipeline-source-{}", tag));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let (remote, remote_tx) = Feeder::new();
+        let (local, local_tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input(&names[2].1, Box::new(remote.peer(&names[1].1)), Box::new(MessagePack::default()), None);
+        pipeline.add_input(&names[3].1, Box::new(local), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.add_output(Box::new(FileOutput::new(&format!("{}/{{@source}}.log", dir.display()), "{id}").unwrap()));
+        pipeline.set_source(Resolution::new(authority));
+
+        let counters: Vec<_> = names.iter().map(|&(_, ref key)| {
+            let counter = metrics::registry().counter(&format!("pipeline.source.{}.records", key));
+            (counter.get(), counter)
+        }).collect();
+
+        let ids = ["remote-field", "remote-bare", "local-field", "local-bare"];
+        remote_tx.send(record(vec![("id", string(ids[0])), ("source", string(&names[0].1))])).unwrap();
+        remote_tx.send(record(vec![("id", string(ids[1]))])).unwrap();
+        local_tx.send(record(vec![("id", string(ids[2])), ("source", string(&names[0].1))])).unwrap();
+        local_tx.send(record(vec![("id", string(ids[3]))])).unwrap();
+        drop(remote_tx);
+        drop(local_tx);
+
+        pipeline.run();
+
+        let mut sources: Vec<(String, String)> = collector.records().iter().map(|record| {
+            match (record.find("id"), source::key(record)) {
+                (Some(&RecordItem::String(ref id)), Some(key)) => (id.clone(), key.to_string()),
+                other => panic!("unexpected record: {:?}", other),
+            }
+        }).collect();
+        sources.sort_by(|a, b| ids.iter().position(|id| *id == a.0).cmp(&ids.iter().position(|id| *id == b.0)));
+
+        for (&(_, ref key), &(before, ref counter)) in names.iter().zip(counters.iter()) {
+            let expected = sources.iter().filter(|&&(_, ref source)| source == key).count();
+            assert_eq!(expected, counter.get() - before);
+
+            let mut content = String::new();
+            if expected > 0 {
+                File::open(dir.join(format!("{}.log", key))).unwrap().read_to_string(&mut content).unwrap();
+            }
+            assert_eq!(expected, content.lines().count());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        sources.into_iter().map(|(id, key)| {
+            let name = names.iter().find(|&&(_, ref name)| *name == key).map(|&(name, _)| name).unwrap();
+            (id, name.to_string())
+        }).collect()
+    }
+
+    fn pairs(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values.iter().map(|&(id, key)| (id.to_string(), key.to_string())).collect()
+    }
+
+    #[test]
+    fn resolve_source_by_field() {
+        let expected = pairs(&[("remote-field", "app"), ("remote-bare", "peer"), ("local-field", "app"), ("local-bare", "local")]);
+        assert_eq!(expected, run_sourced(Authority::Field));
+    }
+
+    #[test]
+    fn resolve_source_by_peer() {
+        let expected = pairs(&[("remote-field", "peer"), ("remote-bare", "peer"), ("local-field", "local"), ("local-bare", "local")]);
+        assert_eq!(expected, run_sourced(Authority::Peer));
+    }
+
+    #[test]
+    fn resolve_source_by_input() {
+        let expected = pairs(&[("remote-field", "remote"), ("remote-bare", "remote"), ("local-field", "local"), ("local-bare", "local")]);
+        assert_eq!(expected, run_sourced(Authority::Input));
+    }
+
+    #[test]
+    fn describe_fields_read_past_validation() {
+        let labels = vec!["fast".to_string(), "slow".to_string()];
+
+        let mut pipeline = Pipeline::new(Validation::default());
+        pipeline.add_filter(Box::new(Bucketize::new("latency", "band", vec![100.0], labels, filter::Missing::Pass).unwrap()));
+        pipeline.add_output(Box::new(FileOutput::new("/tmp/{host}.log", "{@source} {message}").unwrap()));
+        pipeline.set_source(Resolution::new(Authority::Field).field("app"));
+
+        let mut fields: Vec<String> = pipeline.describe_fields().unwrap().into_iter().collect();
+        fields.sort();
+        assert_eq!(vec!["_source", "app", "host", "latency", "message", "source"], fields);
+
+        pipeline.add_output(Box::new(Collector::new()));
+        assert!(pipeline.describe_fields().is_err());
+    }
+
+    #[test]
+    fn reload_filter_chain_keeping_held_records() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let dead_letter = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("reloaded", Box::new(input), Box::new(MessagePack::default()),
+            Some(Validation::new(vec!["id".to_string()], Missing::DeadLetter)));
+        pipeline.add_filter(Box::new(Coalesce::new(Vec::new())));
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_dead_letter(Box::new(dead_letter.clone()));
+        pipeline.set_flush_interval(60000);
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        // The repeat stays held back by the coalescing filter. The dead-lettered record bypasses
+        // the filter and marks the repeat as processed.
+        tx.send(record(vec![("id", string("first"))])).unwrap();
+        tx.send(record(vec![("id", string("held"))])).unwrap();
+        tx.send(record(vec![("id", string("held"))])).unwrap();
+        tx.send(record(vec![("marker", string("processed"))])).unwrap();
+        wait_for(&collector, 2);
+        wait_for(&dead_letter, 1);
+
+        let labels = vec!["reloaded".to_string()];
+        let chain = Bucketize::new("size", "chain", Vec::new(), labels, filter::Missing::Label("reloaded".to_string())).unwrap();
+        let mut update = Pipeline::new(Validation::default());
+        update.add_filter(Box::new(chain));
+        update.add_output(Box::new(Collector::new()));
+
+        let reload = Reload { pipeline: update, inputs: Vec::new(), outputs: vec![Some(0)], filters: true, restart: Vec::new() };
+        handle.reload(reload).unwrap();
+
+        tx.send(record(vec![("id", string("after"))])).unwrap();
+        drop(tx);
+        handle.close();
+        pipeline.join().unwrap();
+
+        let expected = vec![
+            record(vec![("id", string("first"))]),
+            record(vec![("id", string("held"))]),
+            record(vec![("id", string("held")), ("repeated", RecordItem::F64(2.0))]),
+            record(vec![("id", string("after")), ("chain", string("reloaded"))]),
+        ];
+        assert_eq!(expected, collector.records());
+        assert!(handle.reload(Reload { pipeline: Pipeline::new(Validation::default()), inputs: Vec::new(),
+            outputs: Vec::new(), filters: false, restart: Vec::new() }).is_err());
+    }
+
+    #[test]
+    fn reload_carrying_pending_correlations() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+        let correlate = || {
+            let parts = vec!["begin".to_string(), "end".to_string()];
+            Box::new(Correlate::new("request_id", "event", parts, 60000, Arc::new(SystemClock)))
+        };
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("reloaded", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_filter(correlate());
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_flush_interval(60000);
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        tx.send(record(vec![("request_id", string("a")), ("event", string("begin")), ("path", string("/"))])).unwrap();
+        tx.send(record(vec![("request_id", string("b")), ("event", string("begin"))])).unwrap();
+        tx.send(record(vec![("id", string("unrelated"))])).unwrap();
+        wait_for(&collector, 1);
+
+        let mut update = Pipeline::new(Validation::default());
+        update.add_filter(correlate());
+        update.add_output(Box::new(Collector::new()));
+        let reload = Reload { pipeline: update, inputs: Vec::new(), outputs: vec![Some(0)], filters: true, restart: Vec::new() };
+        handle.reload(reload).unwrap();
+
+        // The pending record merges with its part arriving after the reload, while the one
+        // still incomplete at shutdown is emitted partial.
+        tx.send(record(vec![("request_id", string("a")), ("event", string("end")), ("status", RecordItem::U64(200))])).unwrap();
+        drop(tx);
+        handle.close();
+        pipeline.join().unwrap();
+
+        let expected = vec![
+            record(vec![("id", string("unrelated"))]),
+            record(vec![("request_id", string("a")), ("path", string("/")), ("status", RecordItem::U64(200))]),
+            record(vec![
+                ("request_id", string("b")),
+                ("_partial", RecordItem::Bool(true)),
+                ("_parts", RecordItem::Array(vec![string("begin")])),
+            ]),
+        ];
+        assert_eq!(expected, collector.records());
+    }
+
+    #[test]
+    fn carry_soft_state_over_restart() {
+        let dir = env::temp_dir().join("logdrop-test-pipeline-snapshot");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let run = |records: Vec<Record>| -> Vec<Record> {
+            let (input, tx) = Feeder::new();
+            let collector = Collector::new();
+
+            let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+            pipeline.add_input("restarted", Box::new(input), Box::new(MessagePack::default()), None);
+            pipeline.add_filter(Box::new(Coalesce::new(vec!["id".to_string()])));
+            pipeline.add_output(Box::new(Dedup::new(Box::new(collector.clone()), DedupSettings::default())));
+            pipeline.set_flush_interval(60000);
+            pipeline.set_snapshot(snapshot::Settings { dir: dir.clone(), max_age: 60000 });
+            let pipeline = thread::spawn(move || pipeline.run());
+
+            for payload in records.into_iter() {
+                tx.send(payload).unwrap();
+            }
+            drop(tx);
+            pipeline.join().unwrap();
+
+            collector.records()
+        };
+
+        let payload = |id: &str, key: &str| record(vec![("id", string(id)), ("idempotency_id", string(key))]);
+
+        // The repeat of the second record stays held back by the coalescing filter.
+        assert_eq!(vec![payload("a", "1"), payload("b", "2")], run(vec![payload("a", "1"), payload("b", "2"), payload("b", "3")]));
+
+        // The held repeat collapses with the next one, while the rewrite of the first record is
+        // suppressed.
+        let mut expected = payload("b", "4");
+        expected.insert("repeated".to_string(), RecordItem::F64(3.0));
+        assert_eq!(vec![expected], run(vec![payload("b", "4"), payload("a", "1")]));
+    }
+
+    #[test]
+    fn send_startup_record() {
+        let (input, tx) = Feeder::new();
+        let collector = Collector::new();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("announced", Box::new(input), Box::new(MessagePack::default()), None);
+        pipeline.add_output(Box::new(collector.clone()));
+        pipeline.set_startup_record(true);
+        drop(tx);
+        pipeline.run();
+
+        let records = collector.records();
+        assert_eq!(1, records.len());
+        assert_eq!(Some("logdrop started"), records[0].find("message").and_then(|value| value.as_str()));
+        assert_eq!(Some(version::VERSION), records[0].find("version").and_then(|value| value.as_str()));
+        let inputs = RecordItem::Array(vec![string("announced")]);
+        assert_eq!(Some(&inputs), records[0].find("inputs"));
+    }
+
+    #[test]
+    fn produce_identical_files_in_deterministic_mode() {
+        let run = |name: &str| -> String {
+            let path = env::temp_dir().join(name);
+            let _ = fs::remove_file(&path);
+
+            let (input, tx) = Feeder::new();
+            let mut rates = HashMap::new();
+            rates.insert("debug".to_string(), 0.3);
+
+            let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+            pipeline.add_input("replay", Box::new(input), Box::new(MessagePack::default()), None);
+            pipeline.add_filter(Box::new(LevelSample::new(rates, 0.5).unwrap()));
+            pipeline.add_output(Box::new(FileOutput::new(path.to_str().unwrap(), "{id} {level}").unwrap()));
+            pipeline.set_deterministic(determinism::Settings { seed: 42 });
+
+            for id in 0..200 {
+                let level = if id % 2 == 0 { "debug" } else { "info" };
+                tx.send(record(vec![("id", string(&id.to_string())), ("level", string(level))])).unwrap();
+            }
+            drop(tx);
+            pipeline.run();
+
+            let mut content = String::new();
+            File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+            content
+        };
+
+        let first = run("logdrop-deterministic-first.log");
+        let second = run("logdrop-deterministic-second.log");
+        assert_eq!(first, second);
+
+        let lines = first.lines().count();
+        assert!(lines > 0 && lines < 200);
+    }
+}
+
+#[cfg(test)]
+mod benchmarking {
+    extern crate test;
+
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use self::test::Bencher;
+
+    use super::super::{Record, RecordItem};
+    use super::super::codec::{Codec, Json, MessagePack};
+    use super::super::codec::{json, msgpack};
+    use super::super::input::TcpInput;
+    use super::super::output::{Error, Null, Output};
+    use super::super::testing::{record, string};
+    use super::super::validation::{Missing, Validation};
+    use super::Pipeline;
+
+    /// Records sent per iteration, so that records per second are `RECORDS * 1e9 / ns/iter`.
+    const RECORDS: usize = 1000;
+
+    /// Null output counting delivered records, letting an iteration wait for its batch.
+    struct Counted {
+        inner: Null,
+        delivered: Arc<AtomicUsize>,
+    }
+
+    impl Output for Counted {
+        fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+            self.delivered.fetch_add(1, Ordering::SeqCst);
+            self.inner.feed(payload)
+        }
+
+        fn describe_fields(&self) -> Option<Vec<String>> {
+            self.inner.describe_fields()
+        }
+    }
+
+    fn fixture(seq: usize) -> Record {
+        record(vec![
+            ("timestamp", RecordItem::F64(1420070400.0 + seq as f64)),
+            ("host", string("web-01.example.com")),
+            ("level", string("info")),
+            ("message", string(&format!("GET /api/v1/users/{} 200", seq))),
+            ("duration", RecordItem::F64(0.125)),
+            ("seq", RecordItem::U64(seq as u64)),
+        ])
+    }
+
+    fn json_line(record: &Record) -> Vec<u8> {
+        let mut data = json::encode(record).into_bytes();
+        data.push(b'\n');
+        data
+    }
+
+    /// Measures records going from a TCP client through decoding and dispatching to a null output.
+    ///
+    /// The same batch is sent over a single connection kept across iterations, so that neither the
+    /// record order nor connection setup varies between runs, and each iteration waits until the
+    /// whole batch is delivered.
+    fn throughput(b: &mut Bencher, codec: Box<Codec>, encode: fn(&Record) -> Vec<u8>) {
+        let mut data = Vec::new();
+        for seq in 0..RECORDS {
+            data.extend(encode(&fixture(seq)).into_iter());
+        }
+        let delivered = Arc::new(AtomicUsize::new(0));
+
+        // The bound listener takes the connection before the input starts accepting.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut pipeline = Pipeline::new(Validation::new(Vec::new(), Missing::Pass));
+        pipeline.add_input("bench", Box::new(TcpInput::from_listener(listener)), codec, None);
+        pipeline.add_output(Box::new(Counted { inner: Null, delivered: delivered.clone() }));
+        let handle = pipeline.handle();
+        let pipeline = thread::spawn(move || pipeline.run());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut expected = 0;
+        b.bytes = data.len() as u64;
+        b.iter(|| {
+            stream.write_all(&data).unwrap();
+            expected += RECORDS;
+            while delivered.load(Ordering::SeqCst) < expected {
+                thread::yield_now();
+            }
+        });
+
+        drop(stream);
+        handle.stop(0);
+        pipeline.join().unwrap();
+    }
+
+    #[bench]
+    fn tcp_json_to_null(b: &mut Bencher) {
+        throughput(b, Box::new(Json::default()), json_line);
+    }
+
+    #[bench]
+    fn tcp_msgpack_to_null(b: &mut Bencher) {
+        throughput(b, Box::new(MessagePack::default()), msgpack::encode);
+    }
+}
@@ -0,0 +1,484 @@
+//! Detection of poison records, ones crashing the same component over and over.
+//!
+//! Policies like `on_error` deal with a record failing once, while requeueing and retries give a
+//! record failing persistently another go. A pathological record making a filter or an output
+//! panic therefore keeps crashing it on every attempt. The detector remembers records that
+//! components panicked on by the hash of their content, and once a record has caused the given
+//! number of panics it is quarantined: written to the quarantine file with the failure details
+//! and excluded from processing from then on, while the alert record carrying its truncated
+//! sample is sent through the pipeline, so that the crash can be reproduced.
+//!
+//! Fields the dead-letter and requeue machinery stamps records with are ignored by the hash, so
+//! a record is recognized whichever way it came back. The number of remembered records is
+//! bounded and each one is forgotten once it hasn't failed for the expiry time.
+//!
+//! Filters see records by the hash taken once at ingest, before the chain, so that a watched
+//! chain hashes each record a single time and quarantined records never reach it. Outputs hash
+//! records they panic on, and ones they're fed only while some record is quarantined.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+
+use super::{Record, RecordItem};
+use super::alert;
+use super::clock::{Clock, SystemClock};
+use super::codec::json;
+use super::config::{Config, Error, FromConfig};
+use super::deadletter;
+use super::digest;
+use super::metrics;
+use super::spool::Writer;
+
+/// Name of the alert announcing a quarantined record.
+pub const ALERT: &'static str = "poison_record";
+/// Field of quarantine entries holding the record fingerprint.
+pub const FINGERPRINT: &'static str = "_poison_fingerprint";
+/// Field of quarantine entries holding the component the record crashed.
+pub const COMPONENT: &'static str = "_poison_component";
+/// Field of quarantine entries holding the last panic message.
+pub const REASON: &'static str = "_poison_reason";
+/// Field of quarantine entries holding the number of panics the record caused.
+pub const FAILURES: &'static str = "_poison_failures";
+
+/// Poison record detection settings, see `Pipeline::set_poison`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// File quarantined records are appended to.
+    pub quarantine: PathBuf,
+    /// Number of panics after which the record is quarantined.
+    pub attempts: u32,
+    /// Maximum number of remembered records.
+    pub capacity: usize,
+    /// Time in milliseconds a record is remembered for since its last panic.
+    pub expiry: u64,
+    /// Maximum number of bytes of strings in the sample of the alert record.
+    pub sample: usize,
+    /// Maximum nesting of objects and arrays in the sample of the alert record.
+    pub depth: usize,
+}
+
+impl Settings {
+    pub fn new(quarantine: &Path) -> Settings {
+        Settings {
+            quarantine: quarantine.to_path_buf(),
+            attempts: 3,
+            capacity: 1024,
+            expiry: 3600 * 1000,
+            sample: 256,
+            depth: 4,
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let default = Settings::new(Path::new(&try!(config.string("quarantine"))));
+        let attempts = try!(config.u64_or("attempts", default.attempts as u64));
+        let capacity = try!(config.u64_or("capacity", default.capacity as u64));
+
+        if attempts == 0 || capacity == 0 {
+            return Err(Error::Invalid("poison 'attempts' and 'capacity' must be positive".to_string()));
+        }
+
+        Ok(Settings {
+            attempts: attempts as u32,
+            capacity: capacity as usize,
+            expiry: try!(config.u64_or("expiry", default.expiry)),
+            sample: try!(config.u64_or("sample", default.sample as u64)) as usize,
+            depth: try!(config.u64_or("depth", default.depth as u64)) as usize,
+            .. default
+        })
+    }
+}
+
+/// Returns the hash of the record content, leaving out fields stamped by the dead-letter path.
+pub fn fingerprint(record: &Record) -> String {
+    let stamped = [deadletter::REASON, deadletter::STAGE, deadletter::REQUEUED];
+    if !stamped.iter().any(|&name| record.find(name).is_some()) {
+        return digest::hex(&digest::sha256(json::encode_sorted(record).as_bytes()));
+    }
+
+    let mut record = record.clone();
+    for name in stamped.iter() {
+        record.remove(name);
+    }
+    digest::hex(&digest::sha256(json::encode_sorted(&record).as_bytes()))
+}
+
+/// Returns the copy of the value with long strings cut and deep values replaced, see `Settings`.
+fn sample(value: &RecordItem, limit: usize, depth: usize) -> RecordItem {
+    match *value {
+        RecordItem::String(..) | RecordItem::Text(..) => {
+            let value = value.as_str().unwrap();
+            if value.len() <= limit {
+                return RecordItem::String(value.to_string());
+            }
+
+            let mut end = limit;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            RecordItem::String(format!("{}...", &value[..end]))
+        }
+        RecordItem::Bytes(ref value) if value.len() > limit => RecordItem::Bytes(value[..limit].to_vec()),
+        RecordItem::Array(..) | RecordItem::Object(..) if depth == 0 => RecordItem::String("...".to_string()),
+        RecordItem::Array(ref values) => {
+            RecordItem::Array(values.iter().map(|value| sample(value, limit, depth - 1)).collect())
+        }
+        RecordItem::Object(ref values) => {
+            RecordItem::Object(values.iter().map(|(key, value)| (key.clone(), sample(value, limit, depth - 1))).collect())
+        }
+        ref value => value.clone(),
+    }
+}
+
+struct Suspect {
+    failures: u32,
+    /// Time of the last panic in milliseconds.
+    last: u64,
+}
+
+/// Record the filter chain is processing on this thread, see `Detector::enter`.
+struct Current {
+    key: String,
+    /// Copy of the record, taken only if its next panic gets it quarantined.
+    copy: Option<Record>,
+}
+
+thread_local!(static CURRENT: RefCell<Option<Current>> = RefCell::new(None));
+
+/// Forgets the record entered into the filter chain on this thread, see `Detector::enter`.
+pub fn leave() {
+    CURRENT.with(|current| *current.borrow_mut() = None);
+}
+
+/// Remembers records components panicked on, quarantining ones doing it too many times, see the
+/// module documentation.
+///
+/// The detector is shared by the filter chain and output workers, checking records under a read
+/// lock and only while some record is quarantined or suspected, so that it costs nothing but
+/// the hash at ingest until a component panics.
+///
+/// Quarantined records are counted in `poison.quarantined`, their later occurrences excluded
+/// from processing in `poison.excluded`.
+pub struct Detector {
+    settings: Settings,
+    clock: Arc<Clock>,
+    suspects: RwLock<HashMap<String, Suspect>>,
+    /// Number of remembered records, and of quarantined ones among them.
+    suspected: AtomicUsize,
+    quarantined: AtomicUsize,
+    writer: Mutex<Option<Writer>>,
+    alerts: Mutex<Sender<Record>>,
+}
+
+impl Detector {
+    /// Creates the detector sending alert records through the given channel.
+    pub fn new(settings: Settings, alerts: Sender<Record>) -> Detector {
+        Detector::with_clock(settings, alerts, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(settings: Settings, alerts: Sender<Record>, clock: Arc<Clock>) -> Detector {
+        Detector {
+            settings: settings,
+            clock: clock,
+            suspects: RwLock::new(HashMap::new()),
+            suspected: AtomicUsize::new(0),
+            quarantined: AtomicUsize::new(0),
+            writer: Mutex::new(None),
+            alerts: Mutex::new(alerts),
+        }
+    }
+
+    /// Returns true if the record is quarantined and must not be processed.
+    ///
+    /// Records are hashed only while some record is quarantined.
+    pub fn excluded(&self, record: &Record) -> bool {
+        if self.quarantined.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+
+        self.excluded_by(&fingerprint(record))
+    }
+
+    fn excluded_by(&self, key: &str) -> bool {
+        if self.quarantined.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+
+        let now = self.clock.now();
+        let excluded = match self.suspects.read().unwrap().get(key) {
+            Some(suspect) => suspect.failures >= self.settings.attempts && now.saturating_sub(suspect.last) < self.settings.expiry,
+            None => false,
+        };
+
+        if excluded {
+            metrics::registry().counter("poison.excluded").inc();
+        }
+        excluded
+    }
+
+    /// Hashes the record about to enter the filter chain on this thread, returning false if it
+    /// is quarantined and must not be processed.
+    ///
+    /// Filters panicking on the record report it by that hash, see `Detector::panicked`. The
+    /// record is copied only if its next panic gets it quarantined, so that the quarantine
+    /// entry has its content.
+    pub fn enter(&self, record: &Record) -> bool {
+        let key = fingerprint(record);
+        if self.excluded_by(&key) {
+            leave();
+            return false;
+        }
+
+        let copy = if self.settings.attempts == 1 {
+            Some(record.clone())
+        } else if self.suspected.load(Ordering::SeqCst) == 0 {
+            None
+        } else {
+            match self.suspects.read().unwrap().get(&key) {
+                Some(suspect) if suspect.failures + 1 >= self.settings.attempts => Some(record.clone()),
+                Some(..) | None => None,
+            }
+        };
+
+        CURRENT.with(|current| *current.borrow_mut() = Some(Current { key: key, copy: copy }));
+        true
+    }
+
+    /// Remembers the panic of the component on the record entered into the filter chain on
+    /// this thread, returning true if it got the record quarantined.
+    pub fn panicked(&self, component: &str, reason: &str) -> bool {
+        match CURRENT.with(|current| current.borrow_mut().take()) {
+            Some(Current { key, copy }) => self.report(key, copy.as_ref(), component, reason),
+            None => false,
+        }
+    }
+
+    /// Remembers the panic of the component on the record, returning true if it got the record
+    /// quarantined.
+    pub fn failure(&self, record: &Record, component: &str, reason: &str) -> bool {
+        self.report(fingerprint(record), Some(record), component, reason)
+    }
+
+    fn report(&self, key: String, record: Option<&Record>, component: &str, reason: &str) -> bool {
+        let failures = {
+            let mut suspects = self.suspects.write().unwrap();
+            self.expire(&mut suspects);
+
+            let now = self.clock.now();
+            if !suspects.contains_key(&key) && suspects.len() >= self.settings.capacity {
+                self.evict(&mut suspects);
+            }
+
+            let failures = {
+                let suspect = suspects.entry(key.clone()).or_insert(Suspect { failures: 0, last: now });
+                suspect.failures += 1;
+                suspect.last = now;
+                suspect.failures
+            };
+            if failures == self.settings.attempts {
+                self.quarantined.fetch_add(1, Ordering::SeqCst);
+            }
+            self.suspected.store(suspects.len(), Ordering::SeqCst);
+            failures
+        };
+
+        if failures != self.settings.attempts {
+            debug!(target: "Poison", "record {} crashed '{}' {} times: {}", key, component, failures, reason);
+            return false;
+        }
+
+        metrics::registry().counter("poison.quarantined").inc();
+        error!(target: "Poison", "quarantining record {} having crashed '{}' {} times: {}", key, component, failures, reason);
+
+        let record = match record {
+            Some(record) => record.clone(),
+            None => Record::new(),
+        };
+        self.quarantine(&record, &key, component, reason, failures);
+        let _ = self.alerts.lock().unwrap().send(self.alert(&record, &key, component, reason, failures));
+        true
+    }
+
+    fn quarantine(&self, record: &Record, key: &str, component: &str, reason: &str, failures: u32) {
+        let mut entry = record.clone();
+        entry.insert(FINGERPRINT.to_string(), RecordItem::String(key.to_string()));
+        entry.insert(COMPONENT.to_string(), RecordItem::String(component.to_string()));
+        entry.insert(REASON.to_string(), RecordItem::String(reason.to_string()));
+        entry.insert(FAILURES.to_string(), RecordItem::U64(failures as u64));
+
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_none() {
+            match Writer::open(&self.settings.quarantine) {
+                Ok(opened) => *writer = Some(opened),
+                Err(err) => {
+                    error!(target: "Poison", "unable to open quarantine '{}': {}", self.settings.quarantine.display(), err);
+                    return;
+                }
+            }
+        }
+
+        let writer = writer.as_mut().unwrap();
+        let result = match writer.append(&entry) {
+            Ok(..) => writer.sync(),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            error!(target: "Poison", "unable to quarantine record {}: {}", key, err);
+        }
+    }
+
+    fn alert(&self, record: &Record, key: &str, component: &str, reason: &str, failures: u32) -> Record {
+        let fields = record.iter().map(|(name, value)| {
+            (name.clone(), sample(value, self.settings.sample, self.settings.depth))
+        }).collect();
+
+        let message = format!("record {} crashed '{}' {} times and is quarantined in '{}'", key, component, failures,
+            self.settings.quarantine.display());
+
+        let mut alert = Record::new();
+        alert.insert("type".to_string(), RecordItem::String(alert::TYPE.to_string()));
+        alert.insert("timestamp".to_string(), RecordItem::F64(self.clock.now() as f64 / 1000.0));
+        alert.insert("alert".to_string(), RecordItem::String(ALERT.to_string()));
+        alert.insert("state".to_string(), RecordItem::String(alert::State::Firing.name().to_string()));
+        alert.insert("fingerprint".to_string(), RecordItem::String(key.to_string()));
+        alert.insert("component".to_string(), RecordItem::String(component.to_string()));
+        alert.insert("reason".to_string(), RecordItem::String(reason.to_string()));
+        alert.insert("failures".to_string(), RecordItem::U64(failures as u64));
+        alert.insert("sample".to_string(), RecordItem::Object(fields));
+        alert.insert("message".to_string(), RecordItem::String(message));
+        alert
+    }
+
+    /// Forgets records that haven't failed for the expiry time.
+    fn expire(&self, suspects: &mut HashMap<String, Suspect>) {
+        let now = self.clock.now();
+        let expiry = self.settings.expiry;
+        let attempts = self.settings.attempts;
+        let expired: Vec<String> = suspects.iter()
+            .filter(|&(_, suspect)| now.saturating_sub(suspect.last) >= expiry)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired.iter() {
+            if suspects.remove(key).map_or(false, |suspect| suspect.failures >= attempts) {
+                self.quarantined.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Forgets the record failed the longest time ago, making room for another one.
+    fn evict(&self, suspects: &mut HashMap<String, Suspect>) {
+        let oldest = suspects.iter().fold(None, |oldest: Option<(&String, u64)>, (key, suspect)| {
+            match oldest {
+                Some((_, last)) if last <= suspect.last => oldest,
+                _ => Some((key, suspect.last)),
+            }
+        }).map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            if suspects.remove(&key).map_or(false, |suspect| suspect.failures >= self.settings.attempts) {
+                self.quarantined.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use super::super::RecordItem;
+    use super::super::clock::MockClock;
+    use super::super::deadletter::REQUEUED;
+    use super::super::spool::Reader;
+    use super::super::testing::{record, string};
+    use super::{Detector, Settings, FAILURES, fingerprint, leave};
+
+    #[test]
+    fn ignore_dead_letter_fields_in_fingerprint() {
+        let payload = record(vec![("message", string("le message"))]);
+        let mut requeued = payload.clone();
+        requeued.insert(REQUEUED.to_string(), RecordItem::U64(2));
+
+        assert_eq!(fingerprint(&payload), fingerprint(&requeued));
+        assert!(fingerprint(&payload) != fingerprint(&record(vec![("message", string("other"))])));
+    }
+
+    #[test]
+    fn forget_records_once_expired_or_evicted() {
+        let path = env::temp_dir().join("logdrop-poison-expiry");
+        let _ = fs::remove_file(&path);
+
+        let clock = Arc::new(MockClock::new(0));
+        let mut settings = Settings::new(&path);
+        settings.attempts = 2;
+        settings.capacity = 2;
+        settings.expiry = 1000;
+        let (tx, rx) = channel();
+        let detector = Detector::with_clock(settings, tx, clock.clone());
+
+        let first = record(vec![("message", string("first"))]);
+        let second = record(vec![("message", string("second"))]);
+        let third = record(vec![("message", string("third"))]);
+
+        assert!(!detector.failure(&first, "filter", "boom"));
+        clock.advance(100);
+        assert!(!detector.failure(&second, "filter", "boom"));
+        clock.advance(100);
+        // Evicts the first one, failed the longest time ago.
+        assert!(!detector.failure(&third, "filter", "boom"));
+        clock.advance(100);
+        assert!(detector.failure(&second, "filter", "boom"));
+        assert!(detector.excluded(&second));
+        assert!(rx.try_recv().is_ok());
+
+        // Failures of the evicted one are counted anew.
+        assert!(!detector.failure(&first, "filter", "boom"));
+        assert!(!detector.excluded(&first));
+
+        clock.advance(1000);
+        assert!(!detector.excluded(&second));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn quarantine_entered_record_by_its_hash() {
+        let path = env::temp_dir().join("logdrop-poison-entered");
+        let _ = fs::remove_file(&path);
+
+        let mut settings = Settings::new(&path);
+        settings.attempts = 2;
+        let (tx, rx) = channel();
+        let detector = Detector::new(settings, tx);
+
+        let payload = record(vec![("message", string("poison"))]);
+        assert!(detector.enter(&payload));
+        assert!(!detector.panicked("filter.test", "boom"));
+
+        // Nothing is reported once the record has left the chain.
+        assert!(detector.enter(&record(vec![("message", string("other"))])));
+        leave();
+        assert!(!detector.panicked("filter.test", "boom"));
+
+        // The copy is taken as the next panic quarantines the record.
+        assert!(detector.enter(&payload));
+        assert!(detector.panicked("filter.test", "boom"));
+        assert!(!detector.enter(&payload));
+        assert!(rx.try_recv().is_ok());
+
+        let entry = Reader::open(&path).unwrap().next().unwrap().unwrap();
+        assert_eq!(Some(&string("poison")), entry.find("message"));
+        assert_eq!(Some(&RecordItem::U64(2)), entry.find(FAILURES));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
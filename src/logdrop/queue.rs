@@ -0,0 +1,509 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use libc;
+
+use super::{Envelope, Origin, Record};
+use super::ack::Ack;
+use super::config::{Config, Error, FromConfig};
+use super::metrics;
+use super::metrics::Counter;
+use super::spool;
+
+/// Elastic queue limits.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// High-water mark: the number of records kept in memory.
+    pub capacity: usize,
+    /// Low-water mark: spilled records are replayed once memory drains below it.
+    pub low: usize,
+    /// Number of records held in memory above the high-water mark before spilling anything.
+    pub grace: usize,
+    /// Directory for spill files.
+    pub dir: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            capacity: 65536,
+            low: 16384,
+            grace: 4096,
+            dir: PathBuf::from("/var/spool/logdrop"),
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let default = Settings::default();
+        let settings = Settings {
+            capacity: try!(config.u64_or("capacity", default.capacity as u64)) as usize,
+            low: try!(config.u64_or("low", default.low as u64)) as usize,
+            grace: try!(config.u64_or("grace", default.grace as u64)) as usize,
+            dir: PathBuf::from(try!(config.string_or("dir", default.dir.to_str().unwrap()))),
+        };
+
+        if settings.capacity == 0 || settings.low > settings.capacity {
+            return Err(Error::Invalid("elastic queue requires 0 < low <= capacity".to_string()));
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Number of queues created by the process, telling their spill files apart.
+static QUEUES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Item of an elastic queue: its record goes to the spill file, while the rest of it stays in
+/// memory until the record is replayed.
+pub trait Spillable: Send + 'static {
+    type Meta: Send + 'static;
+
+    fn split(self) -> (Record, Self::Meta);
+    fn join(record: Record, meta: Self::Meta) -> Self;
+}
+
+impl Spillable for Record {
+    type Meta = ();
+
+    fn split(self) -> (Record, ()) {
+        (self, ())
+    }
+
+    fn join(record: Record, _: ()) -> Record {
+        record
+    }
+}
+
+impl Spillable for Envelope {
+    type Meta = (Arc<Origin>, Option<Arc<String>>, Option<String>, Option<Ack>);
+
+    fn split(self) -> (Record, Self::Meta) {
+        (self.record, (self.origin, self.peer, self.rejected, self.ack))
+    }
+
+    fn join(record: Record, (origin, peer, rejected, ack): Self::Meta) -> Envelope {
+        Envelope {
+            record: record,
+            origin: origin,
+            peer: peer,
+            rejected: rejected,
+            ack: ack,
+        }
+    }
+}
+
+struct Spill<M> {
+    writer: spool::Writer,
+    reader: spool::Reader,
+    /// What is left of spilled items in memory, in the order of their records in the file.
+    metas: VecDeque<M>,
+}
+
+struct State<T: Spillable> {
+    memory: VecDeque<T>,
+    grace: VecDeque<T>,
+    spill: Option<Spill<T::Meta>>,
+    /// Grace buffer length to try spilling again at, after the spill file has failed to open.
+    retry: usize,
+    senders: usize,
+}
+
+struct Shared<T: Spillable> {
+    state: Mutex<State<T>>,
+    available: Condvar,
+    capacity: usize,
+    low: usize,
+    grace: usize,
+    path: PathBuf,
+    bytes: Counter,
+    transitions: Counter,
+}
+
+/// Creates an elastic queue spilling into `<dir>/<name>-<pid>-<seq>.spill` under pressure, the
+/// process id and the sequence number of the queue keeping files of queues of the same name apart,
+/// e.g. of several pipelines or of a pipeline and its reloaded successor.
+///
+/// Records are kept in memory up to the high-water mark, then in a small grace buffer, and only
+/// after the grace buffer overflows are they written to the spill file. Once memory drains below
+/// the low-water mark, the spill is replayed back before any later records, so the queue is always
+/// strictly FIFO: no record is received before one sent earlier. Should the spill file fail to
+/// open, the grace buffer keeps growing in memory rather than losing records, the spill being
+/// retried once it has grown by another grace buffer.
+///
+/// Metrics are reported as `queue.spill.bytes` and `queue.spill.transitions`, the latter counting
+/// both entering and leaving the spilling mode.
+pub fn elastic<T: Spillable>(settings: &Settings, name: &str) -> (ElasticSender<T>, ElasticReceiver<T>) {
+    let state = State {
+        memory: VecDeque::new(),
+        grace: VecDeque::new(),
+        spill: None,
+        retry: settings.grace,
+        senders: 1,
+    };
+
+    let pid = unsafe { libc::getpid() };
+    let seq = QUEUES.fetch_add(1, Ordering::SeqCst);
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(state),
+        available: Condvar::new(),
+        capacity: settings.capacity,
+        low: settings.low,
+        grace: settings.grace,
+        path: settings.dir.join(format!("{}-{}-{}.spill", name, pid, seq)),
+        bytes: metrics::registry().counter("queue.spill.bytes"),
+        transitions: metrics::registry().counter("queue.spill.transitions"),
+    });
+
+    (ElasticSender { shared: shared.clone() }, ElasticReceiver { shared: shared })
+}
+
+pub struct ElasticSender<T: Spillable> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Spillable> ElasticSender<T> {
+    /// Enqueues the item, never blocking on the consumer.
+    ///
+    /// Fails only if the item must be spilled and the spill file can't be written, the item
+    /// being lost then.
+    pub fn send(&self, item: T) -> io::Result<()> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+
+        if state.spill.is_some() {
+            try!(shared.append(&mut state, item));
+        } else if state.grace.is_empty() && state.memory.len() < shared.capacity {
+            state.memory.push_back(item);
+        } else {
+            state.grace.push_back(item);
+            if state.grace.len() > state.retry {
+                if let Err(err) = shared.start_spill(&mut state) {
+                    error!(target: "Queue", "failed to spill to {:?}, keeping {} records in memory: {}",
+                        shared.path, state.grace.len(), err);
+                    state.retry = state.grace.len() + shared.grace;
+                }
+            }
+        }
+
+        shared.available.notify_one();
+        Ok(())
+    }
+}
+
+impl<T: Spillable> Clone for ElasticSender<T> {
+    fn clone(&self) -> ElasticSender<T> {
+        self.shared.state.lock().unwrap().senders += 1;
+        ElasticSender { shared: self.shared.clone() }
+    }
+}
+
+impl<T: Spillable> Drop for ElasticSender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.available.notify_all();
+    }
+}
+
+pub struct ElasticReceiver<T: Spillable> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Result of a receive attempt with timeout.
+#[derive(Debug, PartialEq)]
+pub enum Received<T> {
+    Record(T),
+    Timeout,
+    Closed,
+}
+
+impl<T: Spillable> ElasticReceiver<T> {
+    /// Blocks until the next item, returning `None` once all senders are gone and the queue is
+    /// drained.
+    pub fn recv(&self) -> Option<T> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some(record) = shared.pop(&mut state) {
+                return Some(record);
+            }
+
+            if state.senders == 0 {
+                return None;
+            }
+
+            state = shared.available.wait(state).unwrap();
+        }
+    }
+
+    /// Like `recv`, but gives up after the given number of milliseconds.
+    pub fn recv_timeout(&self, timeout: u32) -> Received<T> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some(record) = shared.pop(&mut state) {
+                return Received::Record(record);
+            }
+
+            if state.senders == 0 {
+                return Received::Closed;
+            }
+
+            let (guard, notified) = shared.available.wait_timeout_ms(state, timeout).unwrap();
+            state = guard;
+            if notified {
+                continue;
+            }
+
+            return match shared.pop(&mut state) {
+                Some(record) => Received::Record(record),
+                None if state.senders == 0 => Received::Closed,
+                None => Received::Timeout,
+            };
+        }
+    }
+
+    /// Returns true if the queue currently keeps records on disk.
+    pub fn is_spilling(&self) -> bool {
+        self.shared.state.lock().unwrap().spill.is_some()
+    }
+
+    /// Returns the path of the spill file of the queue, whether it exists or not.
+    pub fn spill_path(&self) -> &Path {
+        &self.shared.path
+    }
+}
+
+impl<T: Spillable> Iterator for ElasticReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv()
+    }
+}
+
+impl<T: Spillable> Shared<T> {
+    /// Moves the grace buffer to the new spill file, leaving the buffer intact if anything fails.
+    fn start_spill(&self, state: &mut MutexGuard<State<T>>) -> io::Result<()> {
+        info!(target: "Queue", "memory is exhausted, spilling records to {:?}", self.path);
+
+        let mut records = Vec::with_capacity(state.grace.len());
+        let mut metas = VecDeque::with_capacity(state.grace.len());
+        while let Some(item) = state.grace.pop_front() {
+            let (record, meta) = item.split();
+            records.push(record);
+            metas.push_back(meta);
+        }
+
+        // The grace buffer precedes everything sent from now on, so it goes to the disk first.
+        let mut size = 0;
+        let opened = spool::Writer::create(&self.path).and_then(|mut writer| {
+            for record in records.iter() {
+                size += try!(writer.append(record));
+            }
+            let reader = try!(spool::Reader::open(&self.path));
+            Ok((writer, reader))
+        });
+
+        let (writer, reader) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                let _ = fs::remove_file(&self.path);
+                for (record, meta) in records.into_iter().zip(metas.into_iter()) {
+                    state.grace.push_back(T::join(record, meta));
+                }
+                return Err(err);
+            }
+        };
+
+        state.spill = Some(Spill {
+            writer: writer,
+            reader: reader,
+            metas: metas,
+        });
+        state.retry = self.grace;
+        self.bytes.add(size);
+        self.transitions.inc();
+        Ok(())
+    }
+
+    fn append(&self, state: &mut MutexGuard<State<T>>, item: T) -> io::Result<()> {
+        let spill = state.spill.as_mut().unwrap();
+        let (record, meta) = item.split();
+        let size = try!(spill.writer.append(&record));
+        spill.metas.push_back(meta);
+        self.bytes.add(size);
+        Ok(())
+    }
+
+    fn pop(&self, state: &mut MutexGuard<State<T>>) -> Option<T> {
+        if state.memory.len() < self.low || state.memory.is_empty() {
+            self.refill(state);
+        }
+
+        state.memory.pop_front()
+    }
+
+    /// Moves records from the spill file, then from the grace buffer, into memory.
+    ///
+    /// While spilling the grace buffer is always empty, so both sources are in FIFO order.
+    fn refill(&self, state: &mut MutexGuard<State<T>>) {
+        while state.memory.len() < self.capacity {
+            let drained = match state.spill {
+                Some(ref spill) => spill.metas.is_empty(),
+                None => false,
+            };
+
+            if drained {
+                self.stop_spill(state);
+            }
+
+            let item = match state.spill {
+                Some(ref mut spill) => {
+                    match spill.reader.next() {
+                        Ok(Some(record)) => T::join(record, spill.metas.pop_front().unwrap()),
+                        Ok(None) => {
+                            error!(target: "Queue", "lost {} spilled records: unexpected end of {:?}", spill.metas.len(), self.path);
+                            spill.metas.clear();
+                            continue;
+                        }
+                        Err(err) => {
+                            error!(target: "Queue", "lost {} spilled records: {}", spill.metas.len(), err);
+                            spill.metas.clear();
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    match state.grace.pop_front() {
+                        Some(item) => item,
+                        None => break,
+                    }
+                }
+            };
+
+            state.memory.push_back(item);
+        }
+    }
+
+    fn stop_spill(&self, state: &mut MutexGuard<State<T>>) {
+        if let Some(spill) = state.spill.take() {
+            drop(spill.writer);
+            drop(spill.reader);
+            if let Err(err) = fs::remove_file(&self.path) {
+                warn!(target: "Queue", "failed to remove spill file {:?}: {}", self.path, err);
+            }
+
+            info!(target: "Queue", "spill is drained, back to memory");
+            self.transitions.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use super::super::{Record, RecordItem};
+    use super::super::testing::record;
+    use super::{ElasticReceiver, ElasticSender, Received, Settings, elastic};
+
+    fn settings(capacity: usize, low: usize, grace: usize) -> Settings {
+        Settings {
+            capacity: capacity,
+            low: low,
+            grace: grace,
+            dir: env::temp_dir(),
+        }
+    }
+
+    fn queue(settings: &Settings, name: &str) -> (ElasticSender<Record>, ElasticReceiver<Record>) {
+        elastic(settings, name)
+    }
+
+    #[test]
+    fn burst_is_spilled_and_replayed_in_order() {
+        let (tx, rx) = queue(&settings(16, 4, 4), "logdrop-queue-burst");
+        let path = rx.spill_path().to_path_buf();
+
+        let consumer = thread::spawn(move || {
+            let mut result = Vec::new();
+            let mut spilled = false;
+            while let Some(record) = rx.recv() {
+                spilled |= rx.is_spilling();
+                result.push(record);
+                thread::sleep_ms(1);
+            }
+            (result, spilled)
+        });
+
+        for id in 0..200 {
+            tx.send(record(vec![("id", RecordItem::F64(id as f64))])).unwrap();
+        }
+        drop(tx);
+
+        let (result, spilled) = consumer.join().unwrap();
+        let expected: Vec<_> = (0..200).map(|id| record(vec![("id", RecordItem::F64(id as f64))])).collect();
+        assert_eq!(expected, result);
+        assert!(spilled);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sub_threshold_burst_never_touches_disk() {
+        let (tx, rx) = queue(&settings(16, 4, 4), "logdrop-queue-small");
+
+        for id in 0..20 {
+            tx.send(record(vec![("id", RecordItem::F64(id as f64))])).unwrap();
+        }
+
+        assert!(!rx.is_spilling());
+        assert!(!rx.spill_path().exists());
+
+        drop(tx);
+        let result: Vec<_> = rx.collect();
+        let expected: Vec<_> = (0..20).map(|id| record(vec![("id", RecordItem::F64(id as f64))])).collect();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn keep_records_in_memory_if_spill_fails() {
+        let mut settings = settings(16, 4, 4);
+        settings.dir = PathBuf::from("/nonexistent/logdrop");
+        let (tx, rx) = queue(&settings, "logdrop-queue-unspillable");
+
+        for id in 0..40 {
+            tx.send(record(vec![("id", RecordItem::F64(id as f64))])).unwrap();
+        }
+        assert!(!rx.is_spilling());
+
+        drop(tx);
+        let result: Vec<_> = rx.collect();
+        let expected: Vec<_> = (0..40).map(|id| record(vec![("id", RecordItem::F64(id as f64))])).collect();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn spill_queues_of_same_name_apart() {
+        let (_, first) = queue(&settings(16, 4, 4), "output-0");
+        let (_, second) = queue(&settings(16, 4, 4), "output-0");
+        assert!(first.spill_path() != second.spill_path());
+    }
+
+    #[test]
+    fn recv_timeout_on_empty_queue() {
+        let (tx, rx) = queue(&settings(16, 4, 4), "logdrop-queue-timeout");
+        assert_eq!(Received::Timeout, rx.recv_timeout(10));
+
+        drop(tx);
+        assert_eq!(Received::Closed, rx.recv_timeout(10));
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use super::{Record, RecordItem};
+use super::calendar::{Calendar, Zone};
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error, FromConfig};
+use super::metrics;
+use super::spool::{Reader, Writer};
+
+/// Field marking records of sources over their soft quota.
+pub const FIELD: &'static str = "over_quota";
+
+/// Name of the file the usage is kept in within the state directory.
+const STATE: &'static str = "quota.state";
+
+/// Minimum period between saves of the usage in milliseconds.
+const SAVE_INTERVAL: u64 = 1000;
+
+/// Accounting window, aligned to wall-clock boundaries of the zone of the settings.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Window {
+    Hour,
+    Day,
+}
+
+impl Window {
+    fn len(&self) -> u64 {
+        match *self {
+            Window::Hour => 3600 * 1000,
+            Window::Day => 24 * 3600 * 1000,
+        }
+    }
+
+    /// Returns the start of the window the instant falls into in UTC.
+    pub fn start(&self, now: u64) -> u64 {
+        now - now % self.len()
+    }
+}
+
+/// What happens to records of a source over its hard quota.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Records are dropped.
+    Drop,
+    /// Records are dropped and connections they arrive on are closed.
+    Close,
+}
+
+/// Quotas in decoded bytes per source key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub window: Window,
+    /// Zone the window boundaries follow the local time of, so that daily quotas reset at its
+    /// midnight.
+    pub zone: Zone,
+    pub action: Action,
+    /// Quotas crossing which only marks records.
+    pub soft: HashMap<String, u64>,
+    /// Quotas crossing which stops accepting records until the window resets.
+    pub hard: HashMap<String, u64>,
+    /// Directory the usage is kept in across restarts, if any.
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            window: Window::Day,
+            zone: Zone::utc(),
+            action: Action::Drop,
+            soft: HashMap::new(),
+            hard: HashMap::new(),
+            dir: None,
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let window = match &try!(config.string_or("window", "day"))[..] {
+            "hour" => Window::Hour,
+            "day" => Window::Day,
+            window => return Err(Error::Invalid(format!("unknown quota window '{}'", window))),
+        };
+
+        let zone = try!(Zone::parse(&try!(config.string_or("timezone", "UTC"))).map_err(Error::Invalid));
+
+        let action = match &try!(config.string_or("action", "drop"))[..] {
+            "drop" => Action::Drop,
+            "close" => Action::Close,
+            action => return Err(Error::Invalid(format!("unknown quota action '{}'", action))),
+        };
+
+        let dir = match config.find("dir") {
+            Some(..) => Some(PathBuf::from(try!(config.string("dir")))),
+            None => None,
+        };
+
+        Ok(Settings {
+            window: window,
+            zone: zone,
+            action: action,
+            soft: try!(config.sizes("soft")),
+            hard: try!(config.sizes("hard")),
+            dir: dir,
+        })
+    }
+}
+
+impl Settings {
+    /// Returns the start of the window the instant falls into in the local time of the zone.
+    fn start(&self, now: u64) -> u64 {
+        Calendar::new(self.window.len(), 0, self.zone.clone()).window(now).start
+    }
+}
+
+/// Decoded bytes and records accepted from a source within the window.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub records: u64,
+}
+
+/// Outcome of charging a record.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// The source is within its quotas.
+    Pass,
+    /// The source is over its soft quota, with the notification record if it has just crossed it.
+    Over(Option<Record>),
+    /// The source is over its hard quota, the record must be dropped.
+    Exceeded,
+}
+
+struct State {
+    window: u64,
+    usage: HashMap<String, Usage>,
+    /// Sources notified about crossing their soft quota.
+    notified: HashSet<String>,
+    /// Sources over their hard quota.
+    exceeded: HashSet<String>,
+    /// Peers whose connections must be closed.
+    blocked: HashSet<String>,
+    saved: u64,
+}
+
+impl State {
+    fn to_record(&self) -> Record {
+        let usage = self.usage.iter().map(|(key, usage)| {
+            let mut map = HashMap::new();
+            map.insert("bytes".to_string(), RecordItem::F64(usage.bytes as f64));
+            map.insert("records".to_string(), RecordItem::F64(usage.records as f64));
+            (key.clone(), RecordItem::Object(map))
+        }).collect();
+
+        let keys = |keys: &HashSet<String>| {
+            RecordItem::Array(keys.iter().map(|key| RecordItem::String(key.clone())).collect())
+        };
+
+        let mut record = Record::new();
+        record.insert("window".to_string(), RecordItem::F64(self.window as f64));
+        record.insert("usage".to_string(), RecordItem::Object(usage));
+        record.insert("notified".to_string(), keys(&self.notified));
+        record.insert("exceeded".to_string(), keys(&self.exceeded));
+        record
+    }
+
+    /// Restores the usage from the saved record, ignoring malformed entries.
+    fn restore(&mut self, record: &Record) {
+        if let Some(&RecordItem::Object(ref usage)) = record.find("usage") {
+            for (key, value) in usage.iter() {
+                let count = |name: &str| -> u64 {
+                    match value.find(name) {
+                        Some(&RecordItem::F64(count)) => count as u64,
+                        _ => 0,
+                    }
+                };
+                self.usage.insert(key.clone(), Usage { bytes: count("bytes"), records: count("records") });
+            }
+        }
+
+        let keys = |name: &str| -> HashSet<String> {
+            match record.find(name) {
+                Some(&RecordItem::Array(ref keys)) => {
+                    keys.iter().filter_map(|key| key.as_str()).map(|key| key.to_string()).collect()
+                }
+                _ => HashSet::new(),
+            }
+        };
+        self.notified = keys("notified");
+        self.exceeded = keys("exceeded");
+    }
+}
+
+/// Per-source byte accounting enforcing quotas within wall-clock windows, shared by all its clones.
+///
+/// Usage is saved into the state directory at most once a second and on `save`, and restored on
+/// construction unless saved within a previous window.
+#[derive(Clone)]
+pub struct Quota {
+    settings: Arc<Settings>,
+    state: Arc<Mutex<State>>,
+    clock: Arc<Clock>,
+}
+
+impl Quota {
+    pub fn new(settings: Settings) -> Quota {
+        Quota::with_clock(settings, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(settings: Settings, clock: Arc<Clock>) -> Quota {
+        let now = clock.now();
+        let mut state = State {
+            window: settings.start(now),
+            usage: HashMap::new(),
+            notified: HashSet::new(),
+            exceeded: HashSet::new(),
+            blocked: HashSet::new(),
+            saved: now,
+        };
+
+        if let Some(ref dir) = settings.dir {
+            match Reader::open(&dir.join(STATE)).and_then(|mut rd| rd.next()) {
+                Ok(Some(record)) => {
+                    match record.find("window") {
+                        Some(&RecordItem::F64(window)) if window == state.window as f64 => state.restore(&record),
+                        _ => {}
+                    }
+                }
+                Ok(None) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => warn!(target: "Quota", "unable to restore usage from '{}': {}", dir.display(), err),
+            }
+        }
+
+        Quota {
+            settings: Arc::new(settings),
+            state: Arc::new(Mutex::new(state)),
+            clock: clock,
+        }
+    }
+
+    /// Charges the record of the given decoded size to the source, arrived from the peer if any.
+    ///
+    /// Records over the hard quota are not charged, they are counted in the
+    /// `pipeline.quota.<key>.dropped` metrics instead.
+    pub fn charge(&self, key: &str, peer: Option<&str>, bytes: u64) -> Verdict {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, now);
+
+        let usage = state.usage.get(key).cloned().unwrap_or(Usage::default());
+        let verdict = match self.settings.hard.get(key) {
+            Some(&hard) if state.exceeded.contains(key) || usage.bytes + bytes > hard => {
+                if state.exceeded.insert(key.to_string()) {
+                    warn!(target: "Quota", "'{}' source is over its hard quota of {} bytes", key, hard);
+                }
+                if let (Action::Close, Some(peer)) = (self.settings.action, peer) {
+                    state.blocked.insert(peer.to_string());
+                }
+                metrics::registry().counter(&format!("pipeline.quota.{}.dropped", key)).inc();
+                Verdict::Exceeded
+            }
+            _ => {
+                let usage = Usage { bytes: usage.bytes + bytes, records: usage.records + 1 };
+                state.usage.insert(key.to_string(), usage);
+
+                match self.settings.soft.get(key) {
+                    Some(&soft) if usage.bytes > soft => {
+                        let notification = if state.notified.insert(key.to_string()) {
+                            Some(self.notification(key, soft, usage, state.window, now))
+                        } else {
+                            None
+                        };
+                        Verdict::Over(notification)
+                    }
+                    _ => Verdict::Pass,
+                }
+            }
+        };
+
+        if now - state.saved >= SAVE_INTERVAL {
+            self.store(&mut state, now);
+        }
+
+        verdict
+    }
+
+    /// Returns the usage of the source within the current window.
+    pub fn usage(&self, key: &str) -> Usage {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, self.clock.now());
+        state.usage.get(key).cloned().unwrap_or(Usage::default())
+    }
+
+    /// Returns whether connections from the peer must be closed for sending records over a hard
+    /// quota.
+    pub fn is_blocked(&self, peer: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.roll(&mut state, self.clock.now());
+        state.blocked.contains(peer)
+    }
+
+    /// Saves the usage into the state directory, if any.
+    pub fn save(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.save_state(&mut state, self.clock.now())
+    }
+
+    /// Resets the usage once the window is over.
+    fn roll(&self, state: &mut State, now: u64) {
+        let window = self.settings.start(now);
+        if window != state.window {
+            state.window = window;
+            state.usage.clear();
+            state.notified.clear();
+            state.exceeded.clear();
+            state.blocked.clear();
+            self.store(state, now);
+        }
+    }
+
+    fn store(&self, state: &mut State, now: u64) {
+        if let Err(err) = self.save_state(state, now) {
+            warn!(target: "Quota", "unable to save usage: {}", err);
+        }
+    }
+
+    /// Writes the state aside and renames it over the previous one, so that a crash leaves
+    /// either of them intact.
+    fn save_state(&self, state: &mut State, now: u64) -> io::Result<()> {
+        state.saved = now;
+
+        let dir = match self.settings.dir {
+            Some(ref dir) => dir,
+            None => return Ok(()),
+        };
+
+        let path = dir.join(STATE);
+        let temporary = dir.join(format!("{}.tmp", STATE));
+        {
+            let mut wr = try!(Writer::create(&temporary));
+            try!(wr.append(&state.to_record()));
+            try!(wr.sync());
+        }
+        fs::rename(&temporary, &path)
+    }
+
+    fn notification(&self, key: &str, soft: u64, usage: Usage, window: u64, now: u64) -> Record {
+        let mut record = Record::new();
+        record.insert("type".to_string(), RecordItem::String("quota_notification".to_string()));
+        record.insert("timestamp".to_string(), RecordItem::F64(now as f64 / 1000.0));
+        record.insert("source".to_string(), RecordItem::String(key.to_string()));
+        record.insert("quota".to_string(), RecordItem::F64(soft as f64));
+        record.insert("bytes".to_string(), RecordItem::F64(usage.bytes as f64));
+        record.insert("records".to_string(), RecordItem::F64(usage.records as f64));
+        record.insert("window".to_string(), RecordItem::F64(window as f64 / 1000.0));
+        record
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::super::RecordItem;
+    use super::super::calendar::Zone;
+    use super::super::clock::MockClock;
+    use super::super::metrics;
+    use super::{Action, Quota, Settings, Usage, Verdict, Window};
+
+    /// Hour boundary plus a minute.
+    const NOW: u64 = 1000 * 3600 * 1000 + 60 * 1000;
+
+    fn settings(key: &str, soft: u64, hard: u64) -> Settings {
+        let mut settings = Settings::default();
+        settings.window = Window::Hour;
+        settings.soft.insert(key.to_string(), soft);
+        settings.hard.insert(key.to_string(), hard);
+        settings
+    }
+
+    #[test]
+    fn notify_once_over_soft_quota() {
+        let quota = Quota::with_clock(settings("quota-soft", 100, 1000), Arc::new(MockClock::new(NOW)));
+
+        assert_eq!(Verdict::Pass, quota.charge("quota-soft", None, 60));
+        assert_eq!(Verdict::Pass, quota.charge("other", None, 600));
+
+        let notification = match quota.charge("quota-soft", None, 60) {
+            Verdict::Over(Some(notification)) => notification,
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        };
+        assert_eq!(Some(&RecordItem::String("quota_notification".to_string())), notification.find("type"));
+        assert_eq!(Some(&RecordItem::String("quota-soft".to_string())), notification.find("source"));
+        assert_eq!(Some(&RecordItem::F64(120.0)), notification.find("bytes"));
+
+        assert_eq!(Verdict::Over(None), quota.charge("quota-soft", None, 60));
+        assert_eq!(Usage { bytes: 180, records: 3 }, quota.usage("quota-soft"));
+    }
+
+    #[test]
+    fn drop_over_hard_quota_until_window_resets() {
+        let clock = MockClock::new(NOW);
+        let mut settings = settings("quota-hard", 1000, 100);
+        settings.action = Action::Close;
+        let quota = Quota::with_clock(settings, Arc::new(clock.clone()));
+        let dropped = metrics::registry().counter("pipeline.quota.quota-hard.dropped");
+
+        assert_eq!(Verdict::Pass, quota.charge("quota-hard", Some("10.0.0.1"), 80));
+        assert_eq!(Verdict::Exceeded, quota.charge("quota-hard", Some("10.0.0.1"), 40));
+        assert_eq!(Verdict::Exceeded, quota.charge("quota-hard", Some("10.0.0.2"), 10));
+        assert_eq!(2, dropped.get());
+        assert!(quota.is_blocked("10.0.0.1"));
+        assert_eq!(Usage { bytes: 80, records: 1 }, quota.usage("quota-hard"));
+
+        clock.advance(3600 * 1000);
+        assert!(!quota.is_blocked("10.0.0.1"));
+        assert_eq!(Verdict::Pass, quota.charge("quota-hard", Some("10.0.0.1"), 40));
+        assert_eq!(Usage { bytes: 40, records: 1 }, quota.usage("quota-hard"));
+    }
+
+    #[test]
+    fn reset_daily_quota_at_local_midnight() {
+        // 16:01 UTC is a minute past midnight eight hours east of it.
+        let clock = MockClock::new(NOW);
+        let mut settings = settings("quota-zone", 1000, 1000);
+        settings.window = Window::Day;
+        settings.zone = Zone::parse("+08:00").unwrap();
+        let quota = Quota::with_clock(settings, Arc::new(clock.clone()));
+
+        quota.charge("quota-zone", None, 100);
+        clock.advance(24 * 3600 * 1000 - 2 * 60 * 1000);
+        quota.charge("quota-zone", None, 100);
+        assert_eq!(Usage { bytes: 200, records: 2 }, quota.usage("quota-zone"));
+
+        clock.advance(60 * 1000);
+        quota.charge("quota-zone", None, 100);
+        assert_eq!(Usage { bytes: 100, records: 1 }, quota.usage("quota-zone"));
+    }
+
+    #[test]
+    fn keep_usage_across_restarts() {
+        let dir = env::temp_dir().join("logdrop-quota-restart");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let clock = MockClock::new(NOW);
+        let mut settings = settings("quota-restart", 100, 150);
+        settings.dir = Some(dir.clone());
+
+        let quota = Quota::with_clock(settings.clone(), Arc::new(clock.clone()));
+        quota.charge("quota-restart", None, 120);
+        quota.save().unwrap();
+        drop(quota);
+
+        clock.advance(60 * 1000);
+        let quota = Quota::with_clock(settings.clone(), Arc::new(clock.clone()));
+        assert_eq!(Usage { bytes: 120, records: 1 }, quota.usage("quota-restart"));
+        assert_eq!(Verdict::Over(None), quota.charge("quota-restart", None, 20));
+        assert_eq!(Verdict::Exceeded, quota.charge("quota-restart", None, 20));
+        quota.save().unwrap();
+        drop(quota);
+
+        clock.advance(3600 * 1000);
+        let quota = Quota::with_clock(settings, Arc::new(clock));
+        assert_eq!(Usage::default(), quota.usage("quota-restart"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
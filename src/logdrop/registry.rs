@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Mutex, Once, ONCE_INIT};
+
+use super::codec;
+use super::codec::Codec;
+use super::config::{Config, Error, FromConfig};
+use super::filter;
+use super::filter::Filter;
+use super::input;
+use super::input::Input;
+use super::output;
+use super::output::Output;
+
+pub type CodecFactory = fn(&Config) -> Result<Box<Codec>, Error>;
+pub type InputFactory = fn(&Config) -> Result<Box<Input>, Error>;
+pub type FilterFactory = fn(&Config) -> Result<Box<Filter>, Error>;
+pub type OutputFactory = fn(&Config) -> Result<Box<Output>, Error>;
+
+/// Name to constructor mapping for every component kind the config loader knows about.
+struct Registry {
+    codecs: HashMap<String, CodecFactory>,
+    inputs: HashMap<String, InputFactory>,
+    filters: HashMap<String, FilterFactory>,
+    outputs: HashMap<String, OutputFactory>,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        let mut registry = Registry {
+            codecs: HashMap::new(),
+            inputs: HashMap::new(),
+            filters: HashMap::new(),
+            outputs: HashMap::new(),
+        };
+
+        registry.codecs.insert("msgpack".to_string(), codec::msgpack::factory);
+        registry.codecs.insert("fallback".to_string(), codec::fallback::factory);
+        registry.codecs.insert("json".to_string(), make_codec::<codec::Json>);
+        registry.codecs.insert("logfmt".to_string(), make_codec::<codec::Logfmt>);
+
+        registry.inputs.insert("http".to_string(), make_input::<input::HttpInput>);
+        registry.inputs.insert("migration".to_string(), make_input::<input::MigrationInput>);
+        registry.inputs.insert("requeue".to_string(), make_input::<input::Requeue>);
+        registry.inputs.insert("tcp".to_string(), make_input::<input::TcpInput>);
+
+        registry.filters.insert("bucketize".to_string(), make_filter::<filter::Bucketize>);
+        registry.filters.insert("coalesce".to_string(), make_filter::<filter::Coalesce>);
+        registry.filters.insert("conditional".to_string(), make_filter::<filter::Conditional>);
+        registry.filters.insert("correlate".to_string(), make_filter::<filter::Correlate>);
+        registry.filters.insert("copy".to_string(), make_filter::<filter::CopyFields>);
+        registry.filters.insert("ensure_id".to_string(), make_filter::<filter::EnsureId>);
+        registry.filters.insert("escape_reserved".to_string(), make_filter::<filter::EscapeReserved>);
+        registry.filters.insert("governor".to_string(), make_filter::<filter::Governor>);
+        registry.filters.insert("influx_line".to_string(), make_filter::<filter::InfluxLine>);
+        registry.filters.insert("level_sample".to_string(), make_filter::<filter::LevelSample>);
+        registry.filters.insert("lookup".to_string(), make_filter::<filter::Lookup>);
+        registry.filters.insert("mask_format".to_string(), make_filter::<filter::MaskFormat>);
+        registry.filters.insert("max_size".to_string(), make_filter::<filter::MaxSize>);
+        registry.filters.insert("number_normalize".to_string(), make_filter::<filter::NumberNormalize>);
+        registry.filters.insert("parse_query".to_string(), make_filter::<filter::ParseQuery>);
+        registry.filters.insert("project".to_string(), make_filter::<filter::Project>);
+        registry.filters.insert("promote".to_string(), make_filter::<filter::Promote>);
+        registry.filters.insert("rate_annotate".to_string(), make_filter::<filter::RateAnnotate>);
+        registry.filters.insert("render_message".to_string(), make_filter::<filter::RenderMessage>);
+        registry.filters.insert("skew".to_string(), make_filter::<filter::SkewFilter>);
+        registry.filters.insert("syslog_severity".to_string(), make_filter::<filter::SyslogSeverity>);
+        registry.filters.insert("time_bucket".to_string(), make_filter::<filter::TimeBucket>);
+        registry.filters.insert("to_metrics".to_string(), make_filter::<filter::ToMetrics>);
+        registry.filters.insert("truncate".to_string(), make_filter::<filter::Truncate>);
+
+        registry.outputs.insert("null".to_string(), make_output::<output::Null>);
+        registry.outputs.insert("file".to_string(), make_output::<output::FileOutput>);
+        registry.outputs.insert("elasticsearch".to_string(), make_output::<output::ElasticsearchOutput>);
+        registry.outputs.insert("failover".to_string(), make_output::<output::Failover>);
+        registry.outputs.insert("dedup".to_string(), make_output::<output::Dedup>);
+        registry.outputs.insert("redis".to_string(), make_output::<output::RedisOutput>);
+        registry.outputs.insert("otlp".to_string(), make_output::<output::OtlpOutput>);
+        registry.outputs.insert("dead_letter_archive".to_string(), make_output::<output::Archive>);
+
+        registry
+    }
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static mut REGISTRY: *const Mutex<Registry> = 0 as *const Mutex<Registry>;
+    static INIT: Once = ONCE_INIT;
+
+    unsafe {
+        INIT.call_once(|| {
+            REGISTRY = mem::transmute(Box::new(Mutex::new(Registry::new())));
+        });
+
+        &*REGISTRY
+    }
+}
+
+fn make_codec<T: Codec + FromConfig + 'static>(config: &Config) -> Result<Box<Codec>, Error> {
+    Ok(Box::new(try!(T::from_config(config))))
+}
+
+fn make_input<T: Input + FromConfig + 'static>(config: &Config) -> Result<Box<Input>, Error> {
+    Ok(Box::new(try!(T::from_config(config))))
+}
+
+fn make_filter<T: Filter + FromConfig + 'static>(config: &Config) -> Result<Box<Filter>, Error> {
+    Ok(Box::new(try!(T::from_config(config))))
+}
+
+fn make_output<T: Output + FromConfig + 'static>(config: &Config) -> Result<Box<Output>, Error> {
+    Ok(Box::new(try!(T::from_config(config))))
+}
+
+/// Registers a codec constructor, replacing the previous one with the same name.
+pub fn register_codec(name: &str, factory: CodecFactory) {
+    registry().lock().unwrap().codecs.insert(name.to_string(), factory);
+}
+
+pub fn register_input(name: &str, factory: InputFactory) {
+    registry().lock().unwrap().inputs.insert(name.to_string(), factory);
+}
+
+pub fn register_filter(name: &str, factory: FilterFactory) {
+    registry().lock().unwrap().filters.insert(name.to_string(), factory);
+}
+
+pub fn register_output(name: &str, factory: OutputFactory) {
+    registry().lock().unwrap().outputs.insert(name.to_string(), factory);
+}
+
+/// Constructs a codec described by the given config entry.
+pub fn codec(config: &Config) -> Result<Box<Codec>, Error> {
+    let name = try!(config.typename());
+    let factory = registry().lock().unwrap().codecs.get(&name).cloned();
+    match factory {
+        Some(factory) => factory(config),
+        None => Err(Error::Invalid(format!("unknown codec '{}'", name))),
+    }
+}
+
+pub fn input(config: &Config) -> Result<Box<Input>, Error> {
+    let name = try!(config.typename());
+    let factory = registry().lock().unwrap().inputs.get(&name).cloned();
+    match factory {
+        Some(factory) => factory(config),
+        None => Err(Error::Invalid(format!("unknown input '{}'", name))),
+    }
+}
+
+pub fn filter(config: &Config) -> Result<Box<Filter>, Error> {
+    let name = try!(config.typename());
+    let factory = registry().lock().unwrap().filters.get(&name).cloned();
+    match factory {
+        Some(factory) => factory(config),
+        None => Err(Error::Invalid(format!("unknown filter '{}'", name))),
+    }
+}
+
+pub fn output(config: &Config) -> Result<Box<Output>, Error> {
+    let name = try!(config.typename());
+    let factory = registry().lock().unwrap().outputs.get(&name).cloned();
+    match factory {
+        Some(factory) => factory(config),
+        None => Err(Error::Invalid(format!("unknown output '{}'", name))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::super::Record;
+    use super::super::codec::Codec;
+    use super::super::config::{Config, Error};
+    use super::super::json;
+    use super::{codec, register_codec};
+
+    struct Dummy;
+
+    impl Codec for Dummy {
+        fn new(&self) -> Box<Codec> {
+            Box::new(Dummy)
+        }
+
+        fn decode(&self, _: Box<Read>) -> Box<Iterator<Item=Record>> {
+            Box::new(vec![Record::new()].into_iter())
+        }
+    }
+
+    fn dummy(_: &Config) -> Result<Box<Codec>, Error> {
+        Ok(Box::new(Dummy))
+    }
+
+    #[test]
+    fn construct_registered_codec() {
+        register_codec("dummy", dummy);
+
+        let value = json::from_str(r#"{"type": "dummy"}"#).unwrap();
+        let codec = codec(&Config::new(&value)).unwrap();
+        let records: Vec<Record> = codec.decode(Box::new("".as_bytes())).collect();
+        assert_eq!(vec![Record::new()], records);
+    }
+
+    #[test]
+    fn construct_builtin_codec_by_name() {
+        let value = json::Value::String("msgpack".to_string());
+        assert!(codec(&Config::new(&value)).is_ok());
+    }
+
+    #[test]
+    fn fail_on_unknown_codec() {
+        let value = json::Value::String("unknown".to_string());
+        assert!(codec(&Config::new(&value)).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::thread::JoinHandle;
+
+use libc::c_int;
+
+use super::audit;
+use super::config;
+use super::identity;
+use super::identity::Identity;
+use super::pipeline::Handle;
+use super::sys;
+
+static HANGUP: AtomicBool = ATOMIC_BOOL_INIT;
+
+static GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Only raises the flag, as nothing else is safe to do within a signal handler.
+extern fn hangup(_: c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+/// Asks the listening reloader to reload the pipeline, as if SIGHUP has been received.
+pub fn request() {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+/// Returns the number of reloads so far, letting components holding data loaded from elsewhere
+/// than the config, e.g. lookup tables, load it again on reload.
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Makes components following `generation` load their data again.
+pub fn advance() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Reloads the running pipeline from its config file.
+///
+/// An invalid new config is rejected, keeping the current one running.
+pub struct Reloader {
+    path: PathBuf,
+    content: String,
+    handle: Handle,
+}
+
+impl Reloader {
+    /// Creates the reloader of the pipeline built out of the given config content.
+    pub fn new(path: &Path, content: String, handle: Handle) -> Reloader {
+        Reloader {
+            path: path.to_path_buf(),
+            content: content,
+            handle: handle,
+        }
+    }
+
+    pub fn reload(&mut self) {
+        info!(target: "Reload", "reloading config {:?}", self.path);
+        advance();
+
+        let content = match config::read(&self.path) {
+            Ok(content) => content,
+            Err(err) => {
+                error!(target: "Reload", "keeping current config: {}", err);
+                self.audit(vec![("outcome", format!("rejected: {}", err))]);
+                return;
+            }
+        };
+
+        let reload = match config::diff(&self.content, &content) {
+            Ok(reload) => reload,
+            Err(err) => {
+                error!(target: "Reload", "keeping current config: {}", err);
+                self.audit(vec![("outcome", format!("rejected: {}", err))]);
+                return;
+            }
+        };
+
+        let mut summary = vec![
+            ("inputs", reload.inputs.join(", ")),
+            ("outputs", reload.outputs.iter().filter(|output| output.is_none()).count().to_string()),
+            ("filters", (if reload.filters { "replaced" } else { "kept" }).to_string()),
+            ("restart", reload.restart.join("; ")),
+        ];
+
+        for change in reload.restart.iter() {
+            warn!(target: "Reload", "{}, restart to apply", change);
+        }
+
+        match self.handle.reload(reload) {
+            Ok(()) => {
+                info!(target: "Reload", "config {:?} has been reloaded", self.path);
+                identity::install(Identity::new(&identity::current().instance, &content));
+                self.content = content;
+                summary.push(("outcome", "applied".to_string()));
+                self.audit(summary);
+            }
+            Err(err) => {
+                error!(target: "Reload", "unable to reload: {}", err);
+                self.audit(vec![("outcome", format!("failed: {}", err))]);
+            }
+        }
+    }
+
+    /// Records the reload attempt with its outcome and, if applied, the summary of changes: the
+    /// inputs started, the number of outputs started, whether the filter chain is replaced and
+    /// changes awaiting restart.
+    fn audit(&self, mut details: Vec<(&str, String)>) {
+        details.push(("path", self.path.display().to_string()));
+        audit::record("reload", "reload", details);
+    }
+
+    /// Installs the SIGHUP handler, spawning a thread reloading the pipeline on each signal.
+    pub fn listen(self) -> JoinHandle<()> {
+        sys::on_signal(sys::SIGHUP, hangup);
+
+        thread::spawn(move || {
+            let mut reloader = self;
+            loop {
+                thread::sleep_ms(100);
+                if HANGUP.swap(false, Ordering::SeqCst) {
+                    reloader.reload();
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use std::io;
+use std::io::{BufRead, Read};
+
+/// Reply to a command in the Redis serialization protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// Encodes the command as an array of bulk strings, the way clients send commands.
+///
+/// Only what is required to push records into Redis: no inline commands, no RESP3 types.
+pub fn command(args: &[&[u8]]) -> Vec<u8> {
+    let mut data = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args.iter() {
+        data.extend(format!("${}\r\n", arg.len()).bytes());
+        data.extend(arg.iter().cloned());
+        data.extend(b"\r\n".iter().cloned());
+    }
+    data
+}
+
+/// Reads a single reply, failing if the connection is closed before the reply is complete.
+pub fn read<R: BufRead>(rd: &mut R) -> io::Result<Reply> {
+    let line = try!(read_line(rd));
+    let rest = &line[1..];
+
+    match line.as_bytes()[0] {
+        b'+' => Ok(Reply::Status(rest.to_string())),
+        b'-' => Ok(Reply::Error(rest.to_string())),
+        b':' => Ok(Reply::Integer(try!(parse(rest)))),
+        b'$' => {
+            let len = try!(parse(rest));
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+
+            let len = len as usize;
+            let mut data = Vec::new();
+            try!(rd.by_ref().take(len as u64 + 2).read_to_end(&mut data));
+            if data.len() < len + 2 {
+                return Err(io::Error::new(io::ErrorKind::Other, "connection closed"));
+            }
+            if &data[len..] != b"\r\n" {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed bulk string"));
+            }
+            data.truncate(len);
+
+            Ok(Reply::Bulk(Some(data)))
+        }
+        b'*' => {
+            let len = try!(parse(rest));
+            if len < 0 {
+                return Ok(Reply::Array(None));
+            }
+
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(try!(read(rd)));
+            }
+
+            Ok(Reply::Array(Some(items)))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown reply type")),
+    }
+}
+
+/// Reads a line terminated by CRLF, returning it without the terminator.
+fn read_line<R: BufRead>(rd: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if try!(rd.read_line(&mut line)) == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "connection closed"));
+    }
+
+    if !line.ends_with("\r\n") {
+        return Err(io::Error::new(io::ErrorKind::Other, "connection closed"));
+    }
+
+    let len = line.len() - 2;
+    line.truncate(len);
+    if line.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed reply line"));
+    }
+
+    Ok(line)
+}
+
+fn parse(value: &str) -> io::Result<i64> {
+    value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "malformed reply length"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::BufReader;
+
+    use super::{command, read, Reply};
+
+    #[test]
+    fn frame_command() {
+        assert_eq!(b"*2\r\n$4\r\nPING\r\n$0\r\n\r\n".to_vec(), command(&[&b"PING"[..], &b""[..]]));
+    }
+
+    #[test]
+    fn read_nested_replies() {
+        let data = b"*3\r\n+OK\r\n$5\r\nhello\r\n*2\r\n:42\r\n$-1\r\n-ERR wrong\r\n";
+        let mut rd = BufReader::new(&data[..]);
+
+        let expected = Reply::Array(Some(vec![
+            Reply::Status("OK".to_string()),
+            Reply::Bulk(Some(b"hello".to_vec())),
+            Reply::Array(Some(vec![Reply::Integer(42), Reply::Bulk(None)])),
+        ]));
+        assert_eq!(expected, read(&mut rd).unwrap());
+        assert_eq!(Reply::Error("ERR wrong".to_string()), read(&mut rd).unwrap());
+        assert!(read(&mut rd).is_err());
+    }
+
+    #[test]
+    fn fail_on_truncated_bulk() {
+        let mut rd = BufReader::new(&b"$5\r\nhel"[..]);
+        assert!(read(&mut rd).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error, FromConfig};
+
+/// Retention buffer limits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Maximum number of records kept.
+    pub capacity: usize,
+    /// Maximum number of records kept per source, if limited.
+    pub per_source: Option<usize>,
+    /// Field telling the source of a record.
+    pub source: String,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            capacity: 1000,
+            per_source: None,
+            source: "source".to_string(),
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let default = Settings::default();
+        let capacity = try!(config.u64_or("capacity", default.capacity as u64)) as usize;
+        let per_source = match config.find("per_source") {
+            Some(..) => Some(try!(config.u64_or("per_source", 0)) as usize),
+            None => None,
+        };
+
+        if capacity == 0 || per_source == Some(0) {
+            return Err(Error::Invalid("retention 'capacity' and 'per_source' must be positive".to_string()));
+        }
+
+        Ok(Settings {
+            capacity: capacity,
+            per_source: per_source,
+            source: try!(config.string_or("source", &default.source)),
+        })
+    }
+}
+
+/// Retained record together with its arrival time in milliseconds.
+struct Entry {
+    time: u64,
+    source: Option<String>,
+    record: Arc<Record>,
+}
+
+struct Ring {
+    entries: VecDeque<Entry>,
+    sources: HashMap<String, usize>,
+    subscribers: Vec<SyncSender<Arc<Record>>>,
+}
+
+/// In-memory ring of the last records leaving the filter chain, shared by all its clones.
+///
+/// Once full the oldest record is evicted, unless the source of the new record already has its
+/// own limit of records retained, in which case the oldest one of that source goes instead.
+///
+/// Records are stored behind `Arc`, so live subscribers receive them without further copies.
+#[derive(Clone)]
+pub struct Retention {
+    settings: Arc<Settings>,
+    ring: Arc<Mutex<Ring>>,
+    clock: Arc<Clock>,
+}
+
+impl Retention {
+    pub fn new(settings: Settings) -> Retention {
+        Retention::with_clock(settings, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(settings: Settings, clock: Arc<Clock>) -> Retention {
+        let ring = Ring {
+            entries: VecDeque::with_capacity(settings.capacity),
+            sources: HashMap::new(),
+            subscribers: Vec::new(),
+        };
+
+        Retention {
+            settings: Arc::new(settings),
+            ring: Arc::new(Mutex::new(ring)),
+            clock: clock,
+        }
+    }
+
+    pub fn push(&self, record: Arc<Record>) {
+        let source = record.find(&self.settings.source).and_then(|value| value.as_str()).map(|source| source.to_string());
+
+        let mut ring = self.ring.lock().unwrap();
+        ring.subscribers.retain(|tx| {
+            match tx.try_send(record.clone()) {
+                Ok(()) | Err(TrySendError::Full(..)) => true,
+                Err(TrySendError::Disconnected(..)) => false,
+            }
+        });
+
+        let limited = match (self.settings.per_source, source.as_ref()) {
+            (Some(limit), Some(source)) => ring.sources.get(source).map_or(false, |&count| count >= limit),
+            _ => false,
+        };
+
+        if limited {
+            let position = ring.entries.iter().position(|entry| entry.source == source);
+            if let Some(position) = position {
+                ring.evict(position);
+            }
+        } else if ring.entries.len() >= self.settings.capacity {
+            ring.evict(0);
+        }
+
+        if let Some(ref source) = source {
+            *ring.sources.entry(source.clone()).or_insert(0) += 1;
+        }
+
+        ring.entries.push_back(Entry {
+            time: self.clock.now(),
+            source: source,
+            record: record,
+        });
+    }
+
+    /// Returns the last `count` records, oldest first.
+    pub fn tail(&self, count: usize) -> Vec<Record> {
+        let ring = self.ring.lock().unwrap();
+        let skip = ring.entries.len().saturating_sub(count);
+        ring.entries.iter().skip(skip).map(|entry| (*entry.record).clone()).collect()
+    }
+
+    /// Returns records arrived within the given time range in milliseconds, inclusive.
+    pub fn range(&self, from: u64, to: u64) -> Vec<Record> {
+        self.select(|entry| entry.time >= from && entry.time <= to)
+    }
+
+    /// Returns records having the given field equal to the value.
+    pub fn matching(&self, field: &str, value: &RecordItem) -> Vec<Record> {
+        self.select(|entry| entry.record.find(field) == Some(value))
+    }
+
+    /// Subscribes to records retained from now on.
+    ///
+    /// Records not fitting into the channel bound are skipped for this subscriber only. Dropping
+    /// the receiver cancels the subscription.
+    pub fn subscribe(&self, bound: usize) -> Receiver<Arc<Record>> {
+        let (tx, rx) = sync_channel(bound);
+        self.ring.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.lock().unwrap().entries.len()
+    }
+
+    fn select<F: Fn(&Entry) -> bool>(&self, f: F) -> Vec<Record> {
+        let ring = self.ring.lock().unwrap();
+        ring.entries.iter().filter(|entry| f(entry)).map(|entry| (*entry.record).clone()).collect()
+    }
+}
+
+impl Ring {
+    fn evict(&mut self, position: usize) {
+        let entry = match self.entries.remove(position) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if let Some(source) = entry.source {
+            let empty = match self.sources.get_mut(&source) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+
+            if empty {
+                self.sources.remove(&source);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::{Record, RecordItem};
+    use super::super::clock::MockClock;
+    use super::super::testing::{record, string};
+    use super::{Retention, Settings};
+
+    fn entry(source: &str, id: u32) -> Arc<Record> {
+        Arc::new(record(vec![("source", string(source)), ("id", RecordItem::F64(id as f64))]))
+    }
+
+    fn ids(records: Vec<Record>) -> Vec<u32> {
+        records.iter().map(|record| {
+            match record.find("id") {
+                Some(&RecordItem::F64(id)) => id as u32,
+                other => panic!("unexpected id: {:?}", other),
+            }
+        }).collect()
+    }
+
+    fn retention(capacity: usize, per_source: Option<usize>, clock: &MockClock) -> Retention {
+        let settings = Settings { capacity: capacity, per_source: per_source, ..Settings::default() };
+        Retention::with_clock(settings, Arc::new(clock.clone()))
+    }
+
+    #[test]
+    fn evict_oldest_past_capacity() {
+        let clock = MockClock::new(0);
+        let retention = retention(3, None, &clock);
+        for id in 0..5 {
+            retention.push(entry("app", id));
+        }
+
+        assert_eq!(3, retention.len());
+        assert_eq!(vec![2, 3, 4], ids(retention.tail(10)));
+        assert_eq!(vec![3, 4], ids(retention.tail(2)));
+    }
+
+    #[test]
+    fn limit_noisy_source() {
+        let clock = MockClock::new(0);
+        let retention = retention(4, Some(2), &clock);
+        retention.push(entry("quiet", 0));
+        for id in 1..10 {
+            retention.push(entry("noisy", id));
+        }
+        retention.push(entry("other", 10));
+
+        assert_eq!(vec![0, 8, 9, 10], ids(retention.tail(10)));
+        assert_eq!(vec![8, 9], ids(retention.matching("source", &string("noisy"))));
+    }
+
+    #[test]
+    fn query_by_time_range() {
+        let clock = MockClock::new(1000);
+        let retention = retention(10, None, &clock);
+        for id in 0..5 {
+            retention.push(entry("app", id));
+            clock.advance(100);
+        }
+
+        assert_eq!(vec![1, 2, 3], ids(retention.range(1100, 1300)));
+        assert!(retention.range(0, 999).is_empty());
+    }
+
+    #[test]
+    fn subscribe_to_live_records_with_bound() {
+        let clock = MockClock::new(0);
+        let retention = retention(10, None, &clock);
+        retention.push(entry("app", 0));
+
+        let rx = retention.subscribe(2);
+        for id in 1..5 {
+            retention.push(entry("app", id));
+        }
+
+        let mut received = Vec::new();
+        while let Ok(record) = rx.try_recv() {
+            received.push((*record).clone());
+        }
+        assert_eq!(vec![1, 2], ids(received));
+
+        retention.push(entry("app", 5));
+        assert_eq!(5, ids(vec![(*rx.recv().unwrap()).clone()])[0]);
+    }
+}
@@ -0,0 +1,413 @@
+use std::cell::Cell;
+use std::cmp;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error, FromConfig};
+use super::metrics;
+use super::metrics::Counter;
+use super::sys;
+
+/// Highest nice value, the lowest priority.
+const MAX_NICE: u64 = 19;
+
+thread_local!(static ENTERED: Cell<bool> = Cell::new(false));
+
+/// System calls setting up threads and sampling CPU usage, so that tests can script them.
+pub trait Syscalls : Send + Sync {
+    /// Sets the nice value of the calling thread.
+    fn set_nice(&self, nice: i32) -> io::Result<()>;
+    /// Pins the calling thread to the CPUs, returning false where the platform doesn't allow it.
+    fn set_affinity(&self, cpus: &[usize]) -> io::Result<bool>;
+    /// Returns the CPU time consumed by the process so far, in microseconds.
+    fn cpu_time(&self) -> io::Result<u64>;
+}
+
+pub struct System;
+
+impl Syscalls for System {
+    fn set_nice(&self, nice: i32) -> io::Result<()> {
+        sys::set_nice(nice)
+    }
+
+    fn set_affinity(&self, cpus: &[usize]) -> io::Result<bool> {
+        sys::set_affinity(cpus)
+    }
+
+    fn cpu_time(&self) -> io::Result<u64> {
+        sys::cpu_time()
+    }
+}
+
+/// Soft CPU budget of the process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Budget {
+    /// CPU time allowed per wall-clock time, in percents of a single CPU.
+    pub percent: u64,
+    /// Period the CPU time is sampled at, in milliseconds.
+    pub interval: u64,
+    /// Cap of a single pause, in milliseconds.
+    pub max_pause: u64,
+}
+
+/// Scheduling of data-path threads, each mechanism being off unless set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Nice value of data-path threads.
+    pub nice: Option<i32>,
+    /// CPUs data-path threads are pinned to.
+    pub affinity: Vec<usize>,
+    pub budget: Option<Budget>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            nice: None,
+            affinity: Vec::new(),
+            budget: None,
+        }
+    }
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, Error> {
+        let nice = match config.find("nice") {
+            Some(..) => {
+                let nice = try!(config.u64_or("nice", 0));
+                if nice > MAX_NICE {
+                    return Err(Error::Invalid(format!("'nice' must be at most {}", MAX_NICE)));
+                }
+                Some(nice as i32)
+            }
+            None => None,
+        };
+
+        let mut affinity = Vec::new();
+        for cpu in try!(config.numbers_or("affinity", Vec::new())).into_iter() {
+            if cpu < 0.0 || cpu.fract() != 0.0 {
+                return Err(Error::Invalid(format!("'affinity' must list CPU numbers, got {}", cpu)));
+            }
+            affinity.push(cpu as usize);
+        }
+
+        let budget = match config.find("cpu_budget") {
+            Some(..) => {
+                let percent = try!(config.u64_or("cpu_budget", 0));
+                let interval = try!(config.u64_or("budget_interval", 1000));
+                if percent == 0 || interval == 0 {
+                    return Err(Error::Invalid("'cpu_budget' and 'budget_interval' must be positive".to_string()));
+                }
+
+                Some(Budget {
+                    percent: percent,
+                    interval: interval,
+                    max_pause: try!(config.u64_or("max_pause", 1000)),
+                })
+            }
+            None => None,
+        };
+
+        Ok(Settings {
+            nice: nice,
+            affinity: affinity,
+            budget: budget,
+        })
+    }
+}
+
+/// Decides pauses keeping the CPU usage within the budget out of samples of the CPU time.
+pub struct Controller {
+    budget: Budget,
+    /// Instant and CPU time of the previous sample.
+    last: Option<(u64, u64)>,
+}
+
+impl Controller {
+    pub fn new(budget: Budget) -> Controller {
+        Controller {
+            budget: budget,
+            last: None,
+        }
+    }
+
+    /// Returns true if the next sample is due at the instant.
+    pub fn is_due(&self, now: u64) -> bool {
+        match self.last {
+            Some((at, _)) => now.saturating_sub(at) >= self.budget.interval,
+            None => true,
+        }
+    }
+
+    /// Takes the sample of the CPU time in microseconds consumed by the instant, returning the
+    /// pause in milliseconds that brings the usage since the previous sample back to the budget.
+    ///
+    /// The pause counts as the wall-clock time of the next sample, so that usage a pause is not
+    /// enough to cover is paid off later.
+    pub fn decide(&mut self, now: u64, cpu: u64) -> u64 {
+        let pause = match self.last {
+            Some((at, used)) if now > at => {
+                // Wall-clock time in milliseconds the usage takes to fit into the budget.
+                let fitting = cpu.saturating_sub(used) / (self.budget.percent * 10);
+                cmp::min(fitting.saturating_sub(now - at), self.budget.max_pause)
+            }
+            _ => 0,
+        };
+
+        self.last = Some((now, cpu));
+        pause
+    }
+}
+
+/// Schedules data-path threads, i.e. ones decoding input connections and feeding outputs, apart
+/// from control ones such as the dispatch loop, the ticker, alerting and reloads, which keep the
+/// process priority and CPUs so that a flood of records doesn't starve them.
+///
+/// Data-path threads get the nice value and are pinned to the CPUs once, as they start. Decoding
+/// threads also pause between records while the process is over the CPU budget, holding records
+/// back in their connections, while output workers never pause, so that the backlog keeps
+/// draining.
+///
+/// Outcomes are counted in the `sched.nice.applied`, `sched.nice.failed`,
+/// `sched.affinity.applied`, `sched.affinity.failed` and `sched.affinity.unsupported` metrics,
+/// pauses in `sched.budget.paused` and the time decoding threads spend in them in
+/// `sched.budget.paused_ms`.
+pub struct Scheduler {
+    settings: Settings,
+    syscalls: Arc<Syscalls>,
+    clock: Arc<Clock>,
+    /// Budget controller together with the end of the current pause, if the budget is set.
+    budget: Option<Mutex<(Controller, u64)>>,
+    /// Whether the lack of affinity support has been reported.
+    reported: AtomicBool,
+    paused: Counter,
+    paused_ms: Counter,
+}
+
+impl Scheduler {
+    pub fn new(settings: Settings, syscalls: Arc<Syscalls>, clock: Arc<Clock>) -> Scheduler {
+        let registry = metrics::registry();
+        let budget = settings.budget.clone().map(|budget| Mutex::new((Controller::new(budget), 0)));
+
+        Scheduler {
+            settings: settings,
+            syscalls: syscalls,
+            clock: clock,
+            budget: budget,
+            reported: AtomicBool::new(false),
+            paused: registry.counter("sched.budget.paused"),
+            paused_ms: registry.counter("sched.budget.paused_ms"),
+        }
+    }
+
+    /// Creates the scheduler making actual system calls.
+    pub fn system(settings: Settings) -> Scheduler {
+        Scheduler::new(settings, Arc::new(System), Arc::new(SystemClock))
+    }
+
+    /// Sets the calling thread up as a data-path one, doing nothing if it already is.
+    pub fn enter(&self) {
+        if ENTERED.with(|entered| entered.get()) {
+            return;
+        }
+        ENTERED.with(|entered| entered.set(true));
+
+        let registry = metrics::registry();
+        if let Some(nice) = self.settings.nice {
+            match self.syscalls.set_nice(nice) {
+                Ok(()) => registry.counter("sched.nice.applied").inc(),
+                Err(err) => {
+                    warn!(target: "Sched", "unable to set nice value {}: {}", nice, err);
+                    registry.counter("sched.nice.failed").inc();
+                }
+            }
+        }
+
+        if !self.settings.affinity.is_empty() {
+            match self.syscalls.set_affinity(&self.settings.affinity) {
+                Ok(true) => registry.counter("sched.affinity.applied").inc(),
+                Ok(false) => {
+                    if !self.reported.swap(true, Ordering::SeqCst) {
+                        info!(target: "Sched", "CPU affinity is not supported on this platform, leaving threads unpinned");
+                    }
+                    registry.counter("sched.affinity.unsupported").inc();
+                }
+                Err(err) => {
+                    warn!(target: "Sched", "unable to pin thread to CPUs {:?}: {}", self.settings.affinity, err);
+                    registry.counter("sched.affinity.failed").inc();
+                }
+            }
+        }
+    }
+
+    /// Returns the pause in milliseconds the calling thread must take now to keep the process
+    /// within the budget, sampling the CPU time if it's due.
+    fn pause(&self) -> u64 {
+        let mut state = match self.budget {
+            Some(ref budget) => budget.lock().unwrap(),
+            None => return 0,
+        };
+        let &mut (ref mut controller, ref mut until) = &mut *state;
+
+        let now = self.clock.now();
+        if now >= *until && controller.is_due(now) {
+            match self.syscalls.cpu_time() {
+                Ok(cpu) => {
+                    let pause = controller.decide(now, cpu);
+                    if pause > 0 {
+                        debug!(target: "Sched", "pausing decoding for {} ms: over the CPU budget", pause);
+                        self.paused.inc();
+                        *until = now + pause;
+                    }
+                }
+                Err(err) => warn!(target: "Sched", "unable to sample CPU time: {}", err),
+            }
+        }
+
+        until.saturating_sub(now)
+    }
+
+    /// Sleeps through the current pause, if the process is over the budget.
+    pub fn throttle(&self) {
+        let pause = self.pause();
+        if pause > 0 {
+            self.paused_ms.add(pause as usize);
+            thread::sleep_ms(pause as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::super::clock::MockClock;
+    use super::super::metrics;
+    use super::{Budget, Controller, Scheduler, Settings, Syscalls};
+
+    /// Records calls, answering affinity ones as told and CPU time ones out of the script.
+    struct Mock {
+        calls: Mutex<Vec<String>>,
+        affinity: bool,
+        samples: Mutex<Vec<u64>>,
+    }
+
+    impl Mock {
+        fn new(affinity: bool, mut samples: Vec<u64>) -> Arc<Mock> {
+            samples.reverse();
+            Arc::new(Mock {
+                calls: Mutex::new(Vec::new()),
+                affinity: affinity,
+                samples: Mutex::new(samples),
+            })
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl Syscalls for Mock {
+        fn set_nice(&self, nice: i32) -> io::Result<()> {
+            self.calls.lock().unwrap().push(format!("nice {}", nice));
+            Ok(())
+        }
+
+        fn set_affinity(&self, cpus: &[usize]) -> io::Result<bool> {
+            self.calls.lock().unwrap().push(format!("affinity {:?}", cpus));
+            Ok(self.affinity)
+        }
+
+        fn cpu_time(&self) -> io::Result<u64> {
+            self.calls.lock().unwrap().push("cpu_time".to_string());
+            self.samples.lock().unwrap().pop().ok_or(io::Error::new(io::ErrorKind::Other, "no samples"))
+        }
+    }
+
+    fn budget(percent: u64) -> Budget {
+        Budget {
+            percent: percent,
+            interval: 1000,
+            max_pause: 2000,
+        }
+    }
+
+    #[test]
+    fn decide_pauses_from_samples() {
+        let mut controller = Controller::new(budget(50));
+        assert!(controller.is_due(0));
+        assert_eq!(0, controller.decide(0, 0));
+        assert!(!controller.is_due(999));
+
+        // 0.4s of CPU time within a second fits half of a CPU.
+        assert_eq!(0, controller.decide(1000, 400000));
+        // 1s takes two seconds to fit.
+        assert_eq!(1000, controller.decide(2000, 1400000));
+        // The pause counts as the time of the next sample.
+        assert_eq!(0, controller.decide(4000, 1900000));
+        // Pauses are capped.
+        assert_eq!(2000, controller.decide(5000, 9900000));
+        // Samples taken at the same instant tell nothing.
+        assert_eq!(0, controller.decide(5000, 19900000));
+    }
+
+    #[test]
+    fn set_up_data_threads_once() {
+        let unsupported = metrics::registry().counter("sched.affinity.unsupported");
+        let before = unsupported.get();
+
+        let settings = Settings {
+            nice: Some(10),
+            affinity: vec![1, 3],
+            budget: None,
+        };
+        let mock = Mock::new(true, Vec::new());
+        let scheduler = Arc::new(Scheduler::new(settings.clone(), mock.clone(), Arc::new(MockClock::new(0))));
+        thread::spawn(move || {
+            scheduler.enter();
+            scheduler.enter();
+        }).join().unwrap();
+        assert_eq!(vec!["nice 10".to_string(), "affinity [1, 3]".to_string()], mock.calls());
+
+        let mock = Mock::new(false, Vec::new());
+        let scheduler = Arc::new(Scheduler::new(settings, mock.clone(), Arc::new(MockClock::new(0))));
+        thread::spawn(move || scheduler.enter()).join().unwrap();
+        assert_eq!(before + 1, unsupported.get());
+
+        // Control threads are left alone, as is everything without settings.
+        let mock = Mock::new(true, Vec::new());
+        let scheduler = Arc::new(Scheduler::new(Settings::default(), mock.clone(), Arc::new(MockClock::new(0))));
+        thread::spawn(move || scheduler.enter()).join().unwrap();
+        assert!(mock.calls().is_empty());
+    }
+
+    #[test]
+    fn pause_decoding_over_budget() {
+        let clock = MockClock::new(0);
+        let mock = Mock::new(true, vec![0, 2000000, 2500000]);
+        let settings = Settings {
+            budget: Some(budget(100)),
+            ..Settings::default()
+        };
+        let scheduler = Scheduler::new(settings, mock.clone(), Arc::new(clock.clone()));
+
+        assert_eq!(0, scheduler.pause());
+        clock.advance(500);
+        assert_eq!(0, scheduler.pause());
+
+        clock.advance(500);
+        assert_eq!(1000, scheduler.pause());
+        clock.advance(500);
+        assert_eq!(500, scheduler.pause());
+        assert_eq!(2, mock.calls().len());
+
+        clock.advance(500);
+        assert_eq!(0, scheduler.pause());
+        assert_eq!(3, mock.calls().len());
+    }
+}
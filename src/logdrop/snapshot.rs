@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::{Record, RecordItem};
+use super::clock::{Clock, SystemClock};
+use super::config::{Config, Error as ConfigError, FromConfig};
+use super::spool::{Reader, Writer};
+
+/// Name of the snapshot file within the state directory.
+const FILE: &'static str = "pipeline.snapshot";
+
+/// Soft state of a component worth carrying over a restart, e.g. a deduplication window or a
+/// record held back until its continuation arrives.
+///
+/// The whole snapshot is read into memory at start, so components must keep their state bounded
+/// whatever records go through them.
+pub trait Snapshot {
+    /// Version of the state layout, states of other versions are skipped on restore.
+    fn version(&self) -> u32;
+
+    /// Returns the state, if there is any. Records held back by the component move into the
+    /// state rather than being left to the following flush, listed under the `held` field as
+    /// the flush would emit them, so that they're emitted rather than lost if the state is
+    /// skipped on restore.
+    fn snapshot(&mut self) -> Option<Record>;
+
+    /// Restores the state, failing if it doesn't fit the component, e.g. as its config has changed.
+    fn restore(&mut self, state: &Record) -> Result<(), String>;
+}
+
+/// Where the snapshot is kept and for how long it's worth restoring.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Durable state directory.
+    pub dir: PathBuf,
+    /// Age in milliseconds past which the snapshot is ignored.
+    pub max_age: u64,
+}
+
+impl FromConfig for Settings {
+    fn from_config(config: &Config) -> Result<Settings, ConfigError> {
+        Ok(Settings {
+            dir: PathBuf::from(try!(config.string("dir"))),
+            max_age: try!(config.u64_or("max_age", 600000)),
+        })
+    }
+}
+
+/// States of pipeline components restored at start and taken at shutdown, keyed by the component
+/// position, e.g. `filter.0` or `output.1`.
+///
+/// The snapshot is a single file in the state directory, removed once loaded so that a crash
+/// doesn't restore the same state twice. Restoring is best-effort: the snapshot older than the
+/// configured age is ignored as a whole, while states of another component type or layout
+/// version, or refused by the component, are skipped with a warning. Records held in skipped
+/// states are given back by `Store::held` for the pipeline to emit.
+pub struct Store {
+    settings: Settings,
+    clock: Arc<Clock>,
+    /// States loaded at start.
+    loaded: HashMap<String, RecordItem>,
+    /// Records held in skipped states.
+    skipped: HashMap<String, Vec<Record>>,
+    /// States taken at shutdown.
+    taken: HashMap<String, RecordItem>,
+}
+
+impl Store {
+    pub fn open(settings: Settings) -> Store {
+        Store::with_clock(settings, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(settings: Settings, clock: Arc<Clock>) -> Store {
+        let path = settings.dir.join(FILE);
+        let mut store = Store {
+            settings: settings,
+            clock: clock,
+            loaded: HashMap::new(),
+            skipped: HashMap::new(),
+            taken: HashMap::new(),
+        };
+
+        match Reader::open(&path).and_then(|mut rd| rd.next()) {
+            Ok(Some(record)) => {
+                if let Err(err) = fs::remove_file(&path) {
+                    warn!(target: "Snapshot", "unable to remove '{}': {}", path.display(), err);
+                }
+                store.load(&record);
+            }
+            Ok(None) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!(target: "Snapshot", "unable to read '{}': {}", path.display(), err),
+        }
+
+        store
+    }
+
+    fn load(&mut self, record: &Record) {
+        let age = match record.find("taken") {
+            Some(&RecordItem::F64(taken)) => self.clock.now().saturating_sub(taken as u64),
+            _ => return,
+        };
+
+        self.loaded = match record.find("components") {
+            Some(&RecordItem::Object(ref components)) => components.clone(),
+            _ => return,
+        };
+
+        if age > self.settings.max_age {
+            info!(target: "Snapshot", "ignoring snapshot taken {} ms ago", age);
+            let keys: Vec<String> = self.loaded.keys().cloned().collect();
+            for key in keys.iter() {
+                self.skip(key);
+            }
+        }
+    }
+
+    /// Drops the loaded state of the component, keeping records held in it aside.
+    fn skip(&mut self, key: &str) {
+        let entry = match self.loaded.remove(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let held = match entry.find("state").and_then(|state| state.find("held")) {
+            Some(&RecordItem::Array(ref held)) => held,
+            _ => return,
+        };
+
+        let records: Vec<Record> = held.iter().filter_map(|item| {
+            match *item {
+                RecordItem::Object(ref fields) => {
+                    let mut record = Record::new();
+                    for (name, value) in fields.iter() {
+                        record.insert(name.clone(), value.clone());
+                    }
+                    Some(record)
+                }
+                _ => None,
+            }
+        }).collect();
+
+        if !records.is_empty() {
+            info!(target: "Snapshot", "emitting {} records held in skipped '{}' state", records.len(), key);
+            self.skipped.insert(key.to_string(), records);
+        }
+    }
+
+    /// Returns records held in skipped states of components under the given prefix, e.g.
+    /// `filter.`, together with their keys. States left unrestored by now are skipped as well,
+    /// as their component is gone or has no state anymore.
+    pub fn held(&mut self, prefix: &str) -> Vec<(String, Vec<Record>)> {
+        let keys: Vec<String> = self.loaded.keys().filter(|key| key.starts_with(prefix)).cloned().collect();
+        for key in keys.iter() {
+            warn!(target: "Snapshot", "skipping '{}' state: no such component", key);
+            self.skip(key);
+        }
+
+        let keys: Vec<String> = self.skipped.keys().filter(|key| key.starts_with(prefix)).cloned().collect();
+        keys.into_iter().map(|key| {
+            let records = self.skipped.remove(&key).unwrap();
+            (key, records)
+        }).collect()
+    }
+
+    /// Restores the component from its state, returning true if it was restored.
+    pub fn restore(&mut self, key: &str, typename: &str, component: &mut Snapshot) -> bool {
+        let restored = match self.loaded.get(key) {
+            Some(entry) => Store::apply(key, entry, typename, component),
+            None => return false,
+        };
+
+        if restored {
+            self.loaded.remove(key);
+        } else {
+            self.skip(key);
+        }
+        restored
+    }
+
+    fn apply(key: &str, entry: &RecordItem, typename: &str, component: &mut Snapshot) -> bool {
+        match entry.find("type").and_then(|value| value.as_str()) {
+            Some(name) if name == typename => {}
+            Some(..) | None => {
+                warn!(target: "Snapshot", "skipping '{}' state: component type is changed to '{}'", key, typename);
+                return false;
+            }
+        }
+
+        match entry.find("version") {
+            Some(&RecordItem::F64(version)) if version == component.version() as f64 => {}
+            _ => {
+                warn!(target: "Snapshot", "skipping '{}' state: layout version is not {}", key, component.version());
+                return false;
+            }
+        }
+
+        let state = match entry.find("state") {
+            Some(&RecordItem::Object(ref state)) => {
+                let mut record = Record::new();
+                for (name, value) in state.iter() {
+                    record.insert(name.clone(), value.clone());
+                }
+                record
+            }
+            _ => return false,
+        };
+
+        match component.restore(&state) {
+            Ok(()) => {
+                debug!(target: "Snapshot", "restored '{}' state", key);
+                true
+            }
+            Err(reason) => {
+                warn!(target: "Snapshot", "skipping '{}' state: {}", key, reason);
+                false
+            }
+        }
+    }
+
+    /// Takes the component state into the snapshot.
+    pub fn keep(&mut self, key: &str, typename: &str, component: &mut Snapshot) {
+        let state = match component.snapshot() {
+            Some(state) => state,
+            None => return,
+        };
+
+        let mut entry = HashMap::new();
+        entry.insert("type".to_string(), RecordItem::String(typename.to_string()));
+        entry.insert("version".to_string(), RecordItem::F64(component.version() as f64));
+        entry.insert("state".to_string(), RecordItem::Object(state.iter().map(|(name, value)| {
+            (name.clone(), value.clone())
+        }).collect()));
+        self.taken.insert(key.to_string(), RecordItem::Object(entry));
+    }
+
+    /// Writes the states taken aside and renames them over the previous snapshot, so that a crash
+    /// leaves either of them intact.
+    pub fn save(&self) -> io::Result<()> {
+        let mut record = Record::new();
+        record.insert("taken".to_string(), RecordItem::F64(self.clock.now() as f64));
+        record.insert("components".to_string(), RecordItem::Object(self.taken.clone()));
+
+        let path = self.settings.dir.join(FILE);
+        let temporary = self.settings.dir.join(format!("{}.tmp", FILE));
+        {
+            let mut wr = try!(Writer::create(&temporary));
+            try!(wr.append(&record));
+            try!(wr.sync());
+        }
+        fs::rename(&temporary, &path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::super::{Record, RecordItem};
+    use super::super::clock::MockClock;
+    use super::super::filter::{Coalesce, Filter};
+    use super::super::output::{Dedup, DedupSettings, Output};
+    use super::super::testing::{Collector, record, string};
+    use super::{Settings, Snapshot, Store};
+
+    fn settings(name: &str) -> Settings {
+        let dir = env::temp_dir().join(format!("logdrop-test-snapshot-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        Settings {
+            dir: dir,
+            max_age: 60000,
+        }
+    }
+
+    fn dedup(collector: &Collector) -> Dedup {
+        Dedup::new(Box::new(collector.clone()), DedupSettings::default())
+    }
+
+    #[test]
+    fn carry_state_over_restart() {
+        let settings = settings("restart");
+        let clock = MockClock::new(1000);
+
+        let collector = Collector::new();
+        let mut output = dedup(&collector);
+        output.feed(&record(vec![("idempotency_id", string("1"))])).unwrap();
+        output.flush().unwrap();
+
+        let mut filter = Coalesce::new(Vec::new());
+        assert_eq!(1, filter.apply(record(vec![("id", string("held"))])).len());
+        assert!(filter.apply(record(vec![("id", string("held"))])).is_empty());
+
+        let mut store = Store::with_clock(settings.clone(), Arc::new(clock.clone()));
+        store.keep("output.0", output.typename(), &mut output);
+        store.keep("filter.0", filter.typename(), &mut filter);
+        store.save().unwrap();
+        // Held records move into the snapshot.
+        assert!(filter.flush().is_empty());
+
+        clock.advance(5000);
+        let mut store = Store::with_clock(settings.clone(), Arc::new(clock.clone()));
+        let mut output = dedup(&collector);
+        let mut filter = Coalesce::new(Vec::new());
+        assert!(store.restore("output.0", output.typename(), &mut output));
+        assert!(store.restore("filter.0", filter.typename(), &mut filter));
+
+        output.feed(&record(vec![("idempotency_id", string("1"))])).unwrap();
+        output.flush().unwrap();
+        assert_eq!(1, collector.records().len());
+        assert_eq!(1, output.suppressed());
+
+        assert!(filter.apply(record(vec![("id", string("held"))])).is_empty());
+        let expected = record(vec![("id", string("held")), ("repeated", RecordItem::F64(3.0))]);
+        assert_eq!(vec![expected], filter.flush());
+
+        // Consumed by the restore.
+        let mut filter = Coalesce::new(Vec::new());
+        assert!(!Store::open(settings).restore("filter.0", filter.typename(), &mut filter));
+    }
+
+    #[test]
+    fn skip_mismatching_states() {
+        let settings = settings("mismatch");
+
+        let mut filter = Coalesce::new(vec!["id".to_string()]);
+        filter.apply(record(vec![("id", string("held"))]));
+        filter.apply(record(vec![("id", string("held"))]));
+        let mut output = dedup(&Collector::new());
+        output.feed(&record(vec![("idempotency_id", string("1"))])).unwrap();
+        output.flush().unwrap();
+
+        let mut store = Store::open(settings.clone());
+        store.keep("filter.0", filter.typename(), &mut filter);
+        store.keep("output.0", output.typename(), &mut output);
+        store.save().unwrap();
+
+        let mut store = Store::open(settings);
+        // Fields compared by the filter are changed.
+        let mut filter = Coalesce::new(vec!["message".to_string()]);
+        assert!(!store.restore("filter.0", filter.typename(), &mut filter));
+        assert!(filter.flush().is_empty());
+        // Another component took the position.
+        let mut filter = Coalesce::new(Vec::new());
+        assert!(!store.restore("output.0", filter.typename(), &mut filter));
+
+        // The record held in the skipped state is given back rather than lost.
+        let expected = record(vec![("id", string("held")), ("repeated", RecordItem::F64(2.0))]);
+        let held = vec![("filter.0".to_string(), vec![expected])];
+        assert_eq!(held, store.held("filter."));
+        assert!(store.held("filter.").is_empty());
+    }
+
+    #[test]
+    fn skip_other_layout_version() {
+        struct Versioned(u32, bool);
+
+        impl Snapshot for Versioned {
+            fn version(&self) -> u32 {
+                self.0
+            }
+
+            fn snapshot(&mut self) -> Option<Record> {
+                Some(record(vec![("value", RecordItem::F64(42.0))]))
+            }
+
+            fn restore(&mut self, _state: &Record) -> Result<(), String> {
+                self.1 = true;
+                Ok(())
+            }
+        }
+
+        let settings = settings("version");
+        let mut store = Store::open(settings.clone());
+        store.keep("filter.0", "versioned", &mut Versioned(1, false));
+        store.save().unwrap();
+
+        let mut component = Versioned(2, false);
+        assert!(!Store::open(settings).restore("filter.0", "versioned", &mut component));
+        assert!(!component.1);
+    }
+
+    #[test]
+    fn ignore_stale_snapshot() {
+        let settings = settings("stale");
+        let clock = MockClock::new(1000);
+
+        let mut filter = Coalesce::new(Vec::new());
+        filter.apply(record(vec![("id", string("held"))]));
+        filter.apply(record(vec![("id", string("held"))]));
+        let mut store = Store::with_clock(settings.clone(), Arc::new(clock.clone()));
+        store.keep("filter.0", filter.typename(), &mut filter);
+        store.save().unwrap();
+
+        clock.advance(60001);
+        let mut filter = Coalesce::new(Vec::new());
+        let mut store = Store::with_clock(settings, Arc::new(clock.clone()));
+        assert!(!store.restore("filter.0", filter.typename(), &mut filter));
+        assert!(filter.flush().is_empty());
+
+        let expected = record(vec![("id", string("held")), ("repeated", RecordItem::F64(2.0))]);
+        let held = vec![("filter.0".to_string(), vec![expected])];
+        assert_eq!(held, store.held("filter."));
+    }
+
+    #[test]
+    fn give_back_records_held_by_removed_component() {
+        let settings = settings("removed");
+
+        let mut filter = Coalesce::new(Vec::new());
+        filter.apply(record(vec![("id", string("held"))]));
+        filter.apply(record(vec![("id", string("held"))]));
+        let mut store = Store::open(settings.clone());
+        store.keep("filter.1", filter.typename(), &mut filter);
+        store.save().unwrap();
+
+        let mut store = Store::open(settings);
+        let expected = record(vec![("id", string("held")), ("repeated", RecordItem::F64(2.0))]);
+        assert_eq!(vec![("filter.1".to_string(), vec![expected])], store.held("filter."));
+    }
+}
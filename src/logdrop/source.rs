@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::Record;
+use super::config::{Config, Error, FromConfig};
+use super::metrics;
+use super::metrics::Counter;
+
+/// Field holding the canonical source key stamped by the pipeline.
+pub const FIELD: &'static str = "_source";
+
+/// Default number of source keys counted under their own metrics.
+const METERED: usize = 1000;
+
+/// Key the records of sources past the metered ones are counted under.
+pub const OTHER: &'static str = "_other";
+
+/// Component of the source identity authoritative for keying.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Authority {
+    /// The source field of the record, falling back to the peer and then to the input.
+    Field,
+    /// The peer address of the connection, falling back to the input.
+    Peer,
+    /// The input name.
+    Input,
+}
+
+/// Source of a record: the input it arrived on, the connection peer and its own source field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceId {
+    pub input: String,
+    pub peer: Option<String>,
+    pub field: Option<String>,
+    key: String,
+}
+
+impl SourceId {
+    /// Returns the canonical form used for keying, in metrics names and in file templates.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl fmt::Display for SourceId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.key)
+    }
+}
+
+/// Per-pipeline policy deriving the source identity of each accepted record.
+#[derive(Clone, Debug)]
+pub struct Resolution {
+    authority: Authority,
+    field: String,
+    metered: usize,
+}
+
+impl Resolution {
+    pub fn new(authority: Authority) -> Resolution {
+        Resolution {
+            authority: authority,
+            field: "source".to_string(),
+            metered: METERED,
+        }
+    }
+
+    /// Sets the number of source keys counted under their own metrics, see `Meter`.
+    pub fn metered(mut self, metered: usize) -> Resolution {
+        self.metered = metered;
+        self
+    }
+
+    /// Returns the per-source counters capped as configured.
+    pub fn meter(&self) -> Meter {
+        Meter::new(self.metered)
+    }
+
+    /// Sets the record field telling the source, `source` by default.
+    pub fn field(mut self, field: &str) -> Resolution {
+        self.field = field.to_string();
+        self
+    }
+
+    /// Returns the record field the resolution reads.
+    pub fn field_name(&self) -> &str {
+        &self.field
+    }
+
+    pub fn resolve(&self, input: &str, peer: Option<&str>, record: &Record) -> SourceId {
+        let field = record.find(&self.field).and_then(|value| value.as_str()).map(|source| source.to_string());
+
+        let key = match (self.authority, field.as_ref(), peer) {
+            (Authority::Field, Some(field), _) => field.clone(),
+            (Authority::Field, None, Some(peer)) | (Authority::Peer, _, Some(peer)) => peer.to_string(),
+            _ => input.to_string(),
+        };
+
+        SourceId {
+            input: input.to_string(),
+            peer: peer.map(|peer| peer.to_string()),
+            field: field,
+            key: key,
+        }
+    }
+}
+
+impl FromConfig for Resolution {
+    fn from_config(config: &Config) -> Result<Resolution, Error> {
+        let authority = match &try!(config.string_or("authority", "field"))[..] {
+            "field" => Authority::Field,
+            "peer" => Authority::Peer,
+            "input" => Authority::Input,
+            authority => return Err(Error::Invalid(format!("unknown source authority '{}'", authority))),
+        };
+
+        let metered = try!(config.u64_or("max_metered_sources", METERED as u64)) as usize;
+
+        Ok(Resolution::new(authority).field(&try!(config.string_or("field", "source"))).metered(metered))
+    }
+}
+
+/// Counters of records and decoded bytes per source key, `pipeline.source.<key>.records` and
+/// `pipeline.source.<key>.bytes`.
+///
+/// Keys may come from record fields, i.e. from anyone able to send a record, so only the first
+/// `limit` keys seen get their own counters, the rest being counted under `_other`.
+pub struct Meter {
+    limit: usize,
+    counters: HashMap<String, (Counter, Counter)>,
+    other: (Counter, Counter),
+}
+
+impl Meter {
+    pub fn new(limit: usize) -> Meter {
+        Meter {
+            limit: limit,
+            counters: HashMap::new(),
+            other: counters(OTHER),
+        }
+    }
+
+    /// Counts the record of the given size from the source.
+    pub fn count(&mut self, key: &str, bytes: usize) {
+        if !self.counters.contains_key(key) && self.counters.len() < self.limit {
+            self.counters.insert(key.to_string(), counters(key));
+        }
+
+        let &(ref records, ref size) = self.counters.get(key).unwrap_or(&self.other);
+        records.inc();
+        size.add(bytes);
+    }
+}
+
+fn counters(key: &str) -> (Counter, Counter) {
+    let registry = metrics::registry();
+    (registry.counter(&format!("pipeline.source.{}.records", key)), registry.counter(&format!("pipeline.source.{}.bytes", key)))
+}
+
+/// Returns the source key of the record, the one every source-keyed component must use.
+///
+/// That is the key stamped by the pipeline resolution policy or, without one configured, the
+/// plain `source` field.
+pub fn key(record: &Record) -> Option<&str> {
+    record.find(FIELD).or_else(|| record.find("source")).and_then(|value| value.as_str())
+}
+
+/// Returns the fields `key` looks the source key up in.
+pub fn fields() -> Vec<String> {
+    vec![FIELD.to_string(), "source".to_string()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Record;
+    use super::super::metrics;
+    use super::super::testing::{record, string};
+    use super::{Authority, Meter, Resolution};
+
+    fn keys(authority: Authority) -> Vec<String> {
+        let resolution = Resolution::new(authority);
+        let with = record(vec![("source", string("app"))]);
+        let without = Record::new();
+
+        vec![
+            resolution.resolve("tcp", Some("10.0.0.1"), &with),
+            resolution.resolve("tcp", Some("10.0.0.1"), &without),
+            resolution.resolve("local", None, &with),
+            resolution.resolve("local", None, &without),
+        ].into_iter().map(|source| source.key().to_string()).collect()
+    }
+
+    #[test]
+    fn resolve_by_field() {
+        assert_eq!(vec!["app", "10.0.0.1", "app", "local"], keys(Authority::Field));
+    }
+
+    #[test]
+    fn resolve_by_peer() {
+        assert_eq!(vec!["10.0.0.1", "10.0.0.1", "local", "local"], keys(Authority::Peer));
+    }
+
+    #[test]
+    fn resolve_by_input() {
+        assert_eq!(vec!["tcp", "tcp", "local", "local"], keys(Authority::Input));
+    }
+
+    #[test]
+    fn keep_all_components() {
+        let source = Resolution::new(Authority::Input).field("host")
+            .resolve("tcp", Some("10.0.0.1"), &record(vec![("host", string("web"))]));
+
+        assert_eq!("tcp", source.input);
+        assert_eq!(Some("10.0.0.1".to_string()), source.peer);
+        assert_eq!(Some("web".to_string()), source.field);
+    }
+
+    #[test]
+    fn count_sources_past_limit_as_other() {
+        let registry = metrics::registry();
+        let before = registry.counter("pipeline.source._other.records").get();
+
+        let mut meter = Meter::new(2);
+        for key in ["meter-a", "meter-b", "meter-c", "meter-a", "meter-d"].iter() {
+            meter.count(key, 10);
+        }
+
+        assert_eq!(2, registry.counter("pipeline.source.meter-a.records").get());
+        assert_eq!(20, registry.counter("pipeline.source.meter-a.bytes").get());
+        assert_eq!(1, registry.counter("pipeline.source.meter-b.records").get());
+        assert_eq!(0, registry.counter("pipeline.source.meter-c.records").get());
+        assert_eq!(0, registry.counter("pipeline.source.meter-d.records").get());
+        assert!(registry.counter("pipeline.source._other.records").get() >= before + 2);
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::Record;
+use super::codec::msgpack;
+
+/// Appends records to a spool segment.
+///
+/// A segment is a plain sequence of frames, each one being a 4-byte big-endian length followed by
+/// the MessagePack-encoded record.
+pub struct Writer {
+    file: File,
+    /// Whether map keys are encoded ordered by name.
+    sorted: bool,
+}
+
+impl Writer {
+    /// Creates a new empty segment, truncating the existing one.
+    pub fn create(path: &Path) -> io::Result<Writer> {
+        let file = try!(File::create(path));
+        Ok(Writer { file: file, sorted: false })
+    }
+
+    /// Opens the existing segment for appending, creating it if required.
+    pub fn open(path: &Path) -> io::Result<Writer> {
+        let file = try!(OpenOptions::new().write(true).append(true).create(true).open(path));
+        Ok(Writer { file: file, sorted: false })
+    }
+
+    /// Makes the writer encode map keys ordered by name, so that equal records encode into the
+    /// same bytes.
+    pub fn sort_keys(&mut self) {
+        self.sorted = true;
+    }
+
+    /// Appends the record, returning the number of bytes written.
+    pub fn append(&mut self, record: &Record) -> io::Result<usize> {
+        let payload = msgpack::encode_record(record, self.sorted);
+        let len = payload.len() as u32;
+        let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+        try!(self.file.write_all(&header));
+        try!(self.file.write_all(&payload));
+        Ok(header.len() + payload.len())
+    }
+
+    /// Flushes the segment to the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Reads records from a spool segment in the order they were appended.
+///
+/// Reading may be interleaved with appending through a separate `Writer`: reaching the end of the
+/// segment is not final and the next call picks up records appended meanwhile, unless the reader
+/// is limited to the given offset.
+pub struct Reader {
+    rd: BufReader<File>,
+    offset: u64,
+    end: Option<u64>,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> io::Result<Reader> {
+        let file = try!(File::open(path));
+        Ok(Reader { rd: BufReader::new(file), offset: 0, end: None })
+    }
+
+    /// Stops reading at the given offset, so that records appended past it are not seen.
+    pub fn until(mut self, end: u64) -> Reader {
+        self.end = Some(end);
+        self
+    }
+
+    /// Returns the offset of the next record.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the next record or `None` if the end of the segment is reached.
+    pub fn next(&mut self) -> io::Result<Option<Record>> {
+        if let Some(end) = self.end {
+            if self.offset >= end {
+                return Ok(None);
+            }
+        }
+
+        let mut header = [0u8; 4];
+        if !try!(read_exact(&mut self.rd, &mut header)) {
+            return Ok(None);
+        }
+
+        let len = (header[0] as usize) << 24 | (header[1] as usize) << 16 |
+            (header[2] as usize) << 8 | header[3] as usize;
+
+        let mut payload = vec![0u8; len];
+        if !try!(read_exact(&mut self.rd, &mut payload)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "truncated spool frame"));
+        }
+        self.offset += (header.len() + len) as u64;
+
+        match msgpack::decode(&payload) {
+            Some(record) => Ok(Some(record)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed spool frame")),
+        }
+    }
+}
+
+/// Spooled batch of records.
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    sequence: u64,
+    count: usize,
+    size: u64,
+}
+
+/// Directory of segments, each one holding a batch of records, replayed oldest first.
+///
+/// Segments are named `<sequence>-<count>.seg`, so the spool is restored after a restart without
+/// reading them. Once the total size exceeds the limit the oldest segments are dropped.
+pub struct Spool {
+    dir: PathBuf,
+    limit: u64,
+    segments: VecDeque<Segment>,
+    sequence: u64,
+}
+
+impl Spool {
+    /// Opens the spool in the given directory, picking up segments left by previous runs.
+    pub fn open(dir: &Path, limit: u64) -> io::Result<Spool> {
+        try!(fs::create_dir_all(dir));
+
+        let mut segments = Vec::new();
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if name.ends_with(".seg") => name[..name.len() - 4].to_string(),
+                _ => continue,
+            };
+
+            let mut parts = name.splitn(2, '-');
+            let sequence = parts.next().and_then(|v| v.parse().ok());
+            let count = parts.next().and_then(|v| v.parse().ok());
+            match (sequence, count) {
+                (Some(sequence), Some(count)) => {
+                    let size = try!(fs::metadata(&path)).len();
+                    segments.push(Segment { path: path, sequence: sequence, count: count, size: size });
+                }
+                _ => warn!(target: "Spool", "ignoring unknown file {:?}", path),
+            }
+        }
+
+        segments.sort_by(|a, b| a.sequence.cmp(&b.sequence));
+        let sequence = segments.last().map(|segment| segment.sequence + 1).unwrap_or(0);
+
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            limit: limit,
+            segments: segments.into_iter().collect(),
+            sequence: sequence,
+        })
+    }
+
+    /// Writes the batch as a new segment, returning the number of records dropped to fit the
+    /// size limit.
+    pub fn push(&mut self, records: &[Record]) -> io::Result<usize> {
+        let path = self.dir.join(format!("{:020}-{}.seg", self.sequence, records.len()));
+        let mut size = 0;
+        {
+            let mut writer = try!(Writer::create(&path));
+            for record in records.iter() {
+                size += try!(writer.append(record)) as u64;
+            }
+            try!(writer.sync());
+        }
+
+        self.segments.push_back(Segment { path: path, sequence: self.sequence, count: records.len(), size: size });
+        self.sequence += 1;
+
+        let mut dropped = 0;
+        while self.size() > self.limit && self.segments.len() > 1 {
+            dropped += self.pop().unwrap_or(0);
+        }
+
+        Ok(dropped)
+    }
+
+    /// Reads records of the oldest segment.
+    pub fn peek(&self) -> Option<io::Result<Vec<Record>>> {
+        self.segments.front().map(|segment| {
+            let mut reader = try!(Reader::open(&segment.path));
+            let mut records = Vec::new();
+            while let Some(record) = try!(reader.next()) {
+                records.push(record);
+            }
+            Ok(records)
+        })
+    }
+
+    /// Removes the oldest segment, returning the number of records it held.
+    pub fn pop(&mut self) -> Option<usize> {
+        self.segments.pop_front().map(|segment| {
+            if let Err(err) = fs::remove_file(&segment.path) {
+                warn!(target: "Spool", "failed to remove {:?}: {}", segment.path, err);
+            }
+            segment.count
+        })
+    }
+
+    /// Returns the number of spooled records.
+    pub fn len(&self) -> usize {
+        self.segments.iter().fold(0, |acc, segment| acc + segment.count)
+    }
+
+    /// Returns the total size of segments in bytes.
+    pub fn size(&self) -> u64 {
+        self.segments.iter().fold(0, |acc, segment| acc + segment.size)
+    }
+}
+
+/// Fills the buffer completely, returning false if the reader is exhausted before reading anything.
+fn read_exact<R: Read>(rd: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match try!(rd.read(&mut buf[pos..])) {
+            0 if pos == 0 => return Ok(false),
+            0 => return Err(io::Error::new(io::ErrorKind::InvalidInput, "truncated spool frame")),
+            n => pos += n,
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use super::super::RecordItem;
+    use super::super::testing::{record, string};
+    use super::{Reader, Spool, Writer};
+
+    #[test]
+    fn interleave_append_and_read() {
+        let path = env::temp_dir().join("logdrop-spool-interleave.seg");
+        let first = record(vec![("message", string("first")), ("code", RecordItem::F64(42.0))]);
+        let second = record(vec![("message", string("second")), ("ok", RecordItem::Bool(true))]);
+
+        let mut writer = Writer::create(&path).unwrap();
+        let mut reader = Reader::open(&path).unwrap();
+
+        assert!(writer.append(&first).unwrap() > 4);
+        assert_eq!(Some(first.clone()), reader.next().unwrap());
+        assert_eq!(None, reader.next().unwrap());
+
+        writer.append(&second).unwrap();
+        assert_eq!(Some(second), reader.next().unwrap());
+        assert_eq!(None, reader.next().unwrap());
+
+        // Limited to the offset past the first record, the reader doesn't see the rest.
+        let mut reader = Reader::open(&path).unwrap();
+        reader.next().unwrap();
+        let mut limited = Reader::open(&path).unwrap().until(reader.offset());
+        assert_eq!(Some(first), limited.next().unwrap());
+        assert_eq!(None, limited.next().unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_spool_and_drop_oldest() {
+        let dir = env::temp_dir().join("logdrop-spool-restore");
+        let _ = fs::remove_dir_all(&dir);
+
+        let first = vec![record(vec![("message", string("first"))])];
+        let second = vec![record(vec![("message", string("second"))]), record(vec![("message", string("third"))])];
+
+        {
+            let mut spool = Spool::open(&dir, 1024).unwrap();
+            assert_eq!(0, spool.push(&first).unwrap());
+            assert_eq!(0, spool.push(&second).unwrap());
+        }
+
+        let mut spool = Spool::open(&dir, 1024).unwrap();
+        assert_eq!(3, spool.len());
+        assert_eq!(first, spool.peek().unwrap().unwrap());
+
+        // A tiny limit keeps only the newest segment.
+        let mut spool = Spool::open(&dir, 1).unwrap();
+        assert_eq!(3, spool.push(&first).unwrap());
+        assert_eq!(1, spool.len());
+        assert_eq!(Some(1), spool.pop());
+        assert!(spool.peek().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
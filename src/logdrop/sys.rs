@@ -0,0 +1,327 @@
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use libc::{c_int, c_long, c_uint, c_void, size_t, ssize_t};
+
+#[cfg(target_os = "linux")]
+mod consts {
+    use libc::{c_int, size_t};
+
+    pub const SIGHUP: c_int = 1;
+    pub const SIGUSR2: c_int = 12;
+    pub const SOL_SOCKET: c_int = 1;
+    pub const O_NONBLOCK: c_int = 0o4000;
+
+    pub type IovLength = size_t;
+    pub type ControlLength = size_t;
+}
+
+#[cfg(not(target_os = "linux"))]
+mod consts {
+    use libc::{c_int, c_uint};
+
+    pub const SIGHUP: c_int = 1;
+    pub const SIGUSR2: c_int = 31;
+    pub const SOL_SOCKET: c_int = 0xffff;
+    pub const O_NONBLOCK: c_int = 0x0004;
+
+    pub type IovLength = c_int;
+    pub type ControlLength = c_uint;
+}
+
+pub use self::consts::{SIGHUP, SIGUSR2};
+use self::consts::{ControlLength, IovLength, O_NONBLOCK, SOL_SOCKET};
+
+const AF_UNIX: c_int = 1;
+const SOCK_STREAM: c_int = 1;
+const SCM_RIGHTS: c_int = 1;
+const F_SETFD: c_int = 2;
+const F_GETFL: c_int = 3;
+const F_SETFL: c_int = 4;
+const FD_CLOEXEC: c_int = 1;
+const PRIO_PROCESS: c_int = 0;
+const RUSAGE_SELF: c_int = 0;
+
+/// Number of 64-bit words of the affinity mask, covering 1024 CPUs as `cpu_set_t` of glibc.
+#[cfg(target_os = "linux")]
+const CPU_MASK_WORDS: usize = 16;
+
+#[repr(C)]
+struct IoVec {
+    base: *mut c_void,
+    len: size_t,
+}
+
+#[repr(C)]
+struct MsgHdr {
+    name: *mut c_void,
+    namelen: u32,
+    iov: *mut IoVec,
+    iovlen: IovLength,
+    control: *mut c_void,
+    controllen: ControlLength,
+    flags: c_int,
+}
+
+#[repr(C)]
+struct CmsgHdr {
+    len: ControlLength,
+    level: c_int,
+    kind: c_int,
+}
+
+#[repr(C)]
+struct TimeVal {
+    sec: c_long,
+    usec: c_long,
+}
+
+#[repr(C)]
+struct RUsage {
+    utime: TimeVal,
+    stime: TimeVal,
+    rest: [c_long; 14],
+}
+
+/// Control message carrying a single descriptor, laid out as `CMSG_SPACE(sizeof(int))` bytes.
+#[repr(C)]
+struct Control {
+    header: CmsgHdr,
+    fd: c_int,
+}
+
+extern {
+    fn signal(signum: c_int, handler: extern fn(c_int)) -> size_t;
+    fn socketpair(domain: c_int, kind: c_int, protocol: c_int, fds: *mut c_int) -> c_int;
+    fn sendmsg(fd: c_int, msg: *const MsgHdr, flags: c_int) -> ssize_t;
+    fn recvmsg(fd: c_int, msg: *mut MsgHdr, flags: c_int) -> ssize_t;
+    fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t;
+    fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn close(fd: c_int) -> c_int;
+    fn setpriority(which: c_int, who: c_uint, prio: c_int) -> c_int;
+    fn getrusage(who: c_int, usage: *mut RUsage) -> c_int;
+}
+
+#[cfg(target_os = "linux")]
+extern {
+    fn sched_setaffinity(pid: c_int, size: size_t, mask: *const c_void) -> c_int;
+}
+
+fn check(result: c_int) -> io::Result<c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Installs the handler, which must do nothing but touching atomics.
+pub fn on_signal(signum: c_int, handler: extern fn(c_int)) {
+    unsafe {
+        signal(signum, handler);
+    }
+}
+
+/// Switches the descriptor between blocking and non-blocking modes.
+pub fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<()> {
+    unsafe {
+        let flags = try!(check(fcntl(fd, F_GETFL)));
+        let flags = if nonblocking { flags | O_NONBLOCK } else { flags & !O_NONBLOCK };
+        try!(check(fcntl(fd, F_SETFL, flags)));
+    }
+
+    Ok(())
+}
+
+/// Sets the nice value of the calling thread, as Linux keeps it per thread. Elsewhere it is set
+/// for the whole process.
+pub fn set_nice(nice: i32) -> io::Result<()> {
+    try!(check(unsafe { setpriority(PRIO_PROCESS, 0, nice as c_int) }));
+    Ok(())
+}
+
+/// Pins the calling thread to the given CPUs, returning false where affinity is not supported.
+#[cfg(target_os = "linux")]
+pub fn set_affinity(cpus: &[usize]) -> io::Result<bool> {
+    let mut mask = [0u64; CPU_MASK_WORDS];
+    for &cpu in cpus.iter() {
+        if cpu >= CPU_MASK_WORDS * 64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU number is out of range"));
+        }
+        mask[cpu / 64] |= 1 << (cpu % 64);
+    }
+
+    let size = mem::size_of_val(&mask) as size_t;
+    try!(check(unsafe { sched_setaffinity(0, size, mask.as_ptr() as *const c_void) }));
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_affinity(_: &[usize]) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Returns the user and system CPU time consumed by the process so far, in microseconds.
+pub fn cpu_time() -> io::Result<u64> {
+    let mut usage: RUsage = unsafe { mem::zeroed() };
+    try!(check(unsafe { getrusage(RUSAGE_SELF, &mut usage) }));
+
+    let micros = |time: &TimeVal| time.sec as u64 * 1000000 + time.usec as u64;
+    Ok(micros(&usage.utime) + micros(&usage.stime))
+}
+
+/// Unix stream socket owning its descriptor.
+pub struct Socket {
+    fd: RawFd,
+}
+
+impl Socket {
+    /// Creates a pair of connected sockets, both closed on exec.
+    pub fn pair() -> io::Result<(Socket, Socket)> {
+        let mut fds = [0; 2];
+        try!(check(unsafe { socketpair(AF_UNIX, SOCK_STREAM, 0, fds.as_mut_ptr()) }));
+
+        let pair = (Socket { fd: fds[0] }, Socket { fd: fds[1] });
+        try!(pair.0.set_inheritable(false));
+        try!(pair.1.set_inheritable(false));
+        Ok(pair)
+    }
+
+    /// Takes the ownership of the descriptor.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Socket {
+        Socket {
+            fd: fd,
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Tells whether a spawned process inherits the descriptor.
+    pub fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        let flags = if inheritable { 0 } else { FD_CLOEXEC };
+        try!(check(unsafe { fcntl(self.fd, F_SETFD, flags) }));
+        Ok(())
+    }
+
+    /// Sends the payload together with the descriptor attached to its first byte.
+    pub fn send_fd(&mut self, payload: &[u8], fd: RawFd) -> io::Result<()> {
+        let mut iov = IoVec {
+            base: payload.as_ptr() as *mut c_void,
+            len: payload.len() as size_t,
+        };
+        let mut control: Control = unsafe { mem::zeroed() };
+        control.header.len = (mem::size_of::<CmsgHdr>() + mem::size_of::<c_int>()) as ControlLength;
+        control.header.level = SOL_SOCKET;
+        control.header.kind = SCM_RIGHTS;
+        control.fd = fd;
+
+        let msg = MsgHdr {
+            name: ptr::null_mut(),
+            namelen: 0,
+            iov: &mut iov,
+            iovlen: 1,
+            control: &mut control as *mut Control as *mut c_void,
+            controllen: mem::size_of::<Control>() as ControlLength,
+            flags: 0,
+        };
+
+        let sent = unsafe { sendmsg(self.fd, &msg, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.write_all(&payload[sent as usize..])
+    }
+
+    /// Reads exactly the buffer, returning the descriptor attached to it, if any.
+    pub fn recv_fd(&mut self, buf: &mut [u8]) -> io::Result<Option<RawFd>> {
+        let mut iov = IoVec {
+            base: buf.as_mut_ptr() as *mut c_void,
+            len: buf.len() as size_t,
+        };
+        let mut control: Control = unsafe { mem::zeroed() };
+        let mut msg = MsgHdr {
+            name: ptr::null_mut(),
+            namelen: 0,
+            iov: &mut iov,
+            iovlen: 1,
+            control: &mut control as *mut Control as *mut c_void,
+            controllen: mem::size_of::<Control>() as ControlLength,
+            flags: 0,
+        };
+
+        let received = unsafe { recvmsg(self.fd, &mut msg, 0) };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if received == 0 && buf.len() > 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "connection closed"));
+        }
+
+        let fd = if msg.controllen as usize >= mem::size_of::<CmsgHdr>()
+            && control.header.level == SOL_SOCKET && control.header.kind == SCM_RIGHTS
+        {
+            Some(control.fd)
+        } else {
+            None
+        };
+
+        let mut rest = &mut buf[received as usize..];
+        while !rest.is_empty() {
+            let count = try!(self.read(rest));
+            if count == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "connection closed"));
+            }
+            rest = &mut mem::replace(&mut rest, &mut [])[count..];
+        }
+
+        Ok(fd)
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = unsafe { read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t) };
+        if count < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(count as usize)
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = unsafe { write(self.fd, buf.as_ptr() as *const c_void, buf.len() as size_t) };
+        if count < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(count as usize)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+/// Closes the descriptor not owned by anything.
+pub fn close_fd(fd: RawFd) {
+    unsafe {
+        close(fd);
+    }
+}
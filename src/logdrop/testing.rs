@@ -0,0 +1,408 @@
+use std::collections::VecDeque;
+use std::env;
+use std::io;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use log;
+use log::{LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+
+use super::{Record, RecordItem};
+use super::codec::{Codec, MessagePack};
+use super::codec::msgpack;
+use super::input::{Input, Sink};
+use super::output::{Error, Output, Resolver};
+use super::resp;
+use super::resp::Reply;
+
+/// Builds a record from key-value pairs.
+///
+/// With `LOGDROP_TEST_LAZY` set in the environment the record is encoded and decoded back lazily,
+/// so that running tests with it checks lazy records behave like decoded ones, see `lazy`.
+pub fn record(pairs: Vec<(&str, RecordItem)>) -> Record {
+    let mut record = Record::new();
+    for (key, value) in pairs {
+        record.insert(key.to_string(), value);
+    }
+
+    if env::var_os("LOGDROP_TEST_LAZY").is_none() {
+        return record;
+    }
+
+    let mut records = MessagePack::default().lazy(true).decode(Box::new(Cursor::new(msgpack::encode(&record))));
+    records.next().expect("record must decode back")
+}
+
+pub fn string(value: &str) -> RecordItem {
+    RecordItem::String(value.to_string())
+}
+
+/// Output that remembers everything it was fed. Clones share the same storage.
+#[derive(Clone)]
+pub struct Collector {
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl Collector {
+    pub fn new() -> Collector {
+        Collector {
+            records: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl Output for Collector {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        self.records.lock().unwrap().push(payload.clone());
+        Ok(())
+    }
+}
+
+/// Output failing with the scripted errors in order before delivering into the collector.
+///
+/// An ambiguous failure delivers the record anyway, like a timeout after the request has been
+/// processed would.
+pub struct Scripted {
+    errors: VecDeque<Error>,
+    collector: Collector,
+}
+
+impl Scripted {
+    pub fn new(errors: Vec<Error>, collector: Collector) -> Scripted {
+        Scripted {
+            errors: errors.into_iter().collect(),
+            collector: collector,
+        }
+    }
+}
+
+impl Output for Scripted {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        match self.errors.pop_front() {
+            Some(err @ Error::Ambiguous(..)) => {
+                try!(self.collector.feed(payload));
+                Err(err)
+            }
+            Some(err) => Err(err),
+            None => self.collector.feed(payload),
+        }
+    }
+}
+
+/// Output delivering into the collector while the shared switch is on, failing definitely
+/// otherwise.
+pub struct Flaky {
+    up: Arc<AtomicBool>,
+    collector: Collector,
+}
+
+impl Flaky {
+    pub fn new(up: Arc<AtomicBool>, collector: Collector) -> Flaky {
+        Flaky {
+            up: up,
+            collector: collector,
+        }
+    }
+}
+
+impl Output for Flaky {
+    fn feed(&mut self, payload: &Record) -> Result<(), Error> {
+        if self.up.load(Ordering::SeqCst) {
+            self.collector.feed(payload)
+        } else {
+            Err(Error::Retryable("down".to_string()))
+        }
+    }
+
+    fn probe(&mut self) -> bool {
+        self.up.load(Ordering::SeqCst)
+    }
+}
+
+/// Input passing records sent through the paired channel, finishing when the channel is closed.
+pub struct Feeder {
+    rx: Mutex<Option<Receiver<Record>>>,
+    peer: Option<String>,
+}
+
+impl Feeder {
+    pub fn new() -> (Feeder, Sender<Record>) {
+        let (tx, rx) = channel();
+        let feeder = Feeder {
+            rx: Mutex::new(Some(rx)),
+            peer: None,
+        };
+
+        (feeder, tx)
+    }
+
+    /// Makes records look like arriving from a connection with the given peer address.
+    pub fn peer(mut self, peer: &str) -> Feeder {
+        self.peer = Some(peer.to_string());
+        self
+    }
+}
+
+impl Input for Feeder {
+    fn run(&self, tx: Sink, _: Box<Codec>) {
+        let rx = self.rx.lock().unwrap().take().expect("feeder can be run only once");
+        let tx = match self.peer {
+            Some(ref peer) => tx.with_peer(peer),
+            None => tx,
+        };
+
+        for record in rx.iter() {
+            tx.send(record).unwrap();
+        }
+    }
+}
+
+/// Resolver answering with the scripted addresses whatever the name, or failing without any.
+/// Clones share the same answer.
+#[derive(Clone)]
+pub struct Dns {
+    addrs: Arc<Mutex<Option<Vec<SocketAddr>>>>,
+}
+
+impl Dns {
+    pub fn new(addrs: Vec<SocketAddr>) -> Dns {
+        Dns {
+            addrs: Arc::new(Mutex::new(Some(addrs))),
+        }
+    }
+
+    pub fn failing() -> Dns {
+        Dns {
+            addrs: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn set(&self, addrs: Vec<SocketAddr>) {
+        *self.addrs.lock().unwrap() = Some(addrs);
+    }
+}
+
+impl Resolver for Dns {
+    fn resolve(&self, host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+        match *self.addrs.lock().unwrap() {
+            Some(ref addrs) => Ok(addrs.clone()),
+            None => Err(io::Error::new(io::ErrorKind::Other, format!("unable to resolve {}", host))),
+        }
+    }
+}
+
+/// Spawns an HTTP server answering every request with an empty JSON object, passing request
+/// bodies through the returned channel.
+pub fn http_server(listener: TcpListener) -> Receiver<String> {
+    scripted_http_server(listener, |_| 200)
+}
+
+/// Spawns an HTTP server like `http_server`, answering each request with the status the script
+/// maps the number of the request to.
+pub fn scripted_http_server<F>(listener: TcpListener, script: F) -> Receiver<String>
+    where F: Fn(usize) -> u16 + Send + 'static
+{
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for (id, stream) in listener.incoming().enumerate() {
+            let mut rd = BufReader::new(stream.unwrap());
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                rd.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+
+                let mut parts = line.splitn(2, ':');
+                if parts.next().unwrap().to_lowercase() == "content-length" {
+                    length = parts.next().unwrap().trim().parse().unwrap();
+                }
+            }
+
+            let mut body = String::new();
+            rd.by_ref().take(length).read_to_string(&mut body).unwrap();
+            let status = script(id);
+            write!(rd.get_mut(), "HTTP/1.0 {} Status\r\nContent-Length: 2\r\n\r\n{{}}", status).unwrap();
+
+            if tx.send(body).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+/// Spawns an HTTP server answering requests concurrently, each one after the delay given in
+/// milliseconds with the status the script maps the number of the request to, passing statuses
+/// together with request bodies through the returned channel as they are answered.
+pub fn delayed_http_server<F>(listener: TcpListener, delay: u32, script: F) -> Receiver<(u16, String)>
+    where F: Fn(usize) -> u16 + Send + Sync + 'static
+{
+    let (tx, rx) = channel();
+    let script = Arc::new(script);
+    thread::spawn(move || {
+        for (id, stream) in listener.incoming().enumerate() {
+            let tx = tx.clone();
+            let script = script.clone();
+            thread::spawn(move || {
+                let mut rd = BufReader::new(stream.unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    rd.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+
+                    let mut parts = line.splitn(2, ':');
+                    if parts.next().unwrap().to_lowercase() == "content-length" {
+                        length = parts.next().unwrap().trim().parse().unwrap();
+                    }
+                }
+
+                let mut body = String::new();
+                rd.by_ref().take(length).read_to_string(&mut body).unwrap();
+                thread::sleep_ms(delay);
+
+                let status = script(id);
+                write!(rd.get_mut(), "HTTP/1.0 {} Status\r\nContent-Length: 2\r\n\r\n{{}}", status).unwrap();
+                let _ = tx.send((status, body));
+            });
+        }
+    });
+
+    rx
+}
+
+/// Spawns a Redis server serving connections one by one, passing each command together with the
+/// number of its connection through the returned channel before answering it.
+///
+/// The script maps the connection number and the command to the raw reply, or to `None` for
+/// closing the connection instead, once the client has sent everything it had.
+pub fn redis_server<F>(listener: TcpListener, script: F) -> Receiver<(usize, Vec<String>)>
+    where F: Fn(usize, &[String]) -> Option<String> + Send + 'static
+{
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for (id, stream) in listener.incoming().enumerate() {
+            let mut rd = BufReader::new(stream.unwrap());
+            loop {
+                let args: Vec<String> = match resp::read(&mut rd) {
+                    Ok(Reply::Array(Some(items))) => {
+                        items.into_iter().map(|item| match item {
+                            Reply::Bulk(Some(arg)) => String::from_utf8(arg).unwrap(),
+                            item => panic!("unexpected command argument {:?}", item),
+                        }).collect()
+                    }
+                    Ok(reply) => panic!("unexpected command {:?}", reply),
+                    Err(..) => break,
+                };
+
+                let reply = script(id, &args);
+                if tx.send((id, args)).is_err() {
+                    return;
+                }
+
+                match reply {
+                    Some(reply) => rd.get_mut().write_all(reply.as_bytes()).unwrap(),
+                    None => {
+                        rd.get_mut().shutdown(Shutdown::Write).unwrap();
+                        let mut rest = Vec::new();
+                        let _ = rd.read_to_end(&mut rest);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+/// Lines logged while captured and the handle of the maximum level, once the capturing logger is
+/// installed, see `capture`.
+struct Captured {
+    lines: Option<Vec<String>>,
+    filter: Option<MaxLogLevelFilter>,
+}
+
+fn captured() -> &'static Mutex<Captured> {
+    static mut CAPTURED: *const Mutex<Captured> = 0 as *const Mutex<Captured>;
+    static INIT: Once = ONCE_INIT;
+
+    unsafe {
+        INIT.call_once(|| {
+            let captured = Box::new(Mutex::new(Captured { lines: None, filter: None }));
+            CAPTURED = &*captured;
+            mem::forget(captured);
+        });
+
+        &*CAPTURED
+    }
+}
+
+/// Logger keeping lines logged from all threads while captured instead of printing them.
+struct Capturing;
+
+impl log::Log for Capturing {
+    fn enabled(&self, _: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        let mut captured = captured().lock().unwrap();
+        if let Some(ref mut lines) = captured.lines {
+            lines.push(format!("{} : {}", record.target(), record.args()));
+        }
+    }
+}
+
+/// Captures lines logged at any level until dropped.
+pub struct Capture;
+
+/// Starts capturing logged lines, installing the capturing logger unless it is already.
+pub fn capture() -> Capture {
+    let mut captured = captured().lock().unwrap();
+    if captured.filter.is_none() {
+        let mut filter = None;
+        log::set_logger(|max| {
+            filter = Some(max);
+            Box::new(Capturing)
+        }).unwrap();
+        captured.filter = filter;
+    }
+
+    captured.lines = Some(Vec::new());
+    if let Some(ref filter) = captured.filter {
+        filter.set(LogLevelFilter::Trace);
+    }
+    Capture
+}
+
+impl Capture {
+    /// Returns lines captured so far as `<target> : <message>`.
+    pub fn lines(&self) -> Vec<String> {
+        captured().lock().unwrap().lines.clone().unwrap_or(Vec::new())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let mut captured = captured().lock().unwrap();
+        captured.lines = None;
+        if let Some(ref filter) = captured.filter {
+            filter.set(LogLevelFilter::Off);
+        }
+    }
+}
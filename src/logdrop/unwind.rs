@@ -0,0 +1,51 @@
+use std::any::Any;
+use std::mem;
+use std::thread;
+
+/// Runs the function, returning the panic message instead of unwinding further if it panics.
+///
+/// Unlike `thread::catch_panic` the function may borrow from the caller, which is sound as it is
+/// run to completion before returning.
+pub fn catch<F, R>(f: F) -> Result<R, String>
+    where F: FnOnce() -> R + Send,
+          R: Send
+{
+    let mut f = Some(f);
+    let mut result = None;
+    {
+        let mut call = || result = Some((f.take().unwrap())());
+        let call: &mut (FnMut() + Send) = &mut call;
+        let call: &'static mut (FnMut() + Send) = unsafe { mem::transmute(call) };
+        if let Err(err) = thread::catch_panic(move || call()) {
+            return Err(message(err));
+        }
+    }
+
+    Ok(result.unwrap())
+}
+
+fn message(err: Box<Any + Send>) -> String {
+    match err.downcast::<String>() {
+        Ok(message) => *message,
+        Err(err) => {
+            match err.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(..) => "unknown panic".to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::catch;
+
+    #[test]
+    fn catch_panic_of_borrowing_function() {
+        let mut calls = 0;
+        assert_eq!(Ok(42), catch(|| { calls += 1; 42 }));
+        assert_eq!(Err("broken".to_string()), catch(|| -> u32 { calls += 1; panic!("broken") }));
+        assert_eq!(Err("broken 2".to_string()), catch(|| -> u32 { panic!("broken {}", 2) }));
+        assert_eq!(2, calls);
+    }
+}
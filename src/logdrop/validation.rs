@@ -0,0 +1,157 @@
+use std::fmt::Write;
+
+use super::{Record, RecordItem};
+use super::config::{Config, Error, FromConfig};
+
+/// What to do with a record lacking one of the required fields.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Missing {
+    /// Drop the record with a warning.
+    Drop,
+    /// Route the record to the dead-letter output.
+    DeadLetter,
+    /// Fill the missing fields with a summary built from the fields present.
+    Synthesize,
+    /// Accept the record as is.
+    Pass,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Accept(Record),
+    Drop(String),
+    DeadLetter(Record, String),
+}
+
+/// Set of rules every incoming record is checked against before entering the filter chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validation {
+    pub required: Vec<String>,
+    pub missing: Missing,
+}
+
+impl Validation {
+    pub fn new(required: Vec<String>, missing: Missing) -> Validation {
+        Validation {
+            required: required,
+            missing: missing,
+        }
+    }
+
+    pub fn check(&self, mut record: Record) -> Verdict {
+        let absent: Vec<&String> = self.required.iter().filter(|name| {
+            record.find(name).is_none()
+        }).collect();
+
+        if absent.is_empty() {
+            return Verdict::Accept(record);
+        }
+
+        let reason = format!("{:?} field required", absent);
+        match self.missing {
+            Missing::Drop => Verdict::Drop(reason),
+            Missing::DeadLetter => Verdict::DeadLetter(record, reason),
+            Missing::Synthesize => {
+                let summary = summarize(&record);
+                for name in absent.into_iter() {
+                    record.insert(name.clone(), RecordItem::String(summary.clone()));
+                }
+                Verdict::Accept(record)
+            }
+            Missing::Pass => Verdict::Accept(record),
+        }
+    }
+}
+
+impl FromConfig for Validation {
+    fn from_config(config: &Config) -> Result<Validation, Error> {
+        let required = try!(config.strings_or("required", vec!["message".to_string()]));
+        let missing = match &try!(config.string_or("missing", "drop"))[..] {
+            "drop" => Missing::Drop,
+            "dead_letter" => Missing::DeadLetter,
+            "synthesize" => Missing::Synthesize,
+            "pass" => Missing::Pass,
+            other => return Err(Error::Invalid(format!("unknown missing field policy '{}'", other))),
+        };
+
+        Ok(Validation::new(required, missing))
+    }
+}
+
+impl Default for Validation {
+    fn default() -> Validation {
+        Validation::new(vec!["message".to_string()], Missing::Drop)
+    }
+}
+
+/// Renders the record as space-separated `key=value` pairs sorted by key.
+fn summarize(record: &Record) -> String {
+    let mut pairs: Vec<(&String, &RecordItem)> = record.iter().collect();
+    pairs.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut result = String::new();
+    for (key, value) in pairs.into_iter() {
+        if !result.is_empty() {
+            result.push(' ');
+        }
+
+        match value.as_str() {
+            Some(string) => write!(result, "{}={}", key, string).unwrap(),
+            None => write!(result, "{}={:?}", key, value).unwrap(),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::RecordItem;
+    use super::super::testing::{record, string};
+    use super::{Missing, Validation, Verdict};
+
+    fn validation(missing: Missing) -> Validation {
+        Validation::new(vec!["message".to_string()], missing)
+    }
+
+    #[test]
+    fn accept_complete_record() {
+        let payload = record(vec![("message", string("le message"))]);
+        assert_eq!(Verdict::Accept(payload.clone()), validation(Missing::Drop).check(payload));
+    }
+
+    #[test]
+    fn drop_incomplete_record() {
+        let payload = record(vec![("source", string("app"))]);
+        match validation(Missing::Drop).check(payload) {
+            Verdict::Drop(..) => {}
+            other => panic!("expected drop, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dead_letter_incomplete_record() {
+        let payload = record(vec![("source", string("app"))]);
+        match validation(Missing::DeadLetter).check(payload.clone()) {
+            Verdict::DeadLetter(record, _) => assert_eq!(payload, record),
+            other => panic!("expected dead letter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn synthesize_missing_field() {
+        let payload = record(vec![("source", string("app")), ("value", RecordItem::Bool(true))]);
+        let expected = record(vec![
+            ("source", string("app")),
+            ("value", RecordItem::Bool(true)),
+            ("message", string("source=app value=Bool(true)")),
+        ]);
+        assert_eq!(Verdict::Accept(expected), validation(Missing::Synthesize).check(payload));
+    }
+
+    #[test]
+    fn pass_incomplete_record() {
+        let payload = record(vec![("source", string("app"))]);
+        assert_eq!(Verdict::Accept(payload.clone()), validation(Missing::Pass).check(payload));
+    }
+}
@@ -0,0 +1,46 @@
+use std::fmt;
+
+use super::{Record, RecordItem};
+use super::identity;
+
+/// Version of the crate the binary is built from.
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/// Returns the commit the binary is built from, as given in the `LOGDROP_COMMIT` environment
+/// variable at build time.
+pub fn commit() -> &'static str {
+    option_env!("LOGDROP_COMMIT").unwrap_or("unknown")
+}
+
+/// Components of the pipeline: inputs by their names, filters and outputs by their types.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub inputs: Vec<String>,
+    pub filters: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "inputs {:?}, filters {:?}, outputs {:?}", self.inputs, self.filters, self.outputs)
+    }
+}
+
+/// Returns the record announcing the start of the process together with its build, identity and
+/// the pipeline it runs.
+pub fn startup(summary: &Summary) -> Record {
+    let names = |names: &[String]| {
+        RecordItem::Array(names.iter().map(|name| RecordItem::String(name.clone())).collect())
+    };
+
+    let mut record = Record::new();
+    record.insert("message".to_string(), RecordItem::String("logdrop started".to_string()));
+    record.insert("version".to_string(), RecordItem::String(VERSION.to_string()));
+    record.insert("commit".to_string(), RecordItem::String(commit().to_string()));
+    record.insert("instance".to_string(), RecordItem::String(identity::current().instance.clone()));
+    record.insert("config".to_string(), RecordItem::String(identity::current().config.clone()));
+    record.insert("inputs".to_string(), names(&summary.inputs));
+    record.insert("filters".to_string(), names(&summary.filters));
+    record.insert("outputs".to_string(), names(&summary.outputs));
+    record
+}
@@ -0,0 +1,113 @@
+use std::mem;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+
+use super::clock::{Clock, SystemClock};
+
+/// Fraction of their thresholds components apply right after the start.
+const FLOOR: f64 = 0.1;
+
+struct State {
+    started: Option<u64>,
+    duration: u64,
+}
+
+/// Warm-up phase following the pipeline start, shared by time-window components whose state is
+/// empty at first, so that they can behave more conservatively meanwhile.
+///
+/// The phase starts once the pipeline runs and is never restarted by reloads. Clones share the
+/// same phase.
+#[derive(Clone)]
+pub struct WarmUp {
+    state: Arc<Mutex<State>>,
+    clock: Arc<Clock>,
+}
+
+impl WarmUp {
+    /// Creates the not yet started phase lasting the given number of milliseconds.
+    pub fn new(duration: u64, clock: Arc<Clock>) -> WarmUp {
+        WarmUp {
+            state: Arc::new(Mutex::new(State { started: None, duration: duration })),
+            clock: clock,
+        }
+    }
+
+    pub fn set_duration(&self, duration: u64) {
+        self.state.lock().unwrap().duration = duration;
+    }
+
+    /// Starts the phase, unless already started.
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.started.is_none() {
+            state.started = Some(self.clock.now());
+        }
+    }
+
+    /// Returns the instant the pipeline started at in milliseconds since the Unix epoch, if it
+    /// has.
+    pub fn started_at(&self) -> Option<u64> {
+        self.state.lock().unwrap().started
+    }
+
+    /// Returns the elapsed fraction of the phase, zero before the start.
+    pub fn fraction(&self) -> f64 {
+        let state = self.state.lock().unwrap();
+        match state.started {
+            Some(..) if state.duration == 0 => 1.0,
+            Some(started) => (self.clock.now().saturating_sub(started) as f64 / state.duration as f64).min(1.0),
+            None => 0.0,
+        }
+    }
+
+    pub fn is_warming(&self) -> bool {
+        self.fraction() < 1.0
+    }
+
+    /// Scales the threshold by the elapsed fraction, from a tenth of it right after the start up
+    /// to the whole of it once the phase is over.
+    pub fn scale(&self, threshold: f64) -> f64 {
+        threshold * (FLOOR + (1.0 - FLOOR) * self.fraction())
+    }
+}
+
+/// Returns the warm-up phase of the process, started by the first pipeline run and lasting no
+/// time until configured otherwise.
+pub fn process() -> WarmUp {
+    static mut PROCESS: *const WarmUp = 0 as *const WarmUp;
+    static INIT: Once = ONCE_INIT;
+
+    unsafe {
+        INIT.call_once(|| {
+            PROCESS = mem::transmute(Box::new(WarmUp::new(0, Arc::new(SystemClock))));
+        });
+
+        (*PROCESS).clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::super::clock::MockClock;
+    use super::WarmUp;
+
+    #[test]
+    fn scale_thresholds_by_elapsed_fraction() {
+        let clock = MockClock::new(1000);
+        let warm_up = WarmUp::new(10000, Arc::new(clock.clone()));
+        assert_eq!(None, warm_up.started_at());
+        assert!((warm_up.scale(100.0) - 10.0).abs() < 1e-9);
+
+        warm_up.start();
+        clock.advance(5000);
+        warm_up.start();
+        assert_eq!(Some(1000), warm_up.started_at());
+        assert!(warm_up.is_warming());
+        assert!((warm_up.scale(100.0) - 55.0).abs() < 1e-9);
+
+        clock.advance(5000);
+        assert!(!warm_up.is_warming());
+        assert!((warm_up.scale(100.0) - 100.0).abs() < 1e-9);
+    }
+}
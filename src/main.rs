@@ -1,4 +1,4 @@
-#![feature(core, convert, io, path_ext, test)]
+#![feature(catch_panic, core, convert, io, path_ext, test)]
 
 #[macro_use]
 extern crate log;
@@ -6,84 +6,160 @@ extern crate libc;
 extern crate chrono;
 extern crate rmp as msgpack;
 
-use std::sync::mpsc::channel;
-use std::sync::mpsc::Sender;
-use std::thread;
+use std::env;
+use std::path::Path;
+use std::process;
 
 use log::LogLevel;
 
+use logdrop::audit;
+use logdrop::audit::Auditor;
 use logdrop::codec;
-use logdrop::codec::Codec;
-use logdrop::input::{Input, TcpInput};
+use logdrop::config;
+use logdrop::control;
+use logdrop::control::Control;
+use logdrop::handover;
+use logdrop::handover::Upgrader;
+use logdrop::identity;
+use logdrop::identity::Identity;
+use logdrop::input::TcpInput;
 use logdrop::logging;
-use logdrop::output::{Output, Null};
-use logdrop::Record;
+use logdrop::output::Null;
+use logdrop::pipeline::Pipeline;
+use logdrop::reload::Reloader;
+use logdrop::validation::Validation;
+use logdrop::version;
 
 mod logdrop;
 
-fn run(inputs: Vec<(Box<Input>, Box<Codec>)>, outputs: Vec<Box<Output>>) {
-    let (tx, rx) = channel();
-
-    for (input, codec) in inputs.into_iter() {
-        trace!(target: "Main", "starting '{}' input", input.typename());
+fn main() {
+    logging::init(LogLevel::Info).ok().expect("unable to initialize logging system");
 
-        let tx = tx.clone();
-        thread::spawn(move || {
-            input.run(tx, codec)
-        });
+    let mut path = None;
+    let mut dry = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--dry-run" => dry = true,
+            "--verify-audit" => {
+                match args.next() {
+                    Some(path) => verify_audit(Path::new(&path)),
+                    None => {
+                        error!(target: "Main", "--verify-audit requires an audit trail path");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--version" => {
+                println!("logdrop {} ({})", version::VERSION, version::commit());
+                process::exit(0);
+            }
+            _ => path = Some(arg),
+        }
     }
 
-    let channels: Vec<Sender<Record>> = outputs.into_iter().map(|mut output| {
-        let(tx, rx) = channel();
-        thread::spawn(move || {
-            trace!(target: "Main", "starting '{}' output", output.typename());
-
-            loop {
-                output.feed(&rx.recv().unwrap());
+    if dry {
+        match path {
+            Some(ref path) => dry_run(Path::new(path)),
+            None => {
+                error!(target: "Main", "--dry-run requires a config path");
+                process::exit(1);
             }
-        });
-
-        tx
-    }).collect();
-
-    loop {
-        debug!(target: "Main", "waiting for new data ...");
-
-        let mut value = rx.recv().unwrap();
-        trace!(target: "Main", "processing {:?}", value);
+        }
+    }
 
-        if value.find("message").is_none() {
-            warn!(target: "Main", "dropping '{:?}': message field required", value);
-            continue;
+    let pipeline = match path {
+        Some(path) => {
+            let path = Path::new(&path);
+            match load(path) {
+                Ok((content, mut pipeline, upgrade, commands, audit)) => {
+                    if let Some(settings) = audit {
+                        match Auditor::open(&settings.path) {
+                            Ok(auditor) => {
+                                audit::install(auditor);
+                            }
+                            Err(err) => {
+                                error!(target: "Main", "{}", err);
+                                process::exit(1);
+                            }
+                        }
+                    }
+
+                    // Started by the running process on upgrade, taking its listening sockets over.
+                    if let Some(socket) = handover::inherited() {
+                        if let Err(err) = handover::adopt(socket, &mut pipeline) {
+                            error!(target: "Main", "{}", err);
+                            process::exit(1);
+                        }
+                    }
+
+                    let handle = pipeline.handle();
+                    Reloader::new(path, content, handle.clone()).listen();
+                    Upgrader::new(upgrade, handle.clone()).listen();
+                    if let Err(err) = Control::new(handle).listen(&commands) {
+                        error!(target: "Main", "unable to listen for control commands: {}", err);
+                        process::exit(1);
+                    }
+                    pipeline
+                }
+                Err(err) => {
+                    error!(target: "Main", "{}", err);
+                    process::exit(1);
+                }
+            }
         }
+        None => {
+            let mut pipeline = Pipeline::new(Validation::default());
+            pipeline.add_input("tcp", Box::new(TcpInput::new("::".to_string(), 10053)), Box::new(codec::MessagePack::default()), None);
+            pipeline.add_output(Box::new(Null));
+            pipeline
+        }
+    };
 
-//        match value {
-//            Value::Object(ref mut object) => {
-//                let now = chrono::Local::now();
-//                object.insert("timestamp".to_string(), Value::String(format!("{}", now)));
-//            }
-//            _ => { unimplemented!() }
-//        }
+    let identity = identity::current();
+    info!(target: "Main", "logdrop {} ({}) starting as instance {} with config {}: {}", version::VERSION, version::commit(),
+        identity.instance, identity.config, pipeline.summary());
+    pipeline.run();
+}
 
-        for tx in channels.iter() {
-            tx.send(value.clone()).unwrap();
-        }
-    }
+/// Reads the config, building the pipeline, upgrade, control and audit settings out of it.
+fn load(path: &Path) -> Result<(String, Pipeline, handover::Settings, control::Settings, Option<audit::Settings>), config::Error> {
+    let content = try!(config::read(path));
+    let settings = try!(config::section(&content, "identity")).unwrap_or_else(identity::Settings::default);
+    identity::install(try!(Identity::load(&settings, &content).map_err(config::Error::Io)));
+
+    let pipeline = try!(config::parse(&content));
+    let upgrade = try!(config::section(&content, "upgrade")).unwrap_or_else(handover::Settings::default);
+    let commands = try!(config::section(&content, "control")).unwrap_or_else(control::Settings::default);
+    let audit = try!(config::section(&content, "audit"));
+    Ok((content, pipeline, upgrade, commands, audit))
 }
 
-fn main() {
-    use logdrop::codec::Codec;
+/// Checks the config without running the pipeline, exiting non-zero if any problem is found.
+fn dry_run(path: &Path) -> ! {
+    let errors = config::verify(path);
+    for err in errors.iter() {
+        error!(target: "Main", "{}", err);
+    }
 
-    logging::init(LogLevel::Info).ok().expect("unable to initialize logging system");
+    if errors.is_empty() {
+        info!(target: "Main", "config {:?} is valid", path);
+        process::exit(0);
+    }
 
-    let inputs: Vec<(Box<Input>, Box<Codec>)> = vec![
-        (Box::new(TcpInput::new("::".to_string(), 10053)), Box::new(codec::MessagePack)),
-    ];
+    process::exit(1);
+}
 
-    let outputs: Vec<Box<Output>> = vec![
-        Box::new(Null)
-//        Box::new(FileOutput::new("/tmp/{parent/child}-{source}-logdrop.log", "[{timestamp}]: {message}")) as Box<Output + Sync +Send>,
-//        box ElasticsearchOutput::new("localhost", 9200) as Box<Output + Send>,
-    ];
-    run(inputs, outputs);
+/// Checks the hash chain of the audit trail, exiting non-zero if any entry is broken.
+fn verify_audit(path: &Path) -> ! {
+    match audit::verify(path) {
+        Ok(chain) => {
+            println!("{} entries are intact, the last one is hashed {}", chain.entries, chain.last);
+            process::exit(0);
+        }
+        Err(err) => {
+            println!("audit trail {:?} is broken: {}", path, err);
+            process::exit(1);
+        }
+    }
 }